@@ -0,0 +1,347 @@
+// Encoding of proposer-safekeeper messages: round trip of every message kind with and
+// without framing, in every protocol version, incomplete input, skipping of unknown
+// frame fields and rejection of invalid appends.
+use bytes::{Bytes, BytesMut};
+use walkeeper::safekeeper_protocol::*;
+
+fn node_id(term: u64) -> NodeId {
+    NodeId {
+        term: term,
+        uuid: 0x000102030405060708090a0b0c0d0e0f,
+    }
+}
+
+fn request(begin_lsn: u64, end_lsn: u64) -> SafeKeeperRequest {
+    SafeKeeperRequest {
+        sender_id: node_id(2),
+        begin_lsn: begin_lsn,
+        end_lsn: end_lsn,
+        restart_lsn: 0x1000000,
+        commit_lsn: 0x16B3700,
+    }
+}
+
+fn proposer_messages() -> Vec<ProposerMessage> {
+    let server_info = ServerInfo {
+        protocol_version: SK_PROTOCOL_VERSION,
+        pg_version: 130002,
+        node_id: node_id(0),
+        system_id: 0x1122334455667788,
+        wal_end: 0x16B3748,
+        timeline: 1,
+        wal_seg_size: 16 * 1024 * 1024,
+    };
+    vec![
+        ProposerMessage::Greeting(PeerGreeting {
+            protocol_version: SK_PROTOCOL_VERSION,
+            role: PeerRole::ProposerWithAcceptorSet as u32 | PEER_CAP_FRAMED,
+        }),
+        ProposerMessage::AcceptorSetClaim(AcceptorSetClaim {
+            acceptor_index: 1,
+            n_acceptors: 3,
+            quorum: 2,
+        }),
+        ProposerMessage::ServerInfo(server_info),
+        ProposerMessage::RequestVote(RequestVote {
+            node_id: node_id(2),
+            vcl: 0x16B3748,
+            epoch: 2,
+        }),
+        ProposerMessage::Append(AppendRequest {
+            header: request(0x16B3748, 0x16B3800),
+            wal: Bytes::from(vec![0xAB; 0xB8]),
+            checksum: None,
+        }),
+        /* Heartbeat */
+        ProposerMessage::Append(AppendRequest {
+            header: request(0x16B3800, 0x16B3800),
+            wal: Bytes::new(),
+            checksum: None,
+        }),
+    ]
+}
+
+fn acceptor_messages() -> Vec<AcceptorMessage> {
+    let hs_feedback = HotStandbyFeedback {
+        ts: 700_000_000_000_000,
+        xmin: 0x100000200,
+        catalog_xmin: 0x100000100,
+    };
+    vec![
+        AcceptorMessage::Versions(ProtocolVersions::negotiate(SK_PROTOCOL_VERSION + 1)),
+        AcceptorMessage::Info(SafeKeeperInfo {
+            magic: 0xCafeCeef,
+            format_version: 1,
+            epoch: 1,
+            server: ServerInfo {
+                protocol_version: SK_PROTOCOL_VERSION,
+                pg_version: 130002,
+                node_id: node_id(2),
+                system_id: 0x1122334455667788,
+                wal_end: 0x16B3748,
+                timeline: 1,
+                wal_seg_size: 16 * 1024 * 1024,
+            },
+            commit_lsn: 0x16B3700,
+            flush_lsn: 0x16B3748,
+            restart_lsn: 0x1000000,
+        }),
+        AcceptorMessage::Vote(node_id(3)),
+        AcceptorMessage::Response(SafeKeeperResponse {
+            status: SK_STATUS_FLOW_PAUSE,
+            hs_replicas: 1,
+            epoch: 2,
+            flush_lsn: 0x16B3800,
+            received_lsn: 0x16B3900,
+            hs_feedback: hs_feedback,
+        }),
+    ]
+}
+
+fn codecs() -> Vec<Codec> {
+    let mut codecs = Vec::new();
+    for &framed in &[false, true] {
+        for &wal_checksums in &[false, true] {
+            for version in SK_MIN_PROTOCOL_VERSION..=SK_PROTOCOL_VERSION {
+                codecs.push(Codec {
+                    framed: framed,
+                    wal_checksums: wal_checksums,
+                    version: version,
+                });
+            }
+        }
+    }
+    codecs
+}
+
+// Checksum is present exactly when the codec expects it
+fn with_checksum(msg: &ProposerMessage, codec: &Codec) -> ProposerMessage {
+    match msg {
+        ProposerMessage::Append(append) => ProposerMessage::Append(AppendRequest {
+            checksum: if codec.wal_checksums && !append.wal.is_empty() {
+                Some(0xDEADBEEF)
+            } else {
+                None
+            },
+            ..append.clone()
+        }),
+        other => other.clone(),
+    }
+}
+
+fn encode_proposer(codec: &Codec, msg: &ProposerMessage) -> BytesMut {
+    let mut buf = BytesMut::new();
+    codec.encode_proposer(msg, &mut buf);
+    buf
+}
+
+fn encode_acceptor(codec: &Codec, msg: &AcceptorMessage) -> BytesMut {
+    let mut buf = BytesMut::new();
+    codec.encode_acceptor(msg, &mut buf);
+    buf
+}
+
+#[test]
+fn test_safekeeper_protocol_round_trip() {
+    for codec in codecs() {
+        for msg in proposer_messages() {
+            let msg = with_checksum(&msg, &codec);
+            let encoded = encode_proposer(&codec, &msg);
+            let header_size = codec.header_size(msg.kind());
+            let size = codec
+                .message_size(msg.kind(), &encoded[..header_size])
+                .unwrap();
+            assert_eq!(size, encoded.len(), "{:?} {:?}", codec, msg);
+
+            /* Message is decoded only once complete, and only its bytes are consumed */
+            let mut buf = BytesMut::from(&encoded[..encoded.len() - 1]);
+            assert!(codec
+                .decode_proposer(msg.kind(), &mut buf)
+                .unwrap()
+                .is_none());
+            assert_eq!(buf.len(), encoded.len() - 1);
+            let mut buf = encoded.clone();
+            buf.extend_from_slice(b"next");
+            let decoded = codec
+                .decode_proposer(msg.kind(), &mut buf)
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..], b"next");
+            assert_eq!(encode_proposer(&codec, &decoded), encoded);
+        }
+        for msg in acceptor_messages() {
+            let encoded = encode_acceptor(&codec, &msg);
+            let mut buf = BytesMut::from(&encoded[..encoded.len() - 1]);
+            assert!(codec
+                .decode_acceptor(msg.kind(), &mut buf)
+                .unwrap()
+                .is_none());
+            let mut buf = encoded.clone();
+            let decoded = codec
+                .decode_acceptor(msg.kind(), &mut buf)
+                .unwrap()
+                .unwrap();
+            assert!(buf.is_empty());
+            assert_eq!(encode_acceptor(&codec, &decoded), encoded);
+        }
+    }
+}
+
+#[test]
+fn test_safekeeper_protocol_framing() {
+    let plain = Codec {
+        version: SK_PROTOCOL_VERSION,
+        ..Codec::default()
+    };
+    let framed = Codec {
+        framed: true,
+        ..plain
+    };
+    for msg in proposer_messages() {
+        let encoded = encode_proposer(&framed, &msg);
+        if let ProposerMessage::Greeting(greeting) = &msg {
+            /* Greeting is never framed, it negotiates framing */
+            assert_eq!(encoded, encode_proposer(&plain, &msg));
+            assert!(Codec::from_greeting(greeting).framed);
+            continue;
+        }
+        assert_eq!(&encoded[4..], &encode_proposer(&plain, &msg)[..]);
+        let len = u32::from_le_bytes([encoded[0], encoded[1], encoded[2], encoded[3]]);
+        assert_eq!(len as usize, encoded.len() - 4);
+
+        /* Fields unknown to the receiver at the end of the frame are skipped */
+        let mut extended = encoded.clone();
+        extended.extend_from_slice(&[1, 2, 3, 4, 5]);
+        let len = (extended.len() - 4) as u32;
+        extended[0..4].copy_from_slice(&len.to_le_bytes());
+        let decoded = framed
+            .decode_proposer(msg.kind(), &mut extended)
+            .unwrap()
+            .unwrap();
+        assert!(extended.is_empty());
+        assert_eq!(encode_proposer(&framed, &decoded), encoded);
+    }
+    for msg in acceptor_messages() {
+        let mut extended = encode_acceptor(&framed, &msg);
+        extended.extend_from_slice(&[0; 8]);
+        let len = (extended.len() - 4) as u32;
+        extended[0..4].copy_from_slice(&len.to_le_bytes());
+        let decoded = framed
+            .decode_acceptor(msg.kind(), &mut extended)
+            .unwrap()
+            .unwrap();
+        assert!(extended.is_empty());
+        assert_eq!(
+            encode_acceptor(&plain, &decoded),
+            encode_acceptor(&plain, &msg)
+        );
+    }
+}
+
+#[test]
+fn test_safekeeper_protocol_invalid() {
+    let plain = Codec {
+        version: SK_PROTOCOL_VERSION,
+        ..Codec::default()
+    };
+    let framed = Codec {
+        framed: true,
+        ..plain
+    };
+    let append = |begin_lsn, end_lsn| {
+        ProposerMessage::Append(AppendRequest {
+            header: request(begin_lsn, end_lsn),
+            wal: Bytes::new(),
+            checksum: None,
+        })
+    };
+
+    /* Proposer older than any supported version has no common version */
+    assert_eq!(
+        ProtocolVersions::negotiate(SK_MIN_PROTOCOL_VERSION - 1).selected_version,
+        0
+    );
+
+    /* End of stream carries no WAL whatever its end LSN is */
+    assert_eq!(request(END_OF_STREAM, 0x1000).wal_size().unwrap(), 0);
+    /* WAL going backwards or too large is rejected from the header alone */
+    let too_large = 0x1000 + MAX_SEND_SIZE as u64 + 1;
+    for &(begin_lsn, end_lsn) in &[(0x2000, 0x1000), (0x1000, too_large)] {
+        assert!(request(begin_lsn, end_lsn).wal_size().is_err());
+        let mut buf = encode_proposer(&plain, &append(begin_lsn, end_lsn));
+        assert!(plain
+            .decode_proposer(ProposerMessageKind::Append, &mut buf)
+            .is_err());
+    }
+
+    /* Frame not holding the WAL of its append */
+    let mut buf = encode_proposer(&framed, &append(0x1000, 0x1010));
+    assert!(framed
+        .decode_proposer(ProposerMessageKind::Append, &mut buf)
+        .is_err());
+
+    /* Frame shorter than the message or longer than any message */
+    for &len in &[RequestVote::SIZE - 1, MAX_FRAME_SIZE + 1] {
+        let mut buf = BytesMut::from(&(len as u32).to_le_bytes()[..]);
+        assert!(framed
+            .decode_proposer(ProposerMessageKind::RequestVote, &mut buf)
+            .is_err());
+    }
+    for &len in &[NodeId::SIZE - 1, MAX_FRAME_SIZE + 1] {
+        let mut buf = BytesMut::from(&(len as u32).to_le_bytes()[..]);
+        assert!(framed
+            .decode_acceptor(AcceptorMessageKind::Vote, &mut buf)
+            .is_err());
+    }
+}
+
+#[test]
+fn test_safekeeper_protocol_v1_response() {
+    let v1 = Codec {
+        version: 1,
+        ..Codec::default()
+    };
+    let msg = acceptor_messages().pop().unwrap();
+    let resp = msg.clone().into_response().unwrap();
+
+    /* Proposer of version 1 gets epoch, flush_lsn and hs_feedback only */
+    let encoded = encode_acceptor(&v1, &msg);
+    assert_eq!(encoded.len(), SafeKeeperResponse::V1_SIZE);
+    assert_eq!(
+        AcceptorMessageKind::Response.size(1),
+        SafeKeeperResponse::V1_SIZE
+    );
+    assert_eq!(&encoded[0..8], &resp.epoch.to_le_bytes()[..]);
+    assert_eq!(&encoded[8..16], &resp.flush_lsn.to_le_bytes()[..]);
+    assert_eq!(&encoded[16..24], &resp.hs_feedback.ts.to_le_bytes()[..]);
+
+    /* It is an acknowledgement of WAL up to flush_lsn */
+    let mut buf = encoded.clone();
+    let decoded = v1.decode_acceptor(msg.kind(), &mut buf).unwrap().unwrap();
+    let decoded = decoded.into_response().unwrap();
+    assert_eq!(decoded.status, SK_STATUS_OK);
+    assert_eq!(decoded.hs_replicas, 0);
+    assert_eq!(decoded.received_lsn, resp.flush_lsn);
+
+    /* Number of replicas contributing to the feedback is reported since version 2 */
+    let v2 = Codec {
+        version: 2,
+        ..Codec::default()
+    };
+    let no_replicas = AcceptorMessage::Response(SafeKeeperResponse {
+        hs_replicas: 0,
+        ..resp
+    });
+    assert_eq!(encode_acceptor(&v1, &no_replicas), encoded);
+    assert_ne!(
+        encode_acceptor(&v2, &no_replicas),
+        encode_acceptor(&v2, &msg)
+    );
+
+    /* Legacy proposers, which settle no version, get the same layout */
+    assert_eq!(encode_acceptor(&Codec::default(), &msg), encoded);
+    assert_eq!(
+        AcceptorMessageKind::Response.size(SK_PROTOCOL_VERSION),
+        SafeKeeperResponse::SIZE
+    );
+}
@@ -220,3 +220,44 @@ fn test_race_conditions() {
     storage_cplane.stop();
     failures_thread.join().unwrap();
 }
+
+// Tenant id is used to build paths in the wal_acceptor data directory
+#[test]
+fn test_tenant_id_validation() {
+    assert_eq!(walkeeper::parse_tenant_id("0").unwrap(), 0);
+    assert_eq!(walkeeper::parse_tenant_id("6937654338391347025").unwrap(), 6937654338391347025);
+    assert_eq!(walkeeper::parse_tenant_id("18446744073709551615").unwrap(), u64::MAX);
+
+    for bad in &[
+        "",
+        "..",
+        "../1",
+        "1/../../etc",
+        "/etc/passwd",
+        "1/2",
+        "1\\2",
+        "-1",
+        "+1",
+        " 1",
+        "1 ",
+        "01",
+        "00",
+        "1\0",
+        "0x10",
+        "18446744073709551616",
+        "１",
+    ] {
+        assert!(
+            walkeeper::parse_tenant_id(bad).is_err(),
+            "tenant id {:?} should be rejected",
+            bad
+        );
+    }
+
+    let data_dir = std::path::Path::new("/tmp/wal_acceptor");
+    let id = walkeeper::parse_tenant_id("42").unwrap();
+    assert_eq!(
+        walkeeper::tenant_dir(data_dir, id).parent().unwrap(),
+        data_dir
+    );
+}
@@ -0,0 +1,83 @@
+// Access lists of listeners: CIDR parsing and matching of IPv4, IPv6 and IPv4-mapped
+// peers, and deny taking precedence over allow.
+use std::net::IpAddr;
+use walkeeper::access_list::{AccessList, Cidr};
+
+fn cidr(s: &str) -> Cidr {
+    s.parse().unwrap()
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn test_cidr() {
+    let net = cidr("10.1.0.0/16");
+    assert!(net.contains(ip("10.1.255.7")));
+    assert!(!net.contains(ip("10.2.0.1")));
+    assert!(net.contains(ip("::ffff:10.1.0.1")));
+    assert_eq!(net.to_string(), "10.1.0.0/16");
+
+    /* Prefix not on byte boundary */
+    let net = cidr("192.168.4.0/22");
+    assert!(net.contains(ip("192.168.7.255")));
+    assert!(!net.contains(ip("192.168.8.0")));
+    assert!(!net.contains(ip("192.168.3.255")));
+
+    /* Bare address is the host only, /0 is everything of its family */
+    let host = cidr("127.0.0.1");
+    assert_eq!(host.to_string(), "127.0.0.1/32");
+    assert!(host.contains(ip("127.0.0.1")));
+    assert!(!host.contains(ip("127.0.0.2")));
+    assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+    assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+
+    let net = cidr("fd00::/8");
+    assert!(net.contains(ip("fd12:3456::1")));
+    assert!(!net.contains(ip("fe80::1")));
+    assert!(!net.contains(ip("10.0.0.1")));
+    assert!(cidr("::1/128").contains(ip("::1")));
+
+    for invalid in &[
+        "10.0.0.0/33",
+        "fd00::/129",
+        "10.0.0/8",
+        "10.0.0.0/",
+        "host/8",
+        "",
+    ] {
+        assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_access_list() {
+    let open = AccessList::default();
+    assert!(open.is_empty());
+    assert!(open.is_allowed(ip("198.51.100.1")));
+
+    let list = AccessList {
+        allow: vec![cidr("10.0.0.0/8"), cidr("fd00::/8")],
+        deny: vec![cidr("10.66.0.0/16")],
+    };
+    assert!(!list.is_empty());
+    assert!(list.is_allowed(ip("10.1.2.3")));
+    assert!(list.is_allowed(ip("::ffff:10.1.2.3")));
+    assert!(list.is_allowed(ip("fd00::5")));
+    assert!(!list.is_allowed(ip("10.66.1.1")));
+    assert!(!list.is_allowed(ip("::ffff:10.66.1.1")));
+    assert!(!list.is_allowed(ip("192.0.2.1")));
+    assert_eq!(
+        list.to_string(),
+        "allow=10.0.0.0/8,fd00::/8 deny=10.66.0.0/16"
+    );
+
+    /* Only denied peers are refused without allowed networks */
+    let deny_only = AccessList {
+        allow: Vec::new(),
+        deny: vec![cidr("192.0.2.0/24")],
+    };
+    assert!(!deny_only.is_allowed(ip("192.0.2.77")));
+    assert!(deny_only.is_allowed(ip("192.0.3.1")));
+}
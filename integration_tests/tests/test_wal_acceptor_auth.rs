@@ -0,0 +1,238 @@
+// Authentication of libpq clients: SCRAM-SHA-256 exchange and md5 checked against
+// known vectors, cleartext password refused over plain connections, and admin commands
+// refused to clients which haven't authenticated with a password.
+use std::env;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::runtime;
+use tokio::task;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{Client, NoTls, SimpleQueryMessage};
+use walkeeper::auth::{self, ScramExchange, ScramVerifier, Secret};
+use walkeeper::tenant_dir;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::{serve_connection, TenantRegistry};
+use walkeeper::WalAcceptorConf;
+
+const TRUSTING_TENANT: u64 = 706;
+const MD5_TENANT: u64 = 707;
+const PASSWORD_TENANT: u64 = 752;
+
+/* Example of RFC 7677: user "user" with password "pencil" */
+const SCRAM_SALT: &str = "W22ZaJ0SNY7soEsUEjb6gQ==";
+const SCRAM_CLIENT_FIRST: &str = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+const SCRAM_SERVER_NONCE: &str = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+const SCRAM_SERVER_FIRST: &str =
+    "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+const SCRAM_CLIENT_FINAL: &str = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                  p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+const SCRAM_SERVER_FINAL: &str = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+/* The same user and password with Postgres md5 method */
+const MD5_SECRET: &str = "md520c46e3762c864548e296b33c3406aa9";
+const MD5_SALT: [u8; 4] = [0x93, 0x0d, 0x1e, 0x5a];
+const MD5_RESPONSE: &[u8] = b"md553e66f00decf7b61a4b3811f3c665700\0";
+
+fn scram_secret() -> Secret {
+    Secret::Scram(ScramVerifier::new(
+        "pencil",
+        &base64::decode(SCRAM_SALT).unwrap(),
+        4096,
+    ))
+}
+
+// SASLInitialResponse body: mechanism, NUL, length and client-first-message
+fn sasl_initial_response(client_first: &str) -> Vec<u8> {
+    let mut message = auth::SCRAM_MECHANISM.as_bytes().to_vec();
+    message.push(0);
+    message.extend_from_slice(&(client_first.len() as u32).to_be_bytes());
+    message.extend_from_slice(client_first.as_bytes());
+    message
+}
+
+fn write_tenant_conf(conf: &WalAcceptorConf, id: u64, auth_method: &str) {
+    let dir = tenant_dir(&conf.data_dir, id);
+    fs::create_dir_all(&dir).unwrap();
+    let tenant_conf = format!(
+        "auth_method = \"{}\"\n[users]\nadmin = \"secret\"\n",
+        auth_method
+    );
+    fs::write(dir.join("tenant.toml"), tenant_conf).unwrap();
+}
+
+// Serve libpq connections of the safekeeper in background, returns its address
+async fn start_safekeeper(conf: WalAcceptorConf) -> SocketAddr {
+    let listener = TcpListener::bind(conf.listen_addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tenants = TenantRegistry::new();
+    task::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let conf = conf.clone();
+            let tenants = tenants.clone();
+            task::spawn(async move { serve_connection(socket, &conf, tenants).await });
+        }
+    });
+    addr
+}
+
+async fn connect(
+    addr: SocketAddr,
+    id: u64,
+    password: &str,
+) -> Result<Client, tokio_postgres::Error> {
+    let connstr = format!(
+        "host={} port={} dbname=no_db user=admin password={} options='-c system.id={}'",
+        addr.ip(),
+        addr.port(),
+        password,
+        id
+    );
+    let (client, connection) = tokio_postgres::connect(&connstr, NoTls).await?;
+    task::spawn(connection);
+    Ok(client)
+}
+
+// Columns of the only row returned by the command
+async fn query_row(client: &Client, query: &str) -> Vec<String> {
+    let messages = client.simple_query(query).await.unwrap();
+    let row = messages
+        .iter()
+        .find_map(|message| match message {
+            SimpleQueryMessage::Row(row) => Some(row),
+            _ => None,
+        })
+        .unwrap();
+    (0..row.len())
+        .map(|i| row.get(i).unwrap_or("").to_string())
+        .collect()
+}
+
+#[test]
+fn test_admin_commands_require_authentication() {
+    let dir = env::temp_dir().join(format!("test_admin_auth_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    write_tenant_conf(&conf, MD5_TENANT, "md5");
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async move {
+        let addr = start_safekeeper(conf).await;
+
+        /* Any password is accepted by the trusting tenant, but proves nothing */
+        for command in &[
+            "PAUSE_WAL",
+            "RESUME_WAL",
+            "SAFEKEEPER_STATUS",
+            "PAGESERVER_CHECKPOINT 0/16B3748",
+            "SAFEKEEPER_WAL_STATS",
+            "SAFEKEEPER_WAL_HASH 0/16B3748 0/16B3748 8192",
+        ] {
+            let client = connect(addr, TRUSTING_TENANT, "any").await.unwrap();
+            let e = client.simple_query(command).await.unwrap_err();
+            assert_eq!(e.code(), Some(&SqlState::INSUFFICIENT_PRIVILEGE), "{}", e);
+        }
+        /* Protocol commands are still served */
+        let client = connect(addr, TRUSTING_TENANT, "any").await.unwrap();
+        let identify = query_row(&client, "SAFEKEEPER_IDENTIFY").await;
+        assert_eq!(identify[0], TRUSTING_TENANT.to_string());
+        assert_eq!(identify.len(), 10);
+
+        let client = connect(addr, MD5_TENANT, "secret").await.unwrap();
+        client.simple_query("PAUSE_WAL").await.unwrap();
+        /* systemid, priority, epoch, flush_lsn, commit_lsn, remote_consistent_lsn, paused */
+        let status = query_row(&client, "SAFEKEEPER_STATUS").await;
+        assert_eq!(status[6], "true");
+        client.simple_query("RESUME_WAL").await.unwrap();
+        let status = query_row(&client, "SAFEKEEPER_STATUS").await;
+        assert_eq!(status[6], "false");
+        client
+            .simple_query("PAGESERVER_CHECKPOINT 0/16B3748")
+            .await
+            .unwrap();
+        let status = query_row(&client, "SAFEKEEPER_STATUS").await;
+        assert_eq!(status[5], "0/16B3748");
+    });
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_scram_vectors() {
+    let secret = scram_secret();
+    let initial = sasl_initial_response(SCRAM_CLIENT_FIRST);
+    let exchange =
+        ScramExchange::start_with_nonce(&secret, "user", &initial, SCRAM_SERVER_NONCE).unwrap();
+    assert_eq!(exchange.server_first(), SCRAM_SERVER_FIRST.as_bytes());
+    let server_final = exchange
+        .finish("user", SCRAM_CLIENT_FINAL.as_bytes())
+        .unwrap();
+    assert_eq!(server_final, SCRAM_SERVER_FINAL.as_bytes());
+
+    /* Wrong proof, nonce or channel binding */
+    let wrong_proof = SCRAM_CLIENT_FINAL.replace("p=dHzb", "p=dHzc");
+    let wrong_nonce = SCRAM_CLIENT_FINAL.replace("hNlF$k0", "hNlF$k1");
+    let wrong_binding = SCRAM_CLIENT_FINAL.replace("c=biws", "c=eSws");
+    for client_final in &[wrong_proof, wrong_nonce, wrong_binding] {
+        let e = exchange
+            .finish("user", client_final.as_bytes())
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    /* Only SCRAM-SHA-256 without channel binding is supported */
+    let mut plus = b"SCRAM-SHA-256-PLUS".to_vec();
+    plus.extend_from_slice(&initial[auth::SCRAM_MECHANISM.len()..]);
+    assert!(ScramExchange::start(&secret, "user", &plus).is_err());
+    let binding = sasl_initial_response("p=tls-server-end-point,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+    assert!(ScramExchange::start(&secret, "user", &binding).is_err());
+    let md5 = Secret::parse(MD5_SECRET);
+    assert!(ScramExchange::start(&md5, "user", &initial).is_err());
+}
+
+#[test]
+fn test_password_checks() {
+    let md5 = Secret::parse(MD5_SECRET);
+    assert_eq!(md5, Secret::Md5(MD5_SECRET[3..].to_string()));
+    let plain = Secret::parse("pencil");
+    for secret in &[&md5, &plain] {
+        auth::check_md5(secret, "user", &MD5_SALT, MD5_RESPONSE).unwrap();
+        assert!(auth::check_md5(secret, "user", &[0, 0, 0, 0], MD5_RESPONSE).is_err());
+    }
+    /* md5 of plain secret is salted with the user name */
+    assert!(auth::check_md5(&plain, "other", &MD5_SALT, MD5_RESPONSE).is_err());
+    assert!(auth::check_md5(&scram_secret(), "user", &MD5_SALT, MD5_RESPONSE).is_err());
+
+    for secret in &[md5, plain, scram_secret()] {
+        auth::check_password(&secret, "user", b"pencil\0").unwrap();
+        assert!(auth::check_password(&secret, "user", b"pen\0").is_err());
+    }
+}
+
+#[test]
+fn test_cleartext_password_requires_tls() {
+    let dir = env::temp_dir().join(format!("test_password_tls_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    write_tenant_conf(&conf, PASSWORD_TENANT, "password");
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async move {
+        let addr = start_safekeeper(conf).await;
+        let e = connect(addr, PASSWORD_TENANT, "secret")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.code(),
+            Some(&SqlState::INVALID_AUTHORIZATION_SPECIFICATION),
+            "{}",
+            e
+        );
+    });
+    fs::remove_dir_all(&dir).unwrap();
+}
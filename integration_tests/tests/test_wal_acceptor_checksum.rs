@@ -0,0 +1,74 @@
+// End-to-end WAL checksum: continued across appends and segments, recomputed from stored
+// WAL after reconnect, recorded for completed segments and trimmed with removed segments.
+use std::fs;
+use walkeeper::partial_segment::SegmentPath;
+use walkeeper::wal_checksum::{ChecksumIndex, RollingChecksum, WAL_CHECKSUMS_FILE_NAME};
+use walkeeper::xlog_utils::XLogFileName;
+
+const WAL_SEG_SIZE: usize = 1024 * 1024;
+
+#[test]
+fn test_wal_checksum_rolling() {
+    let dir = std::env::temp_dir().join(format!("test_wal_checksum_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let wal: Vec<u8> = (0..WAL_SEG_SIZE + 100).map(|i| (i % 251) as u8).collect();
+    let start = WAL_SEG_SIZE as u64;
+
+    let mut checksum = RollingChecksum::load(&dir, None, 1, start, WAL_SEG_SIZE).unwrap();
+    assert!(checksum.feed(&wal[..1000], 1, WAL_SEG_SIZE).is_empty());
+    let completed = checksum.feed(&wal[1000..], 1, WAL_SEG_SIZE);
+    assert_eq!(completed.len(), 1);
+
+    /* Split of WAL into appends doesn't matter */
+    let mut whole = RollingChecksum::load(&dir, None, 1, start, WAL_SEG_SIZE).unwrap();
+    assert_eq!(whole.feed(&wal, 1, WAL_SEG_SIZE), completed);
+    assert_eq!(whole.crc, checksum.crc);
+
+    /* Checksum of the stored prefix of the segment is the same */
+    let segment = SegmentPath::new(&dir, 1, 2, WAL_SEG_SIZE);
+    fs::write(&segment.partial, &wal[WAL_SEG_SIZE..]).unwrap();
+    let loaded = RollingChecksum::load(&dir, None, 1, checksum.end_lsn, WAL_SEG_SIZE).unwrap();
+    assert_eq!(loaded.crc, checksum.crc);
+
+    /* The last record of the segment wins, in the index and in the file */
+    let mut index = ChecksumIndex::default();
+    index.record(&dir, &completed, true).unwrap();
+    assert_eq!(
+        index.lookup(&dir, &completed[0].0).unwrap(),
+        Some(completed[0].1)
+    );
+    index
+        .record(&dir, &[(completed[0].0.clone(), 42)], true)
+        .unwrap();
+    assert_eq!(index.lookup(&dir, &completed[0].0).unwrap(), Some(42));
+    assert_eq!(index.lookup(&dir, &segment.name).unwrap(), None);
+    let mut reread = ChecksumIndex::default();
+    assert_eq!(reread.lookup(&dir, &completed[0].0).unwrap(), Some(42));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_checksum_trim() {
+    let dir = std::env::temp_dir().join(format!("test_wal_checksum_trim_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let checksums: Vec<(String, u32)> = (1..5)
+        .map(|segno| (XLogFileName(1, segno, WAL_SEG_SIZE), segno as u32))
+        .collect();
+    let mut index = ChecksumIndex::default();
+    index.record(&dir, &checksums, true).unwrap();
+    index.record(&dir, &checksums[3..], true).unwrap();
+
+    /* Segments below the horizon are forgotten, also by the file */
+    assert_eq!(index.trim(&dir, 3, WAL_SEG_SIZE, true).unwrap(), 2);
+    assert_eq!(index.trim(&dir, 3, WAL_SEG_SIZE, true).unwrap(), 0);
+    let mut reread = ChecksumIndex::default();
+    for (segment, crc) in &checksums {
+        let expected = if *crc < 3 { None } else { Some(*crc) };
+        assert_eq!(index.lookup(&dir, segment).unwrap(), expected);
+        assert_eq!(reread.lookup(&dir, segment).unwrap(), expected);
+    }
+    let content = fs::read_to_string(dir.join(WAL_CHECKSUMS_FILE_NAME)).unwrap();
+    assert_eq!(content.lines().count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
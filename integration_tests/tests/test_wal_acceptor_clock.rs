@@ -0,0 +1,36 @@
+// Check that with tokio's time paused the safekeeper clock is simulated: it moves
+// only when tokio time is advanced, and sleeps complete without real waiting.
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use walkeeper::clock::{self, SystemClock, TokioClock};
+
+#[test]
+fn test_wal_acceptor_clock() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        tokio::time::pause();
+        let start_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        clock::set_clock(Arc::new(TokioClock::new(start_time)));
+
+        let start = clock::now();
+        let timestamp = clock::timestamp();
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        assert_eq!(clock::elapsed(start), Duration::from_secs(3600));
+        assert_eq!(clock::timestamp() - timestamp, 3600 * 1_000_000);
+
+        /* Idle runtime jumps to the next timer */
+        let real_start = Instant::now();
+        clock::sleep(Duration::from_secs(24 * 3600)).await;
+        assert!(clock::elapsed(start) >= Duration::from_secs(25 * 3600));
+        assert!(real_start.elapsed() < Duration::from_secs(10));
+        assert!(
+            clock::timeout(Duration::from_secs(60), std::future::pending::<()>())
+                .await
+                .is_err()
+        );
+    });
+    clock::set_clock(Arc::new(SystemClock));
+}
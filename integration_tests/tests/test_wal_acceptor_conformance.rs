@@ -0,0 +1,10 @@
+// Check message encodings of the safekeeper protocols against the conformance
+// vectors and play the scripted proposer sessions against the safekeeper.
+use walkeeper::wal_service::conformance;
+
+#[test]
+fn test_wal_acceptor_conformance() {
+    if let Err(e) = conformance::run() {
+        panic!("Conformance check failed: {}", e);
+    }
+}
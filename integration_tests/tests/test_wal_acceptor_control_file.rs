@@ -0,0 +1,169 @@
+// Control file formats: files of older formats are parsed and upgraded in place, the
+// current one can be downgraded for rollback, and files which can't be parsed (newer
+// format, damaged header, missing trailers) are refused rather than overwritten.
+use bytes::BytesMut;
+use std::env;
+use std::fs;
+use std::path::Path;
+use walkeeper::safekeeper_protocol::SafeKeeperInfo;
+use walkeeper::tenant_dir;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::wal_service::{
+    check_tenants, convert_control_files, CONTROL_FILE_NAME, CONTROL_SLOT_SIZE,
+};
+
+fn info(content: &[u8]) -> SafeKeeperInfo {
+    SafeKeeperInfo::unpack(&mut BytesMut::from(content))
+}
+
+fn with_version(content: &[u8], format_version: u32) -> Vec<u8> {
+    let mut content = content.to_vec();
+    content[4..8].copy_from_slice(&format_version.to_le_bytes());
+    content
+}
+
+// Format version and positions of both copies of the current format
+fn check_upgraded(path: &Path, expected: &SafeKeeperInfo) {
+    let content = fs::read(path).unwrap();
+    assert!(content.len() > CONTROL_SLOT_SIZE);
+    for copy in &[&content[..CONTROL_SLOT_SIZE], &content[CONTROL_SLOT_SIZE..]] {
+        let copy = info(copy);
+        assert_eq!(copy.format_version, 3);
+        assert_eq!(copy.epoch, expected.epoch);
+        assert_eq!(copy.flush_lsn, expected.flush_lsn);
+        assert_eq!(copy.commit_lsn, expected.commit_lsn);
+        assert_eq!(copy.server, expected.server);
+    }
+}
+
+#[test]
+fn test_control_file_formats() {
+    let dir = env::temp_dir().join(format!("test_control_file_formats_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    let mut old_conf = conf.clone();
+    old_conf.control_file_version = Some(2);
+    let mut session = TestSession::start(old_conf.clone(), 762).unwrap();
+    let start = session.start_lsn();
+    let end = start + 5000;
+    session.stream(end, start, start + 3000).unwrap();
+    let path = tenant_dir(&conf.data_dir, session.system_id()).join(CONTROL_FILE_NAME);
+    drop(session);
+
+    /* Format of the previous release has the only copy */
+    let v2 = fs::read(&path).unwrap();
+    assert!(v2.len() < CONTROL_SLOT_SIZE);
+    let expected = info(&v2);
+    assert_eq!(expected.format_version, 2);
+    assert_eq!(expected.flush_lsn, end);
+
+    /* Version 1 has trailers only if written by a version knowing them */
+    let bare_v1 = with_version(&v2[..SafeKeeperInfo::SIZE], 1);
+    for content in &[v2.clone(), with_version(&v2, 1), bare_v1.clone()] {
+        fs::write(&path, content).unwrap();
+        assert!(check_tenants(&conf).1.is_empty());
+        assert_eq!(convert_control_files(&conf).unwrap(), 1);
+        check_upgraded(&path, &expected);
+        assert_eq!(convert_control_files(&conf).unwrap(), 0);
+        assert!(check_tenants(&conf).1.is_empty());
+    }
+
+    /* Downgrade before rollback */
+    assert_eq!(convert_control_files(&old_conf).unwrap(), 1);
+    let downgraded = fs::read(&path).unwrap();
+    assert!(downgraded.len() < CONTROL_SLOT_SIZE);
+    assert_eq!(info(&downgraded).format_version, 2);
+    assert_eq!(info(&downgraded).flush_lsn, end);
+
+    /* Tenant loaded with an old control file upgrades it */
+    fs::write(&path, &bare_v1).unwrap();
+    let mut session = TestSession::start(conf.clone(), 762).unwrap();
+    session.stream(end + 1000, start, start + 3000).unwrap();
+    drop(session);
+    let content = fs::read(&path).unwrap();
+    assert_eq!(info(&content[CONTROL_SLOT_SIZE..]).format_version, 3);
+
+    for (content, error) in &[
+        (with_version(&v2, 4), "newer than 3"),
+        (
+            with_version(&v2, 0),
+            "unknown control file format version 0",
+        ),
+        (v2[..SafeKeeperInfo::SIZE].to_vec(), "has no archived LSN"),
+        (
+            [&[0u8; 4][..], &v2[4..]].concat(),
+            "invalid control file magic",
+        ),
+        (v2[..20].to_vec(), "truncated to 20 bytes"),
+    ] {
+        fs::write(&path, content).unwrap();
+        let problems = check_tenants(&conf).1;
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains(error), "{}", problems[0]);
+        let e = convert_control_files(&conf).unwrap_err();
+        assert!(e.to_string().contains(error), "{}", e);
+        assert_eq!(&fs::read(&path).unwrap(), content);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Control file of the current format with the given copies in its slots
+fn slotted(slot0: &[u8], slot1: &[u8]) -> Vec<u8> {
+    let mut content = slot0[..slot0.len().min(CONTROL_SLOT_SIZE)].to_vec();
+    content.resize(CONTROL_SLOT_SIZE, 0);
+    content.extend_from_slice(slot1);
+    content
+}
+
+#[test]
+fn test_control_file_slots() {
+    let dir = env::temp_dir().join(format!("test_control_file_slots_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    let mut session = TestSession::start(conf.clone(), 763).unwrap();
+    let start = session.start_lsn();
+    let path = tenant_dir(&conf.data_dir, session.system_id()).join(CONTROL_FILE_NAME);
+    /* Copies of two states, the later one is of a newer generation */
+    let mut copy_of = |end| {
+        session.stream(end, start, start).unwrap();
+        let content = fs::read(&path).unwrap();
+        content
+            .chunks(CONTROL_SLOT_SIZE)
+            .find(|copy| info(copy).flush_lsn == end)
+            .unwrap()
+            .to_vec()
+    };
+    let (old_end, new_end) = (start + 1000, start + 2000);
+    let old = copy_of(old_end);
+    let new = copy_of(new_end);
+    drop(session);
+
+    /* Copy picked by the loader, as downgrade to the format with the only copy shows */
+    let mut old_conf = conf.clone();
+    old_conf.control_file_version = Some(2);
+    let picked = |content: Vec<u8>| {
+        fs::write(&path, content).unwrap();
+        convert_control_files(&old_conf).map(|_| info(&fs::read(&path).unwrap()).flush_lsn)
+    };
+    assert_eq!(picked(slotted(&new, &old)).unwrap(), new_end);
+    assert_eq!(picked(slotted(&old, &new)).unwrap(), new_end);
+
+    /* Copy damaged by a torn write falls back to the other one */
+    let mut damaged = new.clone();
+    damaged[8] ^= 0xFF; /* epoch */
+    assert_eq!(picked(slotted(&damaged, &old)).unwrap(), old_end);
+    assert_eq!(picked(slotted(&old, &damaged)).unwrap(), old_end);
+    let torn = [&new[..100], &[0u8; 100][..]].concat();
+    assert_eq!(picked(slotted(&old, &torn)).unwrap(), old_end);
+    /* Slot not written yet */
+    assert_eq!(picked(slotted(&old, &[0u8; 100])).unwrap(), old_end);
+    assert_eq!(picked(old.clone()).unwrap(), old_end);
+
+    /* No valid copy, or a copy of a newer format, which is not skipped */
+    let e = picked(slotted(&damaged, &torn)).unwrap_err();
+    assert!(e.to_string().contains("wrong checksum"), "{}", e);
+    let e = picked(slotted(&with_version(&new, 4), &old)).unwrap_err();
+    assert!(e.to_string().contains("newer than 3"), "{}", e);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,24 @@
+// Crash safekeeper at random writes and fsyncs of the append workload and check
+// that after restart no acknowledged WAL is lost and no corrupted state is served.
+use walkeeper::wal_service::crash_test;
+
+const SEEDS: u64 = 8;
+const ROUNDS: usize = 20;
+
+#[test]
+fn test_wal_acceptor_crash_recovery() {
+    for seed in 0..SEEDS {
+        if let Err(e) = crash_test::run(seed, ROUNDS) {
+            panic!("Crash test with seed {} failed: {}", seed, e);
+        }
+    }
+}
+
+#[test]
+fn test_wal_acceptor_crash_recovery_group_commit() {
+    for seed in 0..SEEDS {
+        if let Err(e) = crash_test::run_group_commit(seed, ROUNDS) {
+            panic!("Group commit crash test with seed {} failed: {}", seed, e);
+        }
+    }
+}
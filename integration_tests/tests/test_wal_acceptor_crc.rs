@@ -0,0 +1,54 @@
+// CRC check of received WAL records: valid WAL passes however it is split into messages,
+// and a corrupt record is reported by the message completing it, at the record start.
+use std::env;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::xlog_utils::*;
+
+#[test]
+fn test_wal_crc_check() {
+    let dir = env::temp_dir().join(format!("test_wal_crc_{}", std::process::id()));
+    let session = TestSession::start(test_conf(&dir), 765).unwrap();
+    let seg = session.wal_seg_size();
+    let start = session.start_lsn();
+    let end = start + seg as u64 + 5000;
+    let wal = session.wal(start, end);
+
+    /* Records split between messages, across pages and segments */
+    for &chunk in &[3, 100, 1000, 8195, 65536] {
+        let mut scanner = WalRecordScanner::new(seg, true);
+        let mut records = 0;
+        for (i, part) in wal.chunks(chunk).enumerate() {
+            let lsn = start + (i * chunk) as u64;
+            assert_eq!(scanner.feed(lsn, part, |_, _| records += 1), None);
+        }
+        assert!(records > 0);
+    }
+
+    /* Corrupt body of a record, which is at least 32 bytes long */
+    let rec_lsn = session.record_start(start + 5000);
+    let mut corrupt = wal.to_vec();
+    corrupt[(rec_lsn - start) as usize + 28] ^= 0xFF;
+    let split = (rec_lsn - start) as usize + 26;
+    let mut scanner = WalRecordScanner::new(seg, true);
+    assert_eq!(scanner.feed(start, &corrupt[..split], |_, _| {}), None);
+    let lsn = start + split as u64;
+    assert_eq!(
+        scanner.feed(lsn, &corrupt[split..], |_, _| {}),
+        Some(rec_lsn)
+    );
+
+    /* Reported at once if the record is complete in the message */
+    let mut scanner = WalRecordScanner::new(seg, true);
+    assert_eq!(scanner.feed(start, &corrupt, |_, _| {}), Some(rec_lsn));
+
+    /* Corrupt header fails the check as well */
+    let mut corrupt = wal.to_vec();
+    corrupt[(rec_lsn - start) as usize + 8] ^= 0xFF; /* xl_prev */
+    let mut scanner = WalRecordScanner::new(seg, true);
+    assert_eq!(scanner.feed(start, &corrupt, |_, _| {}), Some(rec_lsn));
+
+    /* Unless the check is disabled */
+    let mut scanner = WalRecordScanner::new(seg, false);
+    assert_eq!(scanner.feed(start, &corrupt, |_, _| {}), None);
+}
@@ -0,0 +1,27 @@
+// Drain stops WAL senders without touching the quorum commit position, which is
+// still reported and used by the final flush after the drain.
+use std::env;
+use std::fs;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+
+#[test]
+fn test_wal_acceptor_drain_keeps_commit_lsn() {
+    let dir = env::temp_dir().join(format!("test_wal_drain_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let conf = test_conf(&dir);
+    let mut session = TestSession::start(conf.clone(), 2).unwrap();
+    let start = session.start_lsn();
+    let commit_lsn = start + session.wal_seg_size() as u64;
+    session.stream(session.end_lsn(), start, commit_lsn).unwrap();
+    let system = session.system().unwrap();
+    assert_eq!(system.get_commit_lsn(), commit_lsn);
+
+    assert_eq!(session.tenants().drain(), 1);
+    assert_eq!(system.get_commit_lsn(), commit_lsn);
+    let flush = system.final_flush(&conf);
+    assert!(!flush.is_failed(), "{}", flush.describe());
+    assert_eq!(system.snapshot().commit_lsn, commit_lsn);
+    session.tenants().unload_all();
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,66 @@
+// Start embedded safekeeper, check that it accepts connections and shut it down, twice
+// in the same data directory: the second instance must not trip over the first one.
+use std::io::{self, Read};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use walkeeper::admin::{admin_client, ADMIN_SOCKET_NAME};
+use walkeeper::embed::WalAcceptor;
+use walkeeper::wal_service::crash_test;
+
+#[test]
+fn test_wal_acceptor_embedded_restart() {
+    let data_dir =
+        std::env::temp_dir().join(format!("test_wal_acceptor_embedded_{}", std::process::id()));
+    for _ in 0..2 {
+        let mut wal_acceptor = WalAcceptor::builder()
+            .conf(crash_test::test_conf(&data_dir))
+            .spawn()
+            .unwrap();
+        let addr = wal_acceptor.wait_ready().unwrap();
+        TcpStream::connect(addr).unwrap();
+        assert!(wal_acceptor.tenant(1).is_none());
+        wal_acceptor.shutdown().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+// Move the WAL service to another address at runtime: connections accepted at the old
+// address live on, and a failing bind leaves the listeners as they were
+#[test]
+fn test_wal_acceptor_embedded_listen() {
+    let data_dir = std::env::temp_dir().join(format!(
+        "test_wal_acceptor_embedded_listen_{}",
+        std::process::id()
+    ));
+    let mut wal_acceptor = WalAcceptor::builder()
+        .conf(crash_test::test_conf(&data_dir))
+        .spawn()
+        .unwrap();
+    let addr = wal_acceptor.wait_ready().unwrap();
+    let mut accepted = TcpStream::connect(addr).unwrap();
+    while !data_dir.join(ADMIN_SOCKET_NAME).exists() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let new_addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    admin_client(&data_dir, Some(format!("listen {}", new_addr))).unwrap();
+    TcpStream::connect(new_addr).unwrap();
+    assert!(TcpStream::connect(addr).is_err());
+    accepted
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let err = accepted.read(&mut [0u8; 1]).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    ));
+
+    let cmd = format!("listen {},192.0.2.1:5454", new_addr);
+    assert!(admin_client(&data_dir, Some(cmd)).is_err());
+    TcpStream::connect(new_addr).unwrap();
+    wal_acceptor.shutdown().unwrap();
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
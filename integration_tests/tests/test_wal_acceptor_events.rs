@@ -0,0 +1,19 @@
+// Check that the safekeeper reports its progress on the event bus, which tests
+// use to wait for its state instead of sleeping.
+use std::time::Duration;
+use walkeeper::events::{self, Event};
+use walkeeper::wal_service::conformance;
+
+#[test]
+fn test_wal_acceptor_events() {
+    let subscription = events::subscribe();
+    if let Err(e) = conformance::run() {
+        panic!("Conformance check failed: {}", e);
+    }
+    /* Vote for term 2 in the conformance sessions is persisted with fsync */
+    let synced = subscription.wait_for(Duration::from_secs(10), |event| match event {
+        Event::ControlFileSynced { system_id, .. } => *system_id == conformance::SYSTEM_ID,
+        _ => false,
+    });
+    assert!(synced.is_some(), "control file sync of the test tenant is not reported");
+}
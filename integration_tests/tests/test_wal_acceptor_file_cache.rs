@@ -0,0 +1,76 @@
+// Open segment files of the WAL writer: the cache keeps the most recently used ones, and
+// files of a deleted tenant are closed rather than kept open after removal.
+use std::env;
+use std::fs::{self, File};
+use std::path::Path;
+use walkeeper::tenant_dir;
+use walkeeper::wal_file_cache::{WalFileCache, WAL_FILE_CACHE_SIZE};
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::delete_tenant;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::xlog_utils::*;
+
+// Files open by the process under the directory
+fn open_files(dir: &Path) -> Vec<String> {
+    let dir = dir.to_string_lossy().into_owned();
+    fs::read_dir("/proc/self/fd")
+        .unwrap()
+        .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+        .map(|target| target.to_string_lossy().into_owned())
+        .filter(|target| target.starts_with(&dir))
+        .collect()
+}
+
+#[test]
+fn test_wal_file_cache_lru() {
+    let dir = env::temp_dir().join(format!("test_wal_file_cache_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let file = |segno: XLogSegNo| File::create(dir.join(segno.to_string())).unwrap();
+    let mut cache = WalFileCache::default();
+    let n = WAL_FILE_CACHE_SIZE as XLogSegNo;
+    for segno in 0..=n {
+        cache.put(1, segno, file(segno), segno == n);
+    }
+    /* The least recently used one is closed */
+    assert!(cache.take(1, 0).is_none());
+    assert_eq!(open_files(&dir).len(), WAL_FILE_CACHE_SIZE);
+    let (taken, partial) = cache.take(1, n).unwrap();
+    assert!(partial);
+    assert!(cache.take(1, n).is_none());
+    assert!(cache.take(2, 1).is_none());
+
+    /* Put back, it is the most recent one again, and 1 is the least recent */
+    cache.put(1, n, taken, true);
+    cache.put(1, n + 1, file(n + 1), true);
+    assert!(cache.take(1, 1).is_none());
+    assert!(cache.take(1, 2).is_some());
+    cache.remove(1, n);
+    assert!(cache.take(1, n).is_none());
+    cache.clear();
+    assert!(open_files(&dir).is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_deleted_tenant_files_are_closed() {
+    let dir = env::temp_dir().join(format!("test_wal_file_cache_delete_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    let mut session = TestSession::start(conf.clone(), 777).unwrap();
+    let start = session.start_lsn();
+    session.stream(start + 1000, start, start + 1000).unwrap();
+    let system_dir = tenant_dir(&conf.data_dir, session.system_id());
+    /* The segment being written is kept open */
+    assert!(!open_files(&system_dir).is_empty());
+
+    delete_tenant(&conf, &session.tenants(), session.system_id(), "test").unwrap();
+    assert!(!system_dir.exists());
+    assert!(
+        open_files(&system_dir).is_empty(),
+        "{:?}",
+        open_files(&system_dir)
+    );
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,165 @@
+// WAL GC horizon: WAL still needed by the proposer (above restart_lsn) is kept even
+// when it is archived, and only committed WAL is archived. WAL not checkpointed by the
+// pageserver is kept too. Segments below the confirmed cutoff are removed by the GC task.
+use std::env;
+use std::fs;
+use std::time::Duration;
+use tokio::time::sleep;
+use walkeeper::gc_coordination::{GcProposal, GcState};
+use walkeeper::object_storage::BucketConf;
+use walkeeper::partial_segment::SegmentPath;
+use walkeeper::tenant_dir;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::wal_service::System;
+use walkeeper::xlog_utils::*;
+
+#[test]
+fn test_wal_gc_horizon_with_archive() {
+    let dir = env::temp_dir().join(format!("test_wal_gc_archive_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    /* Bucket is never reached: the archiver runs only while the test waits for sessions */
+    for (name, value) in &[
+        ("S3_REGION", "test"),
+        ("S3_ENDPOINT", "http://127.0.0.1:9"),
+        ("S3_ACCESSKEY", "test"),
+        ("S3_SECRET", "test"),
+    ] {
+        env::set_var(name, value);
+    }
+    let mut conf = test_conf(&dir);
+    conf.archive = Some(BucketConf::new("wal", "archive").unwrap());
+    let mut session = TestSession::start(conf.clone(), 1).unwrap();
+    let seg = session.wal_seg_size() as u64;
+    let start = session.start_lsn();
+    let end = session.end_lsn();
+
+    /* Restart in the second segment of WAL, commit in the third one */
+    let restart_lsn = start + seg + 100;
+    let commit_lsn = start + 2 * seg + 100;
+    assert_eq!(session.stream(end, restart_lsn, commit_lsn).unwrap(), end);
+    let system = session.system().unwrap();
+    let (_, archive_end, archived_lsn) = system.archive_position().unwrap();
+    assert_eq!(archive_end, commit_lsn);
+    assert_eq!(archived_lsn, 0);
+
+    /* Nothing is archived yet */
+    let proposal = system.propose_gc(&conf, end, "test").unwrap();
+    assert_eq!(proposal.limited_by, "archived_lsn");
+    assert_eq!(proposal.cutoff_lsn, 0);
+
+    /* Archived WAL above restart_lsn is kept */
+    system.set_archived_lsn(start + 2 * seg).unwrap();
+    let proposal = system.propose_gc(&conf, end, "test").unwrap();
+    assert_eq!(proposal.limited_by, "restart_lsn");
+    assert_eq!(proposal.cutoff_lsn, start + seg);
+
+    /* Once proposer restarts above it, GC is bounded by the archive */
+    let restart_lsn = start + 3 * seg + 100;
+    session.stream(end, restart_lsn, end).unwrap();
+    let proposal = system.propose_gc(&conf, end, "test").unwrap();
+    assert_eq!(proposal.limited_by, "archived_lsn");
+    assert_eq!(proposal.cutoff_lsn, start + 2 * seg);
+    let (_, archive_end, _) = system.archive_position().unwrap();
+    assert_eq!(archive_end, end);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Wait for the proposed removal to complete
+fn wait_gc(session: &TestSession, system: &System) -> GcProposal {
+    session.block_on(async {
+        loop {
+            let proposal = system.gc_proposal().unwrap();
+            if proposal.state == GcState::Done || proposal.state == GcState::Failed {
+                return proposal;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+}
+
+#[test]
+fn test_wal_gc_removes_segments() {
+    let dir = env::temp_dir().join(format!("test_wal_gc_remove_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut conf = test_conf(&dir);
+    /* Regular GC doesn't remove anything before the proposal */
+    conf.gc_coordinated = true;
+    let mut session = TestSession::start(conf.clone(), 757).unwrap();
+    let seg = session.wal_seg_size();
+    let start = session.start_lsn();
+    let end = session.end_lsn();
+    let restart_lsn = start + 2 * seg as u64 + 100;
+    session.stream(end, restart_lsn, end).unwrap();
+    let system = session.system().unwrap();
+    let system_dir = tenant_dir(&conf.data_dir, session.system_id());
+    let segment = |segno| SegmentPath::new(&system_dir, session.timeline(), segno, seg).complete;
+    let first_segno = XLByteToSeg(start, seg);
+    let horizon_segno = XLByteToSeg(restart_lsn, seg);
+    for segno in first_segno..=horizon_segno {
+        assert!(segment(segno).exists());
+    }
+
+    /* Proposal beyond restart_lsn is held back, segments below it are removed */
+    let proposal = system.propose_gc(&conf, end, "test").unwrap();
+    assert_eq!(proposal.limited_by, "restart_lsn");
+    assert_eq!(
+        proposal.cutoff_lsn,
+        XLogSegNoOffsetToRecPtr(horizon_segno, 0, seg)
+    );
+    let proposal = wait_gc(&session, &system);
+    assert_eq!(proposal.error, None);
+    let removed = (horizon_segno - first_segno) as usize;
+    assert_eq!(proposal.segments_total, removed);
+    assert_eq!(proposal.segments_removed, removed);
+    for segno in first_segno..horizon_segno {
+        assert!(!segment(segno).exists());
+    }
+    assert!(segment(horizon_segno).exists());
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_gc_held_by_pageserver_checkpoint() {
+    let dir = env::temp_dir().join(format!("test_wal_gc_checkpoint_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut conf = test_conf(&dir);
+    conf.gc_coordinated = true;
+    let mut session = TestSession::start(conf.clone(), 715).unwrap();
+    let seg = session.wal_seg_size();
+    let start = session.start_lsn();
+    let end = session.end_lsn();
+    let restart_lsn = start + 3 * seg as u64 + 100;
+    session.stream(end, restart_lsn, end).unwrap();
+    let system = session.system().unwrap();
+    let system_dir = tenant_dir(&conf.data_dir, session.system_id());
+    let segment = |segno| SegmentPath::new(&system_dir, session.timeline(), segno, seg).complete;
+
+    /* WAL above the checkpoint reported by pageserver is retained, even below restart_lsn */
+    let checkpoint_lsn = start + seg as u64 + 100;
+    system
+        .set_remote_consistent_lsn(&conf, checkpoint_lsn)
+        .unwrap();
+    let proposal = system.propose_gc(&conf, end, "test").unwrap();
+    assert_eq!(proposal.limited_by, "remote_consistent_lsn");
+    let checkpoint_segno = XLByteToSeg(checkpoint_lsn, seg);
+    assert_eq!(
+        proposal.cutoff_lsn,
+        XLogSegNoOffsetToRecPtr(checkpoint_segno, 0, seg)
+    );
+    assert_eq!(wait_gc(&session, &system).error, None);
+    for segno in XLByteToSeg(start, seg)..checkpoint_segno {
+        assert!(!segment(segno).exists());
+    }
+    for segno in checkpoint_segno..=XLByteToSeg(restart_lsn, seg) {
+        assert!(segment(segno).exists());
+    }
+
+    /* Next checkpoint lets GC go on up to restart_lsn */
+    system.set_remote_consistent_lsn(&conf, end).unwrap();
+    let proposal = system.propose_gc(&conf, end, "test").unwrap();
+    assert_eq!(proposal.limited_by, "restart_lsn");
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
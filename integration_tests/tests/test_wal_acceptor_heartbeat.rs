@@ -0,0 +1,54 @@
+// Idle proposer in simulated time: the safekeeper sends heartbeats every heartbeat
+// interval and gives up on the proposer after HEARTBEAT_MISSES of them, or once
+// --proposer-read-timeout expires, whichever comes first.
+//
+// The test installs the clock of tokio runtime, which is global, so it has its own binary.
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use walkeeper::clock::{self, SystemClock, TokioClock};
+use walkeeper::safekeeper_protocol::{SK_STATUS_HEARTBEAT, SK_STATUS_OK};
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+
+// Statuses received by idle proposer and for how long it was kept in simulated time
+fn idle(heartbeat: u64, read_timeout: Option<u64>, seed: u64) -> (Vec<u32>, Duration) {
+    let dir = env::temp_dir().join(format!("test_heartbeat_{}_{}", seed, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut conf = test_conf(&dir);
+    conf.heartbeat_interval = Some(Duration::from_secs(heartbeat));
+    conf.proposer_read_timeout = read_timeout.map(Duration::from_secs);
+    let mut session = TestSession::start(conf, seed).unwrap();
+    let start = session.start_lsn();
+    session.stream(start + 1000, start, start + 1000).unwrap();
+
+    let started = session.block_on(async {
+        tokio::time::pause();
+        clock::set_clock(Arc::new(TokioClock::new(SystemTime::now())));
+        clock::now()
+    });
+    let statuses = session.idle().unwrap();
+    let elapsed = session.block_on(async { clock::elapsed(started) });
+    clock::set_clock(Arc::new(SystemClock));
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+    (statuses, elapsed)
+}
+
+#[test]
+fn test_idle_proposer() {
+    let (statuses, elapsed) = idle(10, None, 753);
+    assert_eq!(
+        statuses,
+        vec![SK_STATUS_OK, SK_STATUS_HEARTBEAT, SK_STATUS_HEARTBEAT]
+    );
+    assert!(elapsed >= Duration::from_secs(30), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(40), "{:?}", elapsed);
+
+    /* Read timeout expires before the third heartbeat is missed */
+    let (statuses, elapsed) = idle(10, Some(15), 754);
+    assert_eq!(statuses, vec![SK_STATUS_OK, SK_STATUS_HEARTBEAT]);
+    assert!(elapsed >= Duration::from_secs(15), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(20), "{:?}", elapsed);
+}
@@ -0,0 +1,274 @@
+// WAL import: checks of the manifest and of the segments, zeroing of WAL beyond
+// flush_lsn, tar archives and rollback of an import interrupted before the control file
+// was written.
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkeeper::recovery_log::{self, Action};
+use walkeeper::wal_import;
+use walkeeper::wal_service;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::xlog_utils::*;
+use walkeeper::{tenant_dir, WalAcceptorConf};
+
+struct Source {
+    session: TestSession,
+    conf: WalAcceptorConf,
+    dir: PathBuf,
+    flush_lsn: XLogRecPtr,
+}
+
+impl Source {
+    //
+    // Source directory with the first three segments of generated WAL and the manifest
+    // importing it up to the middle of the third one
+    //
+    fn new(name: &str) -> Source {
+        let dir = env::temp_dir().join(format!("test_wal_import_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::create_dir_all(dir.join("source")).unwrap();
+        let conf = test_conf(&dir.join("data"));
+        let session = TestSession::start(conf.clone(), 778).unwrap();
+        let seg = session.wal_seg_size() as u64;
+        let start = session.start_lsn();
+        for i in 0..3 {
+            let wal = session.wal(start + i * seg, start + (i + 1) * seg);
+            fs::write(dir.join("source").join(segment_name(&session, i)), wal).unwrap();
+        }
+        let source = Source {
+            session: session,
+            conf: conf,
+            dir: dir,
+            flush_lsn: start + 2 * seg + 1000,
+        };
+        source.write_manifest(&format!("flush_lsn = \"{}\"", format_lsn(source.flush_lsn)));
+        source
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join("source")
+    }
+
+    fn write_manifest(&self, positions: &str) {
+        let manifest = format!(
+            "timeline = {}\nwal_seg_size = {}\n{}\n",
+            self.session.timeline(),
+            self.session.wal_seg_size(),
+            positions
+        );
+        fs::write(self.path().join(wal_import::IMPORT_MANIFEST_NAME), manifest).unwrap();
+    }
+
+    fn import(&self, source: &Path) -> io::Result<wal_import::ImportPlan> {
+        wal_service::import_wal(&self.conf, self.session.system_id(), source, None)
+    }
+
+    // Import is refused as invalid with the message, and leaves nothing behind
+    fn assert_invalid(&self, source: &Path, message: &str) {
+        let e = self.import(source).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", e);
+        assert!(e.to_string().contains(message), "{}", e);
+        let system_dir = tenant_dir(&self.conf.data_dir, self.session.system_id());
+        assert!(!wal_import::has_wal(&system_dir).unwrap());
+        assert!(!system_dir.join("import.tmp").exists());
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Name of i-th segment of generated WAL
+fn segment_name(session: &TestSession, i: u64) -> String {
+    let seg = session.wal_seg_size();
+    let segno = XLByteToSeg(session.start_lsn(), seg) + i;
+    XLogFileName(session.timeline(), segno, seg)
+}
+
+// Append regular file entry to ustar archive, the path is split into prefix and name
+fn tar_entry(archive: &mut Vec<u8>, prefix: &str, name: &str, data: &[u8]) {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..107].copy_from_slice(b"0000644");
+    header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+    header[148..156].copy_from_slice(b"        ");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize((archive.len() + 511) / 512 * 512, 0);
+}
+
+#[test]
+fn test_wal_import_checks() {
+    let source = Source::new("checks");
+    let path = source.path();
+    let manifest_path = path.join(wal_import::IMPORT_MANIFEST_NAME);
+    let manifest = fs::read(&manifest_path).unwrap();
+
+    fs::remove_file(&manifest_path).unwrap();
+    source.assert_invalid(&path, "has no import.toml manifest");
+    source.write_manifest("flush_lsn = \"0/0\"");
+    source.assert_invalid(&path, "Invalid manifest");
+    source.write_manifest(&format!(
+        "flush_lsn = \"{}\"\ncommit_lsn = \"{}\"",
+        format_lsn(source.flush_lsn),
+        format_lsn(source.flush_lsn + 1)
+    ));
+    source.assert_invalid(&path, "Invalid manifest");
+    source.write_manifest("flush_lsn = \"not an LSN\"");
+    source.assert_invalid(&path, "");
+    let seg = source.session.wal_seg_size() as u64;
+    source.write_manifest(&format!(
+        "flush_lsn = \"{}\"",
+        format_lsn(source.flush_lsn + seg)
+    ));
+    source.assert_invalid(&path, "is missing");
+    fs::write(
+        &manifest_path,
+        String::from_utf8(manifest)
+            .unwrap()
+            .replace("timeline = 1", "timeline = 2"),
+    )
+    .unwrap();
+    source.assert_invalid(&path, "is of timeline 1");
+    source.write_manifest(&format!("flush_lsn = \"{}\"", format_lsn(source.flush_lsn)));
+
+    /* Gap in the segments */
+    let second = path.join(segment_name(&source.session, 1));
+    let wal = fs::read(&second).unwrap();
+    fs::remove_file(&second).unwrap();
+    source.assert_invalid(&path, "is missing");
+
+    /* Segment renamed to another position */
+    fs::write(
+        &second,
+        fs::read(path.join(segment_name(&source.session, 0))).unwrap(),
+    )
+    .unwrap();
+    source.assert_invalid(&path, "starts with page of");
+    fs::write(&second, &wal).unwrap();
+
+    /* WAL of another system */
+    let other_id = source.session.system_id() + 1;
+    let e = wal_service::import_wal(&source.conf, other_id, &path, None).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("is WAL of system"), "{}", e);
+
+    /* Records must be valid up to flush_lsn */
+    let third = path.join(segment_name(&source.session, 2));
+    let mut wal = fs::read(&third).unwrap();
+    for byte in &mut wal[100..2000] {
+        *byte = 0xFF;
+    }
+    fs::write(&third, &wal).unwrap();
+    source.assert_invalid(&path, "before flush_lsn");
+}
+
+#[test]
+fn test_wal_import() {
+    let mut source = Source::new("dir");
+    let plan = source.import(&source.path()).unwrap();
+    let session = &source.session;
+    let seg = session.wal_seg_size() as u64;
+    let start = session.start_lsn();
+    assert_eq!(plan.start_lsn, start);
+    assert_eq!(plan.flush_lsn, source.flush_lsn);
+    assert_eq!(plan.commit_lsn, source.flush_lsn);
+    let last = segment_name(session, 2) + ".partial";
+    assert_eq!(
+        plan.files,
+        vec![
+            segment_name(session, 0),
+            segment_name(session, 1),
+            last.clone()
+        ]
+    );
+
+    /* WAL beyond flush_lsn is zeroed */
+    let system_dir = tenant_dir(&source.conf.data_dir, session.system_id());
+    let wal = fs::read(system_dir.join(&last)).unwrap();
+    let flush_offset = (source.flush_lsn - start - 2 * seg) as usize;
+    assert!(wal[..flush_offset] == *session.wal(start + 2 * seg, source.flush_lsn));
+    assert!(wal[flush_offset..].iter().all(|&b| b == 0));
+
+    /* Tenant with WAL can't be imported into */
+    let e = source.import(&source.path()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+
+    /* Proposer continues at flush_lsn of the import */
+    let flush_lsn = source.flush_lsn;
+    assert_eq!(
+        source.session.stream(flush_lsn, start, flush_lsn).unwrap(),
+        flush_lsn
+    );
+}
+
+#[test]
+fn test_wal_import_archive() {
+    let source = Source::new("archive");
+    let path = source.path();
+    let mut archive = Vec::new();
+    let manifest = fs::read(path.join(wal_import::IMPORT_MANIFEST_NAME)).unwrap();
+    tar_entry(
+        &mut archive,
+        "",
+        wal_import::IMPORT_MANIFEST_NAME,
+        &manifest,
+    );
+    for i in 0..3 {
+        let name = segment_name(&source.session, i);
+        let wal = fs::read(path.join(&name)).unwrap();
+        tar_entry(&mut archive, "backup/pg_wal", &name, &wal);
+    }
+    archive.resize(archive.len() + 1024, 0);
+
+    let truncated = source.dir.join("truncated.tar");
+    fs::write(&truncated, &archive[..archive.len() / 2]).unwrap();
+    source.assert_invalid(&truncated, "is truncated");
+    let compressed = source.dir.join("wal.tar.gz");
+    fs::write(&compressed, &archive).unwrap();
+    source.assert_invalid(&compressed, "is compressed");
+    let mut corrupted = archive.clone();
+    corrupted[10] ^= 1;
+    let corrupted_path = source.dir.join("corrupted.tar");
+    fs::write(&corrupted_path, &corrupted).unwrap();
+    source.assert_invalid(&corrupted_path, "is not a tar archive or is corrupted");
+
+    let tar_path = source.dir.join("wal.tar");
+    fs::write(&tar_path, &archive).unwrap();
+    let plan = source.import(&tar_path).unwrap();
+    assert_eq!(plan.flush_lsn, source.flush_lsn);
+    assert_eq!(plan.files.len(), 3);
+}
+
+#[test]
+fn test_wal_import_rollback() {
+    let source = Source::new("rollback");
+    let id = source.session.system_id();
+    let system_dir = tenant_dir(&source.conf.data_dir, id);
+    fs::create_dir_all(&system_dir).unwrap();
+
+    /* Segments are installed, but the control file is not written */
+    let plan = wal_import::stage(&system_dir, id, &source.path(), None, false, true).unwrap();
+    wal_import::install(&system_dir, &plan, true).unwrap();
+    assert!(wal_import::has_wal(&system_dir).unwrap());
+    assert!(wal_import::interrupted(&system_dir).unwrap().is_some());
+
+    /* The next import rolls the interrupted one back first */
+    source.import(&source.path()).unwrap();
+    assert!(wal_import::interrupted(&system_dir).unwrap().is_none());
+    let entries = recovery_log::read(&source.conf.data_dir, Some(id)).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].action, Action::ImportRollback);
+    assert_eq!(entries[0].files, plan.files);
+}
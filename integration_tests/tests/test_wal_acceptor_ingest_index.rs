@@ -0,0 +1,136 @@
+// Ingest-time index: entries are recorded at most once per second, survive reload, are
+// cut on WAL truncation and thinned out as the index grows, and --wal-retention-secs
+// keeps WAL received within the retention period from WAL GC.
+//
+// The retention test installs its own clock, which is global, so the file has its own binary.
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use walkeeper::clock::{self, Clock, SystemClock};
+use walkeeper::ingest_index::{IngestIndex, INGEST_INDEX_FILE_NAME};
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::xlog_utils::*;
+
+const SEC: TimestampTz = 1_000_000;
+
+// Wall clock standing still at the given time
+struct FixedClock(SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.0
+    }
+}
+
+#[test]
+fn test_ingest_index() {
+    let dir = env::temp_dir().join(format!("test_ingest_index_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let t = 1000 * SEC;
+
+    let mut index = IngestIndex::load(&dir);
+    assert!(index.is_due(100, t));
+    assert_eq!(index.lsn_by_time(t), None);
+    index.record(100, t).unwrap();
+    /* At most one entry per second */
+    assert!(!index.is_due(200, t + SEC / 2));
+    index.record(200, t + SEC / 2).unwrap();
+    assert!(index.is_due(300, t + SEC));
+    index.record(300, t + SEC).unwrap();
+    for index in &[index, IngestIndex::load(&dir)] {
+        assert_eq!(index.lsn_by_time(t - 1), None);
+        assert_eq!(index.lsn_by_time(t + SEC - 1), Some(100));
+        assert_eq!(index.lsn_by_time(t + SEC), Some(300));
+        assert_eq!(index.time_after_lsn(50), Some(t));
+        assert_eq!(index.time_after_lsn(100), Some(t + SEC));
+        assert_eq!(index.time_after_lsn(300), None);
+    }
+
+    /* Entries beyond truncated WAL are forgotten */
+    let mut index = IngestIndex::load(&dir);
+    assert!(index.is_due(150, t + SEC + 1));
+    index.record(150, t + 2 * SEC).unwrap();
+    assert_eq!(index.lsn_by_time(t + SEC), Some(100));
+    assert_eq!(index.lsn_by_time(t + 2 * SEC), Some(150));
+
+    /* Torn tail is ignored and the file is rewritten on the next record */
+    let path = dir.join(INGEST_INDEX_FILE_NAME);
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0xFF; 5]).unwrap();
+    drop(file);
+    let mut index = IngestIndex::load(&dir);
+    assert_eq!(index.lsn_by_time(t + 2 * SEC), Some(150));
+    assert!(index.is_due(150, t + 2 * SEC));
+    index.record(200, t + 3 * SEC).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 3 * 16);
+
+    /* Growing index is thinned out, but still maps time to LSN received by then */
+    let mut index = IngestIndex::load(&dir);
+    let n = 20_000;
+    for i in 4..n {
+        index.record(i * 100, t + i * SEC).unwrap();
+    }
+    assert!(fs::metadata(&path).unwrap().len() < 16 * 16 * 1024);
+    let index = IngestIndex::load(&dir);
+    for i in (4..n).step_by(1000) {
+        let lsn = index.lsn_by_time(t + i * SEC).unwrap();
+        assert!(lsn <= i * 100);
+    }
+    assert_eq!(index.lsn_by_time(t + n * SEC), Some((n - 1) * 100));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_retention_time() {
+    let dir = env::temp_dir().join(format!("test_wal_retention_time_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let retention = Duration::from_secs(24 * 3600);
+    let mut conf = test_conf(&dir);
+    conf.wal_retention_time = Some(retention);
+    let mut session = TestSession::start(conf.clone(), 737).unwrap();
+    let seg = session.wal_seg_size() as u64;
+    let start = session.start_lsn();
+    let end = session.end_lsn();
+    let mid = start + seg + 100;
+    let restart_lsn = start + 2 * seg + 100;
+    session.stream(mid, mid, mid).unwrap();
+    thread::sleep(Duration::from_millis(10));
+    let received_mid = SystemTime::now();
+    thread::sleep(Duration::from_millis(10));
+    session.stream(end - 1000, restart_lsn, end - 1000).unwrap();
+    /* The last append is a second later, so that it is recorded in the index */
+    thread::sleep(Duration::from_millis(1100));
+    session.stream(end, restart_lsn, end).unwrap();
+    let system = session.system().unwrap();
+
+    /* All WAL has been received within the retention period */
+    let proposal = system.propose_gc(&conf, end, "test").unwrap();
+    assert_eq!(proposal.limited_by, "wal_retention_time");
+    assert_eq!(proposal.cutoff_lsn, 0);
+
+    /* A day later only WAL received after the first session is kept */
+    clock::set_clock(Arc::new(FixedClock(received_mid + retention)));
+    let proposal = system.propose_gc(&conf, end, "test");
+    clock::set_clock(Arc::new(FixedClock(received_mid + retention * 2)));
+    let expired = system.propose_gc(&conf, end, "test");
+    clock::set_clock(Arc::new(SystemClock));
+    let proposal = proposal.unwrap();
+    assert_eq!(proposal.limited_by, "wal_retention_time");
+    assert!(proposal.cutoff_lsn <= mid);
+
+    /* And then nothing is retained by time */
+    let expired = expired.unwrap();
+    assert_eq!(expired.limited_by, "restart_lsn");
+    assert_eq!(expired.cutoff_lsn, start + 2 * seg);
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,45 @@
+// Pre-provisioning of tenants: control file of a new tenant is created once, and a
+// tenant served meanwhile is refused, even before its control file is written.
+use bytes::BytesMut;
+use std::env;
+use std::fs;
+use std::io;
+use walkeeper::safekeeper_protocol::SafeKeeperInfo;
+use walkeeper::tenant_dir;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::wal_service::{init_tenant, CONTROL_FILE_NAME};
+
+#[test]
+fn test_init_tenant() {
+    let dir = env::temp_dir().join(format!("test_init_tenant_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    let mut session = TestSession::start(conf.clone(), 732).unwrap();
+    let id = session.system_id();
+    let path = tenant_dir(&conf.data_dir, id).join(CONTROL_FILE_NAME);
+
+    let timeline = session.timeline();
+    assert_eq!(init_tenant(&conf, id, Some(timeline), None).unwrap(), 0);
+    let info = SafeKeeperInfo::unpack(&mut BytesMut::from(&fs::read(&path).unwrap()[..]));
+    assert_eq!(
+        (info.server.system_id, info.server.timeline),
+        (id, timeline)
+    );
+    let e = init_tenant(&conf, id, None, None).unwrap_err();
+    assert!(e.to_string().contains("already initialized"), "{}", e);
+
+    /* Served tenant, with the control file as empty as right after implicit creation */
+    let start = session.start_lsn();
+    session.stream(start + 1000, start, start).unwrap();
+    fs::write(&path, b"").unwrap();
+    let e = init_tenant(&conf, id, None, None).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    assert!(e.to_string().contains("is served"), "{}", e);
+    assert!(fs::read(&path).unwrap().is_empty());
+
+    session.tenants().unload_system(id);
+    assert_eq!(init_tenant(&conf, id, None, None).unwrap(), 0);
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,60 @@
+// Object storage of WAL through WalStorage: appends are staged locally below the chunk
+// size, and failure to open the staged WAL fails the connection instead of reporting
+// empty WAL.
+use std::env;
+use std::fs;
+use walkeeper::object_storage::{BucketConf, ObjectStorageConf, ObjectWal, STAGING_FILE_NAME};
+use walkeeper::tenant_dir;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+
+fn object_storage_conf(dir: &std::path::Path) -> walkeeper::WalAcceptorConf {
+    /* Bucket is never reached: staged WAL stays below the chunk size */
+    for (name, value) in &[
+        ("S3_REGION", "test"),
+        ("S3_ENDPOINT", "http://127.0.0.1:9"),
+        ("S3_ACCESSKEY", "test"),
+        ("S3_SECRET", "test"),
+    ] {
+        env::set_var(name, value);
+    }
+    let mut conf = test_conf(dir);
+    conf.object_storage = Some(ObjectStorageConf {
+        bucket: BucketConf::new("wal", "objects").unwrap(),
+        chunk_size: 16 * 1024 * 1024,
+    });
+    conf
+}
+
+#[test]
+fn test_object_storage_staging() {
+    let dir = env::temp_dir().join(format!("test_object_storage_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut session = TestSession::start(object_storage_conf(&dir), 756).unwrap();
+    let start = session.start_lsn();
+    let end = start + 64 * 1024; /* a single append */
+    assert_eq!(session.stream(end, start, end).unwrap(), end);
+
+    let system_dir = tenant_dir(&dir, session.system_id());
+    let staged = fs::read(system_dir.join(STAGING_FILE_NAME)).unwrap();
+    assert!(staged[16..] == *session.wal(start, end));
+    assert!(!system_dir.read_dir().unwrap().any(|entry| {
+        let fname = entry.unwrap().file_name().to_string_lossy().into_owned();
+        fname.starts_with("0000")
+    }));
+    let object_wal = ObjectWal::open(&system_dir, session.system_id()).unwrap();
+    assert_eq!(object_wal.end_of_wal(), (end, session.timeline()));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_object_storage_end_of_wal_error() {
+    let dir = env::temp_dir().join(format!("test_object_storage_error_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut session = TestSession::start(object_storage_conf(&dir), 756).unwrap();
+    let system_dir = tenant_dir(&dir, session.system_id());
+    fs::create_dir_all(system_dir.join(STAGING_FILE_NAME)).unwrap();
+    let start = session.start_lsn();
+    assert!(session.stream(start + 1024, start, start).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,217 @@
+// Lifecycle of a partial segment: creation, writer and reader fallback order, rename on
+// completion, reconciliation of crash leftovers (including validation of a partial segment
+// before it is completed), and at-rest encoding of the completed one (including the
+// compression codec).
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fs;
+use std::io::prelude::*;
+use walkeeper::at_rest::{self, AtRestKey, AtRestPolicy};
+use walkeeper::partial_segment::{self, reconcile_partial_segments, SegmentPath};
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::xlog_utils::*;
+
+const WAL_SEG_SIZE: usize = 1024 * 1024;
+
+#[test]
+fn test_partial_segment_lifecycle() {
+    let dir = std::env::temp_dir().join(format!("test_partial_segment_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let segment = SegmentPath::new(&dir, 1, 1, WAL_SEG_SIZE);
+    assert!(!segment.exists());
+    assert!(segment.open_for_write(None).unwrap().is_none());
+    assert!(segment.open_for_read(None).is_err());
+
+    /* New segment is full-sized and has no temporary file left */
+    partial_segment::zero_fill(&segment.partial, WAL_SEG_SIZE, true).unwrap();
+    assert_eq!(
+        fs::metadata(&segment.partial).unwrap().len(),
+        WAL_SEG_SIZE as u64
+    );
+    assert!(!dir.join(segment.name.clone() + ".partial.prep").exists());
+
+    let (mut file, partial) = segment.open_for_write(None).unwrap().unwrap();
+    assert!(partial);
+    file.write_all(b"WAL").unwrap();
+    let mut data = [0u8; 3];
+    segment
+        .open_for_read(None)
+        .unwrap()
+        .read_exact(&mut data)
+        .unwrap();
+    assert_eq!(&data, b"WAL");
+
+    segment.complete().unwrap();
+    assert!(!segment.partial.exists());
+    let (_, partial) = segment.open_for_write(None).unwrap().unwrap();
+    assert!(!partial);
+    segment
+        .open_for_read(None)
+        .unwrap()
+        .read_exact(&mut data)
+        .unwrap();
+    assert_eq!(&data, b"WAL");
+
+    /* Stale partial next to the completed segment and interrupted fill are cleaned up */
+    fs::write(&segment.partial, b"stale").unwrap();
+    let next = SegmentPath::new(&dir, 1, 2, WAL_SEG_SIZE);
+    fs::write(dir.join(next.name.clone() + ".partial.prep"), b"").unwrap();
+    let mut orphaned = Vec::new();
+    let actions = reconcile_partial_segments(&dir, WAL_SEG_SIZE, |fname, _| {
+        orphaned.push(fname.to_string());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(actions.len(), 1);
+    assert!(orphaned.is_empty());
+    assert!(!next.exists());
+    assert!(!dir.join(next.name.clone() + ".partial.prep").exists());
+
+    fs::write(&next.partial, b"").unwrap();
+    reconcile_partial_segments(&dir, WAL_SEG_SIZE, |fname, _| {
+        orphaned.push(fname.to_string());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(orphaned, vec![segment.name.clone() + ".partial"]);
+    assert!(!segment.partial.exists());
+    assert!(next.partial.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_reconcile_validates_followed_segment() {
+    let dir = std::env::temp_dir().join(format!("test_partial_followed_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let session = TestSession::start(test_conf(&dir), 740).unwrap();
+    let seg = session.wal_seg_size();
+    let start = session.start_lsn();
+    let segno = XLByteToSeg(start, seg);
+    let first = SegmentPath::new(&dir, session.timeline(), segno, seg);
+    let second = SegmentPath::new(&dir, session.timeline(), segno + 1, seg);
+    let third = SegmentPath::new(&dir, session.timeline(), segno + 2, seg);
+    let mut orphaned = Vec::new();
+
+    /* Fully written partial segment followed by later WAL is completed */
+    fs::write(&first.partial, session.wal(start, start + seg as u64)).unwrap();
+    fs::write(&second.partial, vec![0u8; seg]).unwrap();
+    reconcile_partial_segments(&dir, seg, |fname, _| {
+        orphaned.push(fname.to_string());
+        Ok(())
+    })
+    .unwrap();
+    assert!(orphaned.is_empty());
+    assert!(first.complete.exists() && !first.partial.exists());
+
+    /* The one with zero-filled tail is orphaned, not promoted */
+    let mut wal = session
+        .wal(start + seg as u64, start + 2 * seg as u64)
+        .to_vec();
+    for byte in &mut wal[seg / 2..] {
+        *byte = 0;
+    }
+    fs::write(&second.partial, &wal).unwrap();
+    fs::write(&third.partial, vec![0u8; seg]).unwrap();
+    reconcile_partial_segments(&dir, seg, |fname, reason| {
+        assert!(reason.contains("not fully written"), "{}", reason);
+        orphaned.push(fname.to_string());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(orphaned, vec![second.name.clone() + ".partial"]);
+    assert!(!second.exists());
+    assert!(third.partial.exists());
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_partial_segment_at_rest() {
+    let dir = std::env::temp_dir().join(format!("test_segment_at_rest_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let key_file = dir.join("key");
+    fs::write(&key_file, "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=").unwrap();
+    let key = AtRestKey::load(&key_file).unwrap();
+
+    let segment = SegmentPath::new(&dir, 1, 1, WAL_SEG_SIZE);
+    let wal: Vec<u8> = (0..WAL_SEG_SIZE).map(|i| (i / 1000) as u8).collect();
+    fs::write(&segment.complete, &wal).unwrap();
+    let policy = AtRestPolicy {
+        compression: true,
+        encryption: true,
+    };
+
+    /* Encoded segment is smaller, readers get it decoded */
+    let tmp_path = at_rest::prepare_rewrite(&segment.complete, policy, Some(&key), true)
+        .unwrap()
+        .unwrap();
+    fs::rename(&tmp_path, &segment.complete).unwrap();
+    assert!(at_rest::is_encoded(&segment.complete));
+    assert!(fs::metadata(&segment.complete).unwrap().len() < WAL_SEG_SIZE as u64);
+    assert!(
+        at_rest::prepare_rewrite(&segment.complete, policy, Some(&key), true)
+            .unwrap()
+            .is_none()
+    );
+    let mut data = Vec::new();
+    segment
+        .open_for_read(Some(&key))
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert!(data == wal);
+
+    /* Encrypted segment can't be read without the key */
+    assert!(segment.open_for_read(None).is_err());
+
+    /* Tampered segment fails authentication */
+    let mut encoded = fs::read(&segment.complete).unwrap();
+    let last = encoded.len() - 1;
+    encoded[last] ^= 1;
+    assert!(at_rest::decode(encoded, Some(&key)).is_err());
+
+    /* Writer decodes the segment in place before overwriting it */
+    let (_, partial) = segment.open_for_write(Some(&key)).unwrap().unwrap();
+    assert!(!partial);
+    assert!(!at_rest::is_encoded(&segment.complete));
+    assert!(fs::read(&segment.complete).unwrap() == wal);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_at_rest_compression() {
+    let policy = AtRestPolicy {
+        compression: true,
+        encryption: false,
+    };
+    let mut rng = StdRng::seed_from_u64(775);
+    let random: Vec<u8> = (0..100_000).map(|_| rng.gen()).collect();
+    /* Runs longer than a length byte, overlapping matches, noise and tails of any length */
+    let mut mixed = vec![0u8; 70_000];
+    mixed.extend_from_slice(&random[..1000]);
+    mixed.extend((0..50_000).map(|i| b"abc"[i % 3]));
+    mixed.extend_from_slice(&random[..1000]);
+    for wal in &[vec![], vec![1u8; 3], vec![7u8; 13], random.clone(), mixed] {
+        let encoded = at_rest::encode(wal, policy, None).unwrap();
+        assert_eq!(at_rest::encoding(&encoded), Some(policy));
+        assert!(&at_rest::decode(encoded, None).unwrap() == wal);
+    }
+
+    /* Corrupted or truncated block is an error rather than a panic or a huge allocation */
+    let encoded = at_rest::encode(&vec![5u8; 100_000], policy, None).unwrap();
+    let header = at_rest::HEADER_SIZE;
+    for len in header..encoded.len() {
+        assert!(at_rest::decode(encoded[..len].to_vec(), None).is_err());
+    }
+    for _ in 0..1000 {
+        let mut corrupted = encoded.clone();
+        let pos = rng.gen_range(header..corrupted.len());
+        corrupted[pos] = rng.gen();
+        let _ = at_rest::decode(corrupted, None);
+    }
+    let mut oversized = encoded;
+    oversized[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(at_rest::decode(oversized, None).is_err());
+}
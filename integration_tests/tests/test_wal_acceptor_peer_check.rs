@@ -0,0 +1,112 @@
+// Consistency check against a peer safekeeper: committed WAL retained by both is compared,
+// WAL beyond the lower commit_lsn is not, and the first mismatching byte is reported.
+// The peer serves hashes of WAL only to the safekeeper authenticated with a password.
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::net::TcpListener;
+use tokio::task;
+use walkeeper::partial_segment::SegmentPath;
+use walkeeper::peer_check;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::wal_service::{serve_connection, TenantRegistry};
+use walkeeper::xlog_utils::*;
+use walkeeper::{tenant_dir, WalAcceptorConf, TENANT_CONF_FILE_NAME};
+
+// Serve libpq connections of the safekeeper in background, returns its address
+async fn start_safekeeper(conf: WalAcceptorConf) -> SocketAddr {
+    let listener = TcpListener::bind(conf.listen_addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tenants = TenantRegistry::new();
+    task::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let conf = conf.clone();
+            let tenants = tenants.clone();
+            task::spawn(async move { serve_connection(socket, &conf, tenants).await });
+        }
+    });
+    addr
+}
+
+// Flip a byte of WAL at the given position
+fn corrupt(session: &TestSession, data_dir: &Path, lsn: XLogRecPtr) {
+    let seg = session.wal_seg_size();
+    let system_dir = tenant_dir(data_dir, session.system_id());
+    let segment = SegmentPath::new(&system_dir, session.timeline(), XLByteToSeg(lsn, seg), seg);
+    let path = segment.path(!segment.complete.exists());
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let mut byte = [0u8; 1];
+    file.seek(SeekFrom::Start(XLogSegmentOffset(lsn, seg) as u64))
+        .unwrap();
+    file.read_exact(&mut byte).unwrap();
+    byte[0] ^= 0xFF;
+    file.seek(SeekFrom::Start(XLogSegmentOffset(lsn, seg) as u64))
+        .unwrap();
+    file.write_all(&byte).unwrap();
+}
+
+#[test]
+fn test_compare_peer() {
+    let dir = env::temp_dir().join(format!("test_compare_peer_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (local_dir, peer_dir) = (dir.join("local"), dir.join("peer"));
+    let local_conf = test_conf(&local_dir);
+    let peer_conf = test_conf(&peer_dir);
+    let mut local = TestSession::start(local_conf.clone(), 755).unwrap();
+    let mut peer = TestSession::start(peer_conf.clone(), 755).unwrap();
+    let seg = local.wal_seg_size() as u64;
+    let start = local.start_lsn();
+    let end = start + 2 * seg;
+    let peer_commit_lsn = start + seg + 1000;
+    local.stream(end, start, end).unwrap();
+    peer.stream(end, start, peer_commit_lsn).unwrap();
+    drop(peer);
+    fs::write(
+        tenant_dir(&peer_dir, local.system_id()).join(TENANT_CONF_FILE_NAME),
+        "auth_method = \"md5\"\n[users]\nsafekeeper = \"secret\"\n",
+    )
+    .unwrap();
+
+    let id = local.system_id().to_string();
+    let tenants = local.tenants();
+    let compare = |peer_addr: SocketAddr, password: Option<&str>| {
+        local.block_on(peer_check::compare(
+            &local_conf,
+            &tenants,
+            &id,
+            &peer_addr.to_string(),
+            password,
+        ))
+    };
+    let peer_addr = local.block_on(start_safekeeper(peer_conf.clone()));
+
+    /* Wrong password and no password at all are refused by the peer */
+    assert!(compare(peer_addr, Some("wrong")).is_err());
+    assert!(compare(peer_addr, None).is_err());
+
+    /* WAL beyond commit_lsn of the peer is not compared */
+    corrupt(&local, &peer_dir, peer_commit_lsn + 10);
+    let report = compare(peer_addr, Some("secret")).unwrap();
+    let identical = format!(
+        "WAL {}-{} is identical",
+        format_lsn(start),
+        format_lsn(peer_commit_lsn)
+    );
+    assert!(report.contains(&identical), "{}", report);
+
+    let diverged_lsn = start + seg / 2 + 7;
+    corrupt(&local, &peer_dir, diverged_lsn);
+    let report = compare(peer_addr, Some("secret")).unwrap();
+    let divergence = format!("first mismatching LSN {}", format_lsn(diverged_lsn));
+    assert!(report.contains(&divergence), "{}", report);
+    drop(local);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,118 @@
+// Postgres version of proposers: a version change is recorded only once the proposer wins
+// the vote, "reject" policy refuses unacknowledged versions, and "new-timeline" policy
+// starts the next timeline with WAL of the parent one preceding the switch point.
+use std::env;
+use std::fs;
+use walkeeper::partial_segment::SegmentPath;
+use walkeeper::timeline_history;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::xlog_utils::*;
+use walkeeper::{tenant_dir, WalAcceptorConf};
+
+const PG13: u32 = 130000;
+const PG14: u32 = 140000;
+
+fn write_tenant_conf(conf: &WalAcceptorConf, id: u64, policy: &str) {
+    let dir = tenant_dir(&conf.data_dir, id);
+    fs::create_dir_all(&dir).unwrap();
+    let tenant_conf = format!("pg_version_mismatch = \"{}\"\n", policy);
+    fs::write(dir.join("tenant.toml"), tenant_conf).unwrap();
+}
+
+#[test]
+fn test_pg_version_recorded_after_vote() {
+    let dir = env::temp_dir().join(format!("test_pg_version_vote_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut session = TestSession::start(test_conf(&dir), 758).unwrap();
+    let start = session.start_lsn();
+    session.stream(start + 1000, start, start + 1000).unwrap();
+    session.stream(start + 2000, start, start + 2000).unwrap();
+    let system = session.system().unwrap();
+    assert_eq!(system.pg_versions().changes.len(), 1);
+
+    /* Proposer of a stale term loses the vote and leaves no trace */
+    session.set_pg_version(PG14);
+    session.set_term(0);
+    assert!(session.stream(start + 3000, start, start + 3000).is_err());
+    let versions = system.pg_versions();
+    assert_eq!(versions.changes.len(), 1);
+    assert_eq!(versions.changes[0].pg_version, PG13);
+    assert!(!versions.mismatch_accepted);
+
+    session.set_term(2);
+    session.stream(start + 3000, start, start + 3000).unwrap();
+    let versions = system.pg_versions();
+    assert_eq!(versions.changes.len(), 2);
+    assert_eq!(versions.changes[1].pg_version, PG14);
+    assert_eq!(versions.changes[1].lsn, start + 2000);
+    assert!(versions.mismatch_accepted);
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_pg_version_reject() {
+    let dir = env::temp_dir().join(format!("test_pg_version_reject_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    let mut session = TestSession::start(conf.clone(), 758).unwrap();
+    write_tenant_conf(&conf, session.system_id(), "reject");
+    let start = session.start_lsn();
+    session.stream(start + 1000, start, start + 1000).unwrap();
+
+    session.set_pg_version(PG14);
+    assert!(session.stream(start + 2000, start, start + 2000).is_err());
+    let system = session.system().unwrap();
+    assert_eq!(system.pg_versions().changes.len(), 1);
+
+    system.ack_pg_version(&conf, PG14).unwrap();
+    session.stream(start + 2000, start, start + 2000).unwrap();
+    let versions = system.pg_versions();
+    assert_eq!(versions.changes.len(), 2);
+    assert!(!versions.mismatch_accepted);
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_pg_version_new_timeline() {
+    let dir = env::temp_dir().join(format!("test_pg_version_tli_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    let mut session = TestSession::start(conf.clone(), 758).unwrap();
+    write_tenant_conf(&conf, session.system_id(), "new-timeline");
+    let seg = session.wal_seg_size();
+    let parent = session.timeline();
+    let start = session.start_lsn();
+    let mid = start + seg as u64 + 1000;
+    session.stream(mid, start, mid).unwrap();
+
+    session.set_pg_version(PG14);
+    session.stream(mid + 2000, start, mid + 2000).unwrap();
+    let system = session.system().unwrap();
+    let change = *system.pg_versions().changes.last().unwrap();
+    assert_eq!(change.timeline, parent + 1);
+    assert_eq!(change.lsn, mid);
+    let system_dir = tenant_dir(&conf.data_dir, session.system_id());
+    let history = timeline_history::load(&system_dir, parent + 1).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].timeline, parent);
+    assert_eq!(history[0].switch_lsn, mid);
+
+    /* The switch segment starts with WAL of the parent timeline */
+    let segno = XLByteToSeg(mid, seg);
+    let offset = XLogSegmentOffset(mid, seg) as usize;
+    let old = SegmentPath::new(&system_dir, parent, segno, seg);
+    let new = SegmentPath::new(&system_dir, parent + 1, segno, seg);
+    let old = fs::read(old.path(!old.complete.exists())).unwrap();
+    let new = fs::read(new.path(!new.complete.exists())).unwrap();
+    assert_eq!(new[..offset], old[..offset]);
+    assert_eq!(new[offset..offset + 2000], *session.wal(mid, mid + 2000));
+
+    let (end, timeline) = find_end_of_wal(&system_dir, seg, true, false);
+    assert_eq!(timeline, parent + 1);
+    assert!(end > mid && end <= mid + 2000, "{}", format_lsn(end));
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
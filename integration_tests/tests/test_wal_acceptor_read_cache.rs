@@ -0,0 +1,63 @@
+// Read cache of committed WAL: blocks are kept per tenant and timeline, so that segments
+// with the same number on different timelines are not mixed up, evicted in LRU order and
+// dropped with the tenant.
+//
+// The cache is global, so the test has its own binary.
+use std::env;
+use std::fs::{self, File};
+use walkeeper::read_cache::{self, CACHE_BLOCK_SIZE};
+
+const WAL_SEG_SIZE: usize = 16 * 1024 * 1024;
+
+#[test]
+fn test_read_cache() {
+    let dir = env::temp_dir().join(format!("test_read_cache_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    /* First segment of timelines 1 and 2, which differ after the switch */
+    let segment = |name: &str, fill: u8| {
+        let path = dir.join(name);
+        fs::write(&path, vec![fill; CACHE_BLOCK_SIZE * 4]).unwrap();
+        File::open(path).unwrap()
+    };
+    let tli1 = segment("000000010000000000000000", 1);
+    let tli2 = segment("000000020000000000000000", 2);
+    let committed = (CACHE_BLOCK_SIZE * 4) as u64;
+    let mut buf = vec![0u8; 100];
+
+    read_cache::set_capacity(CACHE_BLOCK_SIZE * 2);
+    assert!(read_cache::read(1, 1, &tli1, 10, committed, WAL_SEG_SIZE, &mut buf).unwrap());
+    assert!(buf.iter().all(|&b| b == 1));
+    assert!(read_cache::read(1, 2, &tli2, 10, committed, WAL_SEG_SIZE, &mut buf).unwrap());
+    assert!(buf.iter().all(|&b| b == 2), "block of another timeline");
+    assert!(read_cache::read(1, 1, &tli1, 200, committed, WAL_SEG_SIZE, &mut buf).unwrap());
+    assert!(buf.iter().all(|&b| b == 1));
+    let stats = read_cache::stats();
+    assert_eq!((stats.hits, stats.misses), (1, 2));
+    assert_eq!(stats.size, CACHE_BLOCK_SIZE * 2);
+
+    /* Block which is not committed completely is left to the caller */
+    let pos = (CACHE_BLOCK_SIZE * 2) as u64;
+    assert!(!read_cache::read(1, 1, &tli1, pos, pos + 200, WAL_SEG_SIZE, &mut buf).unwrap());
+    assert_eq!(read_cache::stats().misses, 2);
+
+    /* The least recently used block of timeline 2 is evicted */
+    assert!(read_cache::read(1, 1, &tli1, pos, committed, WAL_SEG_SIZE, &mut buf).unwrap());
+    let stats = read_cache::stats();
+    assert_eq!((stats.evictions, stats.size), (1, CACHE_BLOCK_SIZE * 2));
+    assert!(read_cache::read(1, 1, &tli1, 10, committed, WAL_SEG_SIZE, &mut buf).unwrap());
+    assert_eq!(read_cache::stats().hits, 2);
+    assert!(read_cache::read(1, 2, &tli2, 10, committed, WAL_SEG_SIZE, &mut buf).unwrap());
+    assert!(buf.iter().all(|&b| b == 2));
+    assert_eq!(read_cache::stats().misses, 4);
+
+    /* Blocks of other tenants stay */
+    read_cache::invalidate_tenant(2);
+    assert_eq!(read_cache::stats().size, CACHE_BLOCK_SIZE * 2);
+    read_cache::invalidate_tenant(1);
+    assert_eq!(read_cache::stats().size, 0);
+
+    read_cache::set_capacity(0);
+    assert!(!read_cache::read(1, 1, &tli1, 10, committed, WAL_SEG_SIZE, &mut buf).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+}
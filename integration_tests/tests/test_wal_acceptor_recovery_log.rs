@@ -0,0 +1,74 @@
+// Recovery log of WAL truncation: proposer of the new term resending WAL the safekeeper
+// already has isn't logged or counted, overwriting of differing WAL is, from the first
+// differing byte.
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+use walkeeper::partial_segment::SegmentPath;
+use walkeeper::recovery_log::{self, Action, Entry};
+use walkeeper::tenant_dir;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::xlog_utils::*;
+
+fn truncations(session: &TestSession, data_dir: &Path) -> Vec<Entry> {
+    recovery_log::read(data_dir, Some(session.system_id()))
+        .unwrap()
+        .into_iter()
+        .filter(|entry| entry.action == Action::WalTruncation)
+        .collect()
+}
+
+#[test]
+fn test_truncation_logged_if_wal_differs() {
+    let dir = env::temp_dir().join(format!("test_truncation_log_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    let mut session = TestSession::start(conf.clone(), 754).unwrap();
+    let seg = session.wal_seg_size();
+    let start = session.start_lsn();
+    let end = start + seg as u64 + 1000;
+    session.stream(end, start, start).unwrap();
+
+    /* The same WAL is resent by the next proposer */
+    session.stream_from(Some(start), end, start, start).unwrap();
+    assert!(truncations(&session, &conf.data_dir).is_empty());
+    let system = session.system().unwrap();
+    assert_eq!(system.metrics().wal_ops.truncation.count, 0);
+
+    /* Local WAL differs from the proposer's from the flipped byte */
+    let diverged_lsn = start + seg as u64 + 10;
+    let system_dir = tenant_dir(&conf.data_dir, session.system_id());
+    let segment = SegmentPath::new(
+        &system_dir,
+        session.timeline(),
+        XLByteToSeg(diverged_lsn, seg),
+        seg,
+    );
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(segment.path(!segment.complete.exists()))
+        .unwrap();
+    let offset = XLogSegmentOffset(diverged_lsn, seg) as u64;
+    let mut byte = [0u8; 1];
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.read_exact(&mut byte).unwrap();
+    byte[0] ^= 0xFF;
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&byte).unwrap();
+    drop(file);
+
+    session.stream_from(Some(start), end, start, start).unwrap();
+    let entries = truncations(&session, &conf.data_dir);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].start_lsn, Some(format_lsn(diverged_lsn)));
+    assert_eq!(entries[0].end_lsn, Some(format_lsn(end)));
+    let truncation = system.metrics().wal_ops.truncation;
+    assert_eq!(truncation.count, 1);
+    assert_eq!(truncation.bytes, end - diverged_lsn);
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,73 @@
+// Capture and replay of proposer sessions: replay of captured traces against fresh
+// storage matches them, and altered responses or final state are reported as mismatches.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkeeper::trace::{self, TRACE_FINAL_STATE, TRACE_SENT};
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+
+// Copy of the trace with a byte flipped in payload of the first record with the tag
+fn alter(trace: &Path, tag: u8, payload_offset: usize) -> PathBuf {
+    let mut content = fs::read(trace).unwrap();
+    let mut pos = 0;
+    loop {
+        let len = u32::from_le_bytes([
+            content[pos + 1],
+            content[pos + 2],
+            content[pos + 3],
+            content[pos + 4],
+        ]) as usize;
+        if content[pos] == tag && payload_offset < len {
+            content[pos + 5 + payload_offset] ^= 0xFF;
+            break;
+        }
+        pos += 5 + len;
+    }
+    let altered = trace.with_extension("altered");
+    fs::write(&altered, content).unwrap();
+    altered
+}
+
+// Trace files captured so far
+fn traces(trace_dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(trace_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect()
+}
+
+#[test]
+fn test_trace_replay() {
+    let dir = env::temp_dir().join(format!("test_trace_replay_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let trace_dir = dir.join("traces");
+    let mut conf = test_conf(&dir.join("data"));
+    conf.trace_dir = Some(trace_dir.clone());
+    let mut session = TestSession::start(conf, 723).unwrap();
+    let start = session.start_lsn();
+    session.stream(start + 3000, start, start + 1000).unwrap();
+    let first = traces(&trace_dir).pop().unwrap();
+    /* The second session starts with the control file left by the first one */
+    session
+        .stream(start + 100000, start, start + 50000)
+        .unwrap();
+    drop(session);
+    let mut second = traces(&trace_dir);
+    second.retain(|path| *path != first);
+    assert_eq!(second.len(), 1);
+    let second = second.pop().unwrap();
+    assert_eq!(trace::replay(&first).unwrap(), 0);
+    assert_eq!(trace::replay(&second).unwrap(), 0);
+
+    let altered = alter(&second, TRACE_SENT, 0);
+    assert_eq!(trace::replay(&altered).unwrap(), 1);
+    /* Control data follows system id */
+    let altered = alter(&second, TRACE_FINAL_STATE, 8);
+    assert_eq!(trace::replay(&altered).unwrap(), 1);
+
+    let content = fs::read(&first).unwrap();
+    fs::write(&altered, &content[..content.len() - 3]).unwrap();
+    assert!(trace::replay(&altered).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
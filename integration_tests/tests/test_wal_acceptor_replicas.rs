@@ -0,0 +1,94 @@
+// Progress of replicas: flush position reported in standby status updates holds back WAL
+// GC while the replica is connected and for a while after it disconnects.
+//
+// The test installs the clock of tokio runtime, which is global, so it has its own binary.
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+use tokio::time::{sleep, timeout};
+use walkeeper::clock::{self, SystemClock, TokioClock};
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::serve_connection;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::xlog_utils::*;
+
+// Frontend message of the given type
+fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut msg = vec![tag];
+    msg.extend_from_slice(&(4 + body.len() as u32).to_be_bytes());
+    msg.extend_from_slice(body);
+    msg
+}
+
+#[test]
+fn test_disconnected_replica_holds_wal() {
+    let dir = env::temp_dir().join(format!("test_replica_state_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    let mut session = TestSession::start(conf.clone(), 768).unwrap();
+    let start = session.start_lsn();
+    let end = start + session.wal_seg_size() as u64;
+    session.stream(end, start, end).unwrap();
+    let system = session.system().unwrap();
+    let replica_flush_lsn = start + 1000;
+    let tenants = session.tenants();
+
+    session.block_on(async {
+        let listener = TcpListener::bind(conf.listen_addr).await.unwrap();
+        let mut socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server_socket, _) = listener.accept().await.unwrap();
+        let server_conf = conf.clone();
+        task::spawn(async move { serve_connection(server_socket, &server_conf, tenants).await });
+
+        let params = format!(
+            "user\0replica\0options\0-c system.id={}\0\0",
+            session.system_id()
+        );
+        let mut startup = Vec::new();
+        startup.extend_from_slice(&(8 + params.len() as u32).to_be_bytes());
+        startup.extend_from_slice(&196608u32.to_be_bytes()); /* protocol 3.0 */
+        startup.extend_from_slice(params.as_bytes());
+        socket.write_all(&startup).await.unwrap();
+        let query = format!("START_REPLICATION {}\0", format_lsn(start));
+        socket
+            .write_all(&message(b'Q', query.as_bytes()))
+            .await
+            .unwrap();
+        /* Standby status update: write, flush and apply positions, time and reply flag */
+        let mut status = vec![b'r'];
+        for lsn in &[replica_flush_lsn, replica_flush_lsn, replica_flush_lsn, 0] {
+            status.extend_from_slice(&lsn.to_be_bytes());
+        }
+        status.push(0);
+        socket.write_all(&message(b'd', &status)).await.unwrap();
+
+        /* Receive WAL until the update is processed */
+        let mut buf = vec![0u8; 64 * 1024];
+        while system.min_replica_flush_lsn().is_none() {
+            let _ = timeout(Duration::from_millis(100), socket.read(&mut buf)).await;
+        }
+        drop(socket);
+        while !system.snapshot().status().senders.is_empty() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(system.min_replica_flush_lsn(), Some(replica_flush_lsn));
+
+        /* It is forgotten 10 minutes after the disconnect */
+        tokio::time::pause();
+        clock::set_clock(Arc::new(TokioClock::new(SystemTime::now())));
+        tokio::time::advance(Duration::from_secs(590)).await;
+        assert_eq!(system.min_replica_flush_lsn(), Some(replica_flush_lsn));
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert_eq!(system.min_replica_flush_lsn(), None);
+        tokio::time::resume();
+    });
+    clock::set_clock(Arc::new(SystemClock));
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,58 @@
+// Final flush report: tenants are ordered by flush_lsn, then by id, and the report is
+// written to --shutdown-report as JSON.
+use std::env;
+use std::fs;
+use walkeeper::shutdown;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::xlog_utils::*;
+
+#[test]
+fn test_shutdown_report() {
+    let dir = env::temp_dir().join(format!("test_shutdown_report_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let report_path = dir.join("report.json");
+    let mut conf = test_conf(&dir);
+    conf.shutdown_report = Some(report_path.clone());
+    let mut sessions: Vec<TestSession> = (0..3)
+        .map(|seed| TestSession::start(conf.clone(), 776 + seed).unwrap())
+        .collect();
+    let start = sessions[0].start_lsn();
+    let ends = [start + 3000, start + 1000, start + 1000];
+    for (session, &end) in sessions.iter_mut().zip(ends.iter()) {
+        session.stream(end, start, end).unwrap();
+    }
+    let systems: Vec<_> = sessions.iter().map(|s| s.system().unwrap()).collect();
+
+    let report = shutdown::flush_and_report(&conf, &systems, "test");
+    assert!(report.is_clean());
+    let mut expected: Vec<_> = sessions
+        .iter()
+        .zip(ends.iter())
+        .map(|(session, &end)| (end, session.system_id()))
+        .collect();
+    expected.sort();
+    let reported: Vec<_> = report
+        .tenants
+        .iter()
+        .map(|flush| (flush.flush_lsn.clone(), flush.tenant))
+        .collect();
+    let expected: Vec<_> = expected
+        .into_iter()
+        .map(|(lsn, id)| (format_lsn(lsn), id))
+        .collect();
+    assert_eq!(reported, expected);
+
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(&report_path).unwrap()).unwrap();
+    assert_eq!(json["reason"], "test");
+    let tenants = json["tenants"].as_array().unwrap();
+    assert_eq!(tenants.len(), 3);
+    for (tenant, (lsn, id)) in tenants.iter().zip(expected.iter()) {
+        assert_eq!(tenant["tenant"], *id);
+        assert_eq!(tenant["flush_lsn"], lsn.as_str());
+        assert_eq!(tenant["commit_lsn"], lsn.as_str());
+        assert_eq!(tenant["status"], "synced");
+    }
+    drop(sessions);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,50 @@
+// Pool of spare segments: leftovers are cleaned up on load, segments removed by WAL GC are
+// recycled while the pool is short, and all of them are zero-filled before being taken.
+use std::env;
+use std::fs;
+use walkeeper::spare_segments::{SparePool, SPARE_DIR};
+
+#[test]
+fn test_spare_segments() {
+    let dir = env::temp_dir().join(format!("test_spare_segments_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let spare_dir = dir.join(SPARE_DIR);
+    fs::create_dir_all(&spare_dir).unwrap();
+    let seg = 64 * 1024;
+
+    /* Interrupted zero-fills and segments of another size are removed on load */
+    fs::write(spare_dir.join("5.prep"), b"prep").unwrap();
+    fs::write(spare_dir.join("7"), b"short").unwrap();
+    let mut pool = SparePool::load(&dir, seg).unwrap();
+    assert!(!spare_dir.join("5.prep").exists());
+    assert!(!spare_dir.join("7").exists());
+    assert_eq!(pool.count(), 0);
+
+    /* Removed segment is recycled while the pool is short */
+    let removed = dir.join("removed");
+    fs::write(&removed, vec![0xAB; seg]).unwrap();
+    assert!(pool.recycle(&removed, 1).unwrap());
+    assert!(!removed.exists());
+    assert_eq!((pool.count(), pool.ready()), (1, 0));
+    fs::write(&removed, vec![0xAB; seg]).unwrap();
+    assert!(!pool.recycle(&removed, 1).unwrap());
+    assert!(removed.exists());
+
+    /* Recycled segment is zero-filled first, then new ones are preallocated */
+    while let Some(fill) = pool.next_fill(2) {
+        fill.run(seg, true).unwrap();
+        pool.filled(fill);
+    }
+    assert_eq!((pool.count(), pool.ready()), (2, 2));
+    let spares = vec![pool.take().unwrap(), pool.take().unwrap()];
+    assert_eq!(pool.take(), None);
+    for spare in &spares {
+        assert_eq!(fs::read(spare).unwrap(), vec![0u8; seg]);
+    }
+
+    /* Segment which couldn't be renamed into place is taken again */
+    pool.put_back(spares[0].clone());
+    assert_eq!(pool.take(), Some(spares[0].clone()));
+    assert_eq!(SparePool::load(&dir, seg).unwrap().ready(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
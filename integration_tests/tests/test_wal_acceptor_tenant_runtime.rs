@@ -0,0 +1,70 @@
+// Dedicated runtime of a tenant: its thread serves the tenant while it is loaded and
+// exits once the tenant is unloaded or released, even if the system is still referenced.
+//
+// Threads of the process are counted, so the test has its own binary.
+use std::env;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::release_tenant;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::{tenant_dir, TENANT_CONF_FILE_NAME};
+
+// Number of runtime threads of the tenant, names are truncated to 15 bytes by the kernel
+fn runtime_threads(system_id: u64) -> usize {
+    let name = format!("tenant {}", system_id);
+    let name = &name[..name.len().min(15)];
+    fs::read_dir("/proc/self/task")
+        .unwrap()
+        .filter(|entry| {
+            let comm = fs::read_to_string(entry.as_ref().unwrap().path().join("comm"));
+            comm.map(|comm| comm.trim_end() == name).unwrap_or(false)
+        })
+        .count()
+}
+
+fn wait_runtime_threads(system_id: u64, expected: usize) {
+    for _ in 0..100 {
+        if runtime_threads(system_id) == expected {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(runtime_threads(system_id), expected);
+}
+
+#[test]
+fn test_tenant_runtime() {
+    let dir = env::temp_dir().join(format!("test_tenant_runtime_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    let mut session = TestSession::start(conf.clone(), 726).unwrap();
+    let id = session.system_id();
+    let start = session.start_lsn();
+    session.stream(start + 1000, start, start).unwrap();
+    assert_eq!(runtime_threads(id), 0);
+    session.tenants().unload_system(id);
+    fs::write(
+        tenant_dir(&conf.data_dir, id).join(TENANT_CONF_FILE_NAME),
+        "dedicated_runtime = true\n",
+    )
+    .unwrap();
+
+    /* Loaded with the dedicated runtime, which stops when the tenant is unloaded */
+    session.stream(start + 2000, start, start).unwrap();
+    assert_eq!(runtime_threads(id), 1);
+    let system = session.system().unwrap();
+    session.tenants().unload_system(id);
+    wait_runtime_threads(id, 0);
+    drop(system);
+
+    session.stream(start + 3000, start, start).unwrap();
+    assert_eq!(runtime_threads(id), 1);
+    let system = session.system().unwrap();
+    release_tenant(&conf, &session.tenants(), id).unwrap();
+    wait_runtime_threads(id, 0);
+    drop(system);
+    drop(session);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,138 @@
+// Timeline commands of replication clients: TIMELINE_HISTORY serves the history file of
+// a switched timeline, and malformed TIMELINE_HISTORY and TIMELINE option of
+// START_REPLICATION are answered with an error rather than a dropped connection.
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+use walkeeper::timeline_history::history_file_name;
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::test_session::TestSession;
+use walkeeper::wal_service::{serve_connection, TenantRegistry};
+use walkeeper::xlog_utils::*;
+use walkeeper::{tenant_dir, WalAcceptorConf};
+
+// Serve libpq connections of the safekeeper in background, returns its address
+async fn start_safekeeper(conf: WalAcceptorConf) -> SocketAddr {
+    let listener = TcpListener::bind(conf.listen_addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tenants = TenantRegistry::new();
+    task::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let conf = conf.clone();
+            let tenants = tenants.clone();
+            task::spawn(async move { serve_connection(socket, &conf, tenants).await });
+        }
+    });
+    addr
+}
+
+// Backend messages up to ReadyForQuery or ErrorResponse
+async fn read_reply(socket: &mut TcpStream) -> Vec<(u8, Vec<u8>)> {
+    let mut messages = Vec::new();
+    loop {
+        let tag = socket.read_u8().await.unwrap();
+        let len = socket.read_u32().await.unwrap() as usize;
+        let mut body = vec![0u8; len - 4];
+        socket.read_exact(&mut body).await.unwrap();
+        messages.push((tag, body));
+        if tag == b'Z' || tag == b'E' {
+            return messages;
+        }
+    }
+}
+
+// Reply of the safekeeper to the query of a new replication connection
+async fn query(addr: SocketAddr, id: u64, query: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let params = format!("user\0replica\0options\0-c system.id={}\0\0", id);
+    let mut startup = Vec::new();
+    startup.extend_from_slice(&(8 + params.len() as u32).to_be_bytes());
+    startup.extend_from_slice(&196608u32.to_be_bytes()); /* protocol 3.0 */
+    startup.extend_from_slice(params.as_bytes());
+    socket.write_all(&startup).await.unwrap();
+    assert_eq!(read_reply(&mut socket).await.last().unwrap().0, b'Z');
+
+    let mut msg = vec![b'Q'];
+    msg.extend_from_slice(&(4 + query.len() as u32 + 1).to_be_bytes());
+    msg.extend_from_slice(query);
+    msg.push(0);
+    socket.write_all(&msg).await.unwrap();
+    read_reply(&mut socket).await
+}
+
+// SQLSTATE and message of ErrorResponse
+fn error_fields(reply: &[(u8, Vec<u8>)]) -> (String, String) {
+    let (tag, body) = reply.last().unwrap();
+    assert_eq!(*tag, b'E', "{:?}", reply);
+    let mut code = String::new();
+    let mut message = String::new();
+    for field in body.split(|&b| b == 0).filter(|field| !field.is_empty()) {
+        let value = String::from_utf8_lossy(&field[1..]).into_owned();
+        match field[0] {
+            b'C' => code = value,
+            b'M' => message = value,
+            _ => {}
+        }
+    }
+    (code, message)
+}
+
+#[test]
+fn test_timeline_commands() {
+    let dir = env::temp_dir().join(format!("test_timeline_commands_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let conf = test_conf(&dir);
+    let mut session = TestSession::start(conf.clone(), 769).unwrap();
+    let id = session.system_id();
+    let system_dir = tenant_dir(&conf.data_dir, id);
+    fs::create_dir_all(&system_dir).unwrap();
+    fs::write(
+        system_dir.join("tenant.toml"),
+        "pg_version_mismatch = \"new-timeline\"\n",
+    )
+    .unwrap();
+    let parent = session.timeline();
+    let start = session.start_lsn();
+    let mid = start + 1000;
+    session.stream(mid, start, mid).unwrap();
+    session.set_pg_version(140000);
+    session.stream(mid + 1000, start, mid + 1000).unwrap();
+    drop(session);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let addr = start_safekeeper(conf.clone()).await;
+        let history = format!("TIMELINE_HISTORY {}", parent + 1);
+        let reply = query(addr, id, history.as_bytes()).await;
+        let row = reply.iter().find(|(tag, _)| *tag == b'D').unwrap();
+        let fname = history_file_name(parent + 1);
+        let content = String::from_utf8_lossy(&row.1);
+        assert!(content.contains(&fname), "{}", content);
+        let line = format!("{}\t{}\t", parent, format_lsn(mid));
+        assert!(content.contains(&line), "{}", content);
+
+        /* Malformed commands fail the query, not the safekeeper */
+        let reply = query(addr, id, b"TIMELINE_HISTORY \xff").await;
+        assert_eq!(error_fields(&reply).0, "42601");
+        let reply = query(addr, id, b"TIMELINE_HISTORY 99999999999").await;
+        assert!(error_fields(&reply).1.contains("Invalid timeline"));
+        let replication = format!(
+            "START_REPLICATION {} TIMELINE 99999999999",
+            format_lsn(start)
+        );
+        let reply = query(addr, id, replication.as_bytes()).await;
+        assert!(error_fields(&reply).1.contains("Invalid timeline"));
+        let replication = format!("START_REPLICATION {} TIMELINE 7", format_lsn(start));
+        let reply = query(addr, id, replication.as_bytes()).await;
+        assert!(error_fields(&reply)
+            .1
+            .contains("not in this server's history"));
+    });
+    fs::remove_dir_all(&dir).unwrap();
+}
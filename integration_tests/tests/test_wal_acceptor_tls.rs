@@ -0,0 +1,131 @@
+// TLS of safekeeper connections: SSLRequest arriving in pieces is accepted and followed by
+// TLS handshake and an encrypted session, and a client which doesn't complete SSLRequest
+// is given up on.
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::{X509NameBuilder, X509};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::task;
+use tokio::time::sleep;
+use walkeeper::tls::{Stream, TlsConf};
+use walkeeper::wal_service::crash_test::test_conf;
+use walkeeper::wal_service::{serve_connection, TenantRegistry};
+use walkeeper::{tenant_dir, WalAcceptorConf};
+
+const TENANT: u64 = 751;
+const SSL_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+
+// Self-signed certificate and its key in PEM files of the directory
+fn self_signed_tls(dir: &Path) -> TlsConf {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    let cert_file = dir.join("server.crt");
+    let key_file = dir.join("server.key");
+    fs::write(&cert_file, cert.build().to_pem().unwrap()).unwrap();
+    fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    TlsConf::load(&cert_file, &key_file).unwrap()
+}
+
+// Serve connections of the safekeeper in background, returns its address
+async fn start_safekeeper(conf: WalAcceptorConf) -> std::net::SocketAddr {
+    let listener = TcpListener::bind(conf.listen_addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tenants = TenantRegistry::new();
+    task::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let conf = conf.clone();
+            let tenants = tenants.clone();
+            task::spawn(async move { serve_connection(socket, &conf, tenants).await });
+        }
+    });
+    addr
+}
+
+#[test]
+fn test_tls_session() {
+    let dir = env::temp_dir().join(format!("test_tls_session_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut conf = test_conf(&dir);
+    conf.tls = Some(self_signed_tls(&dir));
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let addr = start_safekeeper(conf.clone()).await;
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(&SSL_REQUEST[..4]).await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        socket.write_all(&SSL_REQUEST[4..]).await.unwrap();
+        let mut answer = [0u8; 1];
+        socket.read_exact(&mut answer).await.unwrap();
+        assert_eq!(&answer, b"S");
+
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let mut stream = tokio_native_tls::TlsConnector::from(connector)
+            .connect("localhost", socket)
+            .await
+            .unwrap();
+        let params = format!("user\0test\0options\0-c system.id={}\0\0", TENANT);
+        let mut startup = Vec::new();
+        startup.extend_from_slice(&(8 + params.len() as u32).to_be_bytes());
+        startup.extend_from_slice(&196608u32.to_be_bytes()); /* protocol 3.0 */
+        startup.extend_from_slice(params.as_bytes());
+        stream.write_all(&startup).await.unwrap();
+        /* AuthenticationOk and ReadyForQuery */
+        let mut reply = [0u8; 15];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[0], b'R');
+        assert_eq!(reply[5..9], [0, 0, 0, 0]);
+        assert_eq!(reply[9], b'Z');
+    });
+    assert!(tenant_dir(&conf.data_dir, TENANT).exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_tls_negotiation_timeout() {
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        tokio::time::pause();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        /* Length of SSLRequest, but never its code */
+        client.write_all(&SSL_REQUEST[..4]).await.unwrap();
+        match Stream::accept(socket, None).await {
+            Ok(_) => panic!("incomplete SSLRequest is accepted"),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+        }
+    });
+}
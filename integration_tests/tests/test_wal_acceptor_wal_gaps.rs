@@ -0,0 +1,63 @@
+// WAL gaps of a tenant directory: missing segments and segments shorter than the WAL
+// they should contain up to flush_lsn, the oldest segment, and no panic on odd directory
+// contents.
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::os::unix::ffi::OsStrExt;
+use walkeeper::xlog_utils::*;
+
+const WAL_SEG_SIZE: usize = 16 * 1024 * 1024;
+
+#[test]
+fn test_find_wal_gaps() {
+    let dir = env::temp_dir().join(format!("test_wal_gaps_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    /* Directory of the tenant is gone */
+    assert!(find_wal_gaps(&dir, WAL_SEG_SIZE, 1).is_err());
+    assert!(find_start_of_wal(&dir, WAL_SEG_SIZE).is_err());
+    fs::create_dir_all(&dir).unwrap();
+    assert!(find_wal_gaps(&dir, WAL_SEG_SIZE, 1).unwrap().is_empty());
+    assert_eq!(find_start_of_wal(&dir, WAL_SEG_SIZE).unwrap(), None);
+
+    let segment = |segno: XLogSegNo, suffix: &str, size: usize| {
+        let fname = XLogFileName(1, segno, WAL_SEG_SIZE) + suffix;
+        let file = File::create(dir.join(fname)).unwrap();
+        file.set_len(size as u64).unwrap();
+    };
+    segment(1, "", WAL_SEG_SIZE);
+    segment(4, "", WAL_SEG_SIZE);
+    segment(5, ".partial", 1000);
+    /* Not a segment, and not even UTF-8 */
+    File::create(dir.join(OsStr::from_bytes(b"\xff\xfe"))).unwrap();
+    fs::write(dir.join("safekeeper.control"), b"").unwrap();
+    assert_eq!(find_start_of_wal(&dir, WAL_SEG_SIZE).unwrap(), Some(1));
+
+    let at = |segno: XLogSegNo, offset: u32| XLogSegNoOffsetToRecPtr(segno, offset, WAL_SEG_SIZE);
+    assert!(find_wal_gaps(&dir, WAL_SEG_SIZE, 0).unwrap().is_empty());
+    assert_eq!(
+        find_wal_gaps(&dir, WAL_SEG_SIZE, at(5, 1000)).unwrap(),
+        vec![WalGap::Missing(2, 3)]
+    );
+    assert_eq!(
+        find_wal_gaps(&dir, WAL_SEG_SIZE, at(5, 5000)).unwrap(),
+        vec![WalGap::Missing(2, 3), WalGap::Truncated(5, 1000)]
+    );
+    /* Segments written after the partial one are lost too */
+    assert_eq!(
+        find_wal_gaps(&dir, WAL_SEG_SIZE, at(7, 10)).unwrap(),
+        vec![
+            WalGap::Missing(2, 3),
+            WalGap::Truncated(5, 1000),
+            WalGap::Missing(6, 7)
+        ]
+    );
+
+    /* Partial and completed files of the same segment: the longer one counts */
+    segment(5, "", WAL_SEG_SIZE);
+    assert_eq!(
+        find_wal_gaps(&dir, WAL_SEG_SIZE, at(6, 0)).unwrap(),
+        vec![WalGap::Missing(2, 3)]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
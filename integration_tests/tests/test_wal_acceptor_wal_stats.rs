@@ -0,0 +1,133 @@
+// WAL record scanner behind --wal-stats: resource manager and length of every record
+// however WAL is split into messages, including records spanning pages, timestamp of
+// the last transaction end record, and resynchronization after discontinuous input.
+use walkeeper::xlog_utils::*;
+
+const WAL_SEG_SIZE: usize = 16 * 1024 * 1024;
+const START_LSN: XLogRecPtr = WAL_SEG_SIZE as u64;
+
+// WAL written from the start of a segment, with page headers inserted at page boundaries
+struct WalWriter {
+    data: Vec<u8>,
+}
+
+impl WalWriter {
+    fn lsn(&self) -> XLogRecPtr {
+        START_LSN + self.data.len() as u64
+    }
+
+    fn page_header(&mut self, rem_len: usize) {
+        let long = XLogSegmentOffset(self.lsn(), WAL_SEG_SIZE) == 0;
+        let mut info = if long { XLP_LONG_HEADER } else { 0 };
+        if rem_len != 0 {
+            info |= XLP_FIRST_IS_CONTRECORD;
+        }
+        let lsn = self.lsn();
+        self.data.extend_from_slice(&XLOG_PAGE_MAGIC.to_le_bytes());
+        self.data.extend_from_slice(&info.to_le_bytes());
+        self.data.extend_from_slice(&1u32.to_le_bytes()); /* xlp_tli */
+        self.data.extend_from_slice(&lsn.to_le_bytes());
+        self.data.extend_from_slice(&(rem_len as u32).to_le_bytes());
+        self.data.extend_from_slice(&[0u8; 4]);
+        if long {
+            self.data.extend_from_slice(&[0u8; 8]); /* xlp_sysid */
+            self.data
+                .extend_from_slice(&(WAL_SEG_SIZE as u32).to_le_bytes());
+            self.data
+                .extend_from_slice(&(XLOG_BLCKSZ as u32).to_le_bytes());
+        }
+    }
+
+    // Append record, returns its start
+    fn record(&mut self, rmid: u8, info: u8, body: &[u8]) -> XLogRecPtr {
+        let tot_len = XLOG_SIZE_OF_XLOG_RECORD + body.len();
+        let mut rec = vec![0u8; XLOG_SIZE_OF_XLOG_RECORD];
+        rec[0..4].copy_from_slice(&(tot_len as u32).to_le_bytes());
+        rec[XLOG_RECORD_INFO_OFFS] = info;
+        rec[XLOG_RECORD_RMID_OFFS] = rmid;
+        rec.extend_from_slice(body);
+        rec.resize((tot_len + 7) & !7, 0);
+
+        if self.data.len() % XLOG_BLCKSZ == 0 {
+            self.page_header(0);
+        }
+        let start = self.lsn();
+        let mut written = 0;
+        while written < rec.len() {
+            if self.data.len() % XLOG_BLCKSZ == 0 {
+                self.page_header(tot_len.saturating_sub(written));
+            }
+            let n = (rec.len() - written).min(XLOG_BLCKSZ - self.data.len() % XLOG_BLCKSZ);
+            self.data.extend_from_slice(&rec[written..written + n]);
+            written += n;
+        }
+        start
+    }
+}
+
+// Body of transaction end record: optional headers followed by the main data
+fn xact_body(headers: &[u8], xact_time: TimestampTz) -> Vec<u8> {
+    let mut body = headers.to_vec();
+    body.extend_from_slice(&[XLR_BLOCK_ID_DATA_SHORT, 8]);
+    body.extend_from_slice(&xact_time.to_le_bytes());
+    body
+}
+
+// Records reported by the scanner fed with WAL from lsn in chunks, and the last xact_time
+fn scan(wal: &WalWriter, lsn: XLogRecPtr, chunk: usize) -> (Vec<(u8, u32)>, Option<TimestampTz>) {
+    let mut scanner = WalRecordScanner::new(WAL_SEG_SIZE, false);
+    let mut records = Vec::new();
+    let from = (lsn - START_LSN) as usize;
+    for (i, part) in wal.data[from..].chunks(chunk).enumerate() {
+        let pos = lsn + (i * chunk) as u64;
+        scanner.feed(pos, part, |rmid, len| records.push((rmid, len)));
+    }
+    (records, scanner.take_xact_time())
+}
+
+#[test]
+fn test_wal_record_scanner() {
+    let mut wal = WalWriter { data: Vec::new() };
+    wal.record(10, 0, &[7u8; 76]);
+    wal.record(RM_XACT_ID, XLOG_XACT_COMMIT, &xact_body(&[], 1000));
+    let spanning = wal.record(11, 0, &vec![7u8; 20000 - XLOG_SIZE_OF_XLOG_RECORD]);
+    let origin = [XLR_BLOCK_ID_ORIGIN, 1, 0];
+    let toplevel_xid = [XLR_BLOCK_ID_TOPLEVEL_XID, 1, 2, 3, 4];
+    let abort_body = xact_body(&[&origin[..], &toplevel_xid[..]].concat(), 2000);
+    let abort = wal.record(RM_XACT_ID, XLOG_XACT_ABORT, &abort_body);
+    wal.record(200, 0, &[7u8; 26]);
+    /* Prepare is not the end of transaction */
+    wal.record(RM_XACT_ID, 0x10, &xact_body(&[], 3000));
+    /* The spanning record continues on the next page and ends on the page after it */
+    let page = |lsn: XLogRecPtr| lsn / XLOG_BLCKSZ as u64;
+    assert_eq!(page(abort), page(spanning) + 2);
+
+    let expected = vec![
+        (10, 100),
+        (RM_XACT_ID, 34),
+        (11, 20000),
+        (RM_XACT_ID, 42),
+        (200, 50),
+        (RM_XACT_ID, 34),
+    ];
+    for &chunk in &[1, 7, 100, 8192, 10000, wal.data.len()] {
+        assert_eq!(scan(&wal, START_LSN, chunk), (expected.clone(), Some(2000)));
+    }
+
+    /* Timestamp is taken once, the latest one since the previous call */
+    let mut scanner = WalRecordScanner::new(WAL_SEG_SIZE, false);
+    let split = (spanning - START_LSN) as usize;
+    scanner.feed(START_LSN, &wal.data[..split], |_, _| {});
+    assert_eq!(scanner.take_xact_time(), Some(1000));
+    assert_eq!(scanner.take_xact_time(), None);
+    scanner.feed(spanning, &wal.data[split..], |_, _| {});
+    assert_eq!(scanner.take_xact_time(), Some(2000));
+
+    /*
+     * Starting inside of a record, scanner skips the page continuing it and resyncs at
+     * the page where it ends
+     */
+    let (records, xact_time) = scan(&wal, spanning + 1000, 1000);
+    assert_eq!(records, expected[3..].to_vec());
+    assert_eq!(xact_time, Some(2000));
+}
@@ -0,0 +1,112 @@
+//
+//   Network-level access control: which peer addresses may connect to a listener.
+//
+//   Checked right after accept, before anything is read from the socket, so it is
+//   a cheap first line of defense on networks where authentication is not yet enabled.
+//   A peer is rejected if it matches any denied network, or if allowed networks are
+//   configured and it matches none of them.
+//
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+//
+// Network in CIDR notation, e.g. 10.0.0.0/8 or fd00::/8. Bare address means the host only.
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let prefix_len = match self.addr {
+            IpAddr::V4(_) => self.prefix_len + 96,
+            IpAddr::V6(_) => self.prefix_len,
+        };
+        prefix_matches(&as_ipv6(self.addr), &as_ipv6(ip), prefix_len)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in {:?}", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => match len.parse::<u8>() {
+                Ok(len) if len <= max_len => len,
+                _ => return Err(format!("invalid prefix length in {:?}", s)),
+            },
+            None => max_len,
+        };
+        Ok(Cidr {
+            addr: addr,
+            prefix_len: prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/* IPv4 peers of dual-stack sockets are seen as IPv4-mapped IPv6 addresses, compare in that form */
+fn as_ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let rest_bits = prefix_len % 8;
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>, /* empty means any peer which is not denied */
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+impl fmt::Display for AccessList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |nets: &[Cidr]| {
+            nets.iter()
+                .map(|net| net.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        write!(f, "allow={} deny={}", join(&self.allow), join(&self.deny))
+    }
+}
@@ -0,0 +1,485 @@
+//
+//   Line-based admin protocol on the unix socket in the data directory.
+//
+//   Each request is a single line. Response consists of zero or more lines
+//   of output followed by "OK" or "ERROR: <message>" line.
+//
+use log::*;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::cmp::min;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task;
+
+use crate::diagnostics;
+use crate::handoff;
+use crate::log_filter;
+use crate::metrics;
+use crate::node_file;
+use crate::partial_segment::list_partial_segments;
+use crate::peer_check;
+use crate::recovery_log;
+use crate::tenant_takeover;
+use crate::xlog_utils::*;
+use crate::{parse_tenant_id, tenant_dir};
+use crate::pq_protocol::Result;
+use crate::wal_service::{self, TenantRegistry};
+use crate::WalAcceptorConf;
+
+pub const ADMIN_SOCKET_NAME: &str = "wal_acceptor.sock";
+
+const HELP: &str = "\
+status [tenant]         show identity of the node and state of all tenants, or state of the specified one
+list-tenants            list identifiers of loaded tenants and of tenants in the data directory
+create-tenant <tenant>  create directory and control file of a new tenant
+delete-tenant <tenant>  delete WAL and control file of the tenant (no proposer or WAL senders may be connected)
+pause <tenant>          stop accepting WAL for the tenant
+resume <tenant>         accept WAL for the tenant again
+drain                   reject new connections, pause all tenants and stop WAL senders
+release <tenant>        disconnect, flush and unload the tenant, releasing its lock for another process
+metrics                 per-tenant metrics in Prometheus text format
+diagnostics             runtime scheduling lag, lock waits and longest running connections
+connections             list all live connections with their ids
+terminate-connection <id>
+                        tell the peer that the connection is terminated and close it
+cancel-catchup <tenant> [peer]
+                        stop catching up WAL senders of the tenant
+wal-gaps <tenant>       report missing and truncated WAL segments up to flush_lsn
+compare-peer <tenant> <host:port> [password]
+                        compare WAL of the tenant with peer safekeeper, report the first mismatching LSN
+lsn-by-time <tenant> <time>
+                        closest LSN received at or before the RFC 3339 time, e.g. 2021-05-20T14:05:00Z
+recovery-log [tenant]   destructive operations (WAL truncation, GC, deletion) of all tenants or of the specified one
+gc-now [tenant]         wake up WAL GC of all tenants or of the specified one
+gc-propose <tenant> <lsn>
+                        confirm removal of WAL below the LSN, reporting the effective cutoff
+gc-status <tenant>      progress of the last proposed WAL removal
+pageserver-checkpoint <tenant> <lsn>
+                        record that pageserver has checkpointed the tenant up to the LSN
+at-rest <tenant> [compression=on|off] [encryption=on|off]
+                        show or change encoding of segments completed from now on
+at-rest-rewrite <tenant>
+                        rewrite all completed segments to the current at-rest policy in background
+pg-versions <tenant>    Postgres versions which have written WAL of the tenant
+ack-pg-version <tenant> <version>
+                        accept proposers running this Postgres version and clear mismatch warning
+log-level [filter]      show or set log filter, e.g. \"info,walkeeper::wal_service=trace\"
+listeners               addresses the WAL service and HTTP API listen at
+listen <addr>[,<addr>...]
+                        listen at these addresses instead of the current ones, keeping accepted connections
+http-listen <addr>|off  move HTTP API to the address or stop it, finishing requests in flight
+handoff                 pass listening socket to the peer and exit (used by wal_acceptor --takeover)
+help                    show this message
+";
+
+pub async fn admin_loop(conf: &WalAcceptorConf, tenants: Arc<TenantRegistry>) -> Result<()> {
+    let socket_path = conf.data_dir.join(ADMIN_SOCKET_NAME);
+    // Socket file of the previous instance is not removed on crash
+    if socket_path.exists() {
+        fs::remove_file(&socket_path)?;
+    }
+    let listener = UnixListener::bind(&socket_path)?;
+    /* Socket path is taken over by the next process, this one stays reachable by pid */
+    if let Err(e) = tenant_takeover::link_process_socket(&conf.data_dir, &socket_path) {
+        warn!("Admin socket is not reachable by pid, tenants can't be taken over from this process: {}", e);
+    }
+    info!("Admin socket is listening at {:?}", socket_path);
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                let conf = conf.clone();
+                let tenants = tenants.clone();
+                task::spawn(async move {
+                    if let Err(e) = serve_admin_connection(socket, &conf, &tenants).await {
+                        error!("admin connection error: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept admin connection: {}", e),
+        }
+    }
+}
+
+async fn serve_admin_connection(
+    socket: UnixStream,
+    conf: &WalAcceptorConf,
+    tenants: &TenantRegistry,
+) -> Result<()> {
+    let socket_fd = socket.as_raw_fd();
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        /* Handoff passes descriptor through the connection itself, so it is not a regular command */
+        if line.trim() == "handoff" {
+            match handoff::send_listener(socket_fd) {
+                Ok(()) => handoff::exit_after_handoff(conf, tenants).await,
+                Err(e) => writer.write_all(format!("ERROR: {}\n", e).as_bytes()).await?,
+            }
+            continue;
+        }
+        /* Comparison with a peer goes over the network, so it is awaited here */
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if let ["compare-peer", tenant, peer, password @ ..] = args.as_slice() {
+            let password = match password {
+                [] => None,
+                [password] => Some(*password),
+                _ => {
+                    writer.write_all(b"ERROR: Too many arguments\n").await?;
+                    continue;
+                }
+            };
+            let response = match peer_check::compare(conf, tenants, tenant, peer, password).await {
+                Ok(output) => output + "OK\n",
+                Err(e) => format!("ERROR: {}\n", e),
+            };
+            writer.write_all(response.as_bytes()).await?;
+            continue;
+        }
+        let response = match execute_command(conf, tenants, line.trim()) {
+            Ok(output) => output + "OK\n",
+            Err(e) => format!("ERROR: {}\n", e),
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn get_system(tenants: &TenantRegistry, s: &str) -> Result<Arc<wal_service::System>> {
+    let id = parse_tenant_id(s)?;
+    match tenants.get_system(id) {
+        Some(system) => Ok(system),
+        None => {
+            io_error!("Unknown tenant {}", id);
+        }
+    }
+}
+
+fn describe_system(conf: &WalAcceptorConf, system: &wal_service::System) -> String {
+    let snapshot = system.snapshot();
+    let mut output = snapshot.describe() + "\n";
+    /* More than one partial segment means leftovers which are not reconciled */
+    let partials = list_partial_segments(&tenant_dir(&conf.data_dir, system.id()));
+    if partials.len() > 1 {
+        output += &format!(
+            "  {} partial segments: {}\n",
+            partials.len(),
+            partials.join(", ")
+        );
+    }
+    for sender in snapshot.describe_senders() {
+        output += &format!("  {}\n", sender);
+    }
+    for catchup in snapshot.describe_catchups() {
+        output += &format!("  {}\n", catchup);
+    }
+    for wal_op in snapshot.describe_wal_ops() {
+        output += &format!("  {}\n", wal_op);
+    }
+    output
+}
+
+//
+// Check that tenant has contiguous WAL from the oldest retained segment till flush_lsn
+//
+fn wal_gaps_report(conf: &WalAcceptorConf, system: &wal_service::System) -> Result<String> {
+    let wal_seg_size = system.get_wal_seg_size();
+    if wal_seg_size == 0 {
+        io_error!("WAL segment size of system {} is not known yet", system.id());
+    }
+    let flush_lsn = system.get_flush_lsn();
+    let system_dir = tenant_dir(&conf.data_dir, system.id());
+    let wal_start = match find_start_of_wal(&system_dir, wal_seg_size)? {
+        Some(segno) => XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size),
+        None => flush_lsn,
+    };
+    let gaps = find_wal_gaps(&system_dir, wal_seg_size, flush_lsn)?;
+    let mut output = String::new();
+    for gap in &gaps {
+        match gap {
+            WalGap::Missing(first, last) => {
+                output += &format!(
+                    "missing segments {}-{}: WAL {}-{}\n",
+                    first,
+                    last,
+                    format_lsn(XLogSegNoOffsetToRecPtr(*first, 0, wal_seg_size)),
+                    format_lsn(XLogSegNoOffsetToRecPtr(*last + 1, 0, wal_seg_size))
+                )
+            }
+            WalGap::Truncated(segno, size) => {
+                output += &format!(
+                    "truncated segment {}: {} bytes, WAL {}-{} is lost\n",
+                    segno,
+                    size,
+                    format_lsn(XLogSegNoOffsetToRecPtr(*segno, *size as u32, wal_seg_size)),
+                    format_lsn(min(
+                        XLogSegNoOffsetToRecPtr(*segno + 1, 0, wal_seg_size),
+                        flush_lsn
+                    ))
+                )
+            }
+        }
+    }
+    output += &format!(
+        "retained WAL {}-{}: {}\n",
+        format_lsn(wal_start),
+        format_lsn(flush_lsn),
+        if gaps.is_empty() {
+            "contiguous".to_string()
+        } else {
+            format!("{} gaps", gaps.len())
+        }
+    );
+    Ok(output)
+}
+
+fn parse_listen_addr(addr: &str) -> Result<SocketAddr> {
+    match addr.parse() {
+        Ok(addr) => Ok(addr),
+        Err(_) => {
+            io_error!("Invalid listen address {}, expected e.g. 0.0.0.0:5454", addr);
+        }
+    }
+}
+
+// Parse RFC 3339 time into Postgres timestamp
+pub fn parse_timestamp(time: &str) -> Result<TimestampTz> {
+    match chrono::DateTime::parse_from_rfc3339(time) {
+        Ok(time) => Ok(to_pg_timestamp(time.into())),
+        Err(e) => {
+            io_error!("Invalid time {}: {}", time, e);
+        }
+    }
+}
+
+//
+// Execute admin command and return its output
+//
+pub fn execute_command(
+    conf: &WalAcceptorConf,
+    tenants: &TenantRegistry,
+    cmd: &str,
+) -> Result<String> {
+    let args: Vec<&str> = cmd.split_whitespace().collect();
+    let mut output = String::new();
+    match args.as_slice() {
+        [] => {}
+        ["help"] => output.push_str(HELP),
+        ["status"] => {
+            if let Some(node) = node_file::identity() {
+                output += &(node.describe() + "\n");
+            }
+            for system in tenants.get_systems() {
+                output += &describe_system(conf, &system);
+            }
+        }
+        ["status", tenant] => output += &describe_system(conf, &get_system(tenants, tenant)?),
+        ["cancel-catchup", tenant] => {
+            let n = get_system(tenants, tenant)?.cancel_catchup(None);
+            output += &format!("cancelled {} catch-ups\n", n);
+        }
+        ["cancel-catchup", tenant, peer] => {
+            let peer = match peer.parse() {
+                Ok(peer) => peer,
+                Err(_) => {
+                    io_error!("Invalid peer address {}", peer);
+                }
+            };
+            let n = get_system(tenants, tenant)?.cancel_catchup(Some(peer));
+            output += &format!("cancelled {} catch-ups\n", n);
+        }
+        ["metrics"] => output += &metrics::render_metrics(tenants, conf.metrics_top_tenants),
+        ["diagnostics"] => output += &diagnostics::report().describe(),
+        ["connections"] => {
+            for conn in diagnostics::connections() {
+                conn.describe(&mut output);
+            }
+        }
+        ["terminate-connection", id] => {
+            let id = match id.trim_start_matches('#').parse() {
+                Ok(id) => id,
+                Err(_) => {
+                    io_error!("Invalid connection id {}", id);
+                }
+            };
+            if !diagnostics::terminate_connection(id) {
+                io_error!("Unknown connection {}", id);
+            }
+            info!("Connection {} is asked to terminate", id);
+        }
+        ["wal-gaps", tenant] => output += &wal_gaps_report(conf, &get_system(tenants, tenant)?)?,
+        ["lsn-by-time", tenant, time] => {
+            let lsn = get_system(tenants, tenant)?.lsn_by_time(parse_timestamp(time)?)?;
+            output += &format!("{}\n", format_lsn(lsn));
+        }
+        ["list-tenants"] => {
+            for id in tenants.list_tenants(conf)? {
+                output += &format!("{}\n", id);
+            }
+        }
+        ["create-tenant", tenant] => {
+            let id = parse_tenant_id(tenant)?;
+            if tenants.get_system(id).is_some() {
+                io_error!("Tenant {} is already loaded", id);
+            }
+            wal_service::init_tenant(conf, id, None, None)?;
+        }
+        ["delete-tenant", tenant] => {
+            wal_service::delete_tenant(conf, tenants, parse_tenant_id(tenant)?, "admin")?;
+        }
+        ["pause", tenant] => {
+            get_system(tenants, tenant)?.set_paused(true);
+            info!("WAL ingest for system {} is paused", tenant);
+        }
+        ["resume", tenant] => {
+            get_system(tenants, tenant)?.set_paused(false);
+            info!("WAL ingest for system {} is resumed", tenant);
+        }
+        ["drain"] => {
+            let n_tenants = tenants.drain();
+            info!("Safekeeper is drained");
+            output += &format!("drained {} tenants\n", n_tenants);
+        }
+        ["release", tenant] => {
+            let flush = wal_service::release_tenant(conf, tenants, parse_tenant_id(tenant)?)?;
+            output += &format!("{}\n", flush.describe());
+        }
+        ["recovery-log"] => {
+            for entry in recovery_log::read(&conf.data_dir, None)? {
+                output += &format!("{}\n", entry.describe());
+            }
+        }
+        ["recovery-log", tenant] => {
+            let id = parse_tenant_id(tenant)?;
+            for entry in recovery_log::read(&conf.data_dir, Some(id))? {
+                output += &format!("{}\n", entry.describe());
+            }
+        }
+        ["gc-now"] => {
+            for system in tenants.get_systems() {
+                system.request_gc();
+            }
+        }
+        ["gc-now", tenant] => get_system(tenants, tenant)?.request_gc(),
+        ["gc-propose", tenant, lsn] => {
+            let lsn = wal_service::parse_lsn(lsn)?;
+            let proposal = get_system(tenants, tenant)?.propose_gc(conf, lsn, "admin")?;
+            output += &proposal.describe();
+        }
+        ["gc-status", tenant] => match get_system(tenants, tenant)?.gc_proposal() {
+            Some(proposal) => output += &proposal.describe(),
+            None => output += "no WAL removal was proposed since start\n",
+        },
+        ["pageserver-checkpoint", tenant, lsn] => {
+            let lsn = wal_service::parse_lsn(lsn)?;
+            get_system(tenants, tenant)?.set_remote_consistent_lsn(conf, lsn)?;
+            info!("Pageserver checkpointed system {} up to {}", tenant, format_lsn(lsn));
+        }
+        ["at-rest", tenant] => output += &get_system(tenants, tenant)?.describe_at_rest(),
+        ["at-rest", tenant, settings @ ..] => {
+            let system = get_system(tenants, tenant)?;
+            let mut policy = system.at_rest_policy();
+            policy.apply_settings(settings)?;
+            system.set_at_rest_policy(conf, policy)?;
+            output += &system.describe_at_rest();
+        }
+        ["at-rest-rewrite", tenant] => {
+            output += &get_system(tenants, tenant)?.start_at_rest_rewrite()?.describe();
+        }
+        ["pg-versions", tenant] => output += &get_system(tenants, tenant)?.pg_versions().describe(),
+        ["ack-pg-version", tenant, version] => {
+            let version = match version.parse() {
+                Ok(version) => version,
+                Err(_) => {
+                    io_error!("Invalid Postgres version {}, expected e.g. 140002", version);
+                }
+            };
+            get_system(tenants, tenant)?.ack_pg_version(conf, version)?;
+            info!("Postgres version {} of system {} is acknowledged", version, tenant);
+        }
+        ["listeners"] => output += &tenants.listeners().describe(),
+        ["listen", addrs] => {
+            let addrs = addrs
+                .split(',')
+                .map(parse_listen_addr)
+                .collect::<Result<Vec<_>>>()?;
+            tenants.listeners().set_wal_addrs(&addrs)?;
+            output += &tenants.listeners().describe();
+        }
+        ["http-listen", "off"] => tenants.listeners().set_http_addr(None)?,
+        ["http-listen", addr] => {
+            tenants.listeners().set_http_addr(Some(parse_listen_addr(addr)?))?;
+            output += &tenants.listeners().describe();
+        }
+        ["log-level"] => {
+            output += &format!("{}\n", log_filter::get_log_filter());
+        }
+        ["log-level", spec] => {
+            log_filter::set_log_filter(spec)?;
+            info!("Log filter is set to {}", log_filter::get_log_filter());
+        }
+        _ => {
+            io_error!("Unknown command '{}', try 'help'", cmd);
+        }
+    }
+    Ok(output)
+}
+
+//
+// Client side: send commands to running wal_acceptor.
+// If command is not specified, read commands from stdin.
+//
+pub fn admin_client(data_dir: &Path, command: Option<String>) -> Result<()> {
+    let stream = StdUnixStream::connect(data_dir.join(ADMIN_SOCKET_NAME))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let stdin = io::stdin();
+    loop {
+        let cmd = match &command {
+            Some(cmd) => cmd.clone(),
+            None => {
+                print!("> ");
+                io::stdout().flush()?;
+                let mut line = String::new();
+                if stdin.lock().read_line(&mut line)? == 0 {
+                    return Ok(());
+                }
+                line
+            }
+        };
+        writer.write_all(format!("{}\n", cmd.trim()).as_bytes())?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed by wal_acceptor",
+                ));
+            }
+            if line == "OK\n" {
+                break;
+            }
+            if line.starts_with("ERROR: ") {
+                if command.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        line["ERROR: ".len()..].trim_end().to_string(),
+                    ));
+                }
+                eprint!("{}", line);
+                break;
+            }
+            print!("{}", line);
+        }
+        if command.is_some() {
+            return Ok(());
+        }
+    }
+}
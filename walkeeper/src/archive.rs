@@ -0,0 +1,182 @@
+//
+//   Archive of completed WAL segments in S3.
+//
+//   With --archive, an archiver task of each tenant uploads sealed segments (complete
+//   ones, below commit_lsn, so that WAL which may still be overwritten by a new proposer
+//   isn't archived) to the bucket as <prefix>/<tenant>/<segment file name>, in LSN
+//   order, every ARCHIVE_INTERVAL and as soon as pageserver reports a checkpoint. End
+//   of the archived WAL is recorded in the control file after each segment, so nothing
+//   is uploaded twice across restarts. WAL GC then keeps locally WAL above both the
+//   archived horizon and restart_lsn.
+//   Replicas starting replication below the local WAL get archived segments restored
+//   on demand.
+//
+use log::*;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::task;
+
+use crate::at_rest;
+use crate::clock::sleep;
+use crate::metrics::WalOp;
+use crate::object_storage::BucketConf;
+use crate::pq_protocol::{Result, SystemId};
+use crate::wal_service::System;
+use crate::xlog_utils::*;
+use crate::{tenant_dir, WalAcceptorConf};
+
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(10);
+const RESTORED_SUFFIX: &str = ".restored";
+
+//
+// Archiver of a tenant, exits when the tenant is unloaded
+//
+pub async fn archive_loop(system: Weak<System>, conf: Arc<WalAcceptorConf>) {
+    loop {
+        match system.upgrade() {
+            Some(system) => {
+                tokio::select! {
+                    _ = system.horizon_changed() => {}
+                    _ = sleep(ARCHIVE_INTERVAL) => {}
+                }
+            }
+            None => return,
+        }
+        let system = match system.upgrade() {
+            Some(system) => system,
+            None => return,
+        };
+        let id = system.id();
+        let archive_conf = conf.clone();
+        match task::spawn_blocking(move || archive_segments(&system, &archive_conf)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("Failed to archive WAL of system {}: {}", id, e),
+            Err(e) => error!("Archiver of system {} failed: {}", id, e),
+        }
+    }
+}
+
+//
+// Upload sealed segments following the archived horizon and advance it.
+// Blocks, so it is run on the blocking thread pool. Returns the number of
+// uploaded segments.
+//
+pub fn archive_segments(system: &System, conf: &WalAcceptorConf) -> Result<usize> {
+    let bucket = match &conf.archive {
+        Some(bucket) => bucket,
+        None => return Ok(0),
+    };
+    let (wal_seg_size, end_lsn, archived_lsn) = match system.archive_position() {
+        Some(position) => position,
+        None => return Ok(0),
+    };
+    let system_dir = tenant_dir(&conf.data_dir, system.id());
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(&system_dir)? {
+        let fname = entry?.file_name().to_string_lossy().into_owned();
+        if IsXLogFileName(&fname) {
+            let (segno, _tli) = XLogFromFileName(&fname, wal_seg_size);
+            let seg_start = XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size);
+            let seg_end = XLogSegNoOffsetToRecPtr(segno + 1, 0, wal_seg_size);
+            if seg_start >= archived_lsn && seg_end <= end_lsn {
+                segments.push((segno, fname));
+            }
+        }
+    }
+    /* Of segments of several timelines the latest one is archived */
+    segments.sort();
+    segments.dedup_by(|next, prev| {
+        if next.0 == prev.0 {
+            *prev = next.clone();
+            true
+        } else {
+            false
+        }
+    });
+
+    let mut archived = 0;
+    let mut next_lsn = archived_lsn;
+    for (segno, fname) in segments {
+        let seg_start = XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size);
+        /* Archived WAL is contiguous, nothing is uploaded past a gap */
+        if next_lsn != 0 && seg_start != next_lsn {
+            warn!(
+                "WAL of system {} at {} is missing, archiving stops there",
+                system.id(),
+                format_lsn(next_lsn)
+            );
+            break;
+        }
+        let data = at_rest::read_plain(&system_dir.join(&fname), conf.at_rest_key.as_ref())?;
+        let key = bucket.tenant_prefix(system.id()) + &fname;
+        Handle::current().block_on(bucket.put(&key, &data))?;
+        system.account_wal_op(WalOp::Archive, 1, data.len() as u64);
+        next_lsn = seg_start + wal_seg_size as u64;
+        system.set_archived_lsn(next_lsn)?;
+        debug!("Archived segment {} of system {}", fname, system.id());
+        archived += 1;
+    }
+    if archived > 0 {
+        info!("Archived {} WAL segments of system {}", archived, system.id());
+    }
+    Ok(archived)
+}
+
+//
+// Download archived segment of the tenant for a WAL sender. Of segments of several
+// timelines the latest one not after the requested timeline is taken. The segment
+// is staged in the tenant directory and unlinked once opened, so it takes disk space
+// only while it is streamed.
+//
+pub async fn restore_segment(
+    bucket: &BucketConf,
+    system_dir: &Path,
+    tenant: SystemId,
+    segno: XLogSegNo,
+    timeline: TimeLineID,
+    wal_seg_size: usize,
+) -> Result<File> {
+    let prefix = bucket.tenant_prefix(tenant);
+    let fname = bucket
+        .list(prefix.clone())
+        .await?
+        .iter()
+        .filter_map(|key| key.strip_prefix(&prefix))
+        .filter(|fname| {
+            IsXLogFileName(fname) && {
+                let (seg, tli) = XLogFromFileName(fname, wal_seg_size);
+                seg == segno && tli <= timeline
+            }
+        })
+        .max()
+        .map(|fname| fname.to_string());
+    let fname = match fname {
+        Some(fname) => fname,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("segment {} of system {} is not archived", segno, tenant),
+            ))
+        }
+    };
+    let data = bucket.get(&(prefix + &fname)).await?;
+    if data.len() != wal_seg_size {
+        io_error!(
+            "Archived segment {} of system {} has size {} instead of {}",
+            fname,
+            tenant,
+            data.len(),
+            wal_seg_size
+        );
+    }
+    let path = system_dir.join(fname.clone() + RESTORED_SUFFIX);
+    fs::write(&path, &data)?;
+    let file = File::open(&path);
+    fs::remove_file(&path)?;
+    info!("Restored segment {} of system {} from archive", fname, tenant);
+    file
+}
@@ -0,0 +1,549 @@
+//
+//   At-rest compression and encryption of completed WAL segments.
+//
+//   Policy of a tenant is changed at runtime by "at-rest" admin command and recorded in
+//   the [at_rest] table of tenant.toml. It applies to segments completed after the change,
+//   which are encoded by the GC task of the tenant in background. "at-rest-rewrite" starts
+//   a job bringing all completed segments of the tenant to the current policy, including
+//   decoding them back to plain WAL when the policy was turned off.
+//
+//   The segment being written is always plain, as WAL is written to it in place. Encoded
+//   segment keeps its name and consists of a header (magic "ZSEG", format version, flags,
+//   size of the plain segment, nonce) followed by WAL of the segment, compressed in LZ4
+//   block format and then encrypted by AES-256-GCM (openssl) with the key of
+//   --encryption-key-file, the header being authenticated as well. Plain segment can't be
+//   mistaken for an encoded one, as it starts with the magic of a WAL page.
+//
+//   Readers go through SegmentPath::open_for_read, which decodes an encoded segment to an
+//   anonymous temporary file; before an encoded segment is overwritten by a proposer of a
+//   new term, it is decoded in place. The archive gets plain segments.
+//
+use byteorder::{ByteOrder, LittleEndian};
+use log::*;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::partial_segment::PREP_SUFFIX;
+use crate::pq_protocol::Result;
+use crate::WalAcceptorConf;
+
+pub const ENCODED_MAGIC: &[u8; 4] = b"ZSEG";
+const FORMAT_VERSION: u8 = 1;
+const FLAG_LZ4: u8 = 1;
+const FLAG_AES_GCM: u8 = 2;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16; /* follows the ciphertext */
+const KEY_SIZE: usize = 32;
+const MAX_PLAIN_SIZE: usize = 1 << 30; /* the largest WAL segment */
+/* magic, format version, flags, reserved, size of the plain segment, nonce */
+pub const HEADER_SIZE: usize = 4 + 1 + 1 + 2 + 8 + NONCE_SIZE;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AtRestPolicy {
+    pub compression: bool,
+    pub encryption: bool,
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+impl AtRestPolicy {
+    fn flags(&self) -> u8 {
+        (if self.compression { FLAG_LZ4 } else { 0 })
+            | (if self.encryption { FLAG_AES_GCM } else { 0 })
+    }
+
+    fn from_flags(flags: u8) -> AtRestPolicy {
+        AtRestPolicy {
+            compression: flags & FLAG_LZ4 != 0,
+            encryption: flags & FLAG_AES_GCM != 0,
+        }
+    }
+
+    pub fn is_plain(&self) -> bool {
+        !self.compression && !self.encryption
+    }
+
+    // Whether segments of the tenant can be stored this way by this safekeeper
+    pub fn check(&self, conf: &WalAcceptorConf) -> Result<()> {
+        if !self.is_plain() && conf.pg_wal_layout {
+            io_error!("segments can't be encoded with --pg-wal-layout, Postgres reads them");
+        }
+        if !self.is_plain() && conf.object_storage.is_some() {
+            io_error!("segments can't be encoded with object storage, they are not kept locally");
+        }
+        if self.encryption && conf.at_rest_key.is_none() {
+            io_error!("encryption key is not configured, see --encryption-key-file");
+        }
+        Ok(())
+    }
+
+    //
+    // Apply settings like "compression=on encryption=off" to the policy
+    //
+    pub fn apply_settings(&mut self, settings: &[&str]) -> Result<()> {
+        for setting in settings {
+            let enabled = match setting.splitn(2, '=').nth(1) {
+                Some("on") => true,
+                Some("off") => false,
+                _ => {
+                    io_error!("Invalid setting '{}', expected <name>=on|off", setting);
+                }
+            };
+            match setting.splitn(2, '=').next() {
+                Some("compression") => self.compression = enabled,
+                Some("encryption") => self.encryption = enabled,
+                _ => {
+                    io_error!(
+                        "Unknown setting '{}', expected compression or encryption",
+                        setting
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "compression={} encryption={}",
+            on_off(self.compression),
+            on_off(self.encryption)
+        )
+    }
+}
+
+//
+// Key of --encryption-key-file: 32 bytes in base64
+//
+#[derive(Clone)]
+pub struct AtRestKey([u8; KEY_SIZE]);
+
+impl fmt::Debug for AtRestKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AtRestKey(..)")
+    }
+}
+
+impl AtRestKey {
+    pub fn load(path: &Path) -> io::Result<AtRestKey> {
+        match base64::decode(fs::read_to_string(path)?.trim()) {
+            Ok(bytes) if bytes.len() == KEY_SIZE => {
+                let mut key = [0u8; KEY_SIZE];
+                key.copy_from_slice(&bytes);
+                Ok(AtRestKey(key))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} must contain {} bytes of key in base64",
+                    path, KEY_SIZE
+                ),
+            )),
+        }
+    }
+}
+
+fn key_bytes(key: Option<&AtRestKey>) -> Result<&[u8]> {
+    match key {
+        Some(key) => Ok(&key.0),
+        None => {
+            io_error!("segment is encrypted, but encryption key is not configured");
+        }
+    }
+}
+
+// Policy the segment is encoded with, None if it is plain
+pub fn encoding(data: &[u8]) -> Option<AtRestPolicy> {
+    if data.len() >= HEADER_SIZE && &data[0..4] == ENCODED_MAGIC {
+        Some(AtRestPolicy::from_flags(data[5]))
+    } else {
+        None
+    }
+}
+
+//
+// Encode plain WAL of a segment according to the policy. The key (conf.at_rest_key) is
+// needed for encryption only.
+//
+pub fn encode(wal: &[u8], policy: AtRestPolicy, key: Option<&AtRestKey>) -> Result<Vec<u8>> {
+    let mut payload = if policy.compression {
+        lz4_compress(wal)
+    } else {
+        wal.to_vec()
+    };
+    let mut data = vec![0u8; HEADER_SIZE];
+    data[0..4].copy_from_slice(ENCODED_MAGIC);
+    data[4] = FORMAT_VERSION;
+    data[5] = policy.flags();
+    LittleEndian::write_u64(&mut data[8..16], wal.len() as u64);
+    if policy.encryption {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        data[16..HEADER_SIZE].copy_from_slice(&nonce);
+        let mut tag = [0u8; TAG_SIZE];
+        let cipher = Cipher::aes_256_gcm();
+        payload = encrypt_aead(
+            cipher,
+            key_bytes(key)?,
+            Some(&nonce),
+            &data,
+            &payload,
+            &mut tag,
+        )
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("segment encryption failed: {}", e),
+            )
+        })?;
+        payload.extend_from_slice(&tag);
+    }
+    data.extend_from_slice(&payload);
+    Ok(data)
+}
+
+//
+// Plain WAL of the segment, which may be encoded
+//
+pub fn decode(data: Vec<u8>, key: Option<&AtRestKey>) -> Result<Vec<u8>> {
+    let policy = match encoding(&data) {
+        Some(policy) => policy,
+        None => return Ok(data),
+    };
+    if data[4] != FORMAT_VERSION {
+        io_error!("encoded segment has unknown format version {}", data[4]);
+    }
+    let (header, mut payload) = data.split_at(HEADER_SIZE);
+    let plain_size = LittleEndian::read_u64(&header[8..16]) as usize;
+    if plain_size > MAX_PLAIN_SIZE {
+        io_error!("encoded segment has invalid size {}", plain_size);
+    }
+    let decrypted;
+    if policy.encryption {
+        if payload.len() < TAG_SIZE {
+            return Err(corrupted("encrypted segment is truncated"));
+        }
+        let (ciphertext, tag) = payload.split_at(payload.len() - TAG_SIZE);
+        let nonce = &header[16..HEADER_SIZE];
+        let cipher = Cipher::aes_256_gcm();
+        decrypted = decrypt_aead(
+            cipher,
+            key_bytes(key)?,
+            Some(nonce),
+            header,
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| corrupted("encrypted segment fails authentication"))?;
+        payload = &decrypted;
+    }
+    let wal = if policy.compression {
+        lz4_decompress(payload, plain_size)?
+    } else {
+        payload.to_vec()
+    };
+    if wal.len() != plain_size {
+        io_error!(
+            "decoded segment has {} bytes instead of {}",
+            wal.len(),
+            plain_size
+        );
+    }
+    Ok(wal)
+}
+
+fn corrupted(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+//
+// LZ4 block format: sequences of a token (lengths of literals and of the match, 4 bits
+// each, continued by bytes of 255), literals and the match as offset back into the
+// output (2 bytes) and length (at least 4). The last sequence has literals only.
+// Matches are found greedily through a hash table of 4-byte prefixes; the last bytes are
+// always literals, like in the reference implementation.
+//
+const LZ4_MIN_MATCH: usize = 4;
+const LZ4_HASH_LOG: u32 = 16;
+const LZ4_MAX_OFFSET: usize = 65535;
+const LZ4_LAST_LITERALS: usize = 5;
+const LZ4_MF_LIMIT: usize = 12; /* no match starts that close to the end */
+
+fn lz4_hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - LZ4_HASH_LOG)) as usize
+}
+
+fn lz4_put_length(dst: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        dst.push(255);
+        len -= 255;
+    }
+    dst.push(len as u8);
+}
+
+fn lz4_put_sequence(dst: &mut Vec<u8>, literals: &[u8], offset_len: Option<(usize, usize)>) {
+    let lit_len = literals.len();
+    let match_code = offset_len.map_or(0, |(_, len)| len - LZ4_MIN_MATCH);
+    dst.push(((lit_len.min(15) as u8) << 4) | match_code.min(15) as u8);
+    if lit_len >= 15 {
+        lz4_put_length(dst, lit_len - 15);
+    }
+    dst.extend_from_slice(literals);
+    if let Some((offset, _)) = offset_len {
+        dst.push(offset as u8);
+        dst.push((offset >> 8) as u8);
+        if match_code >= 15 {
+            lz4_put_length(dst, match_code - 15);
+        }
+    }
+}
+
+fn lz4_compress(src: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(src.len() / 2);
+    let mut table = vec![0u32; 1 << LZ4_HASH_LOG]; /* position + 1 of the last prefix */
+    let (mut anchor, mut pos) = (0, 0);
+    while src.len() >= LZ4_MF_LIMIT && pos <= src.len() - LZ4_MF_LIMIT {
+        let sequence = LittleEndian::read_u32(&src[pos..]);
+        let hash = lz4_hash(sequence);
+        let candidate = table[hash] as usize;
+        table[hash] = (pos + 1) as u32;
+        if candidate == 0
+            || pos - (candidate - 1) > LZ4_MAX_OFFSET
+            || LittleEndian::read_u32(&src[candidate - 1..]) != sequence
+        {
+            pos += 1;
+            continue;
+        }
+        let start = candidate - 1;
+        let mut len = LZ4_MIN_MATCH;
+        while pos + len < src.len() - LZ4_LAST_LITERALS && src[start + len] == src[pos + len] {
+            len += 1;
+        }
+        lz4_put_sequence(&mut dst, &src[anchor..pos], Some((pos - start, len)));
+        pos += len;
+        anchor = pos;
+    }
+    lz4_put_sequence(&mut dst, &src[anchor..], None);
+    dst
+}
+
+fn lz4_get_length(src: &[u8], pos: &mut usize, max: usize) -> Result<usize> {
+    let mut len = 0;
+    loop {
+        let byte = *src
+            .get(*pos)
+            .ok_or_else(|| corrupted("compressed segment is truncated"))?;
+        *pos += 1;
+        len += byte as usize;
+        if len > max {
+            return Err(corrupted("compressed segment is corrupted"));
+        }
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+// Decompress a block which must expand to exactly plain_size bytes
+fn lz4_decompress(src: &[u8], plain_size: usize) -> Result<Vec<u8>> {
+    let mut dst = Vec::with_capacity(plain_size);
+    let mut pos = 0;
+    loop {
+        let token = *src
+            .get(pos)
+            .ok_or_else(|| corrupted("compressed segment is truncated"))?;
+        pos += 1;
+        let mut lit_len = (token >> 4) as usize;
+        if lit_len == 15 {
+            lit_len += lz4_get_length(src, &mut pos, plain_size)?;
+        }
+        if dst.len() + lit_len > plain_size || src.len() - pos < lit_len {
+            return Err(corrupted("compressed segment is corrupted"));
+        }
+        dst.extend_from_slice(&src[pos..pos + lit_len]);
+        pos += lit_len;
+        if pos == src.len() {
+            break;
+        }
+        if src.len() - pos < 2 {
+            return Err(corrupted("compressed segment is truncated"));
+        }
+        let offset = src[pos] as usize | (src[pos + 1] as usize) << 8;
+        pos += 2;
+        let mut len = (token & 15) as usize + LZ4_MIN_MATCH;
+        if token & 15 == 15 {
+            len += lz4_get_length(src, &mut pos, plain_size)?;
+        }
+        if offset == 0 || offset > dst.len() || dst.len() + len > plain_size {
+            return Err(corrupted("compressed segment is corrupted"));
+        }
+        /* Match may overlap the bytes it produces, so it is copied byte by byte */
+        let start = dst.len() - offset;
+        for i in start..start + len {
+            let byte = dst[i];
+            dst.push(byte);
+        }
+    }
+    Ok(dst)
+}
+
+// Whether the file is an encoded segment
+pub fn is_encoded(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && &magic == ENCODED_MAGIC
+}
+
+/* Sequence of names of decoded segments, readers of the same segment may run in parallel */
+static PLAIN_FILES: AtomicU64 = AtomicU64::new(0);
+
+//
+// Open completed segment for reading. Encoded segment is decoded to an anonymous
+// temporary file in the same directory: it is unlinked right after creation, so that
+// only the returned descriptor refers to it.
+//
+pub fn open_plain(path: &Path, key: Option<&AtRestKey>) -> Result<File> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    if file.read_exact(&mut magic).is_err() || &magic != ENCODED_MAGIC {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(file);
+    }
+    let mut data = magic.to_vec();
+    file.read_to_end(&mut data)?;
+    let wal =
+        decode(data, key).map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))?;
+    let plain_path = PathBuf::from(format!(
+        "{}.plain.{}.{}",
+        path.display(),
+        process::id(),
+        PLAIN_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut plain = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&plain_path)?;
+    fs::remove_file(&plain_path)?;
+    plain.write_all(&wal)?;
+    plain.seek(SeekFrom::Start(0))?;
+    Ok(plain)
+}
+
+// Plain WAL of the completed segment
+pub fn read_plain(path: &Path, key: Option<&AtRestKey>) -> Result<Vec<u8>> {
+    decode(fs::read(path)?, key).map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))
+}
+
+//
+// Write WAL of the completed segment in the form required by the policy under a
+// temporary name, to be renamed over the segment. Returns None if the segment is
+// in that form already.
+//
+pub fn prepare_rewrite(
+    path: &Path,
+    policy: AtRestPolicy,
+    key: Option<&AtRestKey>,
+    no_sync: bool,
+) -> Result<Option<PathBuf>> {
+    let data = fs::read(path)?;
+    if encoding(&data).unwrap_or_default() == policy {
+        return Ok(None);
+    }
+    let mut content =
+        decode(data, key).map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))?;
+    if !policy.is_plain() {
+        content = encode(&content, policy, key)?;
+    }
+    let tmp_path = PathBuf::from(format!("{}{}", path.display(), PREP_SUFFIX));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&content)?;
+    if !no_sync {
+        file.sync_all()?;
+    }
+    Ok(Some(tmp_path))
+}
+
+//
+// Turn encoded segment back into plain one, before WAL is written to it.
+// Called under the writer lock of the tenant.
+//
+pub fn decode_in_place(path: &Path, key: Option<&AtRestKey>) -> Result<()> {
+    if let Some(tmp_path) = prepare_rewrite(path, AtRestPolicy::default(), key, false)? {
+        fs::rename(&tmp_path, path)?;
+        File::open(path.parent().unwrap())?.sync_all()?;
+        info!("Encoded segment {:?} is decoded to be overwritten", path);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RewriteState {
+    Pending, /* waiting for the GC task */
+    Running,
+    Done,
+    Failed,
+}
+
+//
+// Job bringing all completed segments of the tenant to its policy
+//
+#[derive(Debug, Clone)]
+pub struct RewriteJob {
+    pub policy: AtRestPolicy, /* policy when the job started */
+    pub state: RewriteState,
+    pub segments_total: usize,
+    pub segments_checked: usize,
+    pub segments_rewritten: usize,
+    pub error: Option<String>,
+}
+
+impl RewriteJob {
+    pub fn new(policy: AtRestPolicy) -> RewriteJob {
+        RewriteJob {
+            policy: policy,
+            state: RewriteState::Pending,
+            segments_total: 0,
+            segments_checked: 0,
+            segments_rewritten: 0,
+            error: None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let state = match self.state {
+            RewriteState::Pending => "pending",
+            RewriteState::Running => "running",
+            RewriteState::Done => "done",
+            RewriteState::Failed => "failed",
+        };
+        let mut s = format!(
+            "rewrite to {}: {}, {}/{} segments checked, {} rewritten\n",
+            self.policy.describe(),
+            state,
+            self.segments_checked,
+            self.segments_total,
+            self.segments_rewritten
+        );
+        if let Some(error) = &self.error {
+            s += &format!("error: {}\n", error);
+        }
+        s
+    }
+}
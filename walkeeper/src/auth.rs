@@ -0,0 +1,310 @@
+//
+//   Authentication of libpq replication clients.
+//
+//   Method and credentials are configured per tenant in tenant.toml:
+//
+//     auth_method = "scram-sha-256"   # trust (default), password, md5 or scram-sha-256
+//     [users]
+//     pageserver = "SCRAM-SHA-256$4096:<salt>$<StoredKey>:<ServerKey>"
+//
+//   Secrets have the formats of pg_authid.rolpassword: SCRAM verifier, "md5" followed by
+//   md5 of password and user name, or plain password. md5 authentication needs md5 or
+//   plain secret, the others work with any. Cleartext password is asked for only over TLS.
+//   Passwords are not SASLprep-normalized, so SCRAM verifiers made by Postgres match only
+//   for ASCII passwords.
+//
+use hmac::{Hmac, Mac, NewMac};
+use log::*;
+use md5::Md5;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::str;
+
+use crate::pq_protocol::Result;
+
+pub const SCRAM_MECHANISM: &str = "SCRAM-SHA-256";
+const SCRAM_ITERATIONS: u32 = 4096; /* for verifiers of plain secrets, as in Postgres */
+const SCRAM_KEY_LEN: usize = 32;
+const SCRAM_NONCE_LEN: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    Trust,
+    Password, /* cleartext password, accepted over TLS only */
+    Md5,
+    ScramSha256,
+}
+
+impl Default for AuthMethod {
+    fn default() -> Self {
+        AuthMethod::Trust
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScramVerifier {
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Secret {
+    Plain(String),
+    Md5(String), /* hex md5 of password and user name, without "md5" prefix */
+    Scram(ScramVerifier),
+}
+
+fn auth_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn md5_hex(data: &[u8]) -> String {
+    format!("{:x}", Md5::digest(data))
+}
+
+/* Compare secrets without leaking the position of the first difference through timing */
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl ScramVerifier {
+    pub fn new(password: &str, salt: &[u8], iterations: u32) -> ScramVerifier {
+        /* Hi() of RFC 5802: PBKDF2 with HMAC-SHA-256 */
+        let mut u = hmac(password.as_bytes(), &[salt, &[0u8, 0, 0, 1][..]].concat());
+        let mut salted_password = u.clone();
+        for _ in 1..iterations {
+            u = hmac(password.as_bytes(), &u);
+            for (acc, x) in salted_password.iter_mut().zip(&u) {
+                *acc ^= x;
+            }
+        }
+        let client_key = hmac(&salted_password, b"Client Key");
+        ScramVerifier {
+            iterations: iterations,
+            salt: salt.to_vec(),
+            stored_key: Sha256::digest(&client_key).to_vec(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+
+    /* SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>, base64 encoded */
+    fn parse(s: &str) -> Option<ScramVerifier> {
+        let rest = s.strip_prefix("SCRAM-SHA-256$")?;
+        let (params, keys) = rest.split_once('$')?;
+        let (iterations, salt) = params.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+        let verifier = ScramVerifier {
+            iterations: iterations.parse().ok()?,
+            salt: base64::decode(salt).ok()?,
+            stored_key: base64::decode(stored_key).ok()?,
+            server_key: base64::decode(server_key).ok()?,
+        };
+        if verifier.stored_key.len() != SCRAM_KEY_LEN || verifier.server_key.len() != SCRAM_KEY_LEN {
+            return None;
+        }
+        Some(verifier)
+    }
+}
+
+impl Secret {
+    pub fn parse(s: &str) -> Secret {
+        if let Some(verifier) = ScramVerifier::parse(s) {
+            Secret::Scram(verifier)
+        } else if s.len() == 35 && s.starts_with("md5") {
+            Secret::Md5(s[3..].to_string())
+        } else {
+            Secret::Plain(s.to_string())
+        }
+    }
+
+    fn scram_verifier(&self) -> Option<ScramVerifier> {
+        match self {
+            Secret::Scram(verifier) => Some(verifier.clone()),
+            Secret::Plain(password) => {
+                let salt: [u8; 16] = rand::thread_rng().gen();
+                Some(ScramVerifier::new(password, &salt, SCRAM_ITERATIONS))
+            }
+            Secret::Md5(_) => None,
+        }
+    }
+}
+
+//
+// Check password sent in clear text (PasswordMessage body, NUL terminated)
+//
+pub fn check_password(secret: &Secret, user: &str, message: &[u8]) -> Result<()> {
+    let password = str::from_utf8(message.strip_suffix(b"\0").unwrap_or(message))
+        .map_err(|_| auth_error("password is not valid UTF-8".to_string()))?;
+    let ok = match secret {
+        Secret::Plain(expected) => constant_time_eq(password.as_bytes(), expected.as_bytes()),
+        Secret::Md5(expected) => constant_time_eq(
+            md5_hex(format!("{}{}", password, user).as_bytes()).as_bytes(),
+            expected.as_bytes(),
+        ),
+        Secret::Scram(verifier) => {
+            let computed = ScramVerifier::new(password, &verifier.salt, verifier.iterations);
+            constant_time_eq(&computed.stored_key, &verifier.stored_key)
+        }
+    };
+    if !ok {
+        return Err(auth_error(format!("password authentication failed for user {:?}", user)));
+    }
+    Ok(())
+}
+
+//
+// Check response to md5 challenge: "md5" followed by md5(md5(password || user) || salt)
+//
+pub fn check_md5(secret: &Secret, user: &str, salt: &[u8; 4], message: &[u8]) -> Result<()> {
+    let hash = match secret {
+        Secret::Plain(password) => md5_hex(format!("{}{}", password, user).as_bytes()),
+        Secret::Md5(hash) => hash.clone(),
+        Secret::Scram(_) => {
+            return Err(auth_error(format!(
+                "user {:?} has SCRAM secret, which can't be used for md5 authentication",
+                user
+            )))
+        }
+    };
+    let expected = format!("md5{}", md5_hex(&[hash.as_bytes(), &salt[..]].concat()));
+    let response = message.strip_suffix(b"\0").unwrap_or(message);
+    if !constant_time_eq(response, expected.as_bytes()) {
+        return Err(auth_error(format!("md5 authentication failed for user {:?}", user)));
+    }
+    Ok(())
+}
+
+//
+// Server side of SCRAM-SHA-256 exchange (RFC 5802, RFC 7677) without channel binding
+//
+pub struct ScramExchange {
+    verifier: ScramVerifier,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+}
+
+fn scram_attribute<'a>(message: &'a str, name: char) -> Option<&'a str> {
+    message
+        .split(',')
+        .find(|attr| attr.starts_with(name) && attr[1..].starts_with('='))
+        .map(|attr| &attr[2..])
+}
+
+impl ScramExchange {
+    //
+    // Start exchange by SASLInitialResponse: mechanism name, NUL, length and client-first-message
+    //
+    pub fn start(secret: &Secret, user: &str, message: &[u8]) -> Result<ScramExchange> {
+        let server_nonce: [u8; SCRAM_NONCE_LEN] = rand::thread_rng().gen();
+        ScramExchange::start_with_nonce(secret, user, message, &base64::encode(&server_nonce))
+    }
+
+    // The same with the given server part of the nonce, e.g. to check test vectors
+    pub fn start_with_nonce(
+        secret: &Secret,
+        user: &str,
+        message: &[u8],
+        server_nonce: &str,
+    ) -> Result<ScramExchange> {
+        let verifier = secret.scram_verifier().ok_or_else(|| {
+            auth_error(format!(
+                "user {:?} has md5 secret, which can't be used for SCRAM authentication",
+                user
+            ))
+        })?;
+        let nul = message.iter().position(|&c| c == 0);
+        let (mechanism, client_first) = match nul {
+            Some(pos) if message.len() >= pos + 5 => (&message[..pos], &message[pos + 5..]),
+            _ => return Err(auth_error("malformed SASLInitialResponse".to_string())),
+        };
+        if mechanism != SCRAM_MECHANISM.as_bytes() {
+            return Err(auth_error(format!(
+                "unsupported SASL mechanism {:?}",
+                String::from_utf8_lossy(mechanism)
+            )));
+        }
+        let client_first = str::from_utf8(client_first)
+            .map_err(|_| auth_error("client-first-message is not valid UTF-8".to_string()))?;
+
+        /* gs2-header: channel binding flag and empty authzid, then client-first-message-bare */
+        let mut parts = client_first.splitn(3, ',');
+        let (cbind_flag, authzid, bare) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(flag), Some(authzid), Some(bare)) => (flag, authzid, bare),
+            _ => return Err(auth_error("malformed client-first-message".to_string())),
+        };
+        if cbind_flag != "n" && cbind_flag != "y" {
+            return Err(auth_error("channel binding is not supported".to_string()));
+        }
+        if !authzid.is_empty() {
+            return Err(auth_error("authorization identity is not supported".to_string()));
+        }
+        /* User name of the startup packet is used, like in Postgres */
+        let client_nonce = scram_attribute(bare, 'r')
+            .ok_or_else(|| auth_error("client-first-message has no nonce".to_string()))?;
+
+        let nonce = format!("{}{}", client_nonce, server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            base64::encode(&verifier.salt),
+            verifier.iterations
+        );
+        Ok(ScramExchange {
+            verifier: verifier,
+            gs2_header: format!("{},{},", cbind_flag, authzid),
+            client_first_bare: bare.to_string(),
+            server_first: server_first,
+            nonce: nonce,
+        })
+    }
+
+    pub fn server_first(&self) -> &[u8] {
+        self.server_first.as_bytes()
+    }
+
+    //
+    // Verify client proof of SASLResponse with client-final-message, return server-final-message
+    //
+    pub fn finish(&self, user: &str, message: &[u8]) -> Result<Vec<u8>> {
+        let failed = || auth_error(format!("SCRAM authentication failed for user {:?}", user));
+        let client_final = str::from_utf8(message).map_err(|_| failed())?;
+        let proof_pos = client_final.rfind(",p=").ok_or_else(failed)?;
+        let without_proof = &client_final[..proof_pos];
+        let proof = base64::decode(&client_final[proof_pos + 3..]).map_err(|_| failed())?;
+        if scram_attribute(without_proof, 'c') != Some(base64::encode(&self.gs2_header).as_str())
+            || scram_attribute(without_proof, 'r') != Some(self.nonce.as_str())
+            || proof.len() != SCRAM_KEY_LEN
+        {
+            return Err(failed());
+        }
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+        let client_signature = hmac(&self.verifier.stored_key, auth_message.as_bytes());
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(&client_signature)
+            .map(|(x, y)| x ^ y)
+            .collect();
+        if !constant_time_eq(&Sha256::digest(&client_key), &self.verifier.stored_key) {
+            return Err(failed());
+        }
+        debug!("SCRAM authentication of user {:?} succeeded", user);
+        let server_signature = hmac(&self.verifier.server_key, auth_message.as_bytes());
+        Ok(format!("v={}", base64::encode(&server_signature)).into_bytes())
+    }
+}
@@ -3,21 +3,34 @@
 //
 use daemonize::Daemonize;
 use log::*;
+use std::fmt::Display;
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::thread;
+use std::time::Duration;
 use std::{fs::File, fs::OpenOptions};
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
 use slog;
 use slog::Drain;
 use slog_scope;
 use slog_stdlog;
 
+use walkeeper::access_list::{AccessList, Cidr};
+use walkeeper::admin;
+use walkeeper::at_rest::AtRestKey;
+use walkeeper::handoff;
+use walkeeper::legacy_layout;
+use walkeeper::log_filter::RuntimeFilterDrain;
+use walkeeper::node_file;
+use walkeeper::object_storage::{self, BucketConf, ObjectStorageConf};
+use walkeeper::tls::TlsConf;
+use walkeeper::trace;
 use walkeeper::wal_service;
-use walkeeper::WalAcceptorConf;
+use walkeeper::{AcceptorSetConf, CallbackConf, SyncMethod, WalAcceptorConf};
 
 fn main() -> Result<(), io::Error> {
     let arg_matches = App::new("Zenith wal_acceptor")
@@ -43,6 +56,12 @@ fn main() -> Result<(), io::Error> {
                 .takes_value(true)
                 .help("address ip:port of pageserver with which wal_acceptor should establish connection"),
         )
+        .arg(
+            Arg::with_name("http-listen")
+                .long("http-listen")
+                .takes_value(true)
+                .help("address ip:port of HTTP management API (disabled by default)"),
+        )
         .arg(
             Arg::with_name("daemonize")
                 .short("d")
@@ -57,40 +76,731 @@ fn main() -> Result<(), io::Error> {
                 .takes_value(false)
                 .help("Do not wait for changes to be written safely to disk"),
         )
+        .arg(
+            Arg::with_name("sync-method")
+                .long("sync-method")
+                .takes_value(true)
+                .help("How WAL writes are synced: fsync (default), fdatasync, sync_file_range or none (same as --no-sync)"),
+        )
+        .arg(
+            Arg::with_name("wal-stats")
+                .long("wal-stats")
+                .takes_value(false)
+                .help("Decode headers of received WAL records and collect statistics by resource manager"),
+        )
+        .arg(
+            Arg::with_name("no-wal-crc-check")
+                .long("no-wal-crc-check")
+                .takes_value(false)
+                .help("Do not verify CRC of received WAL records before storing them"),
+        )
+        .arg(
+            Arg::with_name("control-file-version")
+                .long("control-file-version")
+                .takes_value(true)
+                .help("Write control files in this format version, e.g. the one of the previous release to be able to roll back (the latest by default)"),
+        )
+        .arg(
+            Arg::with_name("pg-wal-layout")
+                .long("pg-wal-layout")
+                .takes_value(false)
+                .help("Lay out WAL segments exactly as Postgres pg_wal directory, including archive_status"),
+        )
+        .arg(
+            Arg::with_name("slow-append-ms")
+                .long("slow-append-ms")
+                .takes_value(true)
+                .help("Log appends whose write and fsync took longer than this number of milliseconds"),
+        )
+        .arg(
+            Arg::with_name("slow-send-ms")
+                .long("slow-send-ms")
+                .takes_value(true)
+                .help("Log WAL chunks whose write to the replica socket took longer than this number of milliseconds"),
+        )
+        .arg(
+            Arg::with_name("heartbeat-ms")
+                .long("heartbeat-ms")
+                .takes_value(true)
+                .help("Send heartbeat to idle proposer with this interval in milliseconds, and drop the proposer after 3 silent intervals"),
+        )
+        .arg(
+            Arg::with_name("read-cache-mb")
+                .long("read-cache-mb")
+                .takes_value(true)
+                .help("Size of WAL read cache shared by senders of all tenants in megabytes (disabled by default)"),
+        )
+        .arg(
+            Arg::with_name("keepalive-ms")
+                .long("keepalive-ms")
+                .takes_value(true)
+                .help("Send keepalive to replica waiting for WAL with this interval in milliseconds (10000 by default)"),
+        )
+        .arg(
+            Arg::with_name("proposer-timeout-ms")
+                .long("proposer-timeout-ms")
+                .takes_value(true)
+                .help("Close proposer connection which sends nothing for this number of milliseconds, releasing its tenant"),
+        )
+        .arg(
+            Arg::with_name("tcp-keepalive-ms")
+                .long("tcp-keepalive-ms")
+                .takes_value(true)
+                .help("Enable TCP keepalive on accepted connections, probing peers idle for this number of milliseconds"),
+        )
+        .arg(
+            Arg::with_name("max-clock-skew-ms")
+                .long("max-clock-skew-ms")
+                .takes_value(true)
+                .help("Warn if commit timestamps of proposer differ from the local clock by more than this number of milliseconds"),
+        )
+        .arg(
+            Arg::with_name("catchup-rate-limit")
+                .long("catchup-rate-limit")
+                .takes_value(true)
+                .help("Limit throughput of WAL senders catching up from far behind commit LSN, in bytes per second"),
+        )
+        .arg(
+            Arg::with_name("max-inflight-msgs")
+                .long("max-inflight-msgs")
+                .takes_value(true)
+                .help("Number of WAL append messages which may be read ahead from proposer after handshake (default: 1)"),
+        )
+        .arg(
+            Arg::with_name("receive-high-watermark-kb")
+                .long("receive-high-watermark-kb")
+                .takes_value(true)
+                .help("Pause proposers supporting flow control while appends read ahead exceed this number of kilobytes"),
+        )
+        .arg(
+            Arg::with_name("max-ack-delay-ms")
+                .long("max-ack-delay-ms")
+                .takes_value(true)
+                .help("Acknowledge pipelined appends with a single response, deferring acknowledgement by at most this number of milliseconds"),
+        )
+        .arg(
+            Arg::with_name("group-commit-kb")
+                .long("group-commit-kb")
+                .takes_value(true)
+                .help("Write pipelined appends without fsync, syncing them together once this number of kilobytes is unsynced or proposer has nothing more to send"),
+        )
+        .arg(
+            Arg::with_name("group-commit-delay-ms")
+                .long("group-commit-delay-ms")
+                .takes_value(true)
+                .help("Write pipelined appends without fsync, syncing them together once the first one has waited this number of milliseconds or proposer has nothing more to send"),
+        )
+        .arg(
+            Arg::with_name("prealloc-segments")
+                .long("prealloc-segments")
+                .takes_value(true)
+                .help("Keep this number of zero-filled WAL segments of each tenant ready in advance, recycling segments removed by WAL GC"),
+        )
+        .arg(
+            Arg::with_name("wal-retention")
+                .long("wal-retention")
+                .takes_value(true)
+                .help("Bytes of WAL kept behind the flush position even if they are below restart LSN and removable by WAL GC"),
+        )
+        .arg(
+            Arg::with_name("wal-retention-secs")
+                .long("wal-retention-secs")
+                .takes_value(true)
+                .help("Keep WAL received within this number of seconds (according to the ingest-time index) from WAL GC, e.g. 86400 to keep 24 hours of WAL"),
+        )
+        .arg(
+            Arg::with_name("gc-coordinated")
+                .long("gc-coordinated")
+                .takes_value(false)
+                .help("Remove WAL only below the cutoff proposed by pageserver and confirmed by safekeeper"),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .takes_value(true)
+                .help("Worker threads of the runtime (default: number of CPUs), 1 runs single-threaded"),
+        )
+        .arg(
+            Arg::with_name("callback")
+                .long("callback")
+                .takes_value(true)
+                .help("How to notify pageserver about new WAL: callmemaybe (default), none, or http:// URL of a webhook"),
+        )
+        .arg(
+            Arg::with_name("metrics-top-tenants")
+                .long("metrics-top-tenants")
+                .takes_value(true)
+                .help("Export metrics only of this many most active tenants, aggregate the rest as tenant=\"other\""),
+        )
+        .arg(
+            Arg::with_name("trace-dir")
+                .long("trace-dir")
+                .takes_value(true)
+                .help("Capture every proposer session to a trace file in this directory"),
+        )
+        .arg(
+            Arg::with_name("shutdown-report")
+                .long("shutdown-report")
+                .takes_value(true)
+                .help("On graceful shutdown write final flush_lsn and commit_lsn of every tenant to this JSON file"),
+        )
+        .arg(
+            Arg::with_name("node-index")
+                .long("node-index")
+                .takes_value(true)
+                .requires("peers")
+                .help("Position of this safekeeper in --peers, proposers claiming otherwise are rejected"),
+        )
+        .arg(
+            Arg::with_name("peers")
+                .long("peers")
+                .takes_value(true)
+                .requires("node-index")
+                .help("Comma separated addresses of all safekeepers of the acceptor set, including this one"),
+        )
+        .arg(
+            Arg::with_name("quorum")
+                .long("quorum")
+                .takes_value(true)
+                .requires("peers")
+                .help("Quorum size of the acceptor set (majority of --peers by default)"),
+        )
+        .arg(
+            Arg::with_name("allow")
+                .long("allow")
+                .takes_value(true)
+                .use_delimiter(true)
+                .help("Comma separated networks (CIDR) allowed to connect to WAL service, any by default"),
+        )
+        .arg(
+            Arg::with_name("deny")
+                .long("deny")
+                .takes_value(true)
+                .use_delimiter(true)
+                .help("Comma separated networks (CIDR) not allowed to connect to WAL service"),
+        )
+        .arg(
+            Arg::with_name("http-allow")
+                .long("http-allow")
+                .takes_value(true)
+                .use_delimiter(true)
+                .help("Comma separated networks (CIDR) allowed to connect to HTTP API, any by default"),
+        )
+        .arg(
+            Arg::with_name("http-deny")
+                .long("http-deny")
+                .takes_value(true)
+                .use_delimiter(true)
+                .help("Comma separated networks (CIDR) not allowed to connect to HTTP API"),
+        )
+        .arg(
+            Arg::with_name("legacy-tenant")
+                .long("legacy-tenant")
+                .takes_value(true)
+                .help("Tenant of WAL stored directly in the data directory by old versions, it is moved to the tenant directory at startup"),
+        )
+        .arg(
+            Arg::with_name("node-uuid")
+                .long("node-uuid")
+                .takes_value(true)
+                .help("Identity of this safekeeper (32 hex digits); refuse to start if the data directory belongs to another one"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .requires("tls-key")
+                .help("Certificate chain (PEM) for connections requesting TLS"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("Private key (PEM, PKCS#8 or RSA) of the certificate given by --tls-cert"),
+        )
+        .arg(
+            Arg::with_name("encryption-key-file")
+                .long("encryption-key-file")
+                .takes_value(true)
+                .help("Key (32 bytes in base64) of tenants encrypting completed WAL segments at rest"),
+        )
+        .arg(
+            Arg::with_name("object-storage")
+                .long("object-storage")
+                .takes_value(true)
+                .help("Experimental: keep WAL in this S3 bucket instead of local segments (endpoint and credentials from S3_* environment variables)"),
+        )
+        .arg(
+            Arg::with_name("object-storage-prefix")
+                .long("object-storage-prefix")
+                .takes_value(true)
+                .requires("object-storage")
+                .help("Prefix of WAL objects in the bucket (default: wal)"),
+        )
+        .arg(
+            Arg::with_name("object-storage-chunk-size")
+                .long("object-storage-chunk-size")
+                .takes_value(true)
+                .requires("object-storage")
+                .help("Bytes of WAL staged locally before they are uploaded as one object (default: 1MB)"),
+        )
+        .arg(
+            Arg::with_name("archive")
+                .long("archive")
+                .takes_value(true)
+                .help("Upload completed WAL segments to this S3 bucket (endpoint and credentials from S3_* environment variables)"),
+        )
+        .arg(
+            Arg::with_name("archive-prefix")
+                .long("archive-prefix")
+                .takes_value(true)
+                .requires("archive")
+                .help("Prefix of archived segments in the bucket (default: archive)"),
+        )
+        .arg(
+            Arg::with_name("takeover")
+                .long("takeover")
+                .takes_value(false)
+                .help("Take over listening socket from wal_acceptor running in the data directory"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .takes_value(false)
+                .help("Check configuration, listen addresses and tenants in the data directory, then exit without serving"),
+        )
+        .subcommand(
+            SubCommand::with_name("init-tenant")
+                .about("Create tenant directory and control file before the first compute starts")
+                .arg(
+                    Arg::with_name("tenant")
+                        .long("tenant")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Tenant (Postgres system) identifier"),
+                )
+                .arg(
+                    Arg::with_name("timeline")
+                        .long("timeline")
+                        .takes_value(true)
+                        .help("Timeline of the tenant WAL"),
+                )
+                .arg(
+                    Arg::with_name("from-backup")
+                        .long("from-backup")
+                        .takes_value(true)
+                        .help("Seed WAL segments from this location (local path or file:// URL)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-wal")
+                .about("Install WAL segments from a directory or tar archive into a tenant which has no WAL")
+                .arg(
+                    Arg::with_name("tenant")
+                        .long("tenant")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Tenant (Postgres system) identifier"),
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .long("manifest")
+                        .takes_value(true)
+                        .help("Manifest of the import, import.toml of the source by default"),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .required(true)
+                        .help("Directory or uncompressed tar archive with WAL segments"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Replay captured proposer trace against temporary storage and compare responses")
+                .arg(Arg::with_name("trace").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("admin")
+                .about("Send command to running wal_acceptor through its admin socket, or start interactive session")
+                .arg(Arg::with_name("command").multiple(true)),
+        )
         .get_matches();
 
     let mut conf = WalAcceptorConf {
         data_dir: PathBuf::from("./"),
         daemonize: false,
         no_sync: false,
+        sync_method: SyncMethod::Fsync,
+        wal_stats: false,
+        verify_wal_crc: true,
+        control_file_version: None,
+        pg_wal_layout: false,
+        slow_append_threshold: None,
+        slow_send_threshold: None,
+        heartbeat_interval: None,
+        keepalive_interval: Duration::from_secs(10),
+        proposer_read_timeout: None,
+        tcp_keepalive: None,
+        max_clock_skew: None,
+        catchup_rate_limit: None,
+        max_inflight_msgs: 1,
+        receive_high_watermark: None,
+        max_ack_delay: None,
+        group_commit_bytes: None,
+        group_commit_delay: None,
+        prealloc_segments: 0,
+        wal_retention: None,
+        wal_retention_time: None,
+        gc_coordinated: false,
+        read_cache_size: 0,
         pageserver_addr: None,
+        http_addr: None,
         listen_addr: "127.0.0.1:5454".parse().unwrap(),
+        callback: CallbackConf::CallMeMaybe,
+        trace_dir: None,
+        shutdown_report: None,
+        metrics_top_tenants: None,
+        acceptor_set: None,
+        access_list: AccessList::default(),
+        http_access_list: AccessList::default(),
+        legacy_tenant: None,
+        node_uuid: None,
+        workers: None,
+        tls: None,
+        at_rest_key: None,
+        object_storage: None,
+        archive: None,
     };
 
     if let Some(dir) = arg_matches.value_of("datadir") {
         conf.data_dir = PathBuf::from(dir);
     }
 
+    if let Some(init_matches) = arg_matches.subcommand_matches("init-tenant") {
+        let tenant = walkeeper::parse_tenant_id(init_matches.value_of("tenant").unwrap())?;
+        let timeline = match init_matches.value_of("timeline") {
+            Some(tli) => Some(tli.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid timeline {}", tli))
+            })?),
+            None => None,
+        };
+        let seeded = wal_service::init_tenant(
+            &conf,
+            tenant,
+            timeline,
+            init_matches.value_of("from-backup"),
+        )?;
+        println!("tenant {} is initialized, {} WAL segments seeded", tenant, seeded);
+        return Ok(());
+    }
+
+    if let Some(import_matches) = arg_matches.subcommand_matches("import-wal") {
+        let tenant = walkeeper::parse_tenant_id(import_matches.value_of("tenant").unwrap())?;
+        if arg_matches.is_present("object-storage") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "WAL import is not supported with object storage",
+            ));
+        }
+        /* Segments are named and synced the way the tenant will be served */
+        conf.pg_wal_layout = arg_matches.is_present("pg-wal-layout");
+        conf.no_sync = arg_matches.is_present("no-sync");
+        let plan = wal_service::import_wal(
+            &conf,
+            tenant,
+            Path::new(import_matches.value_of("source").unwrap()),
+            import_matches.value_of("manifest").map(Path::new),
+        )?;
+        println!("tenant {} imported {}", tenant, plan.describe());
+        return Ok(());
+    }
+
+    if let Some(replay_matches) = arg_matches.subcommand_matches("replay") {
+        let trace = Path::new(replay_matches.value_of("trace").unwrap());
+        let mismatches = trace::replay(trace)?;
+        if mismatches != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("replay of {:?} found {} mismatches", trace, mismatches),
+            ));
+        }
+        println!("replay of {:?} matches the capture", trace);
+        return Ok(());
+    }
+
+    if let Some(admin_matches) = arg_matches.subcommand_matches("admin") {
+        let command = admin_matches
+            .values_of("command")
+            .map(|words| words.collect::<Vec<&str>>().join(" "));
+        return admin::admin_client(&conf.data_dir, command);
+    }
+
+    /* Problems with options are collected and reported together with the result of validation */
+    let mut errors = Vec::new();
+
     if arg_matches.is_present("no-sync") {
         conf.no_sync = true;
     }
+    if let Some(sync_method) = parse_arg(&arg_matches, "sync-method", &mut errors) {
+        conf.sync_method = sync_method;
+        if sync_method == SyncMethod::None {
+            conf.no_sync = true;
+        }
+    }
+
+    if arg_matches.is_present("wal-stats") {
+        conf.wal_stats = true;
+    }
+
+    if arg_matches.is_present("no-wal-crc-check") {
+        conf.verify_wal_crc = false;
+    }
+
+    conf.control_file_version = parse_arg(&arg_matches, "control-file-version", &mut errors);
+
+    if arg_matches.is_present("pg-wal-layout") {
+        conf.pg_wal_layout = true;
+    }
+
+    if let Some(ms) = parse_arg(&arg_matches, "slow-append-ms", &mut errors) {
+        conf.slow_append_threshold = Some(Duration::from_millis(ms));
+    }
+
+    if let Some(ms) = parse_arg(&arg_matches, "slow-send-ms", &mut errors) {
+        conf.slow_send_threshold = Some(Duration::from_millis(ms));
+    }
+
+    if let Some(ms) = parse_arg(&arg_matches, "heartbeat-ms", &mut errors) {
+        conf.heartbeat_interval = Some(Duration::from_millis(ms));
+    }
+
+    if let Some(mb) = parse_arg::<usize>(&arg_matches, "read-cache-mb", &mut errors) {
+        conf.read_cache_size = mb * 1024 * 1024;
+    }
+
+    if let Some(ms) = parse_arg(&arg_matches, "keepalive-ms", &mut errors) {
+        conf.keepalive_interval = Duration::from_millis(ms);
+    }
+
+    if let Some(ms) = parse_arg(&arg_matches, "proposer-timeout-ms", &mut errors) {
+        conf.proposer_read_timeout = Some(Duration::from_millis(ms));
+    }
+
+    if let Some(ms) = parse_arg(&arg_matches, "tcp-keepalive-ms", &mut errors) {
+        conf.tcp_keepalive = Some(Duration::from_millis(ms));
+    }
+
+    if let Some(ms) = parse_arg(&arg_matches, "max-clock-skew-ms", &mut errors) {
+        conf.max_clock_skew = Some(Duration::from_millis(ms));
+    }
+
+    conf.catchup_rate_limit = parse_arg(&arg_matches, "catchup-rate-limit", &mut errors);
+
+    if let Some(n) = parse_arg(&arg_matches, "max-inflight-msgs", &mut errors) {
+        conf.max_inflight_msgs = n;
+    }
+
+    if let Some(kb) = parse_arg::<usize>(&arg_matches, "receive-high-watermark-kb", &mut errors) {
+        conf.receive_high_watermark = Some(kb * 1024);
+    }
+
+    if let Some(ms) = parse_arg(&arg_matches, "max-ack-delay-ms", &mut errors) {
+        conf.max_ack_delay = Some(Duration::from_millis(ms));
+    }
+
+    if let Some(kb) = parse_arg::<usize>(&arg_matches, "group-commit-kb", &mut errors) {
+        conf.group_commit_bytes = Some(kb * 1024);
+    }
+
+    if let Some(ms) = parse_arg(&arg_matches, "group-commit-delay-ms", &mut errors) {
+        conf.group_commit_delay = Some(Duration::from_millis(ms));
+    }
+
+    if let Some(n) = parse_arg(&arg_matches, "prealloc-segments", &mut errors) {
+        conf.prealloc_segments = n;
+    }
+
+    conf.wal_retention = parse_arg(&arg_matches, "wal-retention", &mut errors);
+    if let Some(secs) = parse_arg(&arg_matches, "wal-retention-secs", &mut errors) {
+        conf.wal_retention_time = Some(Duration::from_secs(secs));
+    }
+    if arg_matches.is_present("gc-coordinated") {
+        conf.gc_coordinated = true;
+    }
+    conf.workers = parse_arg(&arg_matches, "workers", &mut errors);
 
     if arg_matches.is_present("daemonize") {
         conf.daemonize = true;
     }
 
-    if let Some(addr) = arg_matches.value_of("listen") {
-        conf.listen_addr = addr.parse().unwrap();
+    if let Some(addr) = parse_arg(&arg_matches, "listen", &mut errors) {
+        conf.listen_addr = addr;
+    }
+
+    conf.metrics_top_tenants = parse_arg(&arg_matches, "metrics-top-tenants", &mut errors);
+
+    if let Some(dir) = arg_matches.value_of("trace-dir") {
+        conf.trace_dir = Some(PathBuf::from(dir));
+    }
+    if let Some(path) = arg_matches.value_of("shutdown-report") {
+        conf.shutdown_report = Some(PathBuf::from(path));
+    }
+
+    conf.pageserver_addr = parse_arg(&arg_matches, "pageserver", &mut errors);
+    conf.http_addr = parse_arg(&arg_matches, "http-listen", &mut errors);
+
+    if let Some(callback) = parse_arg(&arg_matches, "callback", &mut errors) {
+        conf.callback = callback;
+    }
+
+    if let Some(peers) = arg_matches.value_of("peers") {
+        let peers: Vec<String> = peers.split(',').map(|peer| peer.trim().to_string()).collect();
+        let majority = peers.len() as u32 / 2 + 1;
+        conf.acceptor_set = Some(AcceptorSetConf {
+            node_index: parse_arg(&arg_matches, "node-index", &mut errors).unwrap_or(0),
+            quorum: parse_arg(&arg_matches, "quorum", &mut errors).unwrap_or(majority),
+            peers: peers,
+        });
+    }
+
+    if let Some(tenant) = arg_matches.value_of("legacy-tenant") {
+        match walkeeper::parse_tenant_id(tenant) {
+            Ok(id) => conf.legacy_tenant = Some(id),
+            Err(e) => errors.push(format!("invalid value of --legacy-tenant: {}", e)),
+        }
+    }
+
+    if let Some(uuid) = arg_matches.value_of("node-uuid") {
+        match node_file::parse_uuid(uuid) {
+            Ok(uuid) => conf.node_uuid = Some(uuid),
+            Err(e) => errors.push(format!("invalid value of --node-uuid: {}", e)),
+        }
+    }
+
+    if let (Some(cert), Some(key)) = (
+        arg_matches.value_of("tls-cert"),
+        arg_matches.value_of("tls-key"),
+    ) {
+        match TlsConf::load(Path::new(cert), Path::new(key)) {
+            Ok(tls) => conf.tls = Some(tls),
+            Err(e) => errors.push(format!("failed to load TLS certificate: {}", e)),
+        }
+    }
+
+    if let Some(path) = arg_matches.value_of("encryption-key-file") {
+        match AtRestKey::load(Path::new(path)) {
+            Ok(key) => conf.at_rest_key = Some(key),
+            Err(e) => errors.push(format!("failed to load encryption key: {}", e)),
+        }
+    }
+
+    if let Some(bucket) = arg_matches.value_of("object-storage") {
+        let prefix = arg_matches.value_of("object-storage-prefix").unwrap_or("wal");
+        let chunk_size = parse_arg(&arg_matches, "object-storage-chunk-size", &mut errors)
+            .unwrap_or(object_storage::DEFAULT_CHUNK_SIZE);
+        match BucketConf::new(bucket, prefix) {
+            Ok(bucket) => {
+                conf.object_storage = Some(ObjectStorageConf {
+                    bucket: bucket,
+                    chunk_size: chunk_size,
+                })
+            }
+            Err(e) => errors.push(format!("failed to configure object storage: {}", e)),
+        }
+    }
+    if let Some(bucket) = arg_matches.value_of("archive") {
+        let prefix = arg_matches.value_of("archive-prefix").unwrap_or("archive");
+        match BucketConf::new(bucket, prefix) {
+            Ok(bucket) => conf.archive = Some(bucket),
+            Err(e) => errors.push(format!("failed to configure WAL archive: {}", e)),
+        }
     }
 
-    if let Some(addr) = arg_matches.value_of("pageserver") {
-        conf.pageserver_addr = Some(addr.parse().unwrap());
+    conf.access_list = AccessList {
+        allow: parse_networks(&arg_matches, "allow", &mut errors),
+        deny: parse_networks(&arg_matches, "deny", &mut errors),
+    };
+    conf.http_access_list = AccessList {
+        allow: parse_networks(&arg_matches, "http-allow", &mut errors),
+        deny: parse_networks(&arg_matches, "http-deny", &mut errors),
+    };
+
+    /*
+     * Validate before taking over the socket: the old process exits after handoff,
+     * so the new one must not fail afterwards because of bad configuration.
+     */
+    let takeover = arg_matches.is_present("takeover");
+    let inherited = handoff::inherited_listener()?;
+    errors.extend(conf.validate(!takeover && inherited.is_none()));
+    if errors.is_empty() {
+        /* Data directory is known to be writable now */
+        match node_file::load_or_create(&conf.data_dir, conf.node_uuid) {
+            Ok(node) => conf.node_uuid = Some(node_file::parse_uuid(&node.uuid)?),
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    if !errors.is_empty() {
+        eprintln!("wal_acceptor is not started, configuration has {} problem(s):", errors.len());
+        for error in &errors {
+            eprintln!("  - {}", error);
+        }
+        std::process::exit(1);
+    }
+    if arg_matches.is_present("dry-run") {
+        let (n_tenants, problems) = wal_service::check_tenants(&conf);
+        if !problems.is_empty() {
+            eprintln!("dry run failed, {} problem(s) found:", problems.len());
+            for problem in &problems {
+                eprintln!("  - {}", problem);
+            }
+            std::process::exit(1);
+        }
+        println!("dry run succeeded, {} tenants checked", n_tenants);
+        return Ok(());
     }
 
-    start_wal_acceptor(conf)
+    let listener = if takeover {
+        Some(handoff::takeover(&conf.data_dir)?)
+    } else {
+        inherited
+    };
+
+    start_wal_acceptor(conf, listener)
+}
+
+// Parse value of the option if it is specified, remembering the error for the report
+fn parse_arg<T>(matches: &ArgMatches, name: &str, errors: &mut Vec<String>) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = matches.value_of(name)?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            errors.push(format!("invalid value '{}' of --{}: {}", value, name, e));
+            None
+        }
+    }
 }
 
-fn start_wal_acceptor(conf: WalAcceptorConf) -> Result<(), io::Error> {
+// Parse comma separated list of networks, remembering errors for the report
+fn parse_networks(matches: &ArgMatches, name: &str, errors: &mut Vec<String>) -> Vec<Cidr> {
+    let mut networks = Vec::new();
+    for value in matches.values_of(name).into_iter().flatten() {
+        match value.trim().parse() {
+            Ok(network) => networks.push(network),
+            Err(e) => errors.push(format!("invalid network in --{}: {}", name, e)),
+        }
+    }
+    networks
+}
+
+fn start_wal_acceptor(
+    conf: WalAcceptorConf,
+    listener: Option<std::net::TcpListener>,
+) -> Result<(), io::Error> {
     // Initialize logger
     let _scope_guard = init_logging(&conf)?;
     let _log_guard = slog_stdlog::init().unwrap();
@@ -125,12 +835,20 @@ fn start_wal_acceptor(conf: WalAcceptorConf) -> Result<(), io::Error> {
         }
     }
 
+    if let Some(id) = conf.legacy_tenant {
+        legacy_layout::migrate(&conf.data_dir, id)?;
+    }
+
+    if conf.control_file_version.is_some() {
+        wal_service::convert_control_files(&conf)?;
+    }
+
     let mut threads = Vec::new();
     let wal_acceptor_thread = thread::Builder::new()
         .name("WAL acceptor thread".into())
         .spawn(|| {
             // thread code
-            wal_service::thread_main(conf);
+            wal_service::thread_main(conf, listener);
         })
         .unwrap();
     threads.push(wal_acceptor_thread);
@@ -152,12 +870,14 @@ fn init_logging(conf: &WalAcceptorConf) -> Result<slog_scope::GlobalLoggerGuard,
         let decorator = slog_term::PlainSyncDecorator::new(log_file);
         let drain = slog_term::CompactFormat::new(decorator).build();
         let drain = std::sync::Mutex::new(drain).fuse();
+        let drain = RuntimeFilterDrain(drain);
         let logger = slog::Logger::root(drain, slog::o!());
         Ok(slog_scope::set_global_logger(logger))
     } else {
         let decorator = slog_term::TermDecorator::new().build();
         let drain = slog_term::FullFormat::new(decorator).build().fuse();
         let drain = slog_async::Async::new(drain).chan_size(1000).build().fuse();
+        let drain = RuntimeFilterDrain(drain);
         let logger = slog::Logger::root(drain, slog::o!());
         Ok(slog_scope::set_global_logger(logger))
     }
@@ -0,0 +1,130 @@
+//
+//   Notification of the pageserver that WAL of a system is available on this safekeeper.
+//
+//   Replication in Postgres is initiated by the receiver, so when a proposer connects
+//   we have to ask the pageserver to come and start streaming from us.
+//
+use async_trait::async_trait;
+use hyper::{Body, Client, Method, Request};
+use log::*;
+use serde_derive::Serialize;
+use std::io;
+use std::net::SocketAddr;
+use tokio_postgres::{connect, NoTls};
+
+use crate::pq_protocol::{Result, SystemId};
+use crate::{CallbackConf, WalAcceptorConf};
+
+#[async_trait]
+pub trait PageserverCallback: Send + Sync {
+    async fn request_callback(&self, system_id: SystemId) -> Result<()>;
+}
+
+fn other_error<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+//
+// Send "callmemaybe" simple query to the pageserver
+//
+pub struct CallMeMaybe {
+    pageserver_addr: SocketAddr,
+    listen_addr: SocketAddr,
+}
+
+#[async_trait]
+impl PageserverCallback for CallMeMaybe {
+    async fn request_callback(&self, system_id: SystemId) -> Result<()> {
+        let ps_connstr = format!(
+            "host={} port={} dbname={} user={}",
+            self.pageserver_addr.ip(),
+            self.pageserver_addr.port(),
+            "no_db",
+            "no_user",
+        );
+        let callme = format!(
+            "callmemaybe host={} port={} replication=1 options='-c system.id={}'",
+            self.listen_addr.ip(),
+            self.listen_addr.port(),
+            system_id,
+        );
+        let (client, connection) = connect(&ps_connstr, NoTls).await.map_err(other_error)?;
+
+        // The connection object performs the actual communication with the database,
+        // so spawn it off to run on its own.
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("pageserver connection error: {}", e);
+            }
+        });
+        client.simple_query(&callme).await.map_err(other_error)?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookPayload {
+    system_id: SystemId,
+    host: String,
+    port: u16,
+}
+
+//
+// POST JSON with the system id and address of this safekeeper to the URL
+//
+pub struct Webhook {
+    url: String,
+    listen_addr: SocketAddr,
+}
+
+#[async_trait]
+impl PageserverCallback for Webhook {
+    async fn request_callback(&self, system_id: SystemId) -> Result<()> {
+        let payload = WebhookPayload {
+            system_id,
+            host: self.listen_addr.ip().to_string(),
+            port: self.listen_addr.port(),
+        };
+        let body = serde_json::to_string(&payload).map_err(other_error)?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(&self.url)
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .map_err(other_error)?;
+        let resp = Client::new().request(req).await.map_err(other_error)?;
+        if !resp.status().is_success() {
+            io_error!("Webhook {} returned {}", self.url, resp.status());
+        }
+        Ok(())
+    }
+}
+
+//
+// Do nothing: pageserver finds safekeepers on its own, or there is no pageserver at all
+//
+pub struct NoCallback;
+
+#[async_trait]
+impl PageserverCallback for NoCallback {
+    async fn request_callback(&self, _system_id: SystemId) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn pageserver_callback(conf: &WalAcceptorConf) -> Box<dyn PageserverCallback> {
+    match &conf.callback {
+        CallbackConf::CallMeMaybe => match conf.pageserver_addr {
+            Some(addr) => Box::new(CallMeMaybe {
+                pageserver_addr: addr,
+                listen_addr: conf.listen_addr,
+            }),
+            None => Box::new(NoCallback),
+        },
+        CallbackConf::Webhook(url) => Box::new(Webhook {
+            url: url.clone(),
+            listen_addr: conf.listen_addr,
+        }),
+        CallbackConf::None => Box::new(NoCallback),
+    }
+}
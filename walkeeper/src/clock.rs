@@ -0,0 +1,99 @@
+//
+//   Clock of the safekeeper.
+//
+//   Timeouts, keepalives, catch-up throttling and backoff take time from here, not from
+//   std::time directly, so that tests can drive them with simulated time. By default
+//   it is the system clock. With TokioClock installed, time follows the clock of tokio
+//   runtime: after tokio::time::pause() (tokio "test-util" feature) both monotonic and
+//   wall clock time move only by tokio::time::advance() or auto-advance of idle runtime,
+//   and sleep() and timeout() of this module complete in simulated time.
+//
+use lazy_static::lazy_static;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::error::Elapsed;
+
+use crate::xlog_utils::{to_pg_timestamp, TimestampTz};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant; /* monotonic time */
+    fn system_time(&self) -> SystemTime; /* wall clock time */
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+//
+// Clock of tokio runtime, wall clock time starts at the given time when it is created
+//
+pub struct TokioClock {
+    start: tokio::time::Instant,
+    start_time: SystemTime,
+}
+
+impl TokioClock {
+    pub fn new(start_time: SystemTime) -> TokioClock {
+        TokioClock {
+            start: tokio::time::Instant::now(),
+            start_time: start_time,
+        }
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_time + (tokio::time::Instant::now() - self.start)
+    }
+}
+
+lazy_static! {
+    static ref CLOCK: RwLock<Arc<dyn Clock>> = RwLock::new(Arc::new(SystemClock));
+}
+
+// Install the clock, returning the previous one
+pub fn set_clock(clock: Arc<dyn Clock>) -> Arc<dyn Clock> {
+    std::mem::replace(&mut *CLOCK.write().unwrap(), clock)
+}
+
+pub fn now() -> Instant {
+    CLOCK.read().unwrap().now()
+}
+
+pub fn system_time() -> SystemTime {
+    CLOCK.read().unwrap().system_time()
+}
+
+// Current time as Postgres timestamp
+pub fn timestamp() -> TimestampTz {
+    to_pg_timestamp(system_time())
+}
+
+pub fn elapsed(since: Instant) -> Duration {
+    now().saturating_duration_since(since)
+}
+
+//
+// Sleeps and timeouts are those of tokio: its timer is the one paused and advanced
+// by tests, so they agree with TokioClock.
+//
+pub fn sleep(duration: Duration) -> tokio::time::Sleep {
+    tokio::time::sleep(duration)
+}
+
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    tokio::time::timeout(duration, future).await
+}
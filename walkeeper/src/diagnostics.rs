@@ -0,0 +1,417 @@
+//
+//   Runtime health diagnostics, to triage "the safekeeper feels slow" without gdb.
+//
+//   - Scheduling lag of each runtime: a probe task sleeps for PROBE_INTERVAL and measures
+//     how late it is woken up. With the single-threaded runtimes this is the time some
+//     task kept the thread busy without yielding (e.g. blocking fsync), the closest thing
+//     to poll latency and queue depth which tokio lets us observe.
+//   - Lock wait statistics of tenant registries and of tenant state mutexes.
+//   - Live connections with their age, protocol state and buffer sizes: the longest
+//     running ones, and the ones holding most memory in buffers. State and the time
+//     spent in it tell where a hung connection is stuck.
+//   - Termination of a single connection by its id, to kick a misbehaving replica or
+//     stale proposer: the connection is woken up, says goodbye to the peer and closes.
+//
+use lazy_static::lazy_static;
+use serde_derive::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time::sleep;
+
+use crate::pq_protocol::SystemId;
+
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+const PROBE_WINDOW: usize = 600; /* samples kept, a minute of probing */
+const TOP_CONNECTIONS: usize = 10;
+
+//
+// Wait statistics of a lock (or of a group of locks of the same kind)
+//
+#[derive(Debug)]
+pub struct LockStats {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_us: AtomicU64,
+    max_wait_us: AtomicU64,
+}
+
+impl LockStats {
+    pub const fn new() -> LockStats {
+        LockStats {
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            wait_us: AtomicU64::new(0),
+            max_wait_us: AtomicU64::new(0),
+        }
+    }
+
+    // Lock the mutex, accounting time spent waiting for it
+    pub fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        match mutex.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => {
+                let started = Instant::now();
+                let guard = mutex.lock().unwrap();
+                let waited = started.elapsed().as_micros() as u64;
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.wait_us.fetch_add(waited, Ordering::Relaxed);
+                self.max_wait_us.fetch_max(waited, Ordering::Relaxed);
+                guard
+            }
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        }
+    }
+
+    fn report(&self, name: &str) -> LockReport {
+        LockReport {
+            name: name.to_string(),
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            wait_us: self.wait_us.load(Ordering::Relaxed),
+            max_wait_us: self.max_wait_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub static SYSTEMS_LOCK: LockStats = LockStats::new();
+pub static TENANT_LOCKS: LockStats = LockStats::new();
+
+//
+// Protocol state of a connection
+//
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ConnectionState {
+    AwaitingGreeting, /* startup packet or proposer greeting and server info */
+    Voting,           /* proposer handshake done, waiting for vote request */
+    Appending,        /* receiving WAL from the elected proposer */
+    Idle,             /* libpq client between commands */
+    StreamingCatchup, /* WAL sender more than a segment behind commit LSN */
+    StreamingLive,    /* WAL sender following commit LSN */
+    Draining,         /* connection is being closed by shutdown or end of stream */
+}
+
+#[derive(Debug)]
+struct ConnectionInfo {
+    peer: Option<SocketAddr>,
+    tenant: Option<SystemId>,
+    started: Instant,
+    state: ConnectionState,
+    state_changed: Instant,
+    buffer_bytes: usize,
+    peak_buffer_bytes: usize,
+    termination: Arc<Termination>,
+}
+
+//
+// Termination request of a connection, shared by the registry and the connection
+//
+#[derive(Debug, Default)]
+struct Termination {
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+lazy_static! {
+    static ref RUNTIME_LAG: Mutex<HashMap<String, VecDeque<Duration>>> = Mutex::new(HashMap::new());
+    static ref CONNECTIONS: Mutex<HashMap<u64, ConnectionInfo>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+//
+// Registration of a live connection, it is unregistered when this is dropped
+//
+#[derive(Debug)]
+pub struct ConnectionRegistration {
+    id: u64,
+    termination: Arc<Termination>,
+}
+
+impl ConnectionRegistration {
+    pub fn new(peer: Option<SocketAddr>) -> ConnectionRegistration {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let termination = Arc::new(Termination::default());
+        CONNECTIONS.lock().unwrap().insert(
+            id,
+            ConnectionInfo {
+                peer: peer,
+                tenant: None,
+                started: Instant::now(),
+                state: ConnectionState::AwaitingGreeting,
+                state_changed: Instant::now(),
+                buffer_bytes: 0,
+                peak_buffer_bytes: 0,
+                termination: termination.clone(),
+            },
+        );
+        ConnectionRegistration {
+            id: id,
+            termination: termination,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_tenant(&self, tenant: SystemId) {
+        if let Some(info) = CONNECTIONS.lock().unwrap().get_mut(&self.id) {
+            info.tenant = Some(tenant);
+        }
+    }
+
+    pub fn set_state(&self, state: ConnectionState) {
+        if let Some(info) = CONNECTIONS.lock().unwrap().get_mut(&self.id) {
+            if info.state != state {
+                info.state = state;
+                info.state_changed = Instant::now();
+            }
+        }
+    }
+
+    // Current capacity of the connection buffers, the peak is tracked as well
+    pub fn set_buffers(&self, bytes: usize) {
+        if let Some(info) = CONNECTIONS.lock().unwrap().get_mut(&self.id) {
+            info.buffer_bytes = bytes;
+            info.peak_buffer_bytes = info.peak_buffer_bytes.max(bytes);
+        }
+    }
+
+    // Whether administrator has asked to terminate the connection
+    pub fn terminate_requested(&self) -> bool {
+        self.termination.requested.load(Ordering::SeqCst)
+    }
+
+    // Completes when the connection is asked to terminate, to be selected on while waiting
+    pub async fn terminated(&self) {
+        if !self.terminate_requested() {
+            /* A request coming after the check leaves a permit, so it is not missed */
+            self.termination.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for ConnectionRegistration {
+    fn drop(&mut self) {
+        CONNECTIONS.lock().unwrap().remove(&self.id);
+    }
+}
+
+//
+// Ask the connection to terminate. Returns false if there is no such connection.
+//
+pub fn terminate_connection(id: u64) -> bool {
+    match CONNECTIONS.lock().unwrap().get(&id) {
+        Some(info) => {
+            info.termination.requested.store(true, Ordering::SeqCst);
+            info.termination.notify.notify_one();
+            true
+        }
+        None => false,
+    }
+}
+
+//
+// Ask all connections of the tenant to terminate. Returns their number.
+//
+pub fn terminate_tenant_connections(tenant: SystemId) -> usize {
+    let connections = CONNECTIONS.lock().unwrap();
+    let mut n_terminated = 0;
+    for info in connections.values().filter(|info| info.tenant == Some(tenant)) {
+        info.termination.requested.store(true, Ordering::SeqCst);
+        info.termination.notify.notify_one();
+        n_terminated += 1;
+    }
+    n_terminated
+}
+
+//
+// Measure scheduling lag of the runtime this task is spawned on. Runs forever.
+//
+pub async fn lag_probe(runtime_name: String) {
+    loop {
+        let started = Instant::now();
+        sleep(PROBE_INTERVAL).await;
+        let lag = started
+            .elapsed()
+            .checked_sub(PROBE_INTERVAL)
+            .unwrap_or_default();
+        let mut runtimes = RUNTIME_LAG.lock().unwrap();
+        let samples = runtimes.entry(runtime_name.clone()).or_default();
+        if samples.len() == PROBE_WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(lag);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuntimeReport {
+    pub name: String,
+    pub last_lag_us: u64,
+    pub avg_lag_us: u64,
+    pub max_lag_us: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LockReport {
+    pub name: String,
+    pub acquisitions: u64,
+    pub contended: u64,
+    pub wait_us: u64,
+    pub max_wait_us: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionReport {
+    pub id: u64,
+    pub peer: Option<String>,
+    pub tenant: Option<SystemId>,
+    pub age_secs: f64,
+    pub state: ConnectionState,
+    pub state_secs: f64, /* time since the last state change */
+    pub buffer_bytes: usize,
+    pub peak_buffer_bytes: usize,
+    pub terminating: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    pub runtimes: Vec<RuntimeReport>,
+    pub locks: Vec<LockReport>,
+    pub connections: usize,
+    pub buffer_bytes: usize, /* buffers of all connections */
+    pub longest_connections: Vec<ConnectionReport>,
+    pub largest_buffers: Vec<ConnectionReport>,
+}
+
+pub fn report() -> Diagnostics {
+    let mut runtimes: Vec<RuntimeReport> = RUNTIME_LAG
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, samples)| {
+            let n = samples.len().max(1) as u64;
+            RuntimeReport {
+                name: name.clone(),
+                last_lag_us: samples.back().map_or(0, |lag| lag.as_micros() as u64),
+                avg_lag_us: samples.iter().map(|lag| lag.as_micros() as u64).sum::<u64>() / n,
+                max_lag_us: samples.iter().max().map_or(0, |lag| lag.as_micros() as u64),
+            }
+        })
+        .collect();
+    runtimes.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let connections = CONNECTIONS.lock().unwrap();
+    let mut longest: Vec<ConnectionReport> =
+        connections.iter().map(|(id, info)| connection_report(id, info)).collect();
+    longest.sort_by(|a, b| b.age_secs.partial_cmp(&a.age_secs).unwrap());
+    longest.truncate(TOP_CONNECTIONS);
+    let mut largest: Vec<ConnectionReport> =
+        connections.iter().map(|(id, info)| connection_report(id, info)).collect();
+    largest.sort_by(|a, b| b.buffer_bytes.cmp(&a.buffer_bytes));
+    largest.truncate(TOP_CONNECTIONS);
+
+    Diagnostics {
+        runtimes: runtimes,
+        locks: vec![
+            SYSTEMS_LOCK.report("SYSTEMS"),
+            TENANT_LOCKS.report("tenant state"),
+        ],
+        connections: connections.len(),
+        buffer_bytes: connections.values().map(|info| info.buffer_bytes).sum(),
+        longest_connections: longest,
+        largest_buffers: largest,
+    }
+}
+
+fn connection_report(id: &u64, info: &ConnectionInfo) -> ConnectionReport {
+    ConnectionReport {
+        id: *id,
+        peer: info.peer.map(|peer| peer.to_string()),
+        tenant: info.tenant,
+        age_secs: info.started.elapsed().as_secs_f64(),
+        state: info.state,
+        state_secs: info.state_changed.elapsed().as_secs_f64(),
+        buffer_bytes: info.buffer_bytes,
+        peak_buffer_bytes: info.peak_buffer_bytes,
+        terminating: info.termination.requested.load(Ordering::SeqCst),
+    }
+}
+
+//
+// All live connections, oldest first
+//
+pub fn connections() -> Vec<ConnectionReport> {
+    let mut connections: Vec<ConnectionReport> = CONNECTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, info)| connection_report(id, info))
+        .collect();
+    connections.sort_by_key(|conn| conn.id);
+    connections
+}
+
+impl ConnectionReport {
+    pub fn describe(&self, output: &mut String) {
+        writeln!(
+            output,
+            "  #{} peer={} tenant={} age={:.0}s state={:?} for {:.0}s buffers={}KiB peak={}KiB{}",
+            self.id,
+            self.peer.as_deref().unwrap_or("unknown"),
+            self.tenant
+                .map_or("none".to_string(), |tenant| tenant.to_string()),
+            self.age_secs,
+            self.state,
+            self.state_secs,
+            self.buffer_bytes / 1024,
+            self.peak_buffer_bytes / 1024,
+            if self.terminating { " terminating" } else { "" }
+        )
+        .unwrap();
+    }
+}
+
+impl Diagnostics {
+    pub fn describe(&self) -> String {
+        let mut output = String::new();
+        for runtime in &self.runtimes {
+            writeln!(
+                output,
+                "runtime {}: scheduling lag last={}us avg={}us max={}us",
+                runtime.name, runtime.last_lag_us, runtime.avg_lag_us, runtime.max_lag_us
+            )
+            .unwrap();
+        }
+        for lock in &self.locks {
+            writeln!(
+                output,
+                "lock {}: acquisitions={} contended={} wait={}us max_wait={}us",
+                lock.name, lock.acquisitions, lock.contended, lock.wait_us, lock.max_wait_us
+            )
+            .unwrap();
+        }
+        writeln!(
+            output,
+            "connections: {} buffers={}KiB",
+            self.connections,
+            self.buffer_bytes / 1024
+        )
+        .unwrap();
+        for conn in &self.longest_connections {
+            conn.describe(&mut output);
+        }
+        writeln!(output, "largest buffers:").unwrap();
+        for conn in &self.largest_buffers {
+            conn.describe(&mut output);
+        }
+        output
+    }
+}
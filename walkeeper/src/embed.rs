@@ -0,0 +1,180 @@
+//
+//   Embedding of wal_acceptor into another program, e.g. tests running safekeepers in
+//   their own process.
+//
+//   WalAcceptor::builder().conf(conf).storage(data_dir).spawn() does what wal_acceptor
+//   binary does after parsing options: validates configuration, checks identity of the
+//   data directory and starts serving on a runtime in its own thread. The returned handle
+//   waits until the WAL service listens, gives access to tenants and shuts the instance
+//   down: the runtime is stopped with its listeners and connections, tenants are flushed
+//   for the last time (see shutdown.rs) and unloaded with their locks released, so that
+//   another instance may be started in the same process afterwards. Each instance has
+//   its own tenant registry, so instances serving different data directories may run
+//   side by side.
+//
+use log::*;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tokio::sync::oneshot;
+
+use crate::node_file;
+use crate::pq_protocol::{Result, SystemId};
+use crate::shutdown;
+use crate::wal_service::{self, System, TenantRegistry};
+use crate::WalAcceptorConf;
+
+#[derive(Debug, Default)]
+pub struct WalAcceptorBuilder {
+    conf: Option<WalAcceptorConf>,
+    data_dir: Option<PathBuf>,
+    listener: Option<TcpListener>,
+}
+
+impl WalAcceptorBuilder {
+    pub fn conf(mut self, conf: WalAcceptorConf) -> WalAcceptorBuilder {
+        self.conf = Some(conf);
+        self
+    }
+
+    // Data directory, overrides the one of the configuration
+    pub fn storage(mut self, data_dir: &Path) -> WalAcceptorBuilder {
+        self.data_dir = Some(data_dir.to_path_buf());
+        self
+    }
+
+    // Already bound listening socket, instead of binding listen_addr of the configuration
+    pub fn listener(mut self, listener: TcpListener) -> WalAcceptorBuilder {
+        self.listener = Some(listener);
+        self
+    }
+
+    pub fn spawn(self) -> Result<WalAcceptor> {
+        let mut conf = match self.conf {
+            Some(conf) => conf,
+            None => {
+                io_error!("Configuration of embedded wal_acceptor is not specified");
+            }
+        };
+        if let Some(data_dir) = self.data_dir {
+            conf.data_dir = data_dir;
+        }
+        let errors = conf.validate(self.listener.is_none());
+        if !errors.is_empty() {
+            io_error!("Invalid configuration: {}", errors.join("; "));
+        }
+        let node = node_file::load_or_create(&conf.data_dir, conf.node_uuid)?;
+        conf.node_uuid = Some(node_file::parse_uuid(&node.uuid)?);
+
+        let runtime = wal_service::build_runtime(&conf)?;
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let listener = self.listener;
+        let tenants = TenantRegistry::new();
+        let serve_conf = conf.clone();
+        let serve_tenants = tenants.clone();
+        let thread = thread::Builder::new()
+            .name("embedded wal_acceptor".into())
+            .spawn(move || {
+                runtime.block_on(async {
+                    let serve =
+                        wal_service::serve(serve_conf, serve_tenants, listener, Some(ready_tx));
+                    tokio::select! {
+                        _ = serve => {}
+                        _ = stop_rx => {}
+                    }
+                });
+                /* Dropping the runtime closes listeners and connections */
+                drop(runtime);
+            })?;
+        Ok(WalAcceptor {
+            conf: conf,
+            tenants: tenants,
+            ready: ready_rx,
+            listen_addr: None,
+            stop: Some(stop_tx),
+            thread: Some(thread),
+        })
+    }
+}
+
+//
+// Handle of embedded wal_acceptor. It is shut down when the handle is dropped.
+//
+#[derive(Debug)]
+pub struct WalAcceptor {
+    conf: WalAcceptorConf,
+    tenants: Arc<TenantRegistry>,
+    ready: Receiver<SocketAddr>,
+    listen_addr: Option<SocketAddr>,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WalAcceptor {
+    pub fn builder() -> WalAcceptorBuilder {
+        WalAcceptorBuilder::default()
+    }
+
+    pub fn conf(&self) -> &WalAcceptorConf {
+        &self.conf
+    }
+
+    // Wait until the WAL service is listening, returns its address
+    pub fn wait_ready(&mut self) -> Result<SocketAddr> {
+        if let Some(addr) = self.listen_addr {
+            return Ok(addr);
+        }
+        match self.ready.recv() {
+            Ok(addr) => {
+                self.listen_addr = Some(addr);
+                Ok(addr)
+            }
+            Err(_) => {
+                io_error!("Embedded wal_acceptor has failed to start, see the log");
+            }
+        }
+    }
+
+    // Tenant loaded by this instance
+    pub fn tenant(&self, id: SystemId) -> Option<Arc<System>> {
+        self.tenants.get_system(id)
+    }
+
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        self.stop.take();
+        if thread.join().is_err() {
+            io_error!("Embedded wal_acceptor has panicked");
+        }
+        let systems = self.tenants.get_systems();
+        let report = shutdown::flush_and_report(&self.conf, &systems, "embedded shutdown");
+        let n_tenants = self.tenants.unload_all();
+        info!(
+            "Embedded wal_acceptor in {:?} is shut down, {} tenants unloaded",
+            self.conf.data_dir, n_tenants
+        );
+        if !report.is_clean() {
+            io_error!("Final flush of some tenants has failed, see the log");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WalAcceptor {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!("Failed to shut down embedded wal_acceptor: {}", e);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//
+//   Bus of internal events, for synchronization of tests driving the safekeeper.
+//
+//   Instead of sleeping and hoping that the safekeeper has got to the expected state,
+//   tests subscribe to the bus and wait for the event they need. The bus is compiled
+//   in only with the "test-events" feature, otherwise emit() does nothing.
+//
+#[cfg(feature = "test-events")]
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+#[cfg(feature = "test-events")]
+use lazy_static::lazy_static;
+#[cfg(feature = "test-events")]
+use std::sync::Mutex;
+#[cfg(feature = "test-events")]
+use std::time::{Duration, Instant};
+
+use crate::pq_protocol::SystemId;
+use crate::xlog_utils::{TimeLineID, XLogRecPtr, XLogSegNo};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    SegmentCompleted {
+        system_id: SystemId,
+        segno: XLogSegNo,
+        timeline: TimeLineID,
+    },
+    ControlFileSynced {
+        system_id: SystemId,
+        epoch: u64,
+        flush_lsn: XLogRecPtr,
+    },
+    EpochSwitched {
+        system_id: SystemId,
+        epoch: u64,
+    },
+    CommitAdvanced {
+        system_id: SystemId,
+        commit_lsn: XLogRecPtr,
+    },
+}
+
+#[cfg(feature = "test-events")]
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<Vec<Sender<Event>>> = Mutex::new(Vec::new());
+}
+
+// Deliver event to all subscribers
+#[cfg(feature = "test-events")]
+pub fn emit(event: Event) {
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+}
+
+#[cfg(not(feature = "test-events"))]
+#[inline(always)]
+pub fn emit(_event: Event) {}
+
+//
+// Events emitted after subscription, until it is dropped
+//
+#[cfg(feature = "test-events")]
+pub struct Subscription {
+    receiver: Receiver<Event>,
+}
+
+#[cfg(feature = "test-events")]
+pub fn subscribe() -> Subscription {
+    let (sender, receiver) = unbounded();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    Subscription { receiver: receiver }
+}
+
+#[cfg(feature = "test-events")]
+impl Subscription {
+    // Wait for event matching the predicate, skipping others. None on timeout.
+    pub fn wait_for<P>(&self, timeout: Duration, predicate: P) -> Option<Event>
+    where
+        P: Fn(&Event) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(left) {
+                Ok(event) if predicate(&event) => return Some(event),
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                    return None
+                }
+            }
+        }
+    }
+
+    // Events received so far, without waiting
+    pub fn drain(&self) -> Vec<Event> {
+        self.receiver.try_iter().collect()
+    }
+}
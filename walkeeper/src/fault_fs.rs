@@ -0,0 +1,233 @@
+//
+//   Fault-injecting layer over storage writes of the safekeeper, for crash-recovery tests.
+//
+//   Storage code reports each write and fsync of WAL segments and control files
+//   before doing it. While the layer is armed, writes to files under its root are
+//   remembered until the file is synced. After a given number of operations the
+//   simulated machine "crashes": this and all further operations fail. crash() then
+//   rewrites the files with what could have survived: the synced content plus a random
+//   subset of unsynced writes, applied in random order and torn at sector granularity.
+//   Files are truncated to the surviving content. Renames are considered atomic and durable.
+//   The layer is compiled in only with the "testing" feature, otherwise the hooks called
+//   by storage code do nothing.
+//
+#[cfg(feature = "testing")]
+use lazy_static::lazy_static;
+#[cfg(feature = "testing")]
+use log::*;
+#[cfg(feature = "testing")]
+use rand::rngs::StdRng;
+#[cfg(feature = "testing")]
+use rand::seq::SliceRandom;
+#[cfg(feature = "testing")]
+use rand::{Rng, SeedableRng};
+#[cfg(feature = "testing")]
+use std::collections::HashMap;
+#[cfg(feature = "testing")]
+use std::fs;
+#[cfg(feature = "testing")]
+use std::io;
+#[cfg(feature = "testing")]
+use std::path::PathBuf;
+#[cfg(feature = "testing")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "testing")]
+use std::sync::Mutex;
+
+use std::path::Path;
+
+use crate::pq_protocol::Result;
+
+#[cfg(feature = "testing")]
+const SECTOR_SIZE: u64 = 512; /* disks don't tear writes within a sector */
+
+#[cfg(feature = "testing")]
+lazy_static! {
+    static ref FAULT_FS: Mutex<Option<FaultFs>> = Mutex::new(None);
+}
+
+/* Fast path check, so that storage code doesn't take the lock when the layer is not armed */
+#[cfg(feature = "testing")]
+static ARMED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "testing")]
+#[derive(Debug, Default)]
+struct TrackedFile {
+    durable: Vec<u8>,            /* content which survives crash */
+    pending: Vec<(u64, Vec<u8>)>, /* writes since the last fsync: offset and data */
+}
+
+#[cfg(feature = "testing")]
+#[derive(Debug)]
+struct FaultFs {
+    root: PathBuf,
+    rng: StdRng,
+    ops: u64,
+    crash_after: u64,
+    crashed: bool,
+    files: HashMap<PathBuf, TrackedFile>,
+}
+
+#[cfg(feature = "testing")]
+impl FaultFs {
+    // Count operation, fail it if the machine has crashed
+    fn tick(&mut self) -> Result<()> {
+        if !self.crashed {
+            self.ops += 1;
+            self.crashed = self.ops >= self.crash_after;
+        }
+        if self.crashed {
+            io_error!("Simulated crash after {} storage operations", self.crash_after);
+        }
+        Ok(())
+    }
+}
+
+//
+// Start tracking writes to the files under root. The crash happens
+// at the crash_after-th write or fsync.
+//
+#[cfg(feature = "testing")]
+pub fn arm(root: &Path, seed: u64, crash_after: u64) {
+    *FAULT_FS.lock().unwrap() = Some(FaultFs {
+        root: root.to_path_buf(),
+        rng: StdRng::seed_from_u64(seed),
+        ops: 0,
+        crash_after: crash_after,
+        crashed: false,
+        files: HashMap::new(),
+    });
+    ARMED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(feature = "testing")]
+pub fn disarm() {
+    ARMED.store(false, Ordering::SeqCst);
+    *FAULT_FS.lock().unwrap() = None;
+}
+
+// Called before writing data at the given offset of the file
+#[cfg(feature = "testing")]
+pub fn write(path: &Path, offset: u64, data: &[u8]) -> Result<()> {
+    if !ARMED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let mut guard = FAULT_FS.lock().unwrap();
+    if let Some(fault_fs) = guard.as_mut() {
+        if path.starts_with(&fault_fs.root) {
+            fault_fs.tick()?;
+            /* Content of the file at the moment it is touched first is considered durable */
+            let file = fault_fs
+                .files
+                .entry(path.to_path_buf())
+                .or_insert_with(|| TrackedFile {
+                    durable: fs::read(path).unwrap_or_default(),
+                    pending: Vec::new(),
+                });
+            file.pending.push((offset, data.to_vec()));
+        }
+    }
+    Ok(())
+}
+
+// Called before fsync of the file
+#[cfg(feature = "testing")]
+pub fn sync(path: &Path) -> Result<()> {
+    if !ARMED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let mut guard = FAULT_FS.lock().unwrap();
+    if let Some(fault_fs) = guard.as_mut() {
+        if path.starts_with(&fault_fs.root) {
+            fault_fs.tick()?;
+            if let Some(file) = fault_fs.files.get_mut(path) {
+                for (offset, data) in file.pending.drain(..) {
+                    apply_write(&mut file.durable, offset, &data);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Called after successful rename of the file
+#[cfg(feature = "testing")]
+pub fn rename(from: &Path, to: &Path) {
+    if !ARMED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut guard = FAULT_FS.lock().unwrap();
+    if let Some(fault_fs) = guard.as_mut() {
+        if let Some(file) = fault_fs.files.remove(from) {
+            fault_fs.files.insert(to.to_path_buf(), file);
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+fn apply_write(content: &mut Vec<u8>, offset: u64, data: &[u8]) {
+    let end = offset as usize + data.len();
+    if content.len() < end {
+        content.resize(end, 0u8);
+    }
+    content[offset as usize..end].copy_from_slice(data);
+}
+
+//
+// Simulate the state of the files after power loss and disarm the layer.
+// Must be called when nobody is writing to the files anymore.
+// Returns number of lost sectors.
+//
+#[cfg(feature = "testing")]
+pub fn crash() -> Result<usize> {
+    ARMED.store(false, Ordering::SeqCst);
+    let mut fault_fs = match FAULT_FS.lock().unwrap().take() {
+        Some(fault_fs) => fault_fs,
+        None => {
+            io_error!("Fault injection is not armed");
+        }
+    };
+    let mut lost = 0;
+    for (path, file) in fault_fs.files.iter_mut() {
+        let mut content = file.durable.clone();
+        file.pending.shuffle(&mut fault_fs.rng);
+        for (offset, data) in &file.pending {
+            let mut pos = *offset;
+            let end = offset + data.len() as u64;
+            while pos < end {
+                let sector_end = ((pos / SECTOR_SIZE + 1) * SECTOR_SIZE).min(end);
+                if fault_fs.rng.gen_bool(0.5) {
+                    let chunk = &data[(pos - offset) as usize..(sector_end - offset) as usize];
+                    apply_write(&mut content, pos, chunk);
+                } else {
+                    lost += 1;
+                }
+                pos = sector_end;
+            }
+        }
+        fs::write(path, &content)?;
+    }
+    info!(
+        "Simulated crash after {} storage operations: {} files touched, {} sectors lost",
+        fault_fs.ops,
+        fault_fs.files.len(),
+        lost
+    );
+    Ok(lost)
+}
+
+#[cfg(not(feature = "testing"))]
+#[inline(always)]
+pub fn write(_path: &Path, _offset: u64, _data: &[u8]) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(feature = "testing"))]
+#[inline(always)]
+pub fn sync(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(feature = "testing"))]
+#[inline(always)]
+pub fn rename(_from: &Path, _to: &Path) {}
@@ -0,0 +1,140 @@
+//
+//   Coordinated WAL removal.
+//
+//   Pageserver (or control plane) proposes a cutoff LSN below which it doesn't need WAL
+//   anymore. Safekeeper holds the proposal back by its own WAL horizon (restart LSN,
+//   archived LSN, positions of WAL senders and replicas, --wal-retention, segments not
+//   archived yet), rounds it down to a segment boundary and replies with the resulting
+//   effective cutoff. Segments below the cutoff are then removed by the GC task of the
+//   tenant in the background; progress of the removal is reported by "gc-status" admin
+//   command and GET /v1/tenant/{id}/gc.
+//
+//   With --gc-coordinated, WAL GC never goes beyond the highest confirmed cutoff. The
+//   cutoff is kept in memory only: after restart no WAL is removed until the next proposal.
+//
+use serde_derive::Serialize;
+use std::time::SystemTime;
+
+use crate::clock;
+use crate::xlog_utils::{format_lsn, XLogRecPtr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcState {
+    Pending, /* confirmed, waiting for the GC task */
+    Running,
+    Done,
+    Failed,
+}
+
+impl GcState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GcState::Pending => "pending",
+            GcState::Running => "running",
+            GcState::Done => "done",
+            GcState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GcProposal {
+    pub id: u64, /* increases with each proposal of the tenant */
+    pub proposed_lsn: XLogRecPtr,
+    pub cutoff_lsn: XLogRecPtr,   /* effective cutoff confirmed to the proposer */
+    pub limited_by: &'static str, /* what holds the cutoff below the proposal, or "proposal" */
+    pub initiator: String,
+    pub state: GcState,
+    pub error: Option<String>,
+    pub segments_total: usize, /* segments below the cutoff when removal started */
+    pub segments_removed: usize,
+    pub accepted: SystemTime,
+    pub finished: Option<SystemTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GcStatus {
+    pub id: u64,
+    pub proposed_lsn: String,
+    pub cutoff_lsn: String,
+    pub limited_by: String,
+    pub initiator: String,
+    pub state: String, /* pending, running, done or failed */
+    pub error: Option<String>,
+    pub segments_total: usize,
+    pub segments_removed: usize,
+    pub accepted: String,         /* RFC 3339 */
+    pub finished: Option<String>, /* RFC 3339 */
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339()
+}
+
+impl GcProposal {
+    pub fn new(
+        id: u64,
+        proposed_lsn: XLogRecPtr,
+        cutoff_lsn: XLogRecPtr,
+        limited_by: &'static str,
+        initiator: &str,
+    ) -> GcProposal {
+        GcProposal {
+            id: id,
+            proposed_lsn: proposed_lsn,
+            cutoff_lsn: cutoff_lsn,
+            limited_by: limited_by,
+            initiator: initiator.to_string(),
+            state: GcState::Pending,
+            error: None,
+            segments_total: 0,
+            segments_removed: 0,
+            accepted: clock::system_time(),
+            finished: None,
+        }
+    }
+
+    pub fn finish(&mut self, error: Option<String>) {
+        self.state = if error.is_some() {
+            GcState::Failed
+        } else {
+            GcState::Done
+        };
+        self.error = error;
+        self.finished = Some(clock::system_time());
+    }
+
+    pub fn status(&self) -> GcStatus {
+        GcStatus {
+            id: self.id,
+            proposed_lsn: format_lsn(self.proposed_lsn),
+            cutoff_lsn: format_lsn(self.cutoff_lsn),
+            limited_by: self.limited_by.to_string(),
+            initiator: self.initiator.clone(),
+            state: self.state.name().to_string(),
+            error: self.error.clone(),
+            segments_total: self.segments_total,
+            segments_removed: self.segments_removed,
+            accepted: rfc3339(self.accepted),
+            finished: self.finished.map(rfc3339),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut s = format!(
+            "removal #{} by {}: proposed {}, cutoff {} (limited by {}), {}, {}/{} segments removed\n",
+            self.id,
+            self.initiator,
+            format_lsn(self.proposed_lsn),
+            format_lsn(self.cutoff_lsn),
+            self.limited_by,
+            self.state.name(),
+            self.segments_removed,
+            self.segments_total
+        );
+        if let Some(error) = &self.error {
+            s += &format!("error: {}\n", error);
+        }
+        s
+    }
+}
@@ -0,0 +1,180 @@
+//
+//   Passing of the listening socket between wal_acceptor processes, e.g. on binary upgrade.
+//
+//   The socket can be inherited from systemd (socket activation, LISTEN_FDS protocol)
+//   or taken over from the running wal_acceptor: a new process started with --takeover
+//   sends "handoff" command to the admin socket of the old one and receives the listening
+//   socket with SCM_RIGHTS. The old process stops accepting connections, drains, flushes
+//   its tenants for the last time (see shutdown.rs) and exits.
+//   Connection attempts made in the meantime wait in the listen backlog, so proposers
+//   reconnect to the new process instead of getting "connection refused". Of listeners
+//   added at runtime (see listeners.rs) only the first one is passed, the others are closed.
+//   Only the listening socket is passed: established connections (proposers, WAL senders)
+//   end with the old process, and each proposer has to reconnect and win an election
+//   again. Handoff saves the restart from refused connections and reconnect backoff, not
+//   from elections.
+//
+use log::*;
+use std::env;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+use crate::admin::ADMIN_SOCKET_NAME;
+use crate::clock;
+use crate::pq_protocol::Result;
+use crate::shutdown;
+use crate::wal_service::TenantRegistry;
+use crate::WalAcceptorConf;
+
+const SD_LISTEN_FDS_START: RawFd = 3; /* first socket passed by systemd */
+const HANDOFF_GRACE: Duration = Duration::from_secs(1); /* time for proposers to get SHUTTING_DOWN */
+
+static LISTENER_FD: AtomicI32 = AtomicI32::new(-1);
+
+// Remember listening socket of this process, so that it can be handed off
+pub fn set_listener_fd(fd: RawFd) {
+    LISTENER_FD.store(fd, Ordering::SeqCst);
+}
+
+//
+// Listening socket passed by systemd socket activation, if any
+//
+pub fn inherited_listener() -> Result<Option<TcpListener>> {
+    match env::var("LISTEN_PID") {
+        Ok(pid) if pid == process::id().to_string() => {}
+        _ => return Ok(None),
+    }
+    let n_fds = env::var("LISTEN_FDS").unwrap_or_default();
+    if n_fds != "1" {
+        io_error!("Expected exactly one socket from systemd, LISTEN_FDS={:?}", n_fds);
+    }
+    /* Don't pass the socket on to the processes we start */
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    let listener = unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    info!("Listening socket {:?} is passed by systemd", listener.local_addr()?);
+    Ok(Some(listener))
+}
+
+//
+// Take over listening socket from the wal_acceptor running in the data directory.
+// Returns when the old process has exited and released its control files.
+//
+pub fn takeover(data_dir: &Path) -> Result<TcpListener> {
+    let mut stream = UnixStream::connect(data_dir.join(ADMIN_SOCKET_NAME))?;
+    stream.write_all(b"handoff\n")?;
+    let (first_byte, fd) = recv_fd(stream.as_raw_fd())?;
+    let fd = match fd {
+        Some(fd) => fd,
+        None => {
+            let mut reply = String::from(first_byte as char);
+            stream.read_to_string(&mut reply)?;
+            io_error!("wal_acceptor refused handoff: {}", reply.trim());
+        }
+    };
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    /* Old process closes the admin connection on exit */
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    info!("Took over listening socket {:?}", listener.local_addr()?);
+    Ok(listener)
+}
+
+//
+// Send listening socket of this process to the peer of the admin connection
+//
+pub fn send_listener(socket: RawFd) -> Result<()> {
+    let fd = LISTENER_FD.load(Ordering::SeqCst);
+    if fd < 0 {
+        io_error!("wal_acceptor is not listening yet");
+    }
+    send_fd(socket, fd)
+}
+
+//
+// Stop accepting connections, drain and exit, giving proposers time to learn
+// that they should reconnect
+//
+pub async fn exit_after_handoff(conf: &WalAcceptorConf, tenants: &TenantRegistry) {
+    tenants.listeners().stop_wal();
+    let n_tenants = tenants.drain();
+    info!(
+        "Listening socket is handed off, {} tenants are drained, exit in {:?}",
+        n_tenants, HANDOFF_GRACE
+    );
+    clock::sleep(HANDOFF_GRACE).await;
+    let clean = shutdown::flush_all(conf, tenants, "handoff").await;
+    process::exit(if clean { 0 } else { 1 });
+}
+
+fn send_fd(socket: RawFd, fd: RawFd) -> Result<()> {
+    let mut data = [b'F'];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    unsafe {
+        let space = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as usize;
+        let mut control = vec![0u8; space];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        if libc::sendmsg(socket, &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/* Receive one byte of data and descriptor passed with it, if any */
+fn recv_fd(socket: RawFd) -> Result<(u8, Option<RawFd>)> {
+    let mut data = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    unsafe {
+        let space = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as usize;
+        let mut control = vec![0u8; space];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        let n = libc::recvmsg(socket, &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by wal_acceptor",
+            ));
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Ok((data[0], None));
+        }
+        let fd = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+        Ok((data[0], Some(fd)))
+    }
+}
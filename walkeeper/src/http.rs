@@ -0,0 +1,353 @@
+//
+//   HTTP management API for control planes.
+//
+//   GET /v1/tenants
+//       status of all tenants, as a list of objects described below
+//
+//   GET /v1/tenant/{id}
+//       status of the tenant: epoch, flush, commit, restart and remote consistent LSNs,
+//       whether a proposer is connected, connected WAL senders with their positions, and
+//       counts of WAL removed by GC, overwritten, archived and restored since start
+//
+//   GET /v1/tenant/{id}/lsn_by_time?ts=<RFC 3339 time>
+//       closest LSN received at or before the time, according to the ingest-time index:
+//       {"lsn": "0/16B3748"}
+//
+//   POST /v1/tenant/{id}/gc?lsn=<LSN>
+//       propose removal of WAL below the LSN: the safekeeper holds it back by its own WAL
+//       horizon and removes segments below the effective cutoff in the background,
+//       progress is reported like by GET below
+//
+//   GET /v1/tenant/{id}/gc
+//       progress of the last proposed WAL removal: {"id": ..., "proposed_lsn": ...,
+//       "cutoff_lsn": ..., "limited_by": ..., "state": ..., "segments_removed": ...}
+//
+//   POST /v1/tenant/{id}/import_wal?path=<directory or archive>[&manifest=<file>]
+//       install WAL segments from the directory or tar archive on the safekeeper host
+//       into the tenant, which must not be loaded and must have no WAL yet (see
+//       wal_import.rs): {"timeline": ..., "segments": ..., "start_lsn": ...,
+//       "flush_lsn": ..., "commit_lsn": ...}. Invalid source or manifest is a bad
+//       request (400), a loaded tenant or one having WAL is a conflict (409).
+//
+//   GET /v1/node
+//       identity of the safekeeper: {"uuid": ..., "layout_version": ..., "created": ...}
+//
+//   GET /v1/diagnostics
+//       runtime scheduling lag, lock wait statistics and longest running connections
+//
+//   GET /metrics
+//       per-tenant metrics in Prometheus text format, like "metrics" admin command
+//
+//   Connections from peers not permitted by the access list are closed before reading
+//   the request.
+//
+//   Errors are reported with an appropriate status code and {"error": "<message>"} body.
+//
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use hyper::server::Builder;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::*;
+use serde_json::json;
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::task;
+
+use crate::access_list::AccessList;
+use crate::admin::parse_timestamp;
+use crate::diagnostics;
+use crate::metrics;
+use crate::node_file;
+use crate::parse_tenant_id;
+use crate::pq_protocol::Result;
+use crate::wal_service::parse_lsn;
+use crate::wal_service::{self, TenantRegistry};
+use crate::xlog_utils::format_lsn;
+use crate::WalAcceptorConf;
+
+// Bind listening socket of HTTP API, reporting failure before the API is served
+pub fn bind(addr: SocketAddr) -> Result<Builder<AddrIncoming>> {
+    Server::try_bind(&addr).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+//
+// Serve HTTP API until stop is sent or dropped, then finish requests in flight
+//
+pub async fn http_loop(
+    server: Builder<AddrIncoming>,
+    conf: Arc<WalAcceptorConf>,
+    tenants: Arc<TenantRegistry>,
+    stop: oneshot::Receiver<()>,
+) -> Result<()> {
+    let access_list: AccessList = conf.http_access_list.clone();
+    if !access_list.is_empty() {
+        info!("HTTP API access list: {}", access_list);
+    }
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let peer_addr = conn.remote_addr();
+        let allowed = access_list.is_allowed(peer_addr.ip());
+        let conf = conf.clone();
+        let tenants = tenants.clone();
+        async move {
+            if !allowed {
+                /* hyper drops the connection if service cannot be created */
+                info!(
+                    "Reject HTTP connection from {}: address is not allowed",
+                    peer_addr
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} is not allowed", peer_addr),
+                ));
+            }
+            Ok(service_fn(move |req| {
+                handle_request(req, conf.clone(), tenants.clone())
+            }))
+        }
+    });
+    let server = server.serve(make_service);
+    info!("HTTP API is listening at {}", server.local_addr());
+    server
+        .with_graceful_shutdown(async {
+            let _ = stop.await;
+        })
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    conf: Arc<WalAcceptorConf>,
+    tenants: Arc<TenantRegistry>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    /* Metrics are scraped by Prometheus in its text format, the rest is JSON */
+    if req.method() == Method::GET && req.uri().path() == "/metrics" {
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics::render_metrics(
+                &tenants,
+                conf.metrics_top_tenants,
+            )))
+            .unwrap());
+    }
+    /* WAL import copies the whole archive, so it runs on the blocking thread pool */
+    let result = match import_wal_request(&req) {
+        Some((tenant, query)) => {
+            let tenants = tenants.clone();
+            let conf = conf.clone();
+            task::spawn_blocking(move || import_wal(&conf, &tenants, &tenant, &query))
+                .await
+                .unwrap_or_else(|e| Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())))
+        }
+        None => route(&req, &conf, &tenants),
+    };
+    let (status, body) = match result {
+        Ok(body) => (StatusCode::OK, body),
+        Err((status, msg)) => {
+            debug!("HTTP {} {}: {}", req.method(), req.uri(), msg);
+            (status, json!({ "error": msg }))
+        }
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap())
+}
+
+type RouteResult = std::result::Result<serde_json::Value, (StatusCode, String)>;
+
+fn route(req: &Request<Body>, conf: &WalAcceptorConf, tenants: &TenantRegistry) -> RouteResult {
+    let path: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+    match (req.method(), path.as_slice()) {
+        (&Method::POST, ["v1", "tenant", tenant, "gc"]) => {
+            propose_gc(conf, tenants, tenant, req.uri().query().unwrap_or(""))
+        }
+        (&Method::GET, ["v1", "tenant", tenant, "gc"]) => gc_status(tenants, tenant),
+        (&Method::GET, ["v1", "tenant", tenant, "lsn_by_time"]) => {
+            lsn_by_time(tenants, tenant, req.uri().query().unwrap_or(""))
+        }
+        (&Method::GET, ["v1", "tenant", tenant]) => tenant_status(tenants, tenant),
+        (&Method::GET, ["v1", "tenants"]) => {
+            let statuses: Vec<_> = tenants
+                .get_systems()
+                .iter()
+                .map(|system| system.snapshot().status())
+                .collect();
+            Ok(json!(statuses))
+        }
+        (&Method::GET, ["v1", "node"]) => match node_file::identity() {
+            Some(node) => Ok(json!(node)),
+            None => Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Data directory is not loaded yet".to_string(),
+            )),
+        },
+        (&Method::GET, ["v1", "diagnostics"]) => Ok(json!(diagnostics::report())),
+        (_, ["v1", "tenant", _, "lsn_by_time"])
+        | (_, ["v1", "tenant", _, "gc"])
+        | (_, ["v1", "tenant", _, "import_wal"])
+        | (_, ["v1", "tenant", _])
+        | (_, ["v1", "tenants"])
+        | (_, ["v1", "node"])
+        | (_, ["v1", "diagnostics"])
+        | (_, ["metrics"]) => Err((
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("Method {} is not allowed", req.method()),
+        )),
+        _ => Err((
+            StatusCode::NOT_FOUND,
+            format!("Unknown path {}", req.uri().path()),
+        )),
+    }
+}
+
+fn tenant_status(tenants: &TenantRegistry, tenant: &str) -> RouteResult {
+    let id = parse_tenant_id(tenant).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let system = tenants
+        .get_system(id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown tenant {}", id)))?;
+    Ok(json!(system.snapshot().status()))
+}
+
+fn lsn_by_time(tenants: &TenantRegistry, tenant: &str, query: &str) -> RouteResult {
+    let bad_request = |e: io::Error| (StatusCode::BAD_REQUEST, e.to_string());
+    let id = parse_tenant_id(tenant).map_err(bad_request)?;
+    let ts = match query_param(query, "ts") {
+        Some(ts) => parse_timestamp(&ts).map_err(bad_request)?,
+        None => {
+            return Err((StatusCode::BAD_REQUEST, "Missing ts parameter".to_string()));
+        }
+    };
+    let system = tenants
+        .get_system(id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown tenant {}", id)))?;
+    let lsn = system
+        .lsn_by_time(ts)
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    Ok(json!({ "lsn": format_lsn(lsn) }))
+}
+
+fn propose_gc(
+    conf: &WalAcceptorConf,
+    tenants: &TenantRegistry,
+    tenant: &str,
+    query: &str,
+) -> RouteResult {
+    let bad_request = |e: io::Error| (StatusCode::BAD_REQUEST, e.to_string());
+    let id = parse_tenant_id(tenant).map_err(bad_request)?;
+    let lsn = match query_param(query, "lsn") {
+        Some(lsn) => parse_lsn(&lsn).map_err(bad_request)?,
+        None => {
+            return Err((StatusCode::BAD_REQUEST, "Missing lsn parameter".to_string()));
+        }
+    };
+    let system = tenants
+        .get_system(id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown tenant {}", id)))?;
+    let proposal = system
+        .propose_gc(conf, lsn, "http")
+        .map_err(|e| (StatusCode::CONFLICT, e.to_string()))?;
+    Ok(json!(proposal.status()))
+}
+
+fn gc_status(tenants: &TenantRegistry, tenant: &str) -> RouteResult {
+    let id = parse_tenant_id(tenant).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let system = tenants
+        .get_system(id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown tenant {}", id)))?;
+    match system.gc_proposal() {
+        Some(proposal) => Ok(json!(proposal.status())),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            format!("No WAL removal of tenant {} was proposed", id),
+        )),
+    }
+}
+
+// Tenant and query of POST /v1/tenant/{id}/import_wal
+fn import_wal_request(req: &Request<Body>) -> Option<(String, String)> {
+    let path: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+    match (req.method(), path.as_slice()) {
+        (&Method::POST, ["v1", "tenant", tenant, "import_wal"]) => Some((
+            tenant.to_string(),
+            req.uri().query().unwrap_or("").to_string(),
+        )),
+        _ => None,
+    }
+}
+
+fn import_wal(
+    conf: &WalAcceptorConf,
+    tenants: &TenantRegistry,
+    tenant: &str,
+    query: &str,
+) -> RouteResult {
+    let id = parse_tenant_id(tenant).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let source = match query_param(query, "path") {
+        Some(path) => path,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Missing path parameter".to_string(),
+            ));
+        }
+    };
+    let manifest = query_param(query, "manifest");
+    if tenants.get_system(id).is_some() {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Tenant {} is loaded, it must be released first", id),
+        ));
+    }
+    let plan = wal_service::import_wal(
+        conf,
+        id,
+        Path::new(&source),
+        manifest.as_ref().map(Path::new),
+    )
+    .map_err(|e| {
+        let status = match e.kind() {
+            /* Invalid source or manifest, see wal_import.rs */
+            io::ErrorKind::InvalidData | io::ErrorKind::NotFound => StatusCode::BAD_REQUEST,
+            io::ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, e.to_string())
+    })?;
+    Ok(plan.status())
+}
+
+// Value of the query parameter, with %XX escapes (e.g. %2B for '+' of time zone) decoded
+fn query_param(query: &str, name: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)?
+        .1;
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(c)) => {
+                decoded.push(c);
+                i += 3;
+            }
+            (c, _) => {
+                decoded.push(c);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
@@ -0,0 +1,151 @@
+//
+//   Ingest-time index of a tenant: wall-clock time at which WAL up to some LSN was received.
+//
+//   At most one entry per second of ingest is appended to a file in the tenant directory,
+//   next to WAL segments, so the mapping survives restarts. The index is used to express
+//   lag of WAL consumers in seconds, to keep WAL received within --wal-retention-secs and
+//   to answer "which LSN corresponds to a given time" for PITR workflows. To keep it compact, when the index grows to MAX_ENTRIES its
+//   older half is thinned out, so precision of old history degrades gradually.
+//
+//   The file is advisory and is not fsynced: a torn tail is ignored on load.
+//
+use byteorder::{ByteOrder, LittleEndian};
+use log::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::pq_protocol::Result;
+use crate::xlog_utils::{TimestampTz, XLogRecPtr};
+
+pub const INGEST_INDEX_FILE_NAME: &str = "ingest.index";
+const ENTRY_SIZE: usize = 16; /* LSN and timestamp, little endian */
+const GRANULARITY: TimestampTz = 1_000_000; /* usec between entries */
+const MAX_ENTRIES: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub struct IngestIndex {
+    path: PathBuf,
+    file: Option<File>, /* opened for append on first write */
+    damaged: bool,      /* file has garbage at the end, rewrite it instead of appending */
+    entries: Vec<(XLogRecPtr, TimestampTz)>, /* WAL before LSN was received by the time */
+}
+
+impl IngestIndex {
+    // Load index of the tenant. Missing or damaged file results in empty (or truncated) index.
+    pub fn load(tenant_dir: &Path) -> IngestIndex {
+        let path = tenant_dir.join(INGEST_INDEX_FILE_NAME);
+        let content = fs::read(&path).unwrap_or_default();
+        let mut entries: Vec<(XLogRecPtr, TimestampTz)> = Vec::new();
+        let mut damaged = content.len() % ENTRY_SIZE != 0;
+        for chunk in content.chunks_exact(ENTRY_SIZE) {
+            let entry = (
+                LittleEndian::read_u64(&chunk[0..8]),
+                LittleEndian::read_u64(&chunk[8..16]),
+            );
+            if let Some(last) = entries.last() {
+                if entry.0 < last.0 || entry.1 < last.1 {
+                    damaged = true;
+                    break;
+                }
+            }
+            entries.push(entry);
+        }
+        if damaged {
+            warn!("Ingest index {:?} is damaged, ignore its tail", path);
+        }
+        IngestIndex {
+            path: path,
+            file: None,
+            damaged: damaged,
+            entries: entries,
+        }
+    }
+
+    // Whether WAL received up to end_lsn by now would change the index
+    pub fn is_due(&self, end_lsn: XLogRecPtr, now: TimestampTz) -> bool {
+        match self.entries.last() {
+            Some(last) => self.damaged || end_lsn < last.0 || now >= last.1 + GRANULARITY,
+            None => true,
+        }
+    }
+
+    //
+    // Remember that WAL up to end_lsn has been received by now. After WAL truncation
+    // (end_lsn below the last entry) entries beyond it are forgotten.
+    //
+    pub fn record(&mut self, end_lsn: XLogRecPtr, now: TimestampTz) -> Result<()> {
+        let mut rewrite = self.damaged;
+        if let Some(last) = self.entries.last() {
+            if end_lsn < last.0 {
+                self.entries.retain(|entry| entry.0 <= end_lsn);
+                rewrite = true;
+            } else if now < last.1 + GRANULARITY {
+                return Ok(());
+            }
+        }
+        self.entries.push((end_lsn, now));
+        if self.entries.len() >= MAX_ENTRIES {
+            /* Drop every other entry of the older half */
+            let half = self.entries.len() / 2;
+            let mut i = 0;
+            self.entries.retain(|_| {
+                i += 1;
+                i > half || i % 2 == 0
+            });
+            rewrite = true;
+        }
+        if rewrite {
+            self.rewrite()
+        } else {
+            self.append_last()
+        }
+    }
+
+    fn append_last(&mut self) -> Result<()> {
+        if self.file.is_none() {
+            self.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        let buf = encode(&self.entries[self.entries.len() - 1..]);
+        self.file.as_mut().unwrap().write_all(&buf)
+    }
+
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, encode(&self.entries))?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = None;
+        self.damaged = false;
+        Ok(())
+    }
+
+    // Closest LSN received at or before the given time, if the index goes back that far
+    pub fn lsn_by_time(&self, ts: TimestampTz) -> Option<XLogRecPtr> {
+        let n = self.entries.partition_point(|entry| entry.1 <= ts);
+        if n == 0 {
+            None
+        } else {
+            Some(self.entries[n - 1].0)
+        }
+    }
+
+    // Time when WAL following the given LSN was received, if it was received at all
+    pub fn time_after_lsn(&self, lsn: XLogRecPtr) -> Option<TimestampTz> {
+        let n = self.entries.partition_point(|entry| entry.0 <= lsn);
+        self.entries.get(n).map(|entry| entry.1)
+    }
+}
+
+fn encode(entries: &[(XLogRecPtr, TimestampTz)]) -> Vec<u8> {
+    let mut buf = vec![0u8; entries.len() * ENTRY_SIZE];
+    for (chunk, (lsn, ts)) in buf.chunks_exact_mut(ENTRY_SIZE).zip(entries) {
+        LittleEndian::write_u64(&mut chunk[0..8], *lsn);
+        LittleEndian::write_u64(&mut chunk[8..16], *ts);
+    }
+    buf
+}
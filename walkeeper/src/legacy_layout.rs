@@ -0,0 +1,103 @@
+//
+//   Migration of the legacy single-tenant data layout.
+//
+//   Early deployments stored WAL segments and the control file directly in the data
+//   directory. Now every tenant has its own subdirectory, so at startup such files are
+//   moved into the directory of the tenant given in configuration. The control file is
+//   moved last: if migration is interrupted, the rest of the files are moved on the
+//   next start, and the tenant is not served with incomplete WAL meanwhile.
+//
+use fs2::FileExt;
+use log::*;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use crate::pq_protocol::SystemId;
+use crate::tenant_dir;
+use crate::wal_service::CONTROL_FILE_NAME;
+use crate::xlog_utils::*;
+
+// Names of files of the legacy layout present in the data directory
+pub fn find_legacy_files(data_dir: &Path) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    if !data_dir.exists() {
+        return Ok(files);
+    }
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        if let Some(fname) = entry.file_name().to_str() {
+            if IsXLogFileName(fname) || IsPartialXLogFileName(fname) {
+                files.push(fname.to_string());
+            }
+        }
+    }
+    files.sort();
+    if data_dir.join(ARCHIVE_STATUS_DIR).is_dir() {
+        files.push(ARCHIVE_STATUS_DIR.to_string());
+    }
+    if data_dir.join(CONTROL_FILE_NAME).exists() {
+        files.push(CONTROL_FILE_NAME.to_string());
+    }
+    Ok(files)
+}
+
+//
+// Move files of the legacy layout into the directory of the tenant.
+// Returns number of moved files.
+//
+pub fn migrate(data_dir: &Path, id: SystemId) -> io::Result<usize> {
+    let files = find_legacy_files(data_dir)?;
+    if files.is_empty() {
+        return Ok(0);
+    }
+    let system_dir = tenant_dir(data_dir, id);
+    let legacy_control_file = data_dir.join(CONTROL_FILE_NAME);
+
+    /* Make sure that legacy wal_acceptor is not running in this directory */
+    let _lock = if legacy_control_file.exists() {
+        let file = OpenOptions::new().read(true).write(true).open(&legacy_control_file)?;
+        file.try_lock_exclusive().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("{:?} is locked by running wal_acceptor", legacy_control_file),
+            )
+        })?;
+        if system_dir.join(CONTROL_FILE_NAME).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "both {:?} and tenant directory {:?} have control file, refuse to merge them",
+                    data_dir, system_dir
+                ),
+            ));
+        }
+        Some(file)
+    } else {
+        None
+    };
+
+    info!(
+        "Migrate {} files of the legacy layout in {:?} to tenant directory {:?}",
+        files.len(),
+        data_dir,
+        system_dir
+    );
+    fs::create_dir_all(&system_dir)?;
+    for fname in &files {
+        let target = system_dir.join(fname);
+        if target.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists in tenant directory", target),
+            ));
+        }
+        /* Files are renamed in the order of find_legacy_files: control file goes last */
+        fs::rename(data_dir.join(fname), &target)?;
+        debug!("Moved {} to {:?}", fname, system_dir);
+    }
+    File::open(&system_dir)?.sync_all()?;
+    File::open(data_dir)?.sync_all()?;
+    info!("Legacy layout is migrated to tenant {}", id);
+    Ok(files.len())
+}
@@ -1,17 +1,489 @@
 //
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
 use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 
+use access_list::AccessList;
+use at_rest::{AtRestKey, AtRestPolicy};
+use auth::AuthMethod;
+use object_storage::{BucketConf, ObjectStorageConf};
+use tls::TlsConf;
+
+//Report and return IO error */
+macro_rules! io_error {
+    ($($arg:tt)*) => (error!($($arg)*); return Err(io::Error::new(io::ErrorKind::Other,format!($($arg)*))))
+}
+
+pub mod access_list;
+pub mod admin;
+pub mod archive;
+pub mod at_rest;
+pub mod auth;
+pub mod callback;
+pub mod clock;
+pub mod diagnostics;
+pub mod embed;
+pub mod events;
+pub mod fault_fs;
+pub mod gc_coordination;
+pub mod handoff;
+pub mod http;
+pub mod ingest_index;
+pub mod legacy_layout;
+pub mod listeners;
+pub mod log_filter;
+pub mod metrics;
+pub mod node_file;
+pub mod object_storage;
+pub mod outbound;
+pub mod partial_segment;
+pub mod peer_check;
 mod pq_protocol;
+pub mod read_cache;
+pub mod recovery_log;
+pub mod safekeeper_protocol;
+pub mod shutdown;
+pub mod spare_segments;
+pub mod tenant_takeover;
+pub mod timeline_history;
+pub mod tls;
+pub mod tombstone;
+pub mod trace;
+pub mod wal_checksum;
+pub mod wal_file_cache;
+pub mod wal_import;
 pub mod wal_service;
+pub mod wal_storage;
 pub mod xlog_utils;
 
+pub const TENANT_CONF_FILE_NAME: &str = "tenant.toml";
+
+//
+// Parse client-supplied tenant identifier.
+// Only canonical form is accepted: decimal number without sign, spaces and leading zeros.
+// Everything which is used to build paths in the data directory must pass through it.
+//
+pub fn parse_tenant_id(s: &str) -> io::Result<pq_protocol::SystemId> {
+    let canonical = !s.is_empty()
+        && s.bytes().all(|c| c.is_ascii_digit())
+        && (s == "0" || !s.starts_with('0'));
+    match s.parse::<pq_protocol::SystemId>() {
+        Ok(id) if canonical => Ok(id),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid tenant id {:?}", s),
+        )),
+    }
+}
+
+//
+// Directory of the tenant in the safekeeper data directory
+//
+pub fn tenant_dir(data_dir: &Path, id: pq_protocol::SystemId) -> PathBuf {
+    data_dir.join(id.to_string())
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct WalAcceptorConf {
     pub data_dir: PathBuf,
     pub daemonize: bool,
     pub no_sync: bool,
+    pub sync_method: SyncMethod, /* how WAL writes are synced, unless no_sync */
+    pub wal_stats: bool,
+    pub verify_wal_crc: bool, /* check CRC of received WAL records before storing them */
+    pub control_file_version: Option<u32>, /* write control files in this format, the latest by default */
+    pub pg_wal_layout: bool, /* store WAL like Postgres pg_wal directory (no .partial, archive_status) */
+    pub slow_append_threshold: Option<Duration>, /* log appends with write+fsync longer than that */
+    pub slow_send_threshold: Option<Duration>,   /* log WAL chunks written to socket longer than that */
+    pub heartbeat_interval: Option<Duration>, /* send heartbeats to idle proposer and detect its death */
+    pub keepalive_interval: Duration, /* send keepalives to replicas waiting for WAL */
+    pub proposer_read_timeout: Option<Duration>, /* drop proposer which sends nothing for that long */
+    pub tcp_keepalive: Option<Duration>, /* idle time before TCP keepalive probes of accepted connections */
+    pub max_clock_skew: Option<Duration>, /* warn if proposer clock differs from the local one more than that */
+    pub catchup_rate_limit: Option<u64>, /* bytes per second for WAL senders catching up from far behind */
+    pub max_inflight_msgs: usize, /* append messages which may be pre-read from proposer socket */
+    pub receive_high_watermark: Option<usize>, /* pre-read bytes pausing proposers with flow control */
+    pub max_ack_delay: Option<Duration>, /* coalesce acks of pipelined appends, deferring them up to that */
+    pub group_commit_bytes: Option<usize>, /* sync pipelined appends together, once that much WAL is unsynced */
+    pub group_commit_delay: Option<Duration>, /* ... or once the first unsynced append has waited that long */
+    pub prealloc_segments: usize, /* spare zero-filled segments kept by each tenant, 0 disables preallocation */
+    pub wal_retention: Option<u64>, /* bytes of WAL kept behind flush_lsn even if below restart_lsn */
+    pub wal_retention_time: Option<Duration>, /* WAL received within that time is kept too */
+    pub gc_coordinated: bool, /* WAL GC doesn't go beyond the cutoff confirmed to pageserver */
+    pub read_cache_size: usize, /* bytes of WAL cached for senders of all tenants, 0 disables the cache */
+    pub workers: Option<usize>, /* worker threads of the main runtime, a worker per CPU by default */
     pub listen_addr: SocketAddr,
     pub pageserver_addr: Option<SocketAddr>,
+    pub http_addr: Option<SocketAddr>, /* HTTP management API */
+    pub callback: CallbackConf, /* how to notify pageserver about new WAL */
+    pub trace_dir: Option<PathBuf>, /* capture proposer sessions to this directory */
+    pub shutdown_report: Option<PathBuf>, /* write final positions of tenants here on graceful shutdown */
+    pub metrics_top_tenants: Option<usize>, /* tenants exported with own label, the rest go to "other" */
+    pub acceptor_set: Option<AcceptorSetConf>, /* position of this node in Paxos, checked against proposer's claim */
+    pub access_list: AccessList,      /* peers which may connect to WAL service */
+    pub http_access_list: AccessList, /* peers which may connect to HTTP API */
+    pub legacy_tenant: Option<pq_protocol::SystemId>, /* tenant owning WAL of the legacy single-tenant layout */
+    pub node_uuid: Option<u128>, /* identity the data directory must belong to, checked against safekeeper.node */
+    pub tls: Option<TlsConf>, /* certificate for connections requesting encryption, plain ones are still accepted */
+    pub at_rest_key: Option<AtRestKey>, /* key of tenants encrypting their segments at rest */
+    pub object_storage: Option<ObjectStorageConf>, /* experimental: keep WAL in the bucket instead of local segments */
+    pub archive: Option<BucketConf>, /* upload completed segments here, local WAL is then kept till archived */
+}
+
+//
+// Acceptor set this safekeeper belongs to. Proposers must agree with it.
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptorSetConf {
+    pub node_index: u32,    /* position of this safekeeper in peers */
+    pub peers: Vec<String>, /* addresses of all safekeepers of the set, including this one */
+    pub quorum: u32,
+}
+
+impl WalAcceptorConf {
+    // Appends are synced in batches rather than one by one (pointless with --no-sync)
+    pub fn group_commit(&self) -> bool {
+        !self.no_sync && (self.group_commit_bytes.is_some() || self.group_commit_delay.is_some())
+    }
+
+    //
+    // Check configuration before starting to serve, so that all problems are reported
+    // at once instead of failing on the first of them halfway through initialization.
+    // Returns descriptions of the problems found. The data directory is created if missing.
+    // Listen address is probed only if the listening socket is not inherited.
+    //
+    pub fn validate(&self, probe_listen_addr: bool) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Err(e) = check_writable_dir(&self.data_dir) {
+            errors.push(format!("data directory {:?} {}", self.data_dir, e));
+        }
+        if let Some(trace_dir) = &self.trace_dir {
+            if let Err(e) = check_writable_dir(trace_dir) {
+                errors.push(format!("trace directory {:?} {}", trace_dir, e));
+            }
+        }
+
+        match legacy_layout::find_legacy_files(&self.data_dir) {
+            Ok(files) if !files.is_empty() && self.legacy_tenant.is_none() => {
+                errors.push(format!(
+                    "data directory {:?} has WAL of the legacy single-tenant layout, specify its tenant with --legacy-tenant to migrate it",
+                    self.data_dir
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => errors.push(format!("cannot list data directory {:?}: {}", self.data_dir, e)),
+        }
+
+        if probe_listen_addr {
+            if let Err(e) = std::net::TcpListener::bind(self.listen_addr) {
+                errors.push(format!("cannot listen at {}: {}", self.listen_addr, e));
+            }
+        }
+        if let Some(http_addr) = self.http_addr {
+            if http_addr == self.listen_addr {
+                errors.push(format!(
+                    "HTTP API and WAL service are configured at the same address {}",
+                    http_addr
+                ));
+            } else if let Err(e) = std::net::TcpListener::bind(http_addr) {
+                errors.push(format!("cannot listen at {} for HTTP API: {}", http_addr, e));
+            }
+        }
+        if self.pageserver_addr == Some(self.listen_addr) {
+            errors.push(format!(
+                "pageserver address {} is the address of this wal_acceptor",
+                self.listen_addr
+            ));
+        }
+
+        if let Some(storage) = &self.object_storage {
+            if self.pg_wal_layout {
+                errors.push("pg-wal-layout can't be used with object storage".to_string());
+            }
+            if storage.chunk_size == 0 {
+                errors.push("object-storage-chunk-size must be positive".to_string());
+            }
+            if self.archive.is_some() {
+                errors.push("archive can't be used with object storage".to_string());
+            }
+            if self.gc_coordinated {
+                errors.push("gc-coordinated can't be used with object storage".to_string());
+            }
+            if self.group_commit() {
+                errors.push("group commit can't be used with object storage".to_string());
+            }
+            if self.prealloc_segments > 0 {
+                errors.push("prealloc-segments can't be used with object storage".to_string());
+            }
+        }
+        if self.workers == Some(0) {
+            errors.push("workers must be at least 1".to_string());
+        }
+        if let Some(version) = self.control_file_version {
+            if version < wal_service::MIN_CONTROL_FILE_VERSION
+                || version > wal_service::CONTROL_FILE_VERSION
+            {
+                errors.push(format!(
+                    "control-file-version must be between {} and {}",
+                    wal_service::MIN_CONTROL_FILE_VERSION,
+                    wal_service::CONTROL_FILE_VERSION
+                ));
+            }
+        }
+        if self.max_inflight_msgs == 0 {
+            errors.push("max-inflight-msgs must be at least 1".to_string());
+        }
+        if self.receive_high_watermark == Some(0) {
+            errors.push("receive-high-watermark-kb must be positive".to_string());
+        }
+        if self.group_commit_bytes == Some(0) {
+            errors.push("group-commit-kb must be positive".to_string());
+        }
+        if self.catchup_rate_limit == Some(0) {
+            errors.push("catchup-rate-limit must be positive".to_string());
+        }
+        if self.heartbeat_interval == Some(Duration::from_millis(0)) {
+            errors.push("heartbeat-ms must be positive".to_string());
+        }
+        if self.keepalive_interval == Duration::from_millis(0) {
+            errors.push("keepalive-ms must be positive".to_string());
+        }
+        if self.proposer_read_timeout == Some(Duration::from_millis(0)) {
+            errors.push("proposer-timeout-ms must be positive".to_string());
+        }
+        if self.tcp_keepalive.map_or(false, |idle| idle.as_secs() == 0) {
+            errors.push("tcp-keepalive-ms must be at least 1000".to_string());
+        }
+        if let Some(set) = &self.acceptor_set {
+            let n = set.peers.len() as u32;
+            if set.node_index >= n {
+                errors.push(format!(
+                    "node-index {} is out of the set of {} peers",
+                    set.node_index, n
+                ));
+            }
+            if set.quorum <= n / 2 || set.quorum > n {
+                errors.push(format!(
+                    "quorum {} is not a majority of {} peers",
+                    set.quorum, n
+                ));
+            }
+        }
+        errors
+    }
+}
+
+/* Create directory if needed and make sure that files can be created in it */
+fn check_writable_dir(dir: &Path) -> io::Result<()> {
+    if dir.exists() && !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "is not a directory",
+        ));
+    }
+    fs::create_dir_all(dir).map_err(|e| {
+        io::Error::new(e.kind(), format!("does not exist and cannot be created: {}", e))
+    })?;
+    let probe = dir.join(".write_probe");
+    fs::write(&probe, b"")
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| io::Error::new(e.kind(), format!("is not writable: {}", e)))
+}
+
+//
+// Way to ask pageserver to start replication from this safekeeper
+//
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallbackConf {
+    CallMeMaybe,     /* "callmemaybe" simple query to pageserver_addr, if it is set */
+    Webhook(String), /* HTTP POST to the URL */
+    None,
+}
+
+impl std::str::FromStr for CallbackConf {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "callmemaybe" => Ok(CallbackConf::CallMeMaybe),
+            "none" => Ok(CallbackConf::None),
+            _ if s.starts_with("http://") => Ok(CallbackConf::Webhook(s.to_string())),
+            _ => Err(format!(
+                "invalid callback '{}': expected callmemaybe, none or http:// URL",
+                s
+            )),
+        }
+    }
+}
+
+//
+// How WAL writes are synced, like wal_sync_method of Postgres. Segments are zero-filled
+// before WAL is written to them, so writes change neither their size nor allocation and
+// fdatasync is enough. sync_file_range only writes out dirty pages of the range without
+// flushing the disk cache, so it is durable only on disks without volatile write cache.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMethod {
+    Fsync,
+    Fdatasync,
+    SyncFileRange,
+    None, /* same as --no-sync */
+}
+
+impl std::str::FromStr for SyncMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fsync" => Ok(SyncMethod::Fsync),
+            "fdatasync" => Ok(SyncMethod::Fdatasync),
+            "sync_file_range" => Ok(SyncMethod::SyncFileRange),
+            "none" => Ok(SyncMethod::None),
+            _ => Err(format!(
+                "invalid sync method '{}': expected fsync, fdatasync, sync_file_range or none",
+                s
+            )),
+        }
+    }
+}
+
+impl SyncMethod {
+    // Sync WAL written to the range of the file
+    pub fn sync(&self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+        match self {
+            SyncMethod::Fsync => file.sync_all(),
+            SyncMethod::Fdatasync => file.sync_data(),
+            SyncMethod::SyncFileRange => sync_file_range(file, offset, len),
+            SyncMethod::None => Ok(()),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sync_file_range(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+        | libc::SYNC_FILE_RANGE_WRITE
+        | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    let res = unsafe {
+        libc::sync_file_range(
+            file.as_raw_fd(),
+            offset as libc::off64_t,
+            len as libc::off64_t,
+            flags,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/* Not available elsewhere, fdatasync is the closest */
+#[cfg(not(target_os = "linux"))]
+fn sync_file_range(file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    file.sync_data()
+}
+
+//
+// Scheduling class of a tenant.
+// Under contention, appends and WAL senders of batch tenants give way to interactive ones.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PriorityClass {
+    Interactive,
+    Batch,
+}
+
+impl Default for PriorityClass {
+    fn default() -> Self {
+        PriorityClass::Interactive
+    }
+}
+
+//
+// What to do when proposer runs another major version of Postgres than the one
+// which wrote WAL of the tenant before, e.g. after pg_upgrade
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PgVersionPolicy {
+    Accept,      /* accept WAL of the new version, setting persistent warning flag of the tenant */
+    Reject,      /* reject proposer until the new version is acknowledged by administrator */
+    NewTimeline, /* write WAL of the new version to the next timeline */
+}
+
+impl Default for PgVersionPolicy {
+    fn default() -> Self {
+        PgVersionPolicy::Accept
+    }
+}
+
+//
+// Per-tenant configuration, stored in tenant.toml in the tenant directory.
+// Missing file or fields mean defaults.
+//
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TenantConf {
+    pub priority: PriorityClass,
+    pub dedicated_runtime: bool, /* serve tenant connections by its own runtime thread */
+    pub mirror_dir: Option<PathBuf>, /* write WAL of the tenant to this directory as well, e.g. on another disk */
+    pub mirror_no_sync: bool, /* don't fsync the mirror copy */
+    pub auth_method: AuthMethod, /* authentication of replication clients */
+    pub users: HashMap<String, String>, /* secrets of replication users, see auth.rs */
+    pub pg_version_mismatch: PgVersionPolicy, /* response to major upgrade of Postgres */
+    pub at_rest: AtRestPolicy, /* encoding of completed segments, set by "at-rest" admin command */
+}
+
+impl TenantConf {
+    pub fn load(tenant_dir: &Path) -> io::Result<TenantConf> {
+        let path = tenant_dir.join(TENANT_CONF_FILE_NAME);
+        if !path.exists() {
+            return Ok(TenantConf::default());
+        }
+        let content = fs::read_to_string(&path)?;
+        toml::from_str(&content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse {:?}: {}", path, e),
+            )
+        })
+    }
+
+    //
+    // Record at-rest policy in tenant.toml, keeping other settings of the file, which is
+    // replaced atomically. Comments of the file are not preserved.
+    //
+    pub fn store_at_rest(tenant_dir: &Path, policy: AtRestPolicy) -> io::Result<()> {
+        let path = tenant_dir.join(TENANT_CONF_FILE_NAME);
+        let invalid_data = |e: &dyn std::fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to update {:?}: {}", path, e),
+            )
+        };
+        let mut content = match fs::read_to_string(&path) {
+            Ok(content) => content.parse::<toml::Value>().map_err(|e| invalid_data(&e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => toml::Value::Table(Default::default()),
+            Err(e) => return Err(e),
+        };
+        if let toml::Value::Table(table) = &mut content {
+            let policy = toml::Value::try_from(policy).map_err(|e| invalid_data(&e))?;
+            table.insert("at_rest".to_string(), policy);
+        }
+        let tmp_path = tenant_dir.join(format!("{}.tmp", TENANT_CONF_FILE_NAME));
+        fs::write(&tmp_path, toml::to_string(&content).map_err(|e| invalid_data(&e))?)?;
+        fs::File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        fs::File::open(tenant_dir)?.sync_all()
+    }
 }
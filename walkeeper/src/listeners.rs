@@ -0,0 +1,209 @@
+//
+//   Listening sockets of the WAL service and of the HTTP API, reconfigurable at runtime.
+//
+//   "listen" admin command replaces the set of addresses the WAL service listens at, and
+//   "http-listen" moves or stops the HTTP API, so that network changes (a new replication
+//   VLAN, enabling the HTTP port) don't need a restart, which would force elections of
+//   proposers of all tenants. Sockets of new addresses are bound before anything is
+//   closed, so a failing bind leaves the listeners as they were. Removed listeners only
+//   stop accepting: connections accepted by them are served by tasks of their own and live
+//   on until they close naturally, and HTTP requests in flight are finished.
+//
+//   The first listener of the WAL service is the one handed off on takeover (see
+//   handoff.rs). Listeners belong to the tenant registry of the instance, so instances
+//   embedded in one process have listeners of their own.
+//
+use log::*;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task;
+
+use crate::handoff;
+use crate::http;
+use crate::pq_protocol::Result;
+use crate::wal_service::{self, TenantRegistry};
+use crate::WalAcceptorConf;
+
+#[derive(Debug)]
+struct Listener {
+    addr: SocketAddr,
+    fd: Option<RawFd>,         /* listening socket of the WAL service */
+    stop: oneshot::Sender<()>, /* stops accepting when sent or dropped */
+}
+
+#[derive(Debug, Default)]
+struct Listeners {
+    context: Option<(Arc<WalAcceptorConf>, Weak<TenantRegistry>)>, /* set by start */
+    wal: Vec<Listener>,
+    http: Option<Listener>,
+}
+
+#[derive(Debug, Default)]
+pub struct ListenerSet {
+    listeners: Mutex<Listeners>,
+}
+
+impl ListenerSet {
+    //
+    // Start accepting connections of the WAL service at the listener bound on startup,
+    // and serving HTTP API at --http-addr
+    //
+    pub fn start(
+        &self,
+        conf: Arc<WalAcceptorConf>,
+        tenants: &Arc<TenantRegistry>,
+        listener: TcpListener,
+    ) -> Result<()> {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.context = Some((conf.clone(), Arc::downgrade(tenants)));
+        let listener = spawn_wal(&conf, tenants, listener)?;
+        handoff::set_listener_fd(listener.fd.unwrap());
+        listeners.wal.push(listener);
+        if let Some(http_addr) = conf.http_addr {
+            match spawn_http(&conf, tenants, http_addr) {
+                Ok(listener) => listeners.http = Some(listener),
+                Err(e) => error!("HTTP API failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Listen at these addresses instead of the current ones. Listeners of addresses
+    // which are kept are not touched.
+    //
+    pub fn set_wal_addrs(&self, addrs: &[SocketAddr]) -> Result<()> {
+        if addrs.is_empty() {
+            io_error!("WAL service must listen at one address at least");
+        }
+        let mut listeners = self.listeners.lock().unwrap();
+        let (conf, tenants) = listeners.context()?;
+        let mut bound = Vec::new();
+        for addr in addrs {
+            if !listeners.wal.iter().any(|listener| listener.addr == *addr) {
+                bound.push((*addr, bind(*addr)?));
+            }
+        }
+        let mut old = mem::take(&mut listeners.wal);
+        for addr in addrs {
+            if let Some(pos) = old.iter().position(|listener| listener.addr == *addr) {
+                listeners.wal.push(old.remove(pos));
+            } else if let Some(pos) = bound.iter().position(|(bound_addr, _)| bound_addr == addr) {
+                let (_, listener) = bound.remove(pos);
+                listeners.wal.push(spawn_wal(&conf, &tenants, listener)?);
+            }
+        }
+        for listener in old {
+            info!("WAL service stops listening at {}", listener.addr);
+            let _ = listener.stop.send(());
+        }
+        handoff::set_listener_fd(listeners.wal[0].fd.unwrap());
+        Ok(())
+    }
+
+    // Move HTTP API to the address, or stop it
+    pub fn set_http_addr(&self, addr: Option<SocketAddr>) -> Result<()> {
+        let mut listeners = self.listeners.lock().unwrap();
+        let (conf, tenants) = listeners.context()?;
+        if listeners.http.as_ref().map(|listener| listener.addr) == addr {
+            return Ok(());
+        }
+        let new = match addr {
+            Some(addr) => Some(spawn_http(&conf, &tenants, addr)?),
+            None => None,
+        };
+        if let Some(old) = mem::replace(&mut listeners.http, new) {
+            info!("HTTP API stops listening at {}", old.addr);
+            let _ = old.stop.send(());
+        }
+        Ok(())
+    }
+
+    // Stop accepting connections of the WAL service, once the socket is handed off
+    pub fn stop_wal(&self) {
+        for listener in self.listeners.lock().unwrap().wal.drain(..) {
+            let _ = listener.stop.send(());
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let listeners = self.listeners.lock().unwrap();
+        let mut s = String::new();
+        for listener in &listeners.wal {
+            s += &format!("wal {}\n", listener.addr);
+        }
+        if let Some(listener) = &listeners.http {
+            s += &format!("http {}\n", listener.addr);
+        }
+        s
+    }
+}
+
+impl Listeners {
+    fn context(&self) -> Result<(Arc<WalAcceptorConf>, Arc<TenantRegistry>)> {
+        match &self.context {
+            Some((conf, tenants)) => match tenants.upgrade() {
+                Some(tenants) => Ok((conf.clone(), tenants)),
+                None => {
+                    io_error!("wal_acceptor is shutting down");
+                }
+            },
+            None => {
+                io_error!("wal_acceptor is not listening yet");
+            }
+        }
+    }
+}
+
+fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+fn spawn_wal(
+    conf: &Arc<WalAcceptorConf>,
+    tenants: &Arc<TenantRegistry>,
+    listener: TcpListener,
+) -> Result<Listener> {
+    let addr = listener.local_addr()?;
+    let fd = listener.as_raw_fd();
+    let (stop_tx, stop_rx) = oneshot::channel();
+    info!("WAL service is listening at {}", addr);
+    task::spawn(wal_service::accept_connections(
+        listener,
+        conf.clone(),
+        tenants.clone(),
+        stop_rx,
+    ));
+    Ok(Listener {
+        addr: addr,
+        fd: Some(fd),
+        stop: stop_tx,
+    })
+}
+
+fn spawn_http(
+    conf: &Arc<WalAcceptorConf>,
+    tenants: &Arc<TenantRegistry>,
+    addr: SocketAddr,
+) -> Result<Listener> {
+    let server = http::bind(addr)?;
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let conf = conf.clone();
+    let tenants = tenants.clone();
+    task::spawn(async move {
+        if let Err(e) = http::http_loop(server, conf, tenants, stop_rx).await {
+            error!("HTTP API at {} failed: {}", addr, e);
+        }
+    });
+    Ok(Listener {
+        addr: addr,
+        fd: None,
+        stop: stop_tx,
+    })
+}
@@ -0,0 +1,127 @@
+//
+//   Log filter which can be changed at runtime through the admin socket.
+//
+//   Filter is specified as comma separated list of directives in env_logger
+//   style: "level" sets the default level and "module=level" overrides it
+//   for the module and its submodules, e.g. "info,walkeeper::wal_service=trace".
+//
+use lazy_static::lazy_static;
+use log::*;
+use std::fmt;
+use std::io;
+use std::sync::RwLock;
+
+use crate::pq_protocol::Result;
+
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+lazy_static! {
+    static ref LOG_FILTER: RwLock<LogFilter> = RwLock::new(LogFilter {
+        default: LevelFilter::Trace,
+        modules: Vec::new(),
+    });
+}
+
+impl LogFilter {
+    pub fn parse(spec: &str) -> Result<LogFilter> {
+        let mut filter = LogFilter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        };
+        for directive in spec.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+            let mut parts = directive.splitn(2, '=');
+            let first = parts.next().unwrap();
+            match parts.next() {
+                Some(level) => match level.parse::<LevelFilter>() {
+                    Ok(level) => filter.modules.push((first.to_string(), level)),
+                    Err(_) => {
+                        io_error!("Invalid log level {}", level);
+                    }
+                },
+                None => match first.parse::<LevelFilter>() {
+                    Ok(level) => filter.default = level,
+                    Err(_) => {
+                        io_error!("Invalid log level {}", first);
+                    }
+                },
+            }
+        }
+        /* Longest prefix should win */
+        filter.modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(filter)
+    }
+
+    pub fn enabled(&self, module: &str, level: Level) -> bool {
+        for (prefix, filter) in &self.modules {
+            if module == prefix || module.starts_with(&format!("{}::", prefix)) {
+                return level <= *filter;
+            }
+        }
+        level <= self.default
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.to_string().to_lowercase())?;
+        for (module, level) in self.modules.iter().rev() {
+            write!(f, ",{}={}", module, level.to_string().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn set_log_filter(spec: &str) -> Result<()> {
+    let filter = LogFilter::parse(spec)?;
+    log::set_max_level(filter.max_level());
+    *LOG_FILTER.write().unwrap() = filter;
+    Ok(())
+}
+
+pub fn get_log_filter() -> String {
+    LOG_FILTER.read().unwrap().to_string()
+}
+
+//
+// Slog drain passing only records enabled by the current log filter.
+// Records coming from the log crate carry their target (module path) as the tag.
+//
+pub struct RuntimeFilterDrain<D>(pub D);
+
+impl<D: slog::Drain> slog::Drain for RuntimeFilterDrain<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> std::result::Result<(), D::Err> {
+        let module = if record.tag().is_empty() {
+            record.module()
+        } else {
+            record.tag()
+        };
+        let level = match record.level() {
+            slog::Level::Critical | slog::Level::Error => Level::Error,
+            slog::Level::Warning => Level::Warn,
+            slog::Level::Info => Level::Info,
+            slog::Level::Debug => Level::Debug,
+            slog::Level::Trace => Level::Trace,
+        };
+        if LOG_FILTER.read().unwrap().enabled(module, level) {
+            self.0.log(record, values)?;
+        }
+        Ok(())
+    }
+}
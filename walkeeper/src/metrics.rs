@@ -0,0 +1,504 @@
+//
+//   Per-tenant metrics in Prometheus text format.
+//
+//   With thousands of tenants, a label per tenant makes cardinality explode,
+//   so only the most active tenants (by received WAL) can be exported with
+//   their own label and the rest are summed into tenant="other".
+//   Full per-tenant detail remains available through the status command.
+//
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::clock;
+use crate::read_cache;
+use crate::wal_service::TenantRegistry;
+
+/* Upper bounds of latency histogram buckets, seconds */
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+//
+// Distribution of latencies, exported as Prometheus histogram
+//
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Histogram {
+    buckets: [u64; 12], /* observations by bucket, not cumulative; above the last bound only in count */
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, seconds: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // Average latency in seconds, 0 if there were no observations
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+
+    fn add(&mut self, other: &Histogram) {
+        for (bucket, n) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += n;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+
+    fn render(&self, output: &mut String, name: &str, tenant: &str) {
+        let mut cumulative = 0;
+        for (bound, n) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += n;
+            writeln!(
+                output,
+                "{}_bucket{{tenant=\"{}\",le=\"{}\"}} {}",
+                name, tenant, bound, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            output,
+            "{}_bucket{{tenant=\"{}\",le=\"+Inf\"}} {}",
+            name, tenant, self.count
+        )
+        .unwrap();
+        writeln!(output, "{}_sum{{tenant=\"{}\"}} {}", name, tenant, self.sum).unwrap();
+        writeln!(
+            output,
+            "{}_count{{tenant=\"{}\"}} {}",
+            name, tenant, self.count
+        )
+        .unwrap();
+    }
+}
+
+//
+// Operations removing or offloading WAL of a tenant, counted since start
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WalOp {
+    Gc,         /* segments removed by WAL GC */
+    Truncation, /* WAL below flush position overwritten by a new term, counted once per proposer */
+    Archive,    /* segments uploaded to --archive */
+    Restore,    /* segments downloaded from --archive for WAL senders */
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WalOpCounter {
+    pub count: u64, /* segments, or truncations */
+    pub bytes: u64,
+    pub last: Option<SystemTime>,
+}
+
+impl WalOpCounter {
+    fn add(&mut self, other: &WalOpCounter) {
+        self.count += other.count;
+        self.bytes += other.bytes;
+        self.last = self.last.max(other.last);
+    }
+
+    // Time of the last operation as Unix time, 0 if there was none
+    fn last_secs(&self) -> f64 {
+        self.last
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0.0, |since| since.as_secs_f64())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WalOpStats {
+    pub gc: WalOpCounter,
+    pub truncation: WalOpCounter,
+    pub archive: WalOpCounter,
+    pub restore: WalOpCounter,
+}
+
+impl WalOpStats {
+    pub fn account(&mut self, op: WalOp, count: u64, bytes: u64) {
+        let counter = self.counter_mut(op);
+        counter.count += count;
+        counter.bytes += bytes;
+        counter.last = Some(clock::system_time());
+    }
+
+    fn counter_mut(&mut self, op: WalOp) -> &mut WalOpCounter {
+        match op {
+            WalOp::Gc => &mut self.gc,
+            WalOp::Truncation => &mut self.truncation,
+            WalOp::Archive => &mut self.archive,
+            WalOp::Restore => &mut self.restore,
+        }
+    }
+
+    // Counters with their names, in the order of WalOp
+    pub fn counters(&self) -> [(&'static str, &WalOpCounter); 4] {
+        [
+            ("gc", &self.gc),
+            ("truncation", &self.truncation),
+            ("archive", &self.archive),
+            ("restore", &self.restore),
+        ]
+    }
+
+    fn add(&mut self, other: &WalOpStats) {
+        self.gc.add(&other.gc);
+        self.truncation.add(&other.truncation);
+        self.archive.add(&other.archive);
+        self.restore.add(&other.restore);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TenantMetrics {
+    pub received_bytes: u64,
+    pub sent_bytes: u64,
+    pub appends: u64,
+    pub paused_appends: u64,
+    pub flow_pauses: u64,
+    pub replicas: u64,
+    pub connections: u64,      /* proposer and WAL senders */
+    pub commit_lag_bytes: u64, /* flushed locally but not yet committed */
+    pub outbound_depth: u64,
+    pub outbound_failures: u64,
+    pub catchups: u64,
+    pub catchup_remaining_bytes: u64,
+    pub clock_skew_seconds: f64,
+    pub sender_lag_seconds: f64,
+    pub pageserver_lag_seconds: f64,
+    pub segment_preparing_seconds: f64, /* 0 if no segment is being zero-filled */
+    pub spare_segments: u64, /* zero-filled segments ready to be taken, with --prealloc-segments */
+    pub mirror_failed: u64,
+    pub append_latency: Histogram, /* append request received -> flush acknowledged */
+    pub ingest_latency: Histogram, /* append request received -> applied by a WAL receiver */
+    pub fsync_latency: Histogram,
+    pub wal_ops: WalOpStats,
+}
+
+impl TenantMetrics {
+    fn add(&mut self, other: &TenantMetrics) {
+        self.received_bytes += other.received_bytes;
+        self.sent_bytes += other.sent_bytes;
+        self.appends += other.appends;
+        self.paused_appends += other.paused_appends;
+        self.flow_pauses += other.flow_pauses;
+        self.replicas += other.replicas;
+        self.connections += other.connections;
+        self.commit_lag_bytes += other.commit_lag_bytes;
+        self.outbound_depth += other.outbound_depth;
+        self.outbound_failures += other.outbound_failures;
+        self.catchups += other.catchups;
+        self.catchup_remaining_bytes += other.catchup_remaining_bytes;
+        /* Skews don't add up, the largest one is of interest */
+        if other.clock_skew_seconds.abs() > self.clock_skew_seconds.abs() {
+            self.clock_skew_seconds = other.clock_skew_seconds;
+        }
+        self.sender_lag_seconds = self.sender_lag_seconds.max(other.sender_lag_seconds);
+        self.pageserver_lag_seconds = self
+            .pageserver_lag_seconds
+            .max(other.pageserver_lag_seconds);
+        self.segment_preparing_seconds = self
+            .segment_preparing_seconds
+            .max(other.segment_preparing_seconds);
+        self.spare_segments += other.spare_segments;
+        self.mirror_failed += other.mirror_failed;
+        self.append_latency.add(&other.append_latency);
+        self.ingest_latency.add(&other.ingest_latency);
+        self.fsync_latency.add(&other.fsync_latency);
+        self.wal_ops.add(&other.wal_ops);
+    }
+}
+
+const METRICS: [(&str, &str, &str, fn(&TenantMetrics) -> f64); 30] = [
+    (
+        "safekeeper_wal_received_bytes_total",
+        "counter",
+        "Bytes of WAL received from proposer",
+        |m| m.received_bytes as f64,
+    ),
+    (
+        "safekeeper_wal_sent_bytes_total",
+        "counter",
+        "Bytes of WAL sent to pageservers and replicas",
+        |m| m.sent_bytes as f64,
+    ),
+    (
+        "safekeeper_connections",
+        "gauge",
+        "Active proposer and WAL sender connections",
+        |m| m.connections as f64,
+    ),
+    (
+        "safekeeper_commit_lag_bytes",
+        "gauge",
+        "WAL flushed locally but not yet known to be committed",
+        |m| m.commit_lag_bytes as f64,
+    ),
+    (
+        "safekeeper_appends_total",
+        "counter",
+        "Append requests written to disk",
+        |m| m.appends as f64,
+    ),
+    (
+        "safekeeper_paused_appends_total",
+        "counter",
+        "Append requests rejected because WAL ingest is paused",
+        |m| m.paused_appends as f64,
+    ),
+    (
+        "safekeeper_flow_pauses_total",
+        "counter",
+        "Proposers paused because received appends exceeded the high watermark",
+        |m| m.flow_pauses as f64,
+    ),
+    (
+        "safekeeper_replicas",
+        "gauge",
+        "Replicas sending hot standby feedback",
+        |m| m.replicas as f64,
+    ),
+    (
+        "safekeeper_outbound_queue_depth",
+        "gauge",
+        "Pending outbound operations",
+        |m| m.outbound_depth as f64,
+    ),
+    (
+        "safekeeper_outbound_failures_total",
+        "counter",
+        "Failed attempts of outbound operations",
+        |m| m.outbound_failures as f64,
+    ),
+    (
+        "safekeeper_catchups",
+        "gauge",
+        "WAL senders catching up from far behind commit LSN",
+        |m| m.catchups as f64,
+    ),
+    (
+        "safekeeper_catchup_remaining_bytes",
+        "gauge",
+        "WAL left to send by catching up senders",
+        |m| m.catchup_remaining_bytes as f64,
+    ),
+    (
+        "safekeeper_clock_skew_seconds",
+        "gauge",
+        "Local time minus timestamp of the last transaction committed by proposer",
+        |m| m.clock_skew_seconds,
+    ),
+    (
+        "safekeeper_wal_sender_lag_seconds",
+        "gauge",
+        "Age of the oldest commit not yet sent by the most lagging WAL sender",
+        |m| m.sender_lag_seconds,
+    ),
+    (
+        "safekeeper_pageserver_lag_seconds",
+        "gauge",
+        "Age of the oldest commit not yet checkpointed by pageserver",
+        |m| m.pageserver_lag_seconds,
+    ),
+    (
+        "safekeeper_segment_preparing_seconds",
+        "gauge",
+        "Duration of zero-fill of a new WAL segment in progress, 0 if none",
+        |m| m.segment_preparing_seconds,
+    ),
+    (
+        "safekeeper_spare_segments",
+        "gauge",
+        "Zero-filled WAL segments ready to be renamed into place",
+        |m| m.spare_segments as f64,
+    ),
+    (
+        "safekeeper_mirror_failed",
+        "gauge",
+        "Tenants whose mirror copy of WAL has failed and is not written anymore",
+        |m| m.mirror_failed as f64,
+    ),
+    (
+        "safekeeper_gc_segments_total",
+        "counter",
+        "WAL segments removed by WAL GC",
+        |m| m.wal_ops.gc.count as f64,
+    ),
+    (
+        "safekeeper_gc_bytes_total",
+        "counter",
+        "Bytes of WAL segments removed by WAL GC",
+        |m| m.wal_ops.gc.bytes as f64,
+    ),
+    (
+        "safekeeper_gc_last_timestamp_seconds",
+        "gauge",
+        "Time of the last WAL GC which removed segments",
+        |m| m.wal_ops.gc.last_secs(),
+    ),
+    (
+        "safekeeper_truncations_total",
+        "counter",
+        "Proposers which overwrote WAL below local flush position",
+        |m| m.wal_ops.truncation.count as f64,
+    ),
+    (
+        "safekeeper_truncated_bytes_total",
+        "counter",
+        "WAL below local flush position overwritten by proposers of new terms",
+        |m| m.wal_ops.truncation.bytes as f64,
+    ),
+    (
+        "safekeeper_truncation_last_timestamp_seconds",
+        "gauge",
+        "Time of the last overwrite of WAL below local flush position",
+        |m| m.wal_ops.truncation.last_secs(),
+    ),
+    (
+        "safekeeper_archived_segments_total",
+        "counter",
+        "WAL segments uploaded to the archive",
+        |m| m.wal_ops.archive.count as f64,
+    ),
+    (
+        "safekeeper_archived_bytes_total",
+        "counter",
+        "Bytes of WAL segments uploaded to the archive",
+        |m| m.wal_ops.archive.bytes as f64,
+    ),
+    (
+        "safekeeper_archive_last_timestamp_seconds",
+        "gauge",
+        "Time of the last upload to the archive",
+        |m| m.wal_ops.archive.last_secs(),
+    ),
+    (
+        "safekeeper_restored_segments_total",
+        "counter",
+        "WAL segments downloaded from the archive for WAL senders",
+        |m| m.wal_ops.restore.count as f64,
+    ),
+    (
+        "safekeeper_restored_bytes_total",
+        "counter",
+        "Bytes of WAL segments downloaded from the archive",
+        |m| m.wal_ops.restore.bytes as f64,
+    ),
+    (
+        "safekeeper_restore_last_timestamp_seconds",
+        "gauge",
+        "Time of the last download from the archive",
+        |m| m.wal_ops.restore.last_secs(),
+    ),
+];
+
+const HISTOGRAMS: [(&str, &str, fn(&TenantMetrics) -> &Histogram); 3] = [
+    (
+        "safekeeper_append_latency_seconds",
+        "Time from receiving append request to acknowledging flush of its WAL",
+        |m| &m.append_latency,
+    ),
+    (
+        "safekeeper_ingest_latency_seconds",
+        "Time from receiving append request to the first WAL receiver reporting its WAL applied",
+        |m| &m.ingest_latency,
+    ),
+    (
+        "safekeeper_fsync_latency_seconds",
+        "Time of fsync of WAL segment after write",
+        |m| &m.fsync_latency,
+    ),
+];
+
+//
+// Render metrics of all tenants. If top_tenants is specified, only that
+// many tenants with the most received WAL get their own label.
+//
+pub fn render_metrics(registry: &TenantRegistry, top_tenants: Option<usize>) -> String {
+    let mut tenants: Vec<(String, TenantMetrics)> = registry
+        .get_systems()
+        .iter()
+        .map(|system| (system.id().to_string(), system.metrics()))
+        .collect();
+    if let Some(top) = top_tenants {
+        if tenants.len() > top {
+            tenants.sort_by(|a, b| b.1.received_bytes.cmp(&a.1.received_bytes));
+            let mut other = TenantMetrics::default();
+            for (_, metrics) in tenants.drain(top..) {
+                other.add(&metrics);
+            }
+            tenants.push(("other".to_string(), other));
+        }
+    }
+
+    let mut output = String::new();
+    for (name, kind, help, value) in METRICS.iter() {
+        writeln!(output, "# HELP {} {}", name, help).unwrap();
+        writeln!(output, "# TYPE {} {}", name, kind).unwrap();
+        for (tenant, metrics) in &tenants {
+            writeln!(
+                output,
+                "{}{{tenant=\"{}\"}} {}",
+                name,
+                tenant,
+                value(metrics)
+            )
+            .unwrap();
+        }
+    }
+
+    for (name, help, histogram) in HISTOGRAMS.iter() {
+        writeln!(output, "# HELP {} {}", name, help).unwrap();
+        writeln!(output, "# TYPE {} histogram", name).unwrap();
+        for (tenant, metrics) in &tenants {
+            histogram(metrics).render(&mut output, name, tenant);
+        }
+    }
+
+    /* WAL read cache is shared by all tenants */
+    let cache = read_cache::stats();
+    let cache_metrics = [
+        (
+            "safekeeper_read_cache_hits_total",
+            "counter",
+            "WAL reads served by the read cache",
+            cache.hits as f64,
+        ),
+        (
+            "safekeeper_read_cache_misses_total",
+            "counter",
+            "WAL reads which had to load a block into the read cache",
+            cache.misses as f64,
+        ),
+        (
+            "safekeeper_read_cache_evictions_total",
+            "counter",
+            "Blocks evicted from the read cache",
+            cache.evictions as f64,
+        ),
+        (
+            "safekeeper_read_cache_size_bytes",
+            "gauge",
+            "WAL held in the read cache",
+            cache.size as f64,
+        ),
+    ];
+    for (name, kind, help, value) in cache_metrics.iter() {
+        writeln!(output, "# HELP {} {}", name, help).unwrap();
+        writeln!(output, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(output, "{} {}", name, value).unwrap();
+    }
+    output
+}
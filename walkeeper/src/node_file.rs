@@ -0,0 +1,122 @@
+//
+//   Identity of the safekeeper owning the data directory.
+//
+//   safekeeper.node at the root of the data directory holds the node uuid, version of
+//   the directory layout and the time the directory was initialized. It is created at
+//   the first start, with the uuid given by --node-uuid or a random one. Later starts
+//   refuse to use the directory if --node-uuid doesn't match it, so that two nodes can't
+//   share a directory by mistake (e.g. over NFS), and if its layout is newer than the
+//   one this binary knows. The identity is reported by status and SAFEKEEPER_IDENTIFY.
+//
+use lazy_static::lazy_static;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::clock;
+use crate::pq_protocol::Result;
+
+pub const NODE_FILE_NAME: &str = "safekeeper.node";
+pub const LAYOUT_VERSION: u32 = 1; /* per-tenant directories */
+
+lazy_static! {
+    static ref IDENTITY: Mutex<Option<NodeFile>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeFile {
+    pub uuid: String, /* 32 hex digits */
+    pub layout_version: u32,
+    pub created: String, /* RFC 3339 */
+}
+
+impl NodeFile {
+    pub fn describe(&self) -> String {
+        format!(
+            "node {} layout_version={} created={}",
+            self.uuid, self.layout_version, self.created
+        )
+    }
+}
+
+pub fn format_uuid(uuid: u128) -> String {
+    format!("{:032x}", uuid)
+}
+
+// Parse node uuid: 32 hex digits, dashes are ignored
+pub fn parse_uuid(s: &str) -> Result<u128> {
+    let digits: String = s.chars().filter(|c| *c != '-').collect();
+    if digits.len() != 32 {
+        io_error!("Invalid node uuid {}, expected 32 hex digits", s);
+    }
+    match u128::from_str_radix(&digits, 16) {
+        Ok(uuid) => Ok(uuid),
+        Err(_) => {
+            io_error!("Invalid node uuid {}, expected 32 hex digits", s);
+        }
+    }
+}
+
+//
+// Load identity of the data directory, creating it at the first start, and check it
+// against the configured uuid. The identity is then reported by identity().
+//
+pub fn load_or_create(data_dir: &Path, node_uuid: Option<u128>) -> Result<NodeFile> {
+    let path = data_dir.join(NODE_FILE_NAME);
+    let node = if path.exists() {
+        let content = fs::read_to_string(&path)?;
+        let node: NodeFile = toml::from_str(&content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse {:?}: {}", path, e),
+            )
+        })?;
+        let uuid = parse_uuid(&node.uuid)?;
+        if let Some(node_uuid) = node_uuid {
+            if node_uuid != uuid {
+                io_error!(
+                    "data directory {:?} belongs to safekeeper {}, not to {}",
+                    data_dir,
+                    node.uuid,
+                    format_uuid(node_uuid)
+                );
+            }
+        }
+        if node.layout_version > LAYOUT_VERSION {
+            io_error!(
+                "data directory {:?} has layout version {}, this wal_acceptor supports up to {}",
+                data_dir,
+                node.layout_version,
+                LAYOUT_VERSION
+            );
+        }
+        node
+    } else {
+        let node = NodeFile {
+            uuid: format_uuid(node_uuid.unwrap_or_else(rand::random)),
+            layout_version: LAYOUT_VERSION,
+            created: chrono::DateTime::<chrono::Utc>::from(clock::system_time()).to_rfc3339(),
+        };
+        let content = toml::to_string(&node)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        File::open(data_dir)?.sync_all()?;
+        info!("Initialized data directory {:?} of safekeeper {}", data_dir, node.uuid);
+        node
+    };
+    *IDENTITY.lock().unwrap() = Some(node.clone());
+    Ok(node)
+}
+
+// Identity of this safekeeper, None until the data directory is loaded
+pub fn identity() -> Option<NodeFile> {
+    IDENTITY.lock().unwrap().clone()
+}
@@ -0,0 +1,387 @@
+//
+//   Object storage as the primary store of WAL ("diskless safekeeper"), experimental.
+//
+//   With --object-storage, WAL of tenants is not written to local segments. Appended WAL
+//   goes to a small staging file in the tenant directory, synced before the append is
+//   acknowledged, and when it grows to the chunk size it is uploaded to the bucket as
+//   object <prefix>/<tenant>/<timeline>_<start lsn>_<end lsn> and the staging file is
+//   reset. Local disk use is thus bounded by the chunk size per tenant. The proposer
+//   protocol and consensus don't change, they reach it through WalStorage (see
+//   wal_storage.rs): end of WAL is the end of the staged data.
+//
+//   The staging file starts with a header holding its start LSN and timeline, which is
+//   always the end of uploaded WAL. Uploaded chunks ending after it are stale (uploaded
+//   before a crash or left by truncation) and are deleted before the first append.
+//   Reading WAL back (WAL senders, compare-peer) is not supported yet.
+//
+use byteorder::{ByteOrder, LittleEndian};
+use log::*;
+use s3::bucket::Bucket;
+use s3::creds::Credentials;
+use s3::region::Region;
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::runtime::Handle;
+
+use crate::pq_protocol::{Result, SystemId};
+use crate::xlog_utils::{format_lsn, TimeLineID, XLogRecPtr};
+
+pub const STAGING_FILE_NAME: &str = "wal.staging";
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+const HEADER_SIZE: u64 = 16; /* start LSN, timeline, padding */
+
+fn other_error<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+//
+// S3 bucket and prefix of objects of the safekeeper. Region and endpoint are taken from
+// S3_REGION and S3_ENDPOINT, credentials from S3_ACCESSKEY and S3_SECRET, like in pageserver.
+// Used by object storage of WAL and by the archive of completed segments.
+//
+#[derive(Clone)]
+pub struct BucketConf {
+    pub bucket_name: String,
+    pub prefix: String,
+    bucket: Bucket,
+}
+
+impl fmt::Debug for BucketConf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BucketConf")
+            .field("bucket_name", &self.bucket_name)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl BucketConf {
+    pub fn new(bucket_name: &str, prefix: &str) -> Result<BucketConf> {
+        let var = |name: &str| env::var(name).map_err(|e| other_error(format!("{}: {}", name, e)));
+        let region = Region::Custom {
+            region: var("S3_REGION")?,
+            endpoint: var("S3_ENDPOINT")?,
+        };
+        let credentials = Credentials::new(
+            Some(&var("S3_ACCESSKEY")?),
+            Some(&var("S3_SECRET")?),
+            None,
+            None,
+            None,
+        )
+        .map_err(other_error)?;
+        let bucket =
+            Bucket::new_with_path_style(bucket_name, region, credentials).map_err(other_error)?;
+        Ok(BucketConf {
+            bucket_name: bucket_name.to_string(),
+            prefix: prefix.trim_end_matches('/').to_string(),
+            bucket: bucket,
+        })
+    }
+
+    pub fn tenant_prefix(&self, tenant: SystemId) -> String {
+        format!("{}/{}/", self.prefix, tenant)
+    }
+
+    pub async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let (_, code) = self
+            .bucket
+            .put_object(key, data)
+            .await
+            .map_err(other_error)?;
+        if code != 200 {
+            io_error!(
+                "Failed to upload {} to bucket {}: HTTP status {}",
+                key,
+                self.bucket_name,
+                code
+            );
+        }
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let (data, code) = self.bucket.get_object(key).await.map_err(other_error)?;
+        if code != 200 {
+            io_error!(
+                "Failed to download {} from bucket {}: HTTP status {}",
+                key,
+                self.bucket_name,
+                code
+            );
+        }
+        Ok(data)
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        self.bucket.delete_object(key).await.map_err(other_error)?;
+        Ok(())
+    }
+
+    // Keys of all objects with the given prefix
+    pub async fn list(&self, prefix: String) -> Result<Vec<String>> {
+        let results = self.bucket.list(prefix, None).await.map_err(other_error)?;
+        Ok(results
+            .into_iter()
+            .flat_map(|result| result.contents.into_iter().map(|object| object.key))
+            .collect())
+    }
+}
+
+//
+// Object storage of WAL of all tenants
+//
+#[derive(Debug, Clone)]
+pub struct ObjectStorageConf {
+    pub bucket: BucketConf,
+    pub chunk_size: u64, /* staged WAL is uploaded when it reaches that size */
+}
+
+/* Uploaded chunk of WAL */
+#[derive(Debug, Clone)]
+struct Chunk {
+    key: String,
+    timeline: TimeLineID,
+    start_lsn: XLogRecPtr,
+    end_lsn: XLogRecPtr,
+}
+
+impl Chunk {
+    fn parse(key: &str) -> Option<Chunk> {
+        let name = key.rsplit('/').next()?;
+        let mut parts = name.split('_');
+        let chunk = Chunk {
+            key: key.to_string(),
+            timeline: u32::from_str_radix(parts.next()?, 16).ok()?,
+            start_lsn: u64::from_str_radix(parts.next()?, 16).ok()?,
+            end_lsn: u64::from_str_radix(parts.next()?, 16).ok()?,
+        };
+        if parts.next().is_some() || chunk.start_lsn >= chunk.end_lsn {
+            return None;
+        }
+        Some(chunk)
+    }
+}
+
+//
+// WAL of a tenant in object storage. Methods doing requests to the bucket block,
+// so they are called on the blocking thread pool only, like writes of local segments.
+//
+pub struct ObjectWal {
+    tenant: SystemId,
+    staging_path: PathBuf,
+    staging: File,
+    start_lsn: XLogRecPtr, /* of staged data, end of uploaded WAL */
+    end_lsn: XLogRecPtr,
+    timeline: TimeLineID,
+    started: bool,    /* staging file has a header */
+    cleaned_up: bool, /* stale chunks are deleted */
+}
+
+impl ObjectWal {
+    //
+    // Open staging file of the tenant. Doesn't access object storage, so it can be
+    // used to find end of WAL from async code.
+    //
+    pub fn open(system_dir: &Path, tenant: SystemId) -> Result<ObjectWal> {
+        let staging_path = system_dir.join(STAGING_FILE_NAME);
+        let mut staging = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&staging_path)?;
+        let len = staging.metadata()?.len();
+        let mut header = [0u8; HEADER_SIZE as usize];
+        let started = len >= HEADER_SIZE;
+        if started {
+            staging.read_exact(&mut header)?;
+        }
+        let start_lsn = LittleEndian::read_u64(&header[0..8]);
+        Ok(ObjectWal {
+            tenant: tenant,
+            staging_path: staging_path,
+            staging: staging,
+            start_lsn: start_lsn,
+            end_lsn: if started {
+                start_lsn + len - HEADER_SIZE
+            } else {
+                0
+            },
+            timeline: LittleEndian::read_u32(&header[8..12]),
+            started: started,
+            cleaned_up: false,
+        })
+    }
+
+    pub fn end_of_wal(&self) -> (XLogRecPtr, TimeLineID) {
+        (self.end_lsn, self.timeline)
+    }
+
+    //
+    // Append WAL at startpos, overwriting WAL after it if any. Returns when the data
+    // is durable: synced to the staging file, or uploaded.
+    //
+    pub fn append(
+        &mut self,
+        storage: &ObjectStorageConf,
+        no_sync: bool,
+        startpos: XLogRecPtr,
+        timeline: TimeLineID,
+        buf: &[u8],
+    ) -> Result<()> {
+        if self.started && !self.cleaned_up {
+            self.delete_chunks_after(storage, self.start_lsn)?;
+            self.cleaned_up = true;
+        }
+        if !self.started {
+            self.reset(startpos, timeline, no_sync)?;
+        } else if startpos < self.end_lsn {
+            self.truncate(storage, startpos, no_sync)?;
+        } else if startpos > self.end_lsn {
+            if self.end_lsn != self.start_lsn {
+                io_error!(
+                    "Gap in WAL of system {}: append at {}, staged WAL ends at {}",
+                    self.tenant,
+                    format_lsn(startpos),
+                    format_lsn(self.end_lsn)
+                );
+            }
+            self.reset(startpos, timeline, no_sync)?;
+        }
+        if timeline != self.timeline {
+            if self.end_lsn != self.start_lsn {
+                self.upload(storage, no_sync)?;
+            }
+            self.reset(self.end_lsn, timeline, no_sync)?;
+        }
+        let offset = HEADER_SIZE + self.end_lsn - self.start_lsn;
+        self.staging.seek(SeekFrom::Start(offset))?;
+        self.staging.write_all(buf)?;
+        if !no_sync {
+            self.staging.sync_data()?;
+        }
+        self.end_lsn += buf.len() as u64;
+        if self.end_lsn - self.start_lsn >= storage.chunk_size {
+            self.upload(storage, no_sync)?;
+        }
+        Ok(())
+    }
+
+    /* Start empty staged chunk at the given position */
+    fn reset(&mut self, start_lsn: XLogRecPtr, timeline: TimeLineID, no_sync: bool) -> Result<()> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        LittleEndian::write_u64(&mut header[0..8], start_lsn);
+        LittleEndian::write_u32(&mut header[8..12], timeline);
+        self.staging.set_len(0)?;
+        self.staging.seek(SeekFrom::Start(0))?;
+        self.staging.write_all(&header)?;
+        if !no_sync {
+            self.staging.sync_data()?;
+        }
+        self.start_lsn = start_lsn;
+        self.end_lsn = start_lsn;
+        self.timeline = timeline;
+        self.started = true;
+        Ok(())
+    }
+
+    fn upload(&mut self, storage: &ObjectStorageConf, no_sync: bool) -> Result<()> {
+        let mut data = vec![0u8; (self.end_lsn - self.start_lsn) as usize];
+        self.staging.seek(SeekFrom::Start(HEADER_SIZE))?;
+        self.staging.read_exact(&mut data)?;
+        let key = format!(
+            "{}{:08X}_{:016X}_{:016X}",
+            storage.bucket.tenant_prefix(self.tenant),
+            self.timeline,
+            self.start_lsn,
+            self.end_lsn
+        );
+        Handle::current().block_on(storage.bucket.put(&key, &data))?;
+        debug!("Uploaded WAL chunk {} of {} bytes", key, data.len());
+        self.reset(self.end_lsn, self.timeline, no_sync)
+    }
+
+    //
+    // Cut WAL at the given position. Uploaded WAL before it, in the chunk containing
+    // the position, is downloaded back to staging before the chunks are deleted.
+    //
+    fn truncate(
+        &mut self,
+        storage: &ObjectStorageConf,
+        lsn: XLogRecPtr,
+        no_sync: bool,
+    ) -> Result<()> {
+        if lsn >= self.start_lsn {
+            self.staging.set_len(HEADER_SIZE + lsn - self.start_lsn)?;
+            if !no_sync {
+                self.staging.sync_data()?;
+            }
+            self.end_lsn = lsn;
+            return Ok(());
+        }
+        let chunks = self.list_chunks(storage)?;
+        match chunks
+            .iter()
+            .find(|chunk| chunk.start_lsn < lsn && chunk.end_lsn > lsn)
+        {
+            Some(chunk) => {
+                let data = Handle::current().block_on(storage.bucket.get(&chunk.key))?;
+                if (data.len() as u64) < lsn - chunk.start_lsn {
+                    io_error!("WAL chunk {} is shorter than its name says", chunk.key);
+                }
+                self.reset(chunk.start_lsn, chunk.timeline, true)?;
+                self.staging
+                    .write_all(&data[..(lsn - chunk.start_lsn) as usize])?;
+                if !no_sync {
+                    self.staging.sync_data()?;
+                }
+                self.end_lsn = lsn;
+            }
+            None => {
+                let timeline = chunks
+                    .iter()
+                    .find(|chunk| chunk.end_lsn == lsn)
+                    .map_or(self.timeline, |chunk| chunk.timeline);
+                self.reset(lsn, timeline, no_sync)?;
+            }
+        }
+        /* Staging file is durable first: stale chunks left by crash are deleted on restart */
+        self.delete_chunks_after(storage, self.start_lsn)
+    }
+
+    fn list_chunks(&self, storage: &ObjectStorageConf) -> Result<Vec<Chunk>> {
+        let prefix = storage.bucket.tenant_prefix(self.tenant);
+        let mut chunks = Vec::new();
+        for key in Handle::current().block_on(storage.bucket.list(prefix))? {
+            match Chunk::parse(&key) {
+                Some(chunk) => chunks.push(chunk),
+                None => warn!(
+                    "Unrecognized object {} in bucket {}",
+                    key, storage.bucket.bucket_name
+                ),
+            }
+        }
+        chunks.sort_by_key(|chunk| chunk.start_lsn);
+        Ok(chunks)
+    }
+
+    /* Delete chunks ending after the position, the latest first */
+    fn delete_chunks_after(&self, storage: &ObjectStorageConf, lsn: XLogRecPtr) -> Result<()> {
+        for chunk in self.list_chunks(storage)?.iter().rev() {
+            if chunk.end_lsn <= lsn {
+                break;
+            }
+            info!(
+                "Delete stale WAL chunk {} of system {} staged from {:?}",
+                chunk.key, self.tenant, self.staging_path
+            );
+            Handle::current().block_on(storage.bucket.delete(&chunk.key))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//
+//   Per-tenant queue of outbound operations (pageserver callbacks, uploads, hooks).
+//
+//   Operations are persisted in the tenant directory before they are attempted,
+//   so they survive restart of the safekeeper. Failed operations are retried
+//   with exponential backoff; the queue of a tenant is processed in order, so a
+//   failing head blocks (throttles) the rest of the queue of that tenant only.
+//
+use lazy_static::lazy_static;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::callback;
+use crate::clock;
+use crate::pq_protocol::{Result, SystemId};
+use crate::wal_service::TenantRegistry;
+use crate::WalAcceptorConf;
+
+pub const OUTBOUND_QUEUE_FILE_NAME: &str = "outbound.json";
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref QUEUE_CHANGED: Notify = Notify::new();
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OutboundOp {
+    PageserverCallback,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingOp {
+    op: OutboundOp,
+    attempts: u32,
+    #[serde(skip, default = "clock::now")]
+    next_attempt: Instant,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OutboundStats {
+    pub enqueued: u64,
+    pub completed: u64,
+    pub failures: u64,
+}
+
+#[derive(Debug)]
+pub struct OutboundQueue {
+    path: PathBuf,
+    items: VecDeque<PendingOp>,
+    stats: OutboundStats,
+}
+
+impl OutboundQueue {
+    pub fn load(tenant_dir: &Path) -> Result<OutboundQueue> {
+        let path = tenant_dir.join(OUTBOUND_QUEUE_FILE_NAME);
+        let items = if path.exists() {
+            let content = fs::read_to_string(&path)?;
+            serde_json::from_str(&content).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Failed to parse {:?}: {}", path, e),
+                )
+            })?
+        } else {
+            VecDeque::new()
+        };
+        Ok(OutboundQueue {
+            path,
+            items,
+            stats: OutboundStats::default(),
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        let content = serde_json::to_string(&self.items)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    //
+    // Add operation to the queue, unless the same operation is already pending
+    //
+    pub fn enqueue(&mut self, op: OutboundOp) -> Result<()> {
+        if self.items.iter().any(|item| item.op == op) {
+            return Ok(());
+        }
+        self.items.push_back(PendingOp {
+            op,
+            attempts: 0,
+            next_attempt: clock::now(),
+        });
+        self.stats.enqueued += 1;
+        self.save()?;
+        QUEUE_CHANGED.notify_one();
+        Ok(())
+    }
+
+    pub fn depth(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn stats(&self) -> OutboundStats {
+        self.stats
+    }
+
+    /* Head of the queue, if it is time to attempt it */
+    fn due(&self) -> Option<OutboundOp> {
+        match self.items.front() {
+            Some(item) if item.next_attempt <= clock::now() => Some(item.op.clone()),
+            _ => None,
+        }
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        self.items.pop_front();
+        self.stats.completed += 1;
+        self.save()
+    }
+
+    fn fail(&mut self) -> Result<Duration> {
+        let item = self.items.front_mut().unwrap();
+        let backoff = MIN_BACKOFF
+            .checked_mul(1 << item.attempts.min(16))
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF);
+        item.attempts += 1;
+        item.next_attempt = clock::now() + backoff;
+        self.stats.failures += 1;
+        self.save()?;
+        Ok(backoff)
+    }
+}
+
+async fn execute(conf: &WalAcceptorConf, system_id: SystemId, op: &OutboundOp) -> Result<()> {
+    match op {
+        OutboundOp::PageserverCallback => {
+            callback::pageserver_callback(conf)
+                .request_callback(system_id)
+                .await
+        }
+    }
+}
+
+//
+// Process due operations of all tenants
+//
+pub async fn outbound_loop(conf: &WalAcceptorConf, tenants: &TenantRegistry) {
+    loop {
+        for system in tenants.get_systems() {
+            loop {
+                let op = match system.outbound_queue().due() {
+                    Some(op) => op,
+                    None => break,
+                };
+                let res = execute(conf, system.id(), &op).await;
+                let mut queue = system.outbound_queue();
+                let saved = match &res {
+                    Ok(()) => queue.complete(),
+                    Err(e) => queue.fail().map(|backoff| {
+                        warn!(
+                            "{:?} for system {} failed: {}, retry in {:?}",
+                            op,
+                            system.id(),
+                            e,
+                            backoff
+                        );
+                    }),
+                };
+                if let Err(e) = saved {
+                    error!("Failed to save outbound queue of system {}: {}", system.id(), e);
+                    break;
+                }
+                if res.is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = clock::timeout(POLL_INTERVAL, QUEUE_CHANGED.notified()).await;
+    }
+}
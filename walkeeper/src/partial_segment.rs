@@ -0,0 +1,315 @@
+//
+//   Partial WAL segments: naming and lifecycle of the segment being written.
+//
+//   The segment being written is named <segment>.partial (in pg_wal layout it gets its
+//   final name right away, like in Postgres). The writer, WAL senders, peer checks and
+//   tenant load all go through this module and rely on the following invariants:
+//   - a new segment is zero-filled to its full size under <name>.prep and renamed into
+//     place, or a spare zero-filled one is renamed into place (see spare_segments.rs), so
+//     a segment file is either absent or has the full size;
+//   - WAL is written to the completed segment if there is one (overwrite of WAL by a
+//     new term), otherwise to the partial one, which is never created next to the
+//     completed one;
+//   - the partial segment gets its final name only after its last byte is written (and
+//     synced unless --no-sync), and never loses it, so a completed segment is full of WAL;
+//   - readers open the partial segment first and fall back to the completed one: as the
+//     rename is one way, this order doesn't miss a segment being completed concurrently;
+//   - completed segment may be encoded at rest (see at_rest.rs), then it is smaller than
+//     the segment size; readers get it decoded and the writer decodes it in place first;
+//   - leftovers of crashes and timeline switches (*.prep files, several partial
+//     segments) are reconciled at tenant load, before anybody writes WAL of the tenant.
+//
+use byteorder::{ByteOrder, LittleEndian};
+use log::*;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use crate::at_rest::{self, AtRestKey};
+use crate::fault_fs;
+use crate::pq_protocol::Result;
+use crate::xlog_utils::*;
+
+pub const PARTIAL_SUFFIX: &str = ".partial";
+pub const PREP_SUFFIX: &str = ".prep";
+
+//
+// Locations of a segment in a directory: its final name and the partial one
+//
+#[derive(Debug, Clone)]
+pub struct SegmentPath {
+    pub name: String, /* final name of the segment */
+    pub complete: PathBuf,
+    pub partial: PathBuf,
+}
+
+impl SegmentPath {
+    pub fn new(
+        dir: &Path,
+        timeline: TimeLineID,
+        segno: XLogSegNo,
+        wal_seg_size: usize,
+    ) -> SegmentPath {
+        let name = XLogFileName(timeline, segno, wal_seg_size);
+        SegmentPath {
+            complete: dir.join(&name),
+            partial: dir.join(name.clone() + PARTIAL_SUFFIX),
+            name: name,
+        }
+    }
+
+    pub fn path(&self, partial: bool) -> &PathBuf {
+        if partial {
+            &self.partial
+        } else {
+            &self.complete
+        }
+    }
+
+    // Whether the segment is present, under either name
+    pub fn exists(&self) -> bool {
+        self.partial.exists() || self.complete.exists()
+    }
+
+    //
+    // Open the segment for reading: the partial one first, the completed one next,
+    // decoded if it is encoded at rest (with key of conf.at_rest_key). Error of opening
+    // the completed one is returned if neither is present.
+    //
+    pub fn open_for_read(&self, key: Option<&AtRestKey>) -> Result<File> {
+        if let Ok(file) = File::open(&self.partial) {
+            return Ok(file);
+        }
+        at_rest::open_plain(&self.complete, key)
+    }
+
+    //
+    // Open existing segment for writing: the completed one first, the partial one next.
+    // Returns the file and whether it is partial, None if the segment doesn't exist yet.
+    // Completed segment encoded at rest is decoded in place first.
+    //
+    pub fn open_for_write(&self, key: Option<&AtRestKey>) -> Result<Option<(File, bool)>> {
+        if at_rest::is_encoded(&self.complete) {
+            at_rest::decode_in_place(&self.complete, key)?;
+        }
+        if let Ok(file) = OpenOptions::new().write(true).open(&self.complete) {
+            return Ok(Some((file, false)));
+        }
+        if let Ok(file) = OpenOptions::new().write(true).open(&self.partial) {
+            return Ok(Some((file, true)));
+        }
+        Ok(None)
+    }
+
+    // Give the partial segment its final name, once its last byte is written
+    pub fn complete(&self) -> Result<()> {
+        fs::rename(&self.partial, &self.complete)?;
+        fault_fs::rename(&self.partial, &self.complete);
+        Ok(())
+    }
+
+    //
+    // Start segment of a new timeline at the path with the first len bytes of this one,
+    // zero-filled up to the segment size, as Postgres does on timeline switch. Written
+    // under <path>.prep and renamed into place, like a new segment.
+    //
+    pub fn copy_prefix(
+        &self,
+        path: &Path,
+        len: usize,
+        wal_seg_size: usize,
+        key: Option<&AtRestKey>,
+        no_sync: bool,
+    ) -> Result<()> {
+        let mut prefix = vec![0u8; len];
+        self.open_for_read(key)?.read_exact(&mut prefix)?;
+        let tmp_path = PathBuf::from(format!("{}{}", path.display(), PREP_SUFFIX));
+        let mut file = File::create(&tmp_path)?;
+        write_zeros(&mut file, &tmp_path, wal_seg_size, true)?;
+        file.seek(SeekFrom::Start(0))?;
+        fault_fs::write(&tmp_path, 0, &prefix)?;
+        file.write_all(&prefix)?;
+        if !no_sync {
+            fault_fs::sync(&tmp_path)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        fault_fs::rename(&tmp_path, path);
+        if !no_sync {
+            File::open(path.parent().unwrap())?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+//
+// Write zero-filled segment of the given size under a temporary name, then rename it
+// to the given path
+//
+pub fn zero_fill(path: &Path, wal_seg_size: usize, no_sync: bool) -> Result<()> {
+    let tmp_path = PathBuf::from(format!("{}{}", path.display(), PREP_SUFFIX));
+    let mut file = File::create(&tmp_path)?;
+    write_zeros(&mut file, &tmp_path, wal_seg_size, no_sync)?;
+    fs::rename(&tmp_path, path)?;
+    fault_fs::rename(&tmp_path, path);
+    Ok(())
+}
+
+// Overwrite the file from the start with zeros up to the segment size, and sync it
+pub fn write_zeros(file: &mut File, path: &Path, wal_seg_size: usize, no_sync: bool) -> Result<()> {
+    const ZERO_BLOCK: &'static [u8] = &[0u8; XLOG_BLCKSZ];
+    file.seek(SeekFrom::Start(0))?;
+    for i in 0..(wal_seg_size / XLOG_BLCKSZ) {
+        fault_fs::write(path, (i * XLOG_BLCKSZ) as u64, ZERO_BLOCK)?;
+        file.write_all(&ZERO_BLOCK)?;
+    }
+    if !no_sync {
+        fault_fs::sync(path)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+// Names of partial WAL segments in the directory, sorted by segment and timeline
+pub fn list_partial_segments(data_dir: &PathBuf) -> Vec<String> {
+    let mut partials = Vec::new();
+    if let Ok(entries) = fs::read_dir(data_dir) {
+        for entry in entries.flatten() {
+            if let Some(fname) = entry.file_name().to_str() {
+                if IsPartialXLogFileName(fname) {
+                    partials.push(fname.to_string());
+                }
+            }
+        }
+    }
+    /* Names start with timeline, segment number comes next */
+    partials.sort_by(|a, b| (&a[8..24], &a[0..8]).cmp(&(&b[8..24], &b[0..8])));
+    partials
+}
+
+// Segment being zero-filled when the process crashed, it contains no WAL
+fn is_prep_file_name(fname: &str) -> bool {
+    match fname.strip_suffix(PREP_SUFFIX) {
+        Some(segment) => IsXLogFileName(segment) || IsPartialXLogFileName(segment),
+        None => false,
+    }
+}
+
+//
+// Whether the partial segment is written up to its end: its records are valid (see
+// find_end_of_wal_segment) up to the end of the segment, or up to the last record, which
+// continues in the next segment, so that all pages after its start are continuation pages.
+// Zero-filled tail or a broken record in the middle mean that it is not.
+//
+fn is_fully_written(data_dir: &PathBuf, fname: &str, wal_seg_size: usize) -> Result<bool> {
+    let end = find_end_of_wal_segment(data_dir, fname, wal_seg_size) as usize;
+    if end >= wal_seg_size {
+        return Ok(true);
+    }
+    if end == 0 {
+        return Ok(false);
+    }
+    let mut file = File::open(data_dir.join(fname))?;
+    let mut xl_tot_len = [0u8; 4];
+    file.seek(SeekFrom::Start(end as u64))?;
+    file.read_exact(&mut xl_tot_len)?;
+    let next_page = (end / XLOG_BLCKSZ + 1) * XLOG_BLCKSZ;
+    let headers = (wal_seg_size - next_page) / XLOG_BLCKSZ * XLOG_SIZE_OF_XLOG_SHORT_PHD;
+    if (LittleEndian::read_u32(&xl_tot_len) as usize) <= wal_seg_size - end - headers {
+        return Ok(false);
+    }
+    let mut page = [0u8; XLOG_BLCKSZ];
+    file.seek(SeekFrom::Start(next_page as u64))?;
+    for _ in (next_page..wal_seg_size).step_by(XLOG_BLCKSZ) {
+        file.read_exact(&mut page)?;
+        let xlp_magic = LittleEndian::read_u16(&page[0..2]);
+        let xlp_info = LittleEndian::read_u16(&page[2..4]);
+        if xlp_magic != XLOG_PAGE_MAGIC || (xlp_info & XLP_FIRST_IS_CONTRECORD) == 0 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+//
+// Normally a tenant has at most one partial segment: the last one, which is being written.
+// Others are leftovers of crashes or timeline switches, and readers and the writer may pick
+// different files for the same segment. Reconcile them:
+// - partial segment shadowed by the completed one is stale, it is orphaned;
+// - partial segment followed by a later segment of the same timeline should have been
+//   fully written (WAL is written sequentially) but not renamed: its rename is completed
+//   if its WAL is valid up to the end, otherwise it is orphaned;
+// - of the remaining ones, the latest (by segment, then timeline) is authoritative,
+//   like in find_end_of_wal, and the rest are orphaned.
+// Orphaned segments are renamed to <name>.partial.orphan and kept for investigation,
+// before_orphan is called with the file name and reason before that. Leftover *.prep
+// files are removed.
+// Returns descriptions of the performed actions.
+//
+pub fn reconcile_partial_segments<F>(
+    data_dir: &PathBuf,
+    wal_seg_size: usize,
+    mut before_orphan: F,
+) -> io::Result<Vec<String>>
+where
+    F: FnMut(&str, &str) -> io::Result<()>,
+{
+    let mut actions = Vec::new();
+    let mut completed: Vec<(XLogSegNo, TimeLineID)> = Vec::new();
+    for entry in fs::read_dir(data_dir)?.flatten() {
+        if let Some(fname) = entry.file_name().to_str() {
+            if IsXLogFileName(fname) {
+                completed.push(XLogFromFileName(fname, wal_seg_size));
+            } else if is_prep_file_name(fname) {
+                fs::remove_file(entry.path())?;
+                actions.push(format!("{} is an interrupted zero-fill, removed", fname));
+            }
+        }
+    }
+    let partials = list_partial_segments(data_dir);
+    let mut remaining = Vec::new();
+    if partials.len() > 1 {
+        for fname in &partials {
+            let (segno, tli) = XLogFromFileName(fname, wal_seg_size);
+            let path = data_dir.join(fname);
+            let final_name = &fname[0..XLOG_FNAME_LEN];
+            let followed = partials
+                .iter()
+                .map(|other| XLogFromFileName(other, wal_seg_size))
+                .chain(completed.iter().copied())
+                .any(|(other_segno, other_tli)| other_tli == tli && other_segno > segno);
+            if completed.contains(&(segno, tli)) {
+                before_orphan(fname, "shadowed by completed segment")?;
+                fs::rename(&path, path.with_extension("partial.orphan"))?;
+                actions.push(format!("{} is shadowed by completed segment, orphaned", fname));
+            } else if followed && is_fully_written(data_dir, fname, wal_seg_size)? {
+                fs::rename(&path, data_dir.join(final_name))?;
+                actions.push(format!("{} is followed by later WAL, completed", fname));
+            } else if followed {
+                before_orphan(fname, "followed by later WAL, but not fully written")?;
+                fs::rename(&path, path.with_extension("partial.orphan"))?;
+                actions.push(format!(
+                    "{} is followed by later WAL, but not fully written, orphaned",
+                    fname
+                ));
+            } else {
+                remaining.push(fname);
+            }
+        }
+    }
+    /* Sorted by segment and timeline, so the authoritative one is the last */
+    if let Some((_, stale)) = remaining.split_last() {
+        for fname in stale {
+            let path = data_dir.join(fname);
+            before_orphan(fname, "superseded by later partial segment")?;
+            fs::rename(&path, path.with_extension("partial.orphan"))?;
+            actions.push(format!("{} is superseded by later partial segment, orphaned", fname));
+        }
+    }
+    for action in &actions {
+        warn!("Reconciliation of partial segments in {:?}: {}", data_dir, action);
+    }
+    Ok(actions)
+}
@@ -0,0 +1,312 @@
+//
+//   Consistency check of WAL against a peer safekeeper of the same tenant.
+//
+//   Safekeepers of a quorum must have identical WAL up to commit_lsn, and a divergence
+//   there means a bug in consensus or silent corruption. "compare-peer" admin command
+//   connects to the peer as a libpq client (user "safekeeper" with the password given to
+//   the command, since the peer serves SAFEKEEPER_WAL_HASH to authenticated clients only),
+//   reports positions of both, and compares hashes of the WAL retained by both
+//   safekeepers up to the lower commit_lsn, which the peer computes on
+//   SAFEKEEPER_WAL_HASH. WAL beyond commit_lsn may legitimately differ (it
+//   is overwritten by a proposer of the next term), so it is not compared. The first
+//   mismatching chunk is split and compared again, until the first mismatching byte is
+//   found. Only hashes go over the network, and WAL is read on the blocking thread pool.
+//
+use log::*;
+use sha2::{Digest, Sha256};
+use std::cmp::{max, min};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio_postgres::{connect, NoTls, SimpleQueryMessage};
+
+use crate::at_rest::AtRestKey;
+use crate::partial_segment::SegmentPath;
+use crate::pq_protocol::Result;
+use crate::wal_service::{parse_lsn, run_blocking, TenantRegistry};
+use crate::xlog_utils::*;
+use crate::{parse_tenant_id, tenant_dir, WalAcceptorConf};
+
+pub const INITIAL_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+pub const MAX_HASH_CHUNKS: u64 = 4096; /* rows in reply to SAFEKEEPER_WAL_HASH */
+const SPLIT_FACTOR: u64 = 16; /* mismatching chunk is split into that many */
+
+fn other_error<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+//
+// SHA-256 of WAL start_lsn..end_lsn in the tenant directory, hex encoded
+//
+pub fn hash_wal(
+    system_dir: &Path,
+    key: Option<&AtRestKey>,
+    timeline: TimeLineID,
+    wal_seg_size: usize,
+    start_lsn: XLogRecPtr,
+    end_lsn: XLogRecPtr,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; XLOG_BLCKSZ * 8];
+    let mut pos = start_lsn;
+    while pos < end_lsn {
+        let segno = XLByteToSeg(pos, wal_seg_size);
+        let segment = SegmentPath::new(system_dir, timeline, segno, wal_seg_size);
+        let mut file = segment.open_for_read(key)?;
+        let offset = XLogSegmentOffset(pos, wal_seg_size) as u64;
+        file.seek(SeekFrom::Start(offset))?;
+        /* Read till the end of the range or of the segment */
+        let mut left = min(end_lsn - pos, wal_seg_size as u64 - offset) as usize;
+        while left > 0 {
+            let n = min(left, buf.len());
+            file.read_exact(&mut buf[..n])?;
+            hasher.update(&buf[..n]);
+            left -= n;
+            pos += n as u64;
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+//
+// WAL range retained in the tenant directory and its timeline
+//
+pub fn retained_wal(
+    system_dir: &Path,
+    wal_seg_size: usize,
+    pg_wal_layout: bool,
+) -> io::Result<(XLogRecPtr, XLogRecPtr, TimeLineID)> {
+    let system_dir = system_dir.to_path_buf();
+    let (wal_end, timeline) = find_end_of_wal(&system_dir, wal_seg_size, true, pg_wal_layout);
+    let wal_start = match find_start_of_wal(&system_dir, wal_seg_size)? {
+        Some(segno) => XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size),
+        None => wal_end,
+    };
+    Ok((wal_start, wal_end, timeline))
+}
+
+//
+// Hashes of consecutive chunks of start_lsn..end_lsn as (start, end, hash), read on the
+// blocking thread pool
+//
+pub async fn hash_wal_chunks(
+    system_dir: PathBuf,
+    key: Option<AtRestKey>,
+    timeline: TimeLineID,
+    wal_seg_size: usize,
+    chunks: Vec<(XLogRecPtr, XLogRecPtr)>,
+) -> Result<Vec<(XLogRecPtr, XLogRecPtr, String)>> {
+    run_blocking(move || {
+        chunks
+            .into_iter()
+            .map(|(start_lsn, end_lsn)| {
+                let hash = hash_wal(
+                    &system_dir,
+                    key.as_ref(),
+                    timeline,
+                    wal_seg_size,
+                    start_lsn,
+                    end_lsn,
+                )?;
+                Ok((start_lsn, end_lsn, hash))
+            })
+            .collect()
+    })
+    .await?
+}
+
+struct Peer {
+    client: tokio_postgres::Client,
+}
+
+impl Peer {
+    async fn query(&self, query: &str) -> Result<Vec<Vec<String>>> {
+        let messages = self.client.simple_query(query).await.map_err(other_error)?;
+        Ok(messages
+            .iter()
+            .filter_map(|message| match message {
+                SimpleQueryMessage::Row(row) => Some(
+                    (0..row.len())
+                        .map(|i| row.get(i).unwrap_or("").to_string())
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /* Hashes of chunks of the range as (start, end, hash), clamped to WAL retained by the peer */
+    async fn hash_wal(
+        &self,
+        start_lsn: XLogRecPtr,
+        end_lsn: XLogRecPtr,
+        chunk_size: u64,
+    ) -> Result<Vec<(XLogRecPtr, XLogRecPtr, String)>> {
+        let rows = self
+            .query(&format!(
+                "SAFEKEEPER_WAL_HASH {} {} {}",
+                format_lsn(start_lsn),
+                format_lsn(end_lsn),
+                chunk_size
+            ))
+            .await?;
+        let mut chunks = Vec::new();
+        for row in rows {
+            if row.len() != 3 {
+                io_error!("Unexpected reply to SAFEKEEPER_WAL_HASH: {:?}", row);
+            }
+            chunks.push((parse_lsn(&row[0])?, parse_lsn(&row[1])?, row[2].clone()));
+        }
+        Ok(chunks)
+    }
+}
+
+//
+// Compare WAL of the tenant with the peer safekeeper listening at the given address.
+// Returns report of the comparison, divergence is not an error.
+//
+pub async fn compare(
+    conf: &WalAcceptorConf,
+    tenants: &TenantRegistry,
+    tenant: &str,
+    peer_addr: &str,
+    password: Option<&str>,
+) -> Result<String> {
+    let id = parse_tenant_id(tenant)?;
+    let system = match tenants.get_system(id) {
+        Some(system) => system,
+        None => {
+            io_error!("Unknown tenant {}", id);
+        }
+    };
+    let wal_seg_size = system.get_wal_seg_size();
+    if wal_seg_size == 0 {
+        io_error!("WAL segment size of system {} is not known yet", id);
+    }
+    let (host, port) = match peer_addr.rsplit_once(':') {
+        Some((host, port)) => (host, port),
+        None => {
+            io_error!("Invalid peer address {}, expected host:port", peer_addr);
+        }
+    };
+    let mut connstr = format!(
+        "host={} port={} dbname=no_db user=safekeeper options='-c system.id={}'",
+        host, port, id
+    );
+    if let Some(password) = password {
+        connstr += &format!(
+            " password='{}'",
+            password.replace('\\', "\\\\").replace('\'', "\\'")
+        );
+    }
+    let (client, connection) = connect(&connstr, NoTls).await.map_err(other_error)?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("peer safekeeper connection error: {}", e);
+        }
+    });
+    let peer = Peer { client: client };
+
+    let mut output = String::new();
+    let snapshot = system.snapshot();
+    let system_dir = tenant_dir(&conf.data_dir, id);
+    let pg_wal_layout = conf.pg_wal_layout;
+    let (wal_start, _, timeline) = {
+        let system_dir = system_dir.clone();
+        run_blocking(move || retained_wal(&system_dir, wal_seg_size, pg_wal_layout)).await??
+    };
+    output += &format!(
+        "local: epoch={} flush_lsn={} commit_lsn={} retained WAL starts at {}\n",
+        snapshot.epoch(),
+        format_lsn(snapshot.flush_lsn()),
+        format_lsn(snapshot.known_commit_lsn()),
+        format_lsn(wal_start)
+    );
+    /*
+     * systemid, timeline, xlogpos, wal_seg_size, epoch, term, node_uuid, safekeeper_uuid,
+     * flush_lsn, commit_lsn
+     */
+    let identify = peer.query("SAFEKEEPER_IDENTIFY").await?;
+    let identify = match identify.first() {
+        Some(identify) if identify.len() >= 10 => identify,
+        _ => {
+            io_error!("Unexpected reply of peer {}", peer_addr);
+        }
+    };
+    let peer_commit_lsn = parse_lsn(&identify[9])?;
+    output += &format!(
+        "peer {}: epoch={} term={} flush_lsn={} commit_lsn={}\n",
+        peer_addr, identify[4], identify[5], identify[8], identify[9]
+    );
+    if identify[3] != wal_seg_size.to_string() {
+        output += &format!(
+            "divergence: WAL segment size is {} on peer, {} locally\n",
+            identify[3], wal_seg_size
+        );
+        return Ok(output);
+    }
+
+    /* Narrow down the first mismatching chunk till a single byte */
+    let commit_lsn = min(snapshot.known_commit_lsn(), peer_commit_lsn);
+    let (mut start_lsn, mut end_lsn) = (wal_start, min(snapshot.flush_lsn(), commit_lsn));
+    let mut chunk_size = max(
+        INITIAL_CHUNK_SIZE,
+        (end_lsn.saturating_sub(start_lsn) + MAX_HASH_CHUNKS - 1) / MAX_HASH_CHUNKS,
+    );
+    let mut compared: Option<(XLogRecPtr, XLogRecPtr)> = None;
+    while start_lsn < end_lsn {
+        let chunks = peer.hash_wal(start_lsn, end_lsn, chunk_size).await?;
+        if compared.is_none() {
+            compared = match (chunks.first(), chunks.last()) {
+                (Some(first), Some(last)) => Some((first.0, last.1)),
+                _ => break,
+            };
+        }
+        let local_chunks = hash_wal_chunks(
+            system_dir.clone(),
+            conf.at_rest_key.clone(),
+            timeline,
+            wal_seg_size,
+            chunks.iter().map(|chunk| (chunk.0, chunk.1)).collect(),
+        )
+        .await?;
+        let mismatch = chunks
+            .iter()
+            .zip(local_chunks.iter())
+            .find(|(peer_chunk, local_chunk)| peer_chunk.2 != local_chunk.2)
+            .map(|(peer_chunk, _)| (peer_chunk.0, peer_chunk.1));
+        match mismatch {
+            None => break,
+            Some((chunk_start, chunk_end)) if chunk_end - chunk_start == 1 => {
+                warn!(
+                    "WAL of system {} diverges from peer {} at {}",
+                    id,
+                    peer_addr,
+                    format_lsn(chunk_start)
+                );
+                output += &format!(
+                    "divergence: first mismatching LSN {}\n",
+                    format_lsn(chunk_start)
+                );
+                return Ok(output);
+            }
+            Some((chunk_start, chunk_end)) => {
+                start_lsn = chunk_start;
+                end_lsn = chunk_end;
+                chunk_size = max(1, (chunk_end - chunk_start + SPLIT_FACTOR - 1) / SPLIT_FACTOR);
+            }
+        }
+    }
+    match compared {
+        Some((start_lsn, end_lsn)) => {
+            output += &format!(
+                "WAL {}-{} is identical\n",
+                format_lsn(start_lsn),
+                format_lsn(end_lsn)
+            )
+        }
+        None => output += "no committed WAL retained by both safekeepers\n",
+    }
+    Ok(output)
+}
@@ -1,5 +1,6 @@
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt;
 use std::io;
 use std::str;
 
@@ -7,12 +8,17 @@ pub type Oid = u32;
 pub type SystemId = u64;
 pub type Result<T> = std::result::Result<T, io::Error>;
 
+pub const NEGOTIATE_SSL_CODE: u32 = (1234 << 16) | 5679;
+pub const NEGOTIATE_GSS_CODE: u32 = (1234 << 16) | 5680;
+
 #[derive(Debug)]
 pub enum FeMessage {
     StartupMessage(FeStartupMessage),
     Query(FeQueryMessage),
     Terminate,
     CopyData(FeCopyData),
+    CopyDone,
+    Password(FePasswordMessage), /* PasswordMessage, SASLInitialResponse or SASLResponse */
 }
 
 #[derive(Debug)]
@@ -29,8 +35,50 @@ pub enum BeMessage<'a> {
     RowDescription(&'a [RowDescriptor]),
     DataRow(&'a [Option<&'a [u8]>]),
     CommandComplete(&'a [u8]),
+    AuthenticationCleartextPassword,
+    AuthenticationMD5Password(&'a [u8; 4]), /* salt */
+    AuthenticationSASL(&'a str),           /* mechanism */
+    AuthenticationSASLContinue(&'a [u8]),
+    AuthenticationSASLFinal(&'a [u8]),
     Negotiate,
     Copy,
+    CopyDone,
+    ErrorResponse(&'a [u8; 5], &'a str), /* SQLSTATE, message */
+}
+
+//
+// Error of libpq session with the SQLSTATE to report to the client in ErrorResponse.
+// It travels wrapped in io::Error, see sql_error().
+//
+#[derive(Debug)]
+pub struct SqlError {
+    pub code: &'static [u8; 5],
+    pub message: String,
+}
+
+impl fmt::Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SqlError {}
+
+pub fn sql_error(code: &'static [u8; 5], message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        SqlError {
+            code: code,
+            message: message,
+        },
+    )
+}
+
+// SQLSTATE the client was given in the error, if any
+pub fn sqlstate_of(e: &io::Error) -> Option<&'static [u8; 5]> {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<SqlError>())
+        .map(|sql_error| sql_error.code)
 }
 
 #[derive(Debug)]
@@ -38,6 +86,7 @@ pub struct FeStartupMessage {
     pub version: u32,
     pub kind: StartupRequestCode,
     pub system_id: SystemId,
+    pub user: Option<String>,
 }
 
 #[derive(Debug)]
@@ -52,8 +101,6 @@ impl FeStartupMessage {
     pub fn parse(buf: &mut BytesMut) -> Result<Option<FeMessage>> {
         const MAX_STARTUP_PACKET_LENGTH: usize = 10000;
         const CANCEL_REQUEST_CODE: u32 = (1234 << 16) | 5678;
-        const NEGOTIATE_SSL_CODE: u32 = (1234 << 16) | 5679;
-        const NEGOTIATE_GSS_CODE: u32 = (1234 << 16) | 5680;
 
         if buf.len() < 4 {
             return Ok(None);
@@ -80,21 +127,32 @@ impl FeStartupMessage {
         };
 
         let params_bytes = &buf[8..len];
-        let params_str = str::from_utf8(&params_bytes).unwrap();
-        let params = params_str.split('\0');
-        let mut options = false;
+        let params_str = match str::from_utf8(&params_bytes) {
+            Ok(params_str) => params_str,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "startup parameters are not valid UTF-8",
+                ));
+            }
+        };
+        let mut params = params_str.split('\0');
         let mut system_id: u64 = 0;
-        for p in params {
-            if p == "options" {
-                options = true;
-            } else if options {
-                for opt in p.split(' ') {
-                    if opt.starts_with("system.id=") {
-                        system_id = opt[10..].parse::<u64>().unwrap();
-                        break;
+        let mut user = None;
+        /* Parameters are pairs of NUL terminated name and value */
+        while let Some(name) = params.next() {
+            let value = params.next().unwrap_or("");
+            match name {
+                "options" => {
+                    for opt in value.split(' ') {
+                        if opt.starts_with("system.id=") {
+                            system_id = crate::parse_tenant_id(&opt[10..])?;
+                            break;
+                        }
                     }
                 }
-                break;
+                "user" => user = Some(value.to_string()),
+                _ => {}
             }
         }
 
@@ -103,6 +161,7 @@ impl FeStartupMessage {
             version,
             kind,
             system_id,
+            user,
         })))
     }
 }
@@ -117,6 +176,11 @@ pub struct FeCopyData {
     pub body: Bytes,
 }
 
+#[derive(Debug)]
+pub struct FePasswordMessage {
+    pub body: Bytes,
+}
+
 impl<'a> BeMessage<'a> {
     pub fn write(buf: &mut BytesMut, message: &BeMessage) {
         match message {
@@ -132,6 +196,42 @@ impl<'a> BeMessage<'a> {
                 buf.put_u8(b'I');
             }
 
+            BeMessage::AuthenticationCleartextPassword => {
+                buf.put_u8(b'R');
+                buf.put_i32(4 + 4);
+                buf.put_i32(3);
+            }
+
+            BeMessage::AuthenticationMD5Password(salt) => {
+                buf.put_u8(b'R');
+                buf.put_i32(4 + 4 + 4);
+                buf.put_i32(5);
+                buf.put_slice(&salt[..]);
+            }
+
+            BeMessage::AuthenticationSASL(mechanism) => {
+                buf.put_u8(b'R');
+                buf.put_i32(4 + 4 + mechanism.len() as i32 + 2);
+                buf.put_i32(10);
+                buf.put_slice(mechanism.as_bytes());
+                buf.put_u8(0);
+                buf.put_u8(0); /* end of mechanism list */
+            }
+
+            BeMessage::AuthenticationSASLContinue(data) => {
+                buf.put_u8(b'R');
+                buf.put_i32(4 + 4 + data.len() as i32);
+                buf.put_i32(11);
+                buf.put_slice(data);
+            }
+
+            BeMessage::AuthenticationSASLFinal(data) => {
+                buf.put_u8(b'R');
+                buf.put_i32(4 + 4 + data.len() as i32);
+                buf.put_i32(12);
+                buf.put_slice(data);
+            }
+
             BeMessage::Negotiate => {
                 buf.put_u8(b'N');
             }
@@ -144,15 +244,20 @@ impl<'a> BeMessage<'a> {
                 buf.put_u8(b'\0');
             }
 
+            BeMessage::CopyDone => {
+                buf.put_u8(b'c');
+                buf.put_i32(4);
+            }
+
             BeMessage::RowDescription(rows) => {
                 buf.put_u8(b'T');
                 let total_len: u32 = rows
                     .iter()
                     .fold(0, |acc, row| acc + row.name.len() as u32 + 3 * (4 + 2));
                 buf.put_u32(4 + 2 + total_len);
+                buf.put_i16(rows.len() as i16);
                 for row in rows.iter() {
-                    buf.put_i16(row.name.len() as i16);
-                    buf.put_slice(row.name);
+                    buf.put_slice(row.name); /* NUL terminated */
                     buf.put_i32(0); /* table oid */
                     buf.put_i16(0); /* attnum */
                     buf.put_u32(row.typoid);
@@ -181,8 +286,30 @@ impl<'a> BeMessage<'a> {
 
             BeMessage::CommandComplete(cmd) => {
                 buf.put_u8(b'C');
-                buf.put_i32(4 + cmd.len() as i32);
+                buf.put_i32(4 + cmd.len() as i32 + 1);
                 buf.put_slice(cmd);
+                buf.put_u8(0);
+            }
+
+            /* Connection is closed after the error, so severity is always FATAL */
+            BeMessage::ErrorResponse(code, message) => {
+                const SEVERITY: &[u8] = b"FATAL";
+                buf.put_u8(b'E');
+                let fields_len = 2 * (1 + SEVERITY.len() + 1) + (1 + code.len() + 1);
+                buf.put_i32(4 + (fields_len + 1 + message.len() + 1) as i32 + 1);
+                buf.put_u8(b'S');
+                buf.put_slice(SEVERITY);
+                buf.put_u8(0);
+                buf.put_u8(b'V'); /* non-localized severity */
+                buf.put_slice(SEVERITY);
+                buf.put_u8(0);
+                buf.put_u8(b'C');
+                buf.put_slice(&code[..]);
+                buf.put_u8(0);
+                buf.put_u8(b'M');
+                buf.put_slice(message.as_bytes());
+                buf.put_u8(0);
+                buf.put_u8(0); /* terminator of the field list */
             }
         }
     }
@@ -224,6 +351,10 @@ impl FeMessage {
                 body: body.freeze(),
             }))),
             b'X' => Ok(Some(FeMessage::Terminate)),
+            b'c' => Ok(Some(FeMessage::CopyDone)),
+            b'p' => Ok(Some(FeMessage::Password(FePasswordMessage {
+                body: body.freeze(),
+            }))),
             tag => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 format!("unknown message tag: {},'{:?}'", tag, buf),
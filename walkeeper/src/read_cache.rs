@@ -0,0 +1,163 @@
+//
+//   Read cache of WAL shared by senders of all tenants.
+//
+//   WAL is cached in blocks of CACHE_BLOCK_SIZE keyed by tenant, timeline, segment and
+//   offset in the segment, with LRU eviction under the global size limit. Only committed WAL
+//   is cached: it is never overwritten, so cached blocks don't have to be invalidated
+//   on writes. Blocks which are not committed completely are read from the file.
+//
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+use crate::pq_protocol::{Result, SystemId};
+use crate::xlog_utils::*;
+
+pub const CACHE_BLOCK_SIZE: usize = XLOG_BLCKSZ * 8;
+
+type BlockKey = (SystemId, TimeLineID, XLogSegNo, usize); /* tenant, timeline, segment, offset */
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReadCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct ReadCache {
+    blocks: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>, /* data and last access tick */
+    lru: BTreeMap<u64, BlockKey>,                    /* access tick -> block */
+    tick: u64,
+    stats: ReadCacheStats,
+}
+
+lazy_static! {
+    static ref READ_CACHE: Mutex<ReadCache> = Mutex::new(ReadCache::default());
+}
+
+impl ReadCache {
+    fn get(&mut self, key: &BlockKey) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (data, last_used) = self.blocks.get_mut(key)?;
+        self.lru.remove(last_used);
+        self.lru.insert(tick, *key);
+        *last_used = tick;
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, data: Arc<Vec<u8>>) {
+        if self.blocks.contains_key(&key) {
+            return; /* loaded by another sender meanwhile */
+        }
+        while self.stats.size + data.len() > self.stats.capacity {
+            let (_, victim) = match self.lru.iter().next() {
+                Some((tick, victim)) => (*tick, *victim),
+                None => return, /* block is larger than the whole cache */
+            };
+            self.remove(&victim);
+            self.stats.evictions += 1;
+        }
+        self.tick += 1;
+        self.stats.size += data.len();
+        self.lru.insert(self.tick, key);
+        self.blocks.insert(key, (data, self.tick));
+    }
+
+    fn remove(&mut self, key: &BlockKey) {
+        if let Some((data, last_used)) = self.blocks.remove(key) {
+            self.lru.remove(&last_used);
+            self.stats.size -= data.len();
+        }
+    }
+}
+
+// Set size limit of the cache in bytes, zero disables caching
+pub fn set_capacity(capacity: usize) {
+    let mut cache = READ_CACHE.lock().unwrap();
+    cache.stats.capacity = capacity;
+    if capacity == 0 {
+        *cache = ReadCache::default();
+    }
+}
+
+pub fn stats() -> ReadCacheStats {
+    READ_CACHE.lock().unwrap().stats
+}
+
+// Drop cached blocks of the tenant
+pub fn invalidate_tenant(system_id: SystemId) {
+    let mut cache = READ_CACHE.lock().unwrap();
+    let keys: Vec<BlockKey> = cache
+        .blocks
+        .keys()
+        .filter(|key| key.0 == system_id)
+        .copied()
+        .collect();
+    for key in keys {
+        cache.remove(&key);
+    }
+}
+
+//
+// Fill buf with WAL starting at pos of the tenant through the cache, loading missing
+// blocks from file (the segment of the timeline containing pos). Returns false without reading anything
+// if the cache is disabled or the range is not committed completely, then the caller
+// should read the file itself.
+//
+pub fn read(
+    system_id: SystemId,
+    timeline: TimeLineID,
+    file: &File,
+    pos: XLogRecPtr,
+    commit_lsn: XLogRecPtr,
+    wal_seg_size: usize,
+    buf: &mut [u8],
+) -> Result<bool> {
+    if READ_CACHE.lock().unwrap().stats.capacity == 0 {
+        return Ok(false);
+    }
+    let segno = XLByteToSeg(pos, wal_seg_size);
+    let seg_start = XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size);
+    let offset = XLogSegmentOffset(pos, wal_seg_size) as usize;
+    let end = offset + buf.len();
+    let last_block_end = ((end + CACHE_BLOCK_SIZE - 1) / CACHE_BLOCK_SIZE) * CACHE_BLOCK_SIZE;
+    if seg_start + last_block_end as u64 > commit_lsn || last_block_end > wal_seg_size {
+        return Ok(false);
+    }
+    let mut block_offs = offset - offset % CACHE_BLOCK_SIZE;
+    while block_offs < end {
+        let key = (system_id, timeline, segno, block_offs);
+        let cached = {
+            let mut cache = READ_CACHE.lock().unwrap();
+            let cached = cache.get(&key);
+            if cached.is_some() {
+                cache.stats.hits += 1;
+            } else {
+                cache.stats.misses += 1;
+            }
+            cached
+        };
+        let block = match cached {
+            Some(block) => block,
+            None => {
+                /* Read outside of the lock, so that other senders are not held up */
+                let mut data = vec![0u8; CACHE_BLOCK_SIZE];
+                file.read_exact_at(&mut data, block_offs as u64)?;
+                let block = Arc::new(data);
+                READ_CACHE.lock().unwrap().insert(key, block.clone());
+                block
+            }
+        };
+        let from = offset.max(block_offs);
+        let to = end.min(block_offs + CACHE_BLOCK_SIZE);
+        buf[from - offset..to - offset].copy_from_slice(&block[from - block_offs..to - block_offs]);
+        block_offs += CACHE_BLOCK_SIZE;
+    }
+    Ok(true)
+}
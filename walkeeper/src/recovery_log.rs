@@ -0,0 +1,142 @@
+//
+//   Recovery log of destructive operations.
+//
+//   Before the safekeeper destroys or sets aside data (overwrites differing WAL of a
+//   previous term, removes segments, deletes a tenant, orphans leftover partial
+//   segments, rolls back an interrupted WAL import) it appends an entry to recovery.log
+//   in the data directory: what is done, why, the affected LSN range and files, and who
+//   initiated it. The entry is synced before the operation proceeds, and the operation
+//   is not done if it can't be logged.
+//   Entries are JSON lines, reviewed with "recovery-log" admin command.
+//
+use lazy_static::lazy_static;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::clock;
+use crate::pq_protocol::{Result, SystemId};
+use crate::xlog_utils::{format_lsn, XLogRecPtr};
+
+pub const RECOVERY_LOG_FILE_NAME: &str = "recovery.log";
+
+lazy_static! {
+    /* Serializes appends, so that concurrent entries are not interleaved */
+    static ref APPEND_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    WalTruncation,
+    SegmentGc,
+    TenantDelete,
+    OrphanPartialSegment,
+    ImportRollback,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: String, /* RFC 3339 */
+    pub action: Action,
+    pub tenant: SystemId,
+    pub start_lsn: Option<String>,
+    pub end_lsn: Option<String>,
+    pub files: Vec<String>,
+    pub reason: String,
+    pub initiator: String,
+}
+
+impl Entry {
+    pub fn new(action: Action, tenant: SystemId, reason: String, initiator: String) -> Entry {
+        Entry {
+            time: chrono::DateTime::<chrono::Utc>::from(clock::system_time()).to_rfc3339(),
+            action: action,
+            tenant: tenant,
+            start_lsn: None,
+            end_lsn: None,
+            files: Vec::new(),
+            reason: reason,
+            initiator: initiator,
+        }
+    }
+
+    // Affected WAL range
+    pub fn lsns(mut self, start_lsn: XLogRecPtr, end_lsn: XLogRecPtr) -> Entry {
+        self.start_lsn = Some(format_lsn(start_lsn));
+        self.end_lsn = Some(format_lsn(end_lsn));
+        self
+    }
+
+    pub fn files(mut self, files: Vec<String>) -> Entry {
+        self.files = files;
+        self
+    }
+
+    pub fn describe(&self) -> String {
+        let mut line = format!(
+            "{} tenant {}: {:?} by {}: {}",
+            self.time, self.tenant, self.action, self.initiator, self.reason
+        );
+        if let (Some(start_lsn), Some(end_lsn)) = (&self.start_lsn, &self.end_lsn) {
+            line += &format!(", WAL {}-{}", start_lsn, end_lsn);
+        }
+        if !self.files.is_empty() {
+            line += &format!(", files {}", self.files.join(" "));
+        }
+        line
+    }
+}
+
+//
+// Append entry to the recovery log and sync it. Must be called before the operation.
+//
+pub fn record(data_dir: &Path, entry: &Entry) -> Result<()> {
+    let mut line = serde_json::to_string(entry)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    line.push('\n');
+    warn!("Recovery log: {}", entry.describe());
+    let _guard = APPEND_LOCK.lock().unwrap();
+    let path = data_dir.join(RECOVERY_LOG_FILE_NAME);
+    let res = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| {
+            file.write_all(line.as_bytes())?;
+            file.sync_data()
+        });
+    if let Err(e) = res {
+        io_error!("Failed to append to recovery log {:?}: {}", path, e);
+    }
+    Ok(())
+}
+
+//
+// Entries of the recovery log, of all tenants or of the given one, oldest first.
+// Lines which can't be parsed (e.g. torn by crash) are skipped.
+//
+pub fn read(data_dir: &Path, tenant: Option<SystemId>) -> Result<Vec<Entry>> {
+    let path = data_dir.join(RECOVERY_LOG_FILE_NAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    for (lineno, line) in content.lines().enumerate() {
+        match serde_json::from_str::<Entry>(line) {
+            Ok(entry) => {
+                if tenant.map_or(true, |id| id == entry.tenant) {
+                    entries.push(entry);
+                }
+            }
+            Err(e) => warn!("Skip line {} of {:?}: {}", lineno + 1, path, e),
+        }
+    }
+    Ok(entries)
+}
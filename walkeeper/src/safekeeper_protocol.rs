@@ -0,0 +1,838 @@
+//
+//   Messages of the proposer-safekeeper protocol and their encoding.
+//
+//   Session of a proposer: SK_GREETING_MAGIC (big endian) and PeerGreeting, optional
+//   AcceptorSetClaim, ServerInfo answered with SafeKeeperInfo, RequestVote answered with
+//   NodeId of the voted candidate, then appends (SafeKeeperRequest followed by WAL and, with
+//   PEER_CAP_WAL_CHECKSUM, its checksum) answered with SafeKeeperResponse. Messages carry
+//   no type tag: the kind of the next message is known from the state of the session.
+//
+//   Numbers are little endian, except for the greeting magic and the term of NodeId (big
+//   endian, so that node ids can be compared with memcmp). By default messages are sent
+//   as is, each one of the fixed size of its kind, and size of an append is learnt from
+//   its LSNs. A proposer announcing PEER_CAP_FRAMED prefixes every message it sends and
+//   receives after the greeting with its length (u32, little endian, not including
+//   itself). Fields unknown to the receiver at the end of a frame are skipped, so that
+//   messages can be extended without breaking older peers.
+//
+//   Safekeeper supports protocol versions SK_MIN_PROTOCOL_VERSION..=SK_PROTOCOL_VERSION.
+//   A proposer announcing PEER_CAP_VERSION_NEGOTIATION puts the highest version it
+//   supports into the greeting and is answered with ProtocolVersions: the supported range
+//   and the highest version both sides support, which is then used in ServerInfo (0 if
+//   there is none, and the connection is closed). The proposer disconnects if the
+//   selected version is below the lowest one it supports. Without the capability, the
+//   version of the greeting must be in the supported range. This allows upgrading a
+//   fleet of safekeepers and proposers one node at a time.
+//
+//   Version 2 extends SafeKeeperResponse with status, hs_replicas and received_lsn.
+//   Proposers speaking version 1, and legacy ones sending no greeting, get responses
+//   of version 1 (epoch, flush_lsn and hs_feedback), which only acknowledge appends.
+//
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::*;
+use std::cmp::min;
+use std::io;
+
+use crate::pq_protocol::{Result, SystemId};
+use crate::xlog_utils::{TimeLineID, TimestampTz, XLogRecPtr, XLOG_BLCKSZ};
+
+pub type FullTransactionId = u64;
+
+pub const SK_PROTOCOL_VERSION: u32 = 2; /* the highest supported protocol version */
+pub const SK_MIN_PROTOCOL_VERSION: u32 = 1; /* the lowest one still supported */
+pub const SK_RESPONSE_STATUS_VERSION: u32 = 2; /* the first version with status in SafeKeeperResponse */
+pub const SK_GREETING_MAGIC: u32 = 0x5AFEC0DEu32; /* first word of proposer greeting, can't be a valid startup packet length */
+pub const END_OF_STREAM: XLogRecPtr = 0;
+pub const MAX_SEND_SIZE: usize = XLOG_BLCKSZ * 16;
+pub const FRAME_HDR_SIZE: usize = 4;
+pub const MAX_FRAME_SIZE: usize = 2 * MAX_SEND_SIZE; /* leaves room for fields added to appends later */
+
+/*
+ * Status of SafeKeeperResponse. Besides OK and HEARTBEAT, it tells proposer
+ * why its append was not accepted and whether to retry:
+ * PAUSED and SHUTTING_DOWN - retry later (or with another safekeeper),
+ * STALE_TERM - another proposer was elected, stop and re-elect,
+ * OUT_OF_SPACE and INTERNAL - hard failure of this safekeeper,
+ * CORRUPT_WAL - record CRC check failed, WAL of the message is not stored.
+ * STALE_TERM..CORRUPT_WAL are sent only to proposers announcing PEER_CAP_STATUS_CODES,
+ * others just see the connection closed.
+ * FLOW_PAUSE and FLOW_RESUME are unsolicited, like HEARTBEAT, and are sent only to
+ * proposers announcing PEER_CAP_FLOW_CONTROL.
+ * Connection is closed after any status other than OK, PAUSED, HEARTBEAT and FLOW_*.
+ * Responses before SK_RESPONSE_STATUS_VERSION have no status: such proposers get only
+ * OK, and the connection is closed instead of PAUSED.
+ */
+pub const SK_STATUS_OK: u32 = 0;
+pub const SK_STATUS_PAUSED: u32 = 1; /* WAL ingest is paused by administrator, proposer should retry later */
+pub const SK_STATUS_HEARTBEAT: u32 = 2; /* unsolicited report of flush progress sent while proposer is idle */
+pub const SK_STATUS_STALE_TERM: u32 = 3; /* message from proposer whose term is not the voted one */
+pub const SK_STATUS_OUT_OF_SPACE: u32 = 4; /* no space left to store WAL */
+pub const SK_STATUS_SHUTTING_DOWN: u32 = 5; /* safekeeper is draining before shutdown */
+pub const SK_STATUS_INTERNAL: u32 = 6; /* any other failure to store WAL or control data */
+pub const SK_STATUS_CORRUPT_WAL: u32 = 7; /* received WAL record doesn't match its CRC */
+pub const SK_STATUS_FLOW_PAUSE: u32 = 8; /* received appends pile up in memory, stop sending */
+pub const SK_STATUS_FLOW_RESUME: u32 = 9; /* backlog of received appends is drained, go on */
+
+/*
+ * Optional features understood by the peer, announced in the high bits of the greeting
+ * role. Unknown capabilities are ignored.
+ */
+pub const PEER_ROLE_MASK: u32 = 0xFFFF;
+pub const PEER_CAP_FLOW_CONTROL: u32 = 0x10000; /* proposer understands FLOW_PAUSE and FLOW_RESUME */
+pub const PEER_CAP_ACK_EACH_APPEND: u32 = 0x20000; /* proposer wants every append acked right away */
+pub const PEER_CAP_WAL_CHECKSUM: u32 = 0x40000; /* proposer follows WAL of appends with its checksum */
+pub const PEER_CAP_FRAMED: u32 = 0x80000; /* messages after the greeting are length-prefixed */
+pub const PEER_CAP_VERSION_NEGOTIATION: u32 = 0x100000; /* greeting is answered with ProtocolVersions */
+pub const PEER_CAP_STATUS_CODES: u32 = 0x200000; /* proposer understands STALE_TERM..CORRUPT_WAL */
+
+/*
+ * Unique node identifier used by Paxos
+ */
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, PartialEq, Eq)]
+pub struct NodeId {
+    pub term: u64,
+    pub uuid: u128,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServerInfo {
+    pub protocol_version: u32, /* proxy-safekeeper protocol version */
+    pub pg_version: u32,       /* Postgres server version */
+    pub node_id: NodeId,
+    pub system_id: SystemId, /* Postgres system identifier */
+    pub wal_end: XLogRecPtr,
+    pub timeline: TimeLineID,
+    pub wal_seg_size: u32,
+}
+
+/*
+ * Role of the peer announced in the greeting frame
+ */
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerRole {
+    Proposer = 1,
+    ProposerWithAcceptorSet = 2, /* proposer which sends AcceptorSetClaim after the greeting */
+}
+
+/*
+ * Greeting frame sent by peer right after establishing connection.
+ * It is preceded by SK_GREETING_MAGIC (big endian) which distinguishes it from libpq startup packet.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct PeerGreeting {
+    pub protocol_version: u32, /* proxy-safekeeper protocol version */
+    pub role: u32,             /* PeerRole and PEER_CAP_* */
+}
+
+/*
+ * Reply to the greeting of proposer announcing PEER_CAP_VERSION_NEGOTIATION
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersions {
+    pub min_version: u32, /* range of versions supported by safekeeper */
+    pub max_version: u32,
+    pub selected_version: u32, /* the highest common version, 0 if there is none */
+}
+
+/*
+ * Acceptor set the proposer is configured with, as seen from this safekeeper.
+ * Checked against the configured one, so that proposer pointing at the wrong
+ * set of safekeepers doesn't form a bogus quorum.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct AcceptorSetClaim {
+    pub acceptor_index: u32, /* position of this safekeeper in the set */
+    pub n_acceptors: u32,
+    pub quorum: u32,
+}
+
+/*
+ * Vote request sent from proxy to safekeepers
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct RequestVote {
+    pub node_id: NodeId,
+    pub vcl: XLogRecPtr, /* volume commit LSN */
+    pub epoch: u64,      /* new epoch when safekeeper reaches vcl */
+}
+
+/*
+ * Information of about storage node
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SafeKeeperInfo {
+    pub magic: u32,              /* magic for verifying content the control file */
+    pub format_version: u32,     /* safekeeper format version */
+    pub epoch: u64,              /* safekeeper's epoch */
+    pub server: ServerInfo,      /* information about server */
+    pub commit_lsn: XLogRecPtr,  /* part of WAL acknowledged by quorum */
+    pub flush_lsn: XLogRecPtr,   /* locally flushed part of WAL */
+    pub restart_lsn: XLogRecPtr, /* minimal LSN which may be needed for recovery of some safekeeper: min(commit_lsn) for all safekeepers */
+}
+
+/*
+ * Hot standby feedback received from replica
+ */
+#[derive(Debug, Copy, Clone)]
+pub struct HotStandbyFeedback {
+    pub ts: TimestampTz,
+    pub xmin: FullTransactionId,
+    pub catalog_xmin: FullTransactionId,
+}
+
+/*
+ * Request with WAL message sent from proxy to safekeeper.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SafeKeeperRequest {
+    pub sender_id: NodeId, /* Sender's node identifier (looks like we do not need it for TCP streaming connection) */
+    pub begin_lsn: XLogRecPtr, /* start position of message in WAL */
+    pub end_lsn: XLogRecPtr, /* end position of message in WAL */
+    pub restart_lsn: XLogRecPtr, /* restart LSN position  (minimal LSN which may be needed by proxy to perform recovery) */
+    pub commit_lsn: XLogRecPtr,  /* LSN committed by quorum of safekeepers */
+}
+
+/*
+ * Report safekeeper state to proxy. Status, hs_replicas and received_lsn are sent
+ * since SK_RESPONSE_STATUS_VERSION, older proposers get only acknowledgements.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SafeKeeperResponse {
+    pub status: u32,      /* SK_STATUS_* */
+    pub hs_replicas: u32, /* number of replicas contributing to hs_feedback, 0 if there are no replicas */
+    pub epoch: u64,
+    pub flush_lsn: XLogRecPtr, /* durably flushed part of WAL, should be used for commit LSN calculation */
+    pub received_lsn: XLogRecPtr, /* end of received WAL (may be not yet synced), used for flow control */
+    pub hs_feedback: HotStandbyFeedback,
+}
+
+/*
+ * Append of WAL: the request, its WAL and the end-to-end checksum if the proposer
+ * sends it (see wal_checksum)
+ */
+#[derive(Debug, Clone)]
+pub struct AppendRequest {
+    pub header: SafeKeeperRequest,
+    pub wal: Bytes,
+    pub checksum: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProposerMessageKind {
+    Greeting,
+    AcceptorSetClaim,
+    ServerInfo,
+    RequestVote,
+    Append,
+}
+
+/*
+ * Message sent from proposer to safekeeper
+ */
+#[derive(Debug, Clone)]
+pub enum ProposerMessage {
+    Greeting(PeerGreeting),
+    AcceptorSetClaim(AcceptorSetClaim),
+    ServerInfo(ServerInfo),
+    RequestVote(RequestVote),
+    Append(AppendRequest),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptorMessageKind {
+    Versions,
+    Info,
+    Vote,
+    Response,
+}
+
+/*
+ * Message sent from safekeeper to proposer
+ */
+#[derive(Debug, Clone)]
+pub enum AcceptorMessage {
+    Versions(ProtocolVersions), /* reply to greeting negotiating the version */
+    Info(SafeKeeperInfo),       /* reply to ServerInfo */
+    Vote(NodeId),               /* reply to RequestVote: the voted candidate */
+    Response(SafeKeeperResponse), /* reply to append, or unsolicited status */
+}
+
+impl NodeId {
+    pub const SIZE: usize = 16 + 8;
+
+    pub fn pack(&self, buf: &mut BytesMut) {
+        buf.put_u128_le(self.uuid);
+        buf.put_u64(self.term); // use big endian to provide compatibility with memcmp
+    }
+
+    pub fn unpack(buf: &mut BytesMut) -> NodeId {
+        NodeId {
+            uuid: buf.get_u128_le(),
+            term: buf.get_u64(), // use big endian to provide compatibility with memcmp
+        }
+    }
+}
+
+impl ServerInfo {
+    pub const SIZE: usize = 4 + 4 + NodeId::SIZE + 8 + 8 + 4 + 4;
+
+    pub fn pack(&self, buf: &mut BytesMut) {
+        buf.put_u32_le(self.protocol_version);
+        buf.put_u32_le(self.pg_version);
+        self.node_id.pack(buf);
+        buf.put_u64_le(self.system_id);
+        buf.put_u64_le(self.wal_end);
+        buf.put_u32_le(self.timeline);
+        buf.put_u32_le(self.wal_seg_size);
+    }
+
+    pub fn unpack(buf: &mut BytesMut) -> ServerInfo {
+        ServerInfo {
+            protocol_version: buf.get_u32_le(),
+            pg_version: buf.get_u32_le(),
+            node_id: NodeId::unpack(buf),
+            system_id: buf.get_u64_le(),
+            wal_end: buf.get_u64_le(),
+            timeline: buf.get_u32_le(),
+            wal_seg_size: buf.get_u32_le(),
+        }
+    }
+}
+
+impl PeerRole {
+    pub fn from_u32(role: u32) -> Option<PeerRole> {
+        match role {
+            1 => Some(PeerRole::Proposer),
+            2 => Some(PeerRole::ProposerWithAcceptorSet),
+            _ => None,
+        }
+    }
+}
+
+impl PeerGreeting {
+    pub const SIZE: usize = 4 + 4;
+
+    pub fn pack(&self, buf: &mut BytesMut) {
+        buf.put_u32_le(self.protocol_version);
+        buf.put_u32_le(self.role);
+    }
+
+    pub fn unpack(buf: &mut BytesMut) -> PeerGreeting {
+        PeerGreeting {
+            protocol_version: buf.get_u32_le(),
+            role: buf.get_u32_le(),
+        }
+    }
+}
+
+impl ProtocolVersions {
+    pub const SIZE: usize = 4 * 3;
+
+    // Settle on the highest version supported by both sides
+    pub fn negotiate(proposer_version: u32) -> ProtocolVersions {
+        ProtocolVersions {
+            min_version: SK_MIN_PROTOCOL_VERSION,
+            max_version: SK_PROTOCOL_VERSION,
+            selected_version: if proposer_version < SK_MIN_PROTOCOL_VERSION {
+                0
+            } else {
+                min(proposer_version, SK_PROTOCOL_VERSION)
+            },
+        }
+    }
+
+    pub fn pack(&self, buf: &mut BytesMut) {
+        buf.put_u32_le(self.min_version);
+        buf.put_u32_le(self.max_version);
+        buf.put_u32_le(self.selected_version);
+    }
+
+    pub fn unpack(buf: &mut BytesMut) -> ProtocolVersions {
+        ProtocolVersions {
+            min_version: buf.get_u32_le(),
+            max_version: buf.get_u32_le(),
+            selected_version: buf.get_u32_le(),
+        }
+    }
+}
+
+impl AcceptorSetClaim {
+    pub const SIZE: usize = 4 * 3;
+
+    pub fn pack(&self, buf: &mut BytesMut) {
+        buf.put_u32_le(self.acceptor_index);
+        buf.put_u32_le(self.n_acceptors);
+        buf.put_u32_le(self.quorum);
+    }
+
+    pub fn unpack(buf: &mut BytesMut) -> AcceptorSetClaim {
+        AcceptorSetClaim {
+            acceptor_index: buf.get_u32_le(),
+            n_acceptors: buf.get_u32_le(),
+            quorum: buf.get_u32_le(),
+        }
+    }
+}
+
+impl RequestVote {
+    pub const SIZE: usize = NodeId::SIZE + 8 + 8;
+
+    pub fn pack(&self, buf: &mut BytesMut) {
+        self.node_id.pack(buf);
+        buf.put_u64_le(self.vcl);
+        buf.put_u64_le(self.epoch);
+    }
+
+    pub fn unpack(buf: &mut BytesMut) -> RequestVote {
+        RequestVote {
+            node_id: NodeId::unpack(buf),
+            vcl: buf.get_u64_le(),
+            epoch: buf.get_u64_le(),
+        }
+    }
+}
+
+impl SafeKeeperInfo {
+    pub const SIZE: usize = 4 + 4 + 8 + ServerInfo::SIZE + 8 * 3;
+
+    pub fn pack(&self, buf: &mut BytesMut) {
+        buf.put_u32_le(self.magic);
+        buf.put_u32_le(self.format_version);
+        buf.put_u64_le(self.epoch);
+        self.server.pack(buf);
+        buf.put_u64_le(self.commit_lsn);
+        buf.put_u64_le(self.flush_lsn);
+        buf.put_u64_le(self.restart_lsn);
+    }
+
+    pub fn unpack(buf: &mut BytesMut) -> SafeKeeperInfo {
+        SafeKeeperInfo {
+            magic: buf.get_u32_le(),
+            format_version: buf.get_u32_le(),
+            epoch: buf.get_u64_le(),
+            server: ServerInfo::unpack(buf),
+            commit_lsn: buf.get_u64_le(),
+            flush_lsn: buf.get_u64_le(),
+            restart_lsn: buf.get_u64_le(),
+        }
+    }
+}
+
+impl HotStandbyFeedback {
+    pub const SIZE: usize = 8 * 3;
+
+    pub fn pack(&self, buf: &mut BytesMut) {
+        buf.put_u64_le(self.ts);
+        buf.put_u64_le(self.xmin);
+        buf.put_u64_le(self.catalog_xmin);
+    }
+
+    pub fn unpack(buf: &mut BytesMut) -> HotStandbyFeedback {
+        HotStandbyFeedback {
+            ts: buf.get_u64_le(),
+            xmin: buf.get_u64_le(),
+            catalog_xmin: buf.get_u64_le(),
+        }
+    }
+}
+
+impl SafeKeeperRequest {
+    pub const SIZE: usize = NodeId::SIZE + 8 * 4;
+
+    pub fn pack(&self, buf: &mut BytesMut) {
+        self.sender_id.pack(buf);
+        buf.put_u64_le(self.begin_lsn);
+        buf.put_u64_le(self.end_lsn);
+        buf.put_u64_le(self.restart_lsn);
+        buf.put_u64_le(self.commit_lsn);
+    }
+
+    pub fn unpack(buf: &mut BytesMut) -> SafeKeeperRequest {
+        SafeKeeperRequest {
+            sender_id: NodeId::unpack(buf),
+            begin_lsn: buf.get_u64_le(),
+            end_lsn: buf.get_u64_le(),
+            restart_lsn: buf.get_u64_le(),
+            commit_lsn: buf.get_u64_le(),
+        }
+    }
+
+    // Size of WAL following the request, zero for heartbeat and end of stream
+    pub fn wal_size(&self) -> Result<usize> {
+        if self.begin_lsn == END_OF_STREAM {
+            return Ok(0);
+        }
+        if self.end_lsn < self.begin_lsn || self.end_lsn - self.begin_lsn > MAX_SEND_SIZE as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid append range {:X}-{:X}",
+                    self.begin_lsn, self.end_lsn
+                ),
+            ));
+        }
+        Ok((self.end_lsn - self.begin_lsn) as usize)
+    }
+}
+
+impl SafeKeeperResponse {
+    pub const SIZE: usize = 4 + 4 + 8 * 3 + HotStandbyFeedback::SIZE;
+    pub const V1_SIZE: usize = 8 * 2 + HotStandbyFeedback::SIZE;
+
+    pub fn size(version: u32) -> usize {
+        if version >= SK_RESPONSE_STATUS_VERSION {
+            SafeKeeperResponse::SIZE
+        } else {
+            SafeKeeperResponse::V1_SIZE
+        }
+    }
+
+    pub fn pack(&self, version: u32, buf: &mut BytesMut) {
+        if version >= SK_RESPONSE_STATUS_VERSION {
+            buf.put_u32_le(self.status);
+            buf.put_u32_le(self.hs_replicas);
+        }
+        buf.put_u64_le(self.epoch);
+        buf.put_u64_le(self.flush_lsn);
+        if version >= SK_RESPONSE_STATUS_VERSION {
+            buf.put_u64_le(self.received_lsn);
+        }
+        self.hs_feedback.pack(buf);
+    }
+
+    // Response of version 1 is an acknowledgement of WAL received up to flush_lsn
+    pub fn unpack(version: u32, buf: &mut BytesMut) -> SafeKeeperResponse {
+        if version >= SK_RESPONSE_STATUS_VERSION {
+            SafeKeeperResponse {
+                status: buf.get_u32_le(),
+                hs_replicas: buf.get_u32_le(),
+                epoch: buf.get_u64_le(),
+                flush_lsn: buf.get_u64_le(),
+                received_lsn: buf.get_u64_le(),
+                hs_feedback: HotStandbyFeedback::unpack(buf),
+            }
+        } else {
+            let epoch = buf.get_u64_le();
+            let flush_lsn = buf.get_u64_le();
+            SafeKeeperResponse {
+                status: SK_STATUS_OK,
+                hs_replicas: 0,
+                epoch: epoch,
+                flush_lsn: flush_lsn,
+                received_lsn: flush_lsn,
+                hs_feedback: HotStandbyFeedback::unpack(buf),
+            }
+        }
+    }
+}
+
+impl ProposerMessageKind {
+    // Size of the fixed part of the message
+    pub fn size(self) -> usize {
+        match self {
+            ProposerMessageKind::Greeting => PeerGreeting::SIZE,
+            ProposerMessageKind::AcceptorSetClaim => AcceptorSetClaim::SIZE,
+            ProposerMessageKind::ServerInfo => ServerInfo::SIZE,
+            ProposerMessageKind::RequestVote => RequestVote::SIZE,
+            ProposerMessageKind::Append => SafeKeeperRequest::SIZE,
+        }
+    }
+}
+
+impl ProposerMessage {
+    pub fn kind(&self) -> ProposerMessageKind {
+        match self {
+            ProposerMessage::Greeting(_) => ProposerMessageKind::Greeting,
+            ProposerMessage::AcceptorSetClaim(_) => ProposerMessageKind::AcceptorSetClaim,
+            ProposerMessage::ServerInfo(_) => ProposerMessageKind::ServerInfo,
+            ProposerMessage::RequestVote(_) => ProposerMessageKind::RequestVote,
+            ProposerMessage::Append(_) => ProposerMessageKind::Append,
+        }
+    }
+}
+
+impl AcceptorMessageKind {
+    // Size of the message in the given protocol version
+    pub fn size(self, version: u32) -> usize {
+        match self {
+            AcceptorMessageKind::Versions => ProtocolVersions::SIZE,
+            AcceptorMessageKind::Info => SafeKeeperInfo::SIZE,
+            AcceptorMessageKind::Vote => NodeId::SIZE,
+            AcceptorMessageKind::Response => SafeKeeperResponse::size(version),
+        }
+    }
+}
+
+impl AcceptorMessage {
+    pub fn kind(&self) -> AcceptorMessageKind {
+        match self {
+            AcceptorMessage::Versions(_) => AcceptorMessageKind::Versions,
+            AcceptorMessage::Info(_) => AcceptorMessageKind::Info,
+            AcceptorMessage::Vote(_) => AcceptorMessageKind::Vote,
+            AcceptorMessage::Response(_) => AcceptorMessageKind::Response,
+        }
+    }
+
+    pub fn into_versions(self) -> Result<ProtocolVersions> {
+        match self {
+            AcceptorMessage::Versions(versions) => Ok(versions),
+            other => unexpected(other),
+        }
+    }
+
+    pub fn into_info(self) -> Result<SafeKeeperInfo> {
+        match self {
+            AcceptorMessage::Info(info) => Ok(info),
+            other => unexpected(other),
+        }
+    }
+
+    pub fn into_vote(self) -> Result<NodeId> {
+        match self {
+            AcceptorMessage::Vote(node_id) => Ok(node_id),
+            other => unexpected(other),
+        }
+    }
+
+    pub fn into_response(self) -> Result<SafeKeeperResponse> {
+        match self {
+            AcceptorMessage::Response(resp) => Ok(resp),
+            other => unexpected(other),
+        }
+    }
+}
+
+fn unexpected<T>(msg: AcceptorMessage) -> Result<T> {
+    io_error!("Unexpected {:?}", msg);
+}
+
+fn invalid_data<T>(msg: String) -> Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+//
+// Encoding of messages of a session, as negotiated by the greeting. The default one
+// (no capabilities, messages of version 1) is used for the greeting itself.
+//
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Codec {
+    pub framed: bool,        /* PEER_CAP_FRAMED */
+    pub wal_checksums: bool, /* PEER_CAP_WAL_CHECKSUM */
+    pub version: u32,        /* protocol version of the session, 0 until it is settled */
+}
+
+impl Codec {
+    // Version is settled after the greeting, see ProtocolVersions
+    pub fn from_greeting(greeting: &PeerGreeting) -> Codec {
+        Codec {
+            framed: (greeting.role & PEER_CAP_FRAMED) != 0,
+            wal_checksums: (greeting.role & PEER_CAP_WAL_CHECKSUM) != 0,
+            version: 0,
+        }
+    }
+
+    // Whether responses carry status, so that they can be more than acknowledgements
+    pub fn response_status(&self) -> bool {
+        self.version >= SK_RESPONSE_STATUS_VERSION
+    }
+
+    // Greeting precedes negotiation of the encoding, so it is never framed
+    fn is_framed(&self, kind: ProposerMessageKind) -> bool {
+        self.framed && kind != ProposerMessageKind::Greeting
+    }
+
+    fn checksum_size(&self, wal_size: usize) -> usize {
+        if self.wal_checksums && wal_size != 0 {
+            4
+        } else {
+            0
+        }
+    }
+
+    // Number of leading bytes of the message which determine its size
+    pub fn header_size(&self, kind: ProposerMessageKind) -> usize {
+        if self.is_framed(kind) {
+            FRAME_HDR_SIZE
+        } else {
+            kind.size()
+        }
+    }
+
+    //
+    // Size of the whole message, given its first header_size() bytes. Length of frame or
+    // LSNs of append which can't be valid are rejected before anything else is read.
+    //
+    pub fn message_size(&self, kind: ProposerMessageKind, header: &[u8]) -> Result<usize> {
+        if self.is_framed(kind) {
+            let len = LittleEndian::read_u32(&header[0..FRAME_HDR_SIZE]) as usize;
+            if len < kind.size() || len > MAX_FRAME_SIZE {
+                return invalid_data(format!("Invalid length {} of {:?} frame", len, kind));
+            }
+            Ok(FRAME_HDR_SIZE + len)
+        } else if kind == ProposerMessageKind::Append {
+            let req = SafeKeeperRequest::unpack(&mut BytesMut::from(&header[..kind.size()]));
+            let wal_size = req.wal_size()?;
+            Ok(kind.size() + wal_size + self.checksum_size(wal_size))
+        } else {
+            Ok(kind.size())
+        }
+    }
+
+    //
+    // Take message of the kind from the beginning of the buffer. Returns None, leaving
+    // the buffer intact, if the message is not complete yet.
+    //
+    pub fn decode_proposer(
+        &self,
+        kind: ProposerMessageKind,
+        buf: &mut BytesMut,
+    ) -> Result<Option<ProposerMessage>> {
+        let header_size = self.header_size(kind);
+        if buf.len() < header_size {
+            return Ok(None);
+        }
+        let size = self.message_size(kind, &buf[..header_size])?;
+        if buf.len() < size {
+            return Ok(None);
+        }
+        let mut body = buf.split_to(size);
+        if self.is_framed(kind) {
+            body.advance(FRAME_HDR_SIZE);
+        }
+        let msg = match kind {
+            ProposerMessageKind::Greeting => {
+                ProposerMessage::Greeting(PeerGreeting::unpack(&mut body))
+            }
+            ProposerMessageKind::AcceptorSetClaim => {
+                ProposerMessage::AcceptorSetClaim(AcceptorSetClaim::unpack(&mut body))
+            }
+            ProposerMessageKind::ServerInfo => {
+                ProposerMessage::ServerInfo(ServerInfo::unpack(&mut body))
+            }
+            ProposerMessageKind::RequestVote => {
+                ProposerMessage::RequestVote(RequestVote::unpack(&mut body))
+            }
+            ProposerMessageKind::Append => {
+                let header = SafeKeeperRequest::unpack(&mut body);
+                let wal_size = header.wal_size()?;
+                let checksum_size = self.checksum_size(wal_size);
+                if body.len() < wal_size + checksum_size {
+                    return invalid_data(format!(
+                        "Frame of append {:X}-{:X} is {} bytes short",
+                        header.begin_lsn,
+                        header.end_lsn,
+                        wal_size + checksum_size - body.len()
+                    ));
+                }
+                let wal = body.split_to(wal_size).freeze();
+                let checksum = if checksum_size != 0 {
+                    Some(body.get_u32_le())
+                } else {
+                    None
+                };
+                ProposerMessage::Append(AppendRequest {
+                    header: header,
+                    wal: wal,
+                    checksum: checksum,
+                })
+            }
+        };
+        if !body.is_empty() {
+            trace!(
+                "{} unknown trailing bytes of {:?} are skipped",
+                body.len(),
+                kind
+            );
+        }
+        Ok(Some(msg))
+    }
+
+    pub fn encode_proposer(&self, msg: &ProposerMessage, buf: &mut BytesMut) {
+        let start = self.start_frame(self.is_framed(msg.kind()), buf);
+        match msg {
+            ProposerMessage::Greeting(greeting) => greeting.pack(buf),
+            ProposerMessage::AcceptorSetClaim(claim) => claim.pack(buf),
+            ProposerMessage::ServerInfo(server_info) => server_info.pack(buf),
+            ProposerMessage::RequestVote(vote) => vote.pack(buf),
+            ProposerMessage::Append(append) => {
+                append.header.pack(buf);
+                buf.extend_from_slice(&append.wal);
+                if let Some(checksum) = append.checksum {
+                    buf.put_u32_le(checksum);
+                }
+            }
+        }
+        self.finish_frame(start, buf);
+    }
+
+    //
+    // Take message of the kind from the beginning of the buffer. Returns None, leaving
+    // the buffer intact, if the message is not complete yet.
+    //
+    pub fn decode_acceptor(
+        &self,
+        kind: AcceptorMessageKind,
+        buf: &mut BytesMut,
+    ) -> Result<Option<AcceptorMessage>> {
+        let mut size = kind.size(self.version);
+        if self.framed {
+            if buf.len() < FRAME_HDR_SIZE {
+                return Ok(None);
+            }
+            let len = LittleEndian::read_u32(&buf[0..FRAME_HDR_SIZE]) as usize;
+            if len < kind.size(self.version) || len > MAX_FRAME_SIZE {
+                return invalid_data(format!("Invalid length {} of {:?} frame", len, kind));
+            }
+            size = FRAME_HDR_SIZE + len;
+        }
+        if buf.len() < size {
+            return Ok(None);
+        }
+        let mut body = buf.split_to(size);
+        if self.framed {
+            body.advance(FRAME_HDR_SIZE);
+        }
+        let msg = match kind {
+            AcceptorMessageKind::Versions => {
+                AcceptorMessage::Versions(ProtocolVersions::unpack(&mut body))
+            }
+            AcceptorMessageKind::Info => AcceptorMessage::Info(SafeKeeperInfo::unpack(&mut body)),
+            AcceptorMessageKind::Vote => AcceptorMessage::Vote(NodeId::unpack(&mut body)),
+            AcceptorMessageKind::Response => {
+                AcceptorMessage::Response(SafeKeeperResponse::unpack(self.version, &mut body))
+            }
+        };
+        Ok(Some(msg))
+    }
+
+    pub fn encode_acceptor(&self, msg: &AcceptorMessage, buf: &mut BytesMut) {
+        let start = self.start_frame(self.framed, buf);
+        match msg {
+            AcceptorMessage::Versions(versions) => versions.pack(buf),
+            AcceptorMessage::Info(info) => info.pack(buf),
+            AcceptorMessage::Vote(node_id) => node_id.pack(buf),
+            AcceptorMessage::Response(resp) => resp.pack(self.version, buf),
+        }
+        self.finish_frame(start, buf);
+    }
+
+    /* Reserve place for the length of the frame, which is known once the body is packed */
+    fn start_frame(&self, framed: bool, buf: &mut BytesMut) -> Option<usize> {
+        if framed {
+            let start = buf.len();
+            buf.put_u32_le(0);
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    fn finish_frame(&self, start: Option<usize>, buf: &mut BytesMut) {
+        if let Some(start) = start {
+            let len = (buf.len() - start - FRAME_HDR_SIZE) as u32;
+            LittleEndian::write_u32(&mut buf[start..start + FRAME_HDR_SIZE], len);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//
+//   Graceful shutdown and the final flush report.
+//
+//   On SIGTERM or SIGINT the standalone wal_acceptor stops listening and drains like on
+//   takeover, then makes everything acknowledged durable: the segment holding flush_lsn
+//   of each tenant and a synced copy of its control file. The final flush_lsn, commit_lsn
+//   and outcome of the sync of every tenant are logged ordered by flush_lsn, then by
+//   tenant, so reports of two nodes can be compared line by line, and written to
+//   --shutdown-report as JSON. Orchestration can check that every tenant is "synced"
+//   before it retires the node or moves its tenants. The same is done after the listening
+//   socket is handed off, and on shutdown of embedded instance. Exit status is 1 if the
+//   final flush of a tenant has failed.
+//
+use log::*;
+use serde_derive::Serialize;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task;
+
+use crate::clock;
+use crate::pq_protocol::{Result, SystemId};
+use crate::safekeeper_protocol::SafeKeeperInfo;
+use crate::wal_service::{System, TenantRegistry};
+use crate::xlog_utils::{format_lsn, XLogRecPtr};
+use crate::WalAcceptorConf;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TenantFlush {
+    pub tenant: SystemId,
+    pub epoch: u64,
+    pub flush_lsn: String,
+    pub commit_lsn: String,
+    pub status: String, /* "synced", "not loaded" or "failed: <error>" */
+    #[serde(skip)]
+    lsn: XLogRecPtr, /* flush_lsn, orders the report */
+}
+
+impl TenantFlush {
+    // Positions stored in the control file by the final flush
+    pub fn new(id: SystemId, info: &SafeKeeperInfo) -> TenantFlush {
+        TenantFlush {
+            tenant: id,
+            epoch: info.epoch,
+            flush_lsn: format_lsn(info.flush_lsn),
+            commit_lsn: format_lsn(info.commit_lsn),
+            status: "synced".to_string(),
+            lsn: info.flush_lsn,
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.status.starts_with("failed")
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "tenant {} epoch {} flush_lsn {} commit_lsn {}: {}",
+            self.tenant, self.epoch, self.flush_lsn, self.commit_lsn, self.status
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShutdownReport {
+    pub time: String,   /* RFC 3339 */
+    pub reason: String, /* signal, "handoff" or "embedded shutdown" */
+    pub tenants: Vec<TenantFlush>,
+}
+
+impl ShutdownReport {
+    pub fn is_clean(&self) -> bool {
+        !self.tenants.iter().any(|flush| flush.is_failed())
+    }
+}
+
+//
+// Flush all tenants for the last time and report their final positions. Tenants should be
+// drained, so that the positions don't move anymore. Blocks on file I/O.
+//
+pub fn flush_and_report(
+    conf: &WalAcceptorConf,
+    systems: &[Arc<System>],
+    reason: &str,
+) -> ShutdownReport {
+    let mut flushes: Vec<TenantFlush> =
+        systems.iter().map(|system| system.final_flush(conf)).collect();
+    flushes.sort_by_key(|flush| (flush.lsn, flush.tenant));
+    let report = ShutdownReport {
+        time: chrono::DateTime::<chrono::Utc>::from(clock::system_time()).to_rfc3339(),
+        reason: reason.to_string(),
+        tenants: flushes,
+    };
+    info!("Final flush of {} tenants on {}", report.tenants.len(), reason);
+    for flush in &report.tenants {
+        if flush.is_failed() {
+            error!("Final flush of {}", flush.describe());
+        } else {
+            info!("Final flush of {}", flush.describe());
+        }
+    }
+    if let Some(path) = &conf.shutdown_report {
+        if let Err(e) = write_report(path, &report) {
+            error!("Failed to write shutdown report to {:?}: {}", path, e);
+        }
+    }
+    report
+}
+
+// Same on the blocking thread pool. Returns true if all tenants are flushed.
+pub async fn flush_all(conf: &WalAcceptorConf, tenants: &TenantRegistry, reason: &str) -> bool {
+    let conf = conf.clone();
+    let systems = tenants.get_systems();
+    let reason = reason.to_string();
+    match task::spawn_blocking(move || flush_and_report(&conf, &systems, &reason)).await {
+        Ok(report) => report.is_clean(),
+        Err(e) => {
+            error!("Final flush has failed: {}", e);
+            false
+        }
+    }
+}
+
+//
+// Wait for SIGTERM or SIGINT, then drain, flush all tenants and exit. Only standalone
+// wal_acceptor handles signals, those of embedded instance belong to its process.
+//
+pub async fn exit_on_signal(conf: WalAcceptorConf, tenants: Arc<TenantRegistry>) {
+    let (mut sigterm, mut sigint) =
+        match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+            (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Failed to handle shutdown signals: {}", e);
+                return;
+            }
+        };
+    let name = tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    };
+    tenants.listeners().stop_wal();
+    let n_tenants = tenants.drain();
+    info!("{} received, {} tenants are drained", name, n_tenants);
+    let clean = flush_all(&conf, &tenants, name).await;
+    process::exit(if clean { 0 } else { 1 });
+}
+
+// Durably replace the report file
+fn write_report(path: &Path, report: &ShutdownReport) -> Result<()> {
+    let content = serde_json::to_string_pretty(report)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all()?,
+        _ => File::open(".")?.sync_all()?,
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//
+//   Spare WAL segments: preallocation and recycling.
+//
+//   A new segment is zero-filled to its full size before WAL is written to it (see
+//   partial_segment.rs), which stalls the append crossing the segment boundary for
+//   milliseconds. With --prealloc-segments N a background task of each tenant keeps N
+//   zero-filled segments in <tenant>/spare, and the writer renames one of them into place
+//   instead. Like Postgres, WAL GC recycles removed segments while the pool is short: they
+//   are renamed into the pool rather than deleted and zero-filled there in place, which
+//   doesn't allocate blocks again. Unlike Postgres, recycled segments have to be zeroed:
+//   end of WAL in the partial segment is found by scanning records without checking page
+//   addresses, so stale WAL must not remain in it. Files of the pool:
+//   - <n>           zero-filled (and synced unless --no-sync), ready to be taken;
+//   - <n>.recycled  segment removed by WAL GC, to be zero-filled;
+//   - <n>.prep      being zero-filled from scratch, removed on load as an interrupted one.
+//   The pool is not a part of WAL: its files don't have segment names, so that WAL
+//   listing, reconciliation and peer checks never see them.
+//
+use log::*;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::fault_fs;
+use crate::partial_segment::{self, PREP_SUFFIX};
+use crate::pq_protocol::Result;
+
+pub const SPARE_DIR: &str = "spare";
+const RECYCLED_SUFFIX: &str = ".recycled";
+
+#[derive(Debug, Default)]
+pub struct SparePool {
+    dir: Option<PathBuf>, /* set by load */
+    ready: Vec<PathBuf>,
+    recycled: Vec<PathBuf>,
+    next: u64, /* number of the next file of the pool */
+}
+
+//
+// Zero-fill of a file of the pool, done without the tenant lock
+//
+#[derive(Debug)]
+pub struct SpareFill {
+    recycled: Option<PathBuf>, /* zeroed in place if set, otherwise created */
+    target: PathBuf,
+}
+
+impl SparePool {
+    //
+    // Scan the pool directory of the tenant, creating it if missing. Interrupted
+    // zero-fills and ready files of another segment size are removed.
+    //
+    pub fn load(tenant_dir: &Path, wal_seg_size: usize) -> Result<SparePool> {
+        let dir = tenant_dir.join(SPARE_DIR);
+        fs::create_dir_all(&dir)?;
+        let mut pool = SparePool {
+            dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let path = entry.path();
+            let fname = entry.file_name().to_string_lossy().into_owned();
+            let (number, suffix) = match fname.find('.') {
+                Some(pos) => (&fname[..pos], &fname[pos..]),
+                None => (&fname[..], ""),
+            };
+            let number: u64 = match number.parse() {
+                Ok(number) => number,
+                Err(_) => {
+                    warn!("Unexpected file {:?} in the spare segments", path);
+                    continue;
+                }
+            };
+            pool.next = pool.next.max(number + 1);
+            match suffix {
+                "" if entry.metadata()?.len() == wal_seg_size as u64 => pool.ready.push(path),
+                RECYCLED_SUFFIX => pool.recycled.push(path),
+                _ => {
+                    info!("Removing spare segment {:?}", path);
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        pool.ready.sort();
+        pool.recycled.sort();
+        Ok(pool)
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    // Number of files in the pool, ready or not
+    pub fn count(&self) -> usize {
+        self.ready.len() + self.recycled.len()
+    }
+
+    pub fn ready(&self) -> usize {
+        self.ready.len()
+    }
+
+    // Take a ready segment, the caller renames it into place
+    pub fn take(&mut self) -> Option<PathBuf> {
+        self.ready.pop()
+    }
+
+    // Put a segment taken back, if it couldn't be renamed into place
+    pub fn put_back(&mut self, path: PathBuf) {
+        self.ready.push(path);
+    }
+
+    //
+    // Move a segment removed by WAL GC into the pool if it has fewer than target files.
+    // Returns false if the segment is not taken, then it should be deleted.
+    //
+    pub fn recycle(&mut self, path: &Path, target: usize) -> Result<bool> {
+        if !self.is_loaded() || self.count() >= target {
+            return Ok(false);
+        }
+        let recycled = self.next_path(RECYCLED_SUFFIX);
+        fs::rename(path, &recycled)?;
+        fault_fs::rename(path, &recycled);
+        self.recycled.push(recycled);
+        Ok(true)
+    }
+
+    // Next zero-fill to do to have target ready segments, recycled ones first
+    pub fn next_fill(&mut self, target: usize) -> Option<SpareFill> {
+        if !self.is_loaded() || self.ready.len() >= target {
+            return None;
+        }
+        Some(match self.recycled.pop() {
+            Some(recycled) => SpareFill {
+                target: recycled.with_extension(""),
+                recycled: Some(recycled),
+            },
+            None => SpareFill {
+                recycled: None,
+                target: self.next_path(""),
+            },
+        })
+    }
+
+    // Zero-fill is done, the segment is ready
+    pub fn filled(&mut self, fill: SpareFill) {
+        self.ready.push(fill.target);
+    }
+
+    fn next_path(&mut self, suffix: &str) -> PathBuf {
+        let number = self.next;
+        self.next += 1;
+        self.dir.as_ref().unwrap().join(format!("{}{}", number, suffix))
+    }
+}
+
+impl SpareFill {
+    //
+    // Zero-fill the file: recycled one in place under its .recycled name, a new one
+    // under the .prep name, then rename it to the name of a ready one
+    //
+    pub fn run(&self, wal_seg_size: usize, no_sync: bool) -> Result<()> {
+        match &self.recycled {
+            Some(recycled) => {
+                let mut file = OpenOptions::new().write(true).open(recycled)?;
+                partial_segment::write_zeros(&mut file, recycled, wal_seg_size, no_sync)?;
+                fs::rename(recycled, &self.target)?;
+                fault_fs::rename(recycled, &self.target);
+            }
+            None => partial_segment::zero_fill(&self.target, wal_seg_size, no_sync)?,
+        }
+        if !no_sync {
+            File::open(self.target.parent().unwrap())?.sync_all()?;
+        }
+        debug!(
+            "Spare segment {:?} is {}",
+            self.target,
+            if self.recycled.is_some() { "recycled" } else { "preallocated" }
+        );
+        Ok(())
+    }
+
+    // Give the file up after a failed zero-fill
+    pub fn abandon(self) {
+        let _ = fs::remove_file(self.recycled.as_ref().unwrap_or(&self.target));
+        let _ = fs::remove_file(format!("{}{}", self.target.display(), PREP_SUFFIX));
+    }
+}
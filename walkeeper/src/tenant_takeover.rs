@@ -0,0 +1,147 @@
+//
+//   Takeover of a tenant locked by another wal_acceptor process.
+//
+//   A tenant is locked by the process serving it (safekeeper.control.lock), which writes
+//   its pid into the lock file. When a blue/green restart on the same host starts the new
+//   process while the old one is still draining, loading of the tenant in the new process
+//   finds the lock held. Instead of failing right away, the new process asks the holder
+//   to release the tenant with "release <tenant>" admin command: the holder pauses it,
+//   terminates its connections, makes the final flush (see shutdown.rs) and unloads it,
+//   dropping the lock. The load fails only if the holder can't be reached, doesn't answer
+//   in time or refuses. Each process can be reached at wal_acceptor.<pid>.sock in the data
+//   directory, a link to its admin socket, as wal_acceptor.sock is taken over by the
+//   latest process started. Instances embedded in one process don't negotiate.
+//
+use fs2::FileExt;
+use log::*;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use crate::admin::ADMIN_SOCKET_NAME;
+use crate::pq_protocol::{Result, SystemId};
+
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(30); /* holder makes the final flush meanwhile */
+const LOCK_RETRIES: u32 = 10; /* attempts to take the lock after the release, 100ms apart */
+
+// Admin socket of the process with this pid
+pub fn process_socket_path(data_dir: &Path, pid: u32) -> PathBuf {
+    let name = ADMIN_SOCKET_NAME.replace(".sock", &format!(".{}.sock", pid));
+    data_dir.join(name)
+}
+
+//
+// Make the admin socket of this process reachable by pid, and remove links left by
+// processes which are gone
+//
+pub fn link_process_socket(data_dir: &Path, socket_path: &Path) -> Result<()> {
+    for entry in fs::read_dir(data_dir)?.flatten() {
+        let fname = entry.file_name().to_string_lossy().into_owned();
+        let pid = fname
+            .strip_prefix("wal_acceptor.")
+            .and_then(|rest| rest.strip_suffix(".sock"))
+            .and_then(|pid| pid.parse::<u32>().ok());
+        if let Some(pid) = pid {
+            if pid == process::id() || !is_alive(pid) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
+    fs::hard_link(socket_path, process_socket_path(data_dir, process::id()))
+}
+
+fn is_alive(pid: u32) -> bool {
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+// Record this process as the holder of the tenant lock
+pub fn record_holder(lock: &File) -> Result<()> {
+    lock.set_len(0)?;
+    let mut lock = lock;
+    lock.write_all(format!("{}\n", process::id()).as_bytes())
+}
+
+//
+// Ask the process holding the lock of the tenant to release it, and take the lock.
+// Error tells why the lock couldn't be taken.
+//
+pub fn take_over_lock(data_dir: &Path, lock: &File, lock_path: &Path, id: SystemId) -> Result<()> {
+    let content = fs::read_to_string(lock_path)?;
+    let pid: u32 = match content.trim().parse() {
+        Ok(pid) => pid,
+        Err(_) => {
+            io_error!("the holder is not known, it doesn't record its pid");
+        }
+    };
+    if pid == process::id() {
+        io_error!("it is held by another instance in this process");
+    }
+    let flush = request_release(data_dir, pid, id)?;
+    info!("Tenant {} is released by process {}: {}", id, pid, flush);
+    for _ in 0..LOCK_RETRIES {
+        if lock.try_lock_exclusive().is_ok() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    io_error!(
+        "it is still held after process {} has released the tenant",
+        pid
+    );
+}
+
+// Send release command to the process, returns its final flush of the tenant
+fn request_release(data_dir: &Path, pid: u32, id: SystemId) -> Result<String> {
+    let socket_path = process_socket_path(data_dir, pid);
+    let mut stream = match UnixStream::connect(&socket_path) {
+        Ok(stream) => stream,
+        Err(e) => {
+            io_error!(
+                "holder process {} can't be reached at {:?}: {}",
+                pid,
+                socket_path,
+                e
+            );
+        }
+    };
+    stream.set_read_timeout(Some(RELEASE_TIMEOUT))?;
+    stream.set_write_timeout(Some(RELEASE_TIMEOUT))?;
+    info!("Asking process {} to release tenant {}", pid, id);
+    stream.write_all(format!("release {}\n", id).as_bytes())?;
+    let mut output = String::new();
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                io_error!(
+                    "holder process {} hasn't released the tenant in {:?}",
+                    pid,
+                    RELEASE_TIMEOUT
+                );
+            }
+            Err(e) => return Err(e),
+        };
+        if line == "OK" {
+            return Ok(output.trim().to_string());
+        }
+        if let Some(error) = line.strip_prefix("ERROR: ") {
+            io_error!(
+                "holder process {} refused to release the tenant: {}",
+                pid,
+                error
+            );
+        }
+        output += &line;
+        output.push('\n');
+    }
+    io_error!("holder process {} closed the connection", pid);
+}
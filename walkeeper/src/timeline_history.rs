@@ -0,0 +1,140 @@
+//
+//   Timeline history files of a tenant.
+//
+//   When WAL of a tenant switches to a new timeline (see PgVersionPolicy::NewTimeline),
+//   <timeline>.history is written to the tenant directory in Postgres format: one line
+//   per ancestor timeline with its number, the LSN where it was switched from and the
+//   reason, tab separated. History of the new timeline includes the history of its
+//   parent. Files are served by TIMELINE_HISTORY, and START_REPLICATION ... TIMELINE
+//   of an ancestor timeline streams WAL up to its switch point, so that standard
+//   walreceiver and pg_receivewal follow the switch.
+//
+use log::*;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::pq_protocol::Result;
+use crate::wal_service::parse_lsn;
+use crate::xlog_utils::{format_lsn, TimeLineID, XLByteToSeg, XLogRecPtr, XLogSegNo};
+
+#[derive(Debug, Clone)]
+pub struct TimelineHistoryEntry {
+    pub timeline: TimeLineID,
+    pub switch_lsn: XLogRecPtr, /* end of WAL of the timeline, start of the next one */
+    pub reason: String,
+}
+
+// Name of history file of the timeline, as in Postgres
+pub fn history_file_name(timeline: TimeLineID) -> String {
+    format!("{:08X}.history", timeline)
+}
+
+fn history_path(system_dir: &Path, timeline: TimeLineID) -> PathBuf {
+    system_dir.join(history_file_name(timeline))
+}
+
+// Contents of history file of the timeline, None if it has no history
+pub fn read(system_dir: &Path, timeline: TimeLineID) -> Result<Option<Vec<u8>>> {
+    match fs::read(history_path(system_dir, timeline)) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+//
+// Ancestors of the timeline, oldest first. Timeline without history file (e.g. the
+// first one) has no ancestors. Comments and empty lines are skipped, like Postgres does.
+//
+pub fn load(system_dir: &Path, timeline: TimeLineID) -> Result<Vec<TimelineHistoryEntry>> {
+    let content = match read(system_dir, timeline)? {
+        Some(content) => String::from_utf8_lossy(&content).into_owned(),
+        None => return Ok(Vec::new()),
+    };
+    let mut entries = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(3, '\t');
+        let parent = fields.next().and_then(|tli| tli.trim().parse::<TimeLineID>().ok());
+        let switch_lsn = fields.next().map(parse_lsn);
+        match (parent, switch_lsn) {
+            (Some(parent), Some(Ok(switch_lsn))) => entries.push(TimelineHistoryEntry {
+                timeline: parent,
+                switch_lsn: switch_lsn,
+                reason: fields.next().unwrap_or("").trim().to_string(),
+            }),
+            _ => {
+                io_error!(
+                    "Invalid line {:?} in history file of timeline {}",
+                    line,
+                    timeline
+                );
+            }
+        }
+    }
+    Ok(entries)
+}
+
+//
+// Durably record switch from the parent timeline to the new one at the given LSN,
+// writing history file of the new timeline
+//
+pub fn record_switch(
+    system_dir: &Path,
+    parent: TimeLineID,
+    timeline: TimeLineID,
+    switch_lsn: XLogRecPtr,
+    reason: &str,
+) -> Result<()> {
+    let mut content = String::new();
+    for entry in load(system_dir, parent)? {
+        content += &format!(
+            "{}\t{}\t{}\n",
+            entry.timeline,
+            format_lsn(entry.switch_lsn),
+            entry.reason
+        );
+    }
+    content += &format!("{}\t{}\t{}\n", parent, format_lsn(switch_lsn), reason);
+
+    let path = history_path(system_dir, timeline);
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    File::open(system_dir)?.sync_all()?;
+    info!(
+        "Timeline {} switched from {} at {} is recorded in {:?}",
+        timeline,
+        parent,
+        format_lsn(switch_lsn),
+        path
+    );
+    Ok(())
+}
+
+//
+// Timeline whose file holds the segment of WAL of the timeline with the given history:
+// the latest one started at or before the segment. Like in Postgres, the segment where
+// a timeline starts is a file of the new timeline, with WAL of its parent copied to it.
+//
+pub fn segment_timeline(
+    history: &[TimelineHistoryEntry],
+    timeline: TimeLineID,
+    segno: XLogSegNo,
+    wal_seg_size: usize,
+) -> TimeLineID {
+    let mut segment_timeline = history.first().map_or(timeline, |entry| entry.timeline);
+    for (i, entry) in history.iter().enumerate() {
+        if XLByteToSeg(entry.switch_lsn, wal_seg_size) <= segno {
+            segment_timeline = history.get(i + 1).map_or(timeline, |next| next.timeline);
+        }
+    }
+    segment_timeline
+}
@@ -0,0 +1,279 @@
+//
+//   TLS encryption of safekeeper connections.
+//
+//   Like Postgres, the safekeeper listens for plain and encrypted connections on the
+//   same port: a client wishing encryption starts with SSLRequest packet, which is
+//   answered with 'S' followed by TLS handshake, or with 'N' if TLS is not configured.
+//   Both libpq replication clients and proposers (before their greeting) may request it.
+//
+use byteorder::{BigEndian, ByteOrder};
+use bytes::BufMut;
+use futures::future::{poll_fn, FutureExt};
+use log::*;
+use native_tls::Identity;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tokio_native_tls::{TlsAcceptor, TlsStream};
+
+use crate::pq_protocol::{NEGOTIATE_GSS_CODE, NEGOTIATE_SSL_CODE};
+
+const NEGOTIATE_PACKET_LEN: usize = 8; /* length and request code */
+pub const NEGOTIATE_TIMEOUT: Duration = Duration::from_secs(10); /* to send the whole SSLRequest */
+const PEEK_INTERVAL: Duration = Duration::from_millis(1); /* while SSLRequest is incomplete */
+
+//
+// Certificate and private key of the safekeeper, loaded at startup
+//
+#[derive(Clone)]
+pub struct TlsConf {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    acceptor: TlsAcceptor,
+}
+
+impl fmt::Debug for TlsConf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConf")
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file)
+            .finish()
+    }
+}
+
+fn invalid_data(path: &Path, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{:?} has no valid {} in PEM format", path, what),
+    )
+}
+
+fn tls_error<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+impl TlsConf {
+    //
+    // Load certificate chain and private key (PKCS#8 or RSA) from PEM files. native-tls
+    // takes the identity as PKCS#12 archive, so they are packed into one.
+    //
+    pub fn load(cert_file: &Path, key_file: &Path) -> io::Result<TlsConf> {
+        let mut chain = X509::stack_from_pem(&fs::read(cert_file)?)
+            .map_err(|_| invalid_data(cert_file, "certificates"))?;
+        if chain.is_empty() {
+            return Err(invalid_data(cert_file, "certificates"));
+        }
+        let key = PKey::private_key_from_pem(&fs::read(key_file)?)
+            .map_err(|_| invalid_data(key_file, "private key"))?;
+        let cert = chain.remove(0);
+        let mut ca = Stack::new().map_err(tls_error)?;
+        for cert in chain {
+            ca.push(cert).map_err(tls_error)?;
+        }
+        let mut builder = Pkcs12::builder();
+        builder.ca(ca);
+        let archive = builder
+            .build("", "safekeeper", &key, &cert)
+            .and_then(|archive| archive.to_der())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let identity = Identity::from_pkcs12(&archive, "")
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let acceptor = native_tls::TlsAcceptor::new(identity).map_err(tls_error)?;
+        Ok(TlsConf {
+            cert_file: cert_file.to_path_buf(),
+            key_file: key_file.to_path_buf(),
+            acceptor: TlsAcceptor::from(acceptor),
+        })
+    }
+}
+
+//
+// Client connection, plain or encrypted. Reads of the encrypted one are buffered,
+// so that readiness means that decrypted data is available, like for plain socket.
+//
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<tokio::io::BufReader<TlsStream<TcpStream>>>),
+}
+
+//
+// Code of SSLRequest or GSSENCRequest packet at the start of the stream, if any. Client
+// which doesn't complete the packet in NEGOTIATE_TIMEOUT is given up on.
+//
+async fn peek_negotiate_request(socket: &TcpStream) -> io::Result<Option<u32>> {
+    let mut buf = [0u8; NEGOTIATE_PACKET_LEN];
+    let peek = async {
+        loop {
+            let n = socket.peek(&mut buf).await?;
+            if n >= 4 && BigEndian::read_u32(&buf[0..4]) != NEGOTIATE_PACKET_LEN as u32 {
+                return Ok(false);
+            }
+            if n == NEGOTIATE_PACKET_LEN || n == 0 {
+                return Ok(true);
+            }
+            /* Rest of the packet is on its way, peek doesn't wait for more than is there */
+            sleep(PEEK_INTERVAL).await;
+        }
+    };
+    match timeout(NEGOTIATE_TIMEOUT, peek).await {
+        Ok(Ok(true)) => {}
+        Ok(Ok(false)) => return Ok(None),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "Negotiation request is not completed in {:?}",
+                    NEGOTIATE_TIMEOUT
+                ),
+            ))
+        }
+    }
+    match BigEndian::read_u32(&buf[4..8]) {
+        code @ NEGOTIATE_SSL_CODE | code @ NEGOTIATE_GSS_CODE => Ok(Some(code)),
+        _ => Ok(None),
+    }
+}
+
+impl Stream {
+    //
+    // Accept encryption requested by the client. GSS encryption is declined, and
+    // so is TLS if it is not configured: then client decides whether to go on in plain.
+    //
+    pub async fn accept(mut socket: TcpStream, tls: Option<&TlsConf>) -> io::Result<Stream> {
+        loop {
+            match peek_negotiate_request(&socket).await? {
+                None => return Ok(Stream::Plain(socket)),
+                Some(code) => {
+                    let mut request = [0u8; NEGOTIATE_PACKET_LEN];
+                    socket.read_exact(&mut request).await?;
+                    match tls {
+                        Some(tls) if code == NEGOTIATE_SSL_CODE => {
+                            socket.write_all(b"S").await?;
+                            let stream = tls.acceptor.accept(socket).await.map_err(tls_error)?;
+                            debug!("TLS handshake is completed");
+                            return Ok(Stream::Tls(Box::new(tokio::io::BufReader::new(stream))));
+                        }
+                        _ => socket.write_all(b"N").await?,
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Stream::Tls(_))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Plain(socket) => socket.peer_addr(),
+            Stream::Tls(stream) => stream.get_ref().get_ref().get_ref().get_ref().peer_addr(),
+        }
+    }
+
+    // Wait until data can be read without blocking
+    pub async fn readable(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.readable().await,
+            Stream::Tls(stream) => {
+                poll_fn(|cx| Pin::new(&mut **stream).poll_fill_buf(cx).map_ok(|_| ())).await
+            }
+        }
+    }
+
+    // Read what is available, WouldBlock if nothing is
+    pub fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.try_read(buf),
+            Stream::Tls(stream) => stream
+                .read(buf)
+                .now_or_never()
+                .unwrap_or_else(|| Err(io::ErrorKind::WouldBlock.into())),
+        }
+    }
+
+    pub fn try_read_buf<B: BufMut>(&mut self, buf: &mut B) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.try_read_buf(buf),
+            Stream::Tls(stream) => stream
+                .read_buf(buf)
+                .now_or_never()
+                .unwrap_or_else(|| Err(io::ErrorKind::WouldBlock.into())),
+        }
+    }
+
+    // Write all data and flush it: TLS records may be kept in the session otherwise
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.write_all(buf).await,
+            Stream::Tls(stream) => {
+                stream.write_all(buf).await?;
+                stream.flush().await
+            }
+        }
+    }
+
+    // Socket of plain stream, to be moved to another runtime
+    pub fn into_std(self) -> io::Result<std::net::TcpStream> {
+        match self {
+            Stream::Plain(socket) => socket.into_std(),
+            Stream::Tls(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "TLS stream can't be moved to another runtime",
+            )),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(socket) => Pin::new(socket).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(&mut **stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(socket) => Pin::new(socket).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(&mut **stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(socket) => Pin::new(socket).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(&mut **stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(socket) => Pin::new(socket).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(&mut **stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//
+//   Tombstones of deleted tenants.
+//
+//   Deletion of a tenant leaves tombstones/<tenant>.toml in the data directory: when
+//   the tenant was deleted, by whom, and its final flush and commit LSNs. Connections of
+//   a late proposer or pageserver of the deleted tenant are refused with "tenant was
+//   deleted" error instead of silently creating an empty tenant. The tombstone is
+//   removed when the tenant is explicitly created again (create-tenant,
+//   --init-tenant, import-wal).
+//
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::clock;
+use crate::pq_protocol::{Result, SystemId};
+use crate::xlog_utils::{format_lsn, XLogRecPtr};
+
+pub const TOMBSTONES_DIR: &str = "tombstones";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub deleted: String, /* RFC 3339 */
+    pub initiator: String,
+    pub flush_lsn: String,
+    pub commit_lsn: String,
+}
+
+impl Tombstone {
+    pub fn new(flush_lsn: XLogRecPtr, commit_lsn: XLogRecPtr, initiator: &str) -> Tombstone {
+        Tombstone {
+            deleted: chrono::DateTime::<chrono::Utc>::from(clock::system_time()).to_rfc3339(),
+            initiator: initiator.to_string(),
+            flush_lsn: format_lsn(flush_lsn),
+            commit_lsn: format_lsn(commit_lsn),
+        }
+    }
+
+    pub fn describe(&self, id: SystemId) -> String {
+        format!(
+            "tenant {} was deleted at {} by {}, flush_lsn={} commit_lsn={}",
+            id, self.deleted, self.initiator, self.flush_lsn, self.commit_lsn
+        )
+    }
+}
+
+fn tombstone_path(data_dir: &Path, id: SystemId) -> PathBuf {
+    data_dir.join(TOMBSTONES_DIR).join(format!("{}.toml", id))
+}
+
+// Durably store tombstone of the tenant, replacing the previous one
+pub fn record(data_dir: &Path, id: SystemId, tombstone: &Tombstone) -> Result<()> {
+    let path = tombstone_path(data_dir, id);
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    let content = toml::to_string(tombstone)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// Tombstone of the tenant, if it was deleted
+pub fn load(data_dir: &Path, id: SystemId) -> Result<Option<Tombstone>> {
+    let path = tombstone_path(data_dir, id);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(&path)?;
+    match toml::from_str(&content) {
+        Ok(tombstone) => Ok(Some(tombstone)),
+        Err(e) => {
+            io_error!("Failed to parse {:?}: {}", path, e);
+        }
+    }
+}
+
+// Forget that the tenant was deleted, returns false if it wasn't
+pub fn remove(data_dir: &Path, id: SystemId) -> Result<bool> {
+    let path = tombstone_path(data_dir, id);
+    if !path.exists() {
+        return Ok(false);
+    }
+    fs::remove_file(&path)?;
+    File::open(path.parent().unwrap())?.sync_all()?;
+    info!("Tombstone of tenant {} is removed", id);
+    Ok(true)
+}
@@ -0,0 +1,227 @@
+//
+//   Capture and replay of proposer protocol traces.
+//
+//   With --trace-dir every proposer connection is recorded to a trace file:
+//   a sequence of records, each consisting of a tag byte, little-endian u32
+//   payload length and the payload. Replay feeds recorded proposer input into
+//   a fresh safekeeper running on temporary storage and checks that it
+//   produces identical responses and final control data.
+//
+use byteorder::{ByteOrder, LittleEndian};
+use log::*;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::task;
+
+use crate::access_list::AccessList;
+use crate::pq_protocol::{Result, SystemId};
+use crate::wal_service::{self, TenantRegistry};
+use crate::{CallbackConf, SyncMethod, WalAcceptorConf};
+
+pub const TRACE_RECEIVED: u8 = b'<'; /* bytes received from proposer */
+pub const TRACE_SENT: u8 = b'>'; /* bytes sent to proposer */
+pub const TRACE_INITIAL_STATE: u8 = b'C'; /* system id and control file at session start */
+pub const TRACE_FINAL_STATE: u8 = b'F'; /* system id and control data at session end */
+
+/* Sequence of temporary data directories of replays */
+static REPLAYS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub struct TraceWriter {
+    file: BufWriter<File>,
+}
+
+impl TraceWriter {
+    pub fn create(trace_dir: &Path, peer: &str) -> Result<TraceWriter> {
+        fs::create_dir_all(trace_dir)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let path = trace_dir.join(format!("{}-{}.trace", peer, now.as_millis()));
+        info!("Capture proposer trace to {:?}", path);
+        Ok(TraceWriter {
+            file: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(&mut self, tag: u8, data: &[u8]) -> Result<()> {
+        let mut hdr = [0u8; 5];
+        hdr[0] = tag;
+        LittleEndian::write_u32(&mut hdr[1..], data.len() as u32);
+        self.file.write_all(&hdr)?;
+        self.file.write_all(data)?;
+        self.file.flush()
+    }
+
+    pub fn record_state(&mut self, tag: u8, id: SystemId, data: &[u8]) -> Result<()> {
+        let mut payload = vec![0u8; 8];
+        LittleEndian::write_u64(&mut payload, id);
+        payload.extend_from_slice(data);
+        self.record(tag, &payload)
+    }
+}
+
+struct TraceRecord {
+    tag: u8,
+    data: Vec<u8>,
+}
+
+fn read_trace(path: &Path) -> Result<Vec<TraceRecord>> {
+    let content = fs::read(path)?;
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < content.len() {
+        if pos + 5 > content.len() {
+            io_error!("Truncated record header at offset {} of {:?}", pos, path);
+        }
+        let tag = content[pos];
+        let len = LittleEndian::read_u32(&content[pos + 1..pos + 5]) as usize;
+        pos += 5;
+        if pos + len > content.len() {
+            io_error!("Truncated record at offset {} of {:?}", pos, path);
+        }
+        records.push(TraceRecord {
+            tag,
+            data: content[pos..pos + len].to_vec(),
+        });
+        pos += len;
+    }
+    Ok(records)
+}
+
+fn split_state(record: &TraceRecord) -> (SystemId, &[u8]) {
+    (LittleEndian::read_u64(&record.data[0..8]), &record.data[8..])
+}
+
+//
+// Replay trace against temporary storage, removed afterwards. Returns number of mismatches.
+//
+pub fn replay(trace_path: &Path) -> Result<usize> {
+    let records = read_trace(trace_path)?;
+    let data_dir = env::temp_dir().join(format!(
+        "wal_acceptor_replay_{}_{}",
+        process::id(),
+        REPLAYS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&data_dir);
+    fs::create_dir_all(&data_dir)?;
+    let res = replay_in(&records, &data_dir);
+    if let Err(e) = fs::remove_dir_all(&data_dir) {
+        warn!("Failed to remove {:?}: {}", data_dir, e);
+    }
+    res
+}
+
+fn replay_in(records: &[TraceRecord], data_dir: &Path) -> Result<usize> {
+    /* Restore control file of the captured session */
+    for record in records.iter().filter(|r| r.tag == TRACE_INITIAL_STATE) {
+        let (id, control_file) = split_state(record);
+        let system_dir = crate::tenant_dir(data_dir, id);
+        fs::create_dir_all(&system_dir)?;
+        fs::write(system_dir.join(wal_service::CONTROL_FILE_NAME), control_file)?;
+    }
+
+    let conf = WalAcceptorConf {
+        data_dir: data_dir.to_path_buf(),
+        daemonize: false,
+        no_sync: true,
+        sync_method: SyncMethod::Fsync,
+        wal_stats: false,
+        verify_wal_crc: false,
+        control_file_version: None,
+        pg_wal_layout: false,
+        slow_append_threshold: None,
+        slow_send_threshold: None,
+        heartbeat_interval: None,
+        keepalive_interval: Duration::from_secs(10),
+        proposer_read_timeout: None,
+        tcp_keepalive: None,
+        max_clock_skew: None,
+        catchup_rate_limit: None,
+        max_inflight_msgs: 1,
+        receive_high_watermark: None,
+        max_ack_delay: None,
+        group_commit_bytes: None,
+        group_commit_delay: None,
+        prealloc_segments: 0,
+        wal_retention: None,
+        wal_retention_time: None,
+        gc_coordinated: false,
+        read_cache_size: 0,
+        listen_addr: "127.0.0.1:0".parse().unwrap(),
+        pageserver_addr: None,
+        http_addr: None,
+        callback: CallbackConf::None,
+        trace_dir: None,
+        shutdown_report: None,
+        metrics_top_tenants: None,
+        acceptor_set: None,
+        access_list: AccessList::default(),
+        http_access_list: AccessList::default(),
+        legacy_tenant: None,
+        node_uuid: None,
+        workers: None,
+        tls: None,
+        at_rest_key: None,
+        object_storage: None,
+        archive: None,
+    };
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(replay_records(records, conf))
+}
+
+async fn replay_records(records: &[TraceRecord], conf: WalAcceptorConf) -> Result<usize> {
+    let listener = TcpListener::bind(conf.listen_addr).await?;
+    let addr = listener.local_addr()?;
+    let tenants = TenantRegistry::new();
+    let server_tenants = tenants.clone();
+    let server = task::spawn(async move {
+        let (socket, _) = listener.accept().await?;
+        wal_service::serve_connection(socket, &conf, server_tenants).await
+    });
+
+    let mut mismatches = 0;
+    let mut client = TcpStream::connect(addr).await?;
+    for (i, record) in records.iter().enumerate() {
+        match record.tag {
+            TRACE_RECEIVED => client.write_all(&record.data).await?,
+            TRACE_SENT => {
+                let mut response = vec![0u8; record.data.len()];
+                client.read_exact(&mut response).await?;
+                if response != record.data {
+                    error!("Record {}: response differs from the captured one", i);
+                    mismatches += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    drop(client);
+    if let Err(e) = server.await.unwrap() {
+        info!("Replayed session ended with: {}", e);
+    }
+
+    for record in records.iter().filter(|r| r.tag == TRACE_FINAL_STATE) {
+        let (id, expected) = split_state(record);
+        match tenants.get_system(id) {
+            Some(system) if system.info_bytes() == expected => {}
+            _ => {
+                error!("Final state of system {} differs from the captured one", id);
+                mismatches += 1;
+            }
+        }
+    }
+    Ok(mismatches)
+}
@@ -0,0 +1,215 @@
+//
+//   End-to-end checksum of the WAL byte stream.
+//
+//   A proposer announcing PEER_CAP_WAL_CHECKSUM follows WAL of each non-empty append with
+//   CRC-32C (little endian) of WAL of the segment of its last byte, from the start of that
+//   segment up to the end of the append: the checksum restarts at every segment boundary.
+//   The safekeeper verifies it against the received bytes before storing them, so WAL
+//   corrupted on the way is rejected with CORRUPT_WAL. Checksums of completed segments,
+//   as computed by the proposer, are recorded in wal_checksums of the tenant directory,
+//   and WAL senders started with WAL_CHECKSUMS option pass them to the receiver after the
+//   last byte of each segment, so that corruption on disk or in memory of the safekeeper
+//   is detected at ingestion as well. Checksums of segments removed by WAL GC are trimmed
+//   from the file.
+//
+use crc32c::crc32c_append;
+use log::*;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::at_rest::AtRestKey;
+use crate::partial_segment::SegmentPath;
+use crate::pq_protocol::Result;
+use crate::xlog_utils::*;
+
+pub const WAL_CHECKSUMS_FILE_NAME: &str = "wal_checksums";
+
+//
+// Checksum of the segment being received, continued by each append
+//
+#[derive(Debug, Clone, Copy)]
+pub struct RollingChecksum {
+    pub end_lsn: XLogRecPtr, /* end of the checksummed WAL */
+    pub crc: u32,            /* of WAL of the segment of the last byte, from its start */
+}
+
+impl RollingChecksum {
+    //
+    // Checksum of WAL of the segment preceding the given position, read from the tenant
+    // directory. Used when appends don't continue the known checksum, e.g. after the
+    // proposer has reconnected.
+    //
+    pub fn load(
+        dir: &Path,
+        key: Option<&AtRestKey>,
+        timeline: TimeLineID,
+        pos: XLogRecPtr,
+        wal_seg_size: usize,
+    ) -> Result<RollingChecksum> {
+        let offset = XLogSegmentOffset(pos, wal_seg_size) as usize;
+        let mut crc = 0;
+        if offset != 0 {
+            let segno = XLByteToSeg(pos, wal_seg_size);
+            let mut prefix = vec![0u8; offset];
+            SegmentPath::new(dir, timeline, segno, wal_seg_size)
+                .open_for_read(key)?
+                .read_exact(&mut prefix)?;
+            crc = crc32c_append(0, &prefix);
+        }
+        Ok(RollingChecksum {
+            end_lsn: pos,
+            crc: crc,
+        })
+    }
+
+    //
+    // Continue the checksum with WAL starting at its end. Returns names and checksums
+    // of the segments completed by the WAL.
+    //
+    pub fn feed(
+        &mut self,
+        data: &[u8],
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+    ) -> Vec<(String, u32)> {
+        let mut completed = Vec::new();
+        let mut data = data;
+        while !data.is_empty() {
+            let offset = XLogSegmentOffset(self.end_lsn, wal_seg_size) as usize;
+            if offset == 0 {
+                self.crc = 0;
+            }
+            let n = min(wal_seg_size - offset, data.len());
+            self.crc = crc32c_append(self.crc, &data[..n]);
+            self.end_lsn += n as u64;
+            data = &data[n..];
+            if XLogSegmentOffset(self.end_lsn, wal_seg_size) == 0 {
+                let segno = XLByteToSeg(self.end_lsn - 1, wal_seg_size);
+                completed.push((XLogFileName(timeline, segno, wal_seg_size), self.crc));
+            }
+        }
+        completed
+    }
+}
+
+//
+// Checksums recorded in wal_checksums of a tenant, indexed by segment name. The file is
+// parsed once, on the first lookup or trim, and the index follows the records from then
+// on. The tenant keeps it under a lock, which also serializes appends with rewrites of
+// the file by trim().
+//
+#[derive(Debug, Default)]
+pub struct ChecksumIndex {
+    checksums: Option<HashMap<String, u32>>, /* None until the file is read */
+}
+
+impl ChecksumIndex {
+    //
+    // Durably record checksums of completed segments. Records are appended, so a segment
+    // overwritten by a proposer of a new term gets its checksum overridden.
+    //
+    pub fn record(&mut self, dir: &Path, checksums: &[(String, u32)], no_sync: bool) -> Result<()> {
+        let mut content = String::new();
+        for (segment, crc) in checksums {
+            content += &format!("{} {:08X}\n", segment, crc);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(WAL_CHECKSUMS_FILE_NAME))?;
+        file.write_all(content.as_bytes())?;
+        if !no_sync {
+            file.sync_all()?;
+        }
+        if let Some(index) = self.checksums.as_mut() {
+            index.extend(checksums.iter().cloned());
+        }
+        Ok(())
+    }
+
+    // Recorded checksum of the completed segment, None if the proposer didn't provide it
+    pub fn lookup(&mut self, dir: &Path, segment: &str) -> Result<Option<u32>> {
+        Ok(self.index(dir)?.get(segment).copied())
+    }
+
+    //
+    // Forget checksums of segments below horizon_segno, removed by WAL GC, rewriting the
+    // file. Returns the number of forgotten segments.
+    //
+    pub fn trim(
+        &mut self,
+        dir: &Path,
+        horizon_segno: XLogSegNo,
+        wal_seg_size: usize,
+        no_sync: bool,
+    ) -> Result<usize> {
+        let index = self.index(dir)?;
+        let total = index.len();
+        index.retain(|segment, _| XLogFromFileName(segment, wal_seg_size).0 >= horizon_segno);
+        let trimmed = total - index.len();
+        if trimmed == 0 {
+            return Ok(0);
+        }
+        let mut retained: Vec<(&String, &u32)> = index.iter().collect();
+        retained.sort();
+        let mut content = String::new();
+        for (segment, crc) in retained {
+            content += &format!("{} {:08X}\n", segment, crc);
+        }
+        if let Err(e) = rewrite(dir, &content, no_sync) {
+            /* The file may still have the trimmed records, read it again next time */
+            self.checksums = None;
+            return Err(e);
+        }
+        Ok(trimmed)
+    }
+
+    fn index(&mut self, dir: &Path) -> Result<&mut HashMap<String, u32>> {
+        if self.checksums.is_none() {
+            self.checksums = Some(read(dir)?);
+        }
+        Ok(self.checksums.as_mut().unwrap())
+    }
+}
+
+// Replace the file with the given content, atomically
+fn rewrite(dir: &Path, content: &str, no_sync: bool) -> Result<()> {
+    let path = dir.join(WAL_CHECKSUMS_FILE_NAME);
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(content.as_bytes())?;
+    if !no_sync {
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, &path)?;
+    if !no_sync {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Checksums recorded in the tenant directory, the last record of a segment wins
+fn read(dir: &Path) -> Result<HashMap<String, u32>> {
+    let path = dir.join(WAL_CHECKSUMS_FILE_NAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut checksums = HashMap::new();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next().map(|crc| u32::from_str_radix(crc, 16))) {
+            (Some(name), Some(Ok(crc))) if IsXLogFileName(name) => {
+                checksums.insert(name.to_string(), crc);
+            }
+            /* E.g. torn by a crash, its segment was not acknowledged then */
+            _ => warn!("Invalid line {:?} in {:?} is skipped", line, path),
+        }
+    }
+    Ok(checksums)
+}
@@ -0,0 +1,69 @@
+//
+//   Open segment files of the WAL writer of a tenant.
+//
+//   write_wal_file() used to open the segment (or create it) for every append, and
+//   sync_wal_file() did the same for every group commit. The cache keeps the few most
+//   recently written segments open, keyed by timeline and segment number, with whether
+//   the file is partial. A file is taken out of the cache for the write and put back
+//   after it, so a failed write drops it. An entry is valid only as long as its name
+//   points to the same file: giving the partial segment its final name keeps the file,
+//   but the segment is dropped from the cache anyway, as it is handed over to at-rest
+//   encoding and archiving. Whoever replaces or removes segment files of a tenant with
+//   loaded WAL (WAL GC, at-rest rewrite, tenant deletion) clears the cache first.
+//   WAL senders don't use it: they read through a handle of their own, kept open while
+//   they stream a segment, as a shared handle would share its file offset.
+//
+use std::collections::VecDeque;
+use std::fs::File;
+
+use crate::xlog_utils::{TimeLineID, XLogSegNo};
+
+pub const WAL_FILE_CACHE_SIZE: usize = 4; /* segments kept open by each tenant */
+
+#[derive(Debug)]
+struct Entry {
+    timeline: TimeLineID,
+    segno: XLogSegNo,
+    file: File,
+    partial: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct WalFileCache {
+    entries: VecDeque<Entry>, /* most recently used first */
+}
+
+impl WalFileCache {
+    // Take the segment file out of the cache, with whether it is partial
+    pub fn take(&mut self, timeline: TimeLineID, segno: XLogSegNo) -> Option<(File, bool)> {
+        let pos = self
+            .entries
+            .iter()
+            .position(|entry| entry.timeline == timeline && entry.segno == segno)?;
+        let entry = self.entries.remove(pos).unwrap();
+        Some((entry.file, entry.partial))
+    }
+
+    // Put the segment file back as the most recently used one, closing the least recent
+    pub fn put(&mut self, timeline: TimeLineID, segno: XLogSegNo, file: File, partial: bool) {
+        self.remove(timeline, segno);
+        self.entries.push_front(Entry {
+            timeline: timeline,
+            segno: segno,
+            file: file,
+            partial: partial,
+        });
+        self.entries.truncate(WAL_FILE_CACHE_SIZE);
+    }
+
+    // Close the segment file, if it is cached
+    pub fn remove(&mut self, timeline: TimeLineID, segno: XLogSegNo) {
+        self.entries
+            .retain(|entry| entry.timeline != timeline || entry.segno != segno);
+    }
+
+    // Close all files, before segments are removed or replaced
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,578 @@
+//
+//   Bulk import of WAL segments into a tenant.
+//
+//   "import-wal" subcommand and POST /v1/tenant/{id}/import_wal install a batch of WAL
+//   segments into a tenant which has no WAL yet: to seed it from a pg_basebackup-style
+//   archive or to move it from another safekeeper offline. The source is a directory
+//   (segments in it or in its pg_wal subdirectory) or an uncompressed tar archive (ustar,
+//   GNU or pax). It comes with import.toml manifest telling what to install:
+//
+//       timeline = 1
+//       flush_lsn = "0/3000148"
+//       commit_lsn = "0/3000148"     # optional, flush_lsn by default
+//       wal_seg_size = 16777216      # optional, 16MB by default
+//       pg_version = 140000          # optional
+//
+//   Segments are staged in <tenant>/import.tmp and validated there: all of the manifest
+//   timeline and of the segment size, without gaps from the first one up to the one
+//   holding flush_lsn, each starting with the long page header of this segment and of
+//   this tenant, with valid WAL records up to flush_lsn in the last one. WAL beyond
+//   flush_lsn is zeroed, and the last segment is named like the writer names it. The
+//   staging directory is then renamed to <tenant>/import with the plan of installation
+//   in it, segments are renamed into place and the control file with flush_lsn,
+//   commit_lsn and restart_lsn of the manifest is written, which commits the import.
+//   If the import is interrupted after that point, installed segments are removed when
+//   the tenant is loaded or imported again, and the rollback is recorded in the
+//   recovery log. Leftovers of an interrupted staging are removed.
+//
+//   Import refused because of the source or the manifest fails with InvalidData error,
+//   which HTTP API reports as a bad request.
+//
+use byteorder::{ByteOrder, LittleEndian};
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::path::Path;
+
+use crate::partial_segment;
+use crate::pq_protocol::{Result, SystemId};
+use crate::wal_service::parse_lsn;
+use crate::xlog_utils::*;
+
+pub const IMPORT_MANIFEST_NAME: &str = "import.toml";
+const STAGING_DIR: &str = "import.tmp";
+const INSTALL_DIR: &str = "import";
+const PLAN_FILE_NAME: &str = "plan.toml";
+const DEFAULT_WAL_SEG_SIZE: usize = 16 * 1024 * 1024;
+const TAR_BLOCK_SIZE: usize = 512;
+
+/* Like io_error!, for the import refused because of the source or the manifest */
+macro_rules! invalid_import {
+    ($($arg:tt)*) => (
+        error!($($arg)*);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!($($arg)*)))
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    timeline: TimeLineID,
+    flush_lsn: String,
+    commit_lsn: Option<String>,
+    wal_seg_size: Option<usize>,
+    pg_version: Option<u32>,
+}
+
+//
+// Validated import, stored in <tenant>/import while segments are installed
+//
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportPlan {
+    pub timeline: TimeLineID,
+    pub wal_seg_size: usize,
+    pub start_lsn: XLogRecPtr, /* start of the first segment */
+    pub flush_lsn: XLogRecPtr,
+    pub commit_lsn: XLogRecPtr,
+    pub pg_version: Option<u32>,
+    pub pg_wal_layout: bool,
+    pub files: Vec<String>, /* names in the tenant directory, oldest first */
+}
+
+impl ImportPlan {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} segments of timeline {}, WAL {}-{}, commit_lsn {}",
+            self.files.len(),
+            self.timeline,
+            format_lsn(self.start_lsn),
+            format_lsn(self.flush_lsn),
+            format_lsn(self.commit_lsn)
+        )
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "timeline": self.timeline,
+            "segments": self.files.len(),
+            "start_lsn": format_lsn(self.start_lsn),
+            "flush_lsn": format_lsn(self.flush_lsn),
+            "commit_lsn": format_lsn(self.commit_lsn),
+        })
+    }
+}
+
+// Does the tenant directory have WAL segments?
+pub fn has_wal(tenant_dir: &Path) -> Result<bool> {
+    for entry in fs::read_dir(tenant_dir)? {
+        let entry = entry?;
+        let fname = entry.file_name().to_string_lossy().into_owned();
+        if IsXLogFileName(&fname) || IsPartialXLogFileName(&fname) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+//
+// Copy segments and the manifest from the source into the staging directory, validate
+// them and prepare for installation. The manifest is taken from the source unless given.
+//
+pub fn stage(
+    tenant_dir: &Path,
+    id: SystemId,
+    source: &Path,
+    manifest_path: Option<&Path>,
+    pg_wal_layout: bool,
+    no_sync: bool,
+) -> Result<ImportPlan> {
+    let staging_dir = tenant_dir.join(STAGING_DIR);
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir)?;
+    }
+    fs::create_dir_all(&staging_dir)?;
+    let res = stage_in(
+        &staging_dir,
+        id,
+        source,
+        manifest_path,
+        pg_wal_layout,
+        no_sync,
+    );
+    if res.is_err() {
+        let _ = fs::remove_dir_all(&staging_dir);
+    }
+    res
+}
+
+fn stage_in(
+    staging_dir: &Path,
+    id: SystemId,
+    source: &Path,
+    manifest_path: Option<&Path>,
+    pg_wal_layout: bool,
+    no_sync: bool,
+) -> Result<ImportPlan> {
+    let source_manifest = if source.is_dir() {
+        copy_dir(source, staging_dir)?
+    } else {
+        unpack_archive(source, staging_dir)?
+    };
+    let content = match (manifest_path, source_manifest) {
+        (Some(path), _) => fs::read_to_string(path)?,
+        (None, Some(content)) => content,
+        (None, None) => {
+            invalid_import!("{:?} has no {} manifest", source, IMPORT_MANIFEST_NAME);
+        }
+    };
+    let manifest: Manifest = match toml::from_str(&content) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            invalid_import!("Invalid import manifest: {}", e);
+        }
+    };
+    let wal_seg_size = manifest.wal_seg_size.unwrap_or(DEFAULT_WAL_SEG_SIZE);
+    if !wal_seg_size.is_power_of_two() || wal_seg_size < XLOG_BLCKSZ {
+        invalid_import!("Invalid wal_seg_size {} in the manifest", wal_seg_size);
+    }
+    let parse = |lsn: &str| {
+        parse_lsn(lsn).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    };
+    let flush_lsn = parse(&manifest.flush_lsn)?;
+    let commit_lsn = match &manifest.commit_lsn {
+        Some(lsn) => parse(lsn)?,
+        None => flush_lsn,
+    };
+    if flush_lsn == 0 || commit_lsn > flush_lsn {
+        invalid_import!(
+            "Invalid manifest: flush_lsn {} and commit_lsn {}",
+            format_lsn(flush_lsn),
+            format_lsn(commit_lsn)
+        );
+    }
+
+    /* Staged segments by number, each under the name it came with */
+    let mut segments = BTreeMap::new();
+    for entry in fs::read_dir(staging_dir)? {
+        let fname = entry?.file_name().to_string_lossy().into_owned();
+        let (segno, tli) = XLogFromFileName(&fname, wal_seg_size);
+        if tli != manifest.timeline {
+            invalid_import!(
+                "Segment {} is of timeline {}, the manifest imports timeline {}",
+                fname,
+                tli,
+                manifest.timeline
+            );
+        }
+        let len = fs::metadata(staging_dir.join(&fname))?.len();
+        if len != wal_seg_size as u64 {
+            invalid_import!(
+                "Segment {} is {} bytes, expected {}",
+                fname,
+                len,
+                wal_seg_size
+            );
+        }
+        if let Some(other) = segments.insert(segno, fname.clone()) {
+            invalid_import!("Segment is imported twice, as {} and {}", other, fname);
+        }
+    }
+    let last_segno = XLByteToSeg(flush_lsn - 1, wal_seg_size);
+    let first_segno = match segments.keys().next() {
+        Some(&segno) if segno <= last_segno => segno,
+        _ => {
+            invalid_import!("No segments up to flush_lsn {}", format_lsn(flush_lsn));
+        }
+    };
+    if let Some((_, fname)) = segments.range(last_segno + 1..).next() {
+        invalid_import!(
+            "Segment {} is beyond flush_lsn {}",
+            fname,
+            format_lsn(flush_lsn)
+        );
+    }
+    for segno in first_segno..=last_segno {
+        match segments.get(&segno) {
+            Some(fname) => check_page_header(staging_dir, fname, id, segno, wal_seg_size)?,
+            None => {
+                invalid_import!(
+                    "Segment {} is missing",
+                    XLogFileName(manifest.timeline, segno, wal_seg_size)
+                );
+            }
+        }
+    }
+
+    /* WAL of the last segment must be valid up to flush_lsn, the rest is cut off */
+    let flush_offset = XLogSegmentOffset(flush_lsn, wal_seg_size) as u64;
+    let last = &segments[&last_segno];
+    if flush_offset != 0 {
+        let end = find_end_of_wal_segment(&staging_dir.to_path_buf(), last, wal_seg_size) as u64;
+        if end < flush_offset {
+            invalid_import!(
+                "WAL of segment {} ends at {}, before flush_lsn {}",
+                last,
+                format_lsn(XLogSegNoOffsetToRecPtr(
+                    last_segno,
+                    end as u32,
+                    wal_seg_size
+                )),
+                format_lsn(flush_lsn)
+            );
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(staging_dir.join(last))?;
+        file.seek(SeekFrom::Start(flush_offset))?;
+        file.write_all(&vec![0u8; wal_seg_size - flush_offset as usize])?;
+    }
+
+    let mut files = Vec::new();
+    for (&segno, fname) in &segments {
+        let mut name = XLogFileName(manifest.timeline, segno, wal_seg_size);
+        if segno == last_segno && flush_offset != 0 && !pg_wal_layout {
+            name += partial_segment::PARTIAL_SUFFIX;
+        }
+        let path = staging_dir.join(&name);
+        if *fname != name {
+            fs::rename(staging_dir.join(fname), &path)?;
+        }
+        if !no_sync {
+            File::open(&path)?.sync_all()?;
+        }
+        files.push(name);
+    }
+    let plan = ImportPlan {
+        timeline: manifest.timeline,
+        wal_seg_size: wal_seg_size,
+        start_lsn: XLogSegNoOffsetToRecPtr(first_segno, 0, wal_seg_size),
+        flush_lsn: flush_lsn,
+        commit_lsn: commit_lsn,
+        pg_version: manifest.pg_version,
+        pg_wal_layout: pg_wal_layout,
+        files: files,
+    };
+    let content =
+        toml::to_string(&plan).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let mut file = File::create(staging_dir.join(PLAN_FILE_NAME))?;
+    file.write_all(content.as_bytes())?;
+    if !no_sync {
+        file.sync_all()?;
+        File::open(staging_dir)?.sync_all()?;
+    }
+    Ok(plan)
+}
+
+// Copy segments of the directory and of its pg_wal subdirectory, returns its manifest
+fn copy_dir(source: &Path, staging_dir: &Path) -> Result<Option<String>> {
+    let mut dirs = vec![source.to_path_buf()];
+    if source.join("pg_wal").is_dir() {
+        dirs.push(source.join("pg_wal"));
+    }
+    for dir in dirs {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let fname = entry.file_name().to_string_lossy().into_owned();
+            if IsXLogFileName(&fname) || IsPartialXLogFileName(&fname) {
+                if staging_dir.join(&fname).exists() {
+                    invalid_import!("Segment {} is found twice in {:?}", fname, source);
+                }
+                fs::copy(entry.path(), staging_dir.join(&fname))?;
+            }
+        }
+    }
+    let manifest_path = source.join(IMPORT_MANIFEST_NAME);
+    if manifest_path.exists() {
+        Ok(Some(fs::read_to_string(manifest_path)?))
+    } else {
+        Ok(None)
+    }
+}
+
+//
+// Unpack segments of the tar archive, wherever they are in it, returns its manifest.
+// Names longer than 100 bytes are taken from ustar prefix, GNU long name or pax path.
+//
+fn unpack_archive(source: &Path, staging_dir: &Path) -> Result<Option<String>> {
+    let name = source.to_string_lossy();
+    if name.ends_with(".gz") || name.ends_with(".tgz") {
+        invalid_import!(
+            "{:?} is compressed, only plain tar archives are imported",
+            source
+        );
+    }
+    let mut archive = BufReader::new(File::open(source)?);
+    let mut manifest = None;
+    let mut long_name = None;
+    let mut header = [0u8; TAR_BLOCK_SIZE];
+    loop {
+        read_tar_data(&mut archive, &mut header, source)?;
+        if header.iter().all(|&b| b == 0) {
+            break; /* end of archive */
+        }
+        let (path, entry_type, size) = parse_tar_header(&header, source)?;
+        let padding =
+            (TAR_BLOCK_SIZE as u64 - size % TAR_BLOCK_SIZE as u64) % TAR_BLOCK_SIZE as u64;
+        let path = long_name.take().unwrap_or(path);
+        let fname = path.rsplit('/').next().unwrap_or("").to_string();
+        let regular = entry_type == b'0' || entry_type == 0;
+        if entry_type == b'L' || entry_type == b'x' || (regular && fname == IMPORT_MANIFEST_NAME) {
+            if size > XLOG_BLCKSZ as u64 {
+                invalid_import!(
+                    "{:?} has entry of {} bytes where up to {} are expected",
+                    source,
+                    size,
+                    XLOG_BLCKSZ
+                );
+            }
+            let mut data = vec![0u8; size as usize];
+            read_tar_data(&mut archive, &mut data, source)?;
+            let data = String::from_utf8_lossy(&data).into_owned();
+            match entry_type {
+                b'L' => long_name = Some(data.trim_end_matches('\0').to_string()),
+                b'x' => long_name = pax_path(&data),
+                _ => manifest = Some(data),
+            }
+        } else if regular && (IsXLogFileName(&fname) || IsPartialXLogFileName(&fname)) {
+            let path = staging_dir.join(&fname);
+            if path.exists() {
+                invalid_import!("Segment {} is found twice in {:?}", fname, source);
+            }
+            let mut file = File::create(&path)?;
+            let copied = io::copy(&mut (&mut archive).take(size), &mut file)?;
+            if copied != size {
+                invalid_import!("{:?} is truncated", source);
+            }
+        } else {
+            io::copy(&mut (&mut archive).take(size), &mut io::sink())?;
+        }
+        io::copy(&mut (&mut archive).take(padding), &mut io::sink())?;
+    }
+    Ok(manifest)
+}
+
+fn read_tar_data(archive: &mut impl Read, buf: &mut [u8], source: &Path) -> Result<()> {
+    match archive.read_exact(buf) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            invalid_import!("{:?} is truncated", source);
+        }
+        res => res,
+    }
+}
+
+// Path, type and size of the entry described by the tar header
+fn parse_tar_header(header: &[u8], source: &Path) -> Result<(String, u8, u64)> {
+    /* Checksum is computed with its own field filled with spaces */
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                b' ' as u64
+            } else {
+                b as u64
+            }
+        })
+        .sum();
+    let field = |range: std::ops::Range<usize>| {
+        let bytes = &header[range];
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..len]).into_owned()
+    };
+    let octal =
+        |range: std::ops::Range<usize>| u64::from_str_radix(field(range).trim_matches(' '), 8).ok();
+    if octal(148..156) != Some(sum) {
+        invalid_import!("{:?} is not a tar archive or is corrupted", source);
+    }
+    let size = match octal(124..136) {
+        Some(size) => size,
+        None => {
+            invalid_import!(
+                "{:?} has entry {} of unsupported size",
+                source,
+                field(0..100)
+            );
+        }
+    };
+    let mut path = field(0..100);
+    if &header[257..263] == b"ustar\0" {
+        let prefix = field(345..500);
+        if !prefix.is_empty() {
+            path = prefix + "/" + &path;
+        }
+    }
+    Ok((path, header[156], size))
+}
+
+// Path given by the pax extended header, records of which are "<length> <key>=<value>\n"
+fn pax_path(data: &str) -> Option<String> {
+    data.lines()
+        .filter_map(|record| record.split_once(' '))
+        .filter_map(|(_, pair)| pair.split_once('='))
+        .find(|(key, _)| *key == "path")
+        .map(|(_, value)| value.to_string())
+}
+
+//
+// Check the long page header the segment starts with: it must be of this segment and
+// this tenant, so that WAL of another system or a renamed segment is not imported
+//
+fn check_page_header(
+    dir: &Path,
+    fname: &str,
+    id: SystemId,
+    segno: XLogSegNo,
+    wal_seg_size: usize,
+) -> Result<()> {
+    let mut hdr = [0u8; XLOG_SIZE_OF_XLOG_LONG_PHD];
+    File::open(dir.join(fname))?.read_exact(&mut hdr)?;
+    let magic = LittleEndian::read_u16(&hdr[0..2]);
+    let info = LittleEndian::read_u16(&hdr[2..4]);
+    let pageaddr = LittleEndian::read_u64(&hdr[8..16]);
+    let long_hdr = &hdr[XLOG_SIZE_OF_XLOG_SHORT_PHD..]; /* xlp_sysid, xlp_seg_size, xlp_xlog_blcksz */
+    let sysid = LittleEndian::read_u64(&long_hdr[0..8]);
+    let seg_size = LittleEndian::read_u32(&long_hdr[8..12]);
+    if magic != XLOG_PAGE_MAGIC || info & XLP_LONG_HEADER == 0 {
+        invalid_import!("Segment {} doesn't start with WAL page header", fname);
+    }
+    if pageaddr != XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size) {
+        invalid_import!(
+            "Segment {} starts with page of {}",
+            fname,
+            format_lsn(pageaddr)
+        );
+    }
+    if sysid != id {
+        invalid_import!("Segment {} is WAL of system {}", fname, sysid);
+    }
+    if seg_size as usize != wal_seg_size {
+        invalid_import!("Segment {} is of WAL segment size {}", fname, seg_size);
+    }
+    Ok(())
+}
+
+//
+// Rename staged segments into the tenant directory. Segments completed in pg_wal layout
+// are marked ready for archiving, like those written by the tenant.
+//
+pub fn install(tenant_dir: &Path, plan: &ImportPlan, no_sync: bool) -> Result<()> {
+    let staging_dir = tenant_dir.join(STAGING_DIR);
+    let install_dir = tenant_dir.join(INSTALL_DIR);
+    fs::rename(&staging_dir, &install_dir)?;
+    if !no_sync {
+        File::open(tenant_dir)?.sync_all()?;
+    }
+    let status_dir = tenant_dir.join(ARCHIVE_STATUS_DIR);
+    for (i, fname) in plan.files.iter().enumerate() {
+        fs::rename(install_dir.join(fname), tenant_dir.join(fname))?;
+        let completed =
+            i + 1 < plan.files.len() || XLogSegmentOffset(plan.flush_lsn, plan.wal_seg_size) == 0;
+        if plan.pg_wal_layout && completed {
+            fs::create_dir_all(&status_dir)?;
+            File::create(status_dir.join(fname.to_owned() + ".ready"))?;
+        }
+    }
+    if !no_sync {
+        File::open(tenant_dir)?.sync_all()?;
+        if status_dir.exists() {
+            File::open(&status_dir)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+// The control file is written, the import is done
+pub fn finish(tenant_dir: &Path) -> Result<()> {
+    fs::remove_dir_all(tenant_dir.join(INSTALL_DIR))?;
+    File::open(tenant_dir)?.sync_all()
+}
+
+//
+// Plan of the import interrupted while segments were installed, if any. An interrupted
+// staging is removed.
+//
+pub fn interrupted(tenant_dir: &Path) -> Result<Option<ImportPlan>> {
+    let staging_dir = tenant_dir.join(STAGING_DIR);
+    if staging_dir.exists() {
+        info!("Removing interrupted WAL import staging {:?}", staging_dir);
+        fs::remove_dir_all(&staging_dir)?;
+    }
+    let plan_path = tenant_dir.join(INSTALL_DIR).join(PLAN_FILE_NAME);
+    if !plan_path.exists() {
+        return Ok(None);
+    }
+    match toml::from_str(&fs::read_to_string(&plan_path)?) {
+        Ok(plan) => Ok(Some(plan)),
+        Err(e) => {
+            io_error!("Failed to parse {:?}: {}", plan_path, e);
+        }
+    }
+}
+
+// Remove segments installed by the interrupted import
+pub fn roll_back(tenant_dir: &Path, plan: &ImportPlan) -> Result<()> {
+    let status_dir = tenant_dir.join(ARCHIVE_STATUS_DIR);
+    for fname in &plan.files {
+        for path in &[
+            tenant_dir.join(fname),
+            status_dir.join(fname.to_owned() + ".ready"),
+        ] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+    finish(tenant_dir)
+}
+
+// Segments of the plan, for the recovery log
+pub fn installed_files(tenant_dir: &Path, plan: &ImportPlan) -> Vec<String> {
+    plan.files
+        .iter()
+        .filter(|fname| tenant_dir.join(fname).exists())
+        .cloned()
+        .collect()
+}
@@ -5,15 +5,16 @@
 
 extern crate fs2;
 
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crc32c::crc32c;
 use fs2::FileExt;
-use lazy_static::lazy_static;
 use log::*;
 use regex::Regex;
+use serde_derive::Serialize;
 use std::cmp::max;
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
@@ -21,114 +22,539 @@ use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::str;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime;
-use tokio::sync::Notify;
+use tokio::sync::{oneshot, Notified, Notify};
 use tokio::task;
-use tokio_postgres::{connect, Error, NoTls};
 
+use crate::admin;
+use crate::archive;
+use crate::at_rest::{self, AtRestPolicy, RewriteJob, RewriteState};
+use crate::auth::{self, AuthMethod, ScramExchange, Secret, SCRAM_MECHANISM};
+use crate::clock::{self, sleep};
+use crate::diagnostics::{
+    self, ConnectionRegistration, ConnectionState, SYSTEMS_LOCK, TENANT_LOCKS,
+};
+use crate::events::{self, Event};
+use crate::fault_fs;
+use crate::gc_coordination::{GcProposal, GcState};
+use crate::ingest_index::IngestIndex;
+use crate::listeners::ListenerSet;
+use crate::metrics::{Histogram, TenantMetrics, WalOp, WalOpStats};
+use crate::node_file;
+use crate::object_storage::{ObjectStorageConf, ObjectWal};
+use crate::outbound::{self, OutboundOp, OutboundQueue, OutboundStats};
+use crate::partial_segment::{self, reconcile_partial_segments, SegmentPath};
+use crate::peer_check;
 use crate::pq_protocol::*;
+use crate::read_cache;
+use crate::recovery_log;
+use crate::safekeeper_protocol::*;
+use crate::shutdown::{self, TenantFlush};
+use crate::spare_segments::SparePool;
+use crate::tenant_takeover;
+use crate::timeline_history;
+use crate::tls::Stream;
+use crate::tombstone;
+use crate::trace::*;
+use crate::wal_checksum::{ChecksumIndex, RollingChecksum};
+use crate::wal_file_cache::WalFileCache;
+use crate::wal_import::{self, ImportPlan};
+use crate::wal_storage::WalStorage;
 use crate::xlog_utils::*;
-use crate::WalAcceptorConf;
+use crate::{
+    parse_tenant_id, tenant_dir, PgVersionPolicy, PriorityClass, TenantConf, WalAcceptorConf,
+};
 
-type FullTransactionId = u64;
+#[cfg(feature = "testing")]
+pub mod conformance;
+#[cfg(feature = "testing")]
+pub mod crash_test;
+#[cfg(feature = "testing")]
+pub mod test_session;
 
 const SK_MAGIC: u32 = 0xCafeCeefu32;
-const SK_FORMAT_VERSION: u32 = 1;
-const SK_PROTOCOL_VERSION: u32 = 1;
+const SK_FORMAT_VERSION: u32 = 1; /* of SafeKeeperInfo sent to proposers */
+/*
+ * Format of the control file, stored in format_version of SafeKeeperInfo on disk:
+ *  1 - SafeKeeperInfo, optionally followed by Postgres version history and archived LSN
+ *  2 - SafeKeeperInfo, Postgres version history and archived LSN
+ *  3 - two copies in slots of CONTROL_SLOT_SIZE, each one as in 2 followed by
+ *      remote_consistent_lsn, generation of the copy and CRC32C of all the preceding
+ *      bytes of the copy
+ */
+pub const CONTROL_FILE_VERSION: u32 = 3;
+/* Oldest format still written (--control-file-version), so that the previous release can read it */
+pub const MIN_CONTROL_FILE_VERSION: u32 = CONTROL_FILE_VERSION - 1;
+pub const CONTROL_SLOT_SIZE: usize = 4096;
 const UNKNOWN_SERVER_VERSION: u32 = 0;
-const END_REPLICATION_MARKER: u64 = u64::MAX;
-const MAX_SEND_SIZE: usize = XLOG_BLCKSZ * 16;
 const XLOG_HDR_SIZE: usize = 1 + 8 * 3; /* 'w' + startPos + walEnd + timestamp */
 const LIBPQ_HDR_SIZE: usize = 5; /* 1 byte with message type + 4 bytes length */
 const LIBPQ_MSG_SIZE_OFFS: usize = 1;
-const CONTROL_FILE_NAME: &str = "safekeeper.control";
-const END_OF_STREAM: XLogRecPtr = 0;
+pub const CONTROL_FILE_NAME: &str = "safekeeper.control";
+const CONTROL_TMP_FILE_NAME: &str = "safekeeper.control.tmp"; /* renamed over the control file */
+const CONTROL_LOCK_FILE_NAME: &str = "safekeeper.control.lock"; /* held by wal_acceptor serving the tenant */
+const PG_VERSIONS_MAGIC: u32 = 0x50475648; /* "PGVH", history of Postgres versions in the control file */
+const MAX_PG_VERSION_CHANGES: usize = 16; /* oldest changes are forgotten */
+const ARCHIVED_LSN_MAGIC: u32 = 0x41524348; /* "ARCH", end of archived WAL in the control file */
+const KEEPALIVE_PROBES: u32 = 3; /* unanswered TCP keepalive probes before the connection is reset */
+const HEARTBEAT_MISSES: u32 = 3; /* proposer is considered dead after this many heartbeat intervals of silence */
+const COMMIT_TIME_GRANULARITY: TimestampTz = 1_000_000; /* usec, precision of time lag */
+const MAX_COMMIT_TIMES: usize = 3600; /* remembered commit timestamps: an hour of busy tenant */
+const MAX_UNAPPLIED_APPENDS: usize = 10000; /* appends awaiting apply by WAL receivers, for ingest latency */
+const BUFFER_BASELINE: usize = 10 * 1024; /* initial capacity of connection buffers */
+const BUFFER_SHRINK_DELAY: Duration = Duration::from_secs(10); /* buffers are shrunk after that long without large messages */
+const GC_INTERVAL: Duration = Duration::from_secs(60); /* WAL GC runs at least that often */
+const REPLICA_RETENTION: Duration = Duration::from_secs(600); /* progress of disconnected replica is kept that long */
+const MAX_DISCONNECTED_REPLICAS: usize = 16; /* per tenant, the oldest ones are forgotten first */
+const SQLSTATE_ADMIN_SHUTDOWN: &[u8; 5] = b"57P01"; /* sent to libpq clients terminated by administrator */
+const SQLSTATE_TENANT_DELETED: &[u8; 5] = b"3D000"; /* invalid_catalog_name, tenant has a tombstone */
+const SQLSTATE_INVALID_PASSWORD: &[u8; 5] = b"28P01"; /* authentication failed */
+const SQLSTATE_INVALID_AUTHORIZATION: &[u8; 5] = b"28000"; /* method not allowed for connection */
+const SQLSTATE_PROTOCOL_VIOLATION: &[u8; 5] = b"08P01"; /* malformed or unexpected message */
+const SQLSTATE_SYNTAX_ERROR: &[u8; 5] = b"42601"; /* unknown or malformed command */
+const SQLSTATE_INSUFFICIENT_PRIVILEGE: &[u8; 5] = b"42501"; /* unauthenticated admin command */
+const SQLSTATE_FEATURE_NOT_SUPPORTED: &[u8; 5] = b"0A000";
+const SQLSTATE_UNDEFINED_FILE: &[u8; 5] = b"58P01"; /* requested WAL is not there */
+const SQLSTATE_INTERNAL_ERROR: &[u8; 5] = b"XX000"; /* any other error */
+
+/*
+ * Postgres versions which have written WAL of the tenant. Stored in the control file
+ * after SafeKeeperInfo, which is sent to proposers as is and can't be extended;
+ * control files of older versions don't have it.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct PgVersionHistory {
+    pub mismatch_accepted: bool, /* WAL of another major version was accepted, until acknowledged */
+    pub acked_version: u32,      /* version acknowledged by administrator */
+    pub changes: Vec<PgVersionChange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PgVersionChange {
+    pub pg_version: u32,
+    pub timeline: TimeLineID,
+    pub lsn: XLogRecPtr, /* end of WAL when the version was first seen */
+}
 
 /*
- * Unique node identifier used by Paxos
+ * Version of the connected proposer which has not written WAL of the tenant yet,
+ * recorded once the proposer wins the vote (see check_pg_version)
  */
-#[repr(C)]
-#[derive(Debug, Clone, Copy, Ord, PartialOrd, PartialEq, Eq)]
-struct NodeId {
-    term: u64,
-    uuid: u128,
+#[derive(Debug, Clone)]
+struct PgVersionUpdate {
+    change: PgVersionChange,
+    old_version: u32,
+    parent_timeline: TimeLineID, /* timeline of WAL preceding the change */
+    mismatch_accepted: bool,     /* WAL of another major version is accepted */
 }
 
-#[repr(C)]
+/*
+ * Statistics of received WAL records by resource manager
+ */
 #[derive(Debug, Clone, Copy)]
-struct ServerInfo {
-    protocol_version: u32, /* proxy-safekeeper protocol version */
-    pg_version: u32,       /* Postgres server version */
-    node_id: NodeId,
-    system_id: SystemId, /* Postgres system identifier */
-    wal_end: XLogRecPtr,
-    timeline: TimeLineID,
-    wal_seg_size: u32,
+struct WalRecordStats {
+    records: [u64; RM_MAX_ID + 1],
+    bytes: [u64; RM_MAX_ID + 1],
 }
 
 /*
- * Vote request sent from proxy to safekeepers
+ * Progress of a WAL sender which started far behind commit LSN, e.g. a new pageserver
  */
-#[repr(C)]
-#[derive(Debug)]
-struct RequestVote {
-    node_id: NodeId,
-    vcl: XLogRecPtr, /* volume commit LSN */
-    epoch: u64,      /* new epoch when safekeeper reaches vcl */
+#[derive(Debug, Clone)]
+struct CatchupProgress {
+    start_lsn: XLogRecPtr,
+    sent_lsn: XLogRecPtr,
+    started: Instant,
+    cancelled: bool, /* cancelled by administrator, sender should stop */
+}
+
+impl CatchupProgress {
+    fn describe(&self, peer: &SocketAddr, commit_lsn: XLogRecPtr) -> String {
+        let remaining = commit_lsn.saturating_sub(self.sent_lsn);
+        let elapsed = clock::elapsed(self.started).as_secs_f64();
+        let throughput = if elapsed > 0.0 {
+            (self.sent_lsn - self.start_lsn) as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = if throughput > 0.0 {
+            format!("{:.0}s", remaining as f64 / throughput)
+        } else {
+            "unknown".to_string()
+        };
+        format!(
+            "catchup of {}: sent_lsn={} remaining={} bytes throughput={:.0} bytes/s eta={}{}",
+            peer,
+            format_lsn(self.sent_lsn),
+            remaining,
+            throughput,
+            eta,
+            if self.cancelled { " (cancelled)" } else { "" }
+        )
+    }
 }
 
 /*
- * Information of about storage node
+ * State of replica connection, as reported in standby status updates ('r' messages).
+ * Kept for REPLICA_RETENTION after the replica disconnects, so that WAL it needs to
+ * resume streaming is not removed meanwhile.
  */
-#[repr(C)]
 #[derive(Debug, Clone, Copy)]
-struct SafeKeeperInfo {
-    magic: u32,              /* magic for verifying content the control file */
-    format_version: u32,     /* safekeeper format version */
-    epoch: u64,              /* safekeeper's epoch */
-    server: ServerInfo,      /* information about server */
-    commit_lsn: XLogRecPtr,  /* part of WAL acknowledged by quorum */
-    flush_lsn: XLogRecPtr,   /* locally flushed part of WAL */
-    restart_lsn: XLogRecPtr, /* minimal LSN which may be needed for recovery of some safekeeper: min(commit_lsn) for all safekeepers */
+struct ReplicaState {
+    write_lsn: XLogRecPtr,
+    flush_lsn: XLogRecPtr,
+    apply_lsn: XLogRecPtr,
+    updated: Instant,              /* when the last status update was received */
+    disconnected: Option<Instant>, /* when the connection ended */
+}
+
+impl ReplicaState {
+    fn expired(&self) -> bool {
+        self.disconnected
+            .map_or(false, |at| clock::elapsed(at) >= REPLICA_RETENTION)
+    }
+}
+
+//
+// Time lag of consumer which has received WAL up to the given LSN: age of the oldest
+// commit it hasn't received yet. If commit timestamps don't tell (no commits were decoded
+// after that LSN), age of the WAL following it according to the ingest-time index.
+// Zero if the consumer has received all WAL.
+//
+fn time_lag(
+    shared_state: &SharedState,
+    index: Option<&IngestIndex>,
+    lsn: XLogRecPtr,
+    now: TimestampTz,
+) -> f64 {
+    let oldest = shared_state
+        .commit_times
+        .iter()
+        .find(|(commit_lsn, _)| *commit_lsn > lsn)
+        .map(|(_, xact_time)| *xact_time)
+        .or_else(|| index.and_then(|index| index.time_after_lsn(lsn)));
+    match oldest {
+        Some(ts) => now.saturating_sub(ts) as f64 / 1_000_000.0,
+        None => 0.0,
+    }
 }
 
 /*
- * Hot standby feedback received from replica
+ * Health of the mirror copy of tenant WAL. After the first failure the mirror is not
+ * written anymore: it would have a hole, so it has to be re-seeded from the primary copy.
  */
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-struct HotStandbyFeedback {
-    ts: TimestampTz,
-    xmin: FullTransactionId,
-    catalog_xmin: FullTransactionId,
+#[derive(Debug, Clone, Default)]
+pub struct MirrorHealth {
+    pub failed_at: Option<XLogRecPtr>, /* start of the append which failed to be mirrored */
+    pub last_error: String,
+    pub mirrored_bytes: u64,
 }
 
 /*
- * Request with WAL message sent from proxy to safekeeper.
+ * Tenant status served by the HTTP API, LSNs are formatted as in Postgres
  */
-#[repr(C)]
-#[derive(Debug)]
-struct SafeKeeperRequest {
-    sender_id: NodeId, /* Sender's node identifier (looks like we do not need it for TCP streaming connection) */
-    begin_lsn: XLogRecPtr, /* start position of message in WAL */
-    end_lsn: XLogRecPtr, /* end position of message in WAL */
-    restart_lsn: XLogRecPtr, /* restart LSN position  (minimal LSN which may be needed by proxy to perform recovery) */
-    commit_lsn: XLogRecPtr,  /* LSN committed by quorum of safekeepers */
+#[derive(Debug, Serialize)]
+pub struct SenderStatus {
+    pub peer: String,
+    pub sent_lsn: String,
+    pub lag_secs: f64,
+    /* reported by replica in standby status updates, None if it hasn't reported */
+    pub write_lsn: Option<String>,
+    pub flush_lsn: Option<String>,
+    pub apply_lsn: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CatchupStatus {
+    pub peer: String,
+    pub start_lsn: String,
+    pub sent_lsn: String,
+    pub remaining_bytes: u64,
+    pub cancelled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TenantStatus {
+    pub id: SystemId,
+    pub epoch: u64,
+    pub flush_lsn: String,
+    pub commit_lsn: String,
+    pub restart_lsn: String,
+    pub remote_consistent_lsn: String,
+    pub archived_lsn: String,
+    pub proposer_connected: bool,
+    pub replicas: usize,
+    pub paused: bool,
+    pub pg_version: u32,
+    pub pg_version_mismatch: bool, /* WAL of another major version was accepted */
+    pub min_replica_flush_lsn: Option<String>, /* None if no replica has reported */
+    pub senders: Vec<SenderStatus>,
+    pub catchups: Vec<CatchupStatus>,
+    pub wal_ops: Vec<WalOpStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WalOpStatus {
+    pub operation: String, /* gc, truncation, archive or restore */
+    pub count: u64,        /* segments, or truncations */
+    pub bytes: u64,
+    pub last: Option<String>, /* RFC 3339 */
 }
 
 /*
- * Report safekeeper state to proxy
+ * Consistent snapshot of the tenant state for status reporting
  */
-#[repr(C)]
-#[derive(Debug)]
-struct SafeKeeperResponse {
-    epoch: u64,
-    flush_lsn: XLogRecPtr,
-    hs_feedback: HotStandbyFeedback,
+#[derive(Debug, Clone)]
+pub struct SystemSnapshot {
+    pub id: SystemId,
+    pub priority: PriorityClass,
+    pub dedicated_runtime: bool,
+    info: SafeKeeperInfo,
+    pub commit_lsn: XLogRecPtr,
+    pub remote_consistent_lsn: XLogRecPtr,
+    pub archived_lsn: XLogRecPtr,
+    pub paused: bool,
+    pub draining: bool,
+    pub paused_appends: u64,
+    pub flow_pauses: u64,
+    pub appends: u64,
+    pub received_bytes: u64,
+    pub sent_bytes: u64,
+    pub clock_skew: Option<i64>, /* usec */
+    pub replicas: usize,
+    pub proposer_connected: bool,
+    pub pg_version_mismatch: bool,
+    catchups: Vec<(SocketAddr, CatchupProgress)>,
+    /* WAL senders: sent LSN, time lag in seconds and progress reported by replica */
+    senders: Vec<(SocketAddr, XLogRecPtr, f64, Option<ReplicaState>)>,
+    pub min_replica_flush_lsn: Option<XLogRecPtr>, /* oldest flush position reported by replicas */
+    pub pageserver_lag: f64, /* time lag of remote_consistent_lsn, seconds */
+    pub segment_preparing: Option<f64>, /* zero-fill of a new segment in progress for that long, seconds */
+    pub spare_segments: usize, /* zero-filled segments ready to be taken */
+    pub mirror: Option<MirrorHealth>, /* None if mirroring is not configured */
+    pub append_latency: Histogram,
+    pub ingest_latency: Histogram,
+    pub fsync_latency: Histogram,
+    pub outbound_depth: usize,
+    pub outbound_stats: OutboundStats,
+    pub wal_ops: WalOpStats,
+}
+
+impl SystemSnapshot {
+    pub fn epoch(&self) -> u64 {
+        self.info.epoch
+    }
+
+    pub fn flush_lsn(&self) -> XLogRecPtr {
+        self.info.flush_lsn
+    }
+
+    pub fn restart_lsn(&self) -> XLogRecPtr {
+        self.info.restart_lsn
+    }
+
+    // commit_lsn of WAL senders starts at zero on load, the control file keeps the last one
+    pub fn known_commit_lsn(&self) -> XLogRecPtr {
+        max(self.commit_lsn, self.info.commit_lsn)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "system {}: priority={:?} runtime={} epoch={} flush_lsn={} commit_lsn={} restart_lsn={} remote_consistent_lsn={} archived_lsn={} pageserver_lag={:.1}s append_latency={:.2}ms paused={} draining={} replicas={} min_replica_flush_lsn={} outbound_queue={} outbound_failures={} clock_skew={} mirror={} pg_version={}{}",
+            self.id,
+            self.priority,
+            if self.dedicated_runtime { "dedicated" } else { "shared" },
+            self.epoch(),
+            format_lsn(self.flush_lsn()),
+            format_lsn(self.commit_lsn),
+            format_lsn(self.restart_lsn()),
+            format_lsn(self.remote_consistent_lsn),
+            format_lsn(self.archived_lsn),
+            self.pageserver_lag,
+            self.append_latency.mean() * 1000.0,
+            self.paused,
+            self.draining,
+            self.replicas,
+            self.min_replica_flush_lsn.map_or("none".to_string(), format_lsn),
+            self.outbound_depth,
+            self.outbound_stats.failures,
+            match self.clock_skew {
+                Some(skew) => format!("{}us", skew),
+                None => "unknown".to_string(),
+            },
+            match &self.mirror {
+                None => "none".to_string(),
+                Some(MirrorHealth {
+                    failed_at: None, ..
+                }) => "healthy".to_string(),
+                Some(MirrorHealth {
+                    failed_at: Some(lsn),
+                    last_error,
+                    ..
+                }) => format!("failed at {} ({})", format_lsn(*lsn), last_error),
+            },
+            self.info.server.pg_version,
+            if self.pg_version_mismatch {
+                " (major version mismatch accepted)"
+            } else {
+                ""
+            }
+        )
+    }
+
+    pub fn status(&self) -> TenantStatus {
+        TenantStatus {
+            id: self.id,
+            epoch: self.epoch(),
+            flush_lsn: format_lsn(self.flush_lsn()),
+            commit_lsn: format_lsn(self.commit_lsn),
+            restart_lsn: format_lsn(self.restart_lsn()),
+            remote_consistent_lsn: format_lsn(self.remote_consistent_lsn),
+            archived_lsn: format_lsn(self.archived_lsn),
+            proposer_connected: self.proposer_connected,
+            replicas: self.replicas,
+            paused: self.paused,
+            pg_version: self.info.server.pg_version,
+            pg_version_mismatch: self.pg_version_mismatch,
+            min_replica_flush_lsn: self.min_replica_flush_lsn.map(format_lsn),
+            senders: self
+                .senders
+                .iter()
+                .map(|(peer, sent_lsn, lag, progress)| SenderStatus {
+                    peer: peer.to_string(),
+                    sent_lsn: format_lsn(*sent_lsn),
+                    lag_secs: *lag,
+                    write_lsn: progress.map(|p| format_lsn(p.write_lsn)),
+                    flush_lsn: progress.map(|p| format_lsn(p.flush_lsn)),
+                    apply_lsn: progress.map(|p| format_lsn(p.apply_lsn)),
+                })
+                .collect(),
+            catchups: self
+                .catchups
+                .iter()
+                .map(|(peer, progress)| CatchupStatus {
+                    peer: peer.to_string(),
+                    start_lsn: format_lsn(progress.start_lsn),
+                    sent_lsn: format_lsn(progress.sent_lsn),
+                    remaining_bytes: self.commit_lsn.saturating_sub(progress.sent_lsn),
+                    cancelled: progress.cancelled,
+                })
+                .collect(),
+            wal_ops: self
+                .wal_ops
+                .counters()
+                .iter()
+                .map(|(operation, counter)| WalOpStatus {
+                    operation: operation.to_string(),
+                    count: counter.count,
+                    bytes: counter.bytes,
+                    last: counter.last.map(|time| {
+                        chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339()
+                    }),
+                })
+                .collect(),
+        }
+    }
+
+    // Operations on WAL which have happened since start
+    pub fn describe_wal_ops(&self) -> Vec<String> {
+        self.wal_ops
+            .counters()
+            .iter()
+            .filter(|(_, counter)| counter.last.is_some())
+            .map(|(operation, counter)| {
+                format!(
+                    "{}: count={} bytes={} last={}",
+                    operation,
+                    counter.count,
+                    counter.bytes,
+                    chrono::DateTime::<chrono::Utc>::from(counter.last.unwrap()).to_rfc3339()
+                )
+            })
+            .collect()
+    }
+
+    pub fn describe_catchups(&self) -> Vec<String> {
+        self.catchups
+            .iter()
+            .map(|(peer, progress)| progress.describe(peer, self.commit_lsn))
+            .collect()
+    }
+
+    pub fn describe_senders(&self) -> Vec<String> {
+        self.senders
+            .iter()
+            .map(|(peer, sent_lsn, lag, progress)| {
+                let reported = match progress {
+                    Some(p) => format!(
+                        " write_lsn={} flush_lsn={} apply_lsn={} reported={:.1}s ago",
+                        format_lsn(p.write_lsn),
+                        format_lsn(p.flush_lsn),
+                        format_lsn(p.apply_lsn),
+                        clock::elapsed(p.updated).as_secs_f64()
+                    ),
+                    None => String::new(),
+                };
+                format!(
+                    "sender {}: sent_lsn={} lag={:.1}s{}",
+                    peer,
+                    format_lsn(*sent_lsn),
+                    lag,
+                    reported
+                )
+            })
+            .collect()
+    }
+
+    pub fn metrics(&self) -> TenantMetrics {
+        TenantMetrics {
+            received_bytes: self.received_bytes,
+            sent_bytes: self.sent_bytes,
+            appends: self.appends,
+            paused_appends: self.paused_appends,
+            flow_pauses: self.flow_pauses,
+            replicas: self.replicas as u64,
+            connections: self.senders.len() as u64 + self.proposer_connected as u64,
+            commit_lag_bytes: self.flush_lsn().saturating_sub(self.commit_lsn),
+            outbound_depth: self.outbound_depth as u64,
+            outbound_failures: self.outbound_stats.failures,
+            catchups: self.catchups.len() as u64,
+            catchup_remaining_bytes: self
+                .catchups
+                .iter()
+                .map(|(_, progress)| self.commit_lsn.saturating_sub(progress.sent_lsn))
+                .sum(),
+            clock_skew_seconds: self.clock_skew.unwrap_or(0) as f64 / 1_000_000.0,
+            sender_lag_seconds: self
+                .senders
+                .iter()
+                .map(|(_, _, lag, _)| *lag)
+                .fold(0.0, f64::max),
+            pageserver_lag_seconds: self.pageserver_lag,
+            segment_preparing_seconds: self.segment_preparing.unwrap_or(0.0),
+            spare_segments: self.spare_segments as u64,
+            mirror_failed: self
+                .mirror
+                .as_ref()
+                .map_or(false, |mirror| mirror.failed_at.is_some())
+                as u64,
+            append_latency: self.append_latency,
+            ingest_latency: self.ingest_latency,
+            fsync_latency: self.fsync_latency,
+            wal_ops: self.wal_ops,
+        }
+    }
 }
 
 /*
@@ -136,10 +562,44 @@ struct SafeKeeperResponse {
  */
 #[derive(Debug)]
 struct SharedState {
-    commit_lsn: XLogRecPtr,          /* quorum commit LSN */
     info: SafeKeeperInfo,            /* information about this safekeeper */
-    control_file: Option<File>, /* opened file control file handle (needed to hold exlusive file lock */
-    hs_feedback: HotStandbyFeedback, /* combined hot standby feedback from all replicas */
+    flushed_restart_lsn: XLogRecPtr, /* restart_lsn last synced to the control file */
+    control_lock: Option<File>,      /* lock file of the tenant, held while the tenant is loaded */
+    control_file: Option<File>, /* current control file, unsynced copies are written to it in place */
+    control_file_path: PathBuf,
+    control_generation: u64, /* generation of the last written copy of the control file */
+    durable_slot: usize,     /* slot of the last synced copy, which is never overwritten */
+    control_file_version: u32, /* format the control file is written in */
+    replicas_feedback: HashMap<SocketAddr, HotStandbyFeedback>, /* hot standby feedback of each connected replica */
+    catchups: HashMap<SocketAddr, CatchupProgress>, /* WAL senders catching up from far behind */
+    senders: HashMap<SocketAddr, XLogRecPtr>,       /* position of each connected WAL sender */
+    replica_states: HashMap<SocketAddr, ReplicaState>, /* standby status of each replica connection */
+    commit_times: VecDeque<(XLogRecPtr, TimestampTz)>, /* recent commit timestamps by LSN, for time lag */
+    mirror: MirrorHealth, /* state of the mirror copy of WAL, if configured */
+    append_latency: Histogram, /* append request received -> flush acknowledged */
+    ingest_latency: Histogram, /* append request received -> applied by a WAL receiver */
+    unapplied_appends: VecDeque<(XLogRecPtr, Instant)>, /* end LSN and receipt time of acknowledged appends */
+    remote_consistent_lsn: XLogRecPtr, /* WAL up to this LSN is checkpointed/uploaded by pageserver */
+    paused: bool,                      /* WAL ingest is paused by administrator */
+    paused_appends: u64,               /* number of appends rejected because of pause */
+    flow_pauses: u64,                  /* number of FLOW_PAUSE sent to proposers */
+    preparing_segment: Option<(XLogSegNo, Instant)>, /* segment being zero-filled, since when */
+    appends: u64,                      /* number of appends written to disk */
+    received_bytes: u64,               /* bytes of WAL written to disk */
+    sent_bytes: u64,                   /* bytes of WAL sent to replication clients */
+    fsync_latency: Histogram,          /* fsync of WAL segment */
+    clock_skew: Option<i64>, /* local time minus the last commit timestamp of proposer, usec */
+    clock_skew_warned: bool, /* clock_skew exceeds max_clock_skew */
+    wal_stats: WalRecordStats, /* received records by resource manager (if enabled) */
+    pg_versions: PgVersionHistory, /* stored in the control file after info */
+    archived_lsn: XLogRecPtr, /* WAL below it is uploaded to --archive, stored after pg_versions */
+    wal_ops: WalOpStats,     /* WAL removed, overwritten, archived and restored since start */
+    gc_proposal: Option<GcProposal>, /* last WAL removal proposed by pageserver, with its progress */
+    gc_cutoff: XLogRecPtr, /* highest confirmed cutoff, bounds WAL GC with --gc-coordinated */
+    at_rest: AtRestPolicy, /* encoding of completed segments, recorded in tenant.toml */
+    at_rest_pending: Vec<String>, /* segments completed since the last pass of the GC task */
+    at_rest_job: Option<RewriteJob>, /* last rewrite of all segments to the policy */
+    spares: SparePool, /* zero-filled segments to be renamed into place, with --prealloc-segments */
 }
 
 /*
@@ -148,125 +608,246 @@ struct SharedState {
 #[derive(Debug)]
 pub struct System {
     id: SystemId,
+    tenant_conf: TenantConf,
     mutex: Mutex<SharedState>,
-    cond: Notify, /* conditional variable used to notify wal senders */
+    /*
+     * Hot fields read by every WAL sender loop iteration are kept outside of the mutex.
+     * They are updated by the WAL receiver only, and never go backwards.
+     */
+    commit_lsn: AtomicU64,                            /* quorum commit LSN */
+    flush_lsn: AtomicU64,                             /* copy of info.flush_lsn */
+    cond: Notify,                   /* conditional variable used to notify wal senders */
+    senders_stopped: AtomicBool,    /* WAL senders exit on drain or unload, commit_lsn is kept */
+    segment_prepared: Notify,       /* wakes up WAL senders waiting for zero-fill of a segment */
+    horizon_changed: Notify, /* wakes up WAL GC and backup when pageserver reports a checkpoint */
+    spares_wanted: Notify, /* wakes up preallocation when a spare segment is taken or recycled */
+    outbound: Mutex<OutboundQueue>, /* pending callbacks, uploads and hooks */
+    runtime: Option<runtime::Handle>, /* dedicated runtime of isolated tenant */
+    runtime_stop: Mutex<Option<oneshot::Sender<()>>>, /* dedicated runtime exits when it is dropped, see unload() */
+    draining: Arc<AtomicBool>, /* draining flag of the registry of the tenant */
+    /*
+     * Connection of the proposer which has voted last, the only one allowed to write WAL.
+     * Locked before the shared state, and held by appends while they write WAL and update
+     * control data, so they can't interleave with writes of a newly elected proposer.
+     */
+    writer: Mutex<Option<u64>>,
+    loading: Mutex<()>, /* serializes loads of the control file, see load_control_file() */
+    superseded: Notify, /* wakes up proposer connections when a new one has voted */
+    object_wal: Mutex<Option<ObjectWal>>, /* staged WAL, with --object-storage only */
+    wal_files: Mutex<WalFileCache>, /* segments kept open by the writer */
+    wal_checksums: Mutex<ChecksumIndex>, /* recorded checksums of completed segments */
+    /*
+     * Ingest time of WAL by LSN, loaded with the control file. Has its own lock, so that
+     * the file is written without holding up users of the shared state.
+     */
+    ingest_index: Mutex<Option<IngestIndex>>,
+    /*
+     * Generation of completed segments overwritten by proposers of new terms, by name.
+     * Bumped under the writer lock on each write, so that at-rest rewrite of a segment,
+     * prepared without the lock, can tell whether the segment has changed.
+     */
+    overwrites: Mutex<HashMap<String, u64>>,
 }
 
 /*
- * Private data
-*/
-#[derive(Debug)]
-struct Connection {
-    system: Option<Arc<System>>,
-    stream: TcpStream,     /* Postgres connection */
-    inbuf: BytesMut,       /* input buffer */
-    outbuf: BytesMut,      /* output buffer */
-    init_done: bool,       /* startup packet proceeded */
-    conf: WalAcceptorConf, /* wal acceptor configuration */
+ * Phase of the proposer protocol. Messages carry no type, so the phase of the session
+ * determines which one is read next; appends are read ahead only when streaming.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProposerState {
+    Handshake, /* waiting for server info */
+    Voting,    /* waiting for vote request */
+    Streaming, /* vote is durably stored and acknowledged, accept WAL */
 }
 
 /*
- * Customer serializer API (TODO: use protobuf?)
+ * Why idle WAL sender woke up
  */
-trait Serializer {
-    fn pack(&self, buf: &mut BytesMut);
-    fn unpack(buf: &mut BytesMut) -> Self;
+enum SenderWakeup {
+    Wal,        /* more WAL is committed */
+    Keepalive,  /* replica should be told that we are alive */
+    Feedback,   /* replica has sent something */
+    Terminated, /* administrator has terminated the connection */
 }
 
-//
-// Implementations
-//
+/*
+ * What WAL sender has got from replica besides feedback
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReplicaInput {
+    Continue,
+    CopyDone, /* replica ends streaming and goes on with the session */
+    Closed,   /* replica has closed the connection or terminated the session */
+}
 
-//Report and return IO error */
-macro_rules! io_error {
-    ($($arg:tt)*) => (error!($($arg)*); return Err(io::Error::new(io::ErrorKind::Other,format!($($arg)*))))
+/*
+ * Why proposer connection waiting for the next message woke up
+ */
+enum ProposerWakeup {
+    Message,    /* proposer has sent something */
+    Heartbeat,  /* heartbeat interval has passed in silence */
+    Superseded, /* another proposer connection has voted */
+    Terminated, /* administrator has terminated the connection */
+    TimedOut,   /* proposer has been silent for proposer_read_timeout */
 }
 
-// Safe hex string parser returning proper result
-fn parse_hex_str(s: &str) -> Result<u64> {
-    if let Ok(val) = u32::from_str_radix(s, 16) {
-        Ok(val as u64)
-    } else {
-        io_error!("Invalid hex number {}", s);
-    }
+/*
+ * Where to continue serving a connection moved to the dedicated runtime of its tenant
+ */
+enum Continuation {
+    ReceiveWal(ServerInfo), /* proposer handshake after server info is received */
+    SendWal,                /* libpq protocol after startup packet */
 }
 
-impl Serializer for NodeId {
-    fn pack(&self, buf: &mut BytesMut) {
-        buf.put_u128_le(self.uuid);
-        buf.put_u64(self.term); // use big endian to provide compatibility with memcmp
-    }
+/*
+ * Acknowledgement of appends deferred to be sent together with the following ones
+ */
+#[derive(Debug)]
+struct PendingAck {
+    epoch: u64,
+    flush_lsn: XLogRecPtr,
+    received_lsn: XLogRecPtr,
+    appends: Vec<(XLogRecPtr, Instant)>, /* end LSN and receipt time of covered appends */
+}
 
-    fn unpack(buf: &mut BytesMut) -> NodeId {
-        NodeId {
-            uuid: buf.get_u128_le(),
-            term: buf.get_u64(), // use big endian to provide compatibility with memcmp
-        }
-    }
+/*
+ * WAL of appends written without sync by group commit, synced and acknowledged together
+ */
+#[derive(Debug)]
+struct UnsyncedWal {
+    end_lsn: XLogRecPtr,
+    commit_lsn: XLogRecPtr, /* of the last append, WAL senders wait for the sync */
+    bytes: usize,
+    since: Instant, /* first of the appends was written */
 }
 
-impl Serializer for ServerInfo {
-    fn pack(&self, buf: &mut BytesMut) {
-        buf.put_u32_le(self.protocol_version);
-        buf.put_u32_le(self.pg_version);
-        self.node_id.pack(buf);
-        buf.put_u64_le(self.system_id);
-        buf.put_u64_le(self.wal_end);
-        buf.put_u32_le(self.timeline);
-        buf.put_u32_le(self.wal_seg_size);
+impl UnsyncedWal {
+    fn is_due(&self, conf: &WalAcceptorConf) -> bool {
+        conf.group_commit_bytes.map_or(false, |bytes| self.bytes >= bytes)
+            || conf
+                .group_commit_delay
+                .map_or(false, |delay| clock::elapsed(self.since) >= delay)
     }
-    fn unpack(buf: &mut BytesMut) -> ServerInfo {
-        ServerInfo {
-            protocol_version: buf.get_u32_le(),
-            pg_version: buf.get_u32_le(),
-            node_id: NodeId::unpack(buf),
-            system_id: buf.get_u64_le(),
-            wal_end: buf.get_u64_le(),
-            timeline: buf.get_u32_le(),
-            wal_seg_size: buf.get_u32_le(),
-        }
+}
+
+/*
+ * Session with the elected proposer, which streams WAL to this connection
+ */
+struct ProposerSession {
+    prop: RequestVote,       /* vote request of the elected proposer */
+    my_info: SafeKeeperInfo, /* control data as of the last update */
+    /*
+     * my_info.flush_lsn is durable WAL, received_lsn includes WAL written without
+     * sync by group commit, which is kept in unsynced until it is synced
+     */
+    received_lsn: XLogRecPtr,
+    timeline: TimeLineID,
+    wal_seg_size: usize,
+    peer_addr: SocketAddr,
+    /*
+     * Scanner verifies record CRCs and collects record statistics (if enabled) and
+     * commit timestamps for clock skew
+     */
+    wal_scanner: WalRecordScanner,
+    rolling_checksum: Option<RollingChecksum>, /* end-to-end checksum of the segment */
+    unsynced: Option<UnsyncedWal>,
+    truncation_logged: bool, /* divergence of WAL is logged once per connection */
+    flow_paused: bool,       /* proposer is asked to pause by flow control */
+}
+
+impl ProposerSession {
+    /* Epoch, flush and received positions reported to the proposer */
+    fn positions(&self) -> (u64, XLogRecPtr, XLogRecPtr) {
+        (
+            self.my_info.epoch,
+            self.my_info.flush_lsn,
+            self.received_lsn,
+        )
     }
 }
 
-impl Serializer for RequestVote {
-    fn pack(&self, buf: &mut BytesMut) {
-        self.node_id.pack(buf);
-        buf.put_u64_le(self.vcl);
-        buf.put_u64_le(self.epoch);
+/*
+ * Epoch switch happen when written WAL record cross the boundary.
+ * The boundary is maximum of last WAL position at this node (FlushLSN) and global
+ * maximum (vcl) determined by safekeeper_proxy during handshake.
+ * Switching epoch means that node completes recovery and start writing in the WAL new data.
+ * Returns the epoch to switch to, if WAL ending at end_pos crosses the boundary.
+ */
+fn epoch_switch(
+    epoch: u64,
+    last_lsn: XLogRecPtr,
+    end_pos: XLogRecPtr,
+    prop: &RequestVote,
+) -> Option<u64> {
+    if epoch < prop.epoch && end_pos > max(last_lsn, prop.vcl) {
+        Some(prop.epoch)
+    } else {
+        None
     }
+}
 
-    fn unpack(buf: &mut BytesMut) -> RequestVote {
-        RequestVote {
-            node_id: NodeId::unpack(buf),
-            vcl: buf.get_u64_le(),
-            epoch: buf.get_u64_le(),
-        }
+/*
+ * Private data
+*/
+#[derive(Debug)]
+struct Connection {
+    system: Option<Arc<System>>,
+    stream: Stream,        /* Postgres connection, plain or TLS */
+    proposer_state: ProposerState,
+    prebuf: BytesMut,           /* data pre-read from the proposer socket */
+    trace: Option<TraceWriter>, /* capture of proposer session */
+    migrated: bool,             /* moved to the dedicated runtime of its tenant */
+    inbuf: BytesMut,            /* input buffer */
+    outbuf: BytesMut,           /* output buffer */
+    init_done: bool,            /* startup packet proceeded */
+    authenticated: bool,        /* libpq client has proven its password, see authenticate */
+    conf: Arc<WalAcceptorConf>, /* wal acceptor configuration, shared with blocking I/O */
+    registration: ConnectionRegistration, /* entry in the list of live connections */
+    large_io_at: Instant,     /* last message which didn't fit in buffers of baseline size */
+    reported_buffers: usize,  /* buffer capacity last reported to diagnostics */
+    pending_ack: Option<PendingAck>, /* appends acknowledged by the next response */
+    tenants: Arc<TenantRegistry>, /* tenants of the wal_acceptor instance */
+    flow_control: bool,         /* proposer understands FLOW_PAUSE and FLOW_RESUME */
+    status_codes: bool,         /* proposer understands STALE_TERM..CORRUPT_WAL */
+    ack_each_append: bool, /* latency-critical proposer, acks of its appends are not deferred */
+    codec: Codec,         /* encoding of proposer messages negotiated by the greeting */
+    protocol_version: u32, /* version settled by the greeting, 0 for legacy proposers */
+}
+
+//
+// Implementations
+//
+
+// Safe hex string parser returning proper result
+fn parse_hex_str(s: &str) -> Result<u64> {
+    if let Ok(val) = u32::from_str_radix(s, 16) {
+        Ok(val as u64)
+    } else {
+        io_error!("Invalid hex number {}", s);
     }
 }
 
-impl Serializer for SafeKeeperInfo {
-    fn pack(&self, buf: &mut BytesMut) {
-        buf.put_u32_le(self.magic);
-        buf.put_u32_le(self.format_version);
-        buf.put_u64_le(self.epoch);
-        self.server.pack(buf);
-        buf.put_u64_le(self.commit_lsn);
-        buf.put_u64_le(self.flush_lsn);
-        buf.put_u64_le(self.restart_lsn);
-    }
-    fn unpack(buf: &mut BytesMut) -> SafeKeeperInfo {
-        SafeKeeperInfo {
-            magic: buf.get_u32_le(),
-            format_version: buf.get_u32_le(),
-            epoch: buf.get_u64_le(),
-            server: ServerInfo::unpack(buf),
-            commit_lsn: buf.get_u64_le(),
-            flush_lsn: buf.get_u64_le(),
-            restart_lsn: buf.get_u64_le(),
+// Parse LSN in %X/%X form
+pub fn parse_lsn(s: &str) -> Result<XLogRecPtr> {
+    let mut parts = s.trim().splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(hi), Some(lo)) => Ok((parse_hex_str(hi)? << 32) | parse_hex_str(lo)?),
+        _ => {
+            io_error!("Invalid LSN {}", s);
         }
     }
 }
 
+// Text of libpq command, which is refused rather than panicked on if it isn't UTF-8
+fn command_str(cmd: &Bytes) -> Result<&str> {
+    str::from_utf8(&cmd[..]).map_err(|_| {
+        sql_error(
+            SQLSTATE_SYNTAX_ERROR,
+            format!("Command {:?} is not valid UTF-8", cmd),
+        )
+    })
+}
+
 impl SafeKeeperInfo {
     fn new() -> SafeKeeperInfo {
         SafeKeeperInfo {
@@ -289,582 +870,6095 @@ impl SafeKeeperInfo {
     }
 }
 
-impl Serializer for HotStandbyFeedback {
-    fn pack(&self, buf: &mut BytesMut) {
-        buf.put_u64_le(self.ts);
-        buf.put_u64_le(self.xmin);
-        buf.put_u64_le(self.catalog_xmin);
+fn pg_major_version(pg_version: u32) -> u32 {
+    /* server_version_num: 90624 is 9.6, 140002 is 14 */
+    if pg_version >= 100000 {
+        pg_version / 10000
+    } else {
+        pg_version / 100
     }
-    fn unpack(buf: &mut BytesMut) -> HotStandbyFeedback {
-        HotStandbyFeedback {
-            ts: buf.get_u64_le(),
-            xmin: buf.get_u64_le(),
-            catalog_xmin: buf.get_u64_le(),
+}
+
+impl PgVersionHistory {
+    fn pack(&self, buf: &mut BytesMut) {
+        buf.put_u32_le(PG_VERSIONS_MAGIC);
+        buf.put_u32_le(self.mismatch_accepted as u32);
+        buf.put_u32_le(self.acked_version);
+        buf.put_u32_le(self.changes.len() as u32);
+        for change in &self.changes {
+            buf.put_u32_le(change.pg_version);
+            buf.put_u32_le(change.timeline);
+            buf.put_u64_le(change.lsn);
         }
     }
-}
 
-impl HotStandbyFeedback {
-    fn parse(body: &Bytes) -> HotStandbyFeedback {
-        HotStandbyFeedback {
-            ts: BigEndian::read_u64(&body[0..8]),
-            xmin: BigEndian::read_u64(&body[8..16]),
-            catalog_xmin: BigEndian::read_u64(&body[16..24]),
+    /* Empty history if the control file doesn't have it */
+    fn unpack(buf: &mut BytesMut) -> PgVersionHistory {
+        let mut history = PgVersionHistory::default();
+        if buf.remaining() < 16 || buf.get_u32_le() != PG_VERSIONS_MAGIC {
+            return history;
+        }
+        history.mismatch_accepted = buf.get_u32_le() != 0;
+        history.acked_version = buf.get_u32_le();
+        let n = buf.get_u32_le() as usize;
+        for _ in 0..n {
+            if buf.remaining() < 16 {
+                break;
+            }
+            history.changes.push(PgVersionChange {
+                pg_version: buf.get_u32_le(),
+                timeline: buf.get_u32_le(),
+                lsn: buf.get_u64_le(),
+            });
         }
+        history
     }
-}
 
-impl Serializer for SafeKeeperRequest {
-    fn pack(&self, buf: &mut BytesMut) {
-        self.sender_id.pack(buf);
-        buf.put_u64_le(self.begin_lsn);
-        buf.put_u64_le(self.end_lsn);
-        buf.put_u64_le(self.restart_lsn);
-        buf.put_u64_le(self.commit_lsn);
+    fn record(&mut self, change: PgVersionChange) {
+        if self.changes.len() == MAX_PG_VERSION_CHANGES {
+            self.changes.remove(0);
+        }
+        self.changes.push(change);
     }
-    fn unpack(buf: &mut BytesMut) -> SafeKeeperRequest {
-        SafeKeeperRequest {
-            sender_id: NodeId::unpack(buf),
-            begin_lsn: buf.get_u64_le(),
-            end_lsn: buf.get_u64_le(),
-            restart_lsn: buf.get_u64_le(),
-            commit_lsn: buf.get_u64_le(),
+
+    pub fn describe(&self) -> String {
+        let mut output = String::new();
+        for change in &self.changes {
+            output += &format!(
+                "pg_version {} on timeline {} since {}\n",
+                change.pg_version,
+                change.timeline,
+                format_lsn(change.lsn)
+            );
         }
+        if self.mismatch_accepted {
+            output += "WAL of another major version was accepted, see ack-pg-version\n";
+        }
+        output
     }
 }
 
-impl Serializer for SafeKeeperResponse {
-    fn pack(&self, buf: &mut BytesMut) {
-        buf.put_u64_le(self.epoch);
-        buf.put_u64_le(self.flush_lsn);
-        self.hs_feedback.pack(buf);
+/*
+ * Contents of the control file
+ */
+struct ControlFileData {
+    info: SafeKeeperInfo,
+    pg_versions: PgVersionHistory,
+    archived_lsn: XLogRecPtr,
+    remote_consistent_lsn: XLogRecPtr, /* not stored in formats before 3 */
+    generation: u64,                   /* copies of older formats are of generation 0 */
+}
+
+impl ControlFileData {
+    //
+    // Parse control file of the current or an older format, returning its contents and
+    // the format version found in the file. Format of a newer safekeeper is refused:
+    // it may keep state which would be lost when the file is written back.
+    //
+    fn parse(content: &[u8]) -> Result<(ControlFileData, u32)> {
+        if content.len() < SafeKeeperInfo::SIZE {
+            io_error!("control file is truncated to {} bytes", content.len());
+        }
+        let mut buf = BytesMut::from(content);
+        let mut info = SafeKeeperInfo::unpack(&mut buf);
+        if info.magic != SK_MAGIC {
+            io_error!("invalid control file magic {:#x}", info.magic);
+        }
+        let format_version = info.format_version;
+        if format_version > CONTROL_FILE_VERSION {
+            io_error!(
+                "control file has format version {}, newer than {} supported by this wal_acceptor",
+                format_version,
+                CONTROL_FILE_VERSION
+            );
+        }
+        if format_version < 1 {
+            io_error!("unknown control file format version {}", format_version);
+        }
+        /* Trailers of version 1 are present only if written by a version knowing them */
+        let pg_versions = PgVersionHistory::unpack(&mut buf);
+        let archived_lsn = if buf.remaining() >= 12 && buf.get_u32_le() == ARCHIVED_LSN_MAGIC {
+            buf.get_u64_le()
+        } else if format_version >= 2 {
+            io_error!(
+                "control file of format version {} has no archived LSN",
+                format_version
+            );
+        } else {
+            0
+        };
+        let (remote_consistent_lsn, generation) = if format_version >= 3 {
+            if buf.remaining() < 20 {
+                io_error!("control file copy is truncated to {} bytes", content.len());
+            }
+            let remote_consistent_lsn = buf.get_u64_le();
+            let generation = buf.get_u64_le();
+            let checksum_offset = content.len() - buf.remaining();
+            let checksum = buf.get_u32_le();
+            if crc32c(&content[..checksum_offset]) != checksum {
+                io_error!(
+                    "control file copy of generation {} has wrong checksum",
+                    generation
+                );
+            }
+            (remote_consistent_lsn, generation)
+        } else {
+            (0, 0)
+        };
+        info.format_version = SK_FORMAT_VERSION;
+        let data = ControlFileData {
+            info: info,
+            pg_versions: pg_versions,
+            archived_lsn: archived_lsn,
+            remote_consistent_lsn: remote_consistent_lsn,
+            generation: generation,
+        };
+        Ok((data, format_version))
     }
-    fn unpack(buf: &mut BytesMut) -> SafeKeeperResponse {
-        SafeKeeperResponse {
-            epoch: buf.get_u64_le(),
-            flush_lsn: buf.get_u64_le(),
-            hs_feedback: HotStandbyFeedback::unpack(buf),
+
+    //
+    // Parse both copies of the control file and pick the valid one of the latest
+    // generation, returning its contents, format version and slot. A copy torn by
+    // crash is skipped with a warning, but a copy of a newer format is refused.
+    // Files of formats before 3 have the only copy in the first slot.
+    //
+    fn parse_file(path: &Path, content: &[u8]) -> Result<(ControlFileData, u32, usize)> {
+        let mut latest: Option<(ControlFileData, u32, usize)> = None;
+        let mut damaged = None;
+        for slot in 0..2 {
+            let start = slot * CONTROL_SLOT_SIZE;
+            if start >= content.len() {
+                break;
+            }
+            let copy = &content[start..min(content.len(), start + CONTROL_SLOT_SIZE)];
+            /* Sectors are not torn, so the header of a copy is either old or new one */
+            let newer = copy.len() >= 8
+                && LittleEndian::read_u32(&copy[0..4]) == SK_MAGIC
+                && LittleEndian::read_u32(&copy[4..8]) > CONTROL_FILE_VERSION;
+            match ControlFileData::parse(copy) {
+                Err(e) if newer => return Err(e),
+                Ok((_, version)) if version < 3 && slot != 0 => {
+                    warn!(
+                        "Copy in slot {} of {:?} has format version {}",
+                        slot, path, version
+                    );
+                }
+                Ok((data, version)) => {
+                    if latest
+                        .as_ref()
+                        .map_or(true, |l| data.generation > l.0.generation)
+                    {
+                        latest = Some((data, version, slot));
+                    }
+                }
+                Err(e) => {
+                    /* Zeroes of a slot not written yet are not a damage */
+                    if copy.iter().any(|b| *b != 0) {
+                        warn!("Copy in slot {} of {:?} is damaged: {}", slot, path, e);
+                    }
+                    damaged.get_or_insert(e);
+                }
+            }
+        }
+        match latest {
+            Some(latest) => Ok(latest),
+            None => Err(damaged.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "no valid copy of control file")
+            })),
         }
     }
 }
 
-lazy_static! {
-    pub static ref SYSTEMS: Mutex<HashMap<SystemId, Arc<System>>> = Mutex::new(HashMap::new());
+//
+// Pack a copy of control file of the given format, to be written to a slot. Formats
+// between MIN_CONTROL_FILE_VERSION and CONTROL_FILE_VERSION can be written: the older
+// one is a downgrade of the current state, readable by wal_acceptor of the previous
+// release. Remote consistent LSN and generation of the copy are not stored in formats
+// before 3.
+//
+fn pack_control_file(
+    info: &SafeKeeperInfo,
+    pg_versions: &PgVersionHistory,
+    archived_lsn: XLogRecPtr,
+    remote_consistent_lsn: XLogRecPtr,
+    generation: u64,
+    format_version: u32,
+    buf: &mut BytesMut,
+) {
+    assert!(format_version >= MIN_CONTROL_FILE_VERSION && format_version <= CONTROL_FILE_VERSION);
+    let start = buf.len();
+    let mut info = *info;
+    info.format_version = format_version;
+    info.pack(buf);
+    pg_versions.pack(buf);
+    buf.put_u32_le(ARCHIVED_LSN_MAGIC);
+    buf.put_u64_le(archived_lsn);
+    if format_version >= 3 {
+        buf.put_u64_le(remote_consistent_lsn);
+        buf.put_u64_le(generation);
+        let checksum = crc32c(&buf[start..]);
+        buf.put_u32_le(checksum);
+    }
+    assert!(buf.len() - start <= CONTROL_SLOT_SIZE);
 }
 
-pub fn thread_main(conf: WalAcceptorConf) {
-    // Create a new thread pool
-    //
-    // FIXME: keep it single-threaded for now, make it easier to debug with gdb,
-    // and we're not concerned with performance yet.
-    //let runtime = runtime::Runtime::new().unwrap();
-    let runtime = runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .unwrap();
-
-    info!("Starting wal acceptor on {}", conf.listen_addr);
+//
+// Put the copy packed by pack_control_file() to both slots, to be written as the whole
+// control file. Formats before 3 have the only copy.
+//
+fn fill_control_slots(buf: &mut BytesMut, format_version: u32) {
+    if format_version >= 3 {
+        let copy = buf.clone();
+        buf.resize(CONTROL_SLOT_SIZE, 0u8);
+        buf.extend_from_slice(&copy);
+    }
+}
 
-    runtime.block_on(async {
-        let _unused = main_loop(&conf).await;
-    });
+/*
+ * Message received from replica in CopyData during streaming
+ */
+#[derive(Debug)]
+enum ReplicaMessage {
+    HotStandbyFeedback(HotStandbyFeedback),
+    NoHotStandbyFeedback, /* standby doesn't hold back vacuum anymore */
+    /* 'r' message of standard standby */
+    StatusUpdate {
+        write_lsn: XLogRecPtr, /* WAL up to this LSN is written by replica */
+        flush_lsn: XLogRecPtr, /* WAL up to this LSN is flushed by replica */
+        apply_lsn: XLogRecPtr, /* WAL up to this LSN is applied (replayed or ingested by pageserver) */
+        reply_requested: bool,
+    },
+    Unknown,
 }
 
-async fn main_loop(conf: &WalAcceptorConf) -> Result<()> {
-    let listener = TcpListener::bind(conf.listen_addr.to_string().as_str()).await?;
-    loop {
-        match listener.accept().await {
-            Ok((socket, peer_addr)) => {
-                debug!("accepted connection from {}", peer_addr);
-                socket.set_nodelay(true)?;
-                let mut conn = Connection::new(socket, &conf);
-                task::spawn(async move {
-                    if let Err(err) = conn.run().await {
-                        error!("error: {}", err);
+impl ReplicaMessage {
+    //
+    // Zenith computes send bare feedback (timestamp, full xmin and catalog xmin),
+    // standard standbys send 'h' and 'r' messages of the streaming replication protocol.
+    //
+    fn parse(body: &Bytes) -> ReplicaMessage {
+        const STANDBY_HS_FEEDBACK_SIZE: usize = 1 + 8 + 4 * 4;
+        const STANDBY_STATUS_UPDATE_SIZE: usize = 1 + 8 * 4 + 1;
+        if body.len() == HotStandbyFeedback::SIZE {
+            return ReplicaMessage::HotStandbyFeedback(HotStandbyFeedback {
+                ts: BigEndian::read_u64(&body[0..8]),
+                xmin: BigEndian::read_u64(&body[8..16]),
+                catalog_xmin: BigEndian::read_u64(&body[16..24]),
+            });
+        }
+        match body.first() {
+            Some(b'h') if body.len() >= STANDBY_HS_FEEDBACK_SIZE => {
+                let full_xid = |offs: usize| {
+                    let xid = BigEndian::read_u32(&body[offs..offs + 4]) as u64;
+                    let epoch = BigEndian::read_u32(&body[offs + 4..offs + 8]) as u64;
+                    /* InvalidTransactionId means no constraint */
+                    if xid == 0 {
+                        u64::MAX
+                    } else {
+                        (epoch << 32) | xid
                     }
-                });
+                };
+                let feedback = HotStandbyFeedback {
+                    ts: BigEndian::read_u64(&body[1..9]),
+                    xmin: full_xid(9),
+                    catalog_xmin: full_xid(17),
+                };
+                if feedback.xmin == u64::MAX && feedback.catalog_xmin == u64::MAX {
+                    ReplicaMessage::NoHotStandbyFeedback
+                } else {
+                    ReplicaMessage::HotStandbyFeedback(feedback)
+                }
             }
-            Err(e) => error!("Failed to accept connection: {}", e),
+            Some(b'r') if body.len() >= STANDBY_STATUS_UPDATE_SIZE => {
+                ReplicaMessage::StatusUpdate {
+                    write_lsn: BigEndian::read_u64(&body[1..9]),
+                    flush_lsn: BigEndian::read_u64(&body[9..17]),
+                    apply_lsn: BigEndian::read_u64(&body[17..25]),
+                    reply_requested: body[STANDBY_STATUS_UPDATE_SIZE - 1] != 0,
+                }
+            }
+            _ => ReplicaMessage::Unknown,
         }
     }
 }
 
-impl System {
-    pub fn new(id: SystemId) -> System {
-        let shared_state = SharedState {
-            commit_lsn: 0,
-            info: SafeKeeperInfo::new(),
-            control_file: None,
-            hs_feedback: HotStandbyFeedback {
-                ts: 0,
-                xmin: u64::MAX,
-                catalog_xmin: u64::MAX,
-            },
-        };
-        System {
-            id: id,
-            mutex: Mutex::new(shared_state),
-            cond: Notify::new(),
+impl WalRecordStats {
+    fn new() -> WalRecordStats {
+        WalRecordStats {
+            records: [0; RM_MAX_ID + 1],
+            bytes: [0; RM_MAX_ID + 1],
         }
     }
 
-    // Notify caught-up WAL senders about new WAL data received
-    fn notify_wal_senders(&self, commit_lsn: XLogRecPtr) {
-        let mut shared_state = self.mutex.lock().unwrap();
-        if shared_state.commit_lsn < commit_lsn {
-            shared_state.commit_lsn = commit_lsn;
-            self.cond.notify_waiters();
-        }
+    fn account(&mut self, rmid: u8, len: u32) {
+        self.records[rmid as usize] += 1;
+        self.bytes[rmid as usize] += len as u64;
     }
+}
+
+//
+// Tenants of a wal_acceptor instance. It is owned by the instance and passed to its
+// connections, admin socket and HTTP API, so that instances embedded in one process
+// don't share tenants, and shutdown of an instance unloads all of them.
+//
+#[derive(Debug, Default)]
+pub struct TenantRegistry {
+    systems: Mutex<HashMap<SystemId, Arc<System>>>,
+    draining: Arc<AtomicBool>, /* set by drain: new connections are rejected */
+    listeners: ListenerSet,    /* listening sockets of the instance */
+    released: Mutex<HashSet<SystemId>>, /* tenants taken over by another process */
+}
 
-    fn _stop_wal_senders(&self) {
-        self.notify_wal_senders(END_REPLICATION_MARKER);
+impl TenantRegistry {
+    pub fn new() -> Arc<TenantRegistry> {
+        Arc::new(TenantRegistry::default())
     }
 
-    fn get_info(&self) -> SafeKeeperInfo {
-        return self.mutex.lock().unwrap().info;
+    pub fn listeners(&self) -> &ListenerSet {
+        &self.listeners
     }
 
-    fn set_info(&self, info: &SafeKeeperInfo) {
-        self.mutex.lock().unwrap().info = *info;
+    // Get system by identifier, if it is known to this safekeeper
+    pub fn get_system(&self, id: SystemId) -> Option<Arc<System>> {
+        SYSTEMS_LOCK.lock(&self.systems).get(&id).cloned()
     }
 
-    // Accumulate hot standby feedbacks from replicas
-    fn add_hs_feedback(&self, feedback: HotStandbyFeedback) {
-        let mut shared_state = self.mutex.lock().unwrap();
-        shared_state.hs_feedback.xmin = min(shared_state.hs_feedback.xmin, feedback.xmin);
-        shared_state.hs_feedback.catalog_xmin =
-            min(shared_state.hs_feedback.catalog_xmin, feedback.catalog_xmin);
-        shared_state.hs_feedback.ts = max(shared_state.hs_feedback.ts, feedback.ts);
+    // Get all systems known to this safekeeper, ordered by identifier
+    pub fn get_systems(&self) -> Vec<Arc<System>> {
+        let mut systems: Vec<Arc<System>> =
+            SYSTEMS_LOCK.lock(&self.systems).values().cloned().collect();
+        systems.sort_by_key(|system| system.id);
+        systems
     }
 
-    fn get_hs_feedback(&self) -> HotStandbyFeedback {
-        let shared_state = self.mutex.lock().unwrap();
-        return shared_state.hs_feedback;
+    // Forget system, as if safekeeper is restarted. It must have no active connections.
+    pub fn unload_system(&self, id: SystemId) {
+        let system = SYSTEMS_LOCK.lock(&self.systems).remove(&id);
+        if let Some(system) = system {
+            system.unload();
+        }
     }
 
-    // Load and lock control file (prevent running more than one instance of safekeeper
-    fn load_control_file(&self, conf: &WalAcceptorConf) {
-        let control_file_path = conf
-            .data_dir
-            .join(self.id.to_string())
-            .join(CONTROL_FILE_NAME);
-        match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&control_file_path)
-        {
-            Ok(file) => {
-                // Lock file to prevent two or more active wal_acceptors
-                match file.try_lock_exclusive() {
-                    Ok(()) => {}
-                    Err(e) => {
-                        panic!(
-                            "Control file {:?} is locked by some other process: {}",
-                            &control_file_path, e
-                        );
-                    }
-                }
-                let mut shared_state = self.mutex.lock().unwrap();
-                shared_state.control_file = Some(file);
-
-                const SIZE: usize = mem::size_of::<SafeKeeperInfo>();
-                let mut buf = [0u8; SIZE];
-                if shared_state
-                    .control_file
-                    .as_mut()
-                    .unwrap()
-                    .read_exact(&mut buf)
-                    .is_ok()
-                {
-                    let mut input = BytesMut::new();
-                    input.extend_from_slice(&buf);
-                    let my_info = SafeKeeperInfo::unpack(&mut input);
+    //
+    // Forget all tenants when the instance is shut down: stop their WAL senders and
+    // dedicated runtimes, and release their locks, so that the data directory can be
+    // served again in this process. Returns number of unloaded tenants.
+    //
+    pub fn unload_all(&self) -> usize {
+        let systems: Vec<Arc<System>> = SYSTEMS_LOCK
+            .lock(&self.systems)
+            .drain()
+            .map(|(_, system)| system)
+            .collect();
+        for system in &systems {
+            system.unload();
+        }
+        self.draining.store(false, Ordering::SeqCst);
+        systems.len()
+    }
 
-                    if my_info.magic != SK_MAGIC {
-                        panic!("Invalid control file magic: {}", my_info.magic);
-                    }
-                    if my_info.format_version != SK_FORMAT_VERSION {
-                        panic!(
-                            "Incompatible format version: {} vs. {}",
-                            my_info.format_version, SK_FORMAT_VERSION
-                        );
-                    }
-                    shared_state.info = my_info;
-                }
-            }
-            Err(e) => {
-                panic!(
-                    "Failed to open control file {:?}: {}",
-                    &control_file_path, e
-                );
-            }
+    //
+    // Prepare safekeeper for shutdown or takeover: reject new connections,
+    // pause WAL ingest and stop WAL senders of all tenants.
+    // Returns number of drained tenants.
+    //
+    pub fn drain(&self) -> usize {
+        self.draining.store(true, Ordering::SeqCst);
+        let systems = self.get_systems();
+        for system in &systems {
+            system.set_paused(true);
+            system.stop_wal_senders();
         }
+        systems.len()
     }
 
-    fn save_control_file(&self, sync: bool) -> Result<()> {
-        let mut buf = BytesMut::new();
-        let mut shared_state = self.mutex.lock().unwrap();
-        shared_state.info.pack(&mut buf);
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
 
-        let file = shared_state.control_file.as_mut().unwrap();
-        file.seek(SeekFrom::Start(0))?;
-        file.write_all(&mut buf[..])?;
-        if sync {
-            file.sync_all()?;
-        }
-        Ok(())
+    //
+    // Identifiers of tenants loaded by this safekeeper or having a directory in the data
+    // directory, ordered
+    //
+    pub fn list_tenants(&self, conf: &WalAcceptorConf) -> Result<Vec<SystemId>> {
+        let mut ids: Vec<SystemId> = SYSTEMS_LOCK.lock(&self.systems).keys().copied().collect();
+        ids.extend(tenant_dirs(conf)?);
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
     }
 }
 
-impl Connection {
-    pub fn new(socket: TcpStream, conf: &WalAcceptorConf) -> Connection {
-        Connection {
-            system: None,
-            stream: socket,
-            inbuf: BytesMut::with_capacity(10 * 1024),
-            outbuf: BytesMut::with_capacity(10 * 1024),
-            init_done: false,
-            conf: conf.clone(),
+//
+// Pre-provision tenant: create its directory and control file, and optionally
+// seed WAL segments from a backup location, before the first proposer connects.
+// The tenant lock is held meanwhile, so a served tenant is refused with AlreadyExists
+// error. Returns number of seeded WAL segments.
+//
+pub fn init_tenant(
+    conf: &WalAcceptorConf,
+    id: SystemId,
+    timeline: Option<TimeLineID>,
+    from_backup: Option<&str>,
+) -> Result<usize> {
+    let system_dir = tenant_dir(&conf.data_dir, id);
+    fs::create_dir_all(&system_dir)?;
+    /* Control file of the served tenant, even if it is still empty, is not replaced */
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(system_dir.join(CONTROL_LOCK_FILE_NAME))?;
+    if let Err(e) = lock.try_lock_exclusive() {
+        let msg = format!("Tenant {} is served: {}", id, e);
+        error!("{}", msg);
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+    }
+    tenant_takeover::record_holder(&lock)?;
+    let control_file_path = system_dir.join(CONTROL_FILE_NAME);
+    if control_file_path.exists() && fs::metadata(&control_file_path)?.len() != 0 {
+        io_error!("Tenant {} is already initialized", id);
+    }
+
+    let mut seeded = 0;
+    if let Some(url) = from_backup {
+        let backup_dir = match url.strip_prefix("file://") {
+            Some(path) => PathBuf::from(path),
+            None if !url.contains("://") => PathBuf::from(url),
+            None => {
+                io_error!("Unsupported backup location {}", url);
+            }
+        };
+        for entry in fs::read_dir(&backup_dir)? {
+            let entry = entry?;
+            let entry_name = entry.file_name();
+            let fname = match entry_name.to_str() {
+                Some(fname) => fname,
+                None => continue,
+            };
+            if IsXLogFileName(fname) || IsPartialXLogFileName(fname) {
+                fs::copy(entry.path(), system_dir.join(fname))?;
+                File::open(system_dir.join(fname))?.sync_all()?;
+                seeded += 1;
+            }
         }
     }
 
-    fn system(&self) -> Arc<System> {
-        self.system.as_ref().unwrap().clone()
+    let mut info = SafeKeeperInfo::new();
+    info.server.system_id = id;
+    if let Some(tli) = timeline {
+        info.server.timeline = tli;
     }
+    let format_version = conf.control_file_version.unwrap_or(CONTROL_FILE_VERSION);
+    let mut buf = BytesMut::new();
+    pack_control_file(
+        &info,
+        &PgVersionHistory::default(),
+        0,
+        0,
+        0,
+        format_version,
+        &mut buf,
+    );
+    let tmp_path = system_dir.join(CONTROL_TMP_FILE_NAME);
+    let mut control_file = File::create(&tmp_path)?;
+    control_file.write_all(&buf)?;
+    control_file.sync_all()?;
+    fs::rename(&tmp_path, &control_file_path)?;
+    File::open(&system_dir)?.sync_all()?;
+    /* Tenant is explicitly created again after deletion */
+    tombstone::remove(&conf.data_dir, id)?;
+    info!(
+        "Tenant {} is initialized in {:?} with {} WAL segments",
+        id, system_dir, seeded
+    );
+    Ok(seeded)
+}
 
-    async fn run(&mut self) -> Result<()> {
-        self.inbuf.resize(4, 0u8);
-        self.stream.read_exact(&mut self.inbuf[0..4]).await?;
-        let startup_pkg_len = BigEndian::read_u32(&mut self.inbuf[0..4]);
-        if startup_pkg_len == 0 {
-            self.receive_wal().await?; // internal protocol between wal_proposer and wal_acceptor
-        } else {
-            self.send_wal().await?; // libpq replication protocol between wal_acceptor and replicas/pagers
-        }
-        Ok(())
+//
+// Import WAL segments from a directory or a tar archive into the tenant, which must not
+// be served and must have no WAL yet, see wal_import.rs. The control file is created
+// or updated with positions given by the manifest. Import refused because of the state
+// of the tenant fails with AlreadyExists error.
+//
+pub fn import_wal(
+    conf: &WalAcceptorConf,
+    id: SystemId,
+    source: &Path,
+    manifest: Option<&Path>,
+) -> Result<ImportPlan> {
+    if conf.object_storage.is_some() {
+        io_error!("WAL import is not supported with object storage");
     }
+    let system_dir = tenant_dir(&conf.data_dir, id);
+    fs::create_dir_all(&system_dir)?;
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(system_dir.join(CONTROL_LOCK_FILE_NAME))?;
+    if let Err(e) = lock.try_lock_exclusive() {
+        let msg = format!("Tenant {} is served, it must be released first: {}", id, e);
+        error!("{}", msg);
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+    }
+    tenant_takeover::record_holder(&lock)?;
 
-    async fn read_req<T: Serializer>(&mut self) -> Result<T> {
-        let size = mem::size_of::<T>();
-        self.inbuf.resize(size, 0u8);
-        self.stream.read_exact(&mut self.inbuf[0..size]).await?;
-        Ok(T::unpack(&mut self.inbuf))
+    let control_file_path = system_dir.join(CONTROL_FILE_NAME);
+    let content = match fs::read(&control_file_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let data = if content.is_empty() {
+        None
+    } else {
+        Some(ControlFileData::parse_file(&control_file_path, &content)?.0)
+    };
+    let flush_lsn = data.as_ref().map_or(0, |data| data.info.flush_lsn);
+    recover_import(conf, id, &system_dir, flush_lsn)?;
+    if flush_lsn != 0 || wal_import::has_wal(&system_dir)? {
+        let msg = format!("Tenant {} already has WAL", id);
+        error!("{}", msg);
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
     }
 
-    async fn request_callback(&self) -> std::result::Result<(), Error> {
-        if let Some(addr) = self.conf.pageserver_addr {
-            let ps_connstr = format!(
-                "host={} port={} dbname={} user={}",
-                addr.ip(),
-                addr.port(),
-                "no_db",
-                "no_user",
-            );
-            let callme = format!(
-                "callmemaybe host={} port={} replication=1 options='-c system.id={}'",
-                self.conf.listen_addr.ip(),
-                self.conf.listen_addr.port(),
-                self.system().get_info().server.system_id,
-            );
-            let (client, connection) = connect(&ps_connstr, NoTls).await?;
+    let plan = wal_import::stage(
+        &system_dir,
+        id,
+        source,
+        manifest,
+        conf.pg_wal_layout,
+        conf.no_sync,
+    )?;
+    let (mut info, pg_versions, archived_lsn, remote_consistent_lsn, generation) = match data {
+        Some(data) => (
+            data.info,
+            data.pg_versions,
+            data.archived_lsn,
+            data.remote_consistent_lsn,
+            data.generation + 1,
+        ),
+        None => (SafeKeeperInfo::new(), PgVersionHistory::default(), 0, 0, 0),
+    };
+    info.server.system_id = id;
+    info.server.timeline = plan.timeline;
+    info.server.wal_seg_size = plan.wal_seg_size as u32;
+    info.server.wal_end = plan.flush_lsn;
+    if let Some(pg_version) = plan.pg_version {
+        info.server.pg_version = pg_version;
+    }
+    info.flush_lsn = plan.flush_lsn;
+    info.commit_lsn = plan.commit_lsn;
+    info.restart_lsn = plan.start_lsn;
+    let format_version = conf.control_file_version.unwrap_or(CONTROL_FILE_VERSION);
+    let mut buf = BytesMut::new();
+    pack_control_file(
+        &info,
+        &pg_versions,
+        archived_lsn,
+        remote_consistent_lsn,
+        generation,
+        format_version,
+        &mut buf,
+    );
+    fill_control_slots(&mut buf, format_version);
+    let tmp_path = system_dir.join(CONTROL_TMP_FILE_NAME);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
 
-            // The connection object performs the actual communication with the database,
-            // so spawn it off to run on its own.
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    error!("pageserver connection error: {}", e);
-                }
-            });
-            client.simple_query(&callme).await?;
+    /* Renaming the control file into place commits the import */
+    let res = wal_import::install(&system_dir, &plan, conf.no_sync)
+        .and_then(|_| fs::rename(&tmp_path, &control_file_path));
+    if let Err(e) = res {
+        if let Err(rollback_err) = recover_import(conf, id, &system_dir, flush_lsn) {
+            error!(
+                "Failed to roll back WAL import of tenant {}: {}",
+                id, rollback_err
+            );
         }
-        Ok(())
+        return Err(e);
     }
+    File::open(&system_dir)?.sync_all()?;
+    wal_import::finish(&system_dir)?;
+    /* Tenant is explicitly created again after deletion */
+    tombstone::remove(&conf.data_dir, id)?;
+    info!("Tenant {} imported {}", id, plan.describe());
+    Ok(plan)
+}
 
-    fn set_system(&mut self, id: SystemId) -> Result<()> {
-        let mut systems = SYSTEMS.lock().unwrap();
-        if id == 0 {
-            // non-multitenant configuration: just a single instance
-            if let Some(system) = systems.values().next() {
-                self.system = Some(system.clone());
-                return Ok(());
-            }
-            io_error!("No active instances");
+//
+// Sort out WAL import of the tenant interrupted before it was committed by the control
+// file with flush_lsn of the import: remove segments it has installed.
+//
+fn recover_import(
+    conf: &WalAcceptorConf,
+    id: SystemId,
+    system_dir: &Path,
+    flush_lsn: XLogRecPtr,
+) -> Result<()> {
+    let plan = match wal_import::interrupted(system_dir)? {
+        Some(plan) => plan,
+        None => return Ok(()),
+    };
+    if flush_lsn == plan.flush_lsn {
+        return wal_import::finish(system_dir);
+    }
+    let entry = recovery_log::Entry::new(
+        recovery_log::Action::ImportRollback,
+        id,
+        "WAL import was interrupted before the control file was written".to_string(),
+        "WAL import recovery".to_string(),
+    )
+    .lsns(plan.start_lsn, plan.flush_lsn)
+    .files(wal_import::installed_files(system_dir, &plan));
+    recovery_log::record(&conf.data_dir, &entry)?;
+    wal_import::roll_back(system_dir, &plan)
+}
+
+//
+// Identifiers of tenants having a directory in the data directory, ordered
+//
+fn tenant_dirs(conf: &WalAcceptorConf) -> Result<Vec<SystemId>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(&conf.data_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
         }
-        if !systems.contains_key(&id) {
-            let system_dir = self.conf.data_dir.join(id.to_string());
-            fs::create_dir_all(system_dir)?;
-            systems.insert(id, Arc::new(System::new(id)));
+        if let Ok(id) = parse_tenant_id(&entry.file_name().to_string_lossy()) {
+            ids.push(id);
         }
-        self.system = Some(systems.get(&id).unwrap().clone());
-        Ok(())
     }
+    ids.sort();
+    Ok(ids)
+}
 
-    // Receive WAL from wal_proposer
-    async fn receive_wal(&mut self) -> Result<()> {
-        // Receive information about server
-        let server_info = self.read_req::<ServerInfo>().await?;
+//
+// Convert control files of all tenants in the data directory to --control-file-version,
+// at startup before any tenant is loaded, so that tenants which are not loaded before
+// rollback to the previous release are readable by it as well. Tenants locked by another
+// wal_acceptor (e.g. the one handing over to this one) are converted when loaded.
+// Returns number of converted control files.
+//
+pub fn convert_control_files(conf: &WalAcceptorConf) -> Result<usize> {
+    let target_version = conf.control_file_version.unwrap_or(CONTROL_FILE_VERSION);
+    let mut converted = 0;
+    for id in tenant_dirs(conf)? {
+        let system_dir = tenant_dir(&conf.data_dir, id);
+        let control_file_path = system_dir.join(CONTROL_FILE_NAME);
+        if !control_file_path.exists() {
+            continue;
+        }
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(system_dir.join(CONTROL_LOCK_FILE_NAME))?;
+        if let Err(e) = lock.try_lock_exclusive() {
+            warn!(
+                "Control file of tenant {} is not converted, it is locked: {}",
+                id, e
+            );
+            continue;
+        }
+        let content = fs::read(&control_file_path)?;
+        if content.is_empty() {
+            continue;
+        }
+        let (data, format_version, _) =
+            match ControlFileData::parse_file(&control_file_path, &content) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    io_error!("Can't convert control file {:?}: {}", &control_file_path, e);
+                }
+            };
+        if format_version == target_version {
+            continue;
+        }
+        let mut buf = BytesMut::new();
+        pack_control_file(
+            &data.info,
+            &data.pg_versions,
+            data.archived_lsn,
+            data.remote_consistent_lsn,
+            data.generation + 1,
+            target_version,
+            &mut buf,
+        );
+        fill_control_slots(&mut buf, target_version);
+        let tmp_path = system_dir.join(CONTROL_TMP_FILE_NAME);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &control_file_path)?;
+        File::open(&system_dir)?.sync_all()?;
         info!(
-            "Start handshake with wal_proposer {} sysid {}",
-            self.stream.peer_addr()?,
-            server_info.system_id
+            "Control file {:?} is converted from format version {} to {}",
+            &control_file_path, format_version, target_version
         );
-        self.set_system(server_info.system_id)?;
-        self.system().load_control_file(&self.conf);
-
-        let mut my_info = self.system().get_info();
+        converted += 1;
+    }
+    Ok(converted)
+}
 
-        /* Check protocol compatibility */
-        if server_info.protocol_version != SK_PROTOCOL_VERSION {
-            io_error!(
-                "Incompatible protocol version {} vs. {}",
-                server_info.protocol_version,
-                SK_PROTOCOL_VERSION
-            );
+//
+// Startup checks of tenants for wal_acceptor --dry-run, done without modifying them:
+// each tenant in the data directory is not locked by a running wal_acceptor, its
+// control file has a valid copy of a known format, tenant configuration and
+// outbound queue can be loaded, and WAL has no gaps up to flush_lsn.
+// Returns number of checked tenants and descriptions of the problems found.
+//
+pub fn check_tenants(conf: &WalAcceptorConf) -> (usize, Vec<String>) {
+    let mut problems = Vec::new();
+    let ids = match tenant_dirs(conf) {
+        Ok(ids) => ids,
+        Err(e) => {
+            problems.push(format!("cannot list tenants: {}", e));
+            return (0, problems);
         }
-        /* Postgres upgrade is not treated as fatal error */
-        if server_info.pg_version != my_info.server.pg_version
-            && my_info.server.pg_version != UNKNOWN_SERVER_VERSION
-        {
-            info!(
-                "Server version doesn't match {} vs. {}",
-                server_info.pg_version, my_info.server.pg_version
-            );
+    };
+    for id in &ids {
+        if let Err(e) = check_tenant(conf, *id) {
+            problems.push(format!("tenant {}: {}", id, e));
         }
-        /* Update information about server, but preserve locally stored node_id */
-        let node_id = my_info.server.node_id;
-        my_info.server = server_info;
-        my_info.server.node_id = node_id;
-
-        /* Calculate WAL end based on local data */
-        let (flush_lsn, timeline) = self.find_end_of_wal(true);
-        my_info.flush_lsn = flush_lsn;
-        my_info.server.timeline = timeline;
-
-        /* Report my identifier to proxy */
-        self.start_sending();
-        my_info.pack(&mut self.outbuf);
-        self.send().await?;
+    }
+    (ids.len(), problems)
+}
 
-        /* Wait for vote request */
-        let prop = self.read_req::<RequestVote>().await?;
-        /* This is Paxos check which should ensure that only one master can perform commits */
-        if prop.node_id < my_info.server.node_id {
-            /* Send my node-id to inform proxy that it's candidate was rejected */
-            self.start_sending();
-            my_info.server.node_id.pack(&mut self.outbuf);
-            self.send().await?;
+fn check_tenant(conf: &WalAcceptorConf, id: SystemId) -> Result<()> {
+    let system_dir = tenant_dir(&conf.data_dir, id);
+    TenantConf::load(&system_dir)?;
+    OutboundQueue::load(&system_dir)?;
+    let control_file_path = system_dir.join(CONTROL_FILE_NAME);
+    if !control_file_path.exists() {
+        return Ok(());
+    }
+    let lock_path = system_dir.join(CONTROL_LOCK_FILE_NAME);
+    if lock_path.exists() {
+        if let Err(e) = File::open(&lock_path)?.try_lock_shared() {
+            io_error!("tenant is locked by running wal_acceptor: {}", e);
+        }
+    }
+    let mut file = File::open(&control_file_path)?;
+    if let Err(e) = file.try_lock_shared() {
+        io_error!("control file is locked by running wal_acceptor: {}", e);
+    }
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    if content.is_empty() {
+        return Ok(());
+    }
+    /* Older formats are fine, they are upgraded when the tenant is loaded */
+    let (data, _, _) = ControlFileData::parse_file(&control_file_path, &content)?;
+    let wal_seg_size = data.info.server.wal_seg_size as usize;
+    if wal_seg_size != 0 && conf.object_storage.is_none() {
+        let gaps = find_wal_gaps(&system_dir, wal_seg_size, data.info.flush_lsn)?;
+        if !gaps.is_empty() {
             io_error!(
-                "Reject connection attempt with term {} because my term is {}",
-                prop.node_id.term,
-                my_info.server.node_id.term
+                "{} gaps in WAL up to flush_lsn {}, see wal-gaps admin command",
+                gaps.len(),
+                format_lsn(data.info.flush_lsn)
             );
         }
-        my_info.server.node_id = prop.node_id;
-        self.system().set_info(&my_info);
-        /* Need to persist our vote first */
-        self.system().save_control_file(true)?;
-
-        let mut flushed_restart_lsn: XLogRecPtr = 0;
-        let wal_seg_size = server_info.wal_seg_size as usize;
-
-        /* Acknowledge the proposed candidate by returning it to the proxy */
-        self.start_sending();
-        prop.node_id.pack(&mut self.outbuf);
-        self.send().await?;
+    }
+    Ok(())
+}
 
-        // Need to establish replication channel with page server.
-        // Add far as replication in postgres is initiated by receiver, we should use callme mechanism
-        if let Err(e) = self.request_callback().await {
-            // Do not treate it as fatal error and continue work
-            error!("Failed to send callme request to pageserver: {}", e);
+//
+// Give the tenant up to another wal_acceptor process taking it over, see tenant_takeover.rs:
+// fence and disconnect its proposer, stop its WAL senders, make the final flush and unload
+// it, releasing its lock. Connections of the tenant are refused afterwards. If the final
+// flush fails, the tenant stays loaded and paused.
+//
+pub fn release_tenant(
+    conf: &WalAcceptorConf,
+    tenants: &TenantRegistry,
+    id: SystemId,
+) -> Result<TenantFlush> {
+    let system = match tenants.get_system(id) {
+        Some(system) => system,
+        None => {
+            io_error!("Tenant {} is not loaded", id);
         }
+    };
+    tenants.released.lock().unwrap().insert(id);
+    system.set_paused(true);
+    system.stop_wal_senders();
+    /* Proposer connection is fenced like a superseded one, once its append in progress is done */
+    system.writer.lock().unwrap().take();
+    diagnostics::terminate_tenant_connections(id);
+    let flush = system.final_flush(conf);
+    if flush.is_failed() {
+        tenants.released.lock().unwrap().remove(&id);
+        io_error!("Final flush of {}", flush.describe());
+    }
+    SYSTEMS_LOCK.lock(&tenants.systems).remove(&id);
+    system.unload();
+    info!("Tenant {} is released: {}", id, flush.describe());
+    Ok(flush)
+}
 
-        info!(
-            "Start streaming from server {} address {:?}",
-            server_info.system_id,
-            self.stream.peer_addr()?
-        );
+//
+// Delete tenant: its WAL segments, control file and the rest of its directory, and
+// forget it. Refused while a proposer or WAL senders are connected: pause the tenant
+// and stop them first. Deletion is recorded in the recovery log beforehand, and a
+// tombstone keeps connections of the tenant from re-creating it afterwards.
+//
+pub fn delete_tenant(
+    conf: &WalAcceptorConf,
+    tenants: &TenantRegistry,
+    id: SystemId,
+    initiator: &str,
+) -> Result<()> {
+    let system_dir = tenant_dir(&conf.data_dir, id);
+    /* Held till the tombstone is recorded, so that connections don't load the tenant meanwhile */
+    let mut systems = SYSTEMS_LOCK.lock(&tenants.systems);
+    if let Some(system) = systems.get(&id) {
+        if system.writer.lock().unwrap().is_some() {
+            io_error!("Tenant {} has connected proposer", id);
+        }
+        let shared_state = TENANT_LOCKS.lock(&system.mutex);
+        if !shared_state.senders.is_empty() || !shared_state.replicas_feedback.is_empty() {
+            io_error!("Tenant {} has connected WAL senders", id);
+        }
+    } else if !system_dir.exists() {
+        io_error!("Unknown tenant {}", id);
+    }
 
-        // Main loop
-        loop {
-            let mut sync_control_file = false;
+    let mut files = Vec::new();
+    if system_dir.exists() {
+        for entry in fs::read_dir(&system_dir)? {
+            files.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        files.sort();
+    }
+    let entry = recovery_log::Entry::new(
+        recovery_log::Action::TenantDelete,
+        id,
+        "tenant is deleted".to_string(),
+        initiator.to_string(),
+    )
+    .files(files);
+    recovery_log::record(&conf.data_dir, &entry)?;
 
-            /* Receive message header */
-            let req = self.read_req::<SafeKeeperRequest>().await?;
-            if req.sender_id != my_info.server.node_id {
-                io_error!("Sender NodeId is changed");
-            }
-            if req.begin_lsn == END_OF_STREAM {
-                info!("Server stops streaming");
-                break;
+    /* Final LSNs for the tombstone, of loaded state or of the control file */
+    let (flush_lsn, commit_lsn) = match systems.get(&id) {
+        Some(system) => {
+            let shared_state = TENANT_LOCKS.lock(&system.mutex);
+            (shared_state.info.flush_lsn, shared_state.info.commit_lsn)
+        }
+        None => {
+            let control_file_path = system_dir.join(CONTROL_FILE_NAME);
+            let parsed = fs::read(&control_file_path)
+                .and_then(|content| ControlFileData::parse_file(&control_file_path, &content));
+            match parsed {
+                Ok((data, _, _)) => (data.info.flush_lsn, data.info.commit_lsn),
+                Err(e) => {
+                    warn!("Final LSNs of tenant {} are not known: {}", id, e);
+                    (0, 0)
+                }
             }
-            let start_pos = req.begin_lsn;
-            let end_pos = req.end_lsn;
-            let rec_size = (end_pos - start_pos) as usize;
-            assert!(rec_size <= MAX_SEND_SIZE);
-
-            /* Receive message body */
-            self.inbuf.resize(rec_size, 0u8);
-            self.stream.read_exact(&mut self.inbuf[0..rec_size]).await?;
-
-            /* Save message in file */
-            self.write_wal_file(start_pos, timeline, wal_seg_size, &self.inbuf[0..rec_size])?;
+        }
+    };
+    tombstone::record(
+        &conf.data_dir,
+        id,
+        &tombstone::Tombstone::new(flush_lsn, commit_lsn, initiator),
+    )?;
 
-            my_info.restart_lsn = req.restart_lsn;
-            my_info.commit_lsn = req.commit_lsn;
+    let system = systems.remove(&id);
+    /* Tombstone keeps the tenant from being re-created while its files are removed */
+    drop(systems);
+    if let Some(system) = system {
+        system.unload();
+    }
+    read_cache::invalidate_tenant(id);
+    if system_dir.exists() {
+        fs::remove_dir_all(&system_dir)?;
+        File::open(&conf.data_dir)?.sync_all()?;
+    }
+    info!("Tenant {} is deleted by {}", id, initiator);
+    Ok(())
+}
 
-            /*
-             * Epoch switch happen when written WAL record cross the boundary.
-             * The boundary is maximum of last WAL position at this node (FlushLSN) and global
-             * maximum (vcl) determined by safekeeper_proxy during handshake.
-             * Switching epoch means that node completes recovery and start writing in the WAL new data.
-             */
-            if my_info.epoch < prop.epoch && end_pos > max(my_info.flush_lsn, prop.vcl) {
-                info!("Switch to new epoch {}", prop.epoch);
-                my_info.epoch = prop.epoch; /* bump epoch */
-                sync_control_file = true;
-            }
-            if end_pos > my_info.flush_lsn {
-                my_info.flush_lsn = end_pos;
-            }
-            /*
-             * Update restart LSN in control file.
-             * To avoid negative impact on performance of extra fsync, do it only
-             * when restart_lsn delta exceeds WAL segment size.
-             */
-            sync_control_file |= flushed_restart_lsn + (wal_seg_size as u64) < my_info.restart_lsn;
-            self.system().save_control_file(sync_control_file)?;
+//
+// Run wal_acceptor. If listening socket is not passed (by systemd or the previous
+// wal_acceptor process), it is bound to listen_addr.
+//
+pub fn thread_main(conf: WalAcceptorConf, listener: Option<std::net::TcpListener>) {
+    let runtime = build_runtime(&conf).unwrap();
+    let tenants = TenantRegistry::new();
+    runtime.spawn(shutdown::exit_on_signal(conf.clone(), tenants.clone()));
+    runtime.block_on(serve(conf, tenants, listener, None));
+}
 
-            if sync_control_file {
-                flushed_restart_lsn = my_info.restart_lsn;
+//
+// Create a new thread pool, with a worker per CPU unless configured otherwise.
+// Single worker means the current-thread runtime, which is easier to debug with gdb.
+//
+pub fn build_runtime(conf: &WalAcceptorConf) -> Result<runtime::Runtime> {
+    match conf.workers {
+        Some(1) => runtime::Builder::new_current_thread().enable_all().build(),
+        workers => {
+            let mut builder = runtime::Builder::new_multi_thread();
+            if let Some(n) = workers {
+                builder.worker_threads(n);
             }
+            builder
+                .thread_name("wal_acceptor worker")
+                .enable_all()
+                .build()
+        }
+    }
+}
 
-            /* Report flush position */
-            //info!("Confirm LSN: {:X}/{:>08X}", (end_pos>>32) as u32, end_pos as u32);
-            let resp = SafeKeeperResponse {
-                epoch: my_info.epoch,
-                flush_lsn: end_pos,
-                hs_feedback: self.system().get_hs_feedback(),
-            };
-            self.start_sending();
-            resp.pack(&mut self.outbuf);
-            self.send().await?;
+//
+// Serve WAL service, admin socket and HTTP API on the current runtime. Address of the
+// WAL service is sent to ready once it is listening. Returns if listening fails.
+//
+pub async fn serve(
+    conf: WalAcceptorConf,
+    tenants: Arc<TenantRegistry>,
+    listener: Option<std::net::TcpListener>,
+    ready: Option<std::sync::mpsc::Sender<SocketAddr>>,
+) {
+    info!(
+        "Starting wal acceptor on {} with {} workers",
+        conf.listen_addr,
+        conf.workers
+            .map_or("default number of".to_string(), |n| n.to_string())
+    );
+    read_cache::set_capacity(conf.read_cache_size);
 
-            /*
-             * Ping wal sender that new data is available.
-             * FlushLSN (end_pos) can be smaller than commitLSN in case we are at catching-up safekeeper.
-             */
-            self.system()
-                .notify_wal_senders(min(req.commit_lsn, end_pos));
+    task::spawn(diagnostics::lag_probe("main".to_string()));
+    let admin_conf = conf.clone();
+    let admin_tenants = tenants.clone();
+    task::spawn(async move {
+        if let Err(e) = admin::admin_loop(&admin_conf, admin_tenants).await {
+            error!("Admin socket failed: {}", e);
         }
-        Ok(())
+    });
+    let outbound_conf = conf.clone();
+    let outbound_tenants = tenants.clone();
+    task::spawn(async move {
+        outbound::outbound_loop(&outbound_conf, &outbound_tenants).await;
+    });
+    if let Err(e) = main_loop(&conf, &tenants, listener, ready).await {
+        error!("Failed to accept connections: {}", e);
     }
+}
 
-    //
-    // Read full message or return None if connection is closed
-    //
-    async fn read_message(&mut self) -> Result<Option<FeMessage>> {
-        loop {
-            if let Some(message) = self.parse_message()? {
-                return Ok(Some(message));
-            }
+async fn main_loop(
+    conf: &WalAcceptorConf,
+    tenants: &Arc<TenantRegistry>,
+    listener: Option<std::net::TcpListener>,
+    ready: Option<std::sync::mpsc::Sender<SocketAddr>>,
+) -> Result<()> {
+    let listener = match listener {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => TcpListener::bind(conf.listen_addr.to_string().as_str()).await?,
+    };
+    if let Some(ready) = ready {
+        let _ = ready.send(listener.local_addr()?);
+    }
+    if !conf.access_list.is_empty() {
+        info!("WAL service access list: {}", conf.access_list);
+    }
+    /* Listeners are served by tasks of their own and may be reconfigured at runtime */
+    tenants
+        .listeners()
+        .start(Arc::new(conf.clone()), tenants, listener)?;
+    std::future::pending().await
+}
 
-            if self.stream.read_buf(&mut self.inbuf).await? == 0 {
-                if self.inbuf.is_empty() {
-                    return Ok(None);
-                } else {
-                    io_error!("connection reset by peer");
+//
+// Accept connections of the WAL service at the listener until stop is sent or dropped.
+// Accepted connections are served by tasks of their own, which outlive the listener.
+//
+pub async fn accept_connections(
+    listener: TcpListener,
+    conf: Arc<WalAcceptorConf>,
+    tenants: Arc<TenantRegistry>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            res = listener.accept() => res,
+            _ = &mut stop => return,
+        };
+        match accepted {
+            Ok((socket, peer_addr)) => {
+                if !conf.access_list.is_allowed(peer_addr.ip()) {
+                    info!(
+                        "Reject connection from {}: address is not allowed",
+                        peer_addr
+                    );
+                    continue;
                 }
+                if tenants.is_draining() {
+                    info!(
+                        "Reject connection from {}: safekeeper is draining",
+                        peer_addr
+                    );
+                    continue;
+                }
+                debug!("accepted connection from {}", peer_addr);
+                if let Err(e) = socket.set_nodelay(true) {
+                    warn!("Failed to disable Nagle algorithm for {}: {}", peer_addr, e);
+                }
+                if let Some(idle) = conf.tcp_keepalive {
+                    if let Err(e) = set_tcp_keepalive(&socket, idle) {
+                        warn!("Failed to enable TCP keepalive for {}: {}", peer_addr, e);
+                    }
+                }
+                let conf = conf.clone();
+                let tenants = tenants.clone();
+                task::spawn(async move {
+                    if let Err(err) = serve_connection(socket, &conf, tenants).await {
+                        error!("error: {}", err);
+                    }
+                });
             }
+            Err(e) => error!("Failed to accept connection: {}", e),
         }
     }
+}
 
-    //
-    // Parse libpq message
-    //
-    fn parse_message(&mut self) -> Result<Option<FeMessage>> {
-        if !self.init_done {
-            FeStartupMessage::parse(&mut self.inbuf)
+//
+// Enable TCP keepalive of accepted connection, so that the connection of a peer which
+// has vanished without closing it (host crash, network partition) is reset after about
+// twice the idle time, instead of the hours of system defaults
+//
+fn set_tcp_keepalive(socket: &TcpStream, idle: Duration) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    let set = |level: libc::c_int, name: libc::c_int, value: libc::c_int| {
+        let res = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            Err(io::Error::last_os_error())
         } else {
-            FeMessage::parse(&mut self.inbuf)
+            Ok(())
         }
+    };
+    set(libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    /* Timings of probes are Linux specific, elsewhere system defaults are used */
+    #[cfg(target_os = "linux")]
+    {
+        let idle_secs = max(idle.as_secs(), 1) as libc::c_int;
+        let interval = max(idle_secs / KEEPALIVE_PROBES as libc::c_int, 1);
+        set(libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle_secs)?;
+        set(libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, interval)?;
+        set(
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPCNT,
+            KEEPALIVE_PROBES as libc::c_int,
+        )?;
     }
+    #[cfg(not(target_os = "linux"))]
+    let _ = idle;
+    Ok(())
+}
 
-    //
-    // Reset output buffer to start accumulating data of new message
-    //
-    fn start_sending(&mut self) {
-        self.outbuf.clear();
+pub async fn serve_connection(
+    socket: TcpStream,
+    conf: &WalAcceptorConf,
+    tenants: Arc<TenantRegistry>,
+) -> Result<()> {
+    let stream = Stream::accept(socket, conf.tls.as_ref()).await?;
+    let mut conn = Connection::new(stream, conf, tenants);
+    match conn.run().await? {
+        Some(cont) => conn.migrate(cont).await,
+        None => Ok(()),
     }
+}
 
-    //
-    // Send buffered messages
-    //
-    async fn send(&mut self) -> Result<()> {
+//
+// Start runtime with its own thread for isolated tenant. The runtime, with connections
+// running on it, is shut down when the returned sender is dropped.
+//
+fn start_tenant_runtime(id: SystemId) -> (runtime::Handle, oneshot::Sender<()>) {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    thread::Builder::new()
+        .name(format!("tenant {}", id))
+        .spawn(move || {
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            tx.send(runtime.handle().clone()).unwrap();
+            runtime.spawn(diagnostics::lag_probe(format!("tenant {}", id)));
+            let _ = runtime.block_on(stop_rx);
+        })
+        .unwrap();
+    (rx.recv().unwrap(), stop_tx)
+}
+
+impl SharedState {
+    //
+    // Oldest flush position reported by replicas, connected or disconnected within
+    // REPLICA_RETENTION. Replica which reconnects resumes streaming from there, so WAL
+    // above it must be kept. Replicas reporting invalid (zero) flush position, e.g. not
+    // writing WAL to disk, don't count.
+    //
+    fn min_replica_flush_lsn(&self) -> Option<XLogRecPtr> {
+        self.replica_states
+            .values()
+            .filter(|state| !state.expired())
+            .map(|state| state.flush_lsn)
+            .filter(|lsn| *lsn != 0)
+            .min()
+    }
+
+    //
+    // Forget disconnected replicas beyond REPLICA_RETENTION, and the oldest ones beyond
+    // MAX_DISCONNECTED_REPLICAS: a replica reconnects from another port, so its old entry
+    // is not reused.
+    //
+    fn prune_replica_states(&mut self) {
+        self.replica_states.retain(|_, state| !state.expired());
+        let mut disconnected: Vec<(Instant, SocketAddr)> = self
+            .replica_states
+            .iter()
+            .filter_map(|(peer, state)| state.disconnected.map(|at| (at, *peer)))
+            .collect();
+        if disconnected.len() > MAX_DISCONNECTED_REPLICAS {
+            disconnected.sort();
+            for (_, peer) in &disconnected[..disconnected.len() - MAX_DISCONNECTED_REPLICAS] {
+                self.replica_states.remove(peer);
+            }
+        }
+    }
+
+    //
+    // Write a new copy of the control file. Synced copy replaces the file atomically:
+    // a file with the copy in both slots is written to a temporary one, synced and
+    // renamed over the control file. Unsynced copy is written in place to the slot
+    // which doesn't hold the last synced copy, so that a write torn by crash leaves
+    // the synced copy intact. Formats before 3 have the only copy in the first slot.
+    //
+    fn save_control_file(&mut self, sync: bool) -> Result<()> {
+        let generation = self.control_generation + 1;
+        let slotted = self.control_file_version >= 3;
+        let mut buf = BytesMut::new();
+        pack_control_file(
+            &self.info,
+            &self.pg_versions,
+            self.archived_lsn,
+            self.remote_consistent_lsn,
+            generation,
+            self.control_file_version,
+            &mut buf,
+        );
+
+        if sync {
+            fill_control_slots(&mut buf, self.control_file_version);
+            let tmp_path = self.control_file_path.with_file_name(CONTROL_TMP_FILE_NAME);
+            let mut file = File::create(&tmp_path)?;
+            fault_fs::write(&tmp_path, 0, &buf)?;
+            file.write_all(&buf[..])?;
+            fault_fs::sync(&tmp_path)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &self.control_file_path)?;
+            fault_fs::rename(&tmp_path, &self.control_file_path);
+            File::open(self.control_file_path.parent().unwrap())?.sync_all()?;
+            self.control_file = Some(file);
+            self.durable_slot = 0;
+        } else {
+            let offset = if slotted {
+                ((1 - self.durable_slot) * CONTROL_SLOT_SIZE) as u64
+            } else {
+                0
+            };
+            let file = self.control_file.as_mut().unwrap();
+            file.seek(SeekFrom::Start(offset))?;
+            fault_fs::write(&self.control_file_path, offset, &buf)?;
+            file.write_all(&buf[..])?;
+        }
+        self.control_generation = generation;
+        Ok(())
+    }
+}
+
+impl System {
+    pub fn new(
+        id: SystemId,
+        tenant_conf: TenantConf,
+        outbound: OutboundQueue,
+        draining: Arc<AtomicBool>,
+    ) -> System {
+        let shared_state = SharedState {
+            info: SafeKeeperInfo::new(),
+            flushed_restart_lsn: 0,
+            control_lock: None,
+            control_file: None,
+            control_file_path: PathBuf::new(),
+            control_generation: 0,
+            durable_slot: 1, /* new control file is written from the first slot */
+            control_file_version: CONTROL_FILE_VERSION,
+            replicas_feedback: HashMap::new(),
+            catchups: HashMap::new(),
+            senders: HashMap::new(),
+            replica_states: HashMap::new(),
+            commit_times: VecDeque::new(),
+            mirror: MirrorHealth::default(),
+            append_latency: Histogram::default(),
+            ingest_latency: Histogram::default(),
+            unapplied_appends: VecDeque::new(),
+            remote_consistent_lsn: 0,
+            paused: false,
+            paused_appends: 0,
+            flow_pauses: 0,
+            preparing_segment: None,
+            appends: 0,
+            received_bytes: 0,
+            sent_bytes: 0,
+            fsync_latency: Histogram::default(),
+            clock_skew: None,
+            clock_skew_warned: false,
+            wal_stats: WalRecordStats::new(),
+            pg_versions: PgVersionHistory::default(),
+            archived_lsn: 0,
+            wal_ops: WalOpStats::default(),
+            gc_proposal: None,
+            gc_cutoff: 0,
+            at_rest: tenant_conf.at_rest,
+            at_rest_pending: Vec::new(),
+            at_rest_job: None,
+            spares: SparePool::default(),
+        };
+        let (runtime, runtime_stop) = if tenant_conf.dedicated_runtime {
+            let (handle, stop) = start_tenant_runtime(id);
+            (Some(handle), Some(stop))
+        } else {
+            (None, None)
+        };
+        System {
+            id: id,
+            tenant_conf: tenant_conf,
+            mutex: Mutex::new(shared_state),
+            commit_lsn: AtomicU64::new(0),
+            flush_lsn: AtomicU64::new(0),
+            cond: Notify::new(),
+            senders_stopped: AtomicBool::new(false),
+            segment_prepared: Notify::new(),
+            horizon_changed: Notify::new(),
+            spares_wanted: Notify::new(),
+            outbound: Mutex::new(outbound),
+            runtime: runtime,
+            runtime_stop: Mutex::new(runtime_stop),
+            draining: draining,
+            writer: Mutex::new(None),
+            loading: Mutex::new(()),
+            superseded: Notify::new(),
+            object_wal: Mutex::new(None),
+            wal_files: Mutex::new(WalFileCache::default()),
+            wal_checksums: Mutex::new(ChecksumIndex::default()),
+            ingest_index: Mutex::new(None),
+            overwrites: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Control data in the control file format
+    pub fn info_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.get_info().pack(&mut buf);
+        buf.to_vec()
+    }
+
+    pub fn outbound_queue(&self) -> MutexGuard<OutboundQueue> {
+        self.outbound.lock().unwrap()
+    }
+
+    //
+    // Persist vote for a proposer and make its connection the only writer of WAL.
+    // Votes are serialized by the writer lock, so of concurrent proposers the one with
+    // the highest term wins (others fail the term check), and of two connections with
+    // the same term the last one, which is the proposer reconnected. Superseded
+    // connection is woken up to be fenced right away.
+    //
+    fn vote<F>(&self, conn_id: u64, f: F) -> Result<SafeKeeperInfo>
+    where
+        F: FnOnce(&mut SafeKeeperInfo) -> Result<()>,
+    {
+        let mut writer = self.writer.lock().unwrap();
+        let info = self.update_info(f)?;
+        if let Some(prev) = writer.replace(conn_id) {
+            if prev != conn_id {
+                info!(
+                    "Proposer connection #{} of system {} is superseded by #{}",
+                    prev, self.id, conn_id
+                );
+                self.superseded.notify_waiters();
+            }
+        }
+        Ok(info)
+    }
+
+    // Lock WAL for writing by the proposer connection, None if it is superseded
+    fn lock_writer(&self, conn_id: u64) -> Option<MutexGuard<Option<u64>>> {
+        let writer = self.writer.lock().unwrap();
+        if *writer == Some(conn_id) {
+            Some(writer)
+        } else {
+            None
+        }
+    }
+
+    fn release_writer(&self, conn_id: u64) {
+        let mut writer = self.writer.lock().unwrap();
+        if *writer == Some(conn_id) {
+            *writer = None;
+        }
+    }
+
+    // Notify caught-up WAL senders about new WAL data received
+    fn notify_wal_senders(&self, commit_lsn: XLogRecPtr) {
+        if self.commit_lsn.fetch_max(commit_lsn, Ordering::AcqRel) < commit_lsn {
+            self.cond.notify_waiters();
+            events::emit(Event::CommitAdvanced {
+                system_id: self.id,
+                commit_lsn: commit_lsn,
+            });
+        }
+    }
+
+    pub fn get_commit_lsn(&self) -> XLogRecPtr {
+        self.commit_lsn.load(Ordering::Acquire)
+    }
+
+    pub fn get_flush_lsn(&self) -> XLogRecPtr {
+        self.flush_lsn.load(Ordering::Acquire)
+    }
+
+    // WAL segment size reported by wal_proposer, 0 if it has never connected
+    pub fn get_wal_seg_size(&self) -> usize {
+        self.get_info().server.wal_seg_size as usize
+    }
+
+    fn stop_wal_senders(&self) {
+        self.senders_stopped.store(true, Ordering::Release);
+        self.cond.notify_waiters();
+    }
+
+    //
+    // Release resources of the tenant removed from the registry: stop its WAL senders
+    // and dedicated runtime, close its files and release its lock. The runtime is stopped
+    // explicitly, as its tasks may still hold the system and keep it from being dropped.
+    //
+    fn unload(&self) {
+        self.stop_wal_senders();
+        self.runtime_stop.lock().unwrap().take();
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        shared_state.control_lock = None;
+        shared_state.control_file = None;
+        drop(shared_state);
+        self.wal_files.lock().unwrap().clear();
+    }
+
+    fn wal_senders_stopped(&self) -> bool {
+        self.senders_stopped.load(Ordering::Acquire)
+    }
+
+    fn get_info(&self) -> SafeKeeperInfo {
+        return TENANT_LOCKS.lock(&self.mutex).info;
+    }
+
+    //
+    // Atomically update information about this safekeeper and save it in the control file.
+    // The closure is applied under the lock; if it fails, no changes are made.
+    // Persistence policy is decided here, so callers don't need to care about fsyncs.
+    //
+    fn update_info<F>(&self, f: F) -> Result<SafeKeeperInfo>
+    where
+        F: FnOnce(&mut SafeKeeperInfo) -> Result<()>,
+    {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        let old_info = shared_state.info;
+        let mut info = old_info;
+        f(&mut info)?;
+
+        /*
+         * Vote, epoch switch and new server info should be durable before
+         * they are reported to proxy. To avoid negative impact on performance
+         * of extra fsync, restart LSN is synced only when its delta exceeds
+         * WAL segment size.
+         */
+        let sync = info.server != old_info.server
+            || info.epoch != old_info.epoch
+            || shared_state.flushed_restart_lsn + (info.server.wal_seg_size as u64)
+                < info.restart_lsn;
+
+        shared_state.info = info;
+        if let Err(e) = shared_state.save_control_file(sync) {
+            shared_state.info = old_info;
+            return Err(e);
+        }
+        if sync {
+            shared_state.flushed_restart_lsn = info.restart_lsn;
+            events::emit(Event::ControlFileSynced {
+                system_id: self.id,
+                epoch: info.epoch,
+                flush_lsn: info.flush_lsn,
+            });
+        }
+        if info.epoch != old_info.epoch {
+            events::emit(Event::EpochSwitched {
+                system_id: self.id,
+                epoch: info.epoch,
+            });
+        }
+        self.flush_lsn.store(info.flush_lsn, Ordering::Release);
+        Ok(info)
+    }
+
+    //
+    // Check Postgres version of the connected proposer against the versions which have
+    // written WAL of the tenant. WAL of another major version is rejected, accepted with
+    // a warning flag, or written to the next timeline, as the tenant policy says.
+    // Returns timeline for WAL of the proposer and the version change, which is applied
+    // by record_pg_version() only once the proposer wins the vote.
+    //
+    fn check_pg_version(
+        &self,
+        pg_version: u32,
+        timeline: TimeLineID,
+        lsn: XLogRecPtr,
+    ) -> Result<(TimeLineID, Option<PgVersionUpdate>)> {
+        let shared_state = TENANT_LOCKS.lock(&self.mutex);
+        let last = shared_state.pg_versions.changes.last().cloned();
+        let old_version = match last {
+            /* WAL of this version still goes to the timeline started for it */
+            Some(change) if change.pg_version == pg_version => {
+                return Ok((max(timeline, change.timeline), None));
+            }
+            Some(change) => change.pg_version,
+            None => shared_state.info.server.pg_version,
+        };
+        let parent_timeline = timeline;
+        let mut timeline = timeline;
+        let mut mismatch_accepted = false;
+        if old_version != UNKNOWN_SERVER_VERSION
+            && pg_major_version(old_version) != pg_major_version(pg_version)
+        {
+            match self.tenant_conf.pg_version_mismatch {
+                PgVersionPolicy::Accept => {
+                    warn!(
+                        "System {} receives WAL of Postgres {} after {}, WAL formats are mixed",
+                        self.id, pg_version, old_version
+                    );
+                    mismatch_accepted = true;
+                }
+                PgVersionPolicy::Reject => {
+                    if shared_state.pg_versions.acked_version != pg_version {
+                        io_error!(
+                            "Reject proposer of system {} running Postgres {} after {}, acknowledge it with \"ack-pg-version {} {}\"",
+                            self.id,
+                            pg_version,
+                            old_version,
+                            self.id,
+                            pg_version
+                        );
+                    }
+                }
+                PgVersionPolicy::NewTimeline => timeline += 1,
+            }
+        }
+        let update = PgVersionUpdate {
+            change: PgVersionChange {
+                pg_version: pg_version,
+                timeline: timeline,
+                lsn: lsn,
+            },
+            old_version: old_version,
+            parent_timeline: parent_timeline,
+            mismatch_accepted: mismatch_accepted,
+        };
+        Ok((timeline, Some(update)))
+    }
+
+    //
+    // Record version of the proposer which has won the vote, see check_pg_version().
+    // Switch to a new timeline starts its first segment with WAL of the parent timeline
+    // preceding the switch point, like in Postgres, and writes its history file.
+    //
+    fn record_pg_version(
+        &self,
+        conf: &WalAcceptorConf,
+        update: PgVersionUpdate,
+        wal_seg_size: usize,
+    ) -> Result<()> {
+        let change = update.change;
+        if change.timeline != update.parent_timeline {
+            info!(
+                "System {} switches to timeline {} for WAL of Postgres {} after {}",
+                self.id, change.timeline, change.pg_version, update.old_version
+            );
+            let system_dir = tenant_dir(&conf.data_dir, self.id);
+            let offset = XLogSegmentOffset(change.lsn, wal_seg_size) as usize;
+            if offset != 0 {
+                let segno = XLByteToSeg(change.lsn, wal_seg_size);
+                let parent =
+                    SegmentPath::new(&system_dir, update.parent_timeline, segno, wal_seg_size);
+                let segment = SegmentPath::new(&system_dir, change.timeline, segno, wal_seg_size);
+                parent.copy_prefix(
+                    segment.path(!conf.pg_wal_layout),
+                    offset,
+                    wal_seg_size,
+                    conf.at_rest_key.as_ref(),
+                    conf.no_sync,
+                )?;
+            }
+            timeline_history::record_switch(
+                &system_dir,
+                update.parent_timeline,
+                change.timeline,
+                change.lsn,
+                &format!(
+                    "Postgres {} after {}",
+                    change.pg_version, update.old_version
+                ),
+            )?;
+        } else if update.old_version != UNKNOWN_SERVER_VERSION {
+            info!(
+                "Server version of system {} changed from {} to {}",
+                self.id, update.old_version, change.pg_version
+            );
+        }
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        if update.mismatch_accepted {
+            shared_state.pg_versions.mismatch_accepted = true;
+        }
+        shared_state.pg_versions.record(change);
+        shared_state.save_control_file(true)
+    }
+
+    //
+    // Acknowledge Postgres version of the tenant: proposers running it are accepted under
+    // "reject" policy, and the warning flag of accepted version mismatch is cleared.
+    //
+    pub fn ack_pg_version(&self, conf: &WalAcceptorConf, pg_version: u32) -> Result<()> {
+        self.load_control_file(conf)?;
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        shared_state.pg_versions.acked_version = pg_version;
+        shared_state.pg_versions.mismatch_accepted = false;
+        shared_state.save_control_file(true)
+    }
+
+    // Oldest flush position reported by replicas of the tenant, None if none has reported
+    pub fn min_replica_flush_lsn(&self) -> Option<XLogRecPtr> {
+        TENANT_LOCKS.lock(&self.mutex).min_replica_flush_lsn()
+    }
+
+    pub fn pg_versions(&self) -> PgVersionHistory {
+        TENANT_LOCKS.lock(&self.mutex).pg_versions.clone()
+    }
+
+    //
+    // WAL segment size, end of WAL which may be archived and end of archived WAL, or None
+    // if the control file is not loaded yet or WAL segment size is not known. Only WAL
+    // below commit_lsn is archived: WAL above it may still be overwritten by proposer of
+    // a new term.
+    //
+    pub fn archive_position(&self) -> Option<(usize, XLogRecPtr, XLogRecPtr)> {
+        let shared_state = TENANT_LOCKS.lock(&self.mutex);
+        let info = &shared_state.info;
+        let wal_seg_size = info.server.wal_seg_size as usize;
+        if shared_state.control_file.is_none() || wal_seg_size == 0 {
+            return None;
+        }
+        let end_lsn = min(info.flush_lsn, info.commit_lsn);
+        Some((wal_seg_size, end_lsn, shared_state.archived_lsn))
+    }
+
+    // Advance end of archived WAL, it is synced before GC may remove the segments
+    pub fn set_archived_lsn(&self, lsn: XLogRecPtr) -> Result<()> {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        if lsn <= shared_state.archived_lsn {
+            return Ok(());
+        }
+        shared_state.archived_lsn = lsn;
+        shared_state.save_control_file(true)
+    }
+
+    // Remember the latest hot standby feedback from replica
+    fn add_hs_feedback(&self, source: SocketAddr, feedback: HotStandbyFeedback) {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        shared_state.replicas_feedback.insert(source, feedback);
+    }
+
+    //
+    // Catch-up tracking. Returns false if catch-up of the sender was cancelled.
+    //
+    fn update_catchup(
+        &self,
+        peer: SocketAddr,
+        start_lsn: XLogRecPtr,
+        sent_lsn: XLogRecPtr,
+    ) -> bool {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        let progress = shared_state
+            .catchups
+            .entry(peer)
+            .or_insert_with(|| CatchupProgress {
+                start_lsn: start_lsn,
+                sent_lsn: start_lsn,
+                started: clock::now(),
+                cancelled: false,
+            });
+        progress.sent_lsn = sent_lsn;
+        !progress.cancelled
+    }
+
+    fn finish_catchup(&self, peer: &SocketAddr) {
+        TENANT_LOCKS.lock(&self.mutex).catchups.remove(peer);
+    }
+
+    //
+    // Remember position of WAL sender (None when it disconnects), for time lag reporting.
+    // Progress of a disconnected replica is kept for a while, see ReplicaState.
+    //
+    fn update_sender(&self, peer: SocketAddr, sent_lsn: Option<XLogRecPtr>) {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        match sent_lsn {
+            Some(lsn) => {
+                shared_state.senders.insert(peer, lsn);
+            }
+            None => {
+                shared_state.senders.remove(&peer);
+                if let Some(state) = shared_state.replica_states.get_mut(&peer) {
+                    state.disconnected = Some(clock::now());
+                }
+                shared_state.prune_replica_states();
+            }
+        }
+    }
+
+    // Remember progress reported by replica in standby status update
+    fn update_replica_state(
+        &self,
+        peer: SocketAddr,
+        write_lsn: XLogRecPtr,
+        flush_lsn: XLogRecPtr,
+        apply_lsn: XLogRecPtr,
+    ) {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        shared_state.replica_states.insert(
+            peer,
+            ReplicaState {
+                write_lsn: write_lsn,
+                flush_lsn: flush_lsn,
+                apply_lsn: apply_lsn,
+                updated: clock::now(),
+                disconnected: None,
+            },
+        );
+    }
+
+    // Whether replica hasn't sent status update for the given interval (or at all)
+    fn replica_reply_due(&self, peer: &SocketAddr, interval: Duration) -> bool {
+        match TENANT_LOCKS.lock(&self.mutex).replica_states.get(peer) {
+            Some(progress) => clock::elapsed(progress.updated) >= interval,
+            None => true,
+        }
+    }
+
+    //
+    // Remember that WAL up to the given LSN contains transaction committed at the given time.
+    // One entry per second is kept, so the oldest unsent commit is known with one second precision.
+    //
+    fn record_commit_time(&self, lsn: XLogRecPtr, xact_time: TimestampTz) {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        let commit_times = &mut shared_state.commit_times;
+        match commit_times.back_mut() {
+            Some(last) if xact_time < last.1 + COMMIT_TIME_GRANULARITY => last.0 = lsn,
+            _ => {
+                if commit_times.len() == MAX_COMMIT_TIMES {
+                    commit_times.pop_front();
+                }
+                commit_times.push_back((lsn, xact_time));
+            }
+        }
+    }
+
+    // Cancel catch-up of the given sender or of all senders. Returns number of cancelled ones.
+    pub fn cancel_catchup(&self, peer: Option<SocketAddr>) -> usize {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        let mut cancelled = 0;
+        for (addr, progress) in shared_state.catchups.iter_mut() {
+            if peer.map_or(true, |peer| peer == *addr) {
+                progress.cancelled = true;
+                cancelled += 1;
+            }
+        }
+        cancelled
+    }
+
+    // Forget feedback of disconnected replica
+    fn remove_hs_feedback(&self, source: &SocketAddr) {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        shared_state.replicas_feedback.remove(source);
+    }
+
+    // Combine hot standby feedbacks from all replicas.
+    // Returns combined feedback and number of contributing replicas, which is reported
+    // only to proposers of protocol version 2.
+    fn get_hs_feedback(&self) -> (HotStandbyFeedback, u32) {
+        let shared_state = TENANT_LOCKS.lock(&self.mutex);
+        let mut combined = HotStandbyFeedback {
+            ts: 0,
+            xmin: u64::MAX,
+            catalog_xmin: u64::MAX,
+        };
+        for feedback in shared_state.replicas_feedback.values() {
+            combined.xmin = min(combined.xmin, feedback.xmin);
+            combined.catalog_xmin = min(combined.catalog_xmin, feedback.catalog_xmin);
+            combined.ts = max(combined.ts, feedback.ts);
+        }
+        return (combined, shared_state.replicas_feedback.len() as u32);
+    }
+
+    //
+    // Pageserver reports that it has checkpointed (uploaded) all WAL up to the given LSN.
+    // This is the event which drives WAL GC and backup watermark advancement. It is stored
+    // in the control file without sync: the older one found after crash only holds WAL longer.
+    // The control file is loaded first, pageserver may report before any proposer connects.
+    //
+    pub fn set_remote_consistent_lsn(&self, conf: &WalAcceptorConf, lsn: XLogRecPtr) -> Result<()> {
+        self.load_control_file(conf)?;
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        if shared_state.remote_consistent_lsn >= lsn {
+            return Ok(());
+        }
+        shared_state.remote_consistent_lsn = lsn;
+        shared_state.save_control_file(false)?;
+        self.horizon_changed.notify_waiters();
+        Ok(())
+    }
+
+    //
+    // Give way to other tenants if this one has lower priority.
+    // Yielding puts the task at the end of the run queue of its worker, so pending
+    // work of interactive tenants is done first.
+    //
+    async fn yield_if_batch(&self) {
+        if self.tenant_conf.priority == PriorityClass::Batch {
+            task::yield_now().await;
+        }
+    }
+
+    pub fn id(&self) -> SystemId {
+        self.id
+    }
+
+    // Wake up WAL GC of this system
+    pub fn request_gc(&self) {
+        self.horizon_changed.notify_waiters();
+    }
+
+    // Wait until pageserver reports a checkpoint or WAL GC is requested
+    pub fn horizon_changed(&self) -> Notified<'_> {
+        self.horizon_changed.notified()
+    }
+
+    // Count operation removing or offloading WAL, for metrics and status
+    pub fn account_wal_op(&self, op: WalOp, count: u64, bytes: u64) {
+        TENANT_LOCKS.lock(&self.mutex).wal_ops.account(op, count, bytes);
+    }
+
+    //
+    // Point-in-time copy of the tenant state. Shared state is copied under
+    // the lock and all formatting is done by the caller after it is released,
+    // so status reporting doesn't hold up the append path.
+    //
+    pub fn snapshot(&self) -> SystemSnapshot {
+        /* Outbound queue has its own lock, it is not taken together with the state lock */
+        let (outbound_depth, outbound_stats) = {
+            let queue = self.outbound_queue();
+            (queue.depth(), queue.stats())
+        };
+        /* Writer lock is taken before the state lock by vote(), so don't nest them */
+        let proposer_connected = self.writer.lock().unwrap().is_some();
+        /* Ingest index is locked before the state lock, appends take it alone */
+        let ingest_index = self.ingest_index.lock().unwrap();
+        let shared_state = TENANT_LOCKS.lock(&self.mutex);
+        let now = get_current_timestamp();
+        let lag = |lsn| time_lag(&shared_state, ingest_index.as_ref(), lsn, now);
+        SystemSnapshot {
+            id: self.id,
+            priority: self.tenant_conf.priority,
+            dedicated_runtime: self.runtime.is_some(),
+            info: shared_state.info,
+            commit_lsn: self.get_commit_lsn(),
+            remote_consistent_lsn: shared_state.remote_consistent_lsn,
+            archived_lsn: shared_state.archived_lsn,
+            wal_ops: shared_state.wal_ops,
+            paused: shared_state.paused,
+            draining: self.draining.load(Ordering::SeqCst),
+            paused_appends: shared_state.paused_appends,
+            flow_pauses: shared_state.flow_pauses,
+            appends: shared_state.appends,
+            received_bytes: shared_state.received_bytes,
+            sent_bytes: shared_state.sent_bytes,
+            clock_skew: shared_state.clock_skew,
+            replicas: shared_state.replicas_feedback.len(),
+            proposer_connected: proposer_connected,
+            pg_version_mismatch: shared_state.pg_versions.mismatch_accepted,
+            catchups: shared_state
+                .catchups
+                .iter()
+                .map(|(peer, progress)| (*peer, progress.clone()))
+                .collect(),
+            senders: shared_state
+                .senders
+                .iter()
+                .map(|(peer, lsn)| {
+                    let progress = shared_state.replica_states.get(peer).copied();
+                    (*peer, *lsn, lag(*lsn), progress)
+                })
+                .collect(),
+            min_replica_flush_lsn: shared_state.min_replica_flush_lsn(),
+            pageserver_lag: lag(shared_state.remote_consistent_lsn),
+            segment_preparing: shared_state
+                .preparing_segment
+                .map(|(_, started)| clock::elapsed(started).as_secs_f64()),
+            spare_segments: shared_state.spares.ready(),
+            mirror: self
+                .tenant_conf
+                .mirror_dir
+                .as_ref()
+                .map(|_| shared_state.mirror.clone()),
+            append_latency: shared_state.append_latency,
+            ingest_latency: shared_state.ingest_latency,
+            fsync_latency: shared_state.fsync_latency,
+            outbound_depth: outbound_depth,
+            outbound_stats: outbound_stats,
+        }
+    }
+
+    // One line summary of the system state
+    pub fn describe(&self) -> String {
+        self.snapshot().describe()
+    }
+
+    pub fn metrics(&self) -> TenantMetrics {
+        self.snapshot().metrics()
+    }
+
+    //
+    // Compare commit timestamp generated by proposer with local clock. The difference
+    // includes delivery latency, so only skews well above it are meaningful.
+    //
+    fn update_clock_skew(&self, xact_time: TimestampTz, threshold: Option<Duration>) {
+        let skew = get_current_timestamp() as i64 - xact_time as i64;
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        shared_state.clock_skew = Some(skew);
+        if let Some(threshold) = threshold {
+            let exceeded = skew.abs() as u64 > threshold.as_micros() as u64;
+            if exceeded && !shared_state.clock_skew_warned {
+                warn!(
+                    "Local clock is {}us ahead of the clock of wal_proposer of system {}",
+                    skew, self.id
+                );
+            } else if !exceeded && shared_state.clock_skew_warned {
+                info!("Clock skew of system {} is back to {}us", self.id, skew);
+            }
+            shared_state.clock_skew_warned = exceeded;
+        }
+    }
+
+    fn account_send(&self, len: usize) {
+        TENANT_LOCKS.lock(&self.mutex).sent_bytes += len as u64;
+    }
+
+    fn account_fsync(&self, elapsed: Duration) {
+        TENANT_LOCKS
+            .lock(&self.mutex)
+            .fsync_latency
+            .observe(elapsed.as_secs_f64());
+    }
+
+    fn account_append(&self, len: usize) {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        shared_state.appends += 1;
+        shared_state.received_bytes += len as u64;
+    }
+
+    // Whether WAL received up to end_lsn by now is to be recorded in the ingest index
+    fn ingest_index_due(&self, end_lsn: XLogRecPtr, now: TimestampTz) -> bool {
+        let index = self.ingest_index.lock().unwrap();
+        index
+            .as_ref()
+            .map_or(false, |index| index.is_due(end_lsn, now))
+    }
+
+    // Record WAL received up to end_lsn in the ingest index. Blocks on I/O.
+    fn record_ingest_time(&self, end_lsn: XLogRecPtr, now: TimestampTz) {
+        if let Some(index) = self.ingest_index.lock().unwrap().as_mut() {
+            /* Index is advisory, failure to maintain it must not fail the append */
+            if let Err(e) = index.record(end_lsn, now) {
+                warn!("Failed to update ingest index of system {}: {}", self.id, e);
+            }
+        }
+    }
+
+    //
+    // Append received at the given time is acknowledged to proposer. Remember it
+    // until some WAL receiver reports that WAL up to end_lsn is applied.
+    //
+    fn account_ack(&self, end_lsn: XLogRecPtr, received: Instant) {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        shared_state
+            .append_latency
+            .observe(clock::elapsed(received).as_secs_f64());
+        if shared_state.unapplied_appends.len() == MAX_UNAPPLIED_APPENDS {
+            /* Nobody applies WAL of the tenant now, forget the oldest */
+            shared_state.unapplied_appends.pop_front();
+        }
+        shared_state
+            .unapplied_appends
+            .push_back((end_lsn, received));
+    }
+
+    // WAL receiver (pageserver or replica) reports that WAL up to apply_lsn is applied
+    fn account_applied(&self, apply_lsn: XLogRecPtr) {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        while let Some((end_lsn, received)) = shared_state.unapplied_appends.front().copied() {
+            if end_lsn > apply_lsn {
+                break;
+            }
+            shared_state.unapplied_appends.pop_front();
+            shared_state
+                .ingest_latency
+                .observe(clock::elapsed(received).as_secs_f64());
+        }
+    }
+
+    //
+    // Apply storage operation to the mirror directory of the tenant, if it is configured
+    // and has not failed yet. Failure of the mirror doesn't fail the append, primary copy
+    // is authoritative.
+    //
+    fn mirror_wal<F>(&self, lsn: XLogRecPtr, len: usize, op: F)
+    where
+        F: FnOnce(&Path) -> Result<()>,
+    {
+        let mirror_dir = match &self.tenant_conf.mirror_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        if TENANT_LOCKS.lock(&self.mutex).mirror.failed_at.is_some() {
+            return;
+        }
+        let res = op(mirror_dir);
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        match res {
+            Ok(()) => shared_state.mirror.mirrored_bytes += len as u64,
+            Err(e) => {
+                error!(
+                    "Failed to mirror WAL of system {} at {} to {:?}, mirror is not written anymore: {}",
+                    self.id,
+                    format_lsn(lsn),
+                    mirror_dir,
+                    e
+                );
+                shared_state.mirror.failed_at = Some(lsn);
+                shared_state.mirror.last_error = e.to_string();
+            }
+        }
+    }
+
+    //
+    // Closest LSN received at or before the given time according to the ingest-time index.
+    // Fails if the time precedes the index.
+    //
+    pub fn lsn_by_time(&self, ts: TimestampTz) -> Result<XLogRecPtr> {
+        let ingest_index = self.ingest_index.lock().unwrap();
+        let index = match ingest_index.as_ref() {
+            Some(index) => index,
+            None => {
+                io_error!("System {} has not been loaded", self.id);
+            }
+        };
+        match index.lsn_by_time(ts) {
+            Some(lsn) => Ok(lsn),
+            None => {
+                io_error!(
+                    "Ingest index of system {} starts later than the requested time",
+                    self.id
+                );
+            }
+        }
+    }
+
+    // Pause or resume WAL ingest
+    pub fn set_paused(&self, paused: bool) {
+        TENANT_LOCKS.lock(&self.mutex).paused = paused;
+    }
+
+    fn account_flow_pause(&self) {
+        TENANT_LOCKS.lock(&self.mutex).flow_pauses += 1;
+    }
+
+    //
+    // Wait until the segment is not being prepared anymore. WAL senders call it before
+    // opening a segment, so they never see it half zero-filled.
+    //
+    async fn wait_segment_prepared(&self, segno: XLogSegNo) {
+        loop {
+            let notified = self.segment_prepared.notified();
+            match TENANT_LOCKS.lock(&self.mutex).preparing_segment {
+                Some((preparing, _)) if preparing == segno => {}
+                _ => return,
+            }
+            notified.await;
+        }
+    }
+
+    //
+    // Create new segment zero-filled to its full size. A spare segment is renamed into
+    // place if there is one, otherwise the segment is filled under a temporary name and
+    // renamed into place, so that the segment is either absent or complete; the
+    // preparation is shown by the segment_preparing gauge meanwhile.
+    //
+    fn prepare_segment(
+        &self,
+        conf: &WalAcceptorConf,
+        segno: XLogSegNo,
+        path: &Path,
+        wal_seg_size: usize,
+    ) -> Result<File> {
+        if let Some(file) = self.take_spare_segment(path) {
+            return Ok(file);
+        }
+        let started = clock::now();
+        TENANT_LOCKS.lock(&self.mutex).preparing_segment = Some((segno, started));
+        let res = partial_segment::zero_fill(path, wal_seg_size, conf.no_sync);
+        TENANT_LOCKS.lock(&self.mutex).preparing_segment = None;
+        self.segment_prepared.notify_waiters();
+        if let Err(e) = res {
+            error!("Failed to prepare log file {:?}: {}", path, e);
+            return Err(e);
+        }
+        debug!(
+            "Prepared log file {:?} in {:?}",
+            path,
+            clock::elapsed(started)
+        );
+        OpenOptions::new().write(true).open(path)
+    }
+
+    // Rename a spare segment to the path, None if there is no spare one or it failed
+    fn take_spare_segment(&self, path: &Path) -> Option<File> {
+        let spare = TENANT_LOCKS.lock(&self.mutex).spares.take()?;
+        self.spares_wanted.notify_one();
+        match fs::rename(&spare, path) {
+            Ok(()) => fault_fs::rename(&spare, path),
+            Err(e) => {
+                warn!("Failed to take spare segment {:?}: {}", spare, e);
+                /* Keep it for the next segment, unless it is gone */
+                if spare.exists() {
+                    TENANT_LOCKS.lock(&self.mutex).spares.put_back(spare);
+                }
+                return None;
+            }
+        }
+        debug!("Spare segment {:?} is taken as {:?}", spare, path);
+        match OpenOptions::new().write(true).open(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                error!("Failed to open log file {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    //
+    // Zero-fill spare segments until --prealloc-segments of them are ready, recycled
+    // ones first. Returns number of filled segments. Called on the blocking thread pool.
+    //
+    fn fill_spare_segments(&self, conf: &WalAcceptorConf) -> Result<usize> {
+        let wal_seg_size = {
+            let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+            let wal_seg_size = shared_state.info.server.wal_seg_size as usize;
+            if shared_state.control_file.is_none() || wal_seg_size == 0 {
+                return Ok(0);
+            }
+            if !shared_state.spares.is_loaded() {
+                let system_dir = tenant_dir(&conf.data_dir, self.id);
+                shared_state.spares = SparePool::load(&system_dir, wal_seg_size)?;
+            }
+            wal_seg_size
+        };
+        let mut filled = 0;
+        loop {
+            let fill = TENANT_LOCKS.lock(&self.mutex).spares.next_fill(conf.prealloc_segments);
+            let fill = match fill {
+                Some(fill) => fill,
+                None => return Ok(filled),
+            };
+            if let Err(e) = fill.run(wal_seg_size, conf.no_sync) {
+                fill.abandon();
+                return Err(e);
+            }
+            TENANT_LOCKS.lock(&self.mutex).spares.filled(fill);
+            filled += 1;
+        }
+    }
+
+    //
+    // Move a segment removed by WAL GC to the spare ones, if they are short.
+    // Returns false if the segment should be deleted instead.
+    //
+    fn recycle_segment(&self, conf: &WalAcceptorConf, path: &Path, wal_seg_size: usize) -> Result<bool> {
+        if conf.prealloc_segments == 0 || fs::metadata(path)?.len() != wal_seg_size as u64 {
+            return Ok(false); /* segment encoded at rest is not of the full size */
+        }
+        let recycled = TENANT_LOCKS
+            .lock(&self.mutex)
+            .spares
+            .recycle(path, conf.prealloc_segments)?;
+        if recycled {
+            self.spares_wanted.notify_one();
+        }
+        Ok(recycled)
+    }
+
+    // Check if WAL ingest is paused and account rejected append if so
+    fn check_paused(&self) -> bool {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        if shared_state.paused {
+            shared_state.paused_appends += 1;
+        }
+        return shared_state.paused;
+    }
+
+    //
+    // Lock the tenant (prevent running more than one instance of safekeeper) and load
+    // its control file. The lock is taken on a separate lock file, as the control file
+    // is replaced on updates. Control file of an older format is upgraded in place,
+    // one of a newer format is refused, and the tenant stays unloaded.
+    // Blocks for a long time if the lock is taken over from another process, so it is
+    // called on the blocking thread pool, see run_blocking().
+    //
+    fn load_control_file(&self, conf: &WalAcceptorConf) -> Result<()> {
+        /*
+         * Already loaded and locked by previous connection, locking it again would fail.
+         * Loading lock is held till the end, so that connections of the tenant running
+         * on other worker threads don't load it concurrently; the state lock is taken
+         * only once the tenant lock is acquired and the control file is read.
+         */
+        let _loading = self.loading.lock().unwrap();
+        if TENANT_LOCKS.lock(&self.mutex).control_file.is_some() {
+            return Ok(());
+        }
+        let control_file_path = conf
+            .data_dir
+            .join(self.id.to_string())
+            .join(CONTROL_FILE_NAME);
+        let lock_path = control_file_path.with_file_name(CONTROL_LOCK_FILE_NAME);
+        let lock = match OpenOptions::new().write(true).create(true).open(&lock_path) {
+            Ok(lock) => lock,
+            Err(e) => {
+                io_error!("Failed to open lock file {:?}: {}", &lock_path, e);
+            }
+        };
+        // Lock file to prevent two or more active wal_acceptors
+        if let Err(e) = lock.try_lock_exclusive() {
+            /* Held by another wal_acceptor, e.g. the old one of blue/green restart still draining */
+            if let Err(takeover_err) =
+                tenant_takeover::take_over_lock(&conf.data_dir, &lock, &lock_path, self.id)
+            {
+                io_error!(
+                    "Lock file {:?} is locked by some other process: {}, and {}",
+                    &lock_path,
+                    e,
+                    takeover_err
+                );
+            }
+        }
+        tenant_takeover::record_holder(&lock)?;
+        let system_dir = control_file_path.parent().unwrap();
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&control_file_path)
+        {
+            Ok(file) => file,
+            Err(e) => {
+                io_error!(
+                    "Failed to open control file {:?}: {}",
+                    &control_file_path,
+                    e
+                );
+            }
+        };
+        /* wal_acceptor of older versions locks the control file itself */
+        if let Err(e) = file.try_lock_exclusive() {
+            io_error!(
+                "Control file {:?} is locked by some other process: {}",
+                &control_file_path,
+                e
+            );
+        }
+        file.unlock()?;
+        let mut content = Vec::new();
+        if let Err(e) = file.read_to_end(&mut content) {
+            io_error!(
+                "Failed to read control file {:?}: {}",
+                &control_file_path,
+                e
+            );
+        }
+        /* Empty control file is created for a new tenant */
+        let loaded = if content.is_empty() {
+            None
+        } else {
+            match ControlFileData::parse_file(&control_file_path, &content) {
+                Ok(loaded) => Some(loaded),
+                Err(e) => {
+                    io_error!("Can't load control file {:?}: {}", &control_file_path, e);
+                }
+            }
+        };
+        let loaded_flush_lsn = loaded
+            .as_ref()
+            .map_or(0, |(data, _, _)| data.info.flush_lsn);
+        if let Err(e) = recover_import(conf, self.id, system_dir, loaded_flush_lsn) {
+            io_error!(
+                "Can't sort out interrupted WAL import of tenant {}: {}",
+                self.id,
+                e
+            );
+        }
+
+        let first_load = {
+            let mut index = self.ingest_index.lock().unwrap();
+            let first_load = index.is_none();
+            if first_load {
+                *index = Some(IngestIndex::load(system_dir));
+            }
+            first_load
+        };
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        shared_state.control_lock = Some(lock);
+        shared_state.control_file = Some(file);
+        shared_state.control_file_path = control_file_path.clone();
+        shared_state.control_file_version =
+            conf.control_file_version.unwrap_or(CONTROL_FILE_VERSION);
+        let (data, format_version, slot) = match loaded {
+            Some(loaded) => loaded,
+            None => {
+                shared_state.control_generation = 0;
+                shared_state.durable_slot = 1;
+                return Ok(());
+            }
+        };
+        shared_state.control_generation = data.generation;
+        shared_state.durable_slot = slot;
+        let my_info = data.info;
+        shared_state.info = my_info;
+        shared_state.flushed_restart_lsn = my_info.restart_lsn;
+        shared_state.pg_versions = data.pg_versions;
+        shared_state.archived_lsn = data.archived_lsn;
+        shared_state.remote_consistent_lsn = data.remote_consistent_lsn;
+        self.flush_lsn.store(my_info.flush_lsn, Ordering::Release);
+        /*
+         * Control file is converted to the configured format: upgraded after start of a
+         * new release, downgraded before rollback to the previous one
+         */
+        let target_version = shared_state.control_file_version;
+        if format_version != target_version {
+            shared_state.save_control_file(true)?;
+            info!(
+                "Control file {:?} is {} from format version {} to {}",
+                &control_file_path,
+                if format_version < target_version {
+                    "upgraded"
+                } else {
+                    "downgraded"
+                },
+                format_version,
+                target_version
+            );
+        }
+
+        drop(shared_state);
+
+        /* Nobody writes WAL of the tenant yet, so leftovers can be sorted out */
+        let wal_seg_size = my_info.server.wal_seg_size as usize;
+        if first_load && wal_seg_size != 0 {
+            self.spares_wanted.notify_one();
+            let system_dir = control_file_path.parent().unwrap().to_path_buf();
+            let log_orphan = |fname: &str, reason: &str| {
+                let entry = recovery_log::Entry::new(
+                    recovery_log::Action::OrphanPartialSegment,
+                    self.id,
+                    format!("partial segment is {}", reason),
+                    "reconciliation at tenant load".to_string(),
+                )
+                .files(vec![fname.to_string()]);
+                recovery_log::record(&conf.data_dir, &entry)
+            };
+            if let Err(e) = reconcile_partial_segments(&system_dir, wal_seg_size, log_orphan) {
+                error!(
+                    "Failed to reconcile partial segments of system {}: {}",
+                    self.id, e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Run f with the storage of WAL of the tenant: local segments, or staged WAL of
+    // object storage, which is locked meanwhile
+    //
+    fn with_wal_storage<T>(
+        &self,
+        conf: &WalAcceptorConf,
+        wal_seg_size: usize,
+        f: impl FnOnce(&mut dyn WalStorage) -> Result<T>,
+    ) -> Result<T> {
+        match &conf.object_storage {
+            Some(storage) => {
+                let mut object_wal = self.object_wal.lock().unwrap();
+                if object_wal.is_none() {
+                    let system_dir = tenant_dir(&conf.data_dir, self.id);
+                    *object_wal = Some(ObjectWal::open(&system_dir, self.id)?);
+                }
+                f(&mut StagedObjectWal {
+                    system: self,
+                    conf: conf,
+                    storage: storage,
+                    wal: object_wal.as_mut().unwrap(),
+                })
+            }
+            None => f(&mut SegmentStorage {
+                system: self,
+                conf: conf,
+                wal_seg_size: wal_seg_size,
+            }),
+        }
+    }
+
+    //
+    // Write WAL to the storage of the tenant.
+    // Called on the blocking thread pool, see run_blocking().
+    //
+    fn write_wal_file(
+        &self,
+        conf: &WalAcceptorConf,
+        startpos: XLogRecPtr,
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+        buf: &[u8],
+        sync: bool,
+    ) -> Result<()> {
+        self.with_wal_storage(conf, wal_seg_size, |storage| {
+            storage.write(startpos, timeline, buf, sync)
+        })
+    }
+
+    // Position of the first stored byte of WAL differing from buf, see WalStorage::diverged
+    fn diverged_wal(
+        &self,
+        conf: &WalAcceptorConf,
+        startpos: XLogRecPtr,
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+        buf: &[u8],
+    ) -> Result<Option<XLogRecPtr>> {
+        self.with_wal_storage(conf, wal_seg_size, |storage| {
+            storage.diverged(startpos, timeline, buf)
+        })
+    }
+
+    //
+    // Write WAL to segment files of the tenant, creating them as needed.
+    // Without sync (group commit) only the segment being completed is synced, so that
+    // unsynced WAL is always in the last segment written, see sync_segments().
+    //
+    fn write_segments(
+        &self,
+        conf: &WalAcceptorConf,
+        startpos: XLogRecPtr,
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+        buf: &[u8],
+        sync: bool,
+    ) -> Result<()> {
+        let mut bytes_left: usize = buf.len();
+        let mut bytes_written: usize = 0;
+        let mut partial;
+        let mut start_pos = startpos;
+
+        /* Extract WAL location for this block */
+        let mut xlogoff = XLogSegmentOffset(start_pos, wal_seg_size) as usize;
+
+        while bytes_left != 0 {
+            let bytes_to_write;
+
+            /*
+             * If crossing a WAL boundary, only write up until we reach wal
+             * segment size.
+             */
+            if xlogoff + bytes_left > wal_seg_size {
+                bytes_to_write = wal_seg_size - xlogoff;
+            } else {
+                bytes_to_write = bytes_left;
+            }
+
+            /* Open file */
+            let segno = XLByteToSeg(start_pos, wal_seg_size);
+            let system_dir = tenant_dir(&conf.data_dir, self.id);
+            let segment = SegmentPath::new(&system_dir, timeline, segno, wal_seg_size);
+
+            {
+                let mut wal_file: File;
+                let cached = self.wal_files.lock().unwrap().take(timeline, segno);
+                if let Some((file, is_partial)) = cached {
+                    wal_file = file;
+                    partial = is_partial;
+                } else if let Some((file, is_partial)) =
+                    segment.open_for_write(conf.at_rest_key.as_ref())?
+                {
+                    /* Completed or partial segment, see partial_segment for the order */
+                    wal_file = file;
+                    partial = is_partial;
+                } else {
+                    /*
+                     * Create and fill new partial file.
+                     * In pg_wal layout it gets its final name right away, like in Postgres.
+                     */
+                    partial = !conf.pg_wal_layout;
+                    wal_file =
+                        self.prepare_segment(conf, segno, segment.path(partial), wal_seg_size)?;
+                }
+                let opened_path = segment.path(partial);
+                if !partial {
+                    *self
+                        .overwrites
+                        .lock()
+                        .unwrap()
+                        .entry(segment.name.clone())
+                        .or_insert(0) += 1;
+                }
+                wal_file.seek(SeekFrom::Start(xlogoff as u64))?;
+                let data = &buf[bytes_written..(bytes_written + bytes_to_write)];
+                fault_fs::write(opened_path, xlogoff as u64, data)?;
+                wal_file.write_all(data)?;
+
+                /*
+                 * Flush file is not prohibited. The range synced starts at the beginning
+                 * of the segment, to cover WAL written to it without sync by group commit.
+                 */
+                if !conf.no_sync && (sync || xlogoff + bytes_to_write == wal_seg_size) {
+                    let sync_start = clock::now();
+                    fault_fs::sync(opened_path)?;
+                    conf.sync_method.sync(&wal_file, 0, (xlogoff + bytes_to_write) as u64)?;
+                    self.account_fsync(clock::elapsed(sync_start));
+                }
+
+                /* Copy of the segment in the mirror has the same name and fsync policy of its own */
+                let mirror_no_sync = self.tenant_conf.mirror_no_sync;
+                self.mirror_wal(start_pos, data.len(), |mirror_dir| {
+                    let mirror_path = mirror_dir.join(opened_path.file_name().unwrap());
+                    let mut options = OpenOptions::new();
+                    options.create(true).write(true);
+                    let mut file = match options.open(&mirror_path) {
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                            fs::create_dir_all(mirror_dir)?;
+                            options.open(&mirror_path)?
+                        }
+                        res => res?,
+                    };
+                    file.seek(SeekFrom::Start(xlogoff as u64))?;
+                    file.write_all(data)?;
+                    if !mirror_no_sync {
+                        file.sync_all()?;
+                    }
+                    Ok(())
+                });
+                self.wal_files
+                    .lock()
+                    .unwrap()
+                    .put(timeline, segno, wal_file, partial);
+            }
+            /* Write was successful, advance our position */
+            bytes_written += bytes_to_write;
+            bytes_left -= bytes_to_write;
+            start_pos += bytes_to_write as u64;
+            xlogoff += bytes_to_write;
+
+            /* Did we reach the end of a WAL segment? */
+            if XLogSegmentOffset(start_pos, wal_seg_size) == 0 {
+                xlogoff = 0;
+                self.wal_files.lock().unwrap().remove(timeline, segno);
+                if partial {
+                    segment.complete()?;
+                    self.mirror_wal(start_pos, 0, |mirror_dir| {
+                        fs::rename(
+                            mirror_dir.join(segment.partial.file_name().unwrap()),
+                            mirror_dir.join(&segment.name),
+                        )
+                    });
+                }
+                if conf.pg_wal_layout {
+                    self.mark_segment_ready(conf, &segment.name)?;
+                }
+                events::emit(Event::SegmentCompleted {
+                    system_id: self.id,
+                    segno: segno,
+                    timeline: timeline,
+                });
+                self.queue_at_rest(&segment.name);
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Sync WAL written by write_wal_file() without sync, up to end_lsn.
+    // Called on the blocking thread pool, see run_blocking().
+    //
+    fn sync_wal_file(
+        &self,
+        conf: &WalAcceptorConf,
+        end_lsn: XLogRecPtr,
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+    ) -> Result<()> {
+        self.with_wal_storage(conf, wal_seg_size, |storage| storage.sync(end_lsn, timeline))
+    }
+
+    //
+    // Sync WAL written by write_segments() without sync, up to end_lsn. Segments completed
+    // meanwhile were synced on completion, so only the one holding end_lsn is left.
+    //
+    fn sync_segments(
+        &self,
+        conf: &WalAcceptorConf,
+        end_lsn: XLogRecPtr,
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+    ) -> Result<()> {
+        if conf.no_sync || XLogSegmentOffset(end_lsn, wal_seg_size) == 0 {
+            return Ok(());
+        }
+        let segno = XLByteToSeg(end_lsn, wal_seg_size);
+        let system_dir = tenant_dir(&conf.data_dir, self.id);
+        let segment = SegmentPath::new(&system_dir, timeline, segno, wal_seg_size);
+        let cached = self.wal_files.lock().unwrap().take(timeline, segno);
+        let opened = match cached {
+            Some(cached) => Some(cached),
+            None => segment.open_for_write(conf.at_rest_key.as_ref())?,
+        };
+        match opened {
+            Some((wal_file, partial)) => {
+                let sync_start = clock::now();
+                fault_fs::sync(segment.path(partial))?;
+                conf.sync_method.sync(&wal_file, 0, 0)?; /* whole file */
+                self.account_fsync(clock::elapsed(sync_start));
+                self.wal_files
+                    .lock()
+                    .unwrap()
+                    .put(timeline, segno, wal_file, partial);
+                Ok(())
+            }
+            None => {
+                io_error!("WAL segment {} to sync is missing", segment.name);
+            }
+        }
+    }
+
+    //
+    // Position of the first byte of WAL in segments of the tenant differing from buf
+    // written at startpos, None if WAL is the same. Missing WAL differs.
+    //
+    fn diverged_segments(
+        &self,
+        conf: &WalAcceptorConf,
+        startpos: XLogRecPtr,
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+        buf: &[u8],
+    ) -> Result<Option<XLogRecPtr>> {
+        let system_dir = tenant_dir(&conf.data_dir, self.id);
+        let mut stored = vec![0u8; XLOG_BLCKSZ * 8];
+        let mut pos = startpos;
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let segno = XLByteToSeg(pos, wal_seg_size);
+            let segment = SegmentPath::new(&system_dir, timeline, segno, wal_seg_size);
+            let mut file = match segment.open_for_read(conf.at_rest_key.as_ref()) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some(pos)),
+                Err(e) => return Err(e),
+            };
+            let offset = XLogSegmentOffset(pos, wal_seg_size) as u64;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut left = min(rest.len(), wal_seg_size - offset as usize);
+            while left > 0 {
+                let n = min(left, stored.len());
+                file.read_exact(&mut stored[..n])?;
+                if let Some(i) = (0..n).find(|&i| stored[i] != rest[i]) {
+                    return Ok(Some(pos + i as u64));
+                }
+                rest = &rest[n..];
+                left -= n;
+                pos += n as u64;
+            }
+        }
+        Ok(None)
+    }
+
+    //
+    // Make everything acknowledged durable before shutdown: sync the segment holding
+    // flush_lsn and write a synced copy of the control file with the final positions.
+    // The writer lock is taken first, so that an append still in progress is finished.
+    // Called on the blocking thread pool.
+    //
+    pub fn final_flush(&self, conf: &WalAcceptorConf) -> TenantFlush {
+        let _writer = self.writer.lock().unwrap();
+        let (info, loaded) = {
+            let shared_state = TENANT_LOCKS.lock(&self.mutex);
+            (shared_state.info, shared_state.control_file.is_some())
+        };
+        let mut flush = TenantFlush::new(self.id, &info);
+        if !loaded {
+            flush.status = "not loaded".to_string();
+            return flush;
+        }
+        let mut res = Ok(());
+        let wal_seg_size = info.server.wal_seg_size as usize;
+        if wal_seg_size != 0 {
+            res = self.sync_wal_file(conf, info.flush_lsn, info.server.timeline, wal_seg_size);
+        }
+        if res.is_ok() {
+            res = TENANT_LOCKS.lock(&self.mutex).save_control_file(true);
+        }
+        if let Err(e) = res {
+            flush.status = format!("failed: {}", e);
+        }
+        flush
+    }
+
+    //
+    // WAL horizon of the tenant: restart_lsn of the proposer, held back by end of archived
+    // WAL with --archive, WAL not checkpointed by pageserver once it reports checkpoints,
+    // --wal-retention, WAL received within --wal-retention-secs, positions of WAL senders,
+    // flush positions reported by replicas and the first segment not archived yet.
+    // Returns the horizon together with what limits it; WAL below the horizon is not
+    // needed by anyone.
+    //
+    fn gc_horizon(&self, conf: &WalAcceptorConf) -> Result<(XLogRecPtr, &'static str)> {
+        let (info, archived_lsn, remote_consistent_lsn, oldest_sender, min_replica_flush_lsn) = {
+            let shared_state = TENANT_LOCKS.lock(&self.mutex);
+            (
+                shared_state.info,
+                shared_state.archived_lsn,
+                shared_state.remote_consistent_lsn,
+                shared_state.senders.values().min().cloned(),
+                shared_state.min_replica_flush_lsn(),
+            )
+        };
+        let mut horizon = (info.restart_lsn, "restart_lsn");
+        let mut hold = |lsn: XLogRecPtr, reason: &'static str| {
+            if lsn < horizon.0 {
+                horizon = (lsn, reason);
+            }
+        };
+        if conf.archive.is_some() {
+            hold(archived_lsn, "archived_lsn");
+        }
+        /* Pageserver which has never reported a checkpoint doesn't hold WAL */
+        if remote_consistent_lsn != 0 {
+            hold(remote_consistent_lsn, "remote_consistent_lsn");
+        }
+        if let Some(retention) = conf.wal_retention {
+            hold(info.flush_lsn.saturating_sub(retention), "wal_retention");
+        }
+        if let Some(retention) = conf.wal_retention_time {
+            /*
+             * WAL received before the retention period ends at the closest indexed LSN. If
+             * the index doesn't go back that far, it is unknown when older WAL was received.
+             */
+            let cutoff = get_current_timestamp().saturating_sub(retention.as_micros() as u64);
+            let index = self.ingest_index.lock().unwrap();
+            let lsn = index.as_ref().and_then(|index| index.lsn_by_time(cutoff));
+            hold(lsn.unwrap_or(0), "wal_retention_time");
+        }
+        if let Some(sent_lsn) = oldest_sender {
+            hold(sent_lsn, "wal_sender");
+        }
+        if let Some(flush_lsn) = min_replica_flush_lsn {
+            hold(flush_lsn, "replica");
+        }
+        /* Segments not archived yet are kept, together with all the following ones */
+        let wal_seg_size = info.server.wal_seg_size as usize;
+        let status_dir = tenant_dir(&conf.data_dir, self.id).join(ARCHIVE_STATUS_DIR);
+        if conf.pg_wal_layout && wal_seg_size != 0 && status_dir.exists() {
+            let mut first_ready = None;
+            for entry in fs::read_dir(&status_dir)? {
+                let fname = entry?.file_name().to_string_lossy().into_owned();
+                if let Some(segment) = fname.strip_suffix(".ready") {
+                    if IsXLogFileName(segment) {
+                        let (segno, _tli) = XLogFromFileName(segment, wal_seg_size);
+                        first_ready = Some(first_ready.map_or(segno, |first| min(first, segno)));
+                    }
+                }
+            }
+            if let Some(segno) = first_ready {
+                hold(
+                    XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size),
+                    "unarchived_segment",
+                );
+            }
+        }
+        Ok(horizon)
+    }
+
+    //
+    // Confirm WAL removal proposed by pageserver: the effective cutoff is the proposed LSN
+    // held back by the WAL horizon and rounded down to segment boundary. Segments below
+    // it are removed by the GC task in the background, the returned proposal is
+    // updated with the progress (see gc_proposal).
+    //
+    pub fn propose_gc(
+        &self,
+        conf: &WalAcceptorConf,
+        lsn: XLogRecPtr,
+        initiator: &str,
+    ) -> Result<GcProposal> {
+        if conf.object_storage.is_some() {
+            io_error!(
+                "WAL of system {} is kept in object storage and is not removed",
+                self.id
+            );
+        }
+        if lsn == 0 {
+            io_error!("Cutoff LSN must be positive");
+        }
+        let wal_seg_size = TENANT_LOCKS.lock(&self.mutex).info.server.wal_seg_size as usize;
+        if wal_seg_size == 0 {
+            io_error!("System {} has not received WAL yet", self.id);
+        }
+        let (horizon, reason) = self.gc_horizon(conf)?;
+        let (cutoff, limited_by) = if horizon < lsn {
+            (horizon, reason)
+        } else {
+            (lsn, "proposal")
+        };
+        let cutoff = XLogSegNoOffsetToRecPtr(XLByteToSeg(cutoff, wal_seg_size), 0, wal_seg_size);
+        let proposal = {
+            let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+            let id = shared_state
+                .gc_proposal
+                .as_ref()
+                .map_or(1, |last| last.id + 1);
+            let proposal = GcProposal::new(id, lsn, cutoff, limited_by, initiator);
+            shared_state.gc_proposal = Some(proposal.clone());
+            shared_state.gc_cutoff = max(shared_state.gc_cutoff, cutoff);
+            proposal
+        };
+        info!(
+            "WAL removal #{} of system {} proposed by {} at {}: cutoff {} (limited by {})",
+            proposal.id,
+            self.id,
+            initiator,
+            format_lsn(lsn),
+            format_lsn(cutoff),
+            limited_by
+        );
+        self.request_gc();
+        Ok(proposal)
+    }
+
+    // Last proposed WAL removal with its progress, if there was any since start
+    pub fn gc_proposal(&self) -> Option<GcProposal> {
+        TENANT_LOCKS.lock(&self.mutex).gc_proposal.clone()
+    }
+
+    fn gc_proposal_pending(&self) -> bool {
+        let shared_state = TENANT_LOCKS.lock(&self.mutex);
+        matches!(&shared_state.gc_proposal, Some(p) if p.state == GcState::Pending)
+    }
+
+    // Update progress of the proposed removal, unless it is superseded by a newer one
+    fn update_gc_proposal<F: FnOnce(&mut GcProposal)>(&self, id: u64, f: F) {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        if let Some(proposal) = shared_state.gc_proposal.as_mut() {
+            if proposal.id == id {
+                f(proposal);
+            }
+        }
+    }
+
+    //
+    // Remove completed segments lying entirely below the WAL horizon, and below the
+    // cutoff of the pending proposed removal or, with --gc-coordinated, the highest
+    // confirmed cutoff. Returns the number of removed segments.
+    //
+    fn remove_old_segments(&self, conf: &WalAcceptorConf) -> Result<usize> {
+        if conf.object_storage.is_some() {
+            return Ok(0);
+        }
+        let (proposal, gc_cutoff) = {
+            let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+            let proposal = match shared_state.gc_proposal.as_mut() {
+                Some(proposal) if proposal.state == GcState::Pending => {
+                    proposal.state = GcState::Running;
+                    Some((proposal.id, proposal.cutoff_lsn))
+                }
+                _ => None,
+            };
+            (proposal, shared_state.gc_cutoff)
+        };
+        let res = self.gc_horizon(conf).and_then(|(mut horizon, _)| {
+            if let Some((_, cutoff)) = proposal {
+                horizon = min(horizon, cutoff);
+            } else if conf.gc_coordinated {
+                horizon = min(horizon, gc_cutoff);
+            }
+            self.remove_segments_below(conf, horizon, proposal.map(|(id, _)| id))
+        });
+        if let Some((id, _)) = proposal {
+            let error = res.as_ref().err().map(|e| e.to_string());
+            self.update_gc_proposal(id, |proposal| proposal.finish(error));
+        }
+        res
+    }
+
+    fn remove_segments_below(
+        &self,
+        conf: &WalAcceptorConf,
+        horizon: XLogRecPtr,
+        proposal_id: Option<u64>,
+    ) -> Result<usize> {
+        let wal_seg_size = TENANT_LOCKS.lock(&self.mutex).info.server.wal_seg_size as usize;
+        if wal_seg_size == 0 || horizon == 0 {
+            return Ok(0);
+        }
+        let horizon_segno = XLByteToSeg(horizon, wal_seg_size);
+
+        let system_dir = tenant_dir(&conf.data_dir, self.id);
+        let status_dir = system_dir.join(ARCHIVE_STATUS_DIR);
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&system_dir)? {
+            let fname = entry?.file_name().to_string_lossy().into_owned();
+            if IsXLogFileName(&fname) {
+                let (segno, _tli) = XLogFromFileName(&fname, wal_seg_size);
+                if segno < horizon_segno {
+                    segments.push((segno, fname));
+                }
+            }
+        }
+        segments.sort();
+        if let Some(id) = proposal_id {
+            let total = segments.len();
+            self.update_gc_proposal(id, |proposal| proposal.segments_total = total);
+        }
+        let (first_segno, last_segno) = match (segments.first(), segments.last()) {
+            (Some(first), Some(last)) => (first.0, last.0),
+            _ => return Ok(0),
+        };
+        let files: Vec<String> = segments.into_iter().map(|(_, fname)| fname).collect();
+        let entry = recovery_log::Entry::new(
+            recovery_log::Action::SegmentGc,
+            self.id,
+            format!("segments are below WAL horizon {}", format_lsn(horizon)),
+            "WAL GC".to_string(),
+        )
+        .lsns(
+            XLogSegNoOffsetToRecPtr(first_segno, 0, wal_seg_size),
+            XLogSegNoOffsetToRecPtr(last_segno + 1, 0, wal_seg_size),
+        )
+        .files(files.clone());
+        recovery_log::record(&conf.data_dir, &entry)?;
+        self.wal_files.lock().unwrap().clear();
+        let mut recycled = 0;
+        for fname in &files {
+            let path = system_dir.join(fname);
+            if self.recycle_segment(conf, &path, wal_seg_size)? {
+                recycled += 1;
+            } else {
+                fs::remove_file(&path)?;
+            }
+            for suffix in &[".ready", ".done"] {
+                let _ = fs::remove_file(status_dir.join(fname.clone() + suffix));
+            }
+            if let Some(id) = proposal_id {
+                self.update_gc_proposal(id, |proposal| proposal.segments_removed += 1);
+            }
+        }
+        File::open(&system_dir)?.sync_all()?;
+        self.wal_checksums.lock().unwrap().trim(
+            &system_dir,
+            horizon_segno,
+            wal_seg_size,
+            conf.no_sync,
+        )?;
+        self.account_wal_op(WalOp::Gc, files.len() as u64, (files.len() * wal_seg_size) as u64);
+        info!(
+            "Removed {} WAL segments of system {} below {} ({} recycled)",
+            files.len(),
+            self.id,
+            format_lsn(horizon),
+            recycled
+        );
+        Ok(files.len())
+    }
+
+    pub fn at_rest_policy(&self) -> AtRestPolicy {
+        TENANT_LOCKS.lock(&self.mutex).at_rest
+    }
+
+    //
+    // Change encoding of segments completed from now on. Existing segments are left
+    // as they are, unless start_at_rest_rewrite() is called.
+    //
+    pub fn set_at_rest_policy(&self, conf: &WalAcceptorConf, policy: AtRestPolicy) -> Result<()> {
+        policy.check(conf)?;
+        TenantConf::store_at_rest(&tenant_dir(&conf.data_dir, self.id), policy)?;
+        TENANT_LOCKS.lock(&self.mutex).at_rest = policy;
+        info!(
+            "At-rest policy of system {} is {}",
+            self.id,
+            policy.describe()
+        );
+        Ok(())
+    }
+
+    // Start rewriting all completed segments to the current policy in the GC task
+    pub fn start_at_rest_rewrite(&self) -> Result<RewriteJob> {
+        let job = {
+            let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+            if let Some(job) = &shared_state.at_rest_job {
+                if job.state == RewriteState::Pending || job.state == RewriteState::Running {
+                    io_error!("Rewrite of segments of system {} is in progress", self.id);
+                }
+            }
+            let job = RewriteJob::new(shared_state.at_rest);
+            shared_state.at_rest_job = Some(job.clone());
+            job
+        };
+        self.request_gc();
+        Ok(job)
+    }
+
+    pub fn describe_at_rest(&self) -> String {
+        let shared_state = TENANT_LOCKS.lock(&self.mutex);
+        let mut s = format!("{}\n", shared_state.at_rest.describe());
+        if !shared_state.at_rest_pending.is_empty() {
+            s += &format!(
+                "{} completed segments to encode\n",
+                shared_state.at_rest_pending.len()
+            );
+        }
+        if let Some(job) = &shared_state.at_rest_job {
+            s += &job.describe();
+        }
+        s
+    }
+
+    fn at_rest_job_pending(&self) -> bool {
+        let shared_state = TENANT_LOCKS.lock(&self.mutex);
+        matches!(&shared_state.at_rest_job, Some(job) if job.state == RewriteState::Pending)
+    }
+
+    fn update_at_rest_job<F: FnOnce(&mut RewriteJob)>(&self, f: F) {
+        if let Some(job) = TENANT_LOCKS.lock(&self.mutex).at_rest_job.as_mut() {
+            f(job);
+        }
+    }
+
+    // Completed segment is encoded by the GC task, if the policy asks for it
+    fn queue_at_rest(&self, segment_name: &str) {
+        let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+        if !shared_state.at_rest.is_plain() {
+            shared_state.at_rest_pending.push(segment_name.to_string());
+        }
+    }
+
+    //
+    // Bring completed segments to the at-rest policy: the ones completed since the last
+    // pass, or all of them if a rewrite job is pending. Returns the number of rewritten
+    // segments.
+    //
+    fn apply_at_rest_policy(&self, conf: &WalAcceptorConf) -> Result<usize> {
+        let (policy, pending, job) = {
+            let mut shared_state = TENANT_LOCKS.lock(&self.mutex);
+            let job = match shared_state.at_rest_job.as_mut() {
+                Some(job) if job.state == RewriteState::Pending => {
+                    job.state = RewriteState::Running;
+                    true
+                }
+                _ => false,
+            };
+            let pending = mem::take(&mut shared_state.at_rest_pending);
+            (shared_state.at_rest, pending, job)
+        };
+        let res = self.rewrite_segments(conf, policy, if job { None } else { Some(pending) });
+        if job {
+            self.update_at_rest_job(|job| match &res {
+                Ok(_) => job.state = RewriteState::Done,
+                Err(e) => {
+                    job.state = RewriteState::Failed;
+                    job.error = Some(e.to_string());
+                }
+            });
+        }
+        if let Ok(rewritten) = &res {
+            if *rewritten > 0 {
+                info!(
+                    "Rewrote {} WAL segments of system {} to {}",
+                    rewritten,
+                    self.id,
+                    policy.describe()
+                );
+            }
+        }
+        res
+    }
+
+    //
+    // Rewrite the given completed segments to the policy, or all of them (a rewrite job)
+    //
+    fn rewrite_segments(
+        &self,
+        conf: &WalAcceptorConf,
+        policy: AtRestPolicy,
+        segments: Option<Vec<String>>,
+    ) -> Result<usize> {
+        let system_dir = tenant_dir(&conf.data_dir, self.id);
+        let job = segments.is_none();
+        let segments = match segments {
+            Some(segments) => segments,
+            None => {
+                let mut segments = Vec::new();
+                for entry in fs::read_dir(&system_dir)? {
+                    let fname = entry?.file_name().to_string_lossy().into_owned();
+                    if IsXLogFileName(&fname) {
+                        segments.push(fname);
+                    }
+                }
+                segments.sort();
+                let total = segments.len();
+                self.update_at_rest_job(|job| job.segments_total = total);
+                segments
+            }
+        };
+        let mut rewritten = 0;
+        for fname in &segments {
+            let done = self.rewrite_segment(conf, &system_dir, fname, policy)?;
+            if done {
+                rewritten += 1;
+            }
+            if job {
+                self.update_at_rest_job(|job| {
+                    job.segments_checked += 1;
+                    job.segments_rewritten += done as usize;
+                });
+            }
+        }
+        Ok(rewritten)
+    }
+
+    //
+    // Rewrite completed segment in the form required by the policy, unless it is in this
+    // form already. It is replaced under the writer lock, and only if a proposer of a new
+    // term hasn't overwritten it meanwhile (see overwrites). Returns whether the segment
+    // was rewritten.
+    //
+    fn rewrite_segment(
+        &self,
+        conf: &WalAcceptorConf,
+        system_dir: &Path,
+        fname: &str,
+        policy: AtRestPolicy,
+    ) -> Result<bool> {
+        let path = system_dir.join(fname);
+        let generation = || self.overwrites.lock().unwrap().get(fname).cloned();
+        /* Writes are done under the writer lock, so none is in progress at the snapshot */
+        let prepared_generation = {
+            let _writer = self.writer.lock().unwrap();
+            generation()
+        };
+        let key = conf.at_rest_key.as_ref();
+        let tmp_path = match at_rest::prepare_rewrite(&path, policy, key, conf.no_sync) {
+            Ok(Some(tmp_path)) => tmp_path,
+            Ok(None) => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let _writer = self.writer.lock().unwrap();
+        if generation() != prepared_generation || !path.exists() {
+            fs::remove_file(&tmp_path)?;
+            return Ok(false);
+        }
+        self.wal_files.lock().unwrap().clear();
+        fs::rename(&tmp_path, &path)?;
+        if !conf.no_sync {
+            File::open(system_dir)?.sync_all()?;
+        }
+        Ok(true)
+    }
+
+    //
+    // Create archive_status/<segment>.ready for completed segment, so that
+    // archivers like wal-g or pgBackRest can pick it up
+    //
+    fn mark_segment_ready(&self, conf: &WalAcceptorConf, wal_file_name: &str) -> Result<()> {
+        let system_dir = tenant_dir(&conf.data_dir, self.id);
+        let status_dir = system_dir.join(ARCHIVE_STATUS_DIR);
+        fs::create_dir_all(&status_dir)?;
+        if !is_segment_archivable(&system_dir, wal_file_name) {
+            File::create(status_dir.join(wal_file_name.to_owned() + ".ready"))?;
+        }
+        Ok(())
+    }
+}
+
+//
+// WAL GC of a tenant: remove old segments when the horizon moves, on request, when
+// pageserver proposes removal and every GC_INTERVAL. Exits when the tenant is unloaded.
+// Completed segments are brought to the at-rest policy of the tenant by the same task.
+//
+async fn gc_loop(system: Weak<System>, conf: Arc<WalAcceptorConf>) {
+    loop {
+        if let Some(system) = system.upgrade() {
+            /* Proposed removal confirmed during the previous pass doesn't wait */
+            let notified = system.horizon_changed.notified();
+            if !system.gc_proposal_pending() && !system.at_rest_job_pending() {
+                tokio::select! {
+                    _ = notified => {}
+                    _ = sleep(GC_INTERVAL) => {}
+                }
+            }
+        }
+        let system = match system.upgrade() {
+            Some(system) => system,
+            None => return,
+        };
+        let id = system.id;
+        let gc_conf = conf.clone();
+        let gc_system = system.clone();
+        match run_blocking(move || gc_system.remove_old_segments(&gc_conf)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) | Err(e) => error!("WAL GC of system {} failed: {}", id, e),
+        }
+        let at_rest_conf = conf.clone();
+        match run_blocking(move || system.apply_at_rest_policy(&at_rest_conf)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) | Err(e) => error!("At-rest encoding of system {} failed: {}", id, e),
+        }
+    }
+}
+
+//
+// Preallocation of spare segments of a tenant, see spare_segments.rs: the pool is refilled
+// when a segment is taken or recycled, and every GC_INTERVAL, e.g. to fill it once the
+// tenant is loaded. Exits when the tenant is unloaded.
+//
+async fn prealloc_loop(system: Weak<System>, conf: Arc<WalAcceptorConf>) {
+    loop {
+        let system = match system.upgrade() {
+            Some(system) => system,
+            None => return,
+        };
+        let id = system.id;
+        let notified = system.spares_wanted.notified();
+        let prealloc_conf = conf.clone();
+        let prealloc_system = system.clone();
+        match run_blocking(move || prealloc_system.fill_spare_segments(&prealloc_conf)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) | Err(e) => error!("Preallocation of segments of system {} failed: {}", id, e),
+        }
+        tokio::select! {
+            _ = notified => {}
+            _ = sleep(GC_INTERVAL) => {}
+        }
+    }
+}
+
+//
+// Local segments of the tenant, the default storage of WAL
+//
+struct SegmentStorage<'a> {
+    system: &'a System,
+    conf: &'a WalAcceptorConf,
+    wal_seg_size: usize,
+}
+
+impl WalStorage for SegmentStorage<'_> {
+    fn write(
+        &mut self,
+        startpos: XLogRecPtr,
+        timeline: TimeLineID,
+        buf: &[u8],
+        sync: bool,
+    ) -> Result<()> {
+        let wal_seg_size = self.wal_seg_size;
+        self.system
+            .write_segments(self.conf, startpos, timeline, wal_seg_size, buf, sync)
+    }
+
+    fn sync(&mut self, end_lsn: XLogRecPtr, timeline: TimeLineID) -> Result<()> {
+        self.system
+            .sync_segments(self.conf, end_lsn, timeline, self.wal_seg_size)
+    }
+
+    fn end_of_wal(&mut self, precise: bool) -> Result<(XLogRecPtr, TimeLineID)> {
+        let system_dir = tenant_dir(&self.conf.data_dir, self.system.id);
+        Ok(find_end_of_wal(
+            &system_dir,
+            self.wal_seg_size,
+            precise,
+            self.conf.pg_wal_layout,
+        ))
+    }
+
+    fn diverged(
+        &mut self,
+        startpos: XLogRecPtr,
+        timeline: TimeLineID,
+        buf: &[u8],
+    ) -> Result<Option<XLogRecPtr>> {
+        let wal_seg_size = self.wal_seg_size;
+        self.system
+            .diverged_segments(self.conf, startpos, timeline, wal_seg_size, buf)
+    }
+}
+
+//
+// WAL staged for object storage (--object-storage), synced by each append
+//
+struct StagedObjectWal<'a> {
+    system: &'a System,
+    conf: &'a WalAcceptorConf,
+    storage: &'a ObjectStorageConf,
+    wal: &'a mut ObjectWal,
+}
+
+impl WalStorage for StagedObjectWal<'_> {
+    fn write(
+        &mut self,
+        startpos: XLogRecPtr,
+        timeline: TimeLineID,
+        buf: &[u8],
+        _sync: bool,
+    ) -> Result<()> {
+        let sync_start = clock::now();
+        self.wal
+            .append(self.storage, self.conf.no_sync, startpos, timeline, buf)?;
+        if !self.conf.no_sync {
+            self.system.account_fsync(clock::elapsed(sync_start));
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self, _end_lsn: XLogRecPtr, _timeline: TimeLineID) -> Result<()> {
+        Ok(()) /* appends are durable when they return */
+    }
+
+    /* Doesn't access the bucket */
+    fn end_of_wal(&mut self, _precise: bool) -> Result<(XLogRecPtr, TimeLineID)> {
+        Ok(self.wal.end_of_wal())
+    }
+
+    /* Doesn't read WAL back from the bucket, so any overwrite is taken as diverged */
+    fn diverged(
+        &mut self,
+        startpos: XLogRecPtr,
+        _timeline: TimeLineID,
+        buf: &[u8],
+    ) -> Result<Option<XLogRecPtr>> {
+        Ok(if buf.is_empty() { None } else { Some(startpos) })
+    }
+}
+
+//
+// Run blocking file I/O (writes and fsyncs of WAL and control file) on the blocking
+// thread pool, so that a slow disk of one tenant doesn't stall all connections of
+// the runtime. Tenant locks taken by f are std mutexes, fine to hold in the pool.
+//
+pub(crate) async fn run_blocking<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(f)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+/* Reallocate buffer grown beyond the baseline, keeping its first len bytes */
+fn shrink_buffer(buf: &mut BytesMut, len: usize) {
+    if buf.capacity() > BUFFER_BASELINE {
+        let mut shrunk = BytesMut::with_capacity(max(BUFFER_BASELINE, len));
+        shrunk.extend_from_slice(&buf[..len]);
+        *buf = shrunk;
+    }
+}
+
+impl Connection {
+    pub fn new(stream: Stream, conf: &WalAcceptorConf, tenants: Arc<TenantRegistry>) -> Connection {
+        let registration = ConnectionRegistration::new(stream.peer_addr().ok());
+        Connection {
+            system: None,
+            stream: stream,
+            proposer_state: ProposerState::Handshake,
+            prebuf: BytesMut::new(),
+            trace: None,
+            migrated: false,
+            inbuf: BytesMut::with_capacity(BUFFER_BASELINE),
+            outbuf: BytesMut::with_capacity(BUFFER_BASELINE),
+            init_done: false,
+            authenticated: false,
+            conf: Arc::new(conf.clone()),
+            registration: registration,
+            large_io_at: clock::now(),
+            reported_buffers: 0,
+            pending_ack: None,
+            tenants: tenants,
+            flow_control: false,
+            status_codes: false,
+            ack_each_append: false,
+            codec: Codec::default(),
+            protocol_version: 0,
+        }
+    }
+
+    fn system(&self) -> Arc<System> {
+        self.system.as_ref().unwrap().clone()
+    }
+
+    // Directory with WAL segments and control file of the current system
+    fn system_dir(&self) -> PathBuf {
+        tenant_dir(&self.conf.data_dir, self.system().id)
+    }
+
+    //
+    // Serve connection until it is closed or has to be moved to the dedicated
+    // runtime of its tenant. In the latter case return where to continue.
+    //
+    async fn run(&mut self) -> Result<Option<Continuation>> {
+        self.inbuf.resize(4, 0u8);
+        self.stream.read_exact(&mut self.inbuf[0..4]).await?;
+        let startup_pkg_len = BigEndian::read_u32(&mut self.inbuf[0..4]);
+        if startup_pkg_len == SK_GREETING_MAGIC || startup_pkg_len == 0 {
+            if let Some(trace_dir) = &self.conf.trace_dir {
+                let peer = self.stream.peer_addr()?.to_string();
+                let mut trace = TraceWriter::create(trace_dir, &peer)?;
+                trace.record(TRACE_RECEIVED, &self.inbuf[0..4])?;
+                self.trace = Some(trace);
+            }
+            let res = self.serve_proposer(startup_pkg_len).await;
+            if let Ok(Some(_)) = res {
+                return res;
+            }
+            self.finish_trace()?;
+            res
+        } else {
+            self.send_wal().await // libpq replication protocol between wal_acceptor and replicas/pagers
+        }
+    }
+
+    fn finish_trace(&mut self) -> Result<()> {
+        if let (Some(trace), Some(system)) = (self.trace.as_mut(), self.system.as_ref()) {
+            trace.record_state(TRACE_FINAL_STATE, system.id, &system.info_bytes())?;
+        }
+        Ok(())
+    }
+
+    //
+    // Connection of isolated tenant is still served by the shared runtime.
+    // TLS session can't be moved between runtimes, so encrypted connections stay there.
+    //
+    fn needs_migration(&self) -> bool {
+        !self.migrated && self.system().runtime.is_some() && !self.stream.is_tls()
+    }
+
+    //
+    // Continue serving the connection on the dedicated runtime of its tenant
+    //
+    async fn migrate(self, cont: Continuation) -> Result<()> {
+        let tenant = self.system();
+        let handle = tenant.runtime.clone().unwrap();
+        info!(
+            "Move connection from {:?} to dedicated runtime of system {}",
+            self.stream.peer_addr()?,
+            tenant.id
+        );
+        let Connection {
+            system,
+            stream,
+            proposer_state,
+            prebuf,
+            trace,
+            inbuf,
+            outbuf,
+            init_done,
+            authenticated,
+            conf,
+            registration,
+            large_io_at,
+            reported_buffers,
+            pending_ack,
+            tenants,
+            flow_control,
+            status_codes,
+            ack_each_append,
+            codec,
+            protocol_version,
+            ..
+        } = self;
+        let stream = stream.into_std()?;
+        handle
+            .spawn(async move {
+                let mut conn = Connection {
+                    system,
+                    stream: Stream::Plain(TcpStream::from_std(stream)?),
+                    proposer_state,
+                    prebuf,
+                    trace,
+                    inbuf,
+                    outbuf,
+                    init_done,
+                    authenticated,
+                    migrated: true,
+                    conf,
+                    registration,
+                    large_io_at,
+                    reported_buffers,
+                    pending_ack,
+                    tenants,
+                    flow_control,
+                    status_codes,
+                    ack_each_append,
+                    codec,
+                    protocol_version,
+                };
+                conn.resume(cont).await
+            })
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+    }
+
+    async fn resume(&mut self, cont: Continuation) -> Result<()> {
+        match cont {
+            Continuation::ReceiveWal(server_info) => {
+                let res = self.accept_wal(server_info).await;
+                self.finish_trace()?;
+                res
+            }
+            Continuation::SendWal => self.serve_libpq().await.map(|_| ()),
+        }
+    }
+
+    // Internal protocol between wal_proposer and wal_acceptor
+    async fn serve_proposer(&mut self, startup_pkg_len: u32) -> Result<Option<Continuation>> {
+        if startup_pkg_len == SK_GREETING_MAGIC {
+            let greeting = match self.read_proposer_message(ProposerMessageKind::Greeting).await? {
+                ProposerMessage::Greeting(greeting) => greeting,
+                _ => unreachable!(),
+            };
+            let role = self.check_greeting(&greeting)?;
+            self.flow_control = (greeting.role & PEER_CAP_FLOW_CONTROL) != 0;
+            self.status_codes = (greeting.role & PEER_CAP_STATUS_CODES) != 0;
+            self.ack_each_append = (greeting.role & PEER_CAP_ACK_EACH_APPEND) != 0;
+            self.codec = Codec::from_greeting(&greeting);
+            self.negotiate_version(&greeting).await?;
+            match role {
+                PeerRole::Proposer => {
+                    self.check_acceptor_set(None)?;
+                    self.receive_wal().await
+                }
+                PeerRole::ProposerWithAcceptorSet => {
+                    let kind = ProposerMessageKind::AcceptorSetClaim;
+                    let claim = match self.read_proposer_message(kind).await? {
+                        ProposerMessage::AcceptorSetClaim(claim) => claim,
+                        _ => unreachable!(),
+                    };
+                    self.check_acceptor_set(Some(&claim))?;
+                    self.receive_wal().await
+                }
+            }
+        } else {
+            /*
+             * Legacy proposers announce themselves with zero-length startup packet.
+             * TODO: remove in the next release, when all proposers send the greeting frame.
+             */
+            warn!(
+                "wal_proposer {} uses deprecated zero-length greeting",
+                self.stream.peer_addr()?
+            );
+            self.check_acceptor_set(None)?;
+            self.receive_wal().await
+        }
+    }
+
+    // Validate greeting frame and return role of the peer
+    fn check_greeting(&self, greeting: &PeerGreeting) -> Result<PeerRole> {
+        match PeerRole::from_u32(greeting.role & PEER_ROLE_MASK) {
+            Some(role) => Ok(role),
+            None => {
+                io_error!("Unknown peer role {}", greeting.role);
+            }
+        }
+    }
+
+    //
+    // Settle on the protocol version of the session. Proposer announcing version
+    // negotiation gets the supported range and the highest common version (even if
+    // there is none, so that it can report why it is refused), the others must speak
+    // a supported version.
+    //
+    async fn negotiate_version(&mut self, greeting: &PeerGreeting) -> Result<()> {
+        let proposer_version = greeting.protocol_version;
+        let version = if (greeting.role & PEER_CAP_VERSION_NEGOTIATION) != 0 {
+            let versions = ProtocolVersions::negotiate(proposer_version);
+            self.start_sending();
+            self.codec
+                .encode_acceptor(&AcceptorMessage::Versions(versions), &mut self.outbuf);
+            self.send().await?;
+            versions.selected_version
+        } else if (SK_MIN_PROTOCOL_VERSION..=SK_PROTOCOL_VERSION).contains(&proposer_version) {
+            proposer_version
+        } else {
+            0
+        };
+        if version == 0 {
+            io_error!(
+                "Incompatible protocol version {}, supported versions are {}..{}",
+                proposer_version,
+                SK_MIN_PROTOCOL_VERSION,
+                SK_PROTOCOL_VERSION
+            );
+        }
+        debug!(
+            "Protocol version {} is used with wal_proposer {}",
+            version,
+            self.stream.peer_addr()?
+        );
+        self.protocol_version = version;
+        self.codec.version = version;
+        Ok(())
+    }
+
+    //
+    // Compare acceptor set claimed by proposer with the configured one.
+    // If the acceptor set is configured, proposers which don't claim it are rejected too.
+    //
+    fn check_acceptor_set(&self, claim: Option<&AcceptorSetClaim>) -> Result<()> {
+        let peer = self.stream.peer_addr()?;
+        match (&self.conf.acceptor_set, claim) {
+            (None, Some(claim)) => {
+                info!(
+                    "wal_proposer {} considers this safekeeper #{} of {} with quorum {}",
+                    peer, claim.acceptor_index, claim.n_acceptors, claim.quorum
+                );
+            }
+            (None, None) => {}
+            (Some(_), None) => {
+                io_error!(
+                    "wal_proposer {} doesn't announce its acceptor set, which is required by configuration",
+                    peer
+                );
+            }
+            (Some(set), Some(claim)) => {
+                if claim.acceptor_index != set.node_index
+                    || claim.n_acceptors as usize != set.peers.len()
+                    || claim.quorum != set.quorum
+                {
+                    io_error!(
+                        "wal_proposer {} is misconfigured: it considers this safekeeper #{} of {} with quorum {}, while it is #{} of {} with quorum {}",
+                        peer,
+                        claim.acceptor_index,
+                        claim.n_acceptors,
+                        claim.quorum,
+                        set.node_index,
+                        set.peers.len(),
+                        set.quorum
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Read the next message of the proposer, of the kind expected in the current state
+     * of the session. Its size is learnt from the header (frame length or append LSNs)
+     * before the rest is read.
+     */
+    async fn read_proposer_message(
+        &mut self,
+        kind: ProposerMessageKind,
+    ) -> Result<ProposerMessage> {
+        let header_size = self.codec.header_size(kind);
+        self.fill_buffered(header_size).await?;
+        let size = self.codec.message_size(kind, &self.prebuf[..header_size])?;
+        self.read_exact_buffered(size).await?;
+        match self.codec.decode_proposer(kind, &mut self.inbuf)? {
+            Some(msg) => Ok(msg),
+            None => {
+                io_error!("Incomplete {:?} message of {} bytes", kind, size);
+            }
+        }
+    }
+
+    // Read exactly n bytes of proposer stream into inbuf
+    async fn read_exact_buffered(&mut self, n: usize) -> Result<()> {
+        self.fill_buffered(n).await?;
+        if n > BUFFER_BASELINE {
+            self.large_io_at = clock::now();
+        }
+        self.inbuf.clear();
+        self.inbuf.extend_from_slice(&self.prebuf.split_to(n));
+        Ok(())
+    }
+
+    /*
+     * Make sure at least n bytes of proposer stream are in prebuf. During handshake nothing
+     * is read beyond the current message. Once streaming, up to max_inflight_msgs
+     * append messages are pre-read from the socket, so that proposer can pipeline them.
+     */
+    async fn fill_buffered(&mut self, n: usize) -> Result<()> {
+        let limit = if self.proposer_state == ProposerState::Streaming {
+            self.conf.max_inflight_msgs.max(1) * (SafeKeeperRequest::SIZE + MAX_SEND_SIZE)
+        } else {
+            n
+        };
+        if self.prebuf.len() < n {
+            /* Don't keep appends unacknowledged while waiting for the proposer */
+            self.flush_ack().await?;
+        }
+        while self.prebuf.len() < n {
+            let have = self.prebuf.len();
+            /* Read into spare capacity, the read-ahead window is not zeroed on every read */
+            let want = max(limit, n) - have;
+            self.prebuf.reserve(want);
+            let read = (&mut self.stream)
+                .take(want as u64)
+                .read_buf(&mut self.prebuf);
+            let nread = match self.conf.proposer_read_timeout {
+                Some(read_timeout) => match clock::timeout(read_timeout, read).await {
+                    Ok(res) => res?,
+                    Err(_) => {
+                        io_error!(
+                            "wal_proposer {} has sent nothing for {:?}, close connection",
+                            self.stream.peer_addr()?,
+                            read_timeout
+                        );
+                    }
+                },
+                None => read.await?,
+            };
+            if let Some(trace) = self.trace.as_mut() {
+                trace.record(TRACE_RECEIVED, &self.prebuf[have..])?;
+            }
+            if nread == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "proposer closed connection",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Called when connection is idle. Report buffer sizes to diagnostics, and give back
+     * memory of buffers grown by large messages once they are not needed for a while,
+     * so that memory is proportional to the current load rather than to the past peaks.
+     * Nothing to be sent is pending at this point, so output buffer may be truncated.
+     */
+    fn trim_buffers(&mut self) {
+        let capacity = self.inbuf.capacity() + self.outbuf.capacity() + self.prebuf.capacity();
+        if capacity != self.reported_buffers {
+            self.registration.set_buffers(capacity);
+            self.reported_buffers = capacity;
+        }
+        if capacity <= 3 * BUFFER_BASELINE
+            || clock::elapsed(self.large_io_at) < BUFFER_SHRINK_DELAY
+        {
+            return;
+        }
+        let outbuf_len = min(self.outbuf.len(), BUFFER_BASELINE);
+        shrink_buffer(&mut self.outbuf, outbuf_len);
+        for buf in [&mut self.inbuf, &mut self.prebuf].iter_mut() {
+            let len = buf.len();
+            if len <= BUFFER_BASELINE {
+                shrink_buffer(buf, len);
+            }
+        }
+        let capacity = self.inbuf.capacity() + self.outbuf.capacity() + self.prebuf.capacity();
+        debug!(
+            "Buffers of connection #{} are shrunk from {} to {} bytes",
+            self.registration.id(),
+            self.reported_buffers,
+            capacity
+        );
+        self.registration.set_buffers(capacity);
+        self.reported_buffers = capacity;
+    }
+
+    /*
+     * Send response to proposer, piggybacking combined hot standby feedback of replicas.
+     * Deferred acknowledgement of appends is sent first, unless the response is OK and
+     * covers them anyway. Failure statuses are sent only to proposers understanding them,
+     * older ones would take them for acknowledgements. Proposers of protocol version 1
+     * get acknowledgements (OK) only, their responses have no status.
+     */
+    async fn send_response(
+        &mut self,
+        status: u32,
+        epoch: u64,
+        flush_lsn: XLogRecPtr,
+        received_lsn: XLogRecPtr,
+    ) -> Result<()> {
+        let system = self.system();
+        let (hs_feedback, hs_replicas) = system.get_hs_feedback();
+        let pending = self.pending_ack.take();
+        self.start_sending();
+        if let Some(ack) = pending.as_ref().filter(|_| status != SK_STATUS_OK) {
+            let resp = SafeKeeperResponse {
+                status: SK_STATUS_OK,
+                hs_replicas: hs_replicas,
+                epoch: ack.epoch,
+                flush_lsn: ack.flush_lsn,
+                received_lsn: ack.received_lsn,
+                hs_feedback: hs_feedback,
+            };
+            self.codec
+                .encode_acceptor(&AcceptorMessage::Response(resp), &mut self.outbuf);
+        }
+        let sendable = if self.codec.response_status() {
+            self.status_codes || !(SK_STATUS_STALE_TERM..=SK_STATUS_CORRUPT_WAL).contains(&status)
+        } else {
+            status == SK_STATUS_OK
+        };
+        if sendable {
+            let resp = SafeKeeperResponse {
+                status: status,
+                hs_replicas: hs_replicas,
+                epoch: epoch,
+                flush_lsn: flush_lsn,
+                received_lsn: received_lsn,
+                hs_feedback: hs_feedback,
+            };
+            self.codec
+                .encode_acceptor(&AcceptorMessage::Response(resp), &mut self.outbuf);
+        }
+        if !self.outbuf.is_empty() {
+            self.send().await?;
+        }
+        if let Some(ack) = pending {
+            for (end_lsn, received) in ack.appends {
+                system.account_ack(end_lsn, received);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Acknowledge append received at the given time. With --max-ack-delay-ms, while
+     * more appends pipelined by proposer are already read ahead, the acknowledgement
+     * is deferred, so that one response with the highest LSNs covers the whole batch.
+     * It is sent before waiting for proposer, with any other response, or once the
+     * first append of the batch has waited for max_ack_delay. Proposer announcing
+     * PEER_CAP_ACK_EACH_APPEND gets every append acknowledged as soon as it is flushed.
+     */
+    async fn ack_append(
+        &mut self,
+        epoch: u64,
+        flush_lsn: XLogRecPtr,
+        received_lsn: XLogRecPtr,
+        received: Instant,
+    ) -> Result<()> {
+        self.defer_ack(epoch, flush_lsn, received_lsn, received);
+        let first_received = self.pending_ack.as_ref().unwrap().appends[0].1;
+        match self.conf.max_ack_delay.filter(|_| !self.ack_each_append) {
+            Some(delay) if !self.prebuf.is_empty() && clock::elapsed(first_received) < delay => {
+                Ok(())
+            }
+            _ => self.flush_ack().await,
+        }
+    }
+
+    /* Add append to the deferred acknowledgement without sending anything */
+    fn defer_ack(
+        &mut self,
+        epoch: u64,
+        flush_lsn: XLogRecPtr,
+        received_lsn: XLogRecPtr,
+        received: Instant,
+    ) {
+        let mut ack = self.pending_ack.take().unwrap_or(PendingAck {
+            epoch: epoch,
+            flush_lsn: flush_lsn,
+            received_lsn: received_lsn,
+            appends: Vec::new(),
+        });
+        ack.epoch = epoch;
+        ack.flush_lsn = flush_lsn;
+        ack.received_lsn = received_lsn;
+        ack.appends.push((received_lsn, received));
+        self.pending_ack = Some(ack);
+    }
+
+    /*
+     * Sync WAL written without sync by group commit, advance flush_lsn over it and send
+     * the acknowledgement deferred until then. Both are done under the writer lock, so
+     * flush_lsn in the control file never covers WAL which isn't durable. Control data
+     * of the session is updated.
+     */
+    async fn sync_unsynced(&mut self, session: &mut ProposerSession) -> Result<()> {
+        let unsynced = match session.unsynced.take() {
+            Some(unsynced) => unsynced,
+            None => return Ok(()),
+        };
+        let system = self.system();
+        let conf = self.conf.clone();
+        let conn_id = self.registration.id();
+        let end_lsn = unsynced.end_lsn;
+        let (timeline, wal_seg_size) = (session.timeline, session.wal_seg_size);
+        let (epoch, _, received_lsn) = session.positions();
+        let res = run_blocking(move || match system.lock_writer(conn_id) {
+            Some(_writer) => Some(
+                system
+                    .sync_wal_file(&conf, end_lsn, timeline, wal_seg_size)
+                    .and_then(|_| {
+                        system.update_info(|info| {
+                            if end_lsn > info.flush_lsn {
+                                info.flush_lsn = end_lsn;
+                            }
+                            Ok(())
+                        })
+                    }),
+            ),
+            None => None,
+        })
+        .await?;
+        let info = match res {
+            Some(Ok(info)) => info,
+            Some(Err(e)) => {
+                let flush_lsn = self.system().get_info().flush_lsn;
+                return Err(self.report_failure(e, epoch, flush_lsn, received_lsn).await);
+            }
+            None => {
+                let flush_lsn = self.system().get_info().flush_lsn;
+                return self.fence(epoch, flush_lsn, received_lsn).await;
+            }
+        };
+        if let Some(ack) = self.pending_ack.as_mut() {
+            ack.flush_lsn = info.flush_lsn;
+        }
+        self.flush_ack().await?;
+        self.system()
+            .notify_wal_senders(min(unsynced.commit_lsn, info.flush_lsn));
+        session.my_info = info;
+        Ok(())
+    }
+
+    /* Send deferred acknowledgement of appends, if any */
+    async fn flush_ack(&mut self) -> Result<()> {
+        match self.pending_ack.as_ref() {
+            Some(&PendingAck {
+                epoch,
+                flush_lsn,
+                received_lsn,
+                ..
+            }) => {
+                self.send_response(SK_STATUS_OK, epoch, flush_lsn, received_lsn)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /*
+     * Tell proposer why we are going to close the connection, so that it can
+     * decide whether to retry with us or elect someone else. Returns the error.
+     */
+    async fn report_failure(
+        &mut self,
+        e: io::Error,
+        epoch: u64,
+        flush_lsn: XLogRecPtr,
+        received_lsn: XLogRecPtr,
+    ) -> io::Error {
+        let status = if e.raw_os_error() == Some(libc::ENOSPC) {
+            SK_STATUS_OUT_OF_SPACE
+        } else {
+            SK_STATUS_INTERNAL
+        };
+        error!("Failed to store WAL from wal_proposer: {}", e);
+        if let Err(send_err) = self
+            .send_response(status, epoch, flush_lsn, received_lsn)
+            .await
+        {
+            warn!(
+                "Failed to report status {} to wal_proposer: {}",
+                status, send_err
+            );
+        }
+        e
+    }
+
+    /*
+     * Wait until proposer sends something. While it is idle, send heartbeats
+     * with our flush position every heartbeat interval, and give up on it if
+     * it stays silent for HEARTBEAT_MISSES intervals. Proposer superseded by
+     * a new vote is fenced right away, without waiting for its next message.
+     */
+    async fn wait_proposer_message(
+        &mut self,
+        epoch: u64,
+        flush_lsn: XLogRecPtr,
+        received_lsn: XLogRecPtr,
+    ) -> Result<()> {
+        let system = self.system().clone();
+        let heartbeat = self.conf.heartbeat_interval;
+        let read_timeout = self.conf.proposer_read_timeout;
+        let silent_since = clock::now();
+        let mut missed = 0;
+        while self.prebuf.is_empty() {
+            /* Subscribe before the check, not to miss a vote happening in between */
+            let superseded = system.superseded.notified();
+            let is_writer = system.lock_writer(self.registration.id()).is_some();
+            if !is_writer {
+                return self.fence(epoch, flush_lsn, received_lsn).await;
+            }
+            self.flush_ack().await?;
+            self.trim_buffers();
+            let read_time_left = read_timeout.map_or(Duration::default(), |limit| {
+                limit.saturating_sub(clock::elapsed(silent_since))
+            });
+            let wakeup = tokio::select! {
+                res = self.stream.readable() => {
+                    res?;
+                    ProposerWakeup::Message
+                }
+                _ = superseded => ProposerWakeup::Superseded,
+                _ = self.registration.terminated() => ProposerWakeup::Terminated,
+                _ = sleep(heartbeat.unwrap_or_default()), if heartbeat.is_some() => ProposerWakeup::Heartbeat,
+                _ = sleep(read_time_left), if read_timeout.is_some() => ProposerWakeup::TimedOut,
+            };
+            match wakeup {
+                ProposerWakeup::Message => return Ok(()),
+                ProposerWakeup::Superseded => {} /* rechecked above */
+                ProposerWakeup::Terminated => {
+                    return self.terminate_proposer(epoch, flush_lsn, received_lsn).await;
+                }
+                ProposerWakeup::TimedOut => {
+                    io_error!(
+                        "wal_proposer {} has sent nothing for {:?}, close connection",
+                        self.stream.peer_addr()?,
+                        read_timeout.unwrap()
+                    );
+                }
+                ProposerWakeup::Heartbeat => {
+                    missed += 1;
+                    if missed >= HEARTBEAT_MISSES {
+                        io_error!(
+                            "wal_proposer {} is silent for {:?}, consider it dead",
+                            self.stream.peer_addr()?,
+                            heartbeat.unwrap() * missed
+                        );
+                    }
+                    self.send_response(SK_STATUS_HEARTBEAT, epoch, flush_lsn, received_lsn)
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Tell proposer that administrator has terminated the connection and close it
+    async fn terminate_proposer(
+        &mut self,
+        epoch: u64,
+        flush_lsn: XLogRecPtr,
+        received_lsn: XLogRecPtr,
+    ) -> Result<()> {
+        self.registration.set_state(ConnectionState::Draining);
+        self.flush_ack().await?;
+        self.send_response(SK_STATUS_SHUTTING_DOWN, epoch, flush_lsn, received_lsn)
+            .await?;
+        io_error!(
+            "Connection {} with wal_proposer {} is terminated by administrator",
+            self.registration.id(),
+            self.stream.peer_addr()?
+        );
+    }
+
+    // Tell proposer that another one has been elected (or it has reconnected) and close connection
+    async fn fence<T>(
+        &mut self,
+        epoch: u64,
+        flush_lsn: XLogRecPtr,
+        received_lsn: XLogRecPtr,
+    ) -> Result<T> {
+        self.send_response(SK_STATUS_STALE_TERM, epoch, flush_lsn, received_lsn)
+            .await?;
+        io_error!(
+            "wal_proposer {} of system {} is superseded by another proposer connection",
+            self.stream.peer_addr()?,
+            self.system().id
+        );
+    }
+
+    /*
+     * Proposer must wait for our reply before sending the next handshake message.
+     * Anything already received means it has jumped ahead, e.g. started to send WAL
+     * before its vote was acknowledged.
+     */
+    fn check_proposer_waits(&self) -> Result<()> {
+        let mut buf = [0u8; 1];
+        match self.stream.try_read(&mut buf) {
+            Ok(0) => {
+                io_error!(
+                    "Proposer closed connection during {:?} phase",
+                    self.proposer_state
+                );
+            }
+            Ok(_) => {
+                io_error!(
+                    "Protocol violation: proposer sent data before its {:?} message was answered",
+                    self.proposer_state
+                );
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn set_system(&mut self, id: SystemId) -> Result<()> {
+        let mut systems = SYSTEMS_LOCK.lock(&self.tenants.systems);
+        if id == 0 {
+            // non-multitenant configuration: just a single instance
+            if let Some(system) = systems.values().next() {
+                self.system = Some(system.clone());
+                return Ok(());
+            }
+            io_error!("No active instances");
+        }
+        if !systems.contains_key(&id) {
+            if self.tenants.released.lock().unwrap().contains(&id) {
+                io_error!("Refuse connection: tenant {} is released to another wal_acceptor", id);
+            }
+            let system_dir = tenant_dir(&self.conf.data_dir, id);
+            if !system_dir.exists() {
+                /* Don't resurrect deleted tenant as an empty one */
+                if let Some(tombstone) = tombstone::load(&self.conf.data_dir, id)? {
+                    io_error!("Refuse connection: {}", tombstone.describe(id));
+                }
+            }
+            fs::create_dir_all(&system_dir)?;
+            let tenant_conf = TenantConf::load(&system_dir)?;
+            let outbound = OutboundQueue::load(&system_dir)?;
+            let draining = self.tenants.draining.clone();
+            let system = Arc::new(System::new(id, tenant_conf, outbound, draining));
+            task::spawn(gc_loop(Arc::downgrade(&system), self.conf.clone()));
+            if self.conf.prealloc_segments > 0 {
+                task::spawn(prealloc_loop(Arc::downgrade(&system), self.conf.clone()));
+            }
+            if self.conf.archive.is_some() {
+                task::spawn(archive::archive_loop(Arc::downgrade(&system), self.conf.clone()));
+            }
+            systems.insert(id, system);
+        }
+        self.system = Some(systems.get(&id).unwrap().clone());
+        self.registration.set_tenant(id);
+        Ok(())
+    }
+
+    // Receive WAL from wal_proposer
+    async fn receive_wal(&mut self) -> Result<Option<Continuation>> {
+        // Receive information about server
+        let server_info = match self.read_proposer_message(ProposerMessageKind::ServerInfo).await? {
+            ProposerMessage::ServerInfo(server_info) => server_info,
+            _ => unreachable!(),
+        };
+        info!(
+            "Start handshake with wal_proposer {} sysid {}",
+            self.stream.peer_addr()?,
+            server_info.system_id
+        );
+        self.set_system(server_info.system_id)?;
+        if self.needs_migration() {
+            return Ok(Some(Continuation::ReceiveWal(server_info)));
+        }
+        self.accept_wal(server_info).await?;
+        Ok(None)
+    }
+
+    // Continue handshake with wal_proposer and receive WAL from it
+    async fn accept_wal(&mut self, server_info: ServerInfo) -> Result<()> {
+        let res = match self.elect_proposer(server_info).await {
+            Ok(session) => self.serve_elected_proposer(session).await,
+            Err(e) => Err(e),
+        };
+        /* Vote of the next proposer doesn't supersede anybody */
+        self.system().release_writer(self.registration.id());
+        res
+    }
+
+    //
+    // Handshake with wal_proposer: report our state, vote for its candidate and
+    // acknowledge the vote once it is durable. Returns the session to receive WAL in.
+    //
+    async fn elect_proposer(&mut self, server_info: ServerInfo) -> Result<ProposerSession> {
+        let system = self.system();
+        let conf = self.conf.clone();
+        run_blocking(move || system.load_control_file(&conf)).await??;
+        if let Some(trace) = self.trace.as_mut() {
+            let control_file = fs::read(self.system_dir().join(CONTROL_FILE_NAME))?;
+            if !control_file.is_empty() {
+                trace.record_state(TRACE_INITIAL_STATE, server_info.system_id, &control_file)?;
+            }
+        }
+
+        let mut my_info = self.system().get_info();
+
+        /* Check protocol compatibility: the version settled by the greeting, if any */
+        let version = server_info.protocol_version;
+        if self.protocol_version != 0 && version != self.protocol_version {
+            io_error!(
+                "Protocol version {} differs from version {} settled by the greeting",
+                version,
+                self.protocol_version
+            );
+        }
+        if version < SK_MIN_PROTOCOL_VERSION || version > SK_PROTOCOL_VERSION {
+            io_error!(
+                "Incompatible protocol version {}, supported versions are {}..{}",
+                version,
+                SK_MIN_PROTOCOL_VERSION,
+                SK_PROTOCOL_VERSION
+            );
+        }
+        /* Update information about server, but preserve locally stored node_id */
+        let node_id = my_info.server.node_id;
+        my_info.server = server_info;
+        my_info.server.node_id = node_id;
+
+        /* Calculate WAL end based on local data */
+        let (flush_lsn, timeline) = self.find_end_of_wal(true)?;
+
+        /* Postgres upgrade is handled according to the policy of the tenant */
+        let system = self.system();
+        let pg_version = server_info.pg_version;
+        let (timeline, pg_version_update) =
+            run_blocking(move || system.check_pg_version(pg_version, timeline, flush_lsn))
+                .await??;
+        my_info.flush_lsn = flush_lsn;
+        my_info.server.timeline = timeline;
+
+        /* Report my identifier to proxy */
+        self.check_proposer_waits()?;
+        self.start_sending();
+        self.codec
+            .encode_acceptor(&AcceptorMessage::Info(my_info), &mut self.outbuf);
+        self.send().await?;
+        self.proposer_state = ProposerState::Voting;
+        self.registration.set_state(ConnectionState::Voting);
+
+        /* Wait for vote request */
+        let prop = match self.read_proposer_message(ProposerMessageKind::RequestVote).await? {
+            ProposerMessage::RequestVote(prop) => prop,
+            _ => unreachable!(),
+        };
+        let conn_id = self.registration.id();
+        let system = self.system();
+        let node_id = prop.node_id;
+        let (vote, rejected_by) = run_blocking(move || {
+            let mut rejected_by = None;
+            let vote = system.vote(conn_id, |info| {
+                /* This is Paxos check which should ensure that only one master can perform commits */
+                if node_id < info.server.node_id {
+                    rejected_by = Some(info.server.node_id);
+                    io_error!(
+                        "Reject connection attempt with term {} because my term is {}",
+                        node_id.term,
+                        info.server.node_id.term
+                    );
+                }
+                info.server = server_info;
+                info.server.node_id = node_id;
+                info.server.timeline = timeline;
+                info.flush_lsn = flush_lsn;
+                Ok(())
+            });
+            (vote, rejected_by)
+        })
+        .await?;
+        /* Vote is persisted by update_info, failure to persist it is not a rejection */
+        my_info = match (vote, rejected_by) {
+            (Ok(info), _) => info,
+            (Err(e), Some(voted)) => {
+                /* Send node-id I voted for to inform proxy that its candidate was rejected */
+                self.start_sending();
+                self.codec
+                    .encode_acceptor(&AcceptorMessage::Vote(voted), &mut self.outbuf);
+                self.send().await?;
+                return Err(e);
+            }
+            (Err(e), None) => return Err(e),
+        };
+        let wal_seg_size = server_info.wal_seg_size as usize;
+
+        /* Version of the elected proposer is recorded unless it is superseded meanwhile */
+        if let Some(update) = pg_version_update {
+            let system = self.system();
+            let conf = self.conf.clone();
+            let recorded = run_blocking(move || match system.lock_writer(conn_id) {
+                Some(_writer) => Some(system.record_pg_version(&conf, update, wal_seg_size)),
+                None => None,
+            })
+            .await?;
+            match recorded {
+                Some(res) => res?,
+                None => {
+                    io_error!(
+                        "wal_proposer of system {} is superseded by another proposer connection",
+                        self.system().id
+                    );
+                }
+            }
+        }
+
+        /* Acknowledge the proposed candidate by returning it to the proxy */
+        self.check_proposer_waits()?;
+        self.start_sending();
+        self.codec
+            .encode_acceptor(&AcceptorMessage::Vote(prop.node_id), &mut self.outbuf);
+        self.send().await?;
+        self.proposer_state = ProposerState::Streaming;
+        self.registration.set_state(ConnectionState::Appending);
+
+        // Need to establish replication channel with page server.
+        // Add far as replication in postgres is initiated by receiver, we should use callme mechanism.
+        // It is retried in background until pageserver is reachable.
+        self.system()
+            .outbound_queue()
+            .enqueue(OutboundOp::PageserverCallback)?;
+
+        let peer_addr = self.stream.peer_addr()?;
+        info!(
+            "Start streaming from server {} address {:?}",
+            server_info.system_id, peer_addr
+        );
+        Ok(ProposerSession {
+            prop: prop,
+            my_info: my_info,
+            received_lsn: my_info.flush_lsn,
+            timeline: timeline,
+            wal_seg_size: wal_seg_size,
+            peer_addr: peer_addr,
+            wal_scanner: WalRecordScanner::new(wal_seg_size, self.conf.verify_wal_crc),
+            rolling_checksum: None,
+            unsynced: None,
+            truncation_logged: false,
+            flow_paused: false,
+        })
+    }
+
+    //
+    // Receive WAL from the elected proposer until it ends the stream. Appends are written
+    // only while this connection holds the writer lock, the proposer superseded by another
+    // connection is fenced.
+    //
+    async fn serve_elected_proposer(&mut self, mut session: ProposerSession) -> Result<()> {
+        loop {
+            /*
+             * Group commit: WAL written without sync is synced once no more appends are
+             * read ahead, or the batch reached --group-commit-kb or --group-commit-delay-ms
+             */
+            if session.unsynced.as_ref().map_or(false, |batch| {
+                self.prebuf.is_empty() || batch.is_due(&self.conf)
+            }) {
+                self.sync_unsynced(&mut session).await?;
+            }
+
+            /* Receive append with its WAL, followed by checksum if the proposer sends it */
+            let (epoch, flush_lsn, received_lsn) = session.positions();
+            self.wait_proposer_message(epoch, flush_lsn, received_lsn)
+                .await?;
+            let append = match self.read_proposer_message(ProposerMessageKind::Append).await? {
+                ProposerMessage::Append(append) => append,
+                _ => unreachable!(),
+            };
+            let received = clock::now();
+            self.check_sender(&session, &append.header).await?;
+            if append.header.begin_lsn == END_OF_STREAM {
+                info!("Server stops streaming");
+                self.registration.set_state(ConnectionState::Draining);
+                self.sync_unsynced(&mut session).await?;
+                self.flush_ack().await?;
+                return Ok(());
+            }
+            self.check_draining(&session).await?;
+            self.control_flow(&mut session).await?;
+
+            if append.wal.is_empty() {
+                self.accept_heartbeat(&mut session, &append.header).await?;
+                continue;
+            }
+            /*
+             * Do not accept WAL while ingest is paused, proposer will resend it later.
+             * Proposer of version 1 can't be told so, it reconnects instead.
+             */
+            if self.system().check_paused() {
+                if !self.codec.response_status() {
+                    self.flush_ack().await?;
+                    io_error!(
+                        "WAL ingest of system {} is paused, close connection with wal_proposer {}",
+                        self.system().id,
+                        session.peer_addr
+                    );
+                }
+                self.respond(&session, SK_STATUS_PAUSED).await?;
+                continue;
+            }
+            self.scan_records(&mut session, &append).await?;
+            let completed_checksums = self.verify_checksum(&mut session, &append).await?;
+
+            /* Stagger write and fsync of low priority tenants */
+            self.system().yield_if_batch().await;
+
+            self.log_divergence(&mut session, &append).await?;
+            self.store_append(&mut session, &append, completed_checksums, received)
+                .await?;
+        }
+    }
+
+    /* Send response with the current positions of the session */
+    async fn respond(&mut self, session: &ProposerSession, status: u32) -> Result<()> {
+        let (epoch, flush_lsn, received_lsn) = session.positions();
+        self.send_response(status, epoch, flush_lsn, received_lsn)
+            .await
+    }
+
+    /*
+     * Appends are accepted only from the node elected in this session. Another node
+     * sending on the connection is fenced with STALE_TERM.
+     */
+    async fn check_sender(
+        &mut self,
+        session: &ProposerSession,
+        req: &SafeKeeperRequest,
+    ) -> Result<()> {
+        if req.sender_id != session.my_info.server.node_id {
+            self.respond(session, SK_STATUS_STALE_TERM).await?;
+            io_error!("Sender NodeId is changed");
+        }
+        Ok(())
+    }
+
+    /* Close the connection if the safekeeper is draining or administrator terminates it */
+    async fn check_draining(&mut self, session: &ProposerSession) -> Result<()> {
+        if self.tenants.is_draining() {
+            self.registration.set_state(ConnectionState::Draining);
+            self.respond(session, SK_STATUS_SHUTTING_DOWN).await?;
+            io_error!("Safekeeper is draining, close connection with wal_proposer");
+        }
+        if self.registration.terminate_requested() {
+            let (epoch, flush_lsn, received_lsn) = session.positions();
+            self.terminate_proposer(epoch, flush_lsn, received_lsn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Explicit flow control: pause proposer while appends pre-read from its socket
+     * pile up above the high watermark, resume it once they drain to half of it.
+     */
+    async fn control_flow(&mut self, session: &mut ProposerSession) -> Result<()> {
+        let high_watermark = match self
+            .conf
+            .receive_high_watermark
+            .filter(|_| self.flow_control)
+        {
+            Some(high_watermark) => high_watermark,
+            None => return Ok(()),
+        };
+        let backlog = self.prebuf.len();
+        let status = if !session.flow_paused && backlog >= high_watermark {
+            self.system().account_flow_pause();
+            SK_STATUS_FLOW_PAUSE
+        } else if session.flow_paused && backlog <= high_watermark / 2 {
+            SK_STATUS_FLOW_RESUME
+        } else {
+            return Ok(());
+        };
+        session.flow_paused = status == SK_STATUS_FLOW_PAUSE;
+        debug!(
+            "{} wal_proposer {} with {} bytes of received appends",
+            if session.flow_paused {
+                "Pause"
+            } else {
+                "Resume"
+            },
+            session.peer_addr,
+            backlog
+        );
+        self.respond(session, status).await
+    }
+
+    /*
+     * Empty append is a heartbeat of idle proposer. It carries proposer's term (checked
+     * by check_sender) and commit position, and is answered with our flush position.
+     */
+    async fn accept_heartbeat(
+        &mut self,
+        session: &mut ProposerSession,
+        req: &SafeKeeperRequest,
+    ) -> Result<()> {
+        let system = self.system();
+        let conn_id = self.registration.id();
+        let (restart_lsn, commit_lsn) = (req.restart_lsn, req.commit_lsn);
+        let res = run_blocking(move || match system.lock_writer(conn_id) {
+            Some(_writer) => Some(system.update_info(|info| {
+                info.restart_lsn = restart_lsn;
+                info.commit_lsn = commit_lsn;
+                Ok(())
+            })),
+            None => None,
+        })
+        .await?;
+        let (epoch, flush_lsn, received_lsn) = session.positions();
+        session.my_info = match res {
+            None => return self.fence(epoch, flush_lsn, received_lsn).await,
+            Some(Ok(info)) => info,
+            Some(Err(e)) => {
+                return Err(self.report_failure(e, epoch, flush_lsn, received_lsn).await)
+            }
+        };
+        self.respond(session, SK_STATUS_OK).await?;
+        self.system()
+            .notify_wal_senders(min(commit_lsn, session.my_info.flush_lsn));
+        Ok(())
+    }
+
+    /* Check CRCs of received records before storing them, and collect their statistics */
+    async fn scan_records(
+        &mut self,
+        session: &mut ProposerSession,
+        append: &AppendRequest,
+    ) -> Result<()> {
+        let (start_pos, end_pos) = (append.header.begin_lsn, append.header.end_lsn);
+        /* Records are scanned without the tenant lock, which is only taken to publish them */
+        let wal_stats = self.conf.wal_stats;
+        let mut records = Vec::new();
+        let corrupt = session
+            .wal_scanner
+            .feed(start_pos, &append.wal, |rmid, len| {
+                if wal_stats {
+                    records.push((rmid, len));
+                }
+            });
+        if !records.is_empty() {
+            let system = self.system();
+            let mut shared_state = TENANT_LOCKS.lock(&system.mutex);
+            for (rmid, len) in records {
+                shared_state.wal_stats.account(rmid, len);
+            }
+        }
+        if let Some(rec_lsn) = corrupt {
+            self.respond(session, SK_STATUS_CORRUPT_WAL).await?;
+            io_error!(
+                "CRC mismatch of WAL record at {} received from wal_proposer {} in {}-{}",
+                format_lsn(rec_lsn),
+                session.peer_addr,
+                format_lsn(start_pos),
+                format_lsn(end_pos)
+            );
+        }
+        Ok(())
+    }
+
+    /*
+     * End-to-end checksum of the proposer is verified before WAL is stored. It is
+     * continued from the previous append, or computed from the stored WAL of the segment
+     * if the append doesn't follow it. Returns checksums of segments completed by the append.
+     */
+    async fn verify_checksum(
+        &mut self,
+        session: &mut ProposerSession,
+        append: &AppendRequest,
+    ) -> Result<Vec<(String, u32)>> {
+        let expected = match append
+            .checksum
+            .filter(|_| self.conf.object_storage.is_none())
+        {
+            Some(expected) => expected,
+            None => return Ok(Vec::new()),
+        };
+        let (start_pos, end_pos) = (append.header.begin_lsn, append.header.end_lsn);
+        let (timeline, wal_seg_size) = (session.timeline, session.wal_seg_size);
+        let mut checksum = match session.rolling_checksum.take() {
+            Some(checksum) if checksum.end_lsn == start_pos => checksum,
+            _ => {
+                let dir = self.system_dir();
+                let key = self.conf.at_rest_key.clone();
+                let res = run_blocking(move || {
+                    let key = key.as_ref();
+                    RollingChecksum::load(&dir, key, timeline, start_pos, wal_seg_size)
+                })
+                .await?;
+                match res {
+                    Ok(checksum) => checksum,
+                    Err(e) => {
+                        let (epoch, flush_lsn, received_lsn) = session.positions();
+                        return Err(self.report_failure(e, epoch, flush_lsn, received_lsn).await);
+                    }
+                }
+            }
+        };
+        let completed_checksums = checksum.feed(&append.wal, timeline, wal_seg_size);
+        if checksum.crc != expected {
+            self.respond(session, SK_STATUS_CORRUPT_WAL).await?;
+            io_error!(
+                "WAL checksum {:08X} doesn't match {:08X} sent by wal_proposer {} with WAL {}-{}",
+                checksum.crc,
+                expected,
+                session.peer_addr,
+                format_lsn(start_pos),
+                format_lsn(end_pos)
+            );
+        }
+        session.rolling_checksum = Some(checksum);
+        Ok(completed_checksums)
+    }
+
+    /*
+     * WAL below local received position may differ from ours: proposer of the new term
+     * overwrites the tail which is not in its history. Log it once per connection, when
+     * the overwritten WAL actually differs.
+     */
+    async fn log_divergence(
+        &mut self,
+        session: &mut ProposerSession,
+        append: &AppendRequest,
+    ) -> Result<()> {
+        let start_pos = append.header.begin_lsn;
+        let (epoch, flush_lsn, received_lsn) = session.positions();
+        if start_pos >= received_lsn || session.truncation_logged {
+            return Ok(());
+        }
+        let system = self.system();
+        let conf = self.conf.clone();
+        let data = append.wal.clone();
+        let len = min(data.len() as u64, received_lsn - start_pos) as usize;
+        let (timeline, wal_seg_size) = (session.timeline, session.wal_seg_size);
+        let res = run_blocking(move || {
+            system.diverged_wal(&conf, start_pos, timeline, wal_seg_size, &data[..len])
+        })
+        .await?;
+        let diverged_lsn = match res {
+            Ok(Some(lsn)) => lsn,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(self.report_failure(e, epoch, flush_lsn, received_lsn).await),
+        };
+        let entry = recovery_log::Entry::new(
+            recovery_log::Action::WalTruncation,
+            self.system().id(),
+            format!(
+                "proposer of term {} overwrites differing WAL below local flush position",
+                session.prop.node_id.term
+            ),
+            format!("proposer {}", session.peer_addr),
+        )
+        .lsns(diverged_lsn, received_lsn);
+        if let Err(e) = recovery_log::record(&self.conf.data_dir, &entry) {
+            return Err(self.report_failure(e, epoch, flush_lsn, received_lsn).await);
+        }
+        session.truncation_logged = true;
+        self.system()
+            .account_wal_op(WalOp::Truncation, 1, received_lsn - diverged_lsn);
+        Ok(())
+    }
+
+    /*
+     * Save WAL of the append in file and update control data, switching to the epoch of
+     * the proposer once its WAL crosses the boundary. Both are done under the writer
+     * lock, so a proposer superseded meanwhile doesn't write anything. The append is
+     * acknowledged right away, or once synced by group commit.
+     */
+    async fn store_append(
+        &mut self,
+        session: &mut ProposerSession,
+        append: &AppendRequest,
+        completed_checksums: Vec<(String, u32)>,
+        received: Instant,
+    ) -> Result<()> {
+        let req = append.header;
+        let (start_pos, end_pos) = (req.begin_lsn, req.end_lsn);
+        let rec_size = append.wal.len();
+        let (timeline, wal_seg_size) = (session.timeline, session.wal_seg_size);
+
+        /*
+         * With group commit, pipelined appends are written without sync and synced
+         * together later; flush_lsn is advanced over them only then. Epoch switch is
+         * synced right away, since it is persisted in the control file along with
+         * the WAL. Append not continuing the unsynced WAL (truncation) syncs it first,
+         * so that unsynced WAL is always contiguous.
+         */
+        if session
+            .unsynced
+            .as_ref()
+            .map_or(false, |batch| batch.end_lsn != start_pos)
+        {
+            self.sync_unsynced(session).await?;
+        }
+        let prop = session.prop;
+        let (epoch, prev_flush_lsn, prev_received_lsn) = session.positions();
+        let sync = !self.conf.group_commit()
+            || self.ack_each_append
+            || epoch_switch(epoch, prev_received_lsn, end_pos, &prop).is_some();
+        let system = self.system();
+        let conf = self.conf.clone();
+        let conn_id = self.registration.id();
+        let data = append.wal.clone();
+        let write_start = clock::now();
+        let stored = run_blocking(move || match system.lock_writer(conn_id) {
+            Some(_writer) => Some(
+                match system.write_wal_file(&conf, start_pos, timeline, wal_seg_size, &data, sync) {
+                    Err(e) => Err((e, prev_flush_lsn, prev_received_lsn)),
+                    Ok(()) => system
+                        .update_info(|info| {
+                            info.restart_lsn = req.restart_lsn;
+                            info.commit_lsn = req.commit_lsn;
+                            let last_lsn = max(info.flush_lsn, prev_received_lsn);
+                            if let Some(new_epoch) =
+                                epoch_switch(info.epoch, last_lsn, end_pos, &prop)
+                            {
+                                info!("Switch to new epoch {}", new_epoch);
+                                info.epoch = new_epoch; /* bump epoch */
+                            }
+                            /* Unsynced WAL is covered once synced, see sync_unsynced */
+                            if sync && end_pos > info.flush_lsn {
+                                info.flush_lsn = end_pos;
+                            }
+                            Ok(())
+                        })
+                        .map_err(|e| (e, prev_flush_lsn, end_pos)),
+                },
+            ),
+            None => None,
+        })
+        .await?;
+        session.my_info = match stored {
+            Some(Ok(info)) => info,
+            Some(Err((e, flush_lsn, received_lsn))) => {
+                return Err(self.report_failure(e, epoch, flush_lsn, received_lsn).await)
+            }
+            None => return self.fence(epoch, prev_flush_lsn, prev_received_lsn).await,
+        };
+        if end_pos > session.received_lsn {
+            session.received_lsn = end_pos;
+        }
+        /* Checksums of completed segments are durable before the segments are acked */
+        if !completed_checksums.is_empty() {
+            let dir = self.system_dir();
+            let no_sync = self.conf.no_sync;
+            let system = self.system();
+            let res = run_blocking(move || {
+                let mut checksums = system.wal_checksums.lock().unwrap();
+                checksums.record(&dir, &completed_checksums, no_sync)
+            })
+            .await?;
+            if let Err(e) = res {
+                let (epoch, flush_lsn, received_lsn) = session.positions();
+                return Err(self.report_failure(e, epoch, flush_lsn, received_lsn).await);
+            }
+        }
+        if let Some(threshold) = self.conf.slow_append_threshold {
+            let elapsed = clock::elapsed(write_start);
+            if elapsed > threshold {
+                warn!(
+                    "Slow append to system {}: {} bytes of WAL {}-{} written in {:?}{}",
+                    self.system().id,
+                    rec_size,
+                    format_lsn(start_pos),
+                    format_lsn(end_pos),
+                    elapsed,
+                    if self.conf.no_sync || !sync {
+                        ""
+                    } else {
+                        " (including fsync)"
+                    }
+                );
+            }
+        }
+
+        /*
+         * Synced write makes all received WAL durable, including WAL of preceding
+         * unsynced writes, which is either in the same segment or in segments synced
+         * on completion. In no-sync mode we are explicitly asked not to care about
+         * durability, so received WAL is reported as durable as well.
+         */
+        if sync {
+            session.unsynced = None;
+        } else {
+            let batch = session.unsynced.get_or_insert(UnsyncedWal {
+                end_lsn: start_pos,
+                commit_lsn: 0,
+                bytes: 0,
+                since: clock::now(),
+            });
+            batch.end_lsn = end_pos;
+            batch.commit_lsn = req.commit_lsn;
+            batch.bytes += rec_size;
+        }
+        self.system().account_append(rec_size);
+        let now = get_current_timestamp();
+        if self.system().ingest_index_due(end_pos, now) {
+            let system = self.system();
+            run_blocking(move || system.record_ingest_time(end_pos, now)).await?;
+        }
+
+        if let Some(xact_time) = session.wal_scanner.take_xact_time() {
+            let system = self.system();
+            system.update_clock_skew(xact_time, self.conf.max_clock_skew);
+            system.record_commit_time(end_pos, xact_time);
+        }
+
+        /* Report flush position */
+        let (epoch, flush_lsn, _) = session.positions();
+        if session.unsynced.is_some() {
+            /* Acknowledged with flush position once synced, see sync_unsynced */
+            self.defer_ack(epoch, flush_lsn, end_pos, received);
+            return Ok(());
+        }
+        self.ack_append(epoch, flush_lsn, end_pos, received).await?;
+
+        /*
+         * Ping wal sender that new data is available.
+         * FlushLSN (end_pos) can be smaller than commitLSN in case we are at catching-up safekeeper.
+         */
+        self.system()
+            .notify_wal_senders(min(req.commit_lsn, end_pos));
+        Ok(())
+    }
+
+    //
+    // Read full message or return None if connection is closed
+    //
+    async fn read_message(&mut self) -> Result<Option<FeMessage>> {
+        loop {
+            if let Some(message) = self.parse_message()? {
+                return Ok(Some(message));
+            }
+
+            let read = tokio::select! {
+                res = self.stream.read_buf(&mut self.inbuf) => Some(res?),
+                _ = self.registration.terminated() => None,
+            };
+            match read {
+                Some(0) => {
+                    if self.inbuf.is_empty() {
+                        return Ok(None);
+                    } else {
+                        io_error!("connection reset by peer");
+                    }
+                }
+                Some(_) => {}
+                None => self.terminate_libpq().await?,
+            }
+        }
+    }
+
+    //
+    // Tell libpq client that its tenant was deleted and close the connection, rather than
+    // let set_system create an empty one
+    //
+    async fn check_tombstone(&mut self, id: SystemId) -> Result<()> {
+        if id == 0
+            || self.tenants.get_system(id).is_some()
+            || tenant_dir(&self.conf.data_dir, id).exists()
+        {
+            return Ok(());
+        }
+        if let Some(tombstone) = tombstone::load(&self.conf.data_dir, id)? {
+            let msg = tombstone.describe(id);
+            error!("Refuse connection of {}: {}", self.stream.peer_addr()?, msg);
+            return Err(sql_error(SQLSTATE_TENANT_DELETED, msg));
+        }
+        Ok(())
+    }
+
+    //
+    // Tell libpq client that administrator has terminated the connection and close it
+    //
+    async fn terminate_libpq(&mut self) -> Result<()> {
+        self.registration.set_state(ConnectionState::Draining);
+        error!(
+            "Connection {} with {} is terminated by administrator",
+            self.registration.id(),
+            self.stream.peer_addr()?
+        );
+        Err(sql_error(
+            SQLSTATE_ADMIN_SHUTDOWN,
+            "terminating connection due to administrator command".to_string(),
+        ))
+    }
+
+    //
+    // Tell libpq client why its session fails before the connection is closed, instead of
+    // just dropping it. Errors which are not SqlError are classified by their kind.
+    //
+    async fn report_libpq_error(&mut self, e: &io::Error) {
+        let code = match sqlstate_of(e) {
+            Some(code) => code,
+            None => match e.kind() {
+                /* Client is gone, nobody to tell */
+                io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe => return,
+                io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => {
+                    SQLSTATE_PROTOCOL_VIOLATION
+                }
+                io::ErrorKind::NotFound => SQLSTATE_UNDEFINED_FILE,
+                _ => SQLSTATE_INTERNAL_ERROR,
+            },
+        };
+        self.start_sending();
+        BeMessage::write(&mut self.outbuf, &BeMessage::ErrorResponse(code, &e.to_string()));
+        if let Err(send_err) = self.send().await {
+            debug!("Failed to send error response to libpq client: {}", send_err);
+        }
+    }
+
+    //
+    // Parse libpq message
+    //
+    fn parse_message(&mut self) -> Result<Option<FeMessage>> {
+        if !self.init_done {
+            FeStartupMessage::parse(&mut self.inbuf)
+        } else {
+            FeMessage::parse(&mut self.inbuf)
+        }
+    }
+
+    //
+    // Reset output buffer to start accumulating data of new message
+    //
+    fn start_sending(&mut self) {
+        self.outbuf.clear();
+    }
+
+    //
+    // Send buffered messages
+    //
+    async fn send(&mut self) -> Result<()> {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(TRACE_SENT, &self.outbuf)?;
+        }
         self.stream.write_all(&self.outbuf).await
     }
 
     //
-    // Send WAL to replica or WAL sender using standard libpq replication protocol
+    // Send WAL to replica or WAL sender using standard libpq replication protocol
+    //
+    async fn send_wal(&mut self) -> Result<Option<Continuation>> {
+        info!("WAL sender to {:?} is started", self.stream.peer_addr()?);
+        self.serve_libpq().await
+    }
+
+    async fn serve_libpq(&mut self) -> Result<Option<Continuation>> {
+        let res = self.serve_libpq_messages().await;
+        if let Err(e) = &res {
+            self.report_libpq_error(e).await;
+        }
+        res
+    }
+
+    async fn serve_libpq_messages(&mut self) -> Result<Option<Continuation>> {
+        loop {
+            self.start_sending();
+            match self.read_message().await? {
+                Some(FeMessage::StartupMessage(m)) => {
+                    trace!("got message {:?}", m);
+
+                    match m.kind {
+                        StartupRequestCode::NegotiateGss | StartupRequestCode::NegotiateSsl => {
+                            BeMessage::write(&mut self.outbuf, &BeMessage::Negotiate);
+                            info!("SSL requested");
+                            self.send().await?;
+                        }
+                        StartupRequestCode::Normal => {
+                            self.init_done = true;
+                            self.check_tombstone(m.system_id).await?;
+                            self.set_system(m.system_id)?;
+                            self.authenticate(m.user.as_deref().unwrap_or("")).await?;
+                            BeMessage::write(&mut self.outbuf, &BeMessage::AuthenticationOk);
+                            BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+                            self.send().await?;
+                            self.registration.set_state(ConnectionState::Idle);
+                            if self.needs_migration() {
+                                return Ok(Some(Continuation::SendWal));
+                            }
+                        }
+                        StartupRequestCode::Cancel => return Ok(None),
+                    }
+                }
+                Some(FeMessage::Query(m)) => {
+                    if !self.process_query(&m).await? {
+                        break;
+                    }
+                }
+                Some(FeMessage::Terminate) => {
+                    break;
+                }
+                None => {
+                    info!("connection closed");
+                    break;
+                }
+                _ => {
+                    error!("unexpected message");
+                    return Err(sql_error(
+                        SQLSTATE_PROTOCOL_VIOLATION,
+                        "unexpected message".to_string(),
+                    ));
+                }
+            }
+        }
+        info!("WAL sender to {:?} is finished", self.stream.peer_addr()?);
+        Ok(None)
+    }
+
+    //
+    // Authenticate replication client by the method configured for the tenant.
+    // On success the last message of the exchange, if any, is left in the output buffer.
+    //
+    async fn authenticate(&mut self, user: &str) -> Result<()> {
+        let system = self.system();
+        let method = system.tenant_conf.auth_method;
+        if method == AuthMethod::Trust {
+            return Ok(());
+        }
+        /* Unknown user and wrong password look the same to the client, like in Postgres */
+        let auth_failed = sql_error(
+            SQLSTATE_INVALID_PASSWORD,
+            format!("password authentication failed for user \"{}\"", user),
+        );
+        let secret = match system.tenant_conf.users.get(user) {
+            Some(secret) => Secret::parse(secret),
+            None => {
+                error!("Unknown user {:?} of system {}", user, system.id);
+                return Err(auth_failed);
+            }
+        };
+        let res = match method {
+            AuthMethod::Trust => Ok(()),
+            AuthMethod::Password => {
+                /* Password is not sent in clear text over plain connections */
+                if !self.stream.is_tls() {
+                    return Err(sql_error(
+                        SQLSTATE_INVALID_AUTHORIZATION,
+                        "password authentication requires TLS connection".to_string(),
+                    ));
+                }
+                self.start_sending();
+                BeMessage::write(
+                    &mut self.outbuf,
+                    &BeMessage::AuthenticationCleartextPassword,
+                );
+                self.send().await?;
+                let password = self.read_password().await?;
+                auth::check_password(&secret, user, &password)
+            }
+            AuthMethod::Md5 => {
+                let salt: [u8; 4] = rand::random();
+                self.start_sending();
+                BeMessage::write(
+                    &mut self.outbuf,
+                    &BeMessage::AuthenticationMD5Password(&salt),
+                );
+                self.send().await?;
+                let response = self.read_password().await?;
+                auth::check_md5(&secret, user, &salt, &response)
+            }
+            AuthMethod::ScramSha256 => self.authenticate_scram(&secret, user).await,
+        };
+        if let Err(e) = res {
+            error!("Authentication of {:?} failed: {}", self.stream.peer_addr()?, e);
+            return Err(auth_failed);
+        }
+        self.authenticated = true;
+        Ok(())
+    }
+
+    //
+    // Admin commands over libpq change or expose the state of the tenant, so they are
+    // refused to clients which haven't authenticated, e.g. of tenants trusting everyone.
+    // The admin socket is the way to run them there.
+    //
+    fn require_authentication(&self, cmd: &str) -> Result<()> {
+        if !self.authenticated {
+            return Err(sql_error(
+                SQLSTATE_INSUFFICIENT_PRIVILEGE,
+                format!(
+                    "{} requires password authentication, use the admin socket",
+                    cmd
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn authenticate_scram(&mut self, secret: &Secret, user: &str) -> Result<()> {
+        self.start_sending();
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::AuthenticationSASL(SCRAM_MECHANISM),
+        );
+        self.send().await?;
+        let initial = self.read_password().await?;
+        let exchange = ScramExchange::start(secret, user, &initial)?;
+        self.start_sending();
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::AuthenticationSASLContinue(exchange.server_first()),
+        );
+        self.send().await?;
+        let response = self.read_password().await?;
+        let server_final = exchange.finish(user, &response)?;
+        self.start_sending();
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::AuthenticationSASLFinal(&server_final),
+        );
+        Ok(())
+    }
+
+    // Body of the next PasswordMessage (or SASL response) of the client
+    async fn read_password(&mut self) -> Result<Bytes> {
+        match self.read_message().await? {
+            Some(FeMessage::Password(m)) => Ok(m.body),
+            _ => {
+                io_error!(
+                    "Expected password message from {:?}",
+                    self.stream.peer_addr()?
+                );
+            }
+        }
+    }
+
+    //
+    // Handle IDENTIFY_SYSTEM replication command
+    //
+    async fn handle_identify_system(&mut self) -> Result<bool> {
+        let (start_pos, timeline) = self.find_end_of_wal(false)?;
+        let lsn = format!("{:X}/{:>08X}", (start_pos >> 32) as u32, start_pos as u32);
+        let tli = timeline.to_string();
+        let sysid = self.system().get_info().server.system_id.to_string();
+        let lsn_bytes = lsn.as_bytes();
+        let tli_bytes = tli.as_bytes();
+        let sysid_bytes = sysid.as_bytes();
+
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::RowDescription(&[
+                RowDescriptor {
+                    name: b"systemid\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"timeline\0",
+                    typoid: 23,
+                    typlen: 4,
+                },
+                RowDescriptor {
+                    name: b"xlogpos\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"dbname\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+            ]),
+        );
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::DataRow(&[Some(lsn_bytes), Some(tli_bytes), Some(sysid_bytes), None]),
+        );
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::CommandComplete(b"IDENTIFY_SYSTEM"),
+        );
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
+    }
+
+    //
+    // Handle SAFEKEEPER_IDENTIFY command: IDENTIFY_SYSTEM extended with safekeeper specific
+    // information, so that clients don't have to guess segment geometry.
+    // Standard clients check number of IDENTIFY_SYSTEM columns, so it is a separate command.
+    //
+    async fn handle_safekeeper_identify(&mut self) -> Result<bool> {
+        let (start_pos, timeline) = self.find_end_of_wal(false)?;
+        let info = self.system().get_info();
+        let sysid = info.server.system_id.to_string();
+        let tli = timeline.to_string();
+        let lsn = format_lsn(start_pos);
+        let wal_seg_size = info.server.wal_seg_size.to_string();
+        let epoch = info.epoch.to_string();
+        let term = info.server.node_id.term.to_string();
+        let node_uuid = format!("{:032x}", info.server.node_id.uuid);
+        let safekeeper_uuid = self
+            .conf
+            .node_uuid
+            .map(node_file::format_uuid)
+            .unwrap_or_default();
+        let flush_lsn = format_lsn(info.flush_lsn);
+        let commit_lsn = format_lsn(info.commit_lsn);
+
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::RowDescription(&[
+                RowDescriptor {
+                    name: b"systemid\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"timeline\0",
+                    typoid: 23,
+                    typlen: 4,
+                },
+                RowDescriptor {
+                    name: b"xlogpos\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"wal_seg_size\0",
+                    typoid: 23,
+                    typlen: 4,
+                },
+                RowDescriptor {
+                    name: b"epoch\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"term\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"node_uuid\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"safekeeper_uuid\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"flush_lsn\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"commit_lsn\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+            ]),
+        );
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::DataRow(&[
+                Some(sysid.as_bytes()),
+                Some(tli.as_bytes()),
+                Some(lsn.as_bytes()),
+                Some(wal_seg_size.as_bytes()),
+                Some(epoch.as_bytes()),
+                Some(term.as_bytes()),
+                Some(node_uuid.as_bytes()),
+                Some(safekeeper_uuid.as_bytes()),
+                Some(flush_lsn.as_bytes()),
+                Some(commit_lsn.as_bytes()),
+            ]),
+        );
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::CommandComplete(b"SAFEKEEPER_IDENTIFY"),
+        );
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
+    }
+
+    //
+    // Handle START_REPLICATION replication command
+    //
+    async fn handle_start_replication(&mut self, cmd: &Bytes) -> Result<bool> {
+        if self.conf.object_storage.is_some() {
+            let msg = "Streaming of WAL kept in object storage is not supported yet";
+            error!("{}", msg);
+            return Err(sql_error(SQLSTATE_FEATURE_NOT_SUPPORTED, msg.to_string()));
+        }
+        let peer_addr = self.stream.peer_addr()?;
+        let result = self.stream_wal(cmd, peer_addr).await;
+        /* Replica is gone, so its feedback should not hold back vacuum anymore */
+        self.system().remove_hs_feedback(&peer_addr);
+        self.system().finish_catchup(&peer_addr);
+        self.system().update_sender(peer_addr, None);
+        self.registration.set_state(ConnectionState::Idle);
+        result
+    }
+
+    //
+    // Stream WAL to replica, collecting its hot standby feedback
+    //
+    async fn stream_wal(&mut self, cmd: &Bytes, peer_addr: SocketAddr) -> Result<bool> {
+        let re = Regex::new(r"([[:xdigit:]]*)/([[:xdigit:]]*)").unwrap();
+        let mut caps = re.captures_iter(command_str(cmd)?);
+        let cap = match caps.next() {
+            Some(cap) => cap,
+            None => {
+                return Err(sql_error(
+                    SQLSTATE_SYNTAX_ERROR,
+                    format!("START_REPLICATION expects start LSN: {:?}", cmd),
+                ));
+            }
+        };
+        let mut start_pos: XLogRecPtr = (parse_hex_str(&cap[1])? << 32) | parse_hex_str(&cap[2])?;
+        let mut stop_pos: XLogRecPtr = if let Some(cap) = caps.next() {
+            (parse_hex_str(&cap[1])? << 32) | parse_hex_str(&cap[2])?
+        } else {
+            0
+        };
+        let requested_timeline = match Regex::new(r"TIMELINE\s+(\d+)")
+            .unwrap()
+            .captures(command_str(cmd)?)
+        {
+            Some(cap) => match cap[1].parse::<TimeLineID>() {
+                Ok(tli) => Some(tli),
+                Err(_) => {
+                    io_error!("Invalid timeline {}", &cap[1]);
+                }
+            },
+            None => None,
+        };
+        /* Receiver verifies WAL against checksums of the proposer, see wal_checksum */
+        let send_checksums = command_str(cmd)?.contains("WAL_CHECKSUMS");
+        let wal_seg_size = self.system().get_info().server.wal_seg_size as usize;
+        if wal_seg_size == 0 {
+            io_error!("Can not start replication before connecting to wal_proposer");
+        }
+        let (wal_end, mut timeline) = self.find_end_of_wal(false)?;
+        if start_pos == 0 {
+            start_pos = wal_end;
+        }
+        /* Segments before a timeline switch are files of the ancestor timelines */
+        let latest_timeline = timeline;
+        let history = timeline_history::load(&self.system_dir(), latest_timeline)?;
+        let segment_timeline = |pos: XLogRecPtr| {
+            let segno = XLByteToSeg(pos, wal_seg_size);
+            timeline_history::segment_timeline(&history, latest_timeline, segno, wal_seg_size)
+        };
+
+        /*
+         * Replica following a timeline switch asks for WAL of an ancestor timeline:
+         * it is streamed up to the switch point, then the next timeline is reported.
+         */
+        let mut timeline_end: Option<(TimeLineID, XLogRecPtr)> = None;
+        if let Some(requested) = requested_timeline.filter(|tli| *tli != timeline) {
+            let pos = match history.iter().position(|entry| entry.timeline == requested) {
+                Some(pos) => pos,
+                None => {
+                    io_error!("Requested timeline {} is not in this server's history", requested);
+                }
+            };
+            let switch_lsn = history[pos].switch_lsn;
+            if start_pos > switch_lsn {
+                io_error!(
+                    "Requested starting point {} on timeline {} is not in this server's history, it forked from timeline {} at {}",
+                    format_lsn(start_pos),
+                    requested,
+                    requested,
+                    format_lsn(switch_lsn)
+                );
+            }
+            let next_timeline = history.get(pos + 1).map_or(timeline, |entry| entry.timeline);
+            stop_pos = if stop_pos != 0 { min(stop_pos, switch_lsn) } else { switch_lsn };
+            timeline = requested;
+            timeline_end = Some((next_timeline, switch_lsn));
+        }
+        let flush_lsn = self.system().get_flush_lsn();
+        if start_pos > flush_lsn {
+            /* E.g. standby streamed from another safekeeper which was ahead of this one */
+            info!(
+                "{} requested WAL from {} which is ahead of flush position {}, wait for it",
+                peer_addr,
+                format_lsn(start_pos),
+                format_lsn(flush_lsn)
+            );
+        }
+        info!(
+            "Start replication from {:X}/{:>08X} till {:X}/{:>08X}",
+            (start_pos >> 32) as u32,
+            start_pos as u32,
+            (stop_pos >> 32) as u32,
+            stop_pos as u32
+        );
+        BeMessage::write(&mut self.outbuf, &BeMessage::Copy);
+        self.send().await?;
+
+        /*
+         * Always start streaming at the beginning of a segment
+         *
+         * FIXME: It is common practice to start streaming at the beginning of
+         * the segment, but it should be up to the client to decide that. We
+         * shouldn't enforce that here.
+         */
+        start_pos -= XLogSegmentOffset(start_pos, wal_seg_size) as u64;
+
+        /*
+         * Sender starting more than a segment behind commit LSN is catching up:
+         * its progress is tracked, it may be throttled and cancelled by administrator.
+         */
+        let catchup_start = start_pos;
+        let catchup_started = clock::now();
+        let mut catching_up = self.system().get_commit_lsn() > start_pos + wal_seg_size as u64;
+        self.registration.set_state(if catching_up {
+            ConnectionState::StreamingCatchup
+        } else {
+            ConnectionState::StreamingLive
+        });
+
+        let mut end_pos: XLogRecPtr;
+        let mut commit_lsn: XLogRecPtr;
+        let mut wal_file: Option<File> = None;
+        let mut last_keepalive = clock::now();
+        self.outbuf
+            .resize(LIBPQ_HDR_SIZE + XLOG_HDR_SIZE + MAX_SEND_SIZE, 0u8);
+        loop {
+            /* Wait until we have some data to stream */
+            if stop_pos != 0 {
+                /* recovery mode: stream up to the specified LSN (VCL) */
+                if start_pos >= stop_pos {
+                    /* recovery finished, or end of ancestor timeline reached */
+                    if let Some((next_timeline, switch_lsn)) = timeline_end {
+                        return self.end_historic_timeline(next_timeline, switch_lsn).await;
+                    }
+                    break;
+                }
+                end_pos = stop_pos;
+            } else {
+                /*
+                 * normal mode: wait for committed WAL. Idle (or ahead of us) replica
+                 * gets keepalives, so that it doesn't time out the connection,
+                 * and its feedback is processed meanwhile.
+                 */
+                loop {
+                    let system = self.system();
+                    let notified = system.cond.notified();
+                    commit_lsn = system.get_commit_lsn();
+                    if start_pos < commit_lsn || system.wal_senders_stopped() {
+                        end_pos = commit_lsn;
+                        break;
+                    }
+                    self.trim_buffers();
+                    let wakeup = tokio::select! {
+                        _ = notified => SenderWakeup::Wal,
+                        _ = sleep(self.conf.keepalive_interval) => SenderWakeup::Keepalive,
+                        res = self.stream.readable() => {
+                            res?;
+                            SenderWakeup::Feedback
+                        }
+                        _ = self.registration.terminated() => SenderWakeup::Terminated,
+                    };
+                    match wakeup {
+                        SenderWakeup::Wal => {}
+                        SenderWakeup::Terminated => self.terminate_libpq().await?,
+                        SenderWakeup::Keepalive => {
+                            let reply_requested = self
+                                .system()
+                                .replica_reply_due(&peer_addr, self.conf.keepalive_interval);
+                            self.send_keepalive(commit_lsn, reply_requested).await?;
+                            last_keepalive = clock::now();
+                        }
+                        SenderWakeup::Feedback => {
+                            match self.process_replica_messages(peer_addr, commit_lsn).await? {
+                                ReplicaInput::Continue => {}
+                                ReplicaInput::CopyDone => return self.end_streaming().await,
+                                ReplicaInput::Closed => return Ok(false),
+                            }
+                        }
+                    }
+                }
+            }
+            if self.system().wal_senders_stopped() {
+                self.registration.set_state(ConnectionState::Draining);
+                break;
+            }
+            /* Sender catching up never waits, so the request is checked on each chunk */
+            if self.registration.terminate_requested() {
+                self.terminate_libpq().await?;
+            }
+            match self.process_replica_messages(peer_addr, end_pos).await? {
+                ReplicaInput::Continue => {}
+                ReplicaInput::CopyDone => return self.end_streaming().await,
+                ReplicaInput::Closed => break,
+            }
+            /*
+             * Streaming replica isn't idle, but it is asked to report its progress
+             * if it hasn't done that for a keepalive interval
+             */
+            if clock::elapsed(last_keepalive) >= self.conf.keepalive_interval {
+                if self
+                    .system()
+                    .replica_reply_due(&peer_addr, self.conf.keepalive_interval)
+                {
+                    self.send_keepalive(end_pos, true).await?;
+                }
+                last_keepalive = clock::now();
+            }
+
+            /* Open file if not opened yet, segments removed after archiving are restored */
+            let mut file = match wal_file.take() {
+                Some(opened_file) => opened_file,
+                None => match self
+                    .open_prepared_wal_file(start_pos, segment_timeline(start_pos), wal_seg_size)
+                    .await
+                {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => self
+                        .restore_wal_file(start_pos, segment_timeline(start_pos), wal_seg_size)
+                        .await
+                        .map_err(|_| e)?,
+                    res => res?,
+                },
+            };
+            let file_timeline = segment_timeline(start_pos);
+            let send_size = self
+                .send_wal_chunk(&mut file, start_pos, end_pos, file_timeline, wal_seg_size)
+                .await?;
+            start_pos += send_size as u64;
+            self.system().update_sender(peer_addr, Some(start_pos));
+
+            if XLogSegmentOffset(start_pos, wal_seg_size) != 0 {
+                wal_file = Some(file);
+            } else if send_checksums {
+                let segno = XLByteToSeg(start_pos - 1, wal_seg_size);
+                let segment = XLogFileName(segment_timeline(start_pos - 1), segno, wal_seg_size);
+                let dir = self.system_dir();
+                let system = self.system();
+                let crc = run_blocking(move || {
+                    let mut checksums = system.wal_checksums.lock().unwrap();
+                    checksums.lookup(&dir, &segment)
+                })
+                .await??;
+                if let Some(crc) = crc {
+                    self.send_segment_checksum(start_pos, crc).await?;
+                }
+            }
+            if catching_up {
+                let system = self.system();
+                if system.get_commit_lsn() <= start_pos + wal_seg_size as u64 {
+                    info!("{} has caught up at {}", peer_addr, format_lsn(start_pos));
+                    system.finish_catchup(&peer_addr);
+                    catching_up = false;
+                    self.registration.set_state(ConnectionState::StreamingLive);
+                } else {
+                    if !system.update_catchup(peer_addr, catchup_start, start_pos) {
+                        io_error!("Catch-up of {} is cancelled", peer_addr);
+                    }
+                    /* Bound catch-up throughput */
+                    if let Some(rate) = self.conf.catchup_rate_limit {
+                        let due = Duration::from_secs_f64(
+                            (start_pos - catchup_start) as f64 / rate as f64,
+                        );
+                        let elapsed = clock::elapsed(catchup_started);
+                        if due > elapsed {
+                            sleep(due - elapsed).await;
+                        }
+                    }
+                }
+            }
+            self.system().yield_if_batch().await;
+        }
+        Ok(false)
+    }
+
+    //
+    // Complete streaming of an ancestor timeline: after exchange of CopyDone messages
+    // replica gets the next timeline and its start position, as Postgres sends them,
+    // and may start replication on that timeline in the same session.
     //
-    async fn send_wal(&mut self) -> Result<()> {
-        info!("WAL sender to {:?} is started", self.stream.peer_addr()?);
+    async fn end_historic_timeline(
+        &mut self,
+        next_timeline: TimeLineID,
+        switch_lsn: XLogRecPtr,
+    ) -> Result<bool> {
+        self.start_sending();
+        BeMessage::write(&mut self.outbuf, &BeMessage::CopyDone);
+        self.send().await?;
         loop {
-            self.start_sending();
             match self.read_message().await? {
-                Some(FeMessage::StartupMessage(m)) => {
-                    trace!("got message {:?}", m);
+                Some(FeMessage::CopyDone) => break,
+                /* Feedback sent before replica noticed the end of the timeline */
+                Some(FeMessage::CopyData(_)) => {}
+                Some(FeMessage::Terminate) | None => return Ok(false),
+                Some(_) => {
+                    io_error!("Unexpected message at the end of timeline");
+                }
+            }
+        }
+        let tli = next_timeline.to_string();
+        let lsn = format_lsn(switch_lsn);
+        self.start_sending();
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::RowDescription(&[
+                RowDescriptor {
+                    name: b"next_tli\0",
+                    typoid: 20,
+                    typlen: 8,
+                },
+                RowDescriptor {
+                    name: b"next_tli_startpos\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+            ]),
+        );
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::DataRow(&[Some(tli.as_bytes()), Some(lsn.as_bytes())]),
+        );
+        BeMessage::write(&mut self.outbuf, &BeMessage::CommandComplete(b"START_STREAMING"));
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
+    }
 
-                    match m.kind {
-                        StartupRequestCode::NegotiateGss | StartupRequestCode::NegotiateSsl => {
-                            BeMessage::write(&mut self.outbuf, &BeMessage::Negotiate);
-                            info!("SSL requested");
-                            self.send().await?;
-                        }
-                        StartupRequestCode::Normal => {
-                            BeMessage::write(&mut self.outbuf, &BeMessage::AuthenticationOk);
-                            BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
-                            self.send().await?;
-                            self.init_done = true;
-                            self.set_system(m.system_id)?;
-                        }
-                        StartupRequestCode::Cancel => return Ok(()),
-                    }
+    //
+    // Handle SHOW command for the settings which replication clients (pg_receivewal,
+    // pg_basebackup) ask for. Values are of the tenant.
+    //
+    async fn handle_show(&mut self, cmd: &Bytes) -> Result<bool> {
+        let query = str::from_utf8(&cmd[..]).unwrap_or("").trim_end_matches('\0');
+        let name = query["SHOW".len()..]
+            .trim()
+            .trim_end_matches(';')
+            .trim_matches('"')
+            .to_lowercase();
+        let info = self.system().get_info();
+        let pg_version = info.server.pg_version;
+        let (column, value): (&'static [u8], String) = match name.as_str() {
+            "wal_segment_size" => {
+                if info.server.wal_seg_size == 0 {
+                    io_error!("WAL segment size is unknown before connecting to wal_proposer");
                 }
-                Some(FeMessage::Query(m)) => {
-                    if !self.process_query(&m).await? {
-                        break;
-                    }
+                (
+                    b"wal_segment_size\0",
+                    format!("{}MB", info.server.wal_seg_size / (1024 * 1024)),
+                )
+            }
+            "wal_block_size" => (b"wal_block_size\0", XLOG_BLCKSZ.to_string()),
+            "data_directory_mode" => {
+                let mode = fs::metadata(self.system_dir())?.permissions().mode() & 0o777;
+                (b"data_directory_mode\0", format!("{:04o}", mode))
+            }
+            "server_version_num" => (b"server_version_num\0", pg_version.to_string()),
+            "server_version" => {
+                let version = if pg_version >= 100000 {
+                    format!("{}.{}", pg_version / 10000, pg_version % 10000)
+                } else {
+                    format!(
+                        "{}.{}.{}",
+                        pg_version / 10000,
+                        pg_version / 100 % 100,
+                        pg_version % 100
+                    )
+                };
+                (b"server_version\0", version)
+            }
+            _ => {
+                io_error!("Unrecognized configuration parameter \"{}\"", name);
+            }
+        };
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::RowDescription(&[RowDescriptor {
+                name: column,
+                typoid: 25,
+                typlen: -1,
+            }]),
+        );
+        BeMessage::write(&mut self.outbuf, &BeMessage::DataRow(&[Some(value.as_bytes())]));
+        BeMessage::write(&mut self.outbuf, &BeMessage::CommandComplete(b"SHOW"));
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
+    }
+
+    //
+    // Handle TIMELINE_HISTORY replication command: send history file of the timeline
+    //
+    async fn handle_timeline_history(&mut self, cmd: &Bytes) -> Result<bool> {
+        let re = Regex::new(r"TIMELINE_HISTORY\s+(\d+)").unwrap();
+        let timeline = match re.captures(command_str(cmd)?) {
+            Some(cap) => match cap[1].parse::<TimeLineID>() {
+                Ok(tli) => tli,
+                Err(_) => {
+                    io_error!("Invalid timeline {}", &cap[1]);
                 }
-                Some(FeMessage::Terminate) => {
-                    break;
+            },
+            None => {
+                io_error!("Invalid TIMELINE_HISTORY command {:?}", cmd);
+            }
+        };
+        let content = match timeline_history::read(&self.system_dir(), timeline)? {
+            Some(content) => content,
+            None => {
+                io_error!("Timeline {} of system {} has no history", timeline, self.system().id);
+            }
+        };
+        let fname = timeline_history::history_file_name(timeline);
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::RowDescription(&[
+                RowDescriptor {
+                    name: b"filename\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"content\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+            ]),
+        );
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::DataRow(&[Some(fname.as_bytes()), Some(&content[..])]),
+        );
+        BeMessage::write(&mut self.outbuf, &BeMessage::CommandComplete(b"TIMELINE_HISTORY"));
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
+    }
+
+    //
+    // Handle FETCH_WAL command: stream exactly the specified range of WAL and complete.
+    // Unlike START_REPLICATION the range is validated against the WAL retained by this safekeeper.
+    //
+    async fn handle_fetch_wal(&mut self, cmd: &Bytes) -> Result<bool> {
+        let re = Regex::new(r"([[:xdigit:]]+)/([[:xdigit:]]+)").unwrap();
+        let mut lsns: Vec<XLogRecPtr> = Vec::new();
+        for cap in re.captures_iter(command_str(cmd)?) {
+            lsns.push((parse_hex_str(&cap[1])? << 32) | parse_hex_str(&cap[2])?);
+        }
+        if lsns.len() != 2 {
+            io_error!("FETCH_WAL expects start and end LSN: {:?}", cmd);
+        }
+        let (mut start_pos, end_pos) = (lsns[0], lsns[1]);
+        if start_pos > end_pos {
+            io_error!(
+                "Invalid WAL range {}-{}",
+                format_lsn(start_pos),
+                format_lsn(end_pos)
+            );
+        }
+        let wal_seg_size = self.system().get_info().server.wal_seg_size as usize;
+        if wal_seg_size == 0 {
+            io_error!("Can not fetch WAL before connecting to wal_proposer");
+        }
+
+        /* Check that the whole range is present on disk */
+        let system_dir = self.system_dir();
+        let (wal_end, timeline) =
+            find_end_of_wal(&system_dir, wal_seg_size, true, self.conf.pg_wal_layout);
+        let wal_start = match find_start_of_wal(&system_dir, wal_seg_size)? {
+            Some(segno) => XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size),
+            None => wal_end,
+        };
+        if start_pos < wal_start || end_pos > wal_end {
+            io_error!(
+                "Requested WAL range {}-{} is out of retained WAL {}-{}",
+                format_lsn(start_pos),
+                format_lsn(end_pos),
+                format_lsn(wal_start),
+                format_lsn(wal_end)
+            );
+        }
+        if start_pos < end_pos {
+            let first_segno = XLByteToSeg(start_pos, wal_seg_size);
+            let last_segno = XLByteToSeg(end_pos - 1, wal_seg_size);
+            for segno in first_segno..=last_segno {
+                let segment = SegmentPath::new(&system_dir, timeline, segno, wal_seg_size);
+                if !segment.exists() {
+                    io_error!("WAL segment {} is missing", segment.name);
                 }
+            }
+        }
+        info!(
+            "Fetch WAL from {} till {}",
+            format_lsn(start_pos),
+            format_lsn(end_pos)
+        );
+        BeMessage::write(&mut self.outbuf, &BeMessage::Copy);
+        self.send().await?;
+
+        let mut wal_file: Option<File> = None;
+        self.outbuf
+            .resize(LIBPQ_HDR_SIZE + XLOG_HDR_SIZE + MAX_SEND_SIZE, 0u8);
+        while start_pos < end_pos {
+            let mut file = match wal_file.take() {
+                Some(opened_file) => opened_file,
                 None => {
-                    info!("connection closed");
-                    break;
-                }
-                _ => {
-                    io_error!("unexpected message");
+                    self.open_prepared_wal_file(start_pos, timeline, wal_seg_size)
+                        .await?
                 }
+            };
+            let send_size = self
+                .send_wal_chunk(&mut file, start_pos, end_pos, timeline, wal_seg_size)
+                .await?;
+            start_pos += send_size as u64;
+
+            if XLogSegmentOffset(start_pos, wal_seg_size) != 0 {
+                wal_file = Some(file);
+            }
+            self.system().yield_if_batch().await;
+        }
+        self.start_sending();
+        BeMessage::write(&mut self.outbuf, &BeMessage::CopyDone);
+        BeMessage::write(&mut self.outbuf, &BeMessage::CommandComplete(b"FETCH_WAL"));
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
+    }
+
+    // Open WAL segment as open_wal_file does, once it is not being zero-filled
+    async fn open_prepared_wal_file(
+        &self,
+        pos: XLogRecPtr,
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+    ) -> Result<File> {
+        self.system()
+            .wait_segment_prepared(XLByteToSeg(pos, wal_seg_size))
+            .await;
+        self.open_wal_file(pos, timeline, wal_seg_size)
+    }
+
+    //
+    // Open WAL segment containing the specified position and seek to this position
+    //
+    fn open_wal_file(
+        &self,
+        pos: XLogRecPtr,
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+    ) -> Result<File> {
+        let segno = XLByteToSeg(pos, wal_seg_size);
+        let segment = SegmentPath::new(&self.system_dir(), timeline, segno, wal_seg_size);
+        let mut file = match segment.open_for_read(self.conf.at_rest_key.as_ref()) {
+            Ok(opened_file) => opened_file,
+            Err(e) => {
+                error!("Failed to open log file {:?}: {}", &segment.complete, e);
+                return Err(e);
+            }
+        };
+        file.seek(SeekFrom::Start(XLogSegmentOffset(pos, wal_seg_size) as u64))?;
+        Ok(file)
+    }
+
+    //
+    // Download segment containing the specified position from --archive, if it has been
+    // archived, and seek to this position
+    //
+    async fn restore_wal_file(
+        &self,
+        pos: XLogRecPtr,
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+    ) -> Result<File> {
+        let bucket = match &self.conf.archive {
+            Some(bucket) => bucket,
+            None => {
+                io_error!("WAL archive is not configured");
+            }
+        };
+        match self.system().archive_position() {
+            Some((_, _, archived_lsn)) if pos < archived_lsn => {}
+            _ => {
+                io_error!("WAL at {} is not archived", format_lsn(pos));
+            }
+        }
+        let segno = XLByteToSeg(pos, wal_seg_size);
+        let mut file = archive::restore_segment(
+            bucket,
+            &self.system_dir(),
+            self.system().id,
+            segno,
+            timeline,
+            wal_seg_size,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to restore WAL at {} from archive: {}", format_lsn(pos), e);
+            e
+        })?;
+        self.system().account_wal_op(WalOp::Restore, 1, wal_seg_size as u64);
+        file.seek(SeekFrom::Start(XLogSegmentOffset(pos, wal_seg_size) as u64))?;
+        Ok(file)
+    }
+
+    //
+    // Read and process messages which replica has sent so far, without blocking.
+    // Messages following CopyDone are left for the session.
+    //
+    async fn process_replica_messages(
+        &mut self,
+        peer_addr: SocketAddr,
+        wal_end: XLogRecPtr,
+    ) -> Result<ReplicaInput> {
+        match self.stream.try_read_buf(&mut self.inbuf) {
+            Ok(0) => return Ok(ReplicaInput::Closed),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => {
+                return Err(e.into());
+            }
+        }
+        while let Some(msg) = self.parse_message()? {
+            match msg {
+                FeMessage::CopyData(m) => match ReplicaMessage::parse(&m.body) {
+                    ReplicaMessage::HotStandbyFeedback(feedback) => {
+                        self.system().add_hs_feedback(peer_addr, feedback)
+                    }
+                    ReplicaMessage::NoHotStandbyFeedback => {
+                        self.system().remove_hs_feedback(&peer_addr)
+                    }
+                    ReplicaMessage::StatusUpdate {
+                        write_lsn,
+                        flush_lsn,
+                        apply_lsn,
+                        reply_requested,
+                    } => {
+                        let system = self.system();
+                        system.update_replica_state(peer_addr, write_lsn, flush_lsn, apply_lsn);
+                        system.account_applied(apply_lsn);
+                        if reply_requested {
+                            self.send_keepalive(wal_end, false).await?;
+                        }
+                    }
+                    ReplicaMessage::Unknown => {
+                        debug!("Unknown message from replica {}: {:?}", peer_addr, m.body);
+                    }
+                },
+                FeMessage::CopyDone => return Ok(ReplicaInput::CopyDone),
+                FeMessage::Terminate => return Ok(ReplicaInput::Closed),
+                _ => {}
+            }
+        }
+        Ok(ReplicaInput::Continue)
+    }
+
+    //
+    // Replica has ended streaming with CopyDone: answer it with CopyDone and complete
+    // the command, as Postgres does, so that the session goes on
+    //
+    async fn end_streaming(&mut self) -> Result<bool> {
+        self.start_sending();
+        BeMessage::write(&mut self.outbuf, &BeMessage::CopyDone);
+        BeMessage::write(&mut self.outbuf, &BeMessage::CommandComplete(b"START_STREAMING"));
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
+    }
+
+    //
+    // Send primary keepalive message ('k') telling replica the end of WAL available to it
+    //
+    async fn send_keepalive(&mut self, wal_end: XLogRecPtr, reply_requested: bool) -> Result<()> {
+        const KEEPALIVE_SIZE: usize = 1 + 8 + 8 + 1;
+        let msg_size = LIBPQ_HDR_SIZE + KEEPALIVE_SIZE;
+        self.outbuf[0] = b'd';
+        BigEndian::write_u32(
+            &mut self.outbuf[1..5],
+            (msg_size - LIBPQ_MSG_SIZE_OFFS) as u32,
+        );
+        self.outbuf[5] = b'k';
+        BigEndian::write_u64(&mut self.outbuf[6..14], wal_end);
+        BigEndian::write_u64(&mut self.outbuf[14..22], get_current_timestamp());
+        self.outbuf[22] = reply_requested as u8;
+        self.stream.write_all(&self.outbuf[0..msg_size]).await
+    }
+
+    //
+    // Send checksum of the segment ending at the given position, after its last byte:
+    // CopyData with 'c', the end of the segment and CRC-32C of its WAL as the proposer
+    // computed it
+    //
+    async fn send_segment_checksum(&mut self, segment_end: XLogRecPtr, crc: u32) -> Result<()> {
+        const CHECKSUM_SIZE: usize = 1 + 8 + 4;
+        let msg_size = LIBPQ_HDR_SIZE + CHECKSUM_SIZE;
+        self.outbuf[0] = b'd';
+        BigEndian::write_u32(
+            &mut self.outbuf[1..5],
+            (msg_size - LIBPQ_MSG_SIZE_OFFS) as u32,
+        );
+        self.outbuf[5] = b'c';
+        BigEndian::write_u64(&mut self.outbuf[6..14], segment_end);
+        BigEndian::write_u32(&mut self.outbuf[14..18], crc);
+        self.stream.write_all(&self.outbuf[0..msg_size]).await
+    }
+
+    //
+    // Send XLogData message with WAL starting at start_pos, not crossing segment boundary.
+    // Output buffer should be large enough to hold MAX_SEND_SIZE bytes of WAL.
+    // Returns number of sent WAL bytes.
+    //
+    async fn send_wal_chunk(
+        &mut self,
+        file: &mut File,
+        start_pos: XLogRecPtr,
+        end_pos: XLogRecPtr,
+        timeline: TimeLineID,
+        wal_seg_size: usize,
+    ) -> Result<usize> {
+        let seg_left = wal_seg_size - XLogSegmentOffset(start_pos, wal_seg_size) as usize;
+        let send_size = min(min((end_pos - start_pos) as usize, MAX_SEND_SIZE), seg_left);
+        let msg_size = LIBPQ_HDR_SIZE + XLOG_HDR_SIZE + send_size;
+        let data_start = LIBPQ_HDR_SIZE + XLOG_HDR_SIZE;
+        let data_end = data_start + send_size;
+        /* Buffer may have been shrunk while the sender was idle */
+        if self.outbuf.len() < msg_size {
+            self.outbuf.resize(msg_size, 0u8);
+        }
+        if msg_size > BUFFER_BASELINE {
+            self.large_io_at = clock::now();
+        }
+        let system = self.system();
+        if read_cache::read(
+            system.id,
+            timeline,
+            file,
+            start_pos,
+            system.get_commit_lsn(),
+            wal_seg_size,
+            &mut self.outbuf[data_start..data_end],
+        )? {
+            /* Keep file position in sync for the next chunk */
+            file.seek(SeekFrom::Current(send_size as i64))?;
+        } else {
+            file.read_exact(&mut self.outbuf[data_start..data_end])?;
+        }
+        self.outbuf[0] = b'd';
+        BigEndian::write_u32(
+            &mut self.outbuf[1..5],
+            (msg_size - LIBPQ_MSG_SIZE_OFFS) as u32,
+        );
+        self.outbuf[5] = b'w';
+        BigEndian::write_u64(&mut self.outbuf[6..14], start_pos);
+        BigEndian::write_u64(&mut self.outbuf[14..22], end_pos);
+        BigEndian::write_u64(&mut self.outbuf[22..30], get_current_timestamp());
+
+        let send_start = clock::now();
+        self.stream.write_all(&self.outbuf[0..msg_size]).await?;
+        self.system().account_send(send_size);
+        if let Some(threshold) = self.conf.slow_send_threshold {
+            let elapsed = clock::elapsed(send_start);
+            if elapsed > threshold {
+                warn!(
+                    "Slow send to {:?} of system {}: {} bytes of WAL {}-{} sent in {:?}",
+                    self.stream.peer_addr()?,
+                    self.system().id,
+                    send_size,
+                    format_lsn(start_pos),
+                    format_lsn(start_pos + send_size as u64),
+                    elapsed
+                );
             }
         }
-        info!("WAL sender to {:?} is finished", self.stream.peer_addr()?);
-        Ok(())
+        Ok(send_size)
     }
 
     //
-    // Handle IDENTIFY_SYSTEM replication command
+    // Handle PAUSE_WAL and RESUME_WAL admin commands
     //
-    async fn handle_identify_system(&mut self) -> Result<bool> {
-        let (start_pos, timeline) = self.find_end_of_wal(false);
-        let lsn = format!("{:X}/{:>08X}", (start_pos >> 32) as u32, start_pos as u32);
-        let tli = timeline.to_string();
-        let sysid = self.system().get_info().server.system_id.to_string();
-        let lsn_bytes = lsn.as_bytes();
-        let tli_bytes = tli.as_bytes();
-        let sysid_bytes = sysid.as_bytes();
+    async fn handle_pause_wal(&mut self, paused: bool) -> Result<bool> {
+        self.system().set_paused(paused);
+        info!(
+            "WAL ingest for system {} is {}",
+            self.system().id,
+            if paused { "paused" } else { "resumed" }
+        );
+        let tag: &[u8] = if paused { b"PAUSE_WAL" } else { b"RESUME_WAL" };
+        BeMessage::write(&mut self.outbuf, &BeMessage::CommandComplete(tag));
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
+    }
+
+    //
+    // Handle PAGESERVER_CHECKPOINT command: pageserver notifies us that WAL up to the
+    // specified LSN is checkpointed and not needed by it anymore
+    //
+    async fn handle_pageserver_checkpoint(&mut self, cmd: &Bytes) -> Result<bool> {
+        let cmd = command_str(cmd)?;
+        let lsn = parse_lsn(cmd["PAGESERVER_CHECKPOINT".len()..].trim_end_matches('\0'))?;
+        info!(
+            "Pageserver checkpointed system {} up to {}",
+            self.system().id,
+            format_lsn(lsn)
+        );
+        let system = self.system();
+        let conf = self.conf.clone();
+        run_blocking(move || system.set_remote_consistent_lsn(&conf, lsn)).await??;
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::CommandComplete(b"PAGESERVER_CHECKPOINT"),
+        );
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
+    }
+
+    //
+    // Handle SAFEKEEPER_STATUS admin command
+    //
+    async fn handle_status(&mut self) -> Result<bool> {
+        let snapshot = self.system().snapshot();
+        let sysid = snapshot.id.to_string();
+        let priority = format!("{:?}", snapshot.priority).to_lowercase();
+        let epoch = snapshot.epoch().to_string();
+        let flush_lsn = format_lsn(snapshot.flush_lsn());
+        let commit_lsn = format_lsn(snapshot.commit_lsn);
+        let remote_consistent_lsn = format_lsn(snapshot.remote_consistent_lsn);
+        let paused = snapshot.paused.to_string();
+        let paused_appends = snapshot.paused_appends.to_string();
 
         BeMessage::write(
             &mut self.outbuf,
@@ -875,17 +6969,37 @@ impl Connection {
                     typlen: -1,
                 },
                 RowDescriptor {
-                    name: b"timeline\0",
-                    typoid: 23,
-                    typlen: 4,
+                    name: b"priority\0",
+                    typoid: 25,
+                    typlen: -1,
                 },
                 RowDescriptor {
-                    name: b"xlogpos\0",
+                    name: b"epoch\0",
                     typoid: 25,
                     typlen: -1,
                 },
                 RowDescriptor {
-                    name: b"dbname\0",
+                    name: b"flush_lsn\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"commit_lsn\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"remote_consistent_lsn\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"paused\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"paused_appends\0",
                     typoid: 25,
                     typlen: -1,
                 },
@@ -893,11 +7007,20 @@ impl Connection {
         );
         BeMessage::write(
             &mut self.outbuf,
-            &BeMessage::DataRow(&[Some(lsn_bytes), Some(tli_bytes), Some(sysid_bytes), None]),
+            &BeMessage::DataRow(&[
+                Some(sysid.as_bytes()),
+                Some(priority.as_bytes()),
+                Some(epoch.as_bytes()),
+                Some(flush_lsn.as_bytes()),
+                Some(commit_lsn.as_bytes()),
+                Some(remote_consistent_lsn.as_bytes()),
+                Some(paused.as_bytes()),
+                Some(paused_appends.as_bytes()),
+            ]),
         );
         BeMessage::write(
             &mut self.outbuf,
-            &BeMessage::CommandComplete(b"IDENTIFY_SYSTEM"),
+            &BeMessage::CommandComplete(b"SAFEKEEPER_STATUS"),
         );
         BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
         self.send().await?;
@@ -905,147 +7028,163 @@ impl Connection {
     }
 
     //
-    // Handle START_REPLICATION replication command
+    // Handle SAFEKEEPER_WAL_STATS admin command: report received records by resource manager
     //
-    async fn handle_start_replication(&mut self, cmd: &Bytes) -> Result<bool> {
-        let re = Regex::new(r"([[:xdigit:]]*)/([[:xdigit:]]*)").unwrap();
-        let mut caps = re.captures_iter(str::from_utf8(&cmd[..]).unwrap());
-        let cap = caps.next().unwrap();
-        let mut start_pos: XLogRecPtr = (parse_hex_str(&cap[1])? << 32) | parse_hex_str(&cap[2])?;
-        let stop_pos: XLogRecPtr = if let Some(cap) = caps.next() {
-            (parse_hex_str(&cap[1])? << 32) | parse_hex_str(&cap[2])?
-        } else {
-            0
-        };
-        let wal_seg_size = self.system().get_info().server.wal_seg_size as usize;
-        if wal_seg_size == 0 {
-            io_error!("Can not start replication before connecting to wal_proposer");
+    async fn handle_wal_stats(&mut self) -> Result<bool> {
+        if !self.conf.wal_stats {
+            io_error!("WAL statistics are not enabled");
         }
-        let (wal_end, timeline) = self.find_end_of_wal(false);
-        if start_pos == 0 {
-            start_pos = wal_end;
-        }
-        info!(
-            "Start replication from {:X}/{:>08X} till {:X}/{:>08X}",
-            (start_pos >> 32) as u32,
-            start_pos as u32,
-            (stop_pos >> 32) as u32,
-            stop_pos as u32
-        );
-        BeMessage::write(&mut self.outbuf, &BeMessage::Copy);
-        self.send().await?;
+        let stats = TENANT_LOCKS.lock(&self.system().mutex).wal_stats;
 
-        /*
-         * Always start streaming at the beginning of a segment
-         *
-         * FIXME: It is common practice to start streaming at the beginning of
-         * the segment, but it should be up to the client to decide that. We
-         * shouldn't enforce that here.
-         */
-        start_pos -= XLogSegmentOffset(start_pos, wal_seg_size) as u64;
-
-        let mut end_pos: XLogRecPtr;
-        let mut commit_lsn: XLogRecPtr;
-        let mut wal_file: Option<File> = None;
-        self.outbuf
-            .resize(LIBPQ_HDR_SIZE + XLOG_HDR_SIZE + MAX_SEND_SIZE, 0u8);
-        loop {
-            /* Wait until we have some data to stream */
-            if stop_pos != 0 {
-                /* recovery mode: stream up to the specified LSN (VCL) */
-                if start_pos >= stop_pos {
-                    /* recovery finished */
-                    break;
-                }
-                end_pos = stop_pos;
-            } else {
-                /* normal mode */
-                loop {
-                    // Rust doesn't allow to grab async result from mutex scope
-                    let system = self.system();
-                    let notified = system.cond.notified();
-                    {
-                        let shared_state = system.mutex.lock().unwrap();
-                        commit_lsn = shared_state.commit_lsn;
-                        if start_pos < commit_lsn {
-                            end_pos = commit_lsn;
-                            break;
-                        }
-                    }
-                    notified.await;
-                }
-            }
-            if end_pos == END_REPLICATION_MARKER {
-                break;
-            }
-            // Try to fetch replica's feedback
-            match self.stream.try_read_buf(&mut self.inbuf) {
-                Ok(0) => break,
-                Ok(_) => match self.parse_message()? {
-                    Some(FeMessage::CopyData(m)) => self
-                        .system()
-                        .add_hs_feedback(HotStandbyFeedback::parse(&m.body)),
-                    _ => {}
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::RowDescription(&[
+                RowDescriptor {
+                    name: b"rmgr\0",
+                    typoid: 25,
+                    typlen: -1,
                 },
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                Err(e) => {
-                    return Err(e.into());
-                }
+                RowDescriptor {
+                    name: b"records\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"bytes\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+            ]),
+        );
+        for rmid in 0..=RM_MAX_ID {
+            if stats.records[rmid] != 0 {
+                let rmgr = rm_name(rmid as u8);
+                let records = stats.records[rmid].to_string();
+                let bytes = stats.bytes[rmid].to_string();
+                BeMessage::write(
+                    &mut self.outbuf,
+                    &BeMessage::DataRow(&[
+                        Some(rmgr.as_bytes()),
+                        Some(records.as_bytes()),
+                        Some(bytes.as_bytes()),
+                    ]),
+                );
             }
+        }
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::CommandComplete(b"SAFEKEEPER_WAL_STATS"),
+        );
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
+    }
 
-            /* Open file if not opened yet */
-            let curr_file = wal_file.take();
-            let mut file: File;
-            if let Some(opened_file) = curr_file {
-                file = opened_file;
-            } else {
-                let segno = XLByteToSeg(start_pos, wal_seg_size);
-                let wal_file_name = XLogFileName(timeline, segno, wal_seg_size);
-                let wal_file_path = self
-                    .conf
-                    .data_dir
-                    .join(self.system().id.to_string())
-                    .join(wal_file_name.clone() + ".partial");
-                if let Ok(opened_file) = File::open(&wal_file_path) {
-                    file = opened_file;
-                } else {
-                    let wal_file_path = self
-                        .conf
-                        .data_dir
-                        .join(self.system().id.to_string())
-                        .join(wal_file_name);
-                    match File::open(&wal_file_path) {
-                        Ok(opened_file) => file = opened_file,
-                        Err(e) => {
-                            error!("Failed to open log file {:?}: {}", &wal_file_path, e);
-                            return Err(e.into());
-                        }
-                    }
-                }
+    //
+    // Handle SAFEKEEPER_WAL_HASH <start> <end> <chunk size> command of consistency check
+    // by a peer: SHA-256 of each chunk of the range, clamped to the retained committed WAL.
+    //
+    async fn handle_wal_hash(&mut self, cmd: &Bytes) -> Result<bool> {
+        let args: Vec<&str> = str::from_utf8(&cmd[..])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid UTF-8"))?
+            .split_whitespace()
+            .collect();
+        if args.len() != 4 {
+            io_error!(
+                "SAFEKEEPER_WAL_HASH expects start LSN, end LSN and chunk size: {:?}",
+                cmd
+            );
+        }
+        let (start_lsn, end_lsn) = (parse_lsn(args[1])?, parse_lsn(args[2])?);
+        let chunk_size: u64 = match args[3].parse() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                io_error!("Invalid chunk size {}", args[3]);
             }
-            let send_size = min((end_pos - start_pos) as usize, MAX_SEND_SIZE);
-            let msg_size = LIBPQ_HDR_SIZE + XLOG_HDR_SIZE + send_size;
-            let data_start = LIBPQ_HDR_SIZE + XLOG_HDR_SIZE;
-            let data_end = data_start + send_size;
-            file.read_exact(&mut self.outbuf[data_start..data_end])?;
-            self.outbuf[0] = b'd';
-            BigEndian::write_u32(
-                &mut self.outbuf[1..5],
-                (msg_size - LIBPQ_MSG_SIZE_OFFS) as u32,
+        };
+        let wal_seg_size = self.system().get_wal_seg_size();
+        if wal_seg_size == 0 {
+            io_error!("Can not hash WAL before connecting to wal_proposer");
+        }
+        let system_dir = self.system_dir();
+        let pg_wal_layout = self.conf.pg_wal_layout;
+        let (wal_start, wal_end, timeline) = {
+            let system_dir = system_dir.clone();
+            run_blocking(move || peer_check::retained_wal(&system_dir, wal_seg_size, pg_wal_layout))
+                .await??
+        };
+        let snapshot = self.system().snapshot();
+        let start_lsn = max(start_lsn, wal_start);
+        let end_lsn = min(
+            end_lsn,
+            min(
+                wal_end,
+                min(snapshot.flush_lsn(), snapshot.known_commit_lsn()),
+            ),
+        );
+        if start_lsn < end_lsn && (end_lsn - start_lsn) / chunk_size >= peer_check::MAX_HASH_CHUNKS
+        {
+            io_error!(
+                "Too many chunks of {} bytes in WAL {}-{}",
+                chunk_size,
+                format_lsn(start_lsn),
+                format_lsn(end_lsn)
             );
-            self.outbuf[5] = b'w';
-            BigEndian::write_u64(&mut self.outbuf[6..14], start_pos);
-            BigEndian::write_u64(&mut self.outbuf[14..22], end_pos);
-            BigEndian::write_u64(&mut self.outbuf[22..30], get_current_timestamp());
-
-            self.stream.write_all(&self.outbuf[0..msg_size]).await?;
-            start_pos += send_size as u64;
+        }
 
-            if XLogSegmentOffset(start_pos, wal_seg_size) != 0 {
-                wal_file = Some(file);
-            }
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::RowDescription(&[
+                RowDescriptor {
+                    name: b"start_lsn\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"end_lsn\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+                RowDescriptor {
+                    name: b"sha256\0",
+                    typoid: 25,
+                    typlen: -1,
+                },
+            ]),
+        );
+        let mut chunks = Vec::new();
+        let mut chunk_start = start_lsn;
+        while chunk_start < end_lsn {
+            let chunk_end = min(chunk_start + chunk_size, end_lsn);
+            chunks.push((chunk_start, chunk_end));
+            chunk_start = chunk_end;
         }
-        Ok(false)
+        let hashes = peer_check::hash_wal_chunks(
+            system_dir,
+            self.conf.at_rest_key.clone(),
+            timeline,
+            wal_seg_size,
+            chunks,
+        )
+        .await?;
+        for (chunk_start, chunk_end, hash) in hashes {
+            let (start, end) = (format_lsn(chunk_start), format_lsn(chunk_end));
+            BeMessage::write(
+                &mut self.outbuf,
+                &BeMessage::DataRow(&[
+                    Some(start.as_bytes()),
+                    Some(end.as_bytes()),
+                    Some(hash.as_bytes()),
+                ]),
+            );
+        }
+        BeMessage::write(
+            &mut self.outbuf,
+            &BeMessage::CommandComplete(b"SAFEKEEPER_WAL_HASH"),
+        );
+        BeMessage::write(&mut self.outbuf, &BeMessage::ReadyForQuery);
+        self.send().await?;
+        Ok(true)
     }
 
     async fn process_query(&mut self, q: &FeQueryMessage) -> Result<bool> {
@@ -1053,118 +7192,45 @@ impl Connection {
 
         if q.body.starts_with(b"IDENTIFY_SYSTEM") {
             self.handle_identify_system().await
+        } else if q.body.starts_with(b"SAFEKEEPER_IDENTIFY") {
+            self.handle_safekeeper_identify().await
         } else if q.body.starts_with(b"START_REPLICATION") {
             self.handle_start_replication(&q.body).await
+        } else if q.body.starts_with(b"TIMELINE_HISTORY") {
+            self.handle_timeline_history(&q.body).await
+        } else if q.body.starts_with(b"SHOW") || q.body.starts_with(b"show") {
+            self.handle_show(&q.body).await
+        } else if q.body.starts_with(b"FETCH_WAL") {
+            self.handle_fetch_wal(&q.body).await
+        } else if q.body.starts_with(b"PAGESERVER_CHECKPOINT") {
+            self.require_authentication("PAGESERVER_CHECKPOINT")?;
+            self.handle_pageserver_checkpoint(&q.body).await
+        } else if q.body.starts_with(b"PAUSE_WAL") {
+            self.require_authentication("PAUSE_WAL")?;
+            self.handle_pause_wal(true).await
+        } else if q.body.starts_with(b"RESUME_WAL") {
+            self.require_authentication("RESUME_WAL")?;
+            self.handle_pause_wal(false).await
+        } else if q.body.starts_with(b"SAFEKEEPER_STATUS") {
+            self.require_authentication("SAFEKEEPER_STATUS")?;
+            self.handle_status().await
+        } else if q.body.starts_with(b"SAFEKEEPER_WAL_STATS") {
+            self.require_authentication("SAFEKEEPER_WAL_STATS")?;
+            self.handle_wal_stats().await
+        } else if q.body.starts_with(b"SAFEKEEPER_WAL_HASH") {
+            self.require_authentication("SAFEKEEPER_WAL_HASH")?;
+            self.handle_wal_hash(&q.body).await
         } else {
-            io_error!("Unexpected command {:?}", q.body);
-        }
-    }
-
-    fn write_wal_file(
-        &self,
-        startpos: XLogRecPtr,
-        timeline: TimeLineID,
-        wal_seg_size: usize,
-        buf: &[u8],
-    ) -> Result<()> {
-        let mut bytes_left: usize = buf.len();
-        let mut bytes_written: usize = 0;
-        let mut partial;
-        let mut start_pos = startpos;
-        const ZERO_BLOCK: &'static [u8] = &[0u8; XLOG_BLCKSZ];
-
-        /* Extract WAL location for this block */
-        let mut xlogoff = XLogSegmentOffset(start_pos, wal_seg_size) as usize;
-
-        while bytes_left != 0 {
-            let bytes_to_write;
-
-            /*
-             * If crossing a WAL boundary, only write up until we reach wal
-             * segment size.
-             */
-            if xlogoff + bytes_left > wal_seg_size {
-                bytes_to_write = wal_seg_size - xlogoff;
-            } else {
-                bytes_to_write = bytes_left;
-            }
-
-            /* Open file */
-            let segno = XLByteToSeg(start_pos, wal_seg_size);
-            let wal_file_name = XLogFileName(timeline, segno, wal_seg_size);
-            let wal_file_path = self
-                .conf
-                .data_dir
-                .join(self.system().id.to_string())
-                .join(wal_file_name.clone());
-            let wal_file_partial_path = self
-                .conf
-                .data_dir
-                .join(self.system().id.to_string())
-                .join(wal_file_name.clone() + ".partial");
-
-            {
-                let mut wal_file: File;
-                /* Try to open already completed segment */
-                if let Ok(file) = OpenOptions::new().write(true).open(&wal_file_path) {
-                    wal_file = file;
-                    partial = false;
-                } else if let Ok(file) = OpenOptions::new().write(true).open(&wal_file_partial_path)
-                {
-                    /* Try to open existed partial file */
-                    wal_file = file;
-                    partial = true;
-                } else {
-                    /* Create and fill new partial file */
-                    partial = true;
-                    match OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .open(&wal_file_partial_path)
-                    {
-                        Ok(mut file) => {
-                            for _ in 0..(wal_seg_size / XLOG_BLCKSZ) {
-                                file.write_all(&ZERO_BLOCK)?;
-                            }
-                            wal_file = file;
-                        }
-                        Err(e) => {
-                            error!("Failed to open log file {:?}: {}", &wal_file_path, e);
-                            return Err(e.into());
-                        }
-                    }
-                }
-                wal_file.seek(SeekFrom::Start(xlogoff as u64))?;
-                wal_file.write_all(&buf[bytes_written..(bytes_written + bytes_to_write)])?;
-
-                // Flush file is not prohibited
-                if !self.conf.no_sync {
-                    wal_file.sync_all()?;
-                }
-            }
-            /* Write was successful, advance our position */
-            bytes_written += bytes_to_write;
-            bytes_left -= bytes_to_write;
-            start_pos += bytes_to_write as u64;
-            xlogoff += bytes_to_write;
-
-            /* Did we reach the end of a WAL segment? */
-            if XLogSegmentOffset(start_pos, wal_seg_size) == 0 {
-                xlogoff = 0;
-                if partial {
-                    fs::rename(&wal_file_partial_path, &wal_file_path)?;
-                }
-            }
+            let msg = format!("Unexpected command {:?}", q.body);
+            error!("{}", msg);
+            Err(sql_error(SQLSTATE_SYNTAX_ERROR, msg))
         }
-        Ok(())
     }
 
     // Find last WAL record. If "precise" is false then just locatelast partial segment
-    fn find_end_of_wal(&self, precise: bool) -> (XLogRecPtr, TimeLineID) {
-        find_end_of_wal(
-            &self.conf.data_dir,
-            self.system().get_info().server.wal_seg_size as usize,
-            precise,
-        )
+    fn find_end_of_wal(&self, precise: bool) -> Result<(XLogRecPtr, TimeLineID)> {
+        let system = self.system();
+        let wal_seg_size = system.get_info().server.wal_seg_size as usize;
+        system.with_wal_storage(&self.conf, wal_seg_size, |storage| storage.end_of_wal(precise))
     }
 }
@@ -0,0 +1,758 @@
+//
+//   Protocol conformance test vectors.
+//
+//   Canonical encodings of proposer-safekeeper messages and of libpq messages sent by
+//   the safekeeper, plus scripted proposer sessions with the expected replies. They
+//   define the protocol for all of its implementations: besides this safekeeper, the
+//   C wal_proposer and alternative proposers are expected to produce the same bytes,
+//   vectors() lists them in the form easy to load in their tests.
+//
+//   Numbers are little endian, except for the greeting magic, the term of NodeId (big
+//   endian, so that node ids can be compared with memcmp) and everything of libpq and
+//   of the replication protocol. Messages are encoded by safekeeper_protocol::Codec as
+//   negotiated by the default greeting, i.e. without frame length prefixes, in the
+//   current protocol version. SafeKeeperResponseV1 is the response of version 1.
+//
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Bytes, BytesMut};
+use log::*;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::task;
+use tokio::time::timeout;
+
+use super::crash_test::{append_msg, recv_msg, send_msg, test_conf};
+use super::{serve_connection, ReplicaMessage, TenantRegistry, SK_FORMAT_VERSION, SK_MAGIC};
+use crate::pq_protocol::{BeMessage, Result, RowDescriptor, SystemId, NEGOTIATE_SSL_CODE};
+use crate::safekeeper_protocol::*;
+use crate::xlog_utils::*;
+
+/* Proposer -> safekeeper */
+pub const GREETING_MAGIC: &str = "5afec0de";
+pub const PEER_GREETING: &str = "0200000001000000";
+pub const ACCEPTOR_SET_CLAIM: &str = "000000000300000002000000";
+pub const SERVER_INFO: &str = "\
+    02000000d2fb0100000000000000000000000000000000000000000000000000\
+    887766554433221148376b01000000000100000000000001";
+pub const REQUEST_VOTE: &str = "\
+    0f0e0d0c0b0a09080706050403020100000000000000000248376b0100000000\
+    0200000000000000";
+pub const SAFEKEEPER_REQUEST: &str = "\
+    0f0e0d0c0b0a09080706050403020100000000000000000248376b0100000000\
+    00386b0100000000000000010000000000376b0100000000";
+
+/* Safekeeper -> proposer */
+pub const PROTOCOL_VERSIONS: &str = "010000000200000002000000";
+pub const NODE_ID: &str = "0f0e0d0c0b0a090807060504030201000000000000000002";
+pub const SAFEKEEPER_INFO: &str = "\
+    efcefeca01000000010000000000000002000000d2fb01000f0e0d0c0b0a0908\
+    07060504030201000000000000000002887766554433221148376b0100000000\
+    010000000000000100376b010000000048376b01000000000000000100000000";
+pub const SAFEKEEPER_RESPONSE: &str = "\
+    0000000001000000020000000000000000386b010000000000386b0100000000\
+    00c05773a57c020000020000010000000001000001000000";
+pub const SAFEKEEPER_RESPONSE_V1: &str = "\
+    020000000000000000386b010000000000c05773a57c02000002000001000000\
+    0001000001000000";
+
+/* Replica -> safekeeper, CopyData payloads */
+pub const ZENITH_HS_FEEDBACK: &str = "00027ca57357c00000000001000002000000000100000100";
+pub const STANDBY_HS_FEEDBACK: &str = "6800027ca57357c00000000200000000010000010000000001";
+pub const STANDBY_STATUS_UPDATE: &str = "\
+    7200000000016b380000000000016b380000000000016b370000027ca57357c0\
+    0001";
+
+/* Safekeeper -> libpq client */
+pub const AUTHENTICATION_OK: &str = "520000000800000000";
+pub const READY_FOR_QUERY: &str = "5a0000000549";
+pub const NEGOTIATE: &str = "4e";
+pub const COPY_BOTH_RESPONSE: &str = "5700000007000000";
+pub const COPY_DONE: &str = "6300000004";
+pub const ROW_DESCRIPTION: &str = "\
+    540000003c000273797374656d69640000000000000000000019ffffffffffff\
+    000074696d656c696e6500000000000000000000170004ffffffff0000";
+pub const DATA_ROW: &str = "4400000011000200000003313233ffffffff";
+pub const COMMAND_COMPLETE: &str = "43000000144944454e544946595f53595354454d00";
+
+/* Values encoded by the vectors */
+pub const SYSTEM_ID: SystemId = 0x1122334455667788;
+const PG_VERSION: u32 = 130002;
+const WAL_SEG_SIZE: u32 = 16 * 1024 * 1024;
+const HS_TS: TimestampTz = 700_000_000_000_000;
+
+// Names and hex encodings of all vectors
+pub fn vectors() -> Vec<(&'static str, &'static str)> {
+    vec![
+        ("GreetingMagic", GREETING_MAGIC),
+        ("PeerGreeting", PEER_GREETING),
+        ("AcceptorSetClaim", ACCEPTOR_SET_CLAIM),
+        ("ServerInfo", SERVER_INFO),
+        ("RequestVote", REQUEST_VOTE),
+        ("SafeKeeperRequest", SAFEKEEPER_REQUEST),
+        ("ProtocolVersions", PROTOCOL_VERSIONS),
+        ("NodeId", NODE_ID),
+        ("SafeKeeperInfo", SAFEKEEPER_INFO),
+        ("SafeKeeperResponse", SAFEKEEPER_RESPONSE),
+        ("SafeKeeperResponseV1", SAFEKEEPER_RESPONSE_V1),
+        ("ZenithHotStandbyFeedback", ZENITH_HS_FEEDBACK),
+        ("StandbyHotStandbyFeedback", STANDBY_HS_FEEDBACK),
+        ("StandbyStatusUpdate", STANDBY_STATUS_UPDATE),
+        ("AuthenticationOk", AUTHENTICATION_OK),
+        ("ReadyForQuery", READY_FOR_QUERY),
+        ("Negotiate", NEGOTIATE),
+        ("CopyBothResponse", COPY_BOTH_RESPONSE),
+        ("CopyDone", COPY_DONE),
+        ("RowDescription", ROW_DESCRIPTION),
+        ("DataRow", DATA_ROW),
+        ("CommandComplete", COMMAND_COMPLETE),
+    ]
+}
+
+pub fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn node_id(term: u64) -> NodeId {
+    NodeId {
+        term: term,
+        uuid: 0x000102030405060708090a0b0c0d0e0f,
+    }
+}
+
+fn server_info(node_id: NodeId) -> ServerInfo {
+    ServerInfo {
+        protocol_version: SK_PROTOCOL_VERSION,
+        pg_version: PG_VERSION,
+        node_id: node_id,
+        system_id: SYSTEM_ID,
+        wal_end: 0x16B3748,
+        timeline: 1,
+        wal_seg_size: WAL_SEG_SIZE,
+    }
+}
+
+//
+// Message must be encoded as the vector, and the vector decoded into the same message.
+// Vector of append is its header, WAL of the append follows it.
+//
+fn check_proposer(name: &str, msg: &ProposerMessage, hex: &str) -> Result<()> {
+    let codec = Codec::default();
+    let mut expected = decode_hex(hex);
+    if let ProposerMessage::Append(append) = msg {
+        expected.extend_from_slice(&append.wal);
+    }
+    let mut buf = BytesMut::new();
+    codec.encode_proposer(msg, &mut buf);
+    if buf[..] != expected[..] {
+        io_error!(
+            "{} is encoded as {}, expected {}",
+            name,
+            encode_hex(&buf),
+            hex
+        );
+    }
+    let mut repacked = BytesMut::new();
+    let decoded = codec.decode_proposer(msg.kind(), &mut BytesMut::from(&expected[..]))?;
+    if let Some(decoded) = decoded {
+        codec.encode_proposer(&decoded, &mut repacked);
+    }
+    if repacked[..] != expected[..] {
+        io_error!(
+            "{} is decoded into message encoded as {}",
+            name,
+            encode_hex(&repacked)
+        );
+    }
+    Ok(())
+}
+
+fn check_acceptor(name: &str, msg: &AcceptorMessage, version: u32, hex: &str) -> Result<()> {
+    let codec = Codec {
+        version: version,
+        ..Codec::default()
+    };
+    let expected = decode_hex(hex);
+    let mut buf = BytesMut::new();
+    codec.encode_acceptor(msg, &mut buf);
+    if buf[..] != expected[..] {
+        io_error!(
+            "{} is encoded as {}, expected {}",
+            name,
+            encode_hex(&buf),
+            hex
+        );
+    }
+    let mut repacked = BytesMut::new();
+    let decoded = codec.decode_acceptor(msg.kind(), &mut BytesMut::from(&expected[..]))?;
+    if let Some(decoded) = decoded {
+        codec.encode_acceptor(&decoded, &mut repacked);
+    }
+    if repacked[..] != expected[..] {
+        io_error!(
+            "{} is decoded into message encoded as {}",
+            name,
+            encode_hex(&repacked)
+        );
+    }
+    Ok(())
+}
+
+fn check_written(name: &str, msg: &BeMessage, hex: &str) -> Result<()> {
+    let mut buf = BytesMut::new();
+    BeMessage::write(&mut buf, msg);
+    if encode_hex(&buf) != hex {
+        io_error!(
+            "{} is encoded as {}, expected {}",
+            name,
+            encode_hex(&buf),
+            hex
+        );
+    }
+    Ok(())
+}
+
+fn check_feedback(name: &str, hex: &str) -> Result<()> {
+    match ReplicaMessage::parse(&Bytes::from(decode_hex(hex))) {
+        ReplicaMessage::HotStandbyFeedback(feedback)
+            if feedback.ts == HS_TS
+                && feedback.xmin == 0x100000200
+                && feedback.catalog_xmin == 0x100000100 => {}
+        other => {
+            io_error!("{} is parsed as {:?}", name, other);
+        }
+    }
+    Ok(())
+}
+
+//
+// Check encoding and decoding of all messages against the vectors
+//
+pub fn check_encodings() -> Result<()> {
+    if encode_hex(&SK_GREETING_MAGIC.to_be_bytes()) != GREETING_MAGIC {
+        io_error!("Greeting magic is {:x}", SK_GREETING_MAGIC);
+    }
+    let greeting = PeerGreeting {
+        protocol_version: SK_PROTOCOL_VERSION,
+        role: PeerRole::Proposer as u32,
+    };
+    check_proposer(
+        "PeerGreeting",
+        &ProposerMessage::Greeting(greeting),
+        PEER_GREETING,
+    )?;
+    let claim = AcceptorSetClaim {
+        acceptor_index: 0,
+        n_acceptors: 3,
+        quorum: 2,
+    };
+    let claim = ProposerMessage::AcceptorSetClaim(claim);
+    check_proposer("AcceptorSetClaim", &claim, ACCEPTOR_SET_CLAIM)?;
+    let info_msg = ProposerMessage::ServerInfo(server_info(node_id(0)));
+    check_proposer("ServerInfo", &info_msg, SERVER_INFO)?;
+    let vote = RequestVote {
+        node_id: node_id(2),
+        vcl: 0x16B3748,
+        epoch: 2,
+    };
+    check_proposer(
+        "RequestVote",
+        &ProposerMessage::RequestVote(vote),
+        REQUEST_VOTE,
+    )?;
+    let req = SafeKeeperRequest {
+        sender_id: node_id(2),
+        begin_lsn: 0x16B3748,
+        end_lsn: 0x16B3800,
+        restart_lsn: 0x1000000,
+        commit_lsn: 0x16B3700,
+    };
+    let wal = vec![0u8; req.wal_size()?];
+    check_proposer(
+        "SafeKeeperRequest",
+        &append_msg(req, &wal),
+        SAFEKEEPER_REQUEST,
+    )?;
+
+    let versions = AcceptorMessage::Versions(ProtocolVersions {
+        min_version: 1,
+        max_version: 2,
+        selected_version: 2,
+    });
+    let version = SK_PROTOCOL_VERSION;
+    check_acceptor("ProtocolVersions", &versions, version, PROTOCOL_VERSIONS)?;
+    check_acceptor(
+        "NodeId",
+        &AcceptorMessage::Vote(node_id(2)),
+        version,
+        NODE_ID,
+    )?;
+    let info = SafeKeeperInfo {
+        magic: SK_MAGIC,
+        format_version: SK_FORMAT_VERSION,
+        epoch: 1,
+        server: server_info(node_id(2)),
+        commit_lsn: 0x16B3700,
+        flush_lsn: 0x16B3748,
+        restart_lsn: 0x1000000,
+    };
+    check_acceptor(
+        "SafeKeeperInfo",
+        &AcceptorMessage::Info(info),
+        version,
+        SAFEKEEPER_INFO,
+    )?;
+    let resp = SafeKeeperResponse {
+        status: SK_STATUS_OK,
+        hs_replicas: 1,
+        epoch: 2,
+        flush_lsn: 0x16B3800,
+        received_lsn: 0x16B3800,
+        hs_feedback: HotStandbyFeedback {
+            ts: HS_TS,
+            xmin: 0x100000200,
+            catalog_xmin: 0x100000100,
+        },
+    };
+    let resp = AcceptorMessage::Response(resp);
+    check_acceptor("SafeKeeperResponse", &resp, version, SAFEKEEPER_RESPONSE)?;
+    check_acceptor("SafeKeeperResponseV1", &resp, 1, SAFEKEEPER_RESPONSE_V1)?;
+
+    check_feedback("ZenithHotStandbyFeedback", ZENITH_HS_FEEDBACK)?;
+    check_feedback("StandbyHotStandbyFeedback", STANDBY_HS_FEEDBACK)?;
+    match ReplicaMessage::parse(&Bytes::from(decode_hex(STANDBY_STATUS_UPDATE))) {
+        ReplicaMessage::StatusUpdate {
+            apply_lsn: 0x16B3700,
+            reply_requested: true,
+        } => {}
+        other => {
+            io_error!("StandbyStatusUpdate is parsed as {:?}", other);
+        }
+    }
+
+    check_written(
+        "AuthenticationOk",
+        &BeMessage::AuthenticationOk,
+        AUTHENTICATION_OK,
+    )?;
+    check_written("ReadyForQuery", &BeMessage::ReadyForQuery, READY_FOR_QUERY)?;
+    check_written("Negotiate", &BeMessage::Negotiate, NEGOTIATE)?;
+    check_written("CopyBothResponse", &BeMessage::Copy, COPY_BOTH_RESPONSE)?;
+    check_written("CopyDone", &BeMessage::CopyDone, COPY_DONE)?;
+    let fields = [
+        RowDescriptor {
+            name: b"systemid\0",
+            typoid: 25,
+            typlen: -1,
+        },
+        RowDescriptor {
+            name: b"timeline\0",
+            typoid: 23,
+            typlen: 4,
+        },
+    ];
+    check_written(
+        "RowDescription",
+        &BeMessage::RowDescription(&fields),
+        ROW_DESCRIPTION,
+    )?;
+    let values: [Option<&[u8]>; 2] = [Some(b"123"), None];
+    check_written("DataRow", &BeMessage::DataRow(&values), DATA_ROW)?;
+    check_written(
+        "CommandComplete",
+        &BeMessage::CommandComplete(b"IDENTIFY_SYSTEM"),
+        COMMAND_COMPLETE,
+    )
+}
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn handshake(stream: &mut TcpStream, role: u32) -> Result<()> {
+    handshake_version(stream, SK_PROTOCOL_VERSION, role).await
+}
+
+async fn handshake_version(stream: &mut TcpStream, version: u32, role: u32) -> Result<()> {
+    stream.write_all(&SK_GREETING_MAGIC.to_be_bytes()).await?;
+    let greeting = PeerGreeting {
+        protocol_version: version,
+        role: role,
+    };
+    send_msg(stream, &ProposerMessage::Greeting(greeting)).await?;
+    let server_info = ServerInfo {
+        protocol_version: version,
+        ..server_info(node_id(0))
+    };
+    send_msg(stream, &ProposerMessage::ServerInfo(server_info)).await
+}
+
+async fn recv_reply(stream: &mut TcpStream, kind: AcceptorMessageKind) -> Result<AcceptorMessage> {
+    match timeout(REPLY_TIMEOUT, recv_msg(stream, kind)).await {
+        Ok(reply) => reply,
+        Err(_) => {
+            io_error!("No reply in {:?}", REPLY_TIMEOUT);
+        }
+    }
+}
+
+/* Safekeeper must close the connection after protocol error */
+async fn expect_closed(stream: &mut TcpStream, step: &str) -> Result<()> {
+    let mut buf = [0u8; 1];
+    match timeout(REPLY_TIMEOUT, stream.read(&mut buf)).await {
+        Ok(Ok(0)) | Ok(Err(_)) => Ok(()),
+        Ok(Ok(_)) => {
+            io_error!(
+                "{}: unexpected reply instead of closing the connection",
+                step
+            );
+        }
+        Err(_) => {
+            io_error!("{}: connection is not closed in {:?}", step, REPLY_TIMEOUT);
+        }
+    }
+}
+
+//
+// Play scripted proposer sessions against the safekeeper at addr, which must not know
+// the tenant of the vectors yet, and check its replies at every step:
+//
+//   1. handshake of a new tenant reports empty state: term 0, epoch 0, no WAL;
+//   2. vote for a higher term is granted by echoing the candidate's NodeId;
+//   3. heartbeat (append of no WAL) is answered with OK, current epoch and flush position
+//      and no hot standby feedback;
+//   4. zero begin_lsn ends the stream;
+//   5. after reconnect, the vote and commit position from the heartbeat are reported;
+//   6. vote for a lower term is rejected by returning the voted NodeId, and the
+//      connection is closed;
+//   7. greeting with unknown role closes the connection without reply;
+//   8. proposer streaming in term 3 is fenced with STALE_TERM and disconnected as soon
+//      as a proposer of term 4 is elected, without waiting for its next message;
+//   9. of two connections of the same term the one which voted last wins: the first
+//      one is fenced, and its append sent after the vote is not written; as it hasn't
+//      announced PEER_CAP_STATUS_CODES, it gets no STALE_TERM, only the connection closed;
+//  10. SSLRequest is declined with 'N' (the safekeeper must have no TLS configured)
+//      and the proposer session goes on in plain;
+//  11. proposer negotiating the version from a newer one is answered with the supported
+//      range and the highest supported version, which it uses in ServerInfo;
+//  12. proposer negotiating the version from an unsupported older one is answered with
+//      no common version, and the connection is closed;
+//  13. proposer of version 1 gets its heartbeat acknowledged by SafeKeeperResponse of
+//      version 1, with no status, hs_replicas and received_lsn.
+//
+pub async fn check_sessions(addr: SocketAddr) -> Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    handshake(&mut stream, PeerRole::Proposer as u32).await?;
+    let info = recv_reply(&mut stream, AcceptorMessageKind::Info)
+        .await?
+        .into_info()?;
+    if info.server.node_id.term != 0 || info.epoch != 0 || info.flush_lsn != 0 {
+        io_error!(
+            "Step 1: new tenant reports term {}, epoch {}, flush_lsn {}",
+            info.server.node_id.term,
+            info.epoch,
+            format_lsn(info.flush_lsn)
+        );
+    }
+
+    let vote = RequestVote {
+        node_id: node_id(2),
+        vcl: 0,
+        epoch: 2,
+    };
+    send_msg(&mut stream, &ProposerMessage::RequestVote(vote)).await?;
+    let voted = recv_reply(&mut stream, AcceptorMessageKind::Vote)
+        .await?
+        .into_vote()?;
+    if voted != node_id(2) {
+        io_error!(
+            "Step 2: vote for term 2 is answered with term {}",
+            voted.term
+        );
+    }
+
+    let heartbeat = SafeKeeperRequest {
+        sender_id: node_id(2),
+        begin_lsn: 0x1000000,
+        end_lsn: 0x1000000,
+        restart_lsn: 0,
+        commit_lsn: 0x16B3700,
+    };
+    send_msg(&mut stream, &append_msg(heartbeat, &[])).await?;
+    let resp = recv_reply(&mut stream, AcceptorMessageKind::Response)
+        .await?
+        .into_response()?;
+    if resp.status != SK_STATUS_OK
+        || resp.epoch != 0
+        || resp.flush_lsn != 0
+        || resp.hs_replicas != 0
+        || resp.hs_feedback.xmin != u64::MAX
+    {
+        io_error!("Step 3: heartbeat is answered with {:?}", resp);
+    }
+
+    let end = SafeKeeperRequest {
+        sender_id: node_id(2),
+        begin_lsn: END_OF_STREAM,
+        end_lsn: END_OF_STREAM,
+        restart_lsn: 0,
+        commit_lsn: 0x16B3700,
+    };
+    send_msg(&mut stream, &append_msg(end, &[])).await?;
+    expect_closed(&mut stream, "Step 4").await?;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    handshake(&mut stream, PeerRole::Proposer as u32).await?;
+    let info = recv_reply(&mut stream, AcceptorMessageKind::Info)
+        .await?
+        .into_info()?;
+    if info.server.node_id != node_id(2) || info.commit_lsn != 0x16B3700 {
+        io_error!(
+            "Step 5: tenant reports term {} and commit_lsn {} after reconnect",
+            info.server.node_id.term,
+            format_lsn(info.commit_lsn)
+        );
+    }
+
+    let vote = RequestVote {
+        node_id: node_id(1),
+        vcl: 0,
+        epoch: 1,
+    };
+    send_msg(&mut stream, &ProposerMessage::RequestVote(vote)).await?;
+    let voted = recv_reply(&mut stream, AcceptorMessageKind::Vote)
+        .await?
+        .into_vote()?;
+    if voted != node_id(2) {
+        io_error!(
+            "Step 6: vote for stale term 1 is answered with term {}",
+            voted.term
+        );
+    }
+    expect_closed(&mut stream, "Step 6").await?;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    handshake(&mut stream, 7).await?;
+    expect_closed(&mut stream, "Step 7").await?;
+
+    let mut old = elect(addr, 3, PEER_CAP_STATUS_CODES, "Step 8").await?;
+    send_heartbeat(&mut old, 3, SK_STATUS_OK, "Step 8").await?;
+    let mut new = elect(addr, 4, 0, "Step 8").await?;
+    let resp = recv_reply(&mut old, AcceptorMessageKind::Response)
+        .await?
+        .into_response()?;
+    if resp.status != SK_STATUS_STALE_TERM {
+        io_error!("Step 8: superseded proposer is sent {:?}", resp);
+    }
+    expect_closed(&mut old, "Step 8").await?;
+
+    let mut reconnected = elect(addr, 4, PEER_CAP_STATUS_CODES, "Step 9").await?;
+    let append = SafeKeeperRequest {
+        sender_id: node_id(4),
+        begin_lsn: 0x1000000,
+        end_lsn: 0x1000000 + XLOG_BLCKSZ as u64,
+        restart_lsn: 0,
+        commit_lsn: 0x16B3700,
+    };
+    /* Connection may be already closed by the safekeeper, it doesn't matter */
+    let _ = send_msg(&mut new, &append_msg(append, &[0u8; XLOG_BLCKSZ])).await;
+    expect_closed(&mut new, "Step 9").await?;
+    let resp = send_heartbeat(&mut reconnected, 4, SK_STATUS_OK, "Step 9").await?;
+    if resp.flush_lsn != 0 {
+        io_error!(
+            "Step 9: WAL of superseded connection is written up to {}",
+            format_lsn(resp.flush_lsn)
+        );
+    }
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut ssl_request = [0u8; 8];
+    BigEndian::write_u32(&mut ssl_request[0..4], 8);
+    BigEndian::write_u32(&mut ssl_request[4..8], NEGOTIATE_SSL_CODE);
+    stream.write_all(&ssl_request).await?;
+    let mut answer = [0u8; 1];
+    match timeout(REPLY_TIMEOUT, stream.read_exact(&mut answer)).await {
+        Ok(Ok(_)) if encode_hex(&answer) == NEGOTIATE => {}
+        other => {
+            io_error!(
+                "Step 10: SSLRequest is answered with {:?} ({:?})",
+                answer,
+                other
+            );
+        }
+    }
+    handshake(&mut stream, PeerRole::Proposer as u32).await?;
+    let info = recv_reply(&mut stream, AcceptorMessageKind::Info)
+        .await?
+        .into_info()?;
+    if info.server.node_id != node_id(4) {
+        io_error!(
+            "Step 10: tenant reports term {} after SSLRequest",
+            info.server.node_id.term
+        );
+    }
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let versions = negotiate(&mut stream, SK_PROTOCOL_VERSION + 1).await?;
+    if versions != ProtocolVersions::negotiate(SK_PROTOCOL_VERSION) {
+        io_error!("Step 11: newer proposer is answered with {:?}", versions);
+    }
+    send_msg(
+        &mut stream,
+        &ProposerMessage::ServerInfo(server_info(node_id(0))),
+    )
+    .await?;
+    recv_reply(&mut stream, AcceptorMessageKind::Info)
+        .await?
+        .into_info()?;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let versions = negotiate(&mut stream, SK_MIN_PROTOCOL_VERSION - 1).await?;
+    if versions.selected_version != 0 {
+        io_error!("Step 12: outdated proposer is answered with {:?}", versions);
+    }
+    expect_closed(&mut stream, "Step 12").await?;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    handshake_version(&mut stream, 1, PeerRole::Proposer as u32).await?;
+    recv_reply(&mut stream, AcceptorMessageKind::Info)
+        .await?
+        .into_info()?;
+    let vote = RequestVote {
+        node_id: node_id(5),
+        vcl: 0,
+        epoch: 5,
+    };
+    send_msg(&mut stream, &ProposerMessage::RequestVote(vote)).await?;
+    recv_reply(&mut stream, AcceptorMessageKind::Vote)
+        .await?
+        .into_vote()?;
+    let heartbeat = SafeKeeperRequest {
+        sender_id: node_id(5),
+        begin_lsn: 0x1000000,
+        end_lsn: 0x1000000,
+        restart_lsn: 0,
+        commit_lsn: 0x16B3700,
+    };
+    send_msg(&mut stream, &append_msg(heartbeat, &[])).await?;
+    let mut resp = [0u8; SafeKeeperResponse::V1_SIZE];
+    match timeout(REPLY_TIMEOUT, stream.read_exact(&mut resp)).await {
+        /* Epoch and flush position of the empty tenant, no hot standby feedback */
+        Ok(Ok(_)) if resp[..16] == [0u8; 16] && resp[24..32] == [0xFF; 8] => {}
+        other => {
+            io_error!(
+                "Step 13: heartbeat of version 1 is answered with {:?} ({:?})",
+                resp,
+                other
+            );
+        }
+    }
+    let end = SafeKeeperRequest {
+        begin_lsn: END_OF_STREAM,
+        ..heartbeat
+    };
+    send_msg(&mut stream, &append_msg(end, &[])).await?;
+    expect_closed(&mut stream, "Step 13").await
+}
+
+/* Greet as proposer negotiating the version, starting from the given one */
+async fn negotiate(stream: &mut TcpStream, version: u32) -> Result<ProtocolVersions> {
+    stream.write_all(&SK_GREETING_MAGIC.to_be_bytes()).await?;
+    let greeting = PeerGreeting {
+        protocol_version: version,
+        role: PeerRole::Proposer as u32 | PEER_CAP_VERSION_NEGOTIATION,
+    };
+    send_msg(stream, &ProposerMessage::Greeting(greeting)).await?;
+    recv_reply(stream, AcceptorMessageKind::Versions)
+        .await?
+        .into_versions()
+}
+
+/* Connect as proposer of the term announcing the capabilities and get elected */
+async fn elect(addr: SocketAddr, term: u64, caps: u32, step: &str) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr).await?;
+    handshake(&mut stream, PeerRole::Proposer as u32 | caps).await?;
+    recv_reply(&mut stream, AcceptorMessageKind::Info)
+        .await?
+        .into_info()?;
+    let vote = RequestVote {
+        node_id: node_id(term),
+        vcl: 0,
+        epoch: term,
+    };
+    send_msg(&mut stream, &ProposerMessage::RequestVote(vote)).await?;
+    let voted = recv_reply(&mut stream, AcceptorMessageKind::Vote)
+        .await?
+        .into_vote()?;
+    if voted != node_id(term) {
+        io_error!(
+            "{}: vote for term {} is answered with term {}",
+            step,
+            term,
+            voted.term
+        );
+    }
+    Ok(stream)
+}
+
+async fn send_heartbeat(
+    stream: &mut TcpStream,
+    term: u64,
+    status: u32,
+    step: &str,
+) -> Result<SafeKeeperResponse> {
+    let heartbeat = SafeKeeperRequest {
+        sender_id: node_id(term),
+        begin_lsn: 0x1000000,
+        end_lsn: 0x1000000,
+        restart_lsn: 0,
+        commit_lsn: 0x16B3700,
+    };
+    send_msg(stream, &append_msg(heartbeat, &[])).await?;
+    let resp = recv_reply(stream, AcceptorMessageKind::Response)
+        .await?
+        .into_response()?;
+    if resp.status != status {
+        io_error!(
+            "{}: heartbeat in term {} is answered with {:?}",
+            step,
+            term,
+            resp
+        );
+    }
+    Ok(resp)
+}
+
+//
+// Check the vectors and the sessions against this safekeeper running on temporary storage
+//
+pub fn run() -> Result<()> {
+    check_encodings()?;
+    let data_dir = tempfile::tempdir()?;
+    let conf = test_conf(data_dir.path());
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async move {
+        let listener = TcpListener::bind(conf.listen_addr).await?;
+        let addr = listener.local_addr()?;
+        let tenants = TenantRegistry::new();
+        task::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let conf = conf.clone();
+                let tenants = tenants.clone();
+                task::spawn(async move { serve_connection(socket, &conf, tenants).await });
+            }
+        });
+        check_sessions(addr).await
+    })
+}
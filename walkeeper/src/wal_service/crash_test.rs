@@ -0,0 +1,533 @@
+//
+//   Crash-recovery test of WAL receiver.
+//
+//   Proposer streams generated WAL to the safekeeper running on top of the
+//   fault-injecting storage layer (see fault_fs), which crashes it at a random
+//   write or fsync. After each crash the safekeeper is restarted and has to report
+//   vote, epoch and WAL so that nothing acknowledged is lost and nothing that
+//   was not sent is served. In group commit mode the proposer pipelines appends
+//   of the round and the safekeeper syncs and acknowledges them in batches.
+//
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{BufMut, Bytes, BytesMut};
+use crc32c::{crc32c, crc32c_append};
+use log::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{max, min};
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::task::{self, JoinHandle};
+
+use super::{serve_connection, TenantRegistry};
+use crate::access_list::AccessList;
+use crate::fault_fs;
+use crate::partial_segment::SegmentPath;
+use crate::pq_protocol::{Result, SystemId};
+use crate::safekeeper_protocol::*;
+use crate::xlog_utils::*;
+use crate::{tenant_dir, CallbackConf, SyncMethod, WalAcceptorConf};
+
+pub(super) const WAL_SEG_SIZE: usize = 1024 * 1024; /* minimal segment size, to cross segment boundaries often */
+const WAL_SEGMENTS: u64 = 4; /* amount of generated WAL */
+pub(super) const PG_VERSION: u32 = 130000;
+pub(super) const TIMELINE: TimeLineID = 1;
+const PROPOSER_UUID: u128 = 0xC0FFEE;
+const MAX_APPEND_SIZE: u64 = 64 * 1024;
+const MAX_APPENDS_PER_ROUND: u64 = 32;
+const GROUP_COMMIT_BYTES: usize = 256 * 1024;
+const GROUP_COMMIT_INFLIGHT_MSGS: usize = 8;
+
+/*
+ * WAL stream consisting of valid records, so that safekeeper can locate end of WAL in it.
+ * Records never cross page boundaries: the last record on a page fills it up.
+ */
+pub(super) struct GeneratedWal {
+    pub(super) start_lsn: XLogRecPtr,
+    data: Vec<u8>,
+    record_ends: Vec<XLogRecPtr>, /* positions where safekeeper may find end of WAL */
+}
+
+impl GeneratedWal {
+    pub(super) fn generate(rng: &mut StdRng, system_id: SystemId) -> GeneratedWal {
+        /* Segment 0 is never considered by find_end_of_wal, like in Postgres WAL starts in segment 1 */
+        let start_lsn = WAL_SEG_SIZE as u64;
+        let end_lsn = start_lsn + WAL_SEGMENTS * WAL_SEG_SIZE as u64;
+        let mut wal = GeneratedWal {
+            start_lsn: start_lsn,
+            data: Vec::new(),
+            record_ends: vec![start_lsn],
+        };
+        let mut lsn = start_lsn;
+        let mut prev_lsn = 0;
+        while lsn < end_lsn {
+            let page_offs = (lsn % XLOG_BLCKSZ as u64) as usize;
+            if page_offs == 0 {
+                let hdr = page_header(lsn, system_id);
+                lsn += hdr.len() as u64;
+                wal.data.extend_from_slice(&hdr);
+                continue;
+            }
+            let page_left = XLOG_BLCKSZ - page_offs;
+            let mut tot_len = 8 * rng.gen_range(4..=256);
+            if tot_len + 32 > page_left {
+                tot_len = page_left;
+            }
+            wal.data.extend_from_slice(&record(rng, prev_lsn, tot_len));
+            prev_lsn = lsn;
+            lsn += tot_len as u64;
+            wal.record_ends.push(lsn);
+        }
+        wal
+    }
+
+    pub(super) fn end_lsn(&self) -> XLogRecPtr {
+        self.start_lsn + self.data.len() as u64
+    }
+
+    pub(super) fn slice(&self, from: XLogRecPtr, to: XLogRecPtr) -> &[u8] {
+        &self.data[(from - self.start_lsn) as usize..(to - self.start_lsn) as usize]
+    }
+
+    // End of the last record at or before lsn, 0 if there is none
+    pub(super) fn record_boundary(&self, lsn: XLogRecPtr) -> XLogRecPtr {
+        self.record_ends
+            .iter()
+            .rev()
+            .find(|end| **end <= lsn)
+            .cloned()
+            .unwrap_or(0)
+    }
+}
+
+fn page_header(lsn: XLogRecPtr, system_id: SystemId) -> BytesMut {
+    let mut hdr = BytesMut::new();
+    let long = XLogSegmentOffset(lsn, WAL_SEG_SIZE) == 0;
+    hdr.put_u16_le(XLOG_PAGE_MAGIC);
+    hdr.put_u16_le(if long { XLP_LONG_HEADER } else { 0 }); /* xlp_info */
+    hdr.put_u32_le(TIMELINE);
+    hdr.put_u64_le(lsn); /* xlp_pageaddr */
+    hdr.put_u32_le(0); /* xlp_rem_len */
+    hdr.put_u32_le(0); /* padding */
+    if long {
+        hdr.put_u64_le(system_id);
+        hdr.put_u32_le(WAL_SEG_SIZE as u32);
+        hdr.put_u32_le(XLOG_BLCKSZ as u32);
+    }
+    hdr
+}
+
+fn record(rng: &mut StdRng, prev_lsn: XLogRecPtr, tot_len: usize) -> Vec<u8> {
+    let mut rec = vec![0u8; tot_len];
+    LittleEndian::write_u32(&mut rec[0..4], tot_len as u32);
+    LittleEndian::write_u32(&mut rec[4..8], rng.gen()); /* xl_xid */
+    LittleEndian::write_u64(&mut rec[8..16], prev_lsn);
+    rec[XLOG_RECORD_RMID_OFFS] = rng.gen_range(0..RM_NAMES.len()) as u8;
+    rng.fill(&mut rec[XLOG_SIZE_OF_XLOG_RECORD..]);
+    let crc = crc32c_append(
+        crc32c(&rec[XLOG_SIZE_OF_XLOG_RECORD..]),
+        &rec[0..XLOG_RECORD_CRC_OFFS],
+    );
+    LittleEndian::write_u32(
+        &mut rec[XLOG_RECORD_CRC_OFFS..XLOG_SIZE_OF_XLOG_RECORD],
+        crc,
+    );
+    rec
+}
+
+/* Test proposers don't negotiate framing, like the C proposer */
+pub(super) async fn send_msg(stream: &mut TcpStream, msg: &ProposerMessage) -> Result<()> {
+    let mut buf = BytesMut::new();
+    Codec::default().encode_proposer(msg, &mut buf);
+    stream.write_all(&buf).await
+}
+
+/* Receive message of the current protocol version */
+pub(super) async fn recv_msg(
+    stream: &mut TcpStream,
+    kind: AcceptorMessageKind,
+) -> Result<AcceptorMessage> {
+    recv_versioned_msg(stream, kind, SK_PROTOCOL_VERSION).await
+}
+
+pub(super) async fn recv_versioned_msg(
+    stream: &mut TcpStream,
+    kind: AcceptorMessageKind,
+    version: u32,
+) -> Result<AcceptorMessage> {
+    let codec = Codec {
+        version: version,
+        ..Codec::default()
+    };
+    let mut buf = BytesMut::new();
+    buf.resize(kind.size(version), 0u8);
+    stream.read_exact(&mut buf[..]).await?;
+    match codec.decode_acceptor(kind, &mut buf)? {
+        Some(msg) => Ok(msg),
+        None => unreachable!(),
+    }
+}
+
+pub(super) fn append_msg(header: SafeKeeperRequest, wal: &[u8]) -> ProposerMessage {
+    ProposerMessage::Append(AppendRequest {
+        header: header,
+        wal: Bytes::copy_from_slice(wal),
+        checksum: None,
+    })
+}
+
+// Greet safekeeper as proposer and introduce the test system, returns its state
+pub(super) async fn handshake(
+    stream: &mut TcpStream,
+    system_id: SystemId,
+    pg_version: u32,
+) -> Result<SafeKeeperInfo> {
+    stream.write_all(&SK_GREETING_MAGIC.to_be_bytes()).await?;
+    let greeting = PeerGreeting {
+        protocol_version: SK_PROTOCOL_VERSION,
+        role: PeerRole::Proposer as u32,
+    };
+    send_msg(stream, &ProposerMessage::Greeting(greeting)).await?;
+    let server_info = ServerInfo {
+        protocol_version: SK_PROTOCOL_VERSION,
+        pg_version: pg_version,
+        node_id: NodeId { term: 0, uuid: 0 },
+        system_id: system_id,
+        wal_end: 0,
+        timeline: TIMELINE,
+        wal_seg_size: WAL_SEG_SIZE as u32,
+    };
+    send_msg(stream, &ProposerMessage::ServerInfo(server_info)).await?;
+    recv_msg(stream, AcceptorMessageKind::Info)
+        .await?
+        .into_info()
+}
+
+// Read WAL stored by safekeeper in the given range
+fn read_wal(
+    system_dir: &Path,
+    timeline: TimeLineID,
+    start_lsn: XLogRecPtr,
+    end_lsn: XLogRecPtr,
+) -> Result<Vec<u8>> {
+    let mut wal = Vec::new();
+    let mut lsn = start_lsn;
+    while lsn < end_lsn {
+        let segno = XLByteToSeg(lsn, WAL_SEG_SIZE);
+        let segment = SegmentPath::new(system_dir, timeline, segno, WAL_SEG_SIZE);
+        let mut content = Vec::new();
+        segment.open_for_read(None)?.read_to_end(&mut content)?;
+        let from = XLogSegmentOffset(lsn, WAL_SEG_SIZE) as usize;
+        let to = min(from as u64 + (end_lsn - lsn), WAL_SEG_SIZE as u64) as usize;
+        if content.len() < to {
+            io_error!(
+                "Segment {} is truncated to {} bytes",
+                segment.name,
+                content.len()
+            );
+        }
+        wal.extend_from_slice(&content[from..to]);
+        lsn += (to - from) as u64;
+    }
+    Ok(wal)
+}
+
+struct CrashTest {
+    rng: StdRng,
+    conf: WalAcceptorConf,
+    tenants: Arc<TenantRegistry>,
+    system_id: SystemId,
+    wal: GeneratedWal,
+    term: u64,
+    acked_term: u64,       /* vote acknowledged by safekeeper */
+    acked_epoch: u64,      /* epoch reported in append responses */
+    acked_lsn: XLogRecPtr, /* flush position reported in append responses */
+    sent_lsn: XLogRecPtr,  /* end of WAL sent to safekeeper */
+}
+
+impl CrashTest {
+    fn system_dir(&self) -> std::path::PathBuf {
+        tenant_dir(&self.conf.data_dir, self.system_id)
+    }
+
+    async fn connect(&self, listener: &TcpListener) -> Result<(TcpStream, JoinHandle<Result<()>>)> {
+        let stream = TcpStream::connect(listener.local_addr()?).await?;
+        let (socket, _) = listener.accept().await?;
+        let conf = self.conf.clone();
+        let tenants = self.tenants.clone();
+        let server = task::spawn(async move { serve_connection(socket, &conf, tenants).await });
+        Ok((stream, server))
+    }
+
+    async fn disconnect(&self, stream: TcpStream, server: JoinHandle<Result<()>>) -> Result<()> {
+        drop(stream);
+        match server.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => info!("Safekeeper session ended with: {}", e),
+            Err(e) => {
+                io_error!("Safekeeper failed: {}", e);
+            }
+        }
+        /* Session is over, restart safekeeper */
+        self.tenants.unload_system(self.system_id);
+        Ok(())
+    }
+
+    async fn handshake(&self, stream: &mut TcpStream) -> Result<SafeKeeperInfo> {
+        handshake(stream, self.system_id, PG_VERSION).await
+    }
+
+    // Check state reported by restarted safekeeper against what it has acknowledged before
+    fn check_recovered(&self, info: &SafeKeeperInfo) -> Result<()> {
+        if info.server.node_id.term < self.acked_term {
+            io_error!(
+                "Vote for term {} is lost, safekeeper reports term {}",
+                self.acked_term,
+                info.server.node_id.term
+            );
+        }
+        if info.epoch < self.acked_epoch {
+            io_error!(
+                "Epoch {} is lost, safekeeper reports epoch {}",
+                self.acked_epoch,
+                info.epoch
+            );
+        }
+        let durable_lsn = self.wal.record_boundary(self.acked_lsn);
+        if info.flush_lsn < durable_lsn {
+            io_error!(
+                "Acknowledged WAL up to {} is lost, safekeeper reports flush_lsn {}",
+                format_lsn(durable_lsn),
+                format_lsn(info.flush_lsn)
+            );
+        }
+        if info.flush_lsn > self.sent_lsn {
+            io_error!(
+                "Safekeeper reports flush_lsn {} beyond sent WAL {}",
+                format_lsn(info.flush_lsn),
+                format_lsn(self.sent_lsn)
+            );
+        }
+        if info.flush_lsn > self.wal.start_lsn {
+            let stored = read_wal(
+                &self.system_dir(),
+                info.server.timeline,
+                self.wal.start_lsn,
+                info.flush_lsn,
+            )?;
+            if stored != self.wal.slice(self.wal.start_lsn, info.flush_lsn) {
+                io_error!(
+                    "Stored WAL {}-{} differs from the sent one",
+                    format_lsn(self.wal.start_lsn),
+                    format_lsn(info.flush_lsn)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Get vote and stream WAL until safekeeper fails or the round is over
+    async fn vote_and_stream(
+        &mut self,
+        stream: &mut TcpStream,
+        flush_lsn: XLogRecPtr,
+    ) -> Result<()> {
+        self.term += 1;
+        let node_id = NodeId {
+            term: self.term,
+            uuid: PROPOSER_UUID,
+        };
+        let vote = RequestVote {
+            node_id: node_id,
+            vcl: flush_lsn,
+            epoch: self.term,
+        };
+        send_msg(stream, &ProposerMessage::RequestVote(vote)).await?;
+        let voted = recv_msg(stream, AcceptorMessageKind::Vote)
+            .await?
+            .into_vote()?;
+        if voted != node_id {
+            io_error!("Vote for term {} is rejected", self.term);
+        }
+        self.acked_term = self.term;
+
+        let mut pos = max(flush_lsn, self.wal.start_lsn);
+        let appends = self.rng.gen_range(1..=MAX_APPENDS_PER_ROUND);
+        let pipelined = self.conf.group_commit();
+        for _ in 0..appends {
+            if pos >= self.wal.end_lsn() {
+                break;
+            }
+            let end = min(
+                pos + self.rng.gen_range(1..=MAX_APPEND_SIZE),
+                self.wal.end_lsn(),
+            );
+            let req = SafeKeeperRequest {
+                sender_id: node_id,
+                begin_lsn: pos,
+                end_lsn: end,
+                restart_lsn: self.wal.start_lsn,
+                commit_lsn: self.acked_lsn,
+            };
+            send_msg(stream, &append_msg(req, self.wal.slice(pos, end))).await?;
+            self.sent_lsn = max(self.sent_lsn, end);
+            pos = end;
+            if !pipelined {
+                self.recv_ack(stream).await?;
+            }
+        }
+        /* Pipelined appends are acknowledged in batches, the last one covers the whole round */
+        while pipelined && self.acked_lsn < pos {
+            self.recv_ack(stream).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv_ack(&mut self, stream: &mut TcpStream) -> Result<()> {
+        let resp = recv_msg(stream, AcceptorMessageKind::Response)
+            .await?
+            .into_response()?;
+        if resp.status != SK_STATUS_OK {
+            io_error!("Append is rejected with status {}", resp.status);
+        }
+        if resp.flush_lsn > self.sent_lsn {
+            io_error!(
+                "Safekeeper acknowledges flush_lsn {} beyond sent WAL {}",
+                format_lsn(resp.flush_lsn),
+                format_lsn(self.sent_lsn)
+            );
+        }
+        self.acked_lsn = max(self.acked_lsn, resp.flush_lsn);
+        self.acked_epoch = max(self.acked_epoch, resp.epoch);
+        Ok(())
+    }
+
+    async fn run_rounds(&mut self, rounds: usize) -> Result<()> {
+        let listener = TcpListener::bind(self.conf.listen_addr).await?;
+        for round in 0..=rounds {
+            let (mut stream, server) = self.connect(&listener).await?;
+            /* Nothing is written before the vote, so handshake can't be affected by the crash */
+            let res = match self.handshake(&mut stream).await {
+                Ok(info) => self.check_recovered(&info).map(|_| info.flush_lsn),
+                Err(e) => Err(e),
+            };
+            if let (Ok(flush_lsn), true) = (&res, round < rounds) {
+                let crash_after = self.rng.gen_range(1..=4 * MAX_APPENDS_PER_ROUND);
+                fault_fs::arm(&self.system_dir(), self.rng.gen(), crash_after);
+                if let Err(e) = self.vote_and_stream(&mut stream, *flush_lsn).await {
+                    info!("Round {}: safekeeper stopped: {}", round, e);
+                }
+            }
+            self.disconnect(stream, server).await?;
+            if let Err(e) = res {
+                io_error!("Round {}: {}", round, e);
+            }
+            if round < rounds {
+                fault_fs::crash()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Configuration of safekeeper serving test sessions, with data in the given directory
+pub fn test_conf(data_dir: &Path) -> WalAcceptorConf {
+    WalAcceptorConf {
+        data_dir: data_dir.to_path_buf(),
+        daemonize: false,
+        no_sync: false,
+        sync_method: SyncMethod::Fsync,
+        wal_stats: false,
+        verify_wal_crc: true,
+        control_file_version: None,
+        pg_wal_layout: false,
+        slow_append_threshold: None,
+        slow_send_threshold: None,
+        heartbeat_interval: None,
+        keepalive_interval: Duration::from_secs(10),
+        proposer_read_timeout: None,
+        tcp_keepalive: None,
+        max_clock_skew: None,
+        catchup_rate_limit: None,
+        max_inflight_msgs: 1,
+        receive_high_watermark: None,
+        max_ack_delay: None,
+        group_commit_bytes: None,
+        group_commit_delay: None,
+        prealloc_segments: 0,
+        wal_retention: None,
+        wal_retention_time: None,
+        gc_coordinated: false,
+        read_cache_size: 0,
+        listen_addr: "127.0.0.1:0".parse().unwrap(),
+        pageserver_addr: None,
+        http_addr: None,
+        callback: CallbackConf::None,
+        trace_dir: None,
+        shutdown_report: None,
+        metrics_top_tenants: None,
+        acceptor_set: None,
+        access_list: AccessList::default(),
+        http_access_list: AccessList::default(),
+        legacy_tenant: None,
+        node_uuid: None,
+        workers: None,
+        tls: None,
+        at_rest_key: None,
+        object_storage: None,
+        archive: None,
+    }
+}
+
+//
+// Run the given number of crash-restart rounds with the workload defined by seed.
+// Returns error describing the first detected violation.
+//
+pub fn run(seed: u64, rounds: usize) -> Result<()> {
+    run_with(seed, rounds, |_| {})
+}
+
+//
+// The same with group commit: appends are pipelined, written without sync and synced
+// in batches, so that crash loses WAL which was received but not acknowledged.
+//
+pub fn run_group_commit(seed: u64, rounds: usize) -> Result<()> {
+    run_with(seed, rounds, |conf| {
+        conf.group_commit_bytes = Some(GROUP_COMMIT_BYTES);
+        conf.max_inflight_msgs = GROUP_COMMIT_INFLIGHT_MSGS;
+    })
+}
+
+fn run_with(seed: u64, rounds: usize, configure: impl FnOnce(&mut WalAcceptorConf)) -> Result<()> {
+    let data_dir = tempfile::tempdir()?;
+    let mut conf = test_conf(data_dir.path());
+    configure(&mut conf);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let system_id = rng.gen_range(1..SystemId::MAX);
+    let wal = GeneratedWal::generate(&mut rng, system_id);
+    let mut test = CrashTest {
+        rng: rng,
+        conf: conf,
+        tenants: TenantRegistry::new(),
+        system_id: system_id,
+        wal: wal,
+        term: 0,
+        acked_term: 0,
+        acked_epoch: 0,
+        acked_lsn: 0,
+        sent_lsn: 0,
+    };
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let res = runtime.block_on(test.run_rounds(rounds));
+    fault_fs::disarm();
+    res
+}
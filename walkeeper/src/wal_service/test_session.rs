@@ -0,0 +1,272 @@
+//
+//   Proposer sessions against an in-process safekeeper.
+//
+//   Tests of what the safekeeper does with received WAL (GC, archiving, recycling of
+//   segments, WAL senders) need a tenant with valid WAL and positions set by a proposer.
+//   TestSession elects a proposer of the next term and streams generated WAL (see
+//   crash_test) up to the given position, with the given restart and commit positions.
+//   The tenant stays loaded between sessions and can be inspected through System.
+//
+use log::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{max, min};
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::task;
+
+use super::crash_test::{
+    append_msg, handshake, recv_msg, send_msg, GeneratedWal, PG_VERSION, TIMELINE, WAL_SEG_SIZE,
+};
+use super::{serve_connection, System, TenantRegistry};
+use crate::pq_protocol::{Result, SystemId};
+use crate::safekeeper_protocol::*;
+use crate::xlog_utils::*;
+use crate::WalAcceptorConf;
+
+const MAX_APPEND_SIZE: u64 = 64 * 1024;
+const PROPOSER_UUID: u128 = 0xFACADE;
+
+pub struct TestSession {
+    runtime: runtime::Runtime,
+    listener: TcpListener,
+    conf: WalAcceptorConf,
+    tenants: Arc<TenantRegistry>,
+    system_id: SystemId,
+    wal: GeneratedWal,
+    term: u64,
+    pg_version: u32, /* version of Postgres reported by proposers */
+}
+
+impl TestSession {
+    //
+    // Start safekeeper with the given configuration, WAL of the session is defined by seed
+    //
+    pub fn start(conf: WalAcceptorConf, seed: u64) -> Result<TestSession> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let system_id = rng.gen_range(1..SystemId::MAX);
+        let wal = GeneratedWal::generate(&mut rng, system_id);
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = runtime.block_on(TcpListener::bind(conf.listen_addr))?;
+        Ok(TestSession {
+            runtime: runtime,
+            listener: listener,
+            conf: conf,
+            tenants: TenantRegistry::new(),
+            system_id: system_id,
+            wal: wal,
+            term: 0,
+            pg_version: PG_VERSION,
+        })
+    }
+
+    pub fn system_id(&self) -> SystemId {
+        self.system_id
+    }
+
+    pub fn tenants(&self) -> Arc<TenantRegistry> {
+        self.tenants.clone()
+    }
+
+    pub fn system(&self) -> Option<Arc<System>> {
+        self.tenants.get_system(self.system_id)
+    }
+
+    pub fn wal_seg_size(&self) -> usize {
+        WAL_SEG_SIZE
+    }
+
+    pub fn timeline(&self) -> TimeLineID {
+        TIMELINE
+    }
+
+    // Generated WAL starts at the second segment and spans several segments
+    pub fn start_lsn(&self) -> XLogRecPtr {
+        self.wal.start_lsn
+    }
+
+    pub fn end_lsn(&self) -> XLogRecPtr {
+        self.wal.end_lsn()
+    }
+
+    pub fn wal(&self, from: XLogRecPtr, to: XLogRecPtr) -> &[u8] {
+        self.wal.slice(from, to)
+    }
+
+    // Start of the last generated record at or before lsn
+    pub fn record_start(&self, lsn: XLogRecPtr) -> XLogRecPtr {
+        self.wal.record_boundary(lsn)
+    }
+
+    // Proposers of the following sessions run this version of Postgres
+    pub fn set_pg_version(&mut self, pg_version: u32) {
+        self.pg_version = pg_version;
+    }
+
+    // The next session is of the term following this one, e.g. a stale one
+    pub fn set_term(&mut self, term: u64) {
+        self.term = term;
+    }
+
+    // Run future on the runtime of the safekeeper, e.g. to use its async APIs
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    //
+    // Elect proposer of the next term and stream WAL from flush position of the
+    // safekeeper up to end_lsn, then end the session. Positions are updated by a heartbeat
+    // if there is no WAL to send. Returns the acknowledged flush position.
+    //
+    pub fn stream(
+        &mut self,
+        end_lsn: XLogRecPtr,
+        restart_lsn: XLogRecPtr,
+        commit_lsn: XLogRecPtr,
+    ) -> Result<XLogRecPtr> {
+        self.stream_from(None, end_lsn, restart_lsn, commit_lsn)
+    }
+
+    //
+    // The same, starting at the given position, which may be below flush position of
+    // the safekeeper, like proposer of a new term overwriting the tail of WAL
+    //
+    pub fn stream_from(
+        &mut self,
+        begin_lsn: Option<XLogRecPtr>,
+        end_lsn: XLogRecPtr,
+        restart_lsn: XLogRecPtr,
+        commit_lsn: XLogRecPtr,
+    ) -> Result<XLogRecPtr> {
+        self.term += 1;
+        let node_id = NodeId {
+            term: self.term,
+            uuid: PROPOSER_UUID,
+        };
+        let conf = self.conf.clone();
+        let tenants = self.tenants.clone();
+        let system_id = self.system_id;
+        let pg_version = self.pg_version;
+        let wal = &self.wal;
+        let listener = &self.listener;
+        self.runtime.block_on(async move {
+            let mut stream = TcpStream::connect(listener.local_addr()?).await?;
+            let (socket, _) = listener.accept().await?;
+            let server = task::spawn(async move { serve_connection(socket, &conf, tenants).await });
+
+            let info = handshake(&mut stream, system_id, pg_version).await?;
+            let vote = RequestVote {
+                node_id: node_id,
+                vcl: info.flush_lsn,
+                epoch: node_id.term,
+            };
+            send_msg(&mut stream, &ProposerMessage::RequestVote(vote)).await?;
+            let voted = recv_msg(&mut stream, AcceptorMessageKind::Vote).await?;
+            if voted.into_vote()? != node_id {
+                io_error!("Vote for term {} is rejected", node_id.term);
+            }
+            let mut pos = begin_lsn.unwrap_or_else(|| max(info.flush_lsn, wal.start_lsn));
+            let mut flush_lsn = info.flush_lsn;
+            /* At least one append is sent, empty one is a heartbeat updating the positions */
+            loop {
+                let end = max(min(pos + MAX_APPEND_SIZE, end_lsn), pos);
+                let req = SafeKeeperRequest {
+                    sender_id: node_id,
+                    begin_lsn: pos,
+                    end_lsn: end,
+                    restart_lsn: restart_lsn,
+                    commit_lsn: commit_lsn,
+                };
+                send_msg(&mut stream, &append_msg(req, wal.slice(pos, end))).await?;
+                let resp = recv_msg(&mut stream, AcceptorMessageKind::Response)
+                    .await?
+                    .into_response()?;
+                if resp.status != SK_STATUS_OK {
+                    io_error!(
+                        "Append {}-{} is rejected with status {}",
+                        format_lsn(pos),
+                        format_lsn(end),
+                        resp.status
+                    );
+                }
+                flush_lsn = resp.flush_lsn;
+                pos = end;
+                if pos >= end_lsn {
+                    break;
+                }
+            }
+            let req = SafeKeeperRequest {
+                sender_id: node_id,
+                begin_lsn: END_OF_STREAM,
+                end_lsn: END_OF_STREAM,
+                restart_lsn: restart_lsn,
+                commit_lsn: commit_lsn,
+            };
+            send_msg(&mut stream, &append_msg(req, &[])).await?;
+            match server.await {
+                Ok(res) => res?,
+                Err(e) => {
+                    io_error!("Safekeeper failed: {}", e);
+                }
+            }
+            Ok(flush_lsn)
+        })
+    }
+
+    //
+    // Elect proposer of the next term, send a heartbeat and then nothing, until the
+    // safekeeper gives up on the proposer and closes the connection. Returns statuses of
+    // responses received meanwhile, the one to the heartbeat included.
+    //
+    pub fn idle(&mut self) -> Result<Vec<u32>> {
+        self.term += 1;
+        let node_id = NodeId {
+            term: self.term,
+            uuid: PROPOSER_UUID,
+        };
+        let conf = self.conf.clone();
+        let tenants = self.tenants.clone();
+        let system_id = self.system_id;
+        let pg_version = self.pg_version;
+        let listener = &self.listener;
+        self.runtime.block_on(async move {
+            let mut stream = TcpStream::connect(listener.local_addr()?).await?;
+            let (socket, _) = listener.accept().await?;
+            let server = task::spawn(async move { serve_connection(socket, &conf, tenants).await });
+
+            let info = handshake(&mut stream, system_id, pg_version).await?;
+            let vote = RequestVote {
+                node_id: node_id,
+                vcl: info.flush_lsn,
+                epoch: node_id.term,
+            };
+            send_msg(&mut stream, &ProposerMessage::RequestVote(vote)).await?;
+            let voted = recv_msg(&mut stream, AcceptorMessageKind::Vote).await?;
+            if voted.into_vote()? != node_id {
+                io_error!("Vote for term {} is rejected", node_id.term);
+            }
+            let req = SafeKeeperRequest {
+                sender_id: node_id,
+                begin_lsn: info.flush_lsn,
+                end_lsn: info.flush_lsn,
+                restart_lsn: info.restart_lsn,
+                commit_lsn: info.commit_lsn,
+            };
+            send_msg(&mut stream, &append_msg(req, &[])).await?;
+            let mut statuses = Vec::new();
+            while let Ok(resp) = recv_msg(&mut stream, AcceptorMessageKind::Response).await {
+                statuses.push(resp.into_response()?.status);
+            }
+            if let Ok(Ok(_)) = server.await {
+                io_error!("Safekeeper has ended the session of idle proposer without error");
+            }
+            Ok(statuses)
+        })
+    }
+}
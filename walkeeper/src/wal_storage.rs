@@ -0,0 +1,45 @@
+//
+//   Storage of WAL of a tenant.
+//
+//   Local segments in the tenant directory are the default storage, object storage
+//   (--object-storage, see object_storage.rs) is an experimental one. The proposer
+//   protocol writes WAL, syncs WAL written by group commit and finds end of WAL through
+//   WalStorage, regardless of the storage in use (see System::with_wal_storage).
+//   Methods block on I/O, so they are called on the blocking thread pool.
+//
+use crate::pq_protocol::Result;
+use crate::xlog_utils::{TimeLineID, XLogRecPtr};
+
+pub trait WalStorage {
+    //
+    // Write WAL at startpos, overwriting WAL after it. Without sync (group commit) the
+    // storage may leave it unsynced until sync() is called.
+    //
+    fn write(
+        &mut self,
+        startpos: XLogRecPtr,
+        timeline: TimeLineID,
+        buf: &[u8],
+        sync: bool,
+    ) -> Result<()>;
+
+    // Make WAL written without sync durable up to end_lsn
+    fn sync(&mut self, end_lsn: XLogRecPtr, timeline: TimeLineID) -> Result<()>;
+
+    //
+    // End of stored WAL and its timeline. With precise, the end of the last valid
+    // record, otherwise the end of the last segment may be enough.
+    //
+    fn end_of_wal(&mut self, precise: bool) -> Result<(XLogRecPtr, TimeLineID)>;
+
+    //
+    // Position of the first byte of stored WAL differing from buf written at startpos,
+    // None if the stored WAL is the same, e.g. proposer of the new term resends it.
+    //
+    fn diverged(
+        &mut self,
+        startpos: XLogRecPtr,
+        timeline: TimeLineID,
+        buf: &[u8],
+    ) -> Result<Option<XLogRecPtr>>;
+}
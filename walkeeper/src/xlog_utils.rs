@@ -2,20 +2,40 @@ use byteorder::{ByteOrder, LittleEndian};
 use crc32c::*;
 use log::*;
 use std::cmp::min;
+use std::collections::HashMap;
 use std::fs::{self, File};
+use std::io;
 use std::io::prelude::*;
 use std::path::PathBuf;
 use std::time::SystemTime;
 
+use crate::at_rest;
+
 pub const XLOG_FNAME_LEN: usize = 24;
+pub const ARCHIVE_STATUS_DIR: &str = "archive_status";
 pub const XLOG_BLCKSZ: usize = 8192;
 pub const XLP_FIRST_IS_CONTRECORD: u16 = 0x0001;
+pub const XLP_LONG_HEADER: u16 = 0x0002;
 pub const XLOG_PAGE_MAGIC: u16 = 0xD109;
 pub const XLP_REM_LEN_OFFS: usize = 2 + 2 + 4 + 8;
 pub const XLOG_SIZE_OF_XLOG_SHORT_PHD: usize = XLP_REM_LEN_OFFS + 4 + 4;
 pub const XLOG_SIZE_OF_XLOG_LONG_PHD: usize = XLOG_SIZE_OF_XLOG_SHORT_PHD + 8 + 4 + 4;
 pub const XLOG_RECORD_CRC_OFFS: usize = 4 + 4 + 8 + 1 + 1 + 2;
 pub const XLOG_SIZE_OF_XLOG_RECORD: usize = XLOG_RECORD_CRC_OFFS + 4;
+pub const XLOG_RECORD_RMID_OFFS: usize = 4 + 4 + 8 + 1;
+pub const XLOG_RECORD_INFO_OFFS: usize = 4 + 4 + 8;
+pub const RM_XACT_ID: u8 = 1;
+pub const XLOG_XACT_OPMASK: u8 = 0x70;
+pub const XLOG_XACT_COMMIT: u8 = 0x00;
+pub const XLOG_XACT_ABORT: u8 = 0x20;
+pub const XLOG_XACT_COMMIT_PREPARED: u8 = 0x30;
+pub const XLOG_XACT_ABORT_PREPARED: u8 = 0x40;
+pub const XLR_BLOCK_ID_DATA_SHORT: u8 = 255;
+pub const XLR_BLOCK_ID_DATA_LONG: u8 = 254;
+pub const XLR_BLOCK_ID_ORIGIN: u8 = 253;
+pub const XLR_BLOCK_ID_TOPLEVEL_XID: u8 = 252;
+const XACT_BODY_PREFIX: usize = 24; /* enough for origin, toplevel xid and main data headers and xact_time */
+pub const RM_MAX_ID: usize = 255;
 pub type XLogRecPtr = u64;
 pub type TimeLineID = u32;
 pub type TimestampTz = u64;
@@ -73,14 +93,282 @@ pub fn IsPartialXLogFileName(fname: &str) -> bool {
     return fname.ends_with(".partial") && IsXLogFileName(&fname[0..fname.len() - 8]);
 }
 
+// Names of builtin resource managers, indexed by rmid
+pub const RM_NAMES: [&str; 22] = [
+    "XLOG",
+    "Transaction",
+    "Storage",
+    "CLOG",
+    "Database",
+    "Tablespace",
+    "MultiXact",
+    "RelMap",
+    "Standby",
+    "Heap2",
+    "Heap",
+    "Btree",
+    "Hash",
+    "Gin",
+    "Gist",
+    "Sequence",
+    "SPGist",
+    "BRIN",
+    "CommitTs",
+    "ReplicationOrigin",
+    "Generic",
+    "LogicalMessage",
+];
+
+pub fn rm_name(rmid: u8) -> String {
+    match RM_NAMES.get(rmid as usize) {
+        Some(name) => name.to_string(),
+        None => format!("custom_{}", rmid),
+    }
+}
+
+//
+// Incremental scanner of WAL record headers.
+// WAL is fed in arbitrary chunks (not aligned on record or page boundaries) and
+// scanner reports resource manager and total length of each record header it encounters.
+// It also remembers timestamp of the last transaction commit or abort record.
+// With CRC verification it checks xl_crc of each record once the whole record is fed.
+// Otherwise it doesn't validate records: if it gets lost (discontinuous input, zero
+// record length), it silently resynchronizes at the next page header.
+//
+pub struct WalRecordScanner {
+    lsn: XLogRecPtr, /* position of the next byte to process */
+    wal_seg_size: usize,
+    synced: bool, /* do we know where the next record starts? */
+    page_hdr: [u8; XLOG_SIZE_OF_XLOG_LONG_PHD],
+    page_hdr_size: usize, /* size of page header being collected, 0 if not inside page header */
+    page_hdr_len: usize,
+    rec_hdr: [u8; XLOG_SIZE_OF_XLOG_RECORD],
+    rec_hdr_len: usize,
+    skip: usize, /* bytes of current record body (and alignment padding) left to skip */
+    xact_body: [u8; XACT_BODY_PREFIX], /* beginning of the body of transaction end record */
+    xact_body_len: usize,
+    xact_body_want: usize, /* bytes of the body to collect, 0 if current record is not transaction end */
+    xact_time: Option<TimestampTz>, /* timestamp of the last transaction end record */
+    verify_crc: bool,
+    rec_lsn: XLogRecPtr, /* start of the current record */
+    crc_left: usize, /* bytes of current record body not yet added to crc */
+    crc: u32, /* CRC of the current record body fed so far */
+}
+
+/*
+ * Extract xact_time from the beginning of transaction commit/abort record body:
+ * optional origin and toplevel xid headers, main data header and xl_xact_commit/abort
+ * starting with the timestamp.
+ */
+fn parse_xact_time(body: &[u8]) -> Option<TimestampTz> {
+    let mut pos = 0;
+    loop {
+        match *body.get(pos)? {
+            XLR_BLOCK_ID_DATA_SHORT => {
+                pos += 2;
+                break;
+            }
+            XLR_BLOCK_ID_DATA_LONG => {
+                pos += 5;
+                break;
+            }
+            XLR_BLOCK_ID_ORIGIN => pos += 3,
+            XLR_BLOCK_ID_TOPLEVEL_XID => pos += 5,
+            _ => return None,
+        }
+    }
+    if pos + 8 > body.len() {
+        return None;
+    }
+    Some(LittleEndian::read_u64(&body[pos..pos + 8]))
+}
+
+impl WalRecordScanner {
+    pub fn new(wal_seg_size: usize, verify_crc: bool) -> WalRecordScanner {
+        WalRecordScanner {
+            lsn: 0,
+            wal_seg_size: wal_seg_size,
+            synced: false,
+            page_hdr: [0u8; XLOG_SIZE_OF_XLOG_LONG_PHD],
+            page_hdr_size: 0,
+            page_hdr_len: 0,
+            rec_hdr: [0u8; XLOG_SIZE_OF_XLOG_RECORD],
+            rec_hdr_len: 0,
+            skip: 0,
+            xact_body: [0u8; XACT_BODY_PREFIX],
+            xact_body_len: 0,
+            xact_body_want: 0,
+            xact_time: None,
+            verify_crc: verify_crc,
+            rec_lsn: 0,
+            crc_left: 0,
+            crc: 0,
+        }
+    }
+
+    // Timestamp of the last transaction end record fed since the previous call
+    pub fn take_xact_time(&mut self) -> Option<TimestampTz> {
+        self.xact_time.take()
+    }
+
+    fn desync(&mut self) {
+        self.synced = false;
+        self.rec_hdr_len = 0;
+        self.skip = 0;
+        self.xact_body_want = 0;
+        self.crc_left = 0;
+    }
+
+    /* Add body bytes of the current record to its CRC, check it at the end of the body */
+    fn update_crc(&mut self, body: &[u8]) -> bool {
+        let len = min(self.crc_left, body.len());
+        self.crc = crc32c_append(self.crc, &body[..len]);
+        self.crc_left -= len;
+        if self.crc_left != 0 {
+            return true;
+        }
+        let crc = crc32c_append(self.crc, &self.rec_hdr[..XLOG_RECORD_CRC_OFFS]);
+        crc == LittleEndian::read_u32(&self.rec_hdr[XLOG_RECORD_CRC_OFFS..])
+    }
+
+    //
+    // Feed WAL starting at `lsn`, calling `on_record(rmid, xl_tot_len)` for each record.
+    // With CRC verification returns start of the first record completed by this chunk
+    // whose CRC doesn't match.
+    //
+    pub fn feed<F: FnMut(u8, u32)>(
+        &mut self,
+        lsn: XLogRecPtr,
+        buf: &[u8],
+        mut on_record: F,
+    ) -> Option<XLogRecPtr> {
+        let mut corrupt = None;
+        if lsn != self.lsn {
+            self.lsn = lsn;
+            self.page_hdr_size = 0;
+            self.desync();
+        }
+        let mut pos: usize = 0;
+        while pos < buf.len() {
+            let page_offs = (self.lsn % XLOG_BLCKSZ as u64) as usize;
+            if page_offs == 0 && self.page_hdr_size == 0 {
+                self.page_hdr_size = if XLogSegmentOffset(self.lsn, self.wal_seg_size) == 0 {
+                    XLOG_SIZE_OF_XLOG_LONG_PHD
+                } else {
+                    XLOG_SIZE_OF_XLOG_SHORT_PHD
+                };
+                self.page_hdr_len = 0;
+            }
+            let page_left = min(XLOG_BLCKSZ - page_offs, buf.len() - pos);
+            let n;
+            if self.page_hdr_size != 0 {
+                n = min(self.page_hdr_size - self.page_hdr_len, page_left);
+                self.page_hdr[self.page_hdr_len..self.page_hdr_len + n]
+                    .copy_from_slice(&buf[pos..pos + n]);
+                self.page_hdr_len += n;
+                if self.page_hdr_len == self.page_hdr_size {
+                    if !self.synced {
+                        let xlp_info = LittleEndian::read_u16(&self.page_hdr[2..4]);
+                        let xlp_rem_len = LittleEndian::read_u32(
+                            &self.page_hdr[XLP_REM_LEN_OFFS..XLP_REM_LEN_OFFS + 4],
+                        ) as usize;
+                        if (xlp_info & XLP_FIRST_IS_CONTRECORD) == 0 {
+                            self.synced = true;
+                        } else if xlp_rem_len <= XLOG_BLCKSZ - self.page_hdr_size {
+                            /* continuation record ends on this page */
+                            self.skip = (xlp_rem_len + 7) & !7;
+                            self.synced = true;
+                        }
+                    }
+                    self.page_hdr_size = 0;
+                }
+            } else if !self.synced {
+                /* wait for the next page header */
+                n = page_left;
+            } else if self.skip != 0 {
+                n = min(self.skip, page_left);
+                if self.crc_left != 0 && !self.update_crc(&buf[pos..pos + n]) && corrupt.is_none() {
+                    corrupt = Some(self.rec_lsn);
+                }
+                if self.xact_body_len < self.xact_body_want {
+                    let len = min(self.xact_body_want - self.xact_body_len, n);
+                    self.xact_body[self.xact_body_len..self.xact_body_len + len]
+                        .copy_from_slice(&buf[pos..pos + len]);
+                    self.xact_body_len += len;
+                    if self.xact_body_len == self.xact_body_want {
+                        if let Some(ts) = parse_xact_time(&self.xact_body[..self.xact_body_len]) {
+                            self.xact_time = Some(ts);
+                        }
+                        self.xact_body_want = 0;
+                    }
+                }
+                self.skip -= n;
+            } else {
+                n = min(XLOG_SIZE_OF_XLOG_RECORD - self.rec_hdr_len, page_left);
+                if self.rec_hdr_len == 0 {
+                    self.rec_lsn = self.lsn;
+                }
+                self.rec_hdr[self.rec_hdr_len..self.rec_hdr_len + n]
+                    .copy_from_slice(&buf[pos..pos + n]);
+                self.rec_hdr_len += n;
+                if self.rec_hdr_len == XLOG_SIZE_OF_XLOG_RECORD {
+                    let xl_tot_len = LittleEndian::read_u32(&self.rec_hdr[0..4]) as usize;
+                    if xl_tot_len < XLOG_SIZE_OF_XLOG_RECORD {
+                        /* zero padding at the end of WAL or after segment switch */
+                        self.desync();
+                    } else {
+                        let rmid = self.rec_hdr[XLOG_RECORD_RMID_OFFS];
+                        on_record(rmid, xl_tot_len as u32);
+                        self.skip = ((xl_tot_len + 7) & !7) - XLOG_SIZE_OF_XLOG_RECORD;
+                        self.rec_hdr_len = 0;
+                        if self.verify_crc {
+                            self.crc = 0;
+                            self.crc_left = xl_tot_len - XLOG_SIZE_OF_XLOG_RECORD;
+                            if self.crc_left == 0 && !self.update_crc(&[]) && corrupt.is_none() {
+                                corrupt = Some(self.rec_lsn);
+                            }
+                        }
+                        let xact_op = self.rec_hdr[XLOG_RECORD_INFO_OFFS] & XLOG_XACT_OPMASK;
+                        if rmid == RM_XACT_ID
+                            && (xact_op == XLOG_XACT_COMMIT
+                                || xact_op == XLOG_XACT_ABORT
+                                || xact_op == XLOG_XACT_COMMIT_PREPARED
+                                || xact_op == XLOG_XACT_ABORT_PREPARED)
+                        {
+                            self.xact_body_want =
+                                min(XACT_BODY_PREFIX, xl_tot_len - XLOG_SIZE_OF_XLOG_RECORD);
+                            self.xact_body_len = 0;
+                        }
+                    }
+                }
+            }
+            pos += n;
+            self.lsn += n as u64;
+        }
+        corrupt
+    }
+}
+
+// Format LSN in the conventional %X/%X form
+pub fn format_lsn(lsn: XLogRecPtr) -> String {
+    format!("{:X}/{:>08X}", (lsn >> 32) as u32, lsn as u32)
+}
+
 pub fn get_current_timestamp() -> TimestampTz {
+    to_pg_timestamp(crate::clock::system_time())
+}
+
+// Convert system time to Postgres timestamp (microseconds since 2000-01-01)
+pub fn to_pg_timestamp(time: SystemTime) -> TimestampTz {
     const UNIX_EPOCH_JDATE: u64 = 2440588; /* == date2j(1970, 1, 1) */
     const POSTGRES_EPOCH_JDATE: u64 = 2451545; /* == date2j(2000, 1, 1) */
     const SECS_PER_DAY: u64 = 86400;
     const USECS_PER_SEC: u64 = 1000000;
-    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
         Ok(n) => {
-            (n.as_secs() - ((POSTGRES_EPOCH_JDATE - UNIX_EPOCH_JDATE) * SECS_PER_DAY))
+            /* Times before Postgres epoch are clamped to it */
+            n.as_secs()
+                .saturating_sub((POSTGRES_EPOCH_JDATE - UNIX_EPOCH_JDATE) * SECS_PER_DAY)
                 * USECS_PER_SEC
                 + n.subsec_micros() as u64
         }
@@ -88,21 +376,22 @@ pub fn get_current_timestamp() -> TimestampTz {
     }
 }
 
-fn find_end_of_wal_segment(
-    data_dir: &PathBuf,
-    segno: XLogSegNo,
-    tli: TimeLineID,
-    wal_seg_size: usize,
-) -> u32 {
+// Check if segment is marked as completed in pg_wal/archive_status
+pub fn is_segment_archivable(data_dir: &PathBuf, fname: &str) -> bool {
+    let status_dir = data_dir.join(ARCHIVE_STATUS_DIR);
+    return status_dir.join(fname.to_owned() + ".ready").exists()
+        || status_dir.join(fname.to_owned() + ".done").exists();
+}
+
+pub fn find_end_of_wal_segment(data_dir: &PathBuf, file_name: &str, wal_seg_size: usize) -> u32 {
     let mut offs: usize = 0;
     let mut contlen: usize = 0;
     let mut wal_crc: u32 = 0;
     let mut crc: u32 = 0;
     let mut rec_offs: usize = 0;
     let mut buf = [0u8; XLOG_BLCKSZ];
-    let file_name = XLogFileName(tli, segno, wal_seg_size);
     let mut last_valid_rec_pos: usize = 0;
-    let mut file = File::open(data_dir.join(file_name.clone() + ".partial")).unwrap();
+    let mut file = File::open(data_dir.join(file_name)).unwrap();
     let mut rec_hdr = [0u8; XLOG_RECORD_CRC_OFFS];
 
     while offs < wal_seg_size {
@@ -118,7 +407,7 @@ fn find_end_of_wal_segment(
             let xlp_info = LittleEndian::read_u16(&buf[2..4]);
             let xlp_rem_len = LittleEndian::read_u32(&buf[XLP_REM_LEN_OFFS..XLP_REM_LEN_OFFS + 4]);
             if xlp_magic != XLOG_PAGE_MAGIC {
-                info!("Invalid WAL file {}.partial magic {}", file_name, xlp_magic);
+                info!("Invalid WAL file {} magic {}", file_name, xlp_magic);
                 break;
             }
             if offs == 0 {
@@ -184,18 +473,24 @@ fn find_end_of_wal_segment(
     return last_valid_rec_pos as u32;
 }
 
+//
+// Find end of WAL in the directory. In pg_wal layout the last segment has its final name
+// and is considered completed only when it is marked in archive_status.
+//
 pub fn find_end_of_wal(
     data_dir: &PathBuf,
     wal_seg_size: usize,
     precise: bool,
+    pg_wal_layout: bool,
 ) -> (XLogRecPtr, TimeLineID) {
     let mut high_segno: XLogSegNo = 0;
     let mut high_tli: TimeLineID = 0;
     let mut high_ispartial = false;
+    let mut high_fname = String::new();
 
     for entry in fs::read_dir(data_dir).unwrap() {
         if let Ok(entry) = entry {
-            let ispartial: bool;
+            let mut ispartial: bool;
             let entry_name = entry.file_name();
             let fname = entry_name.to_str().unwrap();
             /*
@@ -209,9 +504,15 @@ pub fn find_end_of_wal(
                 continue;
             }
             let (segno, tli) = XLogFromFileName(fname, wal_seg_size);
-            if !ispartial && entry.metadata().unwrap().len() != wal_seg_size as u64 {
+            if !ispartial
+                && entry.metadata().unwrap().len() != wal_seg_size as u64
+                && !at_rest::is_encoded(&entry.path())
+            {
                 continue;
             }
+            if !ispartial && pg_wal_layout && !is_segment_archivable(data_dir, fname) {
+                ispartial = true;
+            }
             if segno > high_segno
                 || (segno == high_segno && tli > high_tli)
                 || (segno == high_segno && tli == high_tli && high_ispartial && !ispartial)
@@ -219,6 +520,7 @@ pub fn find_end_of_wal(
                 high_segno = segno;
                 high_tli = tli;
                 high_ispartial = ispartial;
+                high_fname = fname.to_string();
             }
         }
     }
@@ -232,7 +534,7 @@ pub fn find_end_of_wal(
             high_segno += 1;
         } else if precise {
             /* otherwise locate last record in last partial segment */
-            high_offs = find_end_of_wal_segment(data_dir, high_segno, high_tli, wal_seg_size);
+            high_offs = find_end_of_wal_segment(data_dir, &high_fname, wal_seg_size);
         }
         let high_ptr = XLogSegNoOffsetToRecPtr(high_segno, high_offs, wal_seg_size);
         return (high_ptr, high_tli);
@@ -240,11 +542,105 @@ pub fn find_end_of_wal(
     return (0, 0);
 }
 
+//
+// Find the oldest WAL segment (complete or partial) in the directory.
+// Files with names which are not UTF-8 can't be segments and are skipped.
+//
+pub fn find_start_of_wal(data_dir: &PathBuf, wal_seg_size: usize) -> io::Result<Option<XLogSegNo>> {
+    let mut low_segno: Option<XLogSegNo> = None;
+    for entry in fs::read_dir(data_dir)? {
+        if let Ok(entry) = entry {
+            let entry_name = entry.file_name();
+            let fname = match entry_name.to_str() {
+                Some(fname) => fname,
+                None => continue,
+            };
+            if IsXLogFileName(fname) || IsPartialXLogFileName(fname) {
+                let (segno, _tli) = XLogFromFileName(fname, wal_seg_size);
+                if low_segno.map_or(true, |low| segno < low) {
+                    low_segno = Some(segno);
+                }
+            }
+        }
+    }
+    Ok(low_segno)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WalGap {
+    Missing(XLogSegNo, XLogSegNo), /* segments first..=last are absent */
+    Truncated(XLogSegNo, u64),     /* segment is shorter than WAL it should contain: its size */
+}
+
+//
+// Find discontinuities of WAL between the oldest segment in the directory and flush_lsn.
+// Files with names which are not UTF-8 can't be segments and are skipped.
+//
+pub fn find_wal_gaps(
+    data_dir: &PathBuf,
+    wal_seg_size: usize,
+    flush_lsn: XLogRecPtr,
+) -> io::Result<Vec<WalGap>> {
+    let mut gaps = Vec::new();
+    if flush_lsn == 0 {
+        return Ok(gaps);
+    }
+    let mut sizes: HashMap<XLogSegNo, u64> = HashMap::new();
+    for entry in fs::read_dir(data_dir)? {
+        if let Ok(entry) = entry {
+            let entry_name = entry.file_name();
+            let fname = match entry_name.to_str() {
+                Some(fname) => fname,
+                None => continue,
+            };
+            if IsXLogFileName(fname) || IsPartialXLogFileName(fname) {
+                let (segno, _tli) = XLogFromFileName(fname, wal_seg_size);
+                let mut size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                if at_rest::is_encoded(&entry.path()) {
+                    size = wal_seg_size as u64; /* completed segment encoded at rest */
+                }
+                let max_size = sizes.entry(segno).or_insert(0);
+                *max_size = (*max_size).max(size);
+            }
+        }
+    }
+    let first_segno = match sizes.keys().min() {
+        Some(segno) => *segno,
+        None => return Ok(gaps),
+    };
+    let last_segno = XLByteToSeg(flush_lsn - 1, wal_seg_size);
+    let mut missing_from: Option<XLogSegNo> = None;
+    for segno in first_segno..=last_segno {
+        match sizes.get(&segno) {
+            None => {
+                missing_from.get_or_insert(segno);
+            }
+            Some(size) => {
+                if let Some(from) = missing_from.take() {
+                    gaps.push(WalGap::Missing(from, segno - 1));
+                }
+                let expected = if segno == last_segno {
+                    flush_lsn - XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size)
+                } else {
+                    wal_seg_size as u64
+                };
+                if *size < expected {
+                    gaps.push(WalGap::Truncated(segno, *size));
+                }
+            }
+        }
+    }
+    if let Some(from) = missing_from {
+        gaps.push(WalGap::Missing(from, last_segno));
+    }
+    Ok(gaps)
+}
+
 pub fn main() {
     let mut data_dir = PathBuf::new();
     data_dir.push(".");
     let wal_seg_size = 16 * 1024 * 1024;
-    let (wal_end, tli) = find_end_of_wal(&data_dir, wal_seg_size, true);
+    let (wal_end, tli) = find_end_of_wal(&data_dir, wal_seg_size, true, false);
     println!(
         "wal_end={:>08X}{:>08X}, tli={}",
         (wal_end >> 32) as u32,